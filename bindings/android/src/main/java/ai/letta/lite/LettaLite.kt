@@ -180,7 +180,9 @@ data class TokenUsage(
     @SerializedName("completion_tokens")
     val completionTokens: Int,
     @SerializedName("total_tokens")
-    val totalTokens: Int
+    val totalTokens: Int,
+    @SerializedName("cost_usd")
+    val costUsd: Double?
 )
 
 data class ArchivalResult(
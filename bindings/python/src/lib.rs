@@ -0,0 +1,316 @@
+//! Python bindings for letta-lite, built with maturin:
+//!
+//! ```python
+//! import letta_lite
+//!
+//! agent = letta_lite.Agent({"name": "helper", "model": "toy"})
+//! print(agent.step("Hello!")["text"])
+//! ```
+//!
+//! Calls block the calling thread but release the GIL while the agent works.
+//! Every failure raises `letta_lite.LettaError`, whose `code` attribute is the
+//! machine-readable code from `LettaError::code`, e.g. `"config.invalid"`.
+
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use lazy_static::lazy_static;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use serde_json::{json, Value};
+
+use letta_core::{
+    Agent, AgentConfig, LettaError as CoreError, LlmProvider, StepEvent,
+    af::AgentFile,
+    agent::StepResult,
+    provider::{ProviderConfig, ProviderEnv, ProviderFactory},
+};
+use letta_storage::{Storage, StorageConfig};
+
+lazy_static! {
+    // Shared by every agent, as in letta-ffi
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Runtime::new().unwrap();
+}
+
+create_exception!(
+    letta_lite,
+    LettaError,
+    PyException,
+    "A letta-lite failure. `code` is machine-readable, e.g. \"memory.limit_exceeded\", and `retryable` says whether the same call may succeed if made again. Provider failures also carry `kind`, e.g. \"rate_limit\"."
+);
+
+/// A `LettaError` exception carrying `code` and `retryable`
+fn raise(code: &str, message: String, retryable: bool) -> PyErr {
+    Python::with_gil(|py| {
+        let error = LettaError::new_err(message);
+        let value = error.value(py);
+        // Plain attribute writes on a fresh exception instance can't fail
+        let _ = value.setattr("code", code);
+        let _ = value.setattr("retryable", retryable);
+        error
+    })
+}
+
+fn py_err(error: impl Into<CoreError>) -> PyErr {
+    let error = error.into();
+    let raised = raise(error.code(), error.to_string(), error.is_retryable());
+    if let CoreError::Provider(e) = &error {
+        Python::with_gil(|py| {
+            let _ = raised.value(py).setattr("kind", e.kind.as_str());
+        });
+    }
+    raised
+}
+
+fn to_py(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(py.import("json")?.call_method1("loads", (value.to_string(),))?.into())
+}
+
+fn from_py(py: Python<'_>, value: &PyAny) -> PyResult<Value> {
+    let text: String = py.import("json")?.call_method1("dumps", (value,))?.extract()?;
+    serde_json::from_str(&text).map_err(py_err)
+}
+
+/// The dict `step` and `reply_only` return, shaped like `letta_converse`'s JSON
+fn step_json(step_result: &StepResult) -> Value {
+    json!({
+        "text": step_result.text,
+        "tool_trace": step_result.tool_trace,
+        "usage": step_result.usage,
+        "stop_reason": step_result.stop_reason,
+    })
+}
+
+/// Build the provider from a `ProviderConfig`-shaped dict, or else from the
+/// model name with API keys from the environment. Unknown models fail with
+/// `config.invalid`.
+fn create_provider(py: Python<'_>, config: &AgentConfig, provider: Option<Value>) -> PyResult<Box<dyn LlmProvider>> {
+    let provider = match provider {
+        Some(provider) => serde_json::from_value(provider)
+            .map_err(|e| py_err(CoreError::InvalidConfig(format!("Invalid provider config: {}", e))))?,
+        None => ProviderConfig::from_model(&config.model, &ProviderEnv::from_env()).map_err(py_err)?,
+    };
+    py.allow_threads(|| RUNTIME.block_on(ProviderFactory::create(provider))).map_err(py_err)
+}
+
+fn provider_arg(py: Python<'_>, provider: Option<&PyDict>) -> PyResult<Option<Value>> {
+    provider.map(|provider| from_py(py, provider)).transpose()
+}
+
+/// Pass one stream event to the Python callback as a dict
+fn call_event(callback: &PyObject, event: &StepEvent) -> PyResult<()> {
+    Python::with_gil(|py| {
+        let event = serde_json::to_value(event).map_err(py_err)?;
+        callback.call1(py, (to_py(py, &event)?,))?;
+        Ok(())
+    })
+}
+
+/// An agent held in memory; save it with `Storage.save`
+#[pyclass(name = "Agent", module = "letta_lite")]
+pub struct PyAgent {
+    agent: Mutex<Agent>,
+}
+
+impl PyAgent {
+    fn wrap(agent: Agent) -> Self {
+        Self { agent: Mutex::new(agent) }
+    }
+
+    /// The agent, or an `agent.busy` error while another call holds it, e.g.
+    /// when a stream callback calls back into the agent that is stepping
+    fn lock(&self) -> PyResult<MutexGuard<'_, Agent>> {
+        match self.agent.try_lock() {
+            Ok(agent) => Ok(agent),
+            Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => Err(raise("agent.busy", "Agent is busy with another call".into(), true)),
+        }
+    }
+
+    fn reply(&self, py: Python<'_>, message: Option<String>, on_event: Option<PyObject>) -> PyResult<PyObject> {
+        let mut guard = self.lock()?;
+        let agent: &mut Agent = &mut guard;
+        let result = match (message, on_event) {
+            (Some(message), None) => py.allow_threads(|| RUNTIME.block_on(agent.step(message))),
+            (Some(message), Some(on_event)) => {
+                // Later events are dropped once the callback raises; its
+                // exception replaces the step's result
+                let mut callback_error = None;
+                let result = py.allow_threads(|| RUNTIME.block_on(agent.step_stream(message, |event| {
+                    if callback_error.is_none() {
+                        callback_error = call_event(&on_event, &event).err();
+                    }
+                })));
+                if let Some(error) = callback_error {
+                    return Err(error);
+                }
+                result
+            }
+            (None, _) => py.allow_threads(|| RUNTIME.block_on(agent.reply_only())),
+        };
+        to_py(py, &step_json(&result.map_err(py_err)?))
+    }
+}
+
+#[pymethods]
+impl PyAgent {
+    /// `config` holds `AgentConfig` fields, e.g. `{"name": "helper",
+    /// "temperature": 0.2}`; fields left out keep their defaults. An optional
+    /// `provider` entry selects the provider in `ProviderConfig`'s format,
+    /// e.g. `{"type": "openai", "api_key": ..., "model": ...}`; without it the
+    /// model name picks one, as in `letta_create_agent`.
+    #[new]
+    #[pyo3(signature = (config = None))]
+    fn new(py: Python<'_>, config: Option<&PyDict>) -> PyResult<Self> {
+        let mut config = match config {
+            Some(config) => from_py(py, config)?,
+            None => json!({}),
+        };
+        let provider = config.as_object_mut().and_then(|config| config.remove("provider"));
+        let config: AgentConfig = letta_core::config::section("config", &config).map_err(py_err)?;
+        config.validate().map_err(py_err)?;
+        let provider = create_provider(py, &config, provider)?;
+        Ok(Self::wrap(Agent::new(config, provider)))
+    }
+
+    /// Create an agent from an agent file's JSON. `provider` is as for the
+    /// constructor; without it the file's model decides.
+    #[staticmethod]
+    #[pyo3(signature = (af_json, provider = None))]
+    fn import_af(py: Python<'_>, af_json: &str, provider: Option<&PyDict>) -> PyResult<Self> {
+        let af = AgentFile::from_json(af_json).map_err(py_err)?;
+        if af.agents.len() > 1 {
+            return Err(raise(
+                "af.multiple_agents",
+                format!("Agent file holds {} agents; import them one at a time", af.agents.len()),
+                false,
+            ));
+        }
+        let (config, state) = AgentFile::import(&af).map_err(py_err)?;
+        config.validate().map_err(py_err)?;
+        let provider = create_provider(py, &config, provider_arg(py, provider)?)?;
+        Ok(Self::wrap(Agent::new(config, provider).with_state(state)))
+    }
+
+    #[getter]
+    fn id(&self) -> PyResult<String> {
+        Ok(self.lock()?.state.id.clone())
+    }
+
+    #[getter]
+    fn name(&self) -> PyResult<String> {
+        Ok(self.lock()?.config.name.clone())
+    }
+
+    /// Send `message` and run the agent until it replies. Returns
+    /// `{"text", "tool_trace", "usage", "stop_reason"}`. If given, `on_event`
+    /// is called with each `{"type": "text_delta", "text": ...}` or
+    /// `{"type": "tool_call", ...}` event as the step runs.
+    #[pyo3(signature = (message, on_event = None))]
+    fn step(&self, py: Python<'_>, message: String, on_event: Option<PyObject>) -> PyResult<PyObject> {
+        self.reply(py, Some(message), on_event)
+    }
+
+    /// Record a user message without replying; returns its id
+    fn send_only(&self, message: String) -> PyResult<String> {
+        Ok(self.lock()?.send_only(message))
+    }
+
+    /// Reply to everything sent so far; returns the same dict as `step`
+    fn reply_only(&self, py: Python<'_>) -> PyResult<PyObject> {
+        self.reply(py, None, None)
+    }
+
+    /// A memory block's value, or `None` if there is no such block
+    fn get_block(&self, label: &str) -> PyResult<Option<String>> {
+        Ok(self.lock()?.get_memory_block(label))
+    }
+
+    fn set_block(&self, label: &str, value: &str) -> PyResult<()> {
+        self.lock()?.set_memory_block(label, value).map_err(py_err)
+    }
+
+    #[pyo3(signature = (text, folder = "default"))]
+    fn archival_insert(&self, text: &str, folder: &str) -> PyResult<()> {
+        self.lock()?.add_archival(folder, text);
+        Ok(())
+    }
+
+    /// Up to `top_k` archival entries whose text contains `query`
+    #[pyo3(signature = (query, top_k = 5))]
+    fn archival_search(&self, py: Python<'_>, query: &str, top_k: usize) -> PyResult<PyObject> {
+        let results = self.lock()?.search_archival(query, top_k);
+        to_py(py, &Value::Array(results))
+    }
+
+    /// The agent as agent file JSON, for `import_af` or another letta-lite app
+    fn export_af(&self) -> PyResult<String> {
+        let agent = self.lock()?;
+        let af = AgentFile::export(&agent.config, &agent.state, Vec::new()).map_err(py_err)?;
+        AgentFile::to_json(&af).map_err(py_err)
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        let agent = self.lock()?;
+        Ok(format!("Agent(id={:?}, name={:?})", agent.state.id, agent.config.name))
+    }
+}
+
+/// A SQLite database of saved agents
+#[pyclass(name = "Storage", module = "letta_lite")]
+pub struct PyStorage {
+    storage: Storage,
+}
+
+#[pymethods]
+impl PyStorage {
+    /// Open the database at `path`, creating it if needed
+    #[staticmethod]
+    fn open(py: Python<'_>, path: PathBuf) -> PyResult<Self> {
+        let config = StorageConfig { path, ..Default::default() };
+        let storage = py.allow_threads(|| Storage::new(config)).map_err(py_err)?;
+        Ok(Self { storage })
+    }
+
+    /// Saved agents as `{"id", "name", "created_at", "updated_at"}` dicts
+    fn list_agents(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let agents: Vec<Value> = self.storage.list_agents().map_err(py_err)?
+            .into_iter()
+            .map(|stored| json!({
+                "id": stored.id,
+                "name": stored.name,
+                "created_at": stored.created_at,
+                "updated_at": stored.updated_at,
+            }))
+            .collect();
+        to_py(py, &Value::Array(agents))
+    }
+
+    /// Persist the agent's config, memory blocks and history
+    fn save(&self, py: Python<'_>, agent: PyRef<'_, PyAgent>) -> PyResult<()> {
+        let agent = agent.lock()?;
+        let agent: &Agent = &agent;
+        py.allow_threads(|| agent.save(&self.storage)).map_err(py_err)
+    }
+
+    /// Load a saved agent. Provider settings are not stored, so pass
+    /// `provider` as for the `Agent` constructor to use a non-toy provider.
+    /// Raises with code `"agent.not_found"` if no such agent was saved.
+    #[pyo3(signature = (agent_id, provider = None))]
+    fn load(&self, py: Python<'_>, agent_id: &str, provider: Option<&PyDict>) -> PyResult<PyAgent> {
+        let (config, state) = py.allow_threads(|| letta_core::persist::load_agent(&self.storage, agent_id))
+            .map_err(py_err)?;
+        let provider = create_provider(py, &config, provider_arg(py, provider)?)?;
+        Ok(PyAgent::wrap(Agent::new(config, provider).with_state(state)))
+    }
+}
+
+#[pymodule]
+fn letta_lite(py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyAgent>()?;
+    m.add_class::<PyStorage>()?;
+    m.add("LettaError", py.get_type::<LettaError>())?;
+    m.add("__version__", letta_core::VERSION)?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+import json
+
+import pytest
+
+import letta_lite
+
+
+def toy_agent(**config):
+    return letta_lite.Agent({"model": "toy", **config})
+
+
+def test_conversation():
+    agent = toy_agent(name="py")
+    assert agent.name == "py"
+
+    result = agent.step("Hello!")
+    assert result["text"]
+    assert result["usage"]["total_tokens"] > 0
+
+    message_id = agent.send_only("Are you there?")
+    assert message_id
+    assert agent.reply_only()["text"]
+
+
+def test_step_stream():
+    agent = toy_agent()
+    events = []
+    result = agent.step("Hello!", on_event=events.append)
+
+    deltas = [event["text"] for event in events if event["type"] == "text_delta"]
+    assert len(deltas) > 1
+    assert "".join(deltas) == result["text"]
+
+
+def test_stream_callback_errors_propagate():
+    def fail(event):
+        raise ValueError("stop")
+
+    with pytest.raises(ValueError, match="stop"):
+        toy_agent().step("Hello!", on_event=fail)
+
+
+def test_memory_and_archival():
+    agent = toy_agent()
+    agent.set_block("human", "Name: Ada")
+    assert agent.get_block("human") == "Name: Ada"
+    assert agent.get_block("missing") is None
+
+    agent.archival_insert("The launch is on Friday", folder="notes")
+    agent.archival_insert("Lunch is at noon")
+    results = agent.archival_search("launch", top_k=1)
+    assert len(results) == 1
+    assert "launch" in json.dumps(results[0])
+
+
+def test_af_round_trip():
+    agent = toy_agent(name="travel")
+    agent.set_block("human", "Likes trains")
+    agent.step("Plan a trip")
+
+    af_json = agent.export_af()
+    copy = letta_lite.Agent.import_af(af_json)
+    assert copy.id == agent.id
+    assert copy.name == "travel"
+    assert copy.get_block("human") == "Likes trains"
+    assert json.loads(copy.export_af())["agents"][0]["id"] == agent.id
+
+
+def test_storage(tmp_path):
+    storage = letta_lite.Storage.open(tmp_path / "agents.db")
+    agent = toy_agent(name="saved")
+    agent.step("Remember me")
+    storage.save(agent)
+
+    assert [a["name"] for a in storage.list_agents()] == ["saved"]
+    loaded = storage.load(agent.id)
+    assert loaded.name == "saved"
+    assert loaded.get_block("persona") == agent.get_block("persona")
+
+    with pytest.raises(letta_lite.LettaError) as error:
+        storage.load("no-such-agent")
+    assert error.value.code == "agent.not_found"
+
+
+def test_errors_carry_codes():
+    with pytest.raises(letta_lite.LettaError) as error:
+        letta_lite.Agent({"temperature": 5.0})
+    assert error.value.code == "config.invalid"
+    assert not error.value.retryable
+
+    with pytest.raises(letta_lite.LettaError) as error:
+        toy_agent().set_block("persona", "x" * 10_000)
+    assert error.value.code == "memory.limit_exceeded"
+
+    with pytest.raises(letta_lite.LettaError) as error:
+        letta_lite.Agent({"provider": {"type": "nope"}})
+    assert error.value.code == "config.invalid"
+
+    with pytest.raises(letta_lite.LettaError) as error:
+        letta_lite.Agent({"model": "mistral-large"})
+    assert error.value.code == "config.invalid"
@@ -214,11 +214,13 @@ public struct TokenUsage: Codable {
     public let promptTokens: Int
     public let completionTokens: Int
     public let totalTokens: Int
+    public let costUsd: Double?
     
     enum CodingKeys: String, CodingKey {
         case promptTokens = "prompt_tokens"
         case completionTokens = "completion_tokens"
         case totalTokens = "total_tokens"
+        case costUsd = "cost_usd"
     }
 }
 
@@ -0,0 +1,3 @@
+fn main() {
+    uniffi::generate_scaffolding("src/letta.udl").expect("UDL should be valid");
+}
@@ -0,0 +1,526 @@
+//! Swift and Kotlin bindings generated by UniFFI from `letta.udl`. The
+//! objects wrap the same core as letta-ffi, but are reference counted by the
+//! host language instead of living in a handle registry. Generate the sources
+//! with:
+//!
+//! ```sh
+//! cargo build --release
+//! cargo run --features cli --bin uniffi-bindgen -- generate \
+//!     --library target/release/libletta_uniffi.so --language swift --out-dir out
+//! ```
+
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::SystemTime;
+use lazy_static::lazy_static;
+
+use letta_core::{
+    Agent as CoreAgent, AgentConfig, LettaError as CoreError, LlmProvider, ProviderErrorKind, StepEvent,
+    af::AgentFile,
+    provider::{ProviderConfig, ProviderEnv, ProviderFactory},
+};
+use letta_storage::{Storage as CoreStorage, StorageConfig};
+use letta_sync::{SyncClient, SyncConfig, SyncError, SyncManager};
+
+uniffi::include_scaffolding!("letta");
+
+lazy_static! {
+    // Shared by every object, as in letta-ffi
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Runtime::new().unwrap();
+}
+
+type Result<T> = std::result::Result<T, LettaError>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LettaError {
+    #[error("Storage error: {message}")]
+    Storage { message: String },
+    #[error("Serialization error: {message}")]
+    Serialization { message: String },
+    /// `kind` is a `ProviderErrorKind` name, such as `"rate_limit"`
+    #[error("Provider error: {message}")]
+    Provider { message: String, kind: String, status: Option<u16>, retryable: bool },
+    #[error("Tool execution error: {message}")]
+    ToolExecution { message: String },
+    #[error("Memory error: {message}")]
+    Memory { message: String },
+    #[error("Memory error: Value exceeds limit: {len} > {limit}")]
+    MemoryLimitExceeded { label: String, len: u64, limit: u64 },
+    #[error("Context overflow: current {current}, max {max}")]
+    ContextOverflow { current: u64, max: u64 },
+    #[error("Agent not found: {agent_id}")]
+    AgentNotFound { agent_id: String },
+    #[error("Invalid configuration: {message}")]
+    InvalidConfig { message: String },
+    #[error("Sync error: {message}")]
+    Sync { message: String },
+    #[error("Agent file is encrypted; a passphrase is needed to open it")]
+    PassphraseRequired,
+    #[error("Wrong passphrase for encrypted agent file")]
+    WrongPassphrase,
+    #[error("Encrypted agent file is damaged or has been tampered with")]
+    Tampered,
+    #[error("Timed out after {elapsed_ms} ms")]
+    Timeout { elapsed_ms: u64 },
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("IO error: {message}")]
+    Io { message: String },
+    #[error("Busy: {message}")]
+    Busy { message: String },
+    #[error("Unknown error: {message}")]
+    Unknown { message: String },
+}
+
+impl LettaError {
+    /// The `LettaError::code` this variant was mapped from; `"agent.busy"`
+    /// for `Busy`, which has no core equivalent
+    pub fn code(&self) -> &'static str {
+        match self {
+            LettaError::Storage { .. } => "storage.failed",
+            LettaError::Serialization { .. } => "serialization.invalid",
+            LettaError::Provider { kind, .. } => {
+                ProviderErrorKind::from_name(kind).map_or("provider.failed", |kind| kind.code())
+            }
+            LettaError::ToolExecution { .. } => "tool.failed",
+            LettaError::Memory { .. } => "memory.failed",
+            LettaError::MemoryLimitExceeded { .. } => "memory.limit_exceeded",
+            LettaError::ContextOverflow { .. } => "context.overflow",
+            LettaError::AgentNotFound { .. } => "agent.not_found",
+            LettaError::InvalidConfig { .. } => "config.invalid",
+            LettaError::Sync { .. } => "sync.failed",
+            LettaError::PassphraseRequired => "af.passphrase_required",
+            LettaError::WrongPassphrase => "af.wrong_passphrase",
+            LettaError::Tampered => "af.tampered",
+            LettaError::Timeout { .. } => "timeout",
+            LettaError::Cancelled => "cancelled",
+            LettaError::Io { .. } => "io.failed",
+            LettaError::Busy { .. } => "agent.busy",
+            LettaError::Unknown { .. } => "unknown",
+        }
+    }
+}
+
+impl From<CoreError> for LettaError {
+    fn from(error: CoreError) -> Self {
+        match error {
+            CoreError::Storage(e) => LettaError::Storage { message: e.to_string() },
+            CoreError::Serialization(e) => LettaError::Serialization { message: e.to_string() },
+            CoreError::Provider(e) => LettaError::Provider {
+                message: e.message,
+                kind: e.kind.as_str().to_string(),
+                status: e.status,
+                retryable: e.retryable,
+            },
+            CoreError::ToolExecution(message) => LettaError::ToolExecution { message },
+            CoreError::Memory(message) => LettaError::Memory { message },
+            CoreError::MemoryLimitExceeded { label, len, limit } => {
+                LettaError::MemoryLimitExceeded { label, len: len as u64, limit: limit as u64 }
+            }
+            CoreError::ContextOverflow { current, max } => {
+                LettaError::ContextOverflow { current: current as u64, max: max as u64 }
+            }
+            CoreError::AgentNotFound(agent_id) => LettaError::AgentNotFound { agent_id },
+            CoreError::InvalidConfig(message) => LettaError::InvalidConfig { message },
+            CoreError::Sync(message) => LettaError::Sync { message },
+            CoreError::PassphraseRequired => LettaError::PassphraseRequired,
+            CoreError::WrongPassphrase => LettaError::WrongPassphrase,
+            CoreError::Tampered => LettaError::Tampered,
+            CoreError::Timeout { elapsed_ms } => LettaError::Timeout { elapsed_ms },
+            CoreError::Cancelled => LettaError::Cancelled,
+            CoreError::Io(e) => LettaError::Io { message: e.to_string() },
+            CoreError::Unknown(message) => LettaError::Unknown { message },
+        }
+    }
+}
+
+impl From<letta_storage::StorageError> for LettaError {
+    fn from(error: letta_storage::StorageError) -> Self {
+        CoreError::from(error).into()
+    }
+}
+
+impl From<serde_json::Error> for LettaError {
+    fn from(error: serde_json::Error) -> Self {
+        CoreError::from(error).into()
+    }
+}
+
+impl From<SyncError> for LettaError {
+    fn from(error: SyncError) -> Self {
+        match error {
+            SyncError::Core(e) => e.into(),
+            SyncError::Storage(e) => e.into(),
+            SyncError::AgentNotFound(agent_id) => LettaError::AgentNotFound { agent_id },
+            e => LettaError::Sync { message: e.to_string() },
+        }
+    }
+}
+
+pub fn version() -> String {
+    letta_core::VERSION.to_string()
+}
+
+pub struct AgentOptions {
+    pub name: Option<String>,
+    pub system_prompt: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_messages: Option<u32>,
+    pub tools_enabled: Option<bool>,
+    pub provider_json: Option<String>,
+}
+
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: Option<f64>,
+}
+
+pub struct StepResult {
+    pub text: String,
+    pub tool_trace_json: String,
+    pub usage: TokenUsage,
+}
+
+impl From<letta_core::agent::StepResult> for StepResult {
+    fn from(result: letta_core::agent::StepResult) -> Self {
+        Self {
+            text: result.text,
+            tool_trace_json: serde_json::json!(result.tool_trace).to_string(),
+            usage: TokenUsage {
+                prompt_tokens: result.usage.prompt_tokens as u64,
+                completion_tokens: result.usage.completion_tokens as u64,
+                total_tokens: result.usage.total_tokens as u64,
+                cost_usd: result.usage.cost_usd,
+            },
+        }
+    }
+}
+
+pub struct ArchivalEntry {
+    pub folder: String,
+    pub text: String,
+}
+
+pub struct AgentSummary {
+    pub id: String,
+    pub name: String,
+    pub updated_at: SystemTime,
+}
+
+pub struct SyncOutcome {
+    pub status: String,
+    pub cloud_version: i64,
+    pub conflict_count: u32,
+}
+
+/// Receives a streaming step's output. Called on a runtime thread, not the
+/// thread that called `converse_stream`.
+pub trait StepListener: Send + Sync {
+    fn on_text_delta(&self, text: String);
+    fn on_tool_call(&self, tool: String, args_json: String, result_json: String);
+}
+
+/// Build the provider from a provider object in `ProviderConfig`'s format,
+/// or else from the model name with API keys from the environment. Unknown
+/// models fail with `InvalidConfig`.
+fn create_provider(config: &AgentConfig, provider_json: Option<&str>) -> Result<Box<dyn LlmProvider>> {
+    let provider = match provider_json {
+        Some(provider_json) => serde_json::from_str(provider_json).map_err(|e| LettaError::InvalidConfig {
+            message: format!("Invalid provider config: {}", e),
+        })?,
+        None => ProviderConfig::from_model(&config.model, &ProviderEnv::from_env())?,
+    };
+    Ok(RUNTIME.block_on(ProviderFactory::create(provider))?)
+}
+
+pub struct Agent {
+    inner: Mutex<CoreAgent>,
+}
+
+impl Agent {
+    fn wrap(agent: CoreAgent) -> Self {
+        Self { inner: Mutex::new(agent) }
+    }
+
+    /// The agent, or `Busy` while another call holds it, e.g. when a listener
+    /// calls back into the agent that is stepping
+    fn lock(&self) -> Result<MutexGuard<'_, CoreAgent>> {
+        match self.inner.try_lock() {
+            Ok(agent) => Ok(agent),
+            Err(TryLockError::Poisoned(poisoned)) => Ok(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => Err(LettaError::Busy { message: "Agent is busy with another call".into() }),
+        }
+    }
+
+    pub fn new(options: AgentOptions) -> Result<Self> {
+        let defaults = AgentConfig::default();
+        let config = AgentConfig {
+            name: options.name.unwrap_or(defaults.name),
+            system_prompt: options.system_prompt.unwrap_or(defaults.system_prompt),
+            model: options.model.unwrap_or(defaults.model),
+            temperature: options.temperature.unwrap_or(defaults.temperature),
+            max_messages: options.max_messages.map_or(defaults.max_messages, |n| n as usize),
+            tools_enabled: options.tools_enabled.unwrap_or(defaults.tools_enabled),
+            ..defaults
+        };
+        config.validate()?;
+        let provider = create_provider(&config, options.provider_json.as_deref())?;
+        Ok(Self::wrap(CoreAgent::new(config, provider)))
+    }
+
+    /// Create an agent from an agent file holding exactly one agent
+    pub fn from_af(af_json: String, provider_json: Option<String>) -> Result<Self> {
+        let af = AgentFile::from_json(&af_json)?;
+        if af.agents.len() > 1 {
+            return Err(LettaError::InvalidConfig {
+                message: format!("Agent file holds {} agents; import them one at a time", af.agents.len()),
+            });
+        }
+        let (config, state) = AgentFile::import(&af)?;
+        config.validate()?;
+        let provider = create_provider(&config, provider_json.as_deref())?;
+        Ok(Self::wrap(CoreAgent::new(config, provider).with_state(state)))
+    }
+
+    pub fn id(&self) -> Result<String> {
+        Ok(self.lock()?.state.id.clone())
+    }
+
+    pub fn name(&self) -> Result<String> {
+        Ok(self.lock()?.config.name.clone())
+    }
+
+    pub fn converse(&self, message: String) -> Result<StepResult> {
+        let mut agent = self.lock()?;
+        Ok(RUNTIME.block_on(agent.step(message))?.into())
+    }
+
+    pub fn converse_stream(&self, message: String, listener: Box<dyn StepListener>) -> Result<StepResult> {
+        let mut agent = self.lock()?;
+        let result = RUNTIME.block_on(agent.step_stream(message, |event| match event {
+            StepEvent::TextDelta { text } => listener.on_text_delta(text),
+            StepEvent::ToolCall { tool, args, result } => {
+                listener.on_tool_call(tool, args.to_string(), result.to_string())
+            }
+        }))?;
+        Ok(result.into())
+    }
+
+    /// Record a user message without replying; returns its id
+    pub fn send_only(&self, message: String) -> Result<String> {
+        Ok(self.lock()?.send_only(message))
+    }
+
+    pub fn reply_only(&self) -> Result<StepResult> {
+        let mut agent = self.lock()?;
+        Ok(RUNTIME.block_on(agent.reply_only())?.into())
+    }
+
+    pub fn get_block(&self, label: String) -> Result<Option<String>> {
+        Ok(self.lock()?.get_memory_block(&label))
+    }
+
+    pub fn set_block(&self, label: String, value: String) -> Result<()> {
+        Ok(self.lock()?.set_memory_block(&label, &value)?)
+    }
+
+    pub fn archival_insert(&self, folder: String, text: String) -> Result<()> {
+        self.lock()?.add_archival(&folder, &text);
+        Ok(())
+    }
+
+    pub fn archival_search(&self, query: String, top_k: u32) -> Result<Vec<ArchivalEntry>> {
+        let field = |entry: &serde_json::Value, key: &str| {
+            entry.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string()
+        };
+        Ok(self.lock()?.search_archival(&query, top_k as usize)
+            .iter()
+            .map(|entry| ArchivalEntry { folder: field(entry, "folder"), text: field(entry, "text") })
+            .collect())
+    }
+
+    pub fn export_af(&self) -> Result<String> {
+        let agent = self.lock()?;
+        let af = AgentFile::export(&agent.config, &agent.state, Vec::new())?;
+        Ok(AgentFile::to_json(&af)?)
+    }
+}
+
+pub struct Storage {
+    inner: CoreStorage,
+}
+
+impl Storage {
+    /// Open the database at `path`, creating it if needed
+    pub fn new(path: String) -> Result<Self> {
+        let config = StorageConfig { path: path.into(), ..Default::default() };
+        Ok(Self { inner: CoreStorage::new(config)? })
+    }
+
+    pub fn list_agents(&self) -> Result<Vec<AgentSummary>> {
+        Ok(self.inner.list_agents()?
+            .into_iter()
+            .map(|stored| AgentSummary { id: stored.id, name: stored.name, updated_at: stored.updated_at.into() })
+            .collect())
+    }
+
+    pub fn save_agent(&self, agent: Arc<Agent>) -> Result<()> {
+        Ok(agent.lock()?.save(&self.inner)?)
+    }
+
+    /// Provider settings are not stored, so pass `provider_json` to use a
+    /// non-toy provider
+    pub fn load_agent(&self, agent_id: String, provider_json: Option<String>) -> Result<Arc<Agent>> {
+        let (config, state) = letta_core::persist::load_agent(&self.inner, &agent_id)?;
+        let provider = create_provider(&config, provider_json.as_deref())?;
+        Ok(Arc::new(Agent::wrap(CoreAgent::new(config, provider).with_state(state))))
+    }
+}
+
+/// A sync client bound to one storage; `config_json` is a `SyncConfig`, as
+/// for `letta_configure_sync`
+pub struct SyncSession {
+    client: Arc<SyncClient>,
+    storage: CoreStorage,
+}
+
+impl SyncSession {
+    pub fn new(config_json: String, storage: Arc<Storage>) -> Result<Self> {
+        let config: SyncConfig = serde_json::from_str(&config_json)?;
+        let client = SyncClient::with_storage(config, &storage.inner)?;
+        Ok(Self { client: Arc::new(client), storage: storage.inner.clone() })
+    }
+
+    pub fn device_id(&self) -> String {
+        self.client.device_id().to_string()
+    }
+
+    /// Save the agent, sync it, and reload it with whatever the server sent
+    /// back, as `letta_sync_with_cloud` does
+    pub fn sync_agent(&self, agent: Arc<Agent>) -> Result<SyncOutcome> {
+        let mut agent = agent.lock()?;
+        agent.save(&self.storage)?;
+
+        let manager = SyncManager::new(self.client.clone(), self.storage.clone());
+        let response = RUNTIME.block_on(manager.sync_and_apply(&agent.state.id))?;
+
+        // The provider stays as is, so keep the model it was built for
+        let (mut config, state) = letta_core::persist::load_agent(&self.storage, &agent.state.id)?;
+        config.model = agent.config.model.clone();
+        agent.update_config(config)?;
+        agent.state = state;
+
+        Ok(SyncOutcome {
+            status: response.status,
+            cloud_version: response.cloud_version,
+            conflict_count: response.conflicts.len() as u32,
+        })
+    }
+
+    /// Download every agent the server has; returns how many were pulled
+    pub fn pull_all(&self) -> Result<u32> {
+        let manager = SyncManager::new(self.client.clone(), self.storage.clone());
+        Ok(RUNTIME.block_on(manager.pull_all())? as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn toy_options() -> AgentOptions {
+        AgentOptions {
+            name: Some("kit".into()),
+            system_prompt: None,
+            model: Some("toy".into()),
+            temperature: None,
+            max_messages: None,
+            tools_enabled: None,
+            provider_json: None,
+        }
+    }
+
+    /// Counts calls and records the streamed text
+    #[derive(Default)]
+    struct RecordingListener {
+        deltas: AtomicUsize,
+        text: Mutex<String>,
+    }
+
+    impl StepListener for Arc<RecordingListener> {
+        fn on_text_delta(&self, text: String) {
+            self.deltas.fetch_add(1, Ordering::SeqCst);
+            self.text.lock().unwrap().push_str(&text);
+        }
+
+        fn on_tool_call(&self, _tool: String, _args_json: String, _result_json: String) {}
+    }
+
+    #[test]
+    fn test_converse_and_stream() {
+        let agent = Agent::new(toy_options()).unwrap();
+        assert_eq!(agent.name().unwrap(), "kit");
+        assert!(!agent.converse("Hello!".into()).unwrap().text.is_empty());
+
+        let listener = Arc::new(RecordingListener::default());
+        let result = agent.converse_stream("Hello again".into(), Box::new(listener.clone())).unwrap();
+        assert!(listener.deltas.load(Ordering::SeqCst) > 1);
+        assert_eq!(*listener.text.lock().unwrap(), result.text);
+        assert_eq!(result.tool_trace_json, "[]");
+    }
+
+    #[test]
+    fn test_af_and_storage_round_trip() {
+        let agent = Arc::new(Agent::new(toy_options()).unwrap());
+        agent.set_block("human".into(), "Likes trains".into()).unwrap();
+        agent.archival_insert("notes".into(), "Launch on Friday".into()).unwrap();
+
+        let copy = Agent::from_af(agent.export_af().unwrap(), None).unwrap();
+        assert_eq!(copy.id().unwrap(), agent.id().unwrap());
+        assert_eq!(copy.get_block("human".into()).unwrap().as_deref(), Some("Likes trains"));
+        let found = copy.archival_search("launch".into(), 5).unwrap();
+        assert_eq!((found[0].folder.as_str(), found.len()), ("notes", 1));
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(dir.path().join("agents.db").display().to_string()).unwrap();
+        storage.save_agent(agent.clone()).unwrap();
+        assert_eq!(storage.list_agents().unwrap()[0].id, agent.id().unwrap());
+        let loaded = storage.load_agent(agent.id().unwrap(), None).unwrap();
+        assert_eq!(loaded.get_block("human".into()).unwrap(), agent.get_block("human".into()).unwrap());
+    }
+
+    #[test]
+    fn test_errors_keep_core_codes() {
+        let core_errors = vec![
+            CoreError::Provider("down".into()),
+            CoreError::MemoryLimitExceeded { label: "human".into(), len: 10, limit: 5 },
+            CoreError::ContextOverflow { current: 10, max: 5 },
+            CoreError::AgentNotFound("agent-1".into()),
+            CoreError::InvalidConfig("bad".into()),
+            CoreError::Timeout { elapsed_ms: 30_000 },
+            CoreError::Cancelled,
+            CoreError::Unknown("?".into()),
+        ];
+        for core_error in core_errors {
+            let code = core_error.code();
+            let message = core_error.to_string();
+            let error = LettaError::from(core_error);
+            assert_eq!(error.code(), code);
+            assert_eq!(error.to_string(), message);
+        }
+        let error = LettaError::from(CoreError::from(letta_core::ProviderError::from_status(429, "slow down")));
+        assert!(matches!(error, LettaError::Provider { kind, status: Some(429), retryable: true, .. } if kind == "rate_limit"));
+        assert_eq!(error.code(), "provider.rate_limited");
+
+        let agent = Agent::new(toy_options()).unwrap();
+        let error = agent.set_block("persona".into(), "x".repeat(10_000)).unwrap_err();
+        assert!(matches!(error, LettaError::MemoryLimitExceeded { limit: 2000, .. }));
+        let options = AgentOptions { temperature: Some(5.0), ..toy_options() };
+        assert!(matches!(Agent::new(options), Err(LettaError::InvalidConfig { .. })));
+        let options = AgentOptions { model: Some("mistral-large".into()), ..toy_options() };
+        assert!(matches!(Agent::new(options), Err(LettaError::InvalidConfig { .. })));
+    }
+}
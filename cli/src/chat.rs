@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+use letta_core::{
+    persist,
+    provider::{ProviderConfig, ProviderEnv, ProviderFactory},
+    AfCompression, Agent, AgentConfig, AgentFile, AppConfig, MessageRole,
+};
+
+const HELP: &str = "\
+Commands:
+  /memory         Show the agent's memory blocks
+  /export <path>  Write the agent to an agent file
+  /undo           Forget the last exchange of this session; memory edits stay
+  /help           Show this help
+  /quit           Leave, saving the agent when there is a database";
+
+/// Whether the REPL keeps reading after a slash command
+enum Flow {
+    Continue,
+    Quit,
+}
+
+pub async fn run(args: &ArgMatches, app_config: &AppConfig) -> Result<()> {
+    let storage = crate::storage_arg(args, app_config)?;
+    let provider_config = match (args.value_of("provider"), args.value_of("model")) {
+        (Some(json), _) => serde_json::from_str(json).context("parsing --provider")?,
+        (None, Some(model)) => ProviderConfig::from_model(model, &ProviderEnv::from_env())?,
+        (None, None) => app_config.provider_config(),
+    };
+    let provider = ProviderFactory::create(provider_config).await?;
+
+    let mut agent = match (args.value_of("agent"), &storage) {
+        (Some(id), Some(storage)) => {
+            let (config, state) = persist::load_agent(storage, id)
+                .with_context(|| format!("loading agent {}", id))?;
+            Agent::new(config, provider).with_state(state)
+        }
+        (Some(_), None) => bail!("--agent needs a database; pass --db or add [storage] to the config"),
+        (None, _) => {
+            let defaults = app_config.agent_config();
+            let name = args.value_of("name").map_or(defaults.name.clone(), str::to_string);
+            let model = args.value_of("model").map_or(defaults.model.clone(), str::to_string);
+            Agent::new(AgentConfig { name, model, ..defaults }, provider)
+        }
+    };
+
+    repl(&mut agent, std::io::stdin().lock(), std::io::stdout().lock()).await?;
+    if let Some(storage) = &storage {
+        agent.save(storage)?;
+        println!("Saved agent {}", agent.state.id);
+    }
+    Ok(())
+}
+
+/// Read messages and slash commands from `input` until it ends or `/quit`
+async fn repl(agent: &mut Agent, input: impl BufRead, mut out: impl Write) -> Result<()> {
+    writeln!(out, "Chatting with {} ({}). Type /help for commands.", agent.config.name, agent.state.id)?;
+    // Saved messages stay in storage, so only this session's can be undone
+    let earlier: HashSet<String> = agent.state.messages.messages.iter().map(|m| m.id.clone()).collect();
+
+    let mut lines = input.lines();
+    loop {
+        write!(out, "you> ")?;
+        out.flush()?;
+        let Some(line) = lines.next() else {
+            writeln!(out)?;
+            return Ok(());
+        };
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = line.strip_prefix('/') {
+            match slash_command(agent, command, &earlier, &mut out)? {
+                Flow::Continue => continue,
+                Flow::Quit => return Ok(()),
+            }
+        }
+
+        match agent.step(line.to_string()).await {
+            Ok(result) => {
+                for call in &result.tool_trace {
+                    writeln!(out, "  [tool] {}({}) -> {}", call.tool, call.arguments, call.result)?;
+                }
+                writeln!(out, "{}> {}", agent.config.name, result.text)?;
+            }
+            Err(e) => writeln!(out, "error: {}", e)?,
+        }
+    }
+}
+
+fn slash_command(agent: &mut Agent, command: &str, earlier: &HashSet<String>, out: &mut impl Write) -> Result<Flow> {
+    let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+    match name {
+        "help" => writeln!(out, "{}", HELP)?,
+        "quit" | "exit" => return Ok(Flow::Quit),
+        "memory" => {
+            let mut blocks: Vec<_> = agent.state.memory.blocks().values().collect();
+            blocks.sort_by(|a, b| a.label.cmp(&b.label));
+            for block in blocks {
+                writeln!(out, "[{}] ({}/{} chars)\n{}", block.label, block.value.chars().count(), block.limit, block.value)?;
+            }
+        }
+        "export" => {
+            let path = argument.trim();
+            if path.is_empty() {
+                writeln!(out, "usage: /export <path>")?;
+                return Ok(Flow::Continue);
+            }
+            let af = AgentFile::export(&agent.config, &agent.state, Vec::new())?;
+            let file = std::fs::File::create(path).with_context(|| format!("creating {}", path))?;
+            AgentFile::to_writer(&af, file, AfCompression::None)?;
+            writeln!(out, "Exported to {}", path)?;
+        }
+        "undo" => {
+            let messages = &mut agent.state.messages.messages;
+            match messages.iter().rposition(|m| m.role == MessageRole::User) {
+                Some(index) if !earlier.contains(&messages[index].id) => {
+                    let removed = messages.len() - index;
+                    messages.truncate(index);
+                    writeln!(out, "Removed {} messages", removed)?;
+                }
+                _ => writeln!(out, "Nothing to undo")?,
+            }
+        }
+        _ => writeln!(out, "Unknown command /{}; type /help for commands", name)?,
+    }
+    Ok(Flow::Continue)
+}
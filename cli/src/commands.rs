@@ -0,0 +1,119 @@
+use std::path::Path;
+use anyhow::{bail, Context, Result};
+use letta_core::{af::AgentFileV1, AgentFile, ImportWarning};
+use letta_storage::{Storage, StoredChunk};
+
+pub fn list_agents(storage: &Storage) -> Result<()> {
+    let agents = storage.list_agents()?;
+    if agents.is_empty() {
+        println!("No agents");
+    }
+    for agent in agents {
+        let messages = storage.count_messages(&agent.id)?;
+        println!("{}  {}  {} messages  updated {}", agent.id, agent.name, messages, agent.updated_at.to_rfc3339());
+    }
+    Ok(())
+}
+
+pub fn delete_agent(storage: &Storage, id: &str) -> Result<()> {
+    if !storage.delete_agent(id)? {
+        bail!("no agent {}", id);
+    }
+    println!("Deleted agent {}", id);
+    Ok(())
+}
+
+/// Encrypted files are opened with `LETTA_AF_PASSPHRASE`, or a passphrase
+/// asked for on the terminal
+fn read_af(path: &Path) -> Result<AgentFileV1> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    if !AgentFile::is_encrypted(&bytes) {
+        return AgentFile::from_reader(bytes.as_slice()).with_context(|| format!("reading {}", path.display()));
+    }
+    let passphrase = match std::env::var("LETTA_AF_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            eprint!("Passphrase for {}: ", path.display());
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    AgentFile::import_encrypted(&bytes, &passphrase).with_context(|| format!("decrypting {}", path.display()))
+}
+
+pub fn validate_af(path: &Path) -> Result<()> {
+    let af = read_af(path)?;
+    AgentFile::import(&af).with_context(|| format!("importing {}", path.display()))?;
+    for warning in AgentFile::import_warnings(&af) {
+        let text = match warning {
+            ImportWarning::UnknownTool { name } => format!("tool {} is not built in and will be dropped", name),
+            ImportWarning::SkippedSection { section } => format!("section {} is not supported and will be skipped", section),
+            ImportWarning::MissingBlock { id } => format!("memory block {} is referenced but missing", id),
+        };
+        println!("warning: {}", text);
+    }
+    println!("{} is valid: {} agent(s)", path.display(), af.agents.len());
+    Ok(())
+}
+
+pub fn inspect_af(path: &Path) -> Result<()> {
+    let af = read_af(path)?;
+    println!("version {}, exported {} by {}", af.version, af.metadata.export_time.to_rfc3339(), af.metadata.export_source);
+    for agent in &af.agents {
+        println!("agent {} ({})", agent.name, agent.id);
+        println!("  model: {} ({} token context)", agent.model.model_endpoint, agent.model.context_window);
+        println!("  messages: {}", agent.messages.len());
+        println!("  passages: {}", agent.passages.len());
+        println!("  tools: {}", agent.agent_state.tools.join(", "));
+        for id in &agent.agent_state.memory.blocks {
+            match af.blocks.iter().find(|b| &b.id == id) {
+                Some(block) => println!("  block {}: {}/{} chars", block.label, block.value.chars().count(), block.limit),
+                None => println!("  block {}: missing", id),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn diff_af(left: &Path, right: &Path) -> Result<()> {
+    let diff = AgentFile::diff(&read_af(left)?, &read_af(right)?)?;
+    if diff.is_empty() {
+        println!("No differences");
+        return Ok(());
+    }
+    if diff.system_prompt_changed {
+        println!("system prompt differs");
+    }
+    for block in &diff.changed_blocks {
+        let side = |value: &Option<String>| value.clone().unwrap_or_else(|| "<none>".to_string());
+        println!("block {}: {:?} -> {:?}", block.label, side(&block.left), side(&block.right));
+    }
+    for id in &diff.left_only_messages {
+        println!("- message {}", id);
+    }
+    for id in &diff.right_only_messages {
+        println!("+ message {}", id);
+    }
+    Ok(())
+}
+
+/// Store each blank-line separated paragraph of `path` as a chunk
+pub fn ingest(storage: &Storage, path: &Path, agent_id: &str, folder: &str) -> Result<()> {
+    if storage.get_agent(agent_id)?.is_none() {
+        bail!("no agent {}", agent_id);
+    }
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let chunks: Vec<StoredChunk> = paragraphs(&text)
+        .map(|paragraph| StoredChunk::new(agent_id, folder, paragraph))
+        .collect();
+    let written = storage.add_chunks(&chunks, None)?;
+    println!("Stored {} chunks in folder {}", written, folder);
+    Ok(())
+}
+
+fn paragraphs(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split("\n\n")
+        .map(|paragraph| paragraph.lines().map(str::trim).collect::<Vec<_>>().join(" "))
+        .filter(|paragraph| !paragraph.trim().is_empty())
+}
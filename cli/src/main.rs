@@ -0,0 +1,153 @@
+//! Command-line front end for letta-lite: chat with an agent, manage stored
+//! agents and inspect agent files. Built on the crates' public APIs only.
+
+use std::path::PathBuf;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use letta_core::AppConfig;
+use letta_storage::{Storage, StorageConfig};
+
+mod chat;
+mod commands;
+
+fn cli() -> Command<'static> {
+    let db = Arg::new("db")
+        .long("db")
+        .takes_value(true)
+        .value_name("PATH")
+        .help("Database file to load and save agents in; overrides the config's [storage]");
+
+    Command::new("letta-cli")
+        .about("Chat with and manage letta-lite agents")
+        .version(letta_core::VERSION)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(Arg::new("config").long("config").takes_value(true).value_name("PATH").global(true)
+            .help("TOML or JSON config file; letta.toml is used when present"))
+        .subcommand(
+            Command::new("chat")
+                .about("Talk to an agent; type /help for commands")
+                .arg(db.clone())
+                .arg(Arg::new("agent").long("agent").takes_value(true).value_name("ID")
+                    .help("Stored agent to continue; a new one is created otherwise"))
+                .arg(Arg::new("name").long("name").takes_value(true)
+                    .help("Name of a new agent [default: the config's, or assistant]"))
+                .arg(Arg::new("provider").long("provider").takes_value(true).value_name("JSON")
+                    .help(r#"Provider config, e.g. {"type": "toy", "deterministic": true}"#))
+                .arg(Arg::new("model").long("model").takes_value(true).conflicts_with("provider")
+                    .help("Model to use, e.g. gpt-4o-mini or claude-3-5-haiku-latest, with its API key from the environment")),
+        )
+        .subcommand(
+            Command::new("agents")
+                .about("Manage stored agents")
+                .subcommand_required(true)
+                .arg(db.clone())
+                .subcommand(Command::new("list").about("List stored agents"))
+                .subcommand(
+                    Command::new("delete")
+                        .about("Delete a stored agent and everything it owns")
+                        .arg(Arg::new("id").required(true)),
+                ),
+        )
+        .subcommand(
+            Command::new("af")
+                .about("Work with agent files")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("validate")
+                        .about("Check that an agent file can be imported")
+                        .arg(Arg::new("file").required(true)),
+                )
+                .subcommand(
+                    Command::new("inspect")
+                        .about("Summarise an agent file's agents, blocks and messages")
+                        .arg(Arg::new("file").required(true)),
+                )
+                .subcommand(
+                    Command::new("diff")
+                        .about("Compare the first agent of two agent files")
+                        .arg(Arg::new("left").required(true))
+                        .arg(Arg::new("right").required(true)),
+                ),
+        )
+        .subcommand(
+            Command::new("ingest")
+                .about("Store a text file's paragraphs as archival chunks of an agent")
+                .arg(db)
+                .arg(Arg::new("file").required(true))
+                .arg(Arg::new("agent").long("agent").takes_value(true).value_name("ID").required(true))
+                .arg(Arg::new("folder").long("folder").takes_value(true).default_value("documents")),
+        )
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(cli().get_matches()).await {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(matches: ArgMatches) -> Result<()> {
+    let config = load_config(&matches)?;
+    match matches.subcommand() {
+        Some(("chat", args)) => chat::run(args, &config).await,
+        Some(("agents", args)) => {
+            let storage = open_storage(args, &config)?;
+            match args.subcommand() {
+                Some(("list", _)) => commands::list_agents(&storage),
+                Some(("delete", delete)) => commands::delete_agent(&storage, value(delete, "id")),
+                _ => unreachable!("subcommand is required"),
+            }
+        }
+        Some(("af", args)) => match args.subcommand() {
+            Some(("validate", af)) => commands::validate_af(&path(af, "file")),
+            Some(("inspect", af)) => commands::inspect_af(&path(af, "file")),
+            Some(("diff", af)) => commands::diff_af(&path(af, "left"), &path(af, "right")),
+            _ => unreachable!("subcommand is required"),
+        },
+        Some(("ingest", args)) => commands::ingest(
+            &open_storage(args, &config)?,
+            &path(args, "file"),
+            value(args, "agent"),
+            value(args, "folder"),
+        ),
+        _ => unreachable!("subcommand is required"),
+    }
+}
+
+/// `--config`, else `letta.toml` in the working directory if there is one
+fn load_config(matches: &ArgMatches) -> Result<AppConfig> {
+    let path = match matches.value_of("config") {
+        Some(path) => Path::new(path),
+        None if Path::new("letta.toml").exists() => Path::new("letta.toml"),
+        None => return Ok(AppConfig::default()),
+    };
+    Ok(AppConfig::load(path)?)
+}
+
+/// The database named by `--db`, else the config's `[storage]`, if any
+fn storage_arg(args: &ArgMatches, config: &AppConfig) -> Result<Option<Storage>> {
+    let storage_config = match (args.value_of("db"), &config.storage) {
+        (Some(path), storage) => StorageConfig { path: path.into(), ..storage.clone().unwrap_or_default() },
+        (None, Some(storage)) => storage.clone(),
+        (None, None) => return Ok(None),
+    };
+    let path = storage_config.path.display().to_string();
+    Storage::new(storage_config)
+        .with_context(|| format!("opening database {}", path))
+        .map(Some)
+}
+
+fn open_storage(args: &ArgMatches, config: &AppConfig) -> Result<Storage> {
+    storage_arg(args, config)?.ok_or_else(|| anyhow!("no database; pass --db or add [storage] to the config"))
+}
+
+fn value<'a>(args: &'a ArgMatches, name: &str) -> &'a str {
+    args.value_of(name).expect("argument is required or defaulted")
+}
+
+fn path(args: &ArgMatches, name: &str) -> PathBuf {
+    PathBuf::from(value(args, name))
+}
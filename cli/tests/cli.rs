@@ -0,0 +1,112 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn letta(args: &[&str], stdin: &str, dir: &Path) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_letta-cli"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn test_chat_repl_with_toy_provider() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = letta(
+        &["chat", "--name", "tester"],
+        "Hello\n/memory\n/undo\n/undo\n/export agent.af\n/nonsense\n/quit\nnever sent\n",
+        dir.path(),
+    );
+    let out = stdout(&output);
+
+    assert!(out.contains("tester> I understand your request."));
+    assert!(out.contains("[persona]"));
+    assert!(out.contains("Removed 2 messages"));
+    assert!(out.contains("Nothing to undo"));
+    assert!(out.contains("Unknown command /nonsense"));
+    assert!(!out.contains("never sent"));
+
+    let inspected = stdout(&letta(&["af", "inspect", "agent.af"], "", dir.path()));
+    assert!(inspected.contains("agent tester"));
+    assert!(inspected.contains("messages: 0"));
+}
+
+#[test]
+fn test_chat_reports_iteration_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    // The toy provider keeps asking for the tool until the step gives up
+    let out = stdout(&letta(&["chat"], "#MEMORY_UPDATE\n/memory\n", dir.path()));
+    assert!(out.contains("> [Stopped after 10 iterations without a final reply]"));
+    assert_eq!(out.matches("[tool] memory_replace").count(), 10);
+    assert!(out.contains("Updated user information"));
+}
+
+#[test]
+fn test_stored_agents_and_agent_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = stdout(&letta(&["chat", "--db", "letta.db", "--name", "keeper"], "Hello\n/export before.af\n", dir.path()));
+    let id = out.lines().last().unwrap().strip_prefix("Saved agent ").unwrap().to_string();
+
+    let listed = stdout(&letta(&["agents", "--db", "letta.db", "list"], "", dir.path()));
+    assert!(listed.contains(&format!("{}  keeper  2 messages", id)));
+
+    // Continuing the stored agent picks up its history
+    stdout(&letta(&["chat", "--db", "letta.db", "--agent", &id], "Again\n/export after.af\n", dir.path()));
+    assert!(stdout(&letta(&["af", "validate", "after.af"], "", dir.path())).contains("valid: 1 agent(s)"));
+    let diff = stdout(&letta(&["af", "diff", "before.af", "after.af"], "", dir.path()));
+    assert_eq!(diff.lines().filter(|line| line.starts_with("+ message")).count(), 2);
+    assert_eq!(stdout(&letta(&["af", "diff", "after.af", "after.af"], "", dir.path())), "No differences\n");
+
+    std::fs::write(dir.path().join("notes.txt"), "First paragraph\nstill first.\n\nSecond paragraph.\n").unwrap();
+    let ingested = stdout(&letta(&["ingest", "--db", "letta.db", "notes.txt", "--agent", &id, "--folder", "notes"], "", dir.path()));
+    assert_eq!(ingested, "Stored 2 chunks in folder notes\n");
+
+    stdout(&letta(&["agents", "--db", "letta.db", "delete", &id], "", dir.path()));
+    assert_eq!(stdout(&letta(&["agents", "--db", "letta.db", "list"], "", dir.path())), "No agents\n");
+    let missing = letta(&["agents", "--db", "letta.db", "delete", &id], "", dir.path());
+    assert!(!missing.status.success());
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("no agent"));
+}
+
+#[test]
+fn test_config_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("letta.toml"), "[storage]\npath = \"config.db\"\n\n[agent]\nname = \"configured\"\n").unwrap();
+    let out = stdout(&letta(&["chat"], "Hello\n", dir.path()));
+    assert!(out.contains("configured> I understand your request."));
+    assert!(dir.path().join("config.db").exists());
+
+    // letta.toml is picked up from the working directory, so --db isn't needed
+    let listed = stdout(&letta(&["agents", "list"], "", dir.path()));
+    assert!(listed.contains("  configured  2 messages"));
+
+    std::fs::write(dir.path().join("bad.json"), r#"{"agent": {"temperature": "warm"}}"#).unwrap();
+    let output = letta(&["agents", "--config", "bad.json", "list"], "", dir.path());
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bad.json: `agent.temperature`: invalid type"), "{}", stderr);
+
+    let empty = tempfile::tempdir().unwrap();
+    let output = letta(&["agents", "list"], "", empty.path());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no database"));
+}
+
+#[test]
+fn test_invalid_agent_file() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("broken.af"), "{\"version\": \"0.1.0\"}").unwrap();
+    let output = letta(&["af", "validate", "broken.af"], "", dir.path());
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: reading broken.af"));
+}
@@ -1,14 +1,19 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use crate::{
     agent::{AgentConfig, AgentState},
-    memory::{Memory, MemoryBlock},
+    memory::MemoryBlock,
     message::Message,
-    tool::ToolSchema,
-    error::Result,
+    tool::{ToolExecutor, ToolSchema},
+    error::{LettaError, Result},
 };
 
+/// Leading bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 /// Agent File format version 0.1.0 - compatible with Letta
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AgentFileV1 {
@@ -37,6 +42,9 @@ pub struct AgentExport {
     pub agent_state: AgentStateExport,
     pub messages: Vec<Message>,
     pub model: ModelConfig,
+    /// Archival memory entries, each with at least `folder` and `text`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub passages: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -133,26 +141,118 @@ pub struct AgentFileMetadata {
     pub additional: Option<HashMap<String, serde_json::Value>>,
 }
 
+/// A memory block whose value differs between two agent files.
+/// `None` means the block only exists on the other side.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockDiff {
+    pub label: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// Differences between the first agent of two agent files
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AgentFileDiff {
+    pub changed_blocks: Vec<BlockDiff>,
+    /// IDs of messages present only in the left file
+    pub left_only_messages: Vec<String>,
+    /// IDs of messages present only in the right file
+    pub right_only_messages: Vec<String>,
+    pub system_prompt_changed: bool,
+}
+
+impl AgentFileDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed_blocks.is_empty()
+            && self.left_only_messages.is_empty()
+            && self.right_only_messages.is_empty()
+            && !self.system_prompt_changed
+    }
+}
+
+/// Encoding of an agent file written with `AgentFile::to_writer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AfCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// Content of an agent file that `AgentFile::import` leaves behind
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ImportWarning {
+    /// A tool the agent lists that isn't built in
+    UnknownTool { name: String },
+    /// A section (groups, files, ...) that letta-lite does not support
+    SkippedSection { section: String },
+    /// A memory block the agent references but the file doesn't contain
+    MissingBlock { id: String },
+}
+
 pub struct AgentFile;
 
+/// The bulky parts of an agent state, copied by `export` or moved out by
+/// `export_owned`
+struct ExportContent {
+    blocks: Vec<(String, MemoryBlock)>,
+    messages: Vec<Message>,
+    passages: Vec<serde_json::Value>,
+    metadata: serde_json::Value,
+}
+
 impl AgentFile {
     /// Export an agent to AF format
     pub fn export(
         config: &AgentConfig,
         state: &AgentState,
         tool_schemas: Vec<ToolSchema>,
+    ) -> Result<AgentFileV1> {
+        let content = ExportContent {
+            blocks: state.memory.blocks().iter()
+                .map(|(label, block)| (label.clone(), block.clone()))
+                .collect(),
+            messages: state.messages.messages.clone(),
+            passages: state.archival_entries.clone(),
+            metadata: state.metadata.clone(),
+        };
+        Self::assemble(config, state, content, tool_schemas)
+    }
+    
+    /// Same as `export` for a state that is no longer needed: its messages,
+    /// passages and memory blocks are moved into the file instead of copied.
+    pub fn export_owned(
+        config: &AgentConfig,
+        mut state: AgentState,
+        tool_schemas: Vec<ToolSchema>,
+    ) -> Result<AgentFileV1> {
+        // Taking the map keeps its iteration order, so blocks come out as in `export`
+        let content = ExportContent {
+            blocks: std::mem::take(state.memory.blocks_mut()).into_iter().collect(),
+            messages: std::mem::take(&mut state.messages.messages),
+            passages: std::mem::take(&mut state.archival_entries),
+            metadata: state.metadata.take(),
+        };
+        Self::assemble(config, &state, content, tool_schemas)
+    }
+    
+    fn assemble(
+        config: &AgentConfig,
+        state: &AgentState,
+        content: ExportContent,
+        tool_schemas: Vec<ToolSchema>,
     ) -> Result<AgentFileV1> {
         // Extract memory blocks
         let mut blocks = Vec::new();
         let mut block_ids = Vec::new();
         
-        for (label, block) in state.memory.blocks() {
+        for (label, block) in content.blocks {
             let block_id = format!("block_{}", label);
             blocks.push(BlockExport {
                 id: block_id.clone(),
-                label: label.clone(),
-                description: block.description.clone(),
-                value: block.value.clone(),
+                label,
+                description: block.description,
+                value: block.value,
                 limit: block.limit,
             });
             block_ids.push(block_id);
@@ -176,23 +276,24 @@ impl AgentFile {
             tools: tool_schemas.iter().map(|s| s.name.clone()).collect(),
             tool_rules: None,
             memory: memory_export,
-            metadata: Some(state.metadata.clone()),
+            metadata: Some(content.metadata),
         };
         
         // Create agent export
         let agent_export = AgentExport {
             id: state.id.clone(),
-            name: state.name.clone(),
+            name: config.name.clone(),
             system_prompt: config.system_prompt.clone(),
             message_buffer_size: config.max_messages,
             agent_state: agent_state_export,
-            messages: state.messages.messages.clone(),
+            messages: content.messages,
             model: ModelConfig {
                 model_endpoint: config.model.clone(),
                 context_window: config.max_context_tokens,
                 temperature: Some(config.temperature),
                 max_tokens: None,
             },
+            passages: content.passages,
         };
         
         // Create tool exports
@@ -239,6 +340,12 @@ impl AgentFile {
             max_context_tokens: agent_export.model.context_window,
             temperature: agent_export.model.temperature.unwrap_or(0.7),
             tools_enabled: !agent_export.agent_state.tools.is_empty(),
+            disabled_tools: Vec::new(),
+            tokenizer: Default::default(),
+            max_response_tokens: None,
+            stop_sequences: Vec::new(),
+            strict_persistence: false,
+            max_tool_iterations: crate::DEFAULT_MAX_TOOL_ITERATIONS,
         };
         
         // Create state
@@ -267,6 +374,8 @@ impl AgentFile {
             state.messages.push(msg.clone());
         }
         
+        state.archival_entries = agent_export.passages.clone();
+        
         // Import metadata
         if let Some(metadata) = &agent_export.agent_state.metadata {
             state.metadata = metadata.clone();
@@ -275,19 +384,145 @@ impl AgentFile {
         Ok((config, state))
     }
     
+    /// What importing the first agent of `af` would drop
+    pub fn import_warnings(af: &AgentFileV1) -> Vec<ImportWarning> {
+        let mut warnings = Vec::new();
+        
+        let sections = [
+            ("groups", af.groups.as_ref().is_some_and(|g| !g.is_empty())),
+            ("files", af.files.as_ref().is_some_and(|f| !f.is_empty())),
+            ("sources", af.sources.as_ref().is_some_and(|s| !s.is_empty())),
+            ("mcp_servers", af.mcp_servers.as_ref().is_some_and(|m| !m.is_empty())),
+        ];
+        for (section, present) in sections {
+            if present {
+                warnings.push(ImportWarning::SkippedSection { section: section.to_string() });
+            }
+        }
+        
+        if let Some(agent) = af.agents.first() {
+            if agent.agent_state.tool_rules.as_ref().is_some_and(|r| !r.is_empty()) {
+                warnings.push(ImportWarning::SkippedSection { section: "tool_rules".to_string() });
+            }
+            
+            let builtin: Vec<String> = ToolExecutor::new().get_schemas().into_iter().map(|s| s.name).collect();
+            for name in agent.agent_state.tools.iter().filter(|name| !builtin.contains(name)) {
+                warnings.push(ImportWarning::UnknownTool { name: name.clone() });
+            }
+            
+            for id in &agent.agent_state.memory.blocks {
+                if !af.blocks.iter().any(|b| &b.id == id) {
+                    warnings.push(ImportWarning::MissingBlock { id: id.clone() });
+                }
+            }
+        }
+        
+        warnings
+    }
+    
+    /// Compare the first agent of `left` and `right`: block values by label,
+    /// messages by ID. Blocks are sorted by label.
+    pub fn diff(left: &AgentFileV1, right: &AgentFileV1) -> Result<AgentFileDiff> {
+        let (left_agent, right_agent) = match (left.agents.first(), right.agents.first()) {
+            (Some(l), Some(r)) => (l, r),
+            _ => return Err(crate::error::LettaError::InvalidConfig("No agents in AF file".into())),
+        };
+        
+        let left_blocks = agent_block_values(left, left_agent);
+        let right_blocks = agent_block_values(right, right_agent);
+        
+        let mut labels: Vec<&String> = left_blocks.keys().chain(right_blocks.keys()).collect();
+        labels.sort();
+        labels.dedup();
+        
+        let changed_blocks = labels.into_iter()
+            .filter(|label| left_blocks.get(*label) != right_blocks.get(*label))
+            .map(|label| BlockDiff {
+                label: label.clone(),
+                left: left_blocks.get(label).cloned(),
+                right: right_blocks.get(label).cloned(),
+            })
+            .collect();
+        
+        let only_in = |a: &AgentExport, b: &AgentExport| -> Vec<String> {
+            a.messages.iter()
+                .filter(|m| !b.messages.iter().any(|other| other.id == m.id))
+                .map(|m| m.id.clone())
+                .collect()
+        };
+        
+        Ok(AgentFileDiff {
+            changed_blocks,
+            left_only_messages: only_in(left_agent, right_agent),
+            right_only_messages: only_in(right_agent, left_agent),
+            system_prompt_changed: left_agent.system_prompt != right_agent.system_prompt,
+        })
+    }
+    
     /// Export to JSON string
     pub fn to_json(af: &AgentFileV1) -> Result<String> {
         serde_json::to_string_pretty(af)
-            .map_err(|e| crate::error::LettaError::Serialization(e))
+            .map_err(crate::error::LettaError::Serialization)
     }
     
     /// Import from JSON string
     pub fn from_json(json: &str) -> Result<AgentFileV1> {
         serde_json::from_str(json)
-            .map_err(|e| crate::error::LettaError::Serialization(e))
+            .map_err(crate::error::LettaError::Serialization)
+    }
+    
+    /// Stream `af` into `writer` without building the whole document in memory
+    pub fn to_writer(af: &AgentFileV1, writer: impl Write, compression: AfCompression) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        match compression {
+            AfCompression::None => {
+                serde_json::to_writer_pretty(&mut writer, af).map_err(write_error)?;
+            }
+            AfCompression::Gzip => {
+                let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+                serde_json::to_writer(&mut encoder, af).map_err(write_error)?;
+                encoder.finish()?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    
+    /// Read an agent file written by `to_writer`. Gzip is detected from the
+    /// data itself, so either encoding is accepted; encrypted files are
+    /// `PassphraseRequired`, see `from_reader_with_passphrase`.
+    pub fn from_reader(reader: impl Read) -> Result<AgentFileV1> {
+        let mut reader = BufReader::new(reader);
+        let start = reader.fill_buf()?;
+        if AgentFile::is_encrypted(start) {
+            return Err(LettaError::PassphraseRequired);
+        }
+        let af = if start.starts_with(&GZIP_MAGIC) {
+            serde_json::from_reader(GzDecoder::new(reader))?
+        } else {
+            serde_json::from_reader(reader)?
+        };
+        Ok(af)
+    }
+}
+
+/// Failing to write is an I/O problem, not a serialization one
+fn write_error(e: serde_json::Error) -> LettaError {
+    if e.is_io() {
+        LettaError::Io(e.into())
+    } else {
+        LettaError::Serialization(e)
     }
 }
 
+/// Block values referenced by `agent`'s memory, keyed by label
+fn agent_block_values(af: &AgentFileV1, agent: &AgentExport) -> HashMap<String, String> {
+    agent.agent_state.memory.blocks.iter()
+        .filter_map(|id| af.blocks.iter().find(|b| &b.id == id))
+        .map(|b| (b.label.clone(), b.value.clone()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -312,4 +547,84 @@ mod tests {
         assert_eq!(config2.name, config.name);
         assert_eq!(state2.memory.get_block("test").unwrap().value, "test value");
     }
+    
+    #[test]
+    fn test_export_owned_matches_export() {
+        let config = AgentConfig::default();
+        let mut state = AgentState::new("test-agent");
+        for label in ["human", "goals", "notes", "plans"] {
+            state.memory.set_block(label, format!("{} value", label)).unwrap();
+        }
+        state.messages.push(Message::user("Hello"));
+        state.messages.push(Message::assistant("Hi there"));
+        state.archival_entries.push(serde_json::json!({"folder": "notes", "text": "Walked 5km"}));
+        state.metadata = serde_json::json!({"source": "test"});
+        
+        let copied = AgentFile::export(&config, &state, ToolExecutor::new().get_schemas()).unwrap();
+        let mut moved = AgentFile::export_owned(&config, state, ToolExecutor::new().get_schemas()).unwrap();
+        moved.metadata.export_time = copied.metadata.export_time;
+        assert_eq!(AgentFile::to_json(&moved).unwrap(), AgentFile::to_json(&copied).unwrap());
+    }
+    
+    #[test]
+    fn test_agent_file_streaming_round_trip() {
+        let mut state = AgentState::new("test-agent");
+        state.memory.set_block("human", "Alice").unwrap();
+        let af = AgentFile::export(&AgentConfig::default(), &state, vec![]).unwrap();
+        
+        for compression in [AfCompression::None, AfCompression::Gzip] {
+            let mut bytes = Vec::new();
+            AgentFile::to_writer(&af, &mut bytes, compression).unwrap();
+            assert_eq!(bytes.starts_with(&GZIP_MAGIC), compression == AfCompression::Gzip);
+            
+            let read = AgentFile::from_reader(bytes.as_slice()).unwrap();
+            let (_, imported) = AgentFile::import(&read).unwrap();
+            assert_eq!(imported.memory.get_block("human").unwrap().value, "Alice");
+        }
+        
+        let corrupt = [&GZIP_MAGIC[..], b"not really gzip"].concat();
+        assert!(matches!(AgentFile::from_reader(corrupt.as_slice()), Err(LettaError::Serialization(_))));
+    }
+    
+    #[test]
+    fn test_import_warnings() {
+        let mut state = AgentState::new("test-agent");
+        state.memory.set_block("human", "Alice").unwrap();
+        let mut af = AgentFile::export(&AgentConfig::default(), &state, vec![]).unwrap();
+        assert!(AgentFile::import_warnings(&af).is_empty());
+        
+        af.agents[0].agent_state.tools = vec!["archival_search".into(), "web_search".into()];
+        af.agents[0].agent_state.memory.blocks.push("block_gone".into());
+        af.groups = Some(vec![GroupExport { id: "g".into(), name: "team".into(), members: vec![] }]);
+        assert_eq!(AgentFile::import_warnings(&af), vec![
+            ImportWarning::SkippedSection { section: "groups".into() },
+            ImportWarning::UnknownTool { name: "web_search".into() },
+            ImportWarning::MissingBlock { id: "block_gone".into() },
+        ]);
+    }
+    
+    #[test]
+    fn test_agent_file_diff() {
+        let config = AgentConfig::default();
+        let mut state = AgentState::new("test-agent");
+        state.memory.set_block("human", "Alice").unwrap();
+        state.messages.push(Message::user("Hello"));
+        let before = AgentFile::export(&config, &state, vec![]).unwrap();
+        
+        state.memory.set_block("human", "Alice, a nurse").unwrap();
+        state.memory.set_block("goals", "Run a marathon").unwrap();
+        state.messages.push(Message::user("I signed up for a race"));
+        let after = AgentFile::export(&config, &state, vec![]).unwrap();
+        
+        assert!(AgentFile::diff(&before, &before).unwrap().is_empty());
+        
+        let diff = AgentFile::diff(&before, &after).unwrap();
+        assert_eq!(diff.changed_blocks, vec![
+            BlockDiff { label: "goals".into(), left: None, right: Some("Run a marathon".into()) },
+            BlockDiff { label: "human".into(), left: Some("Alice".into()), right: Some("Alice, a nurse".into()) },
+        ]);
+        assert!(diff.left_only_messages.is_empty());
+        assert_eq!(diff.right_only_messages, vec![state.messages.messages[1].id.clone()]);
+        assert!(!diff.system_prompt_changed);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,194 @@
+//! Passphrase-encrypted agent files.
+//!
+//! An encrypted file is `LETTAENC`, a format version byte, the Argon2id costs
+//! (m, t and p as little-endian u32s), a 16-byte salt, a 16-byte key check
+//! and a 24-byte nonce, followed by the XChaCha20-Poly1305 ciphertext of the
+//! gzipped JSON. The whole header is authenticated along with the ciphertext.
+//!
+//! The key check is derived together with the key, so a wrong passphrase is
+//! told apart from a damaged file before anything is decrypted.
+
+use std::io::{BufRead, BufReader, Read};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+
+use crate::af::{AfCompression, AgentFile, AgentFileV1};
+use crate::error::{LettaError, Result};
+
+/// Leading bytes of an encrypted agent file
+pub(crate) const ENCRYPTED_MAGIC: [u8; 8] = *b"LETTAENC";
+
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const CHECK_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + 3 * 4 + SALT_LEN + CHECK_LEN + NONCE_LEN;
+
+impl AgentFile {
+    /// Encrypt `af` with a key stretched from `passphrase` by Argon2id
+    pub fn export_encrypted(af: &AgentFileV1, passphrase: &str) -> Result<Vec<u8>> {
+        encrypt(af, passphrase, Params::default())
+    }
+
+    /// Decrypt a file written by `export_encrypted`. A wrong passphrase is
+    /// `WrongPassphrase`; a file altered in any other way is `Tampered`.
+    pub fn import_encrypted(bytes: &[u8], passphrase: &str) -> Result<AgentFileV1> {
+        if !Self::is_encrypted(bytes) {
+            return Err(LettaError::InvalidConfig("Not an encrypted agent file".into()));
+        }
+        if bytes.len() < HEADER_LEN || bytes[ENCRYPTED_MAGIC.len()] != FORMAT_VERSION {
+            return Err(LettaError::Tampered);
+        }
+        let (header, ciphertext) = bytes.split_at(HEADER_LEN);
+
+        let mut fields = &header[ENCRYPTED_MAGIC.len() + 1..];
+        let mut take = |len: usize| {
+            let (field, rest) = fields.split_at(len);
+            fields = rest;
+            field
+        };
+        let mut cost = || u32::from_le_bytes(take(4).try_into().expect("four bytes"));
+        let (m_cost, t_cost, p_cost) = (cost(), cost(), cost());
+        let params = Params::new(m_cost, t_cost, p_cost, None).map_err(|_| LettaError::Tampered)?;
+        let (salt, check, nonce) = (take(SALT_LEN), take(CHECK_LEN), take(NONCE_LEN));
+
+        let (key, expected_check) = derive_key(passphrase, &params, salt)?;
+        if expected_check != check {
+            return Err(LettaError::WrongPassphrase);
+        }
+        let plaintext = XChaCha20Poly1305::new(&key)
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+            .map_err(|_| LettaError::Tampered)?;
+        Self::from_reader(plaintext.as_slice())
+    }
+
+    /// Whether `bytes` start like a file written by `export_encrypted`
+    pub fn is_encrypted(bytes: &[u8]) -> bool {
+        bytes.starts_with(&ENCRYPTED_MAGIC)
+    }
+
+    /// Read a plain, gzipped or encrypted agent file, decrypting with
+    /// `passphrase` when needed
+    pub fn from_reader_with_passphrase(reader: impl Read, passphrase: &str) -> Result<AgentFileV1> {
+        let mut reader = BufReader::new(reader);
+        if !Self::is_encrypted(reader.fill_buf()?) {
+            return Self::from_reader(reader);
+        }
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::import_encrypted(&bytes, passphrase)
+    }
+}
+
+fn encrypt(af: &AgentFileV1, passphrase: &str, params: Params) -> Result<Vec<u8>> {
+    if passphrase.is_empty() {
+        return Err(LettaError::InvalidConfig("Passphrase must not be empty".into()));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let (key, check) = derive_key(passphrase, &params, &salt)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(&ENCRYPTED_MAGIC);
+    bytes.push(FORMAT_VERSION);
+    for cost in [params.m_cost(), params.t_cost(), params.p_cost()] {
+        bytes.extend_from_slice(&cost.to_le_bytes());
+    }
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&check);
+    bytes.extend_from_slice(&nonce);
+
+    let mut plaintext = Vec::new();
+    AgentFile::to_writer(af, &mut plaintext, AfCompression::Gzip)?;
+    let ciphertext = XChaCha20Poly1305::new(&key)
+        .encrypt(&nonce, Payload { msg: &plaintext, aad: &bytes })
+        .map_err(|_| LettaError::Unknown("Failed to encrypt agent file".into()))?;
+    bytes.extend_from_slice(&ciphertext);
+    Ok(bytes)
+}
+
+/// The cipher key and the check value stored beside it
+fn derive_key(passphrase: &str, params: &Params, salt: &[u8]) -> Result<(Key, [u8; CHECK_LEN])> {
+    let mut output = [0u8; 32 + CHECK_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(passphrase.as_bytes(), salt, &mut output)
+        .map_err(|e| LettaError::Unknown(format!("Key derivation failed: {}", e)))?;
+    let (key, check) = output.split_at(32);
+    Ok((*Key::from_slice(key), check.try_into().expect("check length")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AgentConfig, AgentState};
+
+    fn agent_file() -> AgentFileV1 {
+        let mut state = AgentState::new("secret-agent");
+        state.memory.set_block("human", "Alice").unwrap();
+        AgentFile::export(&AgentConfig::default(), &state, vec![]).unwrap()
+    }
+
+    // Cheap parameters keep debug-build tests fast
+    fn encrypted(passphrase: &str) -> Vec<u8> {
+        encrypt(&agent_file(), passphrase, Params::new(1024, 1, 1, None).unwrap()).unwrap()
+    }
+
+    fn human(af: &AgentFileV1) -> String {
+        let (_, state) = AgentFile::import(af).unwrap();
+        state.memory.get_block("human").unwrap().value.clone()
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let bytes = encrypted("correct horse");
+        assert!(AgentFile::is_encrypted(&bytes));
+        assert!(!bytes.windows(5).any(|w| w == b"Alice"));
+
+        assert_eq!(human(&AgentFile::import_encrypted(&bytes, "correct horse").unwrap()), "Alice");
+        let read = AgentFile::from_reader_with_passphrase(bytes.as_slice(), "correct horse").unwrap();
+        assert_eq!(human(&read), "Alice");
+        assert!(matches!(AgentFile::from_reader(bytes.as_slice()), Err(LettaError::PassphraseRequired)));
+        assert!(matches!(
+            encrypt(&agent_file(), "", Params::default()),
+            Err(LettaError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_passphrase_and_tampering() {
+        let bytes = encrypted("correct horse");
+        assert!(matches!(AgentFile::import_encrypted(&bytes, "battery staple"), Err(LettaError::WrongPassphrase)));
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(AgentFile::import_encrypted(&flipped, "correct horse"), Err(LettaError::Tampered)));
+
+        // The header is authenticated too
+        let mut nonce = bytes.clone();
+        nonce[HEADER_LEN - 1] ^= 1;
+        assert!(matches!(AgentFile::import_encrypted(&nonce, "correct horse"), Err(LettaError::Tampered)));
+
+        let truncated = &bytes[..HEADER_LEN - 1];
+        assert!(matches!(AgentFile::import_encrypted(truncated, "correct horse"), Err(LettaError::Tampered)));
+    }
+
+    #[test]
+    fn test_plain_files_need_no_passphrase() {
+        for compression in [AfCompression::None, AfCompression::Gzip] {
+            let mut bytes = Vec::new();
+            AgentFile::to_writer(&agent_file(), &mut bytes, compression).unwrap();
+            assert!(!AgentFile::is_encrypted(&bytes));
+            assert_eq!(human(&AgentFile::from_reader(bytes.as_slice()).unwrap()), "Alice");
+            let read = AgentFile::from_reader_with_passphrase(bytes.as_slice(), "ignored").unwrap();
+            assert_eq!(human(&read), "Alice");
+        }
+        assert!(matches!(
+            AgentFile::import_encrypted(b"{\"version\": \"0.1.0\"}", "correct horse"),
+            Err(LettaError::InvalidConfig(_))
+        ));
+    }
+}
@@ -1,13 +1,19 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use crate::{
-    error::{LettaError, Result},
-    memory::Memory,
-    message::{Message, MessageBuffer, MessageRole, ToolCallInfo},
-    tool::{ToolCall, ToolExecutor, ToolResult, ToolSchema},
-    provider::{LlmProvider, CompletionRequest},
+    archival::{self, SearchMode},
+    cancel::CancellationToken,
+    error::{LettaError, ProviderError, ProviderErrorKind, Result},
+    memory::{Memory, MemoryUsage},
+    message::{Message, MessageBuffer, MessageRole, MessageStats, ToolCallInfo},
+    tool::{ToolExecutor, ToolResult},
+    provider::{Completion, CompletionAccumulator, CompletionChunk, CompletionRequest, Embedder, LlmProvider, ProviderHealth, TokenUsage},
     context::ContextManager,
+    tokenizer::{HeuristicTokenizer, Tokenizer, TokenizerConfig},
+    telemetry::{self, ProviderCallMetrics, StepMetrics, Stopwatch, ToolMetrics},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +25,57 @@ pub struct AgentConfig {
     pub max_context_tokens: usize,
     pub temperature: f32,
     pub tools_enabled: bool,
+    /// Tools the model is neither offered nor allowed to call
+    #[serde(default)]
+    pub disabled_tools: Vec<String>,
+    /// How prompts are measured against `max_context_tokens`
+    #[serde(default)]
+    pub tokenizer: TokenizerConfig,
+    /// Most tokens the model may generate per reply; the provider's default
+    /// if unset
+    #[serde(default)]
+    pub max_response_tokens: Option<usize>,
+    /// The model stops replying before any of these
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    /// Fail a step whose save to attached storage fails, rather than
+    /// reporting it in `StepResult::warnings`; see `Agent::attach_storage`
+    #[serde(default)]
+    pub strict_persistence: bool,
+    /// Most provider calls one step makes while tools ask to continue. A
+    /// step that reaches it ends with `StopReason::MaxIterations`.
+    #[serde(default = "default_max_tool_iterations")]
+    pub max_tool_iterations: usize,
+}
+
+fn default_max_tool_iterations() -> usize {
+    crate::DEFAULT_MAX_TOOL_ITERATIONS
+}
+
+impl AgentConfig {
+    /// Check that the settings are usable
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=2.0).contains(&self.temperature) {
+            return Err(LettaError::InvalidConfig(format!("temperature {} is outside 0.0..=2.0", self.temperature)));
+        }
+        if self.max_messages == 0 {
+            return Err(LettaError::InvalidConfig("max_messages must be at least 1".into()));
+        }
+        if self.max_context_tokens == 0 {
+            return Err(LettaError::InvalidConfig("max_context_tokens must be at least 1".into()));
+        }
+        if self.max_tool_iterations == 0 {
+            return Err(LettaError::InvalidConfig("max_tool_iterations must be at least 1".into()));
+        }
+        if self.max_response_tokens == Some(0) {
+            return Err(LettaError::InvalidConfig("max_response_tokens must be at least 1".into()));
+        }
+        if self.stop_sequences.iter().any(String::is_empty) {
+            return Err(LettaError::InvalidConfig("stop_sequences can't include an empty string".into()));
+        }
+        self.tokenizer.build()?;
+        Ok(())
+    }
 }
 
 impl Default for AgentConfig {
@@ -31,6 +88,12 @@ impl Default for AgentConfig {
             max_context_tokens: 8192,
             temperature: 0.7,
             tools_enabled: true,
+            disabled_tools: Vec::new(),
+            tokenizer: TokenizerConfig::default(),
+            max_response_tokens: None,
+            stop_sequences: Vec::new(),
+            strict_persistence: false,
+            max_tool_iterations: crate::DEFAULT_MAX_TOOL_ITERATIONS,
         }
     }
 }
@@ -45,6 +108,15 @@ pub struct AgentState {
     pub messages: MessageBuffer,
     pub archival_entries: Vec<serde_json::Value>,
     pub metadata: serde_json::Value,
+    /// Tokens used by every completion so far; older states call this `usage`
+    #[serde(default, alias = "usage")]
+    pub usage_totals: TokenUsage,
+    /// `usage_totals` split by the name of the provider that served each completion
+    #[serde(default)]
+    pub usage_by_provider: BTreeMap<String, TokenUsage>,
+    /// Number of calls to each tool so far
+    #[serde(default)]
+    pub tool_calls: HashMap<String, u64>,
 }
 
 impl AgentState {
@@ -59,22 +131,40 @@ impl AgentState {
             messages: MessageBuffer::new(100),
             archival_entries: Vec::new(),
             metadata: serde_json::json!({}),
+            usage_totals: TokenUsage::default(),
+            usage_by_provider: BTreeMap::new(),
+            tool_calls: HashMap::new(),
         }
     }
 }
 
+/// Texts per `embed` call when embedding archival entries
+const EMBED_BATCH_SIZE: usize = 64;
+
+/// `embed` calls in flight at once when embedding archival entries
+const EMBED_CONCURRENCY: usize = 4;
+
 pub struct Agent {
     pub config: AgentConfig,
     pub state: AgentState,
     context: ContextManager,
     tool_executor: ToolExecutor,
-    provider: Box<dyn LlmProvider>,
+    /// Shared with forks of the agent
+    provider: Arc<dyn LlmProvider>,
+    /// Embeds archival memory in place of the provider, if set
+    embedder: Option<Arc<dyn Embedder>>,
+    /// Where unsaved changes go when the agent is dropped; see `flush_on_drop`
+    #[cfg(feature = "storage")]
+    pub(crate) flush: Option<crate::persist::FlushOnDrop>,
+    /// Where each step is saved; see `attach_storage`
+    #[cfg(feature = "storage")]
+    pub(crate) attached: Option<crate::persist::AttachedStorage>,
 }
 
 impl Agent {
     pub fn new(config: AgentConfig, provider: Box<dyn LlmProvider>) -> Self {
         let state = AgentState::new(&config.name);
-        let context = ContextManager::new(config.max_context_tokens);
+        let context = ContextManager::new(config.max_context_tokens, build_tokenizer(&config.tokenizer));
         let tool_executor = ToolExecutor::new();
         
         Self {
@@ -82,7 +172,12 @@ impl Agent {
             state,
             context,
             tool_executor,
-            provider,
+            provider: Arc::from(provider),
+            embedder: None,
+            #[cfg(feature = "storage")]
+            flush: None,
+            #[cfg(feature = "storage")]
+            attached: None,
         }
     }
     
@@ -91,28 +186,196 @@ impl Agent {
         self
     }
     
+    /// Count prompt tokens with `tokenizer` rather than the one named in
+    /// the config
+    pub fn with_tokenizer(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.context = ContextManager::new(self.config.max_context_tokens, tokenizer);
+        self
+    }
+    
+    /// Embed archival memory with `embedder` rather than the chat provider,
+    /// e.g. a local model so semantic search works offline
+    pub fn with_embedder(mut self, embedder: Box<dyn Embedder>) -> Self {
+        self.embedder = Some(Arc::from(embedder));
+        self
+    }
+    
+    /// Whether the provider is reachable with its configured credentials,
+    /// and how long it took to answer
+    pub async fn check_provider(&self) -> Result<ProviderHealth> {
+        self.provider.health().await
+    }
+    
     pub async fn step(&mut self, user_message: String) -> Result<StepResult> {
+        self.run_step(user_message, None, &CancellationToken::new()).await
+    }
+    
+    /// Like `step`, but fails with `LettaError::Cancelled` as soon as `cancel`
+    /// is cancelled, dropping the provider call in progress. The user message
+    /// stays in the buffer; the step's assistant and tool messages are taken
+    /// out again, though tools that already ran keep their effects.
+    pub async fn step_with_cancel(&mut self, user_message: String, cancel: &CancellationToken) -> Result<StepResult> {
+        self.run_step(user_message, None, cancel).await
+    }
+    
+    /// Like `step`, but reports text deltas and tool calls through `on_event`
+    /// while the step runs. The returned `StepResult` is the same as `step`'s.
+    pub async fn step_stream(
+        &mut self,
+        user_message: String,
+        mut on_event: impl FnMut(StepEvent) + Send,
+    ) -> Result<StepResult> {
+        self.run_step(user_message, Some(&mut on_event), &CancellationToken::new()).await
+    }
+    
+    /// `step_stream` that can be cancelled like `step_with_cancel`
+    pub async fn step_stream_with_cancel(
+        &mut self,
+        user_message: String,
+        mut on_event: impl FnMut(StepEvent) + Send,
+        cancel: &CancellationToken,
+    ) -> Result<StepResult> {
+        self.run_step(user_message, Some(&mut on_event), cancel).await
+    }
+    
+    async fn run_step(
+        &mut self,
+        user_message: String,
+        on_event: Option<&mut (dyn FnMut(StepEvent) + Send)>,
+        cancel: &CancellationToken,
+    ) -> Result<StepResult> {
         // Add user message
         let user_msg = Message::user(&user_message);
         self.state.messages.push(user_msg.clone());
         
+        self.respond(on_event, cancel, &StepOptions::default()).await
+    }
+    
+    /// Record a user message without replying; returns the message id.
+    /// A later `reply_only` answers everything sent so far.
+    pub fn send_only(&mut self, user_message: String) -> String {
+        let user_msg = Message::user(user_message);
+        let id = user_msg.id.clone();
+        self.state.messages.push(user_msg);
+        self.state.updated_at = Utc::now();
+        id
+    }
+    
+    /// Reply to the conversation as it stands, without adding a user message
+    pub async fn reply_only(&mut self) -> Result<StepResult> {
+        self.respond(None, &CancellationToken::new(), &StepOptions::default()).await
+    }
+    
+    /// `reply_only` that can be cancelled like `step_with_cancel`
+    pub async fn reply_only_with_cancel(&mut self, cancel: &CancellationToken) -> Result<StepResult> {
+        self.respond(None, cancel, &StepOptions::default()).await
+    }
+    
+    /// Replace the last reply, e.g. for a "regenerate" button: everything
+    /// after the last user message is dropped, including the tool calls and
+    /// results of its step, and the agent replies again. `options` apply to
+    /// the new reply only. If it fails, the dropped messages are put back.
+    /// Tools that already ran keep their effects. With storage attached, the
+    /// dropped messages are deleted from it when the new reply is saved.
+    ///
+    /// Fails with `LettaError::InvalidConfig` if no assistant message follows
+    /// the last user message.
+    pub async fn regenerate(&mut self, options: Option<StepOptions>) -> Result<StepResult> {
+        self.regenerate_with_cancel(options, &CancellationToken::new()).await
+    }
+    
+    /// `regenerate` that can be cancelled like `step_with_cancel`
+    pub async fn regenerate_with_cancel(&mut self, options: Option<StepOptions>, cancel: &CancellationToken) -> Result<StepResult> {
+        let options = options.unwrap_or_default();
+        options.validate()?;
+        
+        let messages = &mut self.state.messages.messages;
+        let last_user = messages.iter().rposition(|m| m.role == MessageRole::User);
+        let Some(last_user) = last_user.filter(|&i| messages[i + 1..].iter().any(|m| m.role == MessageRole::Assistant)) else {
+            return Err(LettaError::InvalidConfig("There is no assistant reply to regenerate".into()));
+        };
+        let dropped = messages.split_off(last_user + 1);
+        
+        #[cfg(feature = "storage")]
+        self.delete_on_save(&dropped);
+        let result = self.respond(None, cancel, &options).await;
+        if result.is_err() {
+            // Drop whatever the failed attempt got through before its error
+            self.state.messages.messages.truncate(last_user + 1);
+            #[cfg(feature = "storage")]
+            self.keep_on_save(&dropped);
+            self.state.messages.messages.extend(dropped);
+        }
+        result
+    }
+    
+    /// Run the step, save it to attached storage and report it to the
+    /// installed metrics recorder. A cancelled step leaves the buffer as it
+    /// found it.
+    async fn respond(
+        &mut self,
+        on_event: Option<&mut (dyn FnMut(StepEvent) + Send)>,
+        cancel: &CancellationToken,
+        options: &StepOptions,
+    ) -> Result<StepResult> {
+        let started = Stopwatch::start();
+        let mut totals = StepTotals::default();
+        let last_id = self.state.messages.messages.last().map(|m| m.id.clone());
+        let result = self.run_iterations(on_event, cancel, options, &mut totals).await;
+        if matches!(result, Err(LettaError::Cancelled)) {
+            match last_id {
+                Some(id) => self.state.messages.truncate_after(&id),
+                None => self.state.messages.clear(),
+            }
+        }
+        #[cfg(feature = "storage")]
+        let result = match result {
+            Ok(step) => self.persist_step(step).await,
+            Err(e) => Err(e),
+        };
+        telemetry::record(|metrics| metrics.on_step(&StepMetrics {
+            agent_id: &self.state.id,
+            duration: started.elapsed(),
+            iterations: totals.iterations,
+            usage: &totals.usage,
+            tool_calls: totals.tool_calls,
+            success: result.is_ok(),
+        }));
+        result
+    }
+    
+    async fn run_iterations(
+        &mut self,
+        mut on_event: Option<&mut (dyn FnMut(StepEvent) + Send)>,
+        cancel: &CancellationToken,
+        options: &StepOptions,
+        totals: &mut StepTotals,
+    ) -> Result<StepResult> {
         let mut tool_trace = Vec::new();
-        let mut iterations = 0;
-        const MAX_ITERATIONS: usize = 10;
         
         loop {
-            iterations += 1;
-            if iterations > MAX_ITERATIONS {
-                return Err(LettaError::ToolExecution("Maximum iterations exceeded".into()));
+            cancel.check()?;
+            if totals.iterations == self.config.max_tool_iterations {
+                // Keep what the tools did; the model just never got to reply
+                self.state.updated_at = Utc::now();
+                return Ok(StepResult {
+                    text: format!("[Stopped after {} iterations without a final reply]", totals.iterations),
+                    tool_trace,
+                    usage: totals.usage.clone(),
+                    warnings: Vec::new(),
+                    stop_reason: StopReason::MaxIterations,
+                });
             }
+            totals.iterations += 1;
             
-            // Build prompt
-            let prompt = self.context.build_prompt(
+            // Build the context, as messages and as one prompt
+            let messages = self.context.build_chat(
                 &self.config.system_prompt,
                 &self.state.memory,
                 &self.state.messages.messages,
                 self.config.max_messages,
             )?;
+            let prompt = ContextManager::flatten(&messages);
             
             // Check if we should summarize
             if self.context.should_summarize() {
@@ -120,12 +383,19 @@ impl Agent {
                 self.state.messages.push(Message::system(format!("Context summary: {}", summary)));
             }
             
-            // Get tool schemas if enabled
-            let tools = if self.config.tools_enabled {
-                self.tool_executor.get_schemas()
+            // Get tool schemas if enabled and the provider can take them
+            let capabilities = self.provider.capabilities();
+            let tools = if self.config.tools_enabled && capabilities.supports_tools {
+                let mut tools: Vec<_> = self.tool_executor.get_schemas()
                     .into_iter()
+                    .filter(|s| !self.config.disabled_tools.contains(&s.name))
                     .map(|s| serde_json::to_value(s).unwrap())
-                    .collect()
+                    .collect();
+                if let Some(max) = capabilities.max_tools.filter(|&max| tools.len() > max) {
+                    tracing::warn!(provider = self.provider.name(), enabled = tools.len(), max, "Sending only the first tools the provider takes");
+                    tools.truncate(max);
+                }
+                tools
             } else {
                 vec![]
             };
@@ -133,47 +403,103 @@ impl Agent {
             // Call LLM
             let request = CompletionRequest {
                 prompt,
+                messages,
                 tools,
-                temperature: Some(self.config.temperature),
-                max_tokens: None,
-                stream: false,
+                temperature: Some(options.temperature.unwrap_or(self.config.temperature)),
+                max_tokens: self.config.max_response_tokens,
+                stream: on_event.is_some(),
+                stop: self.config.stop_sequences.clone(),
+                cancel: cancel.clone(),
             };
             
-            let completion = self.provider.complete(request).await?;
+            let streamed = request.stream;
+            let started = Stopwatch::start();
+            let completion = match on_event.as_deref_mut() {
+                Some(on_event) => cancel.run(stream_completion(self.provider.as_ref(), request, on_event)).await,
+                None => cancel.run(self.provider.complete(request)).await,
+            };
+            telemetry::record(|metrics| metrics.on_provider_call(&ProviderCallMetrics {
+                agent_id: &self.state.id,
+                provider: self.provider.name(),
+                duration: started.elapsed(),
+                usage: completion.as_ref().ok().map(|c| &c.usage),
+                streamed,
+            }));
+            let completion = completion?;
+            self.state.usage_totals.add(&completion.usage);
+            self.state.usage_by_provider.entry(self.provider.name().to_string())
+                .or_default()
+                .add(&completion.usage);
+            totals.usage.add(&completion.usage);
             
             // Handle tool calls
             if !completion.tool_calls.is_empty() {
                 let mut request_heartbeat = false;
                 
+                // The assistant message with the calls, ahead of their
+                // results. Kept back until every call has run, so a failed
+                // call leaves no half-answered calls in the history.
+                let assistant_msg = Message::assistant("")
+                    .with_tool_calls(completion.tool_calls.iter().map(|tc| ToolCallInfo {
+                        id: tc.id.clone(),
+                        name: tc.name.clone(),
+                        arguments: tc.arguments.clone(),
+                    }).collect());
+                let mut answered = vec![assistant_msg];
+                
                 for tool_call in &completion.tool_calls {
-                    let result = self.tool_executor.execute(tool_call, &mut self.state)?;
+                    *self.state.tool_calls.entry(tool_call.name.clone()).or_default() += 1;
+                    totals.tool_calls += 1;
+                    let started = Stopwatch::start();
+                    let result = if self.config.disabled_tools.contains(&tool_call.name) {
+                        Err(LettaError::ToolExecution(format!("Tool {} is disabled", tool_call.name)))
+                    } else if tool_call.name == "archival_search"
+                        && SearchMode::from_args(&tool_call.arguments) == Some(SearchMode::Semantic)
+                    {
+                        cancel.run(self.archival_search_tool(&tool_call.arguments)).await
+                    } else {
+                        self.tool_executor.execute(tool_call, &mut self.state)
+                    };
+                    let duration = started.elapsed();
+                    telemetry::record(|metrics| metrics.on_tool(&ToolMetrics {
+                        agent_id: &self.state.id,
+                        tool: &tool_call.name,
+                        duration,
+                        success: result.as_ref().is_ok_and(|r| r.success),
+                    }));
+                    let result = result?;
                     
                     // Add tool result as message
                     let tool_msg = Message::tool(
                         tool_call.id.clone(),
                         serde_json::to_string(&result.result)?,
                     );
-                    self.state.messages.push(tool_msg);
+                    answered.push(tool_msg);
                     
-                    tool_trace.push(serde_json::json!({
-                        "tool": tool_call.name,
-                        "args": tool_call.arguments,
-                        "result": result.result,
-                    }));
+                    if let Some(on_event) = on_event.as_deref_mut() {
+                        on_event(StepEvent::ToolCall {
+                            tool: tool_call.name.clone(),
+                            args: tool_call.arguments.clone(),
+                            result: result.result.clone(),
+                        });
+                    }
+                    tool_trace.push(ToolTraceEntry {
+                        tool: tool_call.name.clone(),
+                        arguments: tool_call.arguments.clone(),
+                        result: result.result,
+                        success: result.success,
+                        error: result.error,
+                        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+                        iteration: totals.iterations,
+                    });
                     
                     if result.request_heartbeat {
                         request_heartbeat = true;
                     }
                 }
-                
-                // Add assistant message with tool calls
-                let assistant_msg = Message::assistant("")
-                    .with_tool_calls(completion.tool_calls.iter().map(|tc| ToolCallInfo {
-                        id: tc.id.clone(),
-                        name: tc.name.clone(),
-                        arguments: tc.arguments.clone(),
-                    }).collect());
-                self.state.messages.push(assistant_msg);
+                for msg in answered {
+                    self.state.messages.push(msg);
+                }
                 
                 if request_heartbeat || completion.request_heartbeat {
                     continue; // Run another iteration
@@ -190,12 +516,28 @@ impl Agent {
                 return Ok(StepResult {
                     text: completion.text,
                     tool_trace,
-                    usage: completion.usage,
+                    usage: totals.usage.clone(),
+                    warnings: Vec::new(),
+                    stop_reason: StopReason::Completed,
                 });
             }
         }
     }
     
+    /// Replace the config of a live agent; the next step uses the new settings.
+    /// The provider is fixed at construction, so `model` is not re-resolved.
+    pub fn update_config(&mut self, config: AgentConfig) -> Result<()> {
+        config.validate()?;
+        
+        if config.max_context_tokens != self.config.max_context_tokens || config.tokenizer != self.config.tokenizer {
+            self.context = ContextManager::new(config.max_context_tokens, build_tokenizer(&config.tokenizer));
+        }
+        self.state.name = config.name.clone();
+        self.state.updated_at = Utc::now();
+        self.config = config;
+        Ok(())
+    }
+    
     pub fn set_memory_block(&mut self, label: &str, value: &str) -> Result<()> {
         self.state.memory.set_block(label, value)?;
         self.state.updated_at = Utc::now();
@@ -215,18 +557,105 @@ impl Agent {
         self.state.updated_at = Utc::now();
     }
     
+    /// Like `add_archival`, but stores the embedding of `text` so
+    /// semantic search needn't compute it later
+    pub async fn add_archival_embedded(&mut self, folder: &str, text: &str) -> Result<()> {
+        self.import_archival(folder, vec![text.to_string()]).await
+    }
+    
+    /// `add_archival_embedded` for many texts, such as the chunks of an
+    /// imported document, embedded in batches. Nothing is added if any
+    /// batch fails.
+    pub async fn import_archival(&mut self, folder: &str, texts: Vec<String>) -> Result<()> {
+        let vectors = self.embed(texts.clone()).await?;
+        let model = self.embedding_model().to_string();
+        let now = Utc::now();
+        for (text, vector) in texts.into_iter().zip(vectors) {
+            let mut entry = serde_json::json!({
+                "folder": folder,
+                "text": text,
+                "timestamp": now,
+            });
+            archival::set_embedding(&mut entry, &model, vector);
+            self.state.archival_entries.push(entry);
+        }
+        self.state.updated_at = now;
+        Ok(())
+    }
+    
     pub fn search_archival(&self, query: &str, top_k: usize) -> Vec<serde_json::Value> {
-        self.state.archival_entries
-            .iter()
-            .filter(|entry| {
-                entry.get("text")
-                    .and_then(|t| t.as_str())
-                    .map(|t| t.to_lowercase().contains(&query.to_lowercase()))
-                    .unwrap_or(false)
-            })
-            .take(top_k)
-            .cloned()
-            .collect()
+        archival::keyword_search(&self.state.archival_entries, query, top_k)
+    }
+    
+    /// The `top_k` archival entries closest in meaning to `query`, best first,
+    /// each with its cosine similarity as `score`. Entries without an
+    /// embedding from the current embedder are embedded first, in batches.
+    pub async fn search_archival_semantic(&mut self, query: &str, top_k: usize) -> Result<Vec<serde_json::Value>> {
+        let model = self.embedding_model().to_string();
+        let missing: Vec<usize> = self.state.archival_entries.iter()
+            .enumerate()
+            .filter(|(_, entry)| archival::embedding(entry, &model).is_none())
+            .map(|(i, _)| i)
+            .collect();
+        let texts = missing.iter()
+            .map(|&i| self.state.archival_entries[i].get("text").and_then(|t| t.as_str()).unwrap_or_default().to_string())
+            .chain(std::iter::once(query.to_string()))
+            .collect();
+        
+        let mut vectors = self.embed(texts).await?;
+        if vectors.len() != missing.len() + 1 {
+            return Err(ProviderError::new(
+                ProviderErrorKind::InvalidResponse,
+                format!("Asked for {} embeddings, got {}", missing.len() + 1, vectors.len()),
+            ).into());
+        }
+        let query_vector = vectors.pop().unwrap_or_default();
+        if !missing.is_empty() {
+            for (i, vector) in missing.into_iter().zip(vectors) {
+                archival::set_embedding(&mut self.state.archival_entries[i], &model, vector);
+            }
+            self.state.updated_at = Utc::now();
+        }
+        
+        Ok(archival::semantic_search(&self.state.archival_entries, &query_vector, &model, top_k))
+    }
+    
+    /// The embedder's model, or the provider's without one
+    fn embedding_model(&self) -> &str {
+        match &self.embedder {
+            Some(embedder) => embedder.name(),
+            None => self.provider.name(),
+        }
+    }
+    
+    /// Embeds in batches with the embedder if set, else the provider, which
+    /// must support embeddings rather than hand back zero vectors
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match &self.embedder {
+            Some(embedder) => embedder.embed_batched(texts, EMBED_BATCH_SIZE, EMBED_CONCURRENCY).await,
+            None if !self.provider.capabilities().supports_embeddings => Err(LettaError::Provider(format!(
+                "Provider {} can't embed text; give the agent an embedder with `Agent::with_embedder`",
+                self.provider.name(),
+            ).into())),
+            None => self.provider.embed_batched(texts, EMBED_BATCH_SIZE, EMBED_CONCURRENCY).await,
+        }
+    }
+    
+    /// `archival_search` in semantic mode, which needs the provider and so
+    /// can't run in the synchronous tool executor
+    async fn archival_search_tool(&mut self, args: &serde_json::Value) -> Result<ToolResult> {
+        let query = args.get("query")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| LettaError::ToolExecution("Missing 'query' parameter".into()))?;
+        let top_k = args.get("top_k")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(5) as usize;
+        
+        let results = self.search_archival_semantic(query, top_k).await?;
+        Ok(ToolResult::success(serde_json::json!({
+            "results": results,
+            "count": results.len()
+        })).with_heartbeat())
     }
     
     pub fn search_conversation(&self, query: &str, top_k: usize) -> Vec<Message> {
@@ -236,14 +665,104 @@ impl Agent {
             .collect()
     }
     
+    pub fn archival_stats(&self) -> ArchivalStats {
+        let mut folders = BTreeMap::new();
+        let mut total_chars = 0;
+        for entry in &self.state.archival_entries {
+            let folder = entry.get("folder").and_then(|f| f.as_str()).unwrap_or_default();
+            *folders.entry(folder.to_string()).or_default() += 1;
+            total_chars += entry.get("text").and_then(|t| t.as_str()).map_or(0, str::len);
+        }
+        ArchivalStats { entries: self.state.archival_entries.len(), total_chars, folders }
+    }
+    
+    pub fn tool_stats(&self) -> ToolStats {
+        ToolStats {
+            total_calls: self.state.tool_calls.values().sum(),
+            by_tool: self.state.tool_calls.iter().map(|(name, calls)| (name.clone(), *calls)).collect(),
+        }
+    }
+    
+    /// Tokens used by every completion so far, in total and by provider
+    pub fn usage(&self) -> AgentUsage {
+        AgentUsage {
+            total: self.state.usage_totals.clone(),
+            by_provider: self.state.usage_by_provider.clone(),
+        }
+    }
+    
+    /// Usage and health figures, cheap enough to poll
+    pub fn stats(&self) -> AgentStats {
+        AgentStats {
+            id: self.state.id.clone(),
+            name: self.state.name.clone(),
+            created_at: self.state.created_at,
+            updated_at: self.state.updated_at,
+            messages: self.state.messages.stats(),
+            usage: self.state.usage_totals.clone(),
+            memory: self.state.memory.usage(),
+            archival: self.archival_stats(),
+            tools: self.tool_stats(),
+        }
+    }
+    
     pub fn clear_messages(&mut self) {
         self.state.messages.clear();
         self.state.updated_at = Utc::now();
     }
     
+    /// A copy of the agent whose history ends at `message_id`, to try the
+    /// conversation another way while this one is kept. The fork shares the
+    /// provider but gets new agent and message ids; its `state.metadata`
+    /// records `parent_agent_id` and `forked_at_message`. A fork at a tool
+    /// result or tool call ends at the user message before it instead, so no
+    /// call is left without its result. With storage attached, the fork is
+    /// saved as a new agent and saves its own steps.
+    pub fn fork_at(&self, message_id: &str) -> Result<Agent> {
+        let messages = &self.state.messages.messages;
+        let index = messages.iter().position(|m| m.id == message_id)
+            .ok_or_else(|| LettaError::InvalidConfig(format!("Message {} is not in the agent's history", message_id)))?;
+        let end = if messages[index].role == MessageRole::Tool || messages[index].tool_calls.is_some() {
+            messages[..index].iter().rposition(|m| m.role == MessageRole::User).map_or(0, |i| i + 1)
+        } else {
+            index + 1
+        };
+        
+        let now = Utc::now();
+        let mut state = self.state.clone();
+        state.id = Uuid::new_v4().to_string();
+        state.created_at = now;
+        state.updated_at = now;
+        state.messages.messages.truncate(end);
+        for message in &mut state.messages.messages {
+            message.id = Uuid::new_v4().to_string();
+        }
+        if !state.metadata.is_object() {
+            state.metadata = serde_json::json!({});
+        }
+        state.metadata["parent_agent_id"] = self.state.id.clone().into();
+        state.metadata["forked_at_message"] = message_id.into();
+        
+        let fork = Agent {
+            config: self.config.clone(),
+            state,
+            context: self.context.clone(),
+            tool_executor: ToolExecutor::new(),
+            provider: self.provider.clone(),
+            embedder: self.embedder.clone(),
+            #[cfg(feature = "storage")]
+            flush: None,
+            #[cfg(feature = "storage")]
+            attached: None,
+        };
+        #[cfg(feature = "storage")]
+        let fork = fork.attached_like(self)?;
+        Ok(fork)
+    }
+    
     pub fn export_state(&self) -> Result<String> {
         serde_json::to_string_pretty(&self.state)
-            .map_err(|e| LettaError::Serialization(e))
+            .map_err(LettaError::Serialization)
     }
     
     pub fn import_state(&mut self, json: &str) -> Result<()> {
@@ -253,22 +772,169 @@ impl Agent {
     }
 }
 
+/// What a step has done so far, for its metrics
+#[derive(Default)]
+struct StepTotals {
+    iterations: usize,
+    usage: TokenUsage,
+    tool_calls: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StepResult {
     pub text: String,
-    pub tool_trace: Vec<serde_json::Value>,
+    pub tool_trace: Vec<ToolTraceEntry>,
     pub usage: crate::provider::TokenUsage,
+    /// Problems that didn't fail the step, such as a failed save to
+    /// attached storage
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub stop_reason: StopReason,
+}
+
+/// Settings for one step that differ from the agent's config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StepOptions {
+    /// Sampling temperature in place of `AgentConfig::temperature`
+    #[serde(default)]
+    pub temperature: Option<f32>,
+}
+
+impl StepOptions {
+    /// Check that the settings are usable, as `AgentConfig::validate` does
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature.filter(|t| !(0.0..=2.0).contains(t)) {
+            return Err(LettaError::InvalidConfig(format!("temperature {} is outside 0.0..=2.0", temperature)));
+        }
+        Ok(())
+    }
+}
+
+/// A tool call made during a step. Serializes with the `tool`, `args` and
+/// `result` keys traces have always had, plus the fields added since.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolTraceEntry {
+    pub tool: String,
+    #[serde(rename = "args", alias = "arguments")]
+    pub arguments: serde_json::Value,
+    pub result: serde_json::Value,
+    /// Whether the tool reported success; a failed call still gives a result
+    #[serde(default)]
+    pub success: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time spent running the tool
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// The provider call, counting from 1, that asked for the tool
+    #[serde(default)]
+    pub iteration: usize,
+}
+
+/// Why a step ended
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The model gave its final reply
+    #[default]
+    Completed,
+    /// Tools kept asking to continue until `max_tool_iterations` ran out;
+    /// `tool_trace` holds everything they did
+    MaxIterations,
+    /// The step was cancelled. `step_with_cancel` fails with
+    /// `LettaError::Cancelled` instead; this is for hosts recording the
+    /// outcome of every step.
+    Cancelled,
+}
+
+/// Archival memory size, with entry counts by folder
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArchivalStats {
+    pub entries: usize,
+    pub total_chars: usize,
+    pub folders: BTreeMap<String, usize>,
+}
+
+/// Tool calls made by the agent, by tool name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ToolStats {
+    pub total_calls: u64,
+    pub by_tool: BTreeMap<String, u64>,
+}
+
+/// Snapshot returned by `Agent::usage`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AgentUsage {
+    pub total: TokenUsage,
+    pub by_provider: BTreeMap<String, TokenUsage>,
+}
+
+/// Snapshot returned by `Agent::stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStats {
+    pub id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub messages: MessageStats,
+    pub usage: TokenUsage,
+    pub memory: MemoryUsage,
+    pub archival: ArchivalStats,
+    pub tools: ToolStats,
+}
+
+/// The tokenizer `config` names, or the heuristic one if it can't be built;
+/// `AgentConfig::validate` reports why
+fn build_tokenizer(config: &TokenizerConfig) -> Arc<dyn Tokenizer> {
+    config.build().unwrap_or_else(|e| {
+        tracing::warn!(error = %e, "Falling back to the heuristic tokenizer");
+        Arc::new(HeuristicTokenizer)
+    })
+}
+
+/// Run `request` as a stream, passing text deltas to `on_event` as they
+/// arrive
+async fn stream_completion(
+    provider: &dyn LlmProvider,
+    request: CompletionRequest,
+    on_event: &mut (dyn FnMut(StepEvent) + Send),
+) -> Result<Completion> {
+    use futures::StreamExt;
+    
+    let mut stream = provider.complete_stream(request).await?;
+    let mut completion = CompletionAccumulator::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if let CompletionChunk::Text { delta } = &chunk {
+            on_event(StepEvent::TextDelta { text: delta.clone() });
+        }
+        completion.push(chunk)?;
+    }
+    completion.finish()
+}
+
+/// Incremental output of `Agent::step_stream`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StepEvent {
+    TextDelta { text: String },
+    ToolCall {
+        tool: String,
+        args: serde_json::Value,
+        result: serde_json::Value,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::provider::{ToyProvider, ToyConfig};
+    use crate::provider::{ProviderCapabilities, ScriptedTurn, ToyConfig, ToyEmbedder, ToyProvider};
     
     #[tokio::test]
     async fn test_agent_creation() {
         let config = AgentConfig::default();
-        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true }));
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
         let agent = Agent::new(config, provider);
         
         assert_eq!(agent.state.name, "assistant");
@@ -278,17 +944,631 @@ mod tests {
     #[tokio::test]
     async fn test_agent_step() {
         let config = AgentConfig::default();
-        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true }));
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
         let mut agent = Agent::new(config, provider);
         
         let result = agent.step("Hello!".to_string()).await.unwrap();
         assert!(!result.text.is_empty());
     }
     
+    #[tokio::test]
+    async fn test_agent_step_stream() {
+        let config = AgentConfig::default();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(config, provider);
+        
+        let mut streamed = String::new();
+        let mut deltas = 0;
+        let result = agent.step_stream("Hello!".to_string(), |event| {
+            if let StepEvent::TextDelta { text } = event {
+                streamed.push_str(&text);
+                deltas += 1;
+            }
+        }).await.unwrap();
+        
+        assert!(deltas > 1);
+        assert_eq!(streamed, result.text);
+    }
+    
+    #[tokio::test]
+    async fn test_send_then_reply() {
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        let id = agent.send_only("Hello!".to_string());
+        let last = agent.state.messages.messages.last().unwrap();
+        assert_eq!(last.id, id);
+        assert_eq!(last.role, MessageRole::User);
+        
+        let result = agent.reply_only().await.unwrap();
+        assert!(!result.text.is_empty());
+        let roles: Vec<_> = agent.state.messages.messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, vec![MessageRole::User, MessageRole::Assistant]);
+    }
+    
+    #[tokio::test]
+    async fn test_scripted_heartbeat_and_error_recovery() {
+        let script: Vec<ScriptedTurn> = serde_json::from_value(serde_json::json!([
+            { "tool_calls": [{ "name": "memory_append", "arguments": { "label": "human", "text": "Likes tea." } }], "request_heartbeat": true },
+            { "text": "Noted." },
+            { "error": "model overloaded" },
+        ])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        let result = agent.step("I like tea".to_string()).await.unwrap();
+        assert_eq!(result.text, "Noted.");
+        assert_eq!(result.tool_trace[0].tool, "memory_append");
+        assert!(agent.get_memory_block("human").unwrap().contains("Likes tea."));
+        
+        // A failed call leaves the agent usable, and the script then runs out
+        assert!(matches!(agent.step("Still there?".to_string()).await, Err(LettaError::Provider(e)) if e.message == "model overloaded"));
+        let result = agent.step("Hello?".to_string()).await.unwrap();
+        assert!(result.tool_trace.is_empty() && !result.text.is_empty());
+    }
+    
+    #[test]
+    fn test_tool_trace_keeps_its_json_shape() {
+        let old: ToolTraceEntry = serde_json::from_value(serde_json::json!({
+            "tool": "archival_search",
+            "args": { "query": "tea" },
+            "result": [],
+        })).unwrap();
+        assert_eq!(old.arguments["query"], "tea");
+        assert_eq!((old.duration_ms, old.iteration), (0, 0));
+        
+        let json = serde_json::to_value(ToolTraceEntry { success: true, duration_ms: 4, iteration: 2, ..old }).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "tool": "archival_search",
+            "args": { "query": "tea" },
+            "result": [],
+            "success": true,
+            "duration_ms": 4,
+            "iteration": 2,
+        }));
+    }
+    
+    #[tokio::test]
+    async fn test_iteration_limit_keeps_tool_work() {
+        let turn = serde_json::json!({ "tool_calls": [{ "name": "memory_append", "arguments": { "label": "human", "text": "Again." } }], "request_heartbeat": true });
+        let script: Vec<ScriptedTurn> = serde_json::from_value(serde_json::json!([turn, turn, turn, turn])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig { max_tool_iterations: 3, ..Default::default() }, provider);
+        
+        let result = agent.step("Keep going".to_string()).await.unwrap();
+        assert_eq!(result.stop_reason, StopReason::MaxIterations);
+        assert!(result.text.contains("3 iterations"), "{}", result.text);
+        assert_eq!(result.tool_trace.len(), 3);
+        assert!(result.tool_trace.iter().all(|call| call.tool == "memory_append"));
+        let iterations: Vec<_> = result.tool_trace.iter().map(|call| call.iteration).collect();
+        assert_eq!(iterations, [1, 2, 3]);
+        assert_eq!(agent.get_memory_block("human").unwrap().matches("Again.").count(), 3);
+        assert_eq!(serde_json::to_value(&result).unwrap()["stop_reason"], "max_iterations");
+        
+        // The script's last turn, then its usual reply
+        let result = agent.step("Now stop".to_string()).await.unwrap();
+        assert_eq!(result.stop_reason, StopReason::Completed);
+        assert_eq!(result.tool_trace.len(), 1);
+        
+        assert!(AgentConfig { max_tool_iterations: 0, ..Default::default() }.validate().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_injected_failures_fail_only_their_steps() {
+        let config = ToyConfig { seed: Some(42), fail_every_n: Some(3), ..Default::default() };
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(ToyProvider::new(config)));
+        
+        let mut failed = Vec::new();
+        for i in 0..6 {
+            match agent.step(format!("Message {}", i)).await {
+                Ok(result) => assert!(!result.text.is_empty()),
+                Err(LettaError::Provider(e)) => {
+                    assert!(e.message.starts_with("Simulated failure"), "{}", e);
+                    failed.push(i);
+                }
+                Err(other) => panic!("{:?}", other),
+            }
+        }
+        assert_eq!(failed, [2, 5]);
+        
+        // A failed step keeps its user message, and nothing else
+        let roles: Vec<_> = agent.state.messages.messages.iter().map(|m| m.role.clone()).collect();
+        let exchange = [MessageRole::User, MessageRole::Assistant];
+        let expected: Vec<_> = [&exchange[..], &exchange, &[MessageRole::User], &exchange, &exchange, &[MessageRole::User]].concat();
+        assert_eq!(roles, expected);
+    }
+    
+    #[tokio::test]
+    async fn test_failed_tool_call_appends_nothing() {
+        let script: Vec<ScriptedTurn> = serde_json::from_value(serde_json::json!([{
+            "tool_calls": [
+                { "name": "memory_append", "arguments": { "label": "human", "text": "Likes tea." } },
+                { "name": "no_such_tool" },
+            ],
+            "request_heartbeat": true,
+        }])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        assert!(matches!(agent.step("I like tea".to_string()).await, Err(LettaError::ToolExecution(_))));
+        let roles: Vec<_> = agent.state.messages.messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, vec![MessageRole::User]);
+    }
+    
+    /// Calls `archival_search`, then never answers
+    struct Stalling(std::sync::atomic::AtomicUsize);
+    
+    #[cfg_attr(feature = "wasm", async_trait::async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait::async_trait)]
+    impl LlmProvider for Stalling {
+        async fn complete(&self, _request: CompletionRequest) -> Result<Completion> {
+            if self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) > 0 {
+                return futures::future::pending().await;
+            }
+            Ok(Completion {
+                tool_calls: vec![crate::tool::ToolCall {
+                    id: "call_1".into(),
+                    name: "archival_search".into(),
+                    arguments: serde_json::json!({ "query": "notes" }),
+                }],
+                request_heartbeat: true,
+                ..Completion::text("")
+            })
+        }
+        
+        fn name(&self) -> &str {
+            "stalling"
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_cancelled_step_keeps_only_the_user_message() {
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(Stalling(Default::default())));
+        agent.send_only("Hello!".to_string());
+        
+        // Cancelled while waiting on the completion after a tool call
+        let cancel = CancellationToken::new();
+        let (result, ()) = tokio::join!(
+            agent.step_with_cancel("Find my notes".to_string(), &cancel),
+            async { cancel.cancel() },
+        );
+        assert!(matches!(result, Err(LettaError::Cancelled)));
+        let messages = &agent.state.messages.messages;
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[1].role, MessageRole::User));
+        assert_eq!(messages[1].content, "Find my notes");
+        assert_eq!(agent.state.tool_calls["archival_search"], 1);
+        
+        // A cancelled token stops the next step before it asks the provider
+        assert!(matches!(agent.reply_only_with_cancel(&cancel).await, Err(LettaError::Cancelled)));
+        assert_eq!(agent.state.messages.messages.len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_check_provider() {
+        let health = toy_agent().check_provider().await.unwrap();
+        assert_eq!(health.provider, "toy");
+        
+        let script = vec![ScriptedTurn { error: Some("invalid API key".into()), ..Default::default() }];
+        let provider = Box::new(ToyProvider::new(ToyConfig { script, ..Default::default() }));
+        let agent = Agent::new(AgentConfig::default(), provider);
+        assert!(matches!(agent.check_provider().await, Err(LettaError::Provider(e)) if e.message == "invalid API key"));
+    }
+    
+    #[tokio::test]
+    async fn test_update_config() {
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        let mut config = agent.config.clone();
+        config.name = "renamed".into();
+        config.temperature = 0.1;
+        agent.update_config(config).unwrap();
+        assert_eq!(agent.state.name, "renamed");
+        assert_eq!(agent.config.temperature, 0.1);
+        
+        let mut invalid = agent.config.clone();
+        invalid.temperature = 5.0;
+        assert!(matches!(agent.update_config(invalid), Err(LettaError::InvalidConfig(_))));
+        assert_eq!(agent.config.temperature, 0.1);
+    }
+    
+    /// Answers every prompt with "Hello there", remembering the requests
+    struct Recorder(Arc<std::sync::Mutex<Vec<CompletionRequest>>>);
+    
+    #[cfg_attr(feature = "wasm", async_trait::async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait::async_trait)]
+    impl LlmProvider for Recorder {
+        async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+            self.0.lock().unwrap().push(request);
+            Ok(Completion::text("Hello there"))
+        }
+        
+        fn name(&self) -> &str {
+            "recorder"
+        }
+    }
+    
+    /// A recorder claiming only the given capabilities
+    struct Limited(Recorder, ProviderCapabilities);
+    
+    #[cfg_attr(feature = "wasm", async_trait::async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait::async_trait)]
+    impl LlmProvider for Limited {
+        async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+            self.0.complete(request).await
+        }
+        
+        fn name(&self) -> &str {
+            "limited"
+        }
+        
+        fn capabilities(&self) -> ProviderCapabilities {
+            self.1
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_capabilities_shape_requests() {
+        // A plain text-completion model gets no tool schemas and no embedding calls
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let text_only = Limited(Recorder(requests.clone()), ProviderCapabilities::default());
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(text_only));
+        agent.add_archival("pets", "The cat needs her vaccination booster");
+        assert_eq!(agent.step("Hello!".to_string()).await.unwrap().text, "Hello there");
+        assert!(requests.lock().unwrap()[0].tools.is_empty());
+        
+        let error = agent.search_archival_semantic("cat", 1).await.unwrap_err();
+        assert!(matches!(&error, LettaError::Provider(e) if e.message.contains("can't embed")), "{}", error);
+        let mut agent = agent.with_embedder(Box::new(ToyEmbedder));
+        assert_eq!(agent.search_archival_semantic("cat", 1).await.unwrap().len(), 1);
+        
+        // Tools beyond the provider's limit are left out
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let capabilities = ProviderCapabilities { supports_tools: true, max_tools: Some(2), ..Default::default() };
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(Limited(Recorder(requests.clone()), capabilities)));
+        agent.step("Hello!".to_string()).await.unwrap();
+        assert_eq!(requests.lock().unwrap()[0].tools.len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_regenerate_replaces_the_last_reply() {
+        let script: Vec<ScriptedTurn> = serde_json::from_value(serde_json::json!([
+            { "text": "Hi." },
+            { "tool_calls": [{ "name": "archival_search", "arguments": { "query": "tea" } }], "request_heartbeat": true },
+            { "text": "No tea found." },
+            { "text": "Nothing about tea." },
+            { "error": "model overloaded" },
+            { "tool_calls": [{ "name": "archival_search", "arguments": { "query": "tea" } }], "request_heartbeat": true },
+            { "error": "model overloaded" },
+        ])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        assert!(matches!(agent.regenerate(None).await, Err(LettaError::InvalidConfig(_))));
+        
+        agent.step("Hello".to_string()).await.unwrap();
+        agent.step("Any tea?".to_string()).await.unwrap();
+        let roles: Vec<_> = agent.state.messages.messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(&roles[2..], [MessageRole::User, MessageRole::Assistant, MessageRole::Tool, MessageRole::Assistant]);
+        
+        // The tool call and its result go along with the reply
+        let result = agent.regenerate(None).await.unwrap();
+        assert_eq!(result.text, "Nothing about tea.");
+        let contents: Vec<_> = agent.state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Hello", "Hi.", "Any tea?", "Nothing about tea."]);
+        
+        // A failed attempt keeps the reply it would have replaced, and none
+        // of its own tool calls, whichever iteration fails
+        for _ in 0..2 {
+            assert!(agent.regenerate(None).await.is_err());
+            let contents: Vec<_> = agent.state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+            assert_eq!(contents, ["Hello", "Hi.", "Any tea?", "Nothing about tea."]);
+        }
+        
+        // Nothing to replace once the last user message is unanswered
+        agent.send_only("More?".to_string());
+        assert!(matches!(agent.regenerate(None).await, Err(LettaError::InvalidConfig(_))));
+        
+        // Options only apply to the new reply
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut agent = Agent::new(AgentConfig { temperature: 0.7, ..Default::default() }, Box::new(Recorder(requests.clone())));
+        agent.step("Hi".to_string()).await.unwrap();
+        agent.regenerate(Some(StepOptions { temperature: Some(1.5) })).await.unwrap();
+        agent.regenerate(None).await.unwrap();
+        let temperatures: Vec<_> = requests.lock().unwrap().iter().map(|r| r.temperature).collect();
+        assert_eq!(temperatures, [Some(0.7), Some(1.5), Some(0.7)]);
+        assert_eq!(agent.state.messages.messages.len(), 2);
+        assert!(matches!(agent.regenerate(Some(StepOptions { temperature: Some(3.0) })).await, Err(LettaError::InvalidConfig(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_fork_at_message() {
+        let script: Vec<ScriptedTurn> = serde_json::from_value(serde_json::json!([
+            { "text": "Hi." },
+            { "tool_calls": [{ "name": "archival_search", "arguments": { "query": "tea" } }], "request_heartbeat": true },
+            { "text": "No tea found." },
+            { "text": "Coffee, then." },
+        ])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        agent.step("Hello".to_string()).await.unwrap();
+        agent.step("Any tea?".to_string()).await.unwrap();
+        let ids: Vec<_> = agent.state.messages.messages.iter().map(|m| m.id.clone()).collect();
+        
+        let mut fork = agent.fork_at(&ids[1]).unwrap();
+        assert_ne!(fork.state.id, agent.state.id);
+        assert_eq!(fork.state.metadata["parent_agent_id"], agent.state.id.as_str());
+        assert_eq!(fork.state.metadata["forked_at_message"], ids[1].as_str());
+        assert_eq!(fork.get_memory_block("human").as_deref(), Some("Likes tea"));
+        let contents: Vec<_> = fork.state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Hello", "Hi."]);
+        assert!(fork.state.messages.messages.iter().all(|m| !ids.contains(&m.id)));
+        
+        // The fork goes its own way, with the provider they share
+        assert_eq!(fork.step("Coffee?".to_string()).await.unwrap().text, "Coffee, then.");
+        assert_eq!(agent.state.messages.messages.len(), 6);
+        
+        // At a tool call or its result, the fork ends at the user message before
+        for tool_message in &ids[3..5] {
+            let fork = agent.fork_at(tool_message).unwrap();
+            let contents: Vec<_> = fork.state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+            assert_eq!(contents, ["Hello", "Hi.", "Any tea?"]);
+        }
+        
+        assert!(matches!(agent.fork_at("no-such-message"), Err(LettaError::InvalidConfig(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_stop_sequences_and_max_response_tokens() {
+        let config = AgentConfig {
+            max_response_tokens: Some(64),
+            stop_sequences: vec!["How can".into(), "\nUser:".into()],
+            ..Default::default()
+        };
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut agent = Agent::new(config.clone(), Box::new(Recorder(requests.clone())));
+        agent.step("Hi".to_string()).await.unwrap();
+        let sent = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(sent.max_tokens, Some(64));
+        assert_eq!(sent.stop, config.stop_sequences);
+        
+        // The toy provider cuts its reply at the first stop sequence
+        let mut agent = Agent::new(config.clone(), Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() })));
+        let result = agent.step("Hi".to_string()).await.unwrap();
+        assert_eq!(result.text, "I understand your request. ");
+        
+        let invalid = AgentConfig { stop_sequences: vec![String::new()], ..config.clone() };
+        assert!(matches!(invalid.validate(), Err(LettaError::InvalidConfig(_))));
+        let invalid = AgentConfig { max_response_tokens: Some(0), ..config };
+        assert!(matches!(invalid.validate(), Err(LettaError::InvalidConfig(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_agent_stats() {
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.add_archival("notes", "Walked 5km");
+        agent.add_archival("notes", "Slept well");
+        
+        let first = agent.step("Hello!".to_string()).await.unwrap();
+        let second = agent.step("How are you?".to_string()).await.unwrap();
+        
+        let stats = agent.stats();
+        assert_eq!(stats.messages, agent.state.messages.stats());
+        assert_eq!(stats.messages.by_role["user"], 2);
+        assert_eq!(stats.usage.total_tokens, first.usage.total_tokens + second.usage.total_tokens);
+        assert_eq!(stats.memory, agent.state.memory.usage());
+        assert_eq!(stats.archival.entries, 2);
+        assert_eq!(stats.archival.folders["notes"], 2);
+        assert_eq!(stats.tools.total_calls, stats.tools.by_tool.values().sum::<u64>());
+    }
+    
+    #[tokio::test]
+    async fn test_usage_sums_every_completion() {
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        // A search call, then a summary of its results
+        let search = agent.step("#DO_SEARCH".to_string()).await.unwrap();
+        let summary_tokens = HeuristicTokenizer.count_tokens(&search.text);
+        assert_eq!(search.usage.completion_tokens, 10 + summary_tokens);
+        assert!(search.usage.prompt_tokens > 0);
+        assert_eq!(search.usage.total_tokens, search.usage.prompt_tokens + search.usage.completion_tokens);
+        assert_eq!(agent.usage().total, search.usage);
+        
+        let hello = agent.step("Hello!".to_string()).await.unwrap();
+        let usage = agent.usage();
+        assert_eq!(usage.total.total_tokens, search.usage.total_tokens + hello.usage.total_tokens);
+        assert_eq!(usage.by_provider.len(), 1);
+        assert_eq!(usage.by_provider["toy"], usage.total);
+        
+        // States saved before the per-provider split still load their totals
+        let mut saved: serde_json::Value = serde_json::from_str(&agent.export_state().unwrap()).unwrap();
+        let totals = saved.as_object_mut().unwrap().remove("usage_totals").unwrap();
+        saved["usage"] = totals;
+        saved.as_object_mut().unwrap().remove("usage_by_provider");
+        agent.import_state(&saved.to_string()).unwrap();
+        assert_eq!(agent.usage().total, usage.total);
+        assert!(agent.usage().by_provider.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_step_metrics() {
+        let metrics = std::sync::Arc::new(crate::telemetry::InMemoryMetrics::new());
+        crate::telemetry::set_metrics(metrics.clone());
+        // The call after the capped step fails
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, fail_every_n: Some(12), ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        
+        let result = agent.step("Hello!".to_string()).await.unwrap();
+        let snapshot = metrics.agent_snapshot(&agent.state.id);
+        assert_eq!((snapshot.steps, snapshot.failed_steps, snapshot.provider_calls), (1, 0, 1));
+        assert_eq!(snapshot.usage, result.usage);
+        assert_eq!(snapshot.step_latency.count, 1);
+        
+        // The toy provider keeps asking for the tool until the step gives up
+        let capped = agent.step("#MEMORY_UPDATE".to_string()).await.unwrap();
+        assert_eq!(capped.stop_reason, StopReason::MaxIterations);
+        let snapshot = metrics.agent_snapshot(&agent.state.id);
+        assert_eq!((snapshot.steps, snapshot.failed_steps), (2, 0));
+        assert_eq!(snapshot.provider_calls, 11);
+        assert_eq!(snapshot.tools["memory_replace"].calls, 10);
+        assert_eq!(snapshot.usage, agent.state.usage_totals);
+        
+        assert!(agent.step("Hello?".to_string()).await.is_err());
+        let snapshot = metrics.agent_snapshot(&agent.state.id);
+        assert_eq!((snapshot.steps, snapshot.failed_steps), (3, 1));
+    }
+    
+    #[tokio::test]
+    async fn test_disabled_tools() {
+        let config = AgentConfig { disabled_tools: vec!["memory_replace".into()], ..Default::default() };
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(config, provider);
+        let human = agent.get_memory_block("human");
+        
+        let error = agent.step("#MEMORY_UPDATE".to_string()).await.unwrap_err();
+        assert_eq!(error.to_string(), "Tool execution error: Tool memory_replace is disabled");
+        assert_eq!(agent.get_memory_block("human"), human);
+    }
+    
+    fn toy_agent() -> Agent {
+        Agent::new(AgentConfig::default(), Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() })))
+    }
+    
+    #[tokio::test]
+    async fn test_semantic_archival_ranking_is_stable() {
+        let mut agent = toy_agent();
+        agent.add_archival_embedded("health", "Blood glucose reading of 112 after breakfast").await.unwrap();
+        agent.add_archival_embedded("travel", "Flight to Lisbon departs Tuesday morning").await.unwrap();
+        // Added without a vector; embedded when first searched
+        agent.add_archival("health", "Glucose reading 168 before dinner");
+        agent.add_archival("pets", "The cat needs her vaccination booster");
+        
+        let results = agent.search_archival_semantic("glucose reading", 2).await.unwrap();
+        let texts: Vec<_> = results.iter().map(|r| r["text"].as_str().unwrap()).collect();
+        assert_eq!(texts, ["Glucose reading 168 before dinner", "Blood glucose reading of 112 after breakfast"]);
+        assert!(results[0]["score"].as_f64().unwrap() >= results[1]["score"].as_f64().unwrap());
+        assert!(results[0].get("embedding").is_none());
+        assert!(agent.state.archival_entries.iter().all(|e| archival::embedding(e, "toy").is_some()));
+        
+        for _ in 0..3 {
+            assert_eq!(agent.search_archival_semantic("glucose reading", 2).await.unwrap(), results);
+        }
+        let flight = agent.search_archival_semantic("when is my flight", 1).await.unwrap();
+        assert_eq!(flight[0]["folder"], "travel");
+        
+        // Keyword search is unchanged, and doesn't match across word order
+        assert!(agent.search_archival("reading glucose", 5).is_empty());
+        assert_eq!(agent.search_archival("glucose", 5).len(), 2);
+    }
+    
+    /// A provider without embeddings of its own
+    struct Unembedded;
+    
+    #[cfg_attr(feature = "wasm", async_trait::async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait::async_trait)]
+    impl LlmProvider for Unembedded {
+        async fn complete(&self, _request: CompletionRequest) -> Result<Completion> {
+            Ok(Completion::text("Noted"))
+        }
+        
+        fn name(&self) -> &str {
+            "unembedded"
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_separate_embedder_serves_archival() {
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(Unembedded)).with_embedder(Box::new(ToyEmbedder));
+        agent.add_archival_embedded("travel", "Flight to Lisbon departs Tuesday morning").await.unwrap();
+        agent.add_archival("pets", "The cat needs her vaccination booster");
+        
+        let results = agent.search_archival_semantic("vaccination for the cat", 1).await.unwrap();
+        assert_eq!(results[0]["folder"], "pets");
+        assert!(agent.state.archival_entries.iter().all(|e| archival::embedding(e, "toy").is_some()));
+        assert!(agent.state.archival_entries.iter().all(|e| archival::embedding(e, "unembedded").is_none()));
+    }
+    
+    #[tokio::test]
+    async fn test_import_archival_in_batches() {
+        let chunks: Vec<String> = (0..150).map(|i| format!("Chapter {} of the manual", i)).collect();
+        let text_only = Limited(Recorder(Default::default()), ProviderCapabilities::default());
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(text_only));
+        assert!(agent.import_archival("manual", chunks.clone()).await.is_err());
+        assert!(agent.state.archival_entries.is_empty());
+        
+        let mut agent = agent.with_embedder(Box::new(ToyEmbedder));
+        agent.import_archival("manual", chunks).await.unwrap();
+        assert_eq!(agent.state.archival_entries.len(), 150);
+        assert!(agent.state.archival_entries.iter().all(|e| archival::embedding(e, "toy").is_some()));
+        assert_eq!(agent.state.archival_entries[149]["text"], "Chapter 149 of the manual");
+    }
+    
+    /// Searches archival memory semantically, then answers
+    struct SemanticSearcher(ToyProvider);
+    
+    #[cfg_attr(feature = "wasm", async_trait::async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait::async_trait)]
+    impl LlmProvider for SemanticSearcher {
+        async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+            if request.prompt.contains("Tool [") {
+                return Ok(Completion::text("Found it"));
+            }
+            Ok(Completion {
+                tool_calls: vec![crate::tool::ToolCall {
+                    id: "call_1".into(),
+                    name: "archival_search".into(),
+                    arguments: serde_json::json!({ "query": "vaccination for the cat", "top_k": 1, "mode": "semantic" }),
+                }],
+                request_heartbeat: true,
+                ..Completion::text("")
+            })
+        }
+        
+        async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            self.0.embed(texts).await
+        }
+        
+        fn name(&self) -> &str {
+            "toy"
+        }
+        
+        fn capabilities(&self) -> ProviderCapabilities {
+            self.0.capabilities()
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_archival_search_tool_modes() {
+        let provider = Box::new(SemanticSearcher(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() })));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.add_archival("pets", "The cat needs her vaccination booster");
+        agent.add_archival("travel", "Flight to Lisbon departs Tuesday morning");
+        
+        let result = agent.step("Does the cat need anything?".to_string()).await.unwrap();
+        assert_eq!(result.text, "Found it");
+        let tool_result: serde_json::Value = serde_json::from_str(&agent.state.messages.get_recent(10).into_iter()
+            .find(|m| m.role == MessageRole::Tool)
+            .unwrap()
+            .content).unwrap();
+        assert_eq!(tool_result["count"], 1);
+        assert_eq!(tool_result["results"][0]["folder"], "pets");
+        
+        // Without a mode the executor does a keyword search, and can't do a
+        // semantic one itself
+        let call = |arguments| crate::tool::ToolCall { id: "call_2".into(), name: "archival_search".into(), arguments };
+        let keyword = agent.tool_executor.execute(&call(serde_json::json!({ "query": "Lisbon" })), &mut agent.state).unwrap();
+        assert_eq!(keyword.result["results"][0]["folder"], "travel");
+        assert!(agent.tool_executor.execute(&call(serde_json::json!({ "query": "x", "mode": "semantic" })), &mut agent.state).is_err());
+        assert!(agent.tool_executor.execute(&call(serde_json::json!({ "query": "x", "mode": "fuzzy" })), &mut agent.state).is_err());
+    }
+    
     #[tokio::test]
     async fn test_memory_operations() {
         let config = AgentConfig::default();
-        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true }));
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
         let mut agent = Agent::new(config, provider);
         
         agent.set_memory_block("test", "test value").unwrap();
@@ -0,0 +1,140 @@
+//! Searching archival entries, the `{"folder", "text", "timestamp"}` JSON
+//! objects in `AgentState::archival_entries`. Entries added with
+//! `Agent::add_archival_embedded`, or found by a semantic search, also carry
+//! an `embedding` and the `embedding_model` that produced it; search results
+//! leave both out.
+
+use serde_json::Value;
+
+pub const EMBEDDING: &str = "embedding";
+pub const EMBEDDING_MODEL: &str = "embedding_model";
+
+/// How `archival_search` matches entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchMode {
+    /// Case-insensitive substring match, in insertion order
+    Keyword,
+    /// Ranked by cosine similarity of embeddings
+    Semantic,
+}
+
+impl SearchMode {
+    /// The `mode` argument of the `archival_search` tool; keyword if absent
+    pub fn from_args(args: &Value) -> Option<Self> {
+        match args.get("mode").and_then(Value::as_str) {
+            None | Some("keyword") => Some(SearchMode::Keyword),
+            Some("semantic") => Some(SearchMode::Semantic),
+            Some(_) => None,
+        }
+    }
+}
+
+fn text(entry: &Value) -> Option<&str> {
+    entry.get("text").and_then(Value::as_str)
+}
+
+/// `entry` without its embedding
+fn view(entry: &Value) -> Value {
+    let mut entry = entry.clone();
+    if let Some(fields) = entry.as_object_mut() {
+        fields.remove(EMBEDDING);
+        fields.remove(EMBEDDING_MODEL);
+    }
+    entry
+}
+
+/// The first `top_k` entries whose text contains `query`, ignoring case
+pub fn keyword_search(entries: &[Value], query: &str, top_k: usize) -> Vec<Value> {
+    let query = query.to_lowercase();
+    entries.iter()
+        .filter(|entry| text(entry).is_some_and(|t| t.to_lowercase().contains(&query)))
+        .take(top_k)
+        .map(view)
+        .collect()
+}
+
+/// The stored embedding of `entry`, if `model` produced it
+pub fn embedding<'a>(entry: &'a Value, model: &str) -> Option<&'a [Value]> {
+    if entry.get(EMBEDDING_MODEL).and_then(Value::as_str) != Some(model) {
+        return None;
+    }
+    entry.get(EMBEDDING).and_then(Value::as_array).map(Vec::as_slice)
+}
+
+/// Record `vector` from `model` on `entry`
+pub fn set_embedding(entry: &mut Value, model: &str, vector: Vec<f32>) {
+    if let Some(fields) = entry.as_object_mut() {
+        fields.insert(EMBEDDING.to_string(), vector.into());
+        fields.insert(EMBEDDING_MODEL.to_string(), model.into());
+    }
+}
+
+/// The `top_k` entries embedded by `model` most similar to `query`, best
+/// first, each with its similarity as `score`. Ties keep insertion order.
+pub fn semantic_search(entries: &[Value], query: &[f32], model: &str, top_k: usize) -> Vec<Value> {
+    let mut scored: Vec<(f32, &Value)> = entries.iter()
+        .filter_map(|entry| {
+            let vector: Vec<f32> = embedding(entry, model)?.iter()
+                .map(|v| v.as_f64().map(|v| v as f32))
+                .collect::<Option<_>>()?;
+            Some((cosine_similarity(query, &vector)?, entry))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter()
+        .take(top_k)
+        .map(|(score, entry)| {
+            let mut entry = view(entry);
+            if let Some(fields) = entry.as_object_mut() {
+                fields.insert("score".to_string(), score.into());
+            }
+            entry
+        })
+        .collect()
+}
+
+/// `None` for vectors of different sizes or with no direction
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() {
+        return None;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    (norms > 0.0).then(|| dot / norms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_semantic_search_ranks_and_hides_vectors() {
+        let mut entries = vec![
+            json!({ "folder": "notes", "text": "east" }),
+            json!({ "folder": "notes", "text": "north" }),
+            json!({ "folder": "notes", "text": "north-east" }),
+            json!({ "folder": "notes", "text": "not embedded" }),
+        ];
+        set_embedding(&mut entries[0], "toy", vec![1.0, 0.0]);
+        set_embedding(&mut entries[1], "toy", vec![0.0, 1.0]);
+        set_embedding(&mut entries[2], "toy", vec![1.0, 1.0]);
+
+        let results = semantic_search(&entries, &[0.0, 2.0], "toy", 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["text"], "north");
+        assert_eq!(results[1]["text"], "north-east");
+        assert!((results[0]["score"].as_f64().unwrap() - 1.0).abs() < 1e-6);
+        assert!(results[0].get(EMBEDDING).is_none());
+
+        // Vectors from another model are not comparable
+        assert!(semantic_search(&entries, &[0.0, 1.0], "other", 5).is_empty());
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), None);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), None);
+
+        let results = keyword_search(&entries, "NORTH", 5);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].get(EMBEDDING_MODEL).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+//! Stopping a step from outside it, e.g. when the user taps "stop" while a
+//! slow provider is still answering. See `Agent::step_with_cancel`.
+//!
+//! No runtime is needed, so this works on wasm too. Awaiting completions
+//! are dropped when their token is cancelled, which aborts the HTTP
+//! requests behind them. Providers that generate locally also read the
+//! token from `CompletionRequest::cancel` and stop between tokens.
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Poll, Waker};
+
+use futures::future::{select, Either};
+
+use crate::error::{LettaError, Result};
+
+/// Cancels the work it was handed. Clones share one state, so keep a clone
+/// to cancel with. Cancelling can't be undone; use a new token per step.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// Tasks waiting in `cancelled`
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the token and every clone of it
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let wakers = std::mem::take(&mut *self.inner.wakers.lock().unwrap_or_else(PoisonError::into_inner));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// `LettaError::Cancelled` once cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(LettaError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Completes once the token is cancelled
+    pub async fn cancelled(&self) {
+        std::future::poll_fn(|cx| {
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            let mut wakers = self.inner.wakers.lock().unwrap_or_else(PoisonError::into_inner);
+            // Checked again under the lock, so a cancel in between still wakes us
+            if self.is_cancelled() {
+                return Poll::Ready(());
+            }
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }).await
+    }
+
+    /// The output of `future`, or `LettaError::Cancelled` if the token is
+    /// cancelled first, in which case `future` is dropped unfinished
+    pub async fn run<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        self.check()?;
+        match select(pin!(future), pin!(self.cancelled())).await {
+            Either::Left((output, _)) => output,
+            Either::Right(((), _)) => Err(LettaError::Cancelled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_drops_the_pending_future() {
+        let token = CancellationToken::new();
+        let stalled = token.run(futures::future::pending::<Result<()>>());
+        let (result, ()) = tokio::join!(stalled, async { token.clone().cancel() });
+        assert!(matches!(result, Err(LettaError::Cancelled)));
+        assert!(token.is_cancelled());
+
+        // Once cancelled, nothing more runs
+        assert!(matches!(token.run(async { Ok(1) }).await, Err(LettaError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn test_uncancelled_future_finishes() {
+        let token = CancellationToken::new();
+        assert_eq!(token.run(async { Ok(7) }).await.unwrap(), 7);
+        assert!(token.check().is_ok());
+    }
+
+    #[test]
+    fn test_cancel_from_another_thread() {
+        let token = CancellationToken::new();
+        let remote = token.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            remote.cancel();
+        });
+        futures::executor::block_on(token.cancelled());
+        canceller.join().unwrap();
+    }
+}
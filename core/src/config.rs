@@ -0,0 +1,449 @@
+//! Application config file: storage, provider, agent defaults, sync and
+//! tool policy in one TOML or JSON document, e.g.
+//!
+//! ```toml
+//! [storage]
+//! path = "letta.db"
+//!
+//! [provider]
+//! type = "openai"
+//! api_key = "${OPENAI_API_KEY}"
+//! model = "gpt-4o-mini"
+//!
+//! [agent]
+//! temperature = 0.2
+//!
+//! [tools]
+//! disabled = ["archival_insert"]
+//! ```
+//!
+//! String values may reference environment variables as `${NAME}` or
+//! `${NAME:-fallback}`. Variables named `LETTA_<SECTION>__<KEY>` override
+//! single keys, e.g. `LETTA_STORAGE__PATH=/tmp/a.db` or
+//! `LETTA_SYNC__AUTH__TYPE=api_key`; their values are read as JSON when they
+//! parse as a number, boolean or array, and as strings otherwise.
+
+use std::collections::HashMap;
+use std::path::Path;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+#[cfg(feature = "storage")]
+use letta_storage::StorageConfig;
+use crate::{
+    agent::AgentConfig,
+    error::{LettaError, Result},
+    provider::{ProviderConfig, ToyConfig},
+    tool::ToolExecutor,
+};
+
+const SECTIONS: [&str; 5] = ["storage", "provider", "agent", "sync", "tools"];
+
+const ENV_PREFIX: &str = "LETTA_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format named by the file's extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Which built-in tools agents may use
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ToolPolicy {
+    /// Offer tools to the model at all
+    pub enabled: bool,
+    /// Tools the model is neither offered nor allowed to call
+    pub disabled: Vec<String>,
+}
+
+impl Default for ToolPolicy {
+    fn default() -> Self {
+        Self { enabled: true, disabled: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AppConfig {
+    /// `None` when the file has no `[storage]` section
+    #[cfg(feature = "storage")]
+    pub storage: Option<StorageConfig>,
+    pub provider: Option<ProviderConfig>,
+    /// Defaults for new agents; keys left out keep `AgentConfig::default()`
+    pub agent: AgentConfig,
+    /// The `[sync]` table, read by `letta_sync::SyncConfig::from_app_config`
+    pub sync: Option<Value>,
+    pub tools: ToolPolicy,
+}
+
+impl AppConfig {
+    /// Load a `.toml` or `.json` config file, with overrides from the process
+    /// environment
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with_env(path, std::env::vars())
+    }
+
+    /// `load` with the environment given as `vars`. A relative storage path is
+    /// taken relative to the file's directory.
+    pub fn load_with_env(path: impl AsRef<Path>, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path).ok_or_else(|| {
+            LettaError::InvalidConfig(format!("{}: expected a .toml or .json file", path.display()))
+        })?;
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("reading {}: {}", path.display(), e)))?;
+
+        #[cfg_attr(not(feature = "storage"), allow(unused_mut))]
+        let mut config = Self::parse(&text, format, vars).map_err(|e| match e {
+            LettaError::InvalidConfig(message) => LettaError::InvalidConfig(format!("{}: {}", path.display(), message)),
+            e => e,
+        })?;
+        #[cfg(feature = "storage")]
+        if let (Some(storage), Some(dir)) = (&mut config.storage, path.parent()) {
+            if storage.path.is_relative() {
+                storage.path = dir.join(&storage.path);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parse a config document; `vars` supplies `${NAME}` values and
+    /// `LETTA_*` overrides
+    pub fn parse(text: &str, format: ConfigFormat, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        let mut root = match format {
+            ConfigFormat::Toml => toml::from_str::<Value>(text).map_err(|e| invalid(format!("invalid TOML: {}", e)))?,
+            ConfigFormat::Json => serde_json::from_str::<Value>(text).map_err(|e| invalid(format!("invalid JSON: {}", e)))?,
+        };
+        let Value::Object(sections) = &mut root else {
+            return Err(invalid("expected a table of sections"));
+        };
+        if let Some(name) = sections.keys().find(|name| !SECTIONS.contains(&name.as_str())) {
+            return Err(invalid(format!("unknown section `{}`", name)));
+        }
+        for (name, value) in sections.iter_mut() {
+            interpolate(value, name, &vars)?;
+        }
+        apply_env_overrides(sections, &vars)?;
+
+        #[cfg(feature = "storage")]
+        let storage = sections.get("storage").map(|value| section("storage", value)).transpose()?;
+        #[cfg(not(feature = "storage"))]
+        if sections.contains_key("storage") {
+            return Err(invalid("`storage` needs letta-core's `storage` feature"));
+        }
+        let provider = sections.get("provider")
+            .map(|value| serde_json::from_value(value.clone()).map_err(|e| invalid(format!("`provider`: {}", e))))
+            .transpose()?;
+        let agent: AgentConfig = match sections.get("agent") {
+            Some(value) => section("agent", value)?,
+            None => AgentConfig::default(),
+        };
+        agent.validate().map_err(|e| match e {
+            LettaError::InvalidConfig(message) => invalid(format!("`agent`: {}", message)),
+            e => e,
+        })?;
+        let sync = match sections.get("sync") {
+            Some(value) if !value.is_object() => return Err(invalid("`sync` must be a table")),
+            sync => sync.cloned(),
+        };
+
+        let tools: ToolPolicy = match sections.get("tools") {
+            Some(value) => section("tools", value)?,
+            None => ToolPolicy::default(),
+        };
+        let known: Vec<String> = ToolExecutor::new().get_schemas().into_iter().map(|schema| schema.name).collect();
+        if let Some(name) = tools.disabled.iter().find(|name| !known.contains(name)) {
+            return Err(invalid(format!("`tools.disabled`: unknown tool `{}`", name)));
+        }
+
+        Ok(Self {
+            #[cfg(feature = "storage")]
+            storage,
+            provider,
+            agent,
+            sync,
+            tools,
+        })
+    }
+
+    #[cfg(feature = "storage")]
+    pub fn storage_config(&self) -> StorageConfig {
+        self.storage.clone().unwrap_or_default()
+    }
+
+    /// The configured provider, or the deterministic toy provider
+    pub fn provider_config(&self) -> ProviderConfig {
+        self.provider.clone().unwrap_or(ProviderConfig::Toy(ToyConfig { deterministic: true, ..Default::default() }))
+    }
+
+    /// Agent defaults with the tool policy applied
+    pub fn agent_config(&self) -> AgentConfig {
+        let mut config = self.agent.clone();
+        config.tools_enabled &= self.tools.enabled;
+        for name in &self.tools.disabled {
+            if !config.disabled_tools.contains(name) {
+                config.disabled_tools.push(name.clone());
+            }
+        }
+        config
+    }
+}
+
+/// Read the `name` section over `T`'s defaults, naming the offending key when
+/// a value has the wrong type or isn't a field of `T`
+pub fn section<T: Serialize + DeserializeOwned + Default>(name: &str, value: &Value) -> Result<T> {
+    let Value::Object(table) = value else {
+        return Err(invalid(format!("`{}` must be a table", name)));
+    };
+    let defaults = serde_json::to_value(T::default())?;
+    let read = |table: &Map<String, Value>| {
+        let mut merged = defaults.clone();
+        merge(&mut merged, table);
+        serde_json::from_value::<T>(merged)
+    };
+
+    let parsed = read(table).map_err(|e| {
+        // Read the keys one at a time to find the one at fault
+        let culprit = table.iter().find(|(key, value)| {
+            read(&Map::from_iter([((*key).clone(), (*value).clone())])).is_err()
+        });
+        match culprit {
+            Some((key, _)) => invalid(format!("`{}.{}`: {}", name, key, e)),
+            None => invalid(format!("`{}`: {}", name, e)),
+        }
+    })?;
+    // Serde skips keys it doesn't know, so look for any lost on the way through
+    find_unknown_key(&serde_json::to_value(&parsed)?, table, name)?;
+    Ok(parsed)
+}
+
+fn invalid(message: impl Into<String>) -> LettaError {
+    LettaError::InvalidConfig(message.into())
+}
+
+/// Overlay `table` onto `target`, recursing into tables present in both
+fn merge(target: &mut Value, table: &Map<String, Value>) {
+    let Value::Object(target) = target else {
+        *target = Value::Object(table.clone());
+        return;
+    };
+    for (key, value) in table {
+        match (target.get_mut(key), value) {
+            (Some(slot @ Value::Object(_)), Value::Object(inner)) => merge(slot, inner),
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+fn find_unknown_key(parsed: &Value, table: &Map<String, Value>, path: &str) -> Result<()> {
+    for (key, value) in table {
+        let path = format!("{}.{}", path, key);
+        match (parsed.get(key), value) {
+            (None, _) => return Err(invalid(format!("unknown key `{}`", path))),
+            (Some(parsed), Value::Object(inner)) if parsed.is_object() => find_unknown_key(parsed, inner, &path)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Replace `${NAME}` and `${NAME:-fallback}` in every string under `value`
+fn interpolate(value: &mut Value, path: &str, vars: &HashMap<String, String>) -> Result<()> {
+    match value {
+        Value::String(text) if text.contains("${") => {
+            *text = expand(text, vars).map_err(|e| invalid(format!("`{}`: {}", path, e)))?;
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                interpolate(item, &format!("{}[{}]", path, index), vars)?;
+            }
+        }
+        Value::Object(table) => {
+            for (key, item) in table.iter_mut() {
+                interpolate(item, &format!("{}.{}", path, key), vars)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn expand(text: &str, vars: &HashMap<String, String>) -> std::result::Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference.find('}').ok_or("unterminated `${`")?;
+        let (name, fallback) = match reference[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&reference[..end], None),
+        };
+        match vars.get(name).map(String::as_str).or(fallback) {
+            Some(value) => expanded.push_str(value),
+            None => return Err(format!("environment variable {} is not set", name)),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Apply `LETTA_<SECTION>__<KEY>[__<KEY>...]` variables. Other `LETTA_`
+/// variables are left alone.
+fn apply_env_overrides(sections: &mut Map<String, Value>, vars: &HashMap<String, String>) -> Result<()> {
+    let mut overrides: Vec<(Vec<String>, &String)> = vars.iter()
+        .filter_map(|(name, value)| {
+            let keys: Vec<String> = name.strip_prefix(ENV_PREFIX)?.split("__").map(str::to_lowercase).collect();
+            (keys.len() > 1 && SECTIONS.contains(&keys[0].as_str())).then_some((keys, value))
+        })
+        .collect();
+    overrides.sort();
+
+    for (keys, raw) in overrides {
+        let (last, parents) = keys.split_last().expect("at least two keys");
+        let mut table = &mut *sections;
+        for (depth, key) in parents.iter().enumerate() {
+            table = table.entry(key.clone())
+                .or_insert_with(|| Value::Object(Map::new()))
+                .as_object_mut()
+                .ok_or_else(|| invalid(format!(
+                    "`{}` is not a table, so {}{} can't set a key in it",
+                    keys[..=depth].join("."), ENV_PREFIX, keys.join("__").to_uppercase()
+                )))?;
+        }
+        let value = match serde_json::from_str(raw) {
+            Ok(value @ (Value::Bool(_) | Value::Number(_) | Value::Array(_))) => value,
+            _ => Value::String(raw.clone()),
+        };
+        table.insert(last.clone(), value);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "storage"))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/config").join(name)
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn error(text: &str, format: ConfigFormat, vars: &[(&str, &str)]) -> String {
+        AppConfig::parse(text, format, env(vars)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_load_toml_fixture() {
+        let path = fixture("letta.toml");
+        let config = AppConfig::load_with_env(&path, env(&[("OPENAI_API_KEY", "sk-test")])).unwrap();
+
+        let storage = config.storage_config();
+        assert_eq!(storage.path, path.parent().unwrap().join("agents.db"));
+        assert_eq!(storage.max_connections, 2);
+        assert_eq!(storage.busy_timeout_ms, StorageConfig::default().busy_timeout_ms);
+
+        match config.provider_config() {
+            ProviderConfig::OpenAI(openai) => {
+                assert_eq!(openai.api_key, "sk-test");
+                assert_eq!(openai.base_url.as_deref(), Some("http://localhost:8080/v1"));
+            }
+            other => panic!("unexpected provider {:?}", other),
+        }
+
+        let agent = config.agent_config();
+        assert_eq!(agent.name, "desk");
+        assert_eq!(agent.temperature, 0.2);
+        assert_eq!(agent.max_messages, AgentConfig::default().max_messages);
+        assert_eq!(agent.disabled_tools, vec!["archival_insert".to_string()]);
+        assert_eq!(config.sync.unwrap()["endpoint"], "https://sync.example.com");
+    }
+
+    #[test]
+    fn test_json_fixture_and_env_overrides() {
+        let vars = env(&[
+            ("LETTA_STORAGE__PATH", "/tmp/override.db"),
+            ("LETTA_AGENT__MAX_MESSAGES", "12"),
+            ("LETTA_TOOLS__ENABLED", "false"),
+            ("LETTA_LOG_LEVEL", "debug"),
+        ]);
+        let config = AppConfig::load_with_env(fixture("letta.json"), vars).unwrap();
+        assert_eq!(config.storage_config().path, PathBuf::from("/tmp/override.db"));
+        assert_eq!(config.agent.max_messages, 12);
+        assert!(!config.agent_config().tools_enabled);
+        assert!(matches!(config.provider_config(), ProviderConfig::Toy(ToyConfig { deterministic: true, .. })));
+
+        let empty = AppConfig::parse("", ConfigFormat::Toml, Vec::new()).unwrap();
+        assert!(empty.storage.is_none() && empty.sync.is_none());
+        assert_eq!(empty.tools, ToolPolicy::default());
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let text = r#"
+            [provider]
+            type = "anthropic"
+            api_key = "${KEY}-${SUFFIX:-none}"
+            model = "${MODEL:-claude}"
+        "#;
+        let config = AppConfig::parse(text, ConfigFormat::Toml, env(&[("KEY", "abc"), ("MODEL", "")])).unwrap();
+        match config.provider_config() {
+            ProviderConfig::Anthropic(anthropic) => {
+                assert_eq!(anthropic.api_key, "abc-none");
+                assert_eq!(anthropic.model, "");
+            }
+            other => panic!("unexpected provider {:?}", other),
+        }
+
+        assert_eq!(
+            error(text, ConfigFormat::Toml, &[]),
+            "Invalid configuration: `provider.api_key`: environment variable KEY is not set"
+        );
+        assert!(error("[storage]\npath = \"${HOME\"", ConfigFormat::Toml, &[]).contains("`storage.path`: unterminated"));
+    }
+
+    #[test]
+    fn test_bad_values_name_the_key() {
+        let path = fixture("bad_type.toml");
+        let message = AppConfig::load_with_env(&path, Vec::new()).unwrap_err().to_string();
+        assert!(message.contains(&path.display().to_string()), "{}", message);
+        assert!(message.contains("`storage.max_connections`: invalid type: string \"five\""), "{}", message);
+
+        let cases = [
+            ("[storage]\npth = \"a.db\"", "unknown key `storage.pth`"),
+            ("[agent]\ntemperature = 3.5", "`agent`: temperature 3.5 is outside"),
+            ("[tools]\ndisabled = [\"rm_rf\"]", "`tools.disabled`: unknown tool `rm_rf`"),
+            ("[provider]\ntype = \"openai\"\nmodel = \"gpt\"", "`provider`: missing field `api_key`"),
+            ("[providers]\ntype = \"toy\"", "unknown section `providers`"),
+            ("sync = 3", "`sync` must be a table"),
+            ("[storage\n", "invalid TOML"),
+        ];
+        for (text, expected) in cases {
+            let message = error(text, ConfigFormat::Toml, &[]);
+            assert!(message.contains(expected), "{:?} gave {}", text, message);
+        }
+        assert!(error(r#"{"agent": {"max_messages": -1}}"#, ConfigFormat::Json, &[]).contains("`agent.max_messages`"));
+        assert!(error("{\"agent\": }", ConfigFormat::Json, &[]).contains("invalid JSON"));
+        assert!(error("[agent]\nname = \"x\"", ConfigFormat::Toml, &[("LETTA_AGENT__NAME__FIRST", "y")])
+            .contains("`agent.name` is not a table"));
+        assert!(AppConfig::load_with_env("letta.yaml", Vec::new()).unwrap_err().to_string().contains("expected a .toml or .json file"));
+    }
+}
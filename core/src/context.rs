@@ -1,7 +1,12 @@
+use std::collections::HashSet;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 use crate::error::{LettaError, Result};
-use crate::message::Message;
+use crate::message::{Message, MessageRole};
 use crate::memory::Memory;
+use crate::provider::ChatMessage;
+use crate::tokenizer::Tokenizer;
+use crate::tool::ToolCall;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextWindow {
@@ -23,16 +28,19 @@ impl Default for ContextWindow {
 #[derive(Debug, Clone)]
 pub struct ContextManager {
     window: ContextWindow,
+    tokenizer: Arc<dyn Tokenizer>,
 }
 
 impl ContextManager {
-    pub fn new(max_tokens: usize) -> Self {
+    /// Prompts are measured with `tokenizer` against `max_tokens`
+    pub fn new(max_tokens: usize, tokenizer: Arc<dyn Tokenizer>) -> Self {
         Self {
             window: ContextWindow {
                 max_tokens,
                 current_tokens: 0,
                 summarization_threshold: 0.8,
             },
+            tokenizer,
         }
     }
     
@@ -62,6 +70,7 @@ impl ContextManager {
         }
     }
     
+    /// The flattened form of `build_chat`, for text-completion providers
     pub fn build_prompt(
         &mut self,
         system_prompt: &str,
@@ -69,43 +78,90 @@ impl ContextManager {
         messages: &[Message],
         max_messages: usize,
     ) -> Result<String> {
-        let mut prompt_parts = vec![];
-        let mut token_count = 0;
+        let chat = self.build_chat(system_prompt, memory, messages, max_messages)?;
+        Ok(Self::flatten(&chat))
+    }
+    
+    /// The context as chat messages: the system prompt with memory, then the
+    /// last `max_messages` messages. Tool results follow the call they
+    /// answer, and results whose call fell out of the window are left out.
+    /// Usage is measured over these messages.
+    pub fn build_chat(
+        &mut self,
+        system_prompt: &str,
+        memory: &Memory,
+        messages: &[Message],
+        max_messages: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let memory_str = memory.render()?;
+        let mut chat = vec![ChatMessage::new(
+            MessageRole::System,
+            format!("{}\n\n<memory>\n{}</memory>", system_prompt, memory_str),
+        )];
         
-        // Add system prompt
-        prompt_parts.push(format!("System: {}", system_prompt));
-        token_count += system_prompt.len() / 4;
+        let start_idx = messages.len().saturating_sub(max_messages);
+        let mut calls_made = HashSet::new();
+        // Results stored before their call, as older agents did
+        let mut early_results: Vec<&Message> = Vec::new();
+        for msg in &messages[start_idx..] {
+            match (&msg.role, &msg.tool_call_id) {
+                (MessageRole::Tool, Some(id)) if calls_made.contains(id.as_str()) => chat.push(chat_message(msg)),
+                (MessageRole::Tool, _) => early_results.push(msg),
+                (_, _) => {
+                    chat.push(chat_message(msg));
+                    for call in msg.tool_calls.iter().flatten() {
+                        calls_made.insert(call.id.as_str());
+                        if let Some(i) = early_results.iter().position(|r| r.tool_call_id.as_ref() == Some(&call.id)) {
+                            chat.push(chat_message(early_results.remove(i)));
+                        }
+                    }
+                }
+            }
+        }
         
-        // Add memory blocks
-        let memory_str = memory.render()?;
-        prompt_parts.push(format!("\n<memory>\n{}</memory>", memory_str));
-        token_count += memory.token_estimate();
+        let tokens = chat.iter().map(|msg| self.count_message(msg)).sum();
+        self.update_usage(tokens);
+        
+        // Check if we're within limits
+        self.check_overflow(0)?;
         
-        // Add messages (most recent first, then reverse)
-        let message_count = messages.len().min(max_messages);
-        let start_idx = messages.len().saturating_sub(message_count);
+        Ok(chat)
+    }
+    
+    /// `chat` as one prompt: `System: ...` with memory, then the
+    /// conversation a line per message
+    pub fn flatten(chat: &[ChatMessage]) -> String {
+        let mut prompt_parts = vec![];
+        let mut messages = chat.iter();
+        if let Some(system) = messages.next() {
+            prompt_parts.push(format!("System: {}", system.content));
+        }
         
         prompt_parts.push("\n<conversation>".to_string());
-        for msg in &messages[start_idx..] {
+        for msg in messages {
             let msg_str = match msg.role {
-                crate::message::MessageRole::System => format!("System: {}", msg.content),
-                crate::message::MessageRole::User => format!("User: {}", msg.content),
-                crate::message::MessageRole::Assistant => format!("Assistant: {}", msg.content),
-                crate::message::MessageRole::Tool => {
-                    format!("Tool [{}]: {}", msg.tool_call_id.as_ref().unwrap_or(&"unknown".to_string()), msg.content)
+                MessageRole::System => format!("System: {}", msg.content),
+                MessageRole::User => format!("User: {}", msg.content),
+                MessageRole::Assistant => format!("Assistant: {}", msg.content),
+                MessageRole::Tool => {
+                    format!("Tool [{}]: {}", msg.tool_call_id.as_deref().unwrap_or("unknown"), msg.content)
                 }
             };
             prompt_parts.push(msg_str);
-            token_count += msg.token_estimate();
         }
         prompt_parts.push("</conversation>".to_string());
         
-        self.update_usage(token_count);
-        
-        // Check if we're within limits
-        self.check_overflow(0)?;
-        
-        Ok(prompt_parts.join("\n"))
+        prompt_parts.join("\n")
+    }
+    
+    /// Tokens `msg` takes: its content and tool calls, plus what chat APIs
+    /// spend marking a message's role and boundaries
+    fn count_message(&self, msg: &ChatMessage) -> usize {
+        const MESSAGE_OVERHEAD: usize = 4;
+        let calls: usize = msg.tool_calls.iter()
+            .map(|call| self.tokenizer.count_tokens(&call.name) + self.tokenizer.count_tokens(&call.arguments.to_string()))
+            .sum();
+        MESSAGE_OVERHEAD + self.tokenizer.count_tokens(&msg.content) + calls
     }
     
     pub fn summarize_messages(&self, messages: &[Message], keep_recent: usize) -> String {
@@ -142,13 +198,26 @@ impl ContextManager {
     }
 }
 
+fn chat_message(msg: &Message) -> ChatMessage {
+    ChatMessage {
+        role: msg.role.clone(),
+        content: msg.content.clone(),
+        tool_calls: msg.tool_calls.iter().flatten()
+            .map(|call| ToolCall { id: call.id.clone(), name: call.name.clone(), arguments: call.arguments.clone() })
+            .collect(),
+        tool_call_id: msg.tool_call_id.clone(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::message::ToolCallInfo;
+    use crate::tokenizer::HeuristicTokenizer;
     
     #[test]
     fn test_context_overflow() {
-        let mut ctx = ContextManager::new(1000);
+        let mut ctx = ContextManager::new(1000, Arc::new(HeuristicTokenizer));
         ctx.update_usage(800);
         
         assert!(ctx.check_overflow(100).is_ok());
@@ -157,7 +226,7 @@ mod tests {
     
     #[test]
     fn test_summarization_trigger() {
-        let mut ctx = ContextManager::new(1000).with_threshold(0.8);
+        let mut ctx = ContextManager::new(1000, Arc::new(HeuristicTokenizer)).with_threshold(0.8);
         
         ctx.update_usage(700);
         assert!(!ctx.should_summarize());
@@ -165,4 +234,46 @@ mod tests {
         ctx.update_usage(850);
         assert!(ctx.should_summarize());
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_cjk_prompt_is_not_undercounted() {
+        let mut ctx = ContextManager::new(160, Arc::new(HeuristicTokenizer));
+        let memory = Memory::new_chat();
+        
+        // 150 characters in 450 bytes: 112 tokens by `len() / 4`, which
+        // left room to spare
+        let messages = vec![Message::user("我们今天在公园里散步，天气非常好。".repeat(9) + "我们今天在公园里散步")];
+        let result = ctx.build_prompt("", &memory, &messages, 10);
+        assert!(matches!(result, Err(LettaError::ContextOverflow { current, max: 160 }) if current > 150));
+    }
+    
+    #[test]
+    fn test_chat_pairs_tool_results_with_calls() {
+        let mut ctx = ContextManager::new(8192, Arc::new(HeuristicTokenizer));
+        let memory = Memory::new_chat();
+        let call = ToolCallInfo { id: "call_1".into(), name: "archival_search".into(), arguments: serde_json::json!({ "query": "tea" }) };
+        let messages = vec![
+            Message::tool("call_0".into(), "{}"),
+            Message::user("What do I drink?"),
+            // Stored ahead of its call
+            Message::tool("call_1".into(), "[]"),
+            Message::assistant("").with_tool_calls(vec![call]),
+            Message::assistant("Tea, I think."),
+        ];
+        
+        let chat = ctx.build_chat("Be brief.", &memory, &messages, 10).unwrap();
+        let roles: Vec<_> = chat.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, [MessageRole::System, MessageRole::User, MessageRole::Assistant, MessageRole::Tool, MessageRole::Assistant]);
+        assert!(chat[0].content.starts_with("Be brief.\n\n<memory>\n"));
+        assert_eq!(chat[2].tool_calls[0].name, "archival_search");
+        assert_eq!(chat[3].tool_call_id.as_deref(), Some("call_1"));
+        
+        // Usage counts the messages, not the flattened prompt
+        let counted: usize = chat.iter().map(|m| ctx.count_message(m)).sum();
+        assert_eq!(ctx.window.current_tokens, counted);
+        let prompt = ctx.build_prompt("Be brief.", &memory, &messages, 10).unwrap();
+        assert_eq!(prompt, ContextManager::flatten(&chat));
+        assert!(prompt.starts_with("System: Be brief.\n\n<memory>\n"));
+        assert!(prompt.ends_with("\n<conversation>\nUser: What do I drink?\nAssistant: \nTool [call_1]: []\nAssistant: Tea, I think.\n</conversation>"));
+    }
+}
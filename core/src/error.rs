@@ -1,7 +1,11 @@
+use std::fmt;
+
+use serde_json::json;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum LettaError {
+    #[cfg(feature = "storage")]
     #[error("Storage error: {0}")]
     Storage(#[from] letta_storage::StorageError),
     
@@ -9,7 +13,7 @@ pub enum LettaError {
     Serialization(#[from] serde_json::Error),
     
     #[error("Provider error: {0}")]
-    Provider(String),
+    Provider(ProviderError),
     
     #[error("Tool execution error: {0}")]
     ToolExecution(String),
@@ -17,6 +21,9 @@ pub enum LettaError {
     #[error("Memory error: {0}")]
     Memory(String),
     
+    #[error("Memory error: Value exceeds limit: {len} > {limit}")]
+    MemoryLimitExceeded { label: String, len: usize, limit: usize },
+    
     #[error("Context overflow: current {current}, max {max}")]
     ContextOverflow { current: usize, max: usize },
     
@@ -29,6 +36,21 @@ pub enum LettaError {
     #[error("Sync error: {0}")]
     Sync(String),
     
+    #[error("Agent file is encrypted; a passphrase is needed to open it")]
+    PassphraseRequired,
+    
+    #[error("Wrong passphrase for encrypted agent file")]
+    WrongPassphrase,
+    
+    #[error("Encrypted agent file is damaged or has been tampered with")]
+    Tampered,
+    
+    #[error("Timed out after {elapsed_ms} ms")]
+    Timeout { elapsed_ms: u64 },
+    
+    #[error("Cancelled")]
+    Cancelled,
+    
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     
@@ -36,4 +58,345 @@ pub enum LettaError {
     Unknown(String),
 }
 
-pub type Result<T> = std::result::Result<T, LettaError>;
\ No newline at end of file
+impl LettaError {
+    /// Stable machine-readable code, such as `"context.overflow"`. Unlike the
+    /// message it won't change wording between releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "storage")]
+            LettaError::Storage(_) => "storage.failed",
+            LettaError::Serialization(_) => "serialization.invalid",
+            LettaError::Provider(e) => e.kind.code(),
+            LettaError::ToolExecution(_) => "tool.failed",
+            LettaError::Memory(_) => "memory.failed",
+            LettaError::MemoryLimitExceeded { .. } => "memory.limit_exceeded",
+            LettaError::ContextOverflow { .. } => "context.overflow",
+            LettaError::AgentNotFound(_) => "agent.not_found",
+            LettaError::InvalidConfig(_) => "config.invalid",
+            LettaError::Sync(_) => "sync.failed",
+            LettaError::PassphraseRequired => "af.passphrase_required",
+            LettaError::WrongPassphrase => "af.wrong_passphrase",
+            LettaError::Tampered => "af.tampered",
+            LettaError::Timeout { .. } => "timeout",
+            LettaError::Cancelled => "cancelled",
+            LettaError::Io(_) => "io.failed",
+            LettaError::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Whether the same call may succeed if made again unchanged
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            #[cfg(feature = "storage")]
+            LettaError::Storage(e) => e.is_retryable(),
+            LettaError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ),
+            LettaError::Provider(e) => e.retryable,
+            LettaError::Sync(_) | LettaError::Timeout { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// `{"code", "message", "retryable"}` plus a `"details"` object for
+    /// variants that carry structured fields
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = json!({
+            "code": self.code(),
+            "message": self.to_string(),
+            "retryable": self.is_retryable(),
+        });
+        let details = match self {
+            LettaError::MemoryLimitExceeded { label, len, limit } => {
+                Some(json!({ "label": label, "len": len, "limit": limit }))
+            }
+            LettaError::ContextOverflow { current, max } => Some(json!({ "current": current, "max": max })),
+            LettaError::AgentNotFound(id) => Some(json!({ "agent_id": id })),
+            LettaError::Timeout { elapsed_ms } => Some(json!({ "elapsed_ms": elapsed_ms })),
+            LettaError::Provider(e) => Some(json!({ "kind": e.kind.as_str(), "status": e.status })),
+            _ => None,
+        };
+        if let Some(details) = details {
+            value["details"] = details;
+        }
+        value
+    }
+}
+
+pub type Result<T> = std::result::Result<T, LettaError>;
+
+/// What went wrong calling a provider, which decides whether to retry, ask
+/// for a new key or give up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProviderErrorKind {
+    /// The API key is missing, invalid or lacks access to the model
+    Auth,
+    /// Too many requests or tokens for the account; slow down
+    RateLimit,
+    /// The prompt doesn't fit the model's context window
+    ContextLength,
+    /// The provider didn't answer in time
+    Timeout,
+    /// The provider couldn't be reached, or the connection broke
+    Transport,
+    /// The provider answered with something that isn't a completion
+    InvalidResponse,
+    Other,
+}
+
+impl ProviderErrorKind {
+    /// Stable name, such as `"rate_limit"`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProviderErrorKind::Auth => "auth",
+            ProviderErrorKind::RateLimit => "rate_limit",
+            ProviderErrorKind::ContextLength => "context_length",
+            ProviderErrorKind::Timeout => "timeout",
+            ProviderErrorKind::Transport => "transport",
+            ProviderErrorKind::InvalidResponse => "invalid_response",
+            ProviderErrorKind::Other => "other",
+        }
+    }
+
+    /// The kind named `name` by `as_str`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "auth" => ProviderErrorKind::Auth,
+            "rate_limit" => ProviderErrorKind::RateLimit,
+            "context_length" => ProviderErrorKind::ContextLength,
+            "timeout" => ProviderErrorKind::Timeout,
+            "transport" => ProviderErrorKind::Transport,
+            "invalid_response" => ProviderErrorKind::InvalidResponse,
+            "other" => ProviderErrorKind::Other,
+            _ => return None,
+        })
+    }
+
+    /// The `LettaError::code` of a provider error of this kind, such as
+    /// `"provider.rate_limited"`
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProviderErrorKind::Auth => "provider.auth",
+            ProviderErrorKind::RateLimit => "provider.rate_limited",
+            ProviderErrorKind::ContextLength => "provider.context_length",
+            ProviderErrorKind::Timeout => "provider.timeout",
+            ProviderErrorKind::Transport => "provider.transport",
+            ProviderErrorKind::InvalidResponse => "provider.invalid_response",
+            ProviderErrorKind::Other => "provider.failed",
+        }
+    }
+
+    /// Whether errors of this kind usually pass if the call is made again.
+    /// `Other` counts as retryable, as every provider error once did.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ProviderErrorKind::RateLimit | ProviderErrorKind::Timeout | ProviderErrorKind::Transport | ProviderErrorKind::Other
+        )
+    }
+
+    /// The kind of an HTTP error response, from its status and body
+    pub fn from_status(status: u16, body: &str) -> Self {
+        match status {
+            401 | 403 => ProviderErrorKind::Auth,
+            429 => ProviderErrorKind::RateLimit,
+            408 | 504 => ProviderErrorKind::Timeout,
+            400 | 413 | 422 if mentions_context_length(body) => ProviderErrorKind::ContextLength,
+            _ => ProviderErrorKind::Other,
+        }
+    }
+}
+
+/// How OpenAI, Anthropic and OpenAI-compatible servers say a prompt is
+/// too long
+fn mentions_context_length(body: &str) -> bool {
+    let body = body.to_lowercase();
+    ["context_length_exceeded", "maximum context length", "context window", "prompt is too long"]
+        .iter()
+        .any(|phrase| body.contains(phrase))
+}
+
+/// A failed provider call. Plain messages convert with `From`, as
+/// `LettaError::Provider("...".into())`, into an `Other` error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderError {
+    pub kind: ProviderErrorKind,
+    pub message: String,
+    /// The HTTP status, when the provider answered with an error
+    pub status: Option<u16>,
+    /// Whether the same call may succeed if made again
+    pub retryable: bool,
+}
+
+impl ProviderError {
+    pub fn new(kind: ProviderErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into(), status: None, retryable: kind.is_retryable() }
+    }
+
+    /// An HTTP error response whose `message` includes the body. Server
+    /// errors are retryable, like the kinds that usually are; other client
+    /// errors are not.
+    pub fn from_status(status: u16, message: impl Into<String>) -> Self {
+        let message = message.into();
+        let kind = ProviderErrorKind::from_status(status, &message);
+        let retryable = match kind {
+            ProviderErrorKind::Other => status >= 500,
+            kind => kind.is_retryable(),
+        };
+        Self { kind, message, status: Some(status), retryable }
+    }
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for ProviderError {
+    fn from(message: String) -> Self {
+        Self::new(ProviderErrorKind::Other, message)
+    }
+}
+
+impl From<&str> for ProviderError {
+    fn from(message: &str) -> Self {
+        Self::new(ProviderErrorKind::Other, message)
+    }
+}
+
+impl From<ProviderError> for LettaError {
+    fn from(error: ProviderError) -> Self {
+        LettaError::Provider(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// One of every variant. The match has no wildcard, so adding a variant
+    /// fails to compile until it is listed here too.
+    fn every_variant() -> Vec<LettaError> {
+        let samples = vec![
+            #[cfg(feature = "storage")]
+            LettaError::Storage(letta_storage::StorageError::Cancelled),
+            LettaError::Serialization(serde_json::from_str::<u32>("x").unwrap_err()),
+            LettaError::Provider("down".into()),
+            LettaError::ToolExecution("failed".into()),
+            LettaError::Memory("bad template".into()),
+            LettaError::MemoryLimitExceeded { label: "human".into(), len: 10, limit: 5 },
+            LettaError::ContextOverflow { current: 10, max: 5 },
+            LettaError::AgentNotFound("agent-1".into()),
+            LettaError::InvalidConfig("bad".into()),
+            LettaError::Sync("offline".into()),
+            LettaError::PassphraseRequired,
+            LettaError::WrongPassphrase,
+            LettaError::Tampered,
+            LettaError::Timeout { elapsed_ms: 30_000 },
+            LettaError::Cancelled,
+            LettaError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "slow")),
+            LettaError::Unknown("?".into()),
+        ];
+        let mut listed = HashSet::new();
+        for sample in &samples {
+            let index = match sample {
+                #[cfg(feature = "storage")]
+                LettaError::Storage(_) => 0,
+                LettaError::Serialization(_) => 1,
+                LettaError::Provider(_) => 2,
+                LettaError::ToolExecution(_) => 3,
+                LettaError::Memory(_) => 4,
+                LettaError::MemoryLimitExceeded { .. } => 5,
+                LettaError::ContextOverflow { .. } => 6,
+                LettaError::AgentNotFound(_) => 7,
+                LettaError::InvalidConfig(_) => 8,
+                LettaError::Sync(_) => 9,
+                LettaError::PassphraseRequired => 10,
+                LettaError::WrongPassphrase => 11,
+                LettaError::Tampered => 12,
+                LettaError::Timeout { .. } => 13,
+                LettaError::Cancelled => 14,
+                LettaError::Io(_) => 15,
+                LettaError::Unknown(_) => 16,
+            };
+            listed.insert(index);
+        }
+        assert_eq!(listed.len(), samples.len(), "every variant is sampled once");
+        samples
+    }
+
+    #[test]
+    fn test_every_variant_has_unique_code() {
+        let mut codes = HashSet::new();
+        for error in every_variant() {
+            assert!(codes.insert(error.code()), "duplicate code {}", error.code());
+        }
+    }
+
+    #[test]
+    fn test_error_json() {
+        let error = LettaError::ContextOverflow { current: 9000, max: 8192 };
+        assert_eq!(error.to_json(), json!({
+            "code": "context.overflow",
+            "message": "Context overflow: current 9000, max 8192",
+            "retryable": false,
+            "details": { "current": 9000, "max": 8192 },
+        }));
+
+        let error = LettaError::MemoryLimitExceeded { label: "human".into(), len: 10, limit: 5 };
+        assert_eq!(error.to_string(), "Memory error: Value exceeds limit: 10 > 5");
+        assert_eq!(error.to_json()["details"]["label"], "human");
+
+        let error = LettaError::Provider("connection reset".into());
+        assert!(error.is_retryable());
+        assert_eq!(error.to_json()["details"], json!({ "kind": "other", "status": null }));
+        #[cfg(feature = "storage")]
+        assert!(!LettaError::Storage(letta_storage::StorageError::Cancelled).is_retryable());
+    }
+
+    #[test]
+    fn test_provider_error_codes_follow_kind() {
+        let cases = [
+            (ProviderErrorKind::Auth, "provider.auth"),
+            (ProviderErrorKind::RateLimit, "provider.rate_limited"),
+            (ProviderErrorKind::ContextLength, "provider.context_length"),
+            (ProviderErrorKind::Timeout, "provider.timeout"),
+            (ProviderErrorKind::Transport, "provider.transport"),
+            (ProviderErrorKind::InvalidResponse, "provider.invalid_response"),
+            (ProviderErrorKind::Other, "provider.failed"),
+        ];
+        for (kind, code) in cases {
+            let error = LettaError::Provider(ProviderError::new(kind, "failed"));
+            assert_eq!(error.code(), code);
+            assert_eq!(error.to_json()["code"], code);
+            assert_eq!(ProviderErrorKind::from_name(kind.as_str()), Some(kind));
+        }
+        assert_eq!(ProviderErrorKind::from_name("nope"), None);
+    }
+
+    #[test]
+    fn test_provider_error_kinds() {
+        let cases = [
+            (401, r#"{"error": {"code": "invalid_api_key"}}"#, ProviderErrorKind::Auth, false),
+            (429, "Rate limit reached", ProviderErrorKind::RateLimit, true),
+            (400, r#"{"error": {"code": "context_length_exceeded"}}"#, ProviderErrorKind::ContextLength, false),
+            (400, "prompt is too long: 210000 tokens > 200000 maximum", ProviderErrorKind::ContextLength, false),
+            (400, "temperature must be at most 2", ProviderErrorKind::Other, false),
+            (504, "Gateway Timeout", ProviderErrorKind::Timeout, true),
+            (529, "Overloaded", ProviderErrorKind::Other, true),
+        ];
+        for (status, body, kind, retryable) in cases {
+            let error = LettaError::from(ProviderError::from_status(status, format!("API returned {}: {}", status, body)));
+            let LettaError::Provider(e) = &error else { panic!("{:?}", error) };
+            assert_eq!((e.kind, e.status, error.is_retryable()), (kind, Some(status), retryable), "{}", body);
+        }
+
+        let error = LettaError::from(ProviderError::new(ProviderErrorKind::Transport, "connection refused"));
+        assert_eq!(error.to_string(), "Provider error: connection refused");
+        assert_eq!(error.to_json()["details"], json!({ "kind": "transport", "status": null }));
+        assert!(error.is_retryable());
+    }
+}
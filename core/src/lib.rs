@@ -1,20 +1,38 @@
 pub mod agent;
+pub mod archival;
 pub mod memory;
 pub mod message;
 pub mod tool;
 pub mod provider;
 pub mod af;
+mod af_encryption;
 pub mod error;
 pub mod context;
+pub mod tokenizer;
+#[cfg(feature = "storage")]
+pub mod persist;
+pub mod telemetry;
+pub mod shutdown;
+pub mod retry;
+pub mod config;
+pub mod cancel;
 
-pub use agent::{Agent, AgentConfig, AgentState};
+pub use agent::{Agent, AgentConfig, AgentState, StepEvent, StepOptions, StepResult, StopReason, ToolTraceEntry};
 pub use memory::{Memory, MemoryBlock, MemoryType};
+#[cfg(feature = "storage")]
+pub use memory::BlockWriter;
 pub use message::{Message, MessageRole};
 pub use tool::{Tool, ToolCall, ToolResult, ToolExecutor};
-pub use provider::{LlmProvider, Completion, CompletionRequest};
-pub use af::{AgentFile, AgentFileV1};
-pub use error::{LettaError, Result};
+pub use provider::{LlmProvider, Completion, CompletionAccumulator, CompletionChunk, CompletionRequest, CompletionStream, Embedder, MaybeSend, MaybeSync, ProviderCapabilities};
+pub use af::{AfCompression, AgentFile, AgentFileDiff, AgentFileV1, ImportWarning};
+pub use error::{LettaError, ProviderError, ProviderErrorKind, Result};
 pub use context::ContextManager;
+pub use tokenizer::{HeuristicTokenizer, Tokenizer, TokenizerConfig};
+pub use config::AppConfig;
+pub use telemetry::{InMemoryMetrics, Metrics, MetricsSnapshot};
+pub use retry::RetryConfig;
+pub use cancel::CancellationToken;
+pub use shutdown::{on_shutdown, shutdown_all, ShutdownHook, ShutdownStage};
 
 /// Library version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -23,4 +41,7 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const DEFAULT_MAX_CONTEXT: usize = 8192;
 
 /// Default message buffer size
-pub const DEFAULT_MESSAGE_BUFFER: usize = 100;
\ No newline at end of file
+pub const DEFAULT_MESSAGE_BUFFER: usize = 100;
+
+/// Default most provider calls per step while tools ask to continue
+pub const DEFAULT_MAX_TOOL_ITERATIONS: usize = 10;
\ No newline at end of file
@@ -2,6 +2,11 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tera::{Context, Tera};
 use crate::error::{LettaError, Result};
+use crate::tokenizer::{HeuristicTokenizer, Tokenizer};
+
+/// Block changes in memory and in storage are credited the same way
+#[cfg(feature = "storage")]
+pub use letta_storage::BlockWriter;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryBlock {
@@ -34,9 +39,11 @@ impl MemoryBlock {
     pub fn replace(&mut self, new_value: impl Into<String>) -> Result<()> {
         let new = new_value.into();
         if new.len() > self.limit {
-            return Err(LettaError::Memory(format!(
-                "Value exceeds limit: {} > {}", new.len(), self.limit
-            )));
+            return Err(LettaError::MemoryLimitExceeded {
+                label: self.label.clone(),
+                len: new.len(),
+                limit: self.limit,
+            });
         }
         self.value = new;
         Ok(())
@@ -62,6 +69,7 @@ impl MemoryBlock {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum MemoryType {
     #[serde(rename = "chat")]
     Chat(ChatMemory),
@@ -114,6 +122,12 @@ impl ChatMemory {
     }
 }
 
+impl Default for ChatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BasicMemory {
     pub blocks: HashMap<String, MemoryBlock>,
@@ -127,6 +141,30 @@ impl BasicMemory {
     }
 }
 
+impl Default for BasicMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How full one memory block is, in characters
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockUsage {
+    pub label: String,
+    pub used: usize,
+    pub limit: usize,
+}
+
+/// How full core memory is; `fullness` is `used / limit` across all blocks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    /// Sorted by label
+    pub blocks: Vec<BlockUsage>,
+    pub used: usize,
+    pub limit: usize,
+    pub fullness: f32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Memory {
     #[serde(flatten)]
@@ -220,9 +258,22 @@ impl Memory {
     pub fn token_estimate(&self) -> usize {
         self.blocks()
             .values()
-            .map(|b| b.value.len() / 4) // Rough estimate: 4 chars per token
+            .map(|b| HeuristicTokenizer.count_tokens(&b.value))
             .sum()
     }
+    
+    pub fn usage(&self) -> MemoryUsage {
+        let mut blocks: Vec<BlockUsage> = self.blocks()
+            .values()
+            .map(|b| BlockUsage { label: b.label.clone(), used: b.value.len(), limit: b.limit })
+            .collect();
+        blocks.sort_by(|a, b| a.label.cmp(&b.label));
+        
+        let used = blocks.iter().map(|b| b.used).sum();
+        let limit = blocks.iter().map(|b| b.limit).sum();
+        let fullness = if limit == 0 { 0.0 } else { used as f32 / limit as f32 };
+        MemoryUsage { blocks, used, limit, fullness }
+    }
 }
 
 #[cfg(test)]
@@ -263,4 +314,17 @@ mod tests {
         assert!(memory.set_block("custom", "Custom data").is_ok());
         assert!(memory.get_block("custom").is_some());
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_memory_usage() {
+        let mut memory = Memory::new_basic();
+        memory.blocks_mut().insert("notes".into(), MemoryBlock::new("notes", "Notes", "x".repeat(50)).with_limit(100));
+        memory.blocks_mut().insert("goals".into(), MemoryBlock::new("goals", "Goals", "").with_limit(100));
+        
+        let usage = memory.usage();
+        let labels: Vec<_> = usage.blocks.iter().map(|b| b.label.as_str()).collect();
+        assert_eq!(labels, vec!["goals", "notes"]);
+        assert_eq!((usage.used, usage.limit), (50, 200));
+        assert_eq!(usage.fullness, 0.25);
+    }
+}
@@ -2,6 +2,7 @@ use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use std::collections::HashMap;
+use crate::tokenizer::{HeuristicTokenizer, Tokenizer};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
@@ -88,11 +89,20 @@ impl Message {
     }
     
     pub fn token_estimate(&self) -> usize {
-        // Simple estimation: ~4 characters per token
-        self.content.len() / 4
+        HeuristicTokenizer.count_tokens(&self.content)
     }
 }
 
+/// Counts over the messages currently in a `MessageBuffer`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageStats {
+    pub count: usize,
+    pub capacity: usize,
+    pub by_role: HashMap<String, usize>,
+    pub token_estimate: usize,
+    pub last_message_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageBuffer {
     pub messages: Vec<Message>,
@@ -130,4 +140,32 @@ impl MessageBuffer {
     pub fn clear(&mut self) {
         self.messages.clear();
     }
+    
+    /// Drop every message after the one with `id`; does nothing if it is gone
+    pub fn truncate_after(&mut self, id: &str) {
+        if let Some(i) = self.messages.iter().rposition(|m| m.id == id) {
+            self.messages.truncate(i + 1);
+        }
+    }
+    
+    pub fn stats(&self) -> MessageStats {
+        let mut by_role: HashMap<String, usize> = HashMap::new();
+        for message in &self.messages {
+            let role = match message.role {
+                MessageRole::System => "system",
+                MessageRole::User => "user",
+                MessageRole::Assistant => "assistant",
+                MessageRole::Tool => "tool",
+            };
+            *by_role.entry(role.to_string()).or_default() += 1;
+        }
+        
+        MessageStats {
+            count: self.messages.len(),
+            capacity: self.max_size,
+            by_role,
+            token_estimate: self.messages.iter().map(Message::token_estimate).sum(),
+            last_message_at: self.messages.last().map(|m| m.timestamp),
+        }
+    }
 }
\ No newline at end of file
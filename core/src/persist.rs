@@ -0,0 +1,965 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use letta_storage::{AsyncStorage, MessageQuery, Storage, StoredAgent, StoredBlock, StoredChunk, StoredMessage};
+use serde::Serialize;
+use crate::{
+    af::{AfCompression, AgentFile},
+    agent::{Agent, AgentConfig, AgentState, StepResult},
+    archival,
+    error::{LettaError, Result},
+    memory::MemoryBlock,
+    message::{Message, MessageBuffer, MessageRole, ToolCallInfo},
+    provider::LlmProvider,
+};
+
+/// Storage rows for one agent
+pub struct AgentRows {
+    pub agent: StoredAgent,
+    pub blocks: Vec<StoredBlock>,
+    pub messages: Vec<StoredMessage>,
+}
+
+/// Split an agent into storage rows. Blocks and messages go to their own
+/// tables, so the `state` column is stored without the message buffer.
+pub fn to_rows(config: &AgentConfig, state: &AgentState) -> Result<AgentRows> {
+    let blocks = state.memory.blocks().values()
+        .map(|block| {
+            let mut stored = StoredBlock::new(&state.id, &block.label, &block.value);
+            stored.description = block.description.clone();
+            stored.char_limit = i32::try_from(block.limit).unwrap_or(i32::MAX);
+            stored.updated_at = state.updated_at;
+            stored
+        })
+        .collect();
+
+    let messages = state.messages.messages.iter()
+        .map(|message| stored_message(&state.id, message))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut state_value = serde_json::to_value(state)?;
+    if let Some(buffer) = state_value.get_mut("messages").and_then(|m| m.get_mut("messages")) {
+        *buffer = serde_json::json!([]);
+    }
+
+    let agent = StoredAgent {
+        id: state.id.clone(),
+        name: config.name.clone(),
+        system_prompt: config.system_prompt.clone(),
+        config: serde_json::to_value(config)?,
+        state: state_value,
+        created_at: state.created_at,
+        updated_at: state.updated_at,
+        deleted_at: None,
+    };
+
+    Ok(AgentRows { agent, blocks, messages })
+}
+
+/// Rebuild an agent from its storage rows, the inverse of `to_rows`.
+///
+/// Blocks replace any of the same label in the `state` column, and messages
+/// are put in `seq` order, then by timestamp for rows not yet stored. The
+/// message buffer keeps its saved size unless more messages are given.
+/// Fails if the `config` or `state` column is corrupted.
+pub fn from_rows(
+    agent: &StoredAgent,
+    blocks: Vec<StoredBlock>,
+    messages: Vec<StoredMessage>,
+) -> Result<(AgentConfig, AgentState)> {
+    let config = serde_json::from_value(agent.config.clone())?;
+    let state = serde_json::from_value(agent.state.clone())?;
+    assemble(agent, config, state, blocks, messages)
+}
+
+/// Like `from_rows`, but a corrupted `config` or `state` column is replaced
+/// by defaults named after the row, so the blocks and history still load
+pub fn from_rows_or_default(
+    agent: &StoredAgent,
+    blocks: Vec<StoredBlock>,
+    messages: Vec<StoredMessage>,
+) -> Result<(AgentConfig, AgentState)> {
+    let config = serde_json::from_value(agent.config.clone())
+        .unwrap_or_else(|_| AgentConfig {
+            name: agent.name.clone(),
+            system_prompt: agent.system_prompt.clone(),
+            ..Default::default()
+        });
+    let state = serde_json::from_value(agent.state.clone())
+        .unwrap_or_else(|_| AgentState::new(&agent.name));
+    assemble(agent, config, state, blocks, messages)
+}
+
+fn assemble(
+    agent: &StoredAgent,
+    config: AgentConfig,
+    mut state: AgentState,
+    blocks: Vec<StoredBlock>,
+    mut messages: Vec<StoredMessage>,
+) -> Result<(AgentConfig, AgentState)> {
+    state.id = agent.id.clone();
+    state.created_at = agent.created_at;
+    state.updated_at = agent.updated_at;
+
+    for block in blocks {
+        state.memory.blocks_mut().insert(block.label.clone(), block_from_stored(block));
+    }
+
+    messages.sort_by_key(|message| (message.seq, message.timestamp));
+    let mut buffer = MessageBuffer::new(messages.len().max(state.messages.max_size));
+    for message in messages {
+        buffer.push(message_from_stored(message)?);
+    }
+    state.messages = buffer;
+
+    Ok((config, state))
+}
+
+/// Rebuild an agent's config and state from storage.
+///
+/// Blocks and messages come from their own tables; the message buffer keeps
+/// its saved size, so only the newest messages that fit are loaded. An agent
+/// in the trash is not found until it is restored.
+pub fn load_agent(storage: &Storage, agent_id: &str) -> Result<(AgentConfig, AgentState)> {
+    let stored = storage.get_agent(agent_id)?
+        .filter(|agent| agent.deleted_at.is_none())
+        .ok_or_else(|| LettaError::AgentNotFound(agent_id.to_string()))?;
+
+    // Only the page that fits the buffer is read; older history stays in storage
+    let max_size = serde_json::from_value::<AgentState>(stored.state.clone())
+        .map_or(crate::DEFAULT_MESSAGE_BUFFER, |state| state.messages.max_size);
+    let newest = storage.query_messages(agent_id, &MessageQuery {
+        limit: Some(max_size),
+        ascending: true,
+        ..Default::default()
+    })?;
+
+    from_rows_or_default(&stored, storage.get_blocks(agent_id)?, newest)
+}
+
+pub fn block_from_stored(block: StoredBlock) -> MemoryBlock {
+    MemoryBlock {
+        label: block.label,
+        description: block.description,
+        value: block.value,
+        limit: block.char_limit.max(0) as usize,
+    }
+}
+
+pub fn stored_message(agent_id: &str, message: &Message) -> Result<StoredMessage> {
+    let role = match serde_json::to_value(&message.role)? {
+        serde_json::Value::String(role) => role,
+        other => return Err(LettaError::Unknown(format!("Unexpected message role {}", other))),
+    };
+
+    Ok(StoredMessage {
+        id: message.id.clone(),
+        agent_id: agent_id.to_string(),
+        role,
+        content: message.content.clone(),
+        tool_calls: message.tool_calls.as_ref().map(serde_json::to_value).transpose()?,
+        tool_call_id: message.tool_call_id.clone(),
+        metadata: serde_json::to_value(&message.metadata)?,
+        timestamp: message.timestamp,
+        seq: 0,
+    })
+}
+
+pub fn message_from_stored(message: StoredMessage) -> Result<Message> {
+    let role: MessageRole = serde_json::from_value(serde_json::Value::String(message.role))?;
+    let tool_calls: Option<Vec<ToolCallInfo>> = message.tool_calls
+        .map(serde_json::from_value)
+        .transpose()?;
+    let metadata = serde_json::from_value(message.metadata).unwrap_or_default();
+
+    Ok(Message {
+        id: message.id,
+        role,
+        content: message.content,
+        tool_calls,
+        tool_call_id: message.tool_call_id,
+        timestamp: message.timestamp,
+        metadata,
+    })
+}
+
+/// Chunk metadata key holding the index of the archival entry a chunk
+/// mirrors; see `archival_chunks`
+const ARCHIVAL_INDEX: &str = "archival_index";
+
+/// Chunks mirroring the archival entries from `from` on. Ids are derived
+/// from the agent and the entry's index, so writing an entry twice leaves
+/// one chunk.
+pub fn archival_chunks(state: &AgentState, from: usize) -> Vec<StoredChunk> {
+    state.archival_entries.iter()
+        .enumerate()
+        .skip(from)
+        .map(|(index, entry)| {
+            let field = |name: &str| entry.get(name).and_then(|v| v.as_str());
+            let mut chunk = StoredChunk::new(&state.id, field("folder").unwrap_or("archival"), field("text").unwrap_or_default());
+            chunk.id = format!("{}:archival:{}", state.id, index);
+            chunk.metadata = serde_json::json!({ ARCHIVAL_INDEX: index });
+            if let Some(model) = field(archival::EMBEDDING_MODEL) {
+                chunk.embedding = archival::embedding(entry, model)
+                    .map(|vector| vector.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect());
+                chunk.embedding_model = chunk.embedding.is_some().then(|| model.to_string());
+            }
+            if let Some(timestamp) = field("timestamp").and_then(|t| t.parse().ok()) {
+                chunk.created_at = timestamp;
+            }
+            chunk
+        })
+        .collect()
+}
+
+/// How `export_all_to_af` writes its files
+#[derive(Debug, Clone, Copy)]
+pub struct ExportOptions {
+    pub compression: AfCompression,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self { compression: AfCompression::Gzip }
+    }
+}
+
+/// Files written by `export_all_to_af`, and the agents it left out
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExportReport {
+    pub written: Vec<PathBuf>,
+    /// One message per agent that could not be read, naming it
+    pub skipped: Vec<String>,
+}
+
+/// Write every stored agent to `dir` as an agent file named after its id,
+/// with its full message history and archival chunks. Existing files are
+/// never overwritten; a name already taken gets a numeric suffix.
+///
+/// An agent whose stored config or state is corrupted is skipped and listed
+/// in the report rather than failing the export. Errors writing a file still
+/// stop it, since the rest would likely fail the same way.
+pub fn export_all_to_af(storage: &Storage, dir: &Path, options: ExportOptions) -> Result<ExportReport> {
+    fs::create_dir_all(dir)?;
+    let listing = storage.list_agents_lenient()?;
+    let mut report = ExportReport { skipped: listing.warnings, ..Default::default() };
+
+    for stored in listing.items {
+        let (config, state) = match export_parts(storage, &stored) {
+            Ok(parts) => parts,
+            Err(e @ (LettaError::Serialization(_) | LettaError::Storage(letta_storage::StorageError::InvalidData(_)))) => {
+                tracing::warn!(agent_id = %stored.id, error = %e, "Skipping agent in export");
+                report.skipped.push(format!("agent {}: {}", stored.id, e));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let af = AgentFile::export_owned(&config, state, vec![])?;
+
+        let (path, file) = create_unique(dir, &file_stem(&stored.id))?;
+        if let Err(e) = AgentFile::to_writer(&af, file, options.compression) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        report.written.push(path);
+    }
+
+    tracing::info!(written = report.written.len(), skipped = report.skipped.len(), "Exported agents");
+    Ok(report)
+}
+
+/// Like `load_agent`, but strict: corrupted JSON is an error rather than a
+/// fallback, and the whole history and every chunk are included
+fn export_parts(storage: &Storage, stored: &StoredAgent) -> Result<(AgentConfig, AgentState)> {
+    let messages = storage.get_messages_since(&stored.id, 0)?;
+    let (config, mut state) = from_rows(stored, storage.get_blocks(&stored.id)?, messages)?;
+    // Entries kept in the state itself stay; stored chunks join them, less
+    // those that mirror an entry
+    state.archival_entries.extend(storage.get_chunks(&stored.id)?
+        .into_iter()
+        .filter(|chunk| chunk.metadata.get(ARCHIVAL_INDEX).is_none())
+        .map(|chunk| serde_json::json!({
+            "folder": chunk.folder,
+            "text": chunk.text,
+            "timestamp": chunk.created_at,
+        })));
+
+    Ok((config, state))
+}
+
+/// An agent id made safe to use as a file name
+fn file_stem(agent_id: &str) -> String {
+    let stem: String = agent_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if stem.is_empty() { "agent".to_string() } else { stem }
+}
+
+fn create_unique(dir: &Path, stem: &str) -> Result<(PathBuf, fs::File)> {
+    let mut path = dir.join(format!("{}.af", stem));
+    let mut attempt = 1;
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                attempt += 1;
+                path = dir.join(format!("{}-{}.af", stem, attempt));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+impl Agent {
+    /// Rebuild a saved agent around `provider`, as `load_agent` reads it:
+    /// blocks from their table win over those in the `state` column, and the
+    /// newest messages that fit the buffer are loaded oldest first. Rows
+    /// written before `config` and `state` were stored, or corrupted since,
+    /// get defaults and keep the stored name and system prompt.
+    pub fn load(storage: &Storage, agent_id: &str, provider: Box<dyn LlmProvider>) -> Result<Agent> {
+        let (config, state) = load_agent(storage, agent_id)?;
+        Ok(Agent::new(config, provider).with_state(state))
+    }
+    
+    /// Write the agent's config, state, memory blocks and any messages not
+    /// yet stored, all or nothing. Messages already saved are left untouched,
+    /// so history evicted from the buffer stays in storage.
+    pub fn save(&self, storage: &Storage) -> Result<()> {
+        let rows = to_rows(&self.config, &self.state)?;
+        storage.save_agent_snapshot(&rows.agent, &rows.blocks, &rows.messages)?;
+        self.mark_saved();
+        Ok(())
+    }
+    
+    /// `save` for async callers; the write runs off the runtime's worker threads
+    pub async fn save_async(&self, storage: &AsyncStorage) -> Result<()> {
+        let rows = to_rows(&self.config, &self.state)?;
+        storage.save_agent_snapshot(rows.agent, rows.blocks, rows.messages).await?;
+        self.mark_saved();
+        Ok(())
+    }
+    
+    /// Save to `storage` after every successful step: the agent row, memory
+    /// blocks, new messages and new archival entries, as chunks, all in one
+    /// transaction, off the runtime's worker threads. Each message is written
+    /// once, so messages pruned from storage since are not brought back. If
+    /// the save fails the step fails with the error when
+    /// `strict_persistence` is set, and otherwise succeeds with the error in
+    /// `StepResult::warnings`.
+    pub fn attach_storage(&mut self, storage: Arc<Storage>) {
+        self.attached = Some(AttachedStorage {
+            storage,
+            chunked: 0,
+            saved_messages: HashSet::new(),
+            deleted_messages: Vec::new(),
+        });
+    }
+    
+    /// Stop saving after each step
+    pub fn detach_storage(&mut self) {
+        self.attached = None;
+    }
+    
+    /// The storage given to `attach_storage`, if any
+    pub fn attached_storage(&self) -> Option<&Arc<Storage>> {
+        self.attached.as_ref().map(|attached| &attached.storage)
+    }
+    
+    /// Save the step just taken to the attached storage, if any, noting a
+    /// failure in `step` unless `strict_persistence` makes it an error
+    pub(crate) async fn persist_step(&mut self, mut step: StepResult) -> Result<StepResult> {
+        match self.save_attached().await {
+            Ok(()) => Ok(step),
+            Err(e) if self.config.strict_persistence => Err(e),
+            Err(e) => {
+                tracing::warn!(agent_id = %self.state.id, error = %e, "Failed to save step to storage");
+                step.warnings.push(format!("Failed to save to storage: {}", e));
+                Ok(step)
+            }
+        }
+    }
+    
+    /// This new agent, attached to and saved in `other`'s storage if it has
+    /// any, as for a fork of `other`
+    pub(crate) fn attached_like(mut self, other: &Agent) -> Result<Agent> {
+        if let Some(storage) = other.attached_storage() {
+            self.attach_storage(storage.clone());
+            if let Some(changes) = self.attached_changes()? {
+                let written = changes.message_ids();
+                let AttachedChanges { rows, chunks, deleted, .. } = &changes;
+                storage.save_agent_step(&rows.agent, &rows.blocks, &rows.messages, chunks, deleted)?;
+                self.mark_attached_saved(written);
+            }
+        }
+        Ok(self)
+    }
+    
+    async fn save_attached(&mut self) -> Result<()> {
+        let Some(changes) = self.attached_changes()? else { return Ok(()) };
+        let written = changes.message_ids();
+        let AttachedChanges { storage, rows, chunks, deleted } = changes;
+        storage.to_async().save_agent_step(rows.agent, rows.blocks, rows.messages, chunks, deleted).await?;
+        self.mark_attached_saved(written);
+        Ok(())
+    }
+    
+    /// What the next save to attached storage writes, if storage is attached
+    fn attached_changes(&self) -> Result<Option<AttachedChanges>> {
+        let Some(attached) = &self.attached else { return Ok(None) };
+        let mut rows = to_rows(&self.config, &self.state)?;
+        // A message written before may have been pruned since; writing it
+        // again would put it after newer history
+        rows.messages.retain(|message| !attached.saved_messages.contains(&message.id));
+        let chunks = archival_chunks(&self.state, attached.chunked);
+        Ok(Some(AttachedChanges {
+            storage: attached.storage.clone(),
+            rows,
+            chunks,
+            deleted: attached.deleted_messages.clone(),
+        }))
+    }
+    
+    fn mark_attached_saved(&mut self, written: Vec<String>) {
+        if let Some(attached) = &mut self.attached {
+            // Only messages still in the buffer can be written again
+            let buffered: HashSet<&str> = self.state.messages.messages.iter().map(|m| m.id.as_str()).collect();
+            attached.saved_messages.extend(written);
+            attached.saved_messages.retain(|id| buffered.contains(id.as_str()));
+            attached.chunked = self.state.archival_entries.len();
+            attached.deleted_messages.clear();
+        }
+        self.mark_saved();
+    }
+    
+    /// Delete `messages`, taken out of the buffer, from attached storage with
+    /// the next save
+    pub(crate) fn delete_on_save(&mut self, messages: &[Message]) {
+        if let Some(attached) = &mut self.attached {
+            attached.deleted_messages.extend(messages.iter().map(|m| m.id.clone()));
+        }
+    }
+    
+    /// Undo `delete_on_save` for `messages` put back in the buffer
+    pub(crate) fn keep_on_save(&mut self, messages: &[Message]) {
+        if let Some(attached) = &mut self.attached {
+            attached.deleted_messages.retain(|id| !messages.iter().any(|m| &m.id == id));
+        }
+    }
+    
+    /// Save to `storage` when dropped, if anything changed since the last
+    /// `save`. The save is best effort: it gets `timeout` to finish, and is
+    /// skipped with a warning if that runs out, if it fails, or if the agent
+    /// is dropped while a panic unwinds.
+    pub fn flush_on_drop(&mut self, storage: Storage, timeout: Duration) {
+        self.flush = Some(FlushOnDrop { storage, timeout, saved: Mutex::new(None) });
+    }
+    
+    /// Stop saving on drop, e.g. before deleting the agent from storage
+    pub fn disarm_flush_on_drop(&mut self) {
+        self.flush = None;
+    }
+    
+    /// Whether `flush_on_drop` is set and there are changes it would save
+    pub fn needs_flush(&self) -> bool {
+        self.flush.as_ref().is_some_and(|flush| flush.is_dirty(&self.state))
+    }
+    
+    /// Save now to the `flush_on_drop` storage if there are unsaved changes;
+    /// returns whether anything was written
+    pub fn flush(&self) -> Result<bool> {
+        match &self.flush {
+            Some(flush) if flush.is_dirty(&self.state) => {
+                self.save(&flush.storage)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+    
+    fn mark_saved(&self) {
+        if let Some(flush) = &self.flush {
+            *flush.saved() = Some(Watermark::of(&self.state));
+        }
+    }
+}
+
+/// Storage an agent saves each step to
+pub(crate) struct AttachedStorage {
+    storage: Arc<Storage>,
+    /// How many archival entries have been written as chunks. Starts at
+    /// none; entries already stored are skipped by id.
+    chunked: usize,
+    /// Ids of buffered messages already written. Starts empty; messages
+    /// already stored are skipped by id.
+    saved_messages: HashSet<String>,
+    /// Ids of messages taken out of the history since the last save, to be
+    /// deleted by the next one
+    deleted_messages: Vec<String>,
+}
+
+/// Rows and chunks for one save to attached storage
+struct AttachedChanges {
+    storage: Arc<Storage>,
+    rows: AgentRows,
+    chunks: Vec<StoredChunk>,
+    deleted: Vec<String>,
+}
+
+impl AttachedChanges {
+    fn message_ids(&self) -> Vec<String> {
+        self.rows.messages.iter().map(|message| message.id.clone()).collect()
+    }
+}
+
+/// Default time an agent's save on drop may take
+pub const DEFAULT_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Storage a dropped agent saves itself to, and what it last saved
+pub(crate) struct FlushOnDrop {
+    storage: Storage,
+    timeout: Duration,
+    saved: Mutex<Option<Watermark>>,
+}
+
+/// How far the state had got when it was saved. Steps and the other
+/// mutating methods bump `updated_at`; the message count catches messages
+/// pushed onto the buffer directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watermark {
+    updated_at: DateTime<Utc>,
+    messages: usize,
+}
+
+impl Watermark {
+    fn of(state: &AgentState) -> Self {
+        Self { updated_at: state.updated_at, messages: state.messages.messages.len() }
+    }
+}
+
+impl FlushOnDrop {
+    fn saved(&self) -> MutexGuard<'_, Option<Watermark>> {
+        self.saved.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    
+    fn is_dirty(&self, state: &AgentState) -> bool {
+        *self.saved() != Some(Watermark::of(state))
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let Some(flush) = self.flush.take() else { return };
+        if !flush.is_dirty(&self.state) {
+            return;
+        }
+        let agent_id = self.state.id.clone();
+        // Half-finished changes are not worth keeping
+        if std::thread::panicking() {
+            tracing::warn!(%agent_id, "Skipping save on drop: agent dropped during a panic");
+            return;
+        }
+        let rows = match to_rows(&self.config, &self.state) {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::warn!(%agent_id, error = %e, "Skipping save on drop");
+                return;
+            }
+        };
+        
+        // The write runs on its own thread so a locked database can't hold
+        // up the drop for longer than the timeout
+        let FlushOnDrop { storage, timeout, .. } = flush;
+        let (sender, receiver) = mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("letta-flush".to_string())
+            .spawn(move || {
+                let _ = sender.send(storage.save_agent_snapshot(&rows.agent, &rows.blocks, &rows.messages));
+            });
+        if let Err(e) = spawned {
+            tracing::warn!(%agent_id, error = %e, "Skipping save on drop");
+            return;
+        }
+        match receiver.recv_timeout(timeout) {
+            Ok(Ok(())) => tracing::debug!(%agent_id, "Saved agent on drop"),
+            Ok(Err(e)) => tracing::warn!(%agent_id, error = %e, "Save on drop failed"),
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::warn!(%agent_id, ?timeout, "Save on drop timed out; it may still finish in the background")
+            }
+            Err(RecvTimeoutError::Disconnected) => tracing::warn!(%agent_id, "Save on drop panicked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{CompletionRequest, ToyConfig, ToyProvider};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_save_and_load_round_trip() {
+        let storage = Storage::memory().unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        agent.step("Hello!".to_string()).await.unwrap();
+
+        agent.save(&storage).unwrap();
+        // Saving twice does not duplicate history
+        agent.save(&storage).unwrap();
+
+        let (config, state) = load_agent(&storage, &agent.state.id).unwrap();
+        assert_eq!(config.name, agent.config.name);
+        assert_eq!(state.memory.get_block("human").unwrap().value, "Likes tea");
+        let ids: Vec<_> = state.messages.messages.iter().map(|m| &m.id).collect();
+        let expected: Vec<_> = agent.state.messages.messages.iter().map(|m| &m.id).collect();
+        assert_eq!(ids, expected);
+
+        assert!(matches!(load_agent(&storage, "missing"), Err(LettaError::AgentNotFound(_))));
+        
+        storage.soft_delete_agent(&agent.state.id).unwrap();
+        assert!(matches!(load_agent(&storage, &agent.state.id), Err(LettaError::AgentNotFound(_))));
+        storage.restore_agent(&agent.state.id).unwrap();
+        assert!(load_agent(&storage, &agent.state.id).is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_load_survives_drop() {
+        let storage = Storage::memory().unwrap();
+        let toy = || Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig { name: "keeper".to_string(), ..Default::default() }, toy());
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        agent.step("Hello!".to_string()).await.unwrap();
+        agent.step("Still there?".to_string()).await.unwrap();
+        agent.save(&storage).unwrap();
+        let id = agent.state.id.clone();
+        let history: Vec<_> = agent.state.messages.messages.iter().map(|m| (m.id.clone(), m.content.clone())).collect();
+        drop(agent);
+        
+        let loaded = Agent::load(&storage, &id, toy()).unwrap();
+        assert_eq!(loaded.state.id, id);
+        assert_eq!(loaded.config.name, "keeper");
+        assert_eq!(loaded.get_memory_block("human").as_deref(), Some("Likes tea"));
+        let loaded_history: Vec<_> = loaded.state.messages.messages.iter().map(|m| (m.id.clone(), m.content.clone())).collect();
+        assert_eq!(loaded_history, history);
+        
+        assert!(matches!(Agent::load(&storage, "missing", toy()), Err(LettaError::AgentNotFound(_))));
+    }
+    
+    #[test]
+    fn test_load_legacy_rows() {
+        let storage = Storage::memory().unwrap();
+        // Written before config and state were stored: both are `{}`
+        let stored = StoredAgent::new("old-timer", "You are terse.");
+        storage.create_agent(&stored).unwrap();
+        storage.upsert_block(&StoredBlock::new(&stored.id, "persona", "Grumpy")).unwrap();
+        storage.add_message(&StoredMessage::new(&stored.id, "user", "Hi")).unwrap();
+        storage.add_message(&StoredMessage::new(&stored.id, "assistant", "What?")).unwrap();
+        
+        let provider = Box::new(ToyProvider::new(ToyConfig::default()));
+        let agent = Agent::load(&storage, &stored.id, provider).unwrap();
+        assert_eq!(agent.state.id, stored.id);
+        assert_eq!(agent.config.name, "old-timer");
+        assert_eq!(agent.config.system_prompt, "You are terse.");
+        assert_eq!(agent.config.model, AgentConfig::default().model);
+        assert_eq!(agent.get_memory_block("persona").as_deref(), Some("Grumpy"));
+        let contents: Vec<_> = agent.state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Hi", "What?"]);
+    }
+    
+    /// A state exercising every role, tool calls, metadata and block limits,
+    /// varied by `seed`
+    fn sample_state(seed: usize) -> AgentState {
+        let mut state = AgentState::new(format!("agent-{}", seed));
+        state.memory.blocks_mut().insert("notes".to_string(), MemoryBlock {
+            label: "notes".to_string(),
+            description: format!("Notes {}", seed),
+            value: "x".repeat(seed),
+            limit: seed * 100,
+        });
+        state.metadata = serde_json::json!({ "seed": seed });
+        for i in 0..seed * 3 {
+            let mut message = match i % 4 {
+                0 => Message::system(format!("System {}", i)),
+                1 => Message::user(format!("User {}", i)),
+                2 => Message::assistant("").with_tool_calls(vec![ToolCallInfo {
+                    id: format!("call_{}", i),
+                    name: "archival_search".to_string(),
+                    arguments: serde_json::json!({ "query": i }),
+                }]),
+                _ => Message::tool(format!("call_{}", i - 1), "No results"),
+            };
+            // Equal timestamps leave the order to `seq`
+            message.timestamp = state.created_at + chrono::Duration::seconds((i / 2) as i64);
+            if i % 3 == 0 {
+                message.metadata.insert("pinned".to_string(), serde_json::json!(true));
+            }
+            state.messages.push(message);
+        }
+        state
+    }
+    
+    #[test]
+    fn test_rows_round_trip() {
+        for seed in 0..8 {
+            let config = AgentConfig { name: format!("agent-{}", seed), temperature: 0.1 * seed as f32, ..Default::default() };
+            let state = sample_state(seed);
+            
+            let AgentRows { agent, blocks, mut messages } = to_rows(&config, &state).unwrap();
+            assert!(agent.state["messages"]["messages"].as_array().unwrap().is_empty());
+            // Storage assigns seq on insert and may return rows in any order
+            for (seq, message) in messages.iter_mut().enumerate() {
+                message.seq = seq as i64 + 1;
+            }
+            messages.reverse();
+            
+            let (loaded_config, loaded_state) = from_rows(&agent, blocks, messages).unwrap();
+            assert_eq!(serde_json::to_value(&loaded_config).unwrap(), serde_json::to_value(&config).unwrap());
+            assert_eq!(serde_json::to_value(&loaded_state).unwrap(), serde_json::to_value(&state).unwrap(), "seed {}", seed);
+        }
+    }
+    
+    #[test]
+    fn test_from_rows_corrupted_columns() {
+        let mut state = sample_state(2);
+        state.memory.blocks_mut().get_mut("notes").unwrap().limit = usize::MAX;
+        let AgentRows { mut agent, blocks, messages } = to_rows(&AgentConfig::default(), &state).unwrap();
+        assert_eq!(blocks.iter().find(|b| b.label == "notes").unwrap().char_limit, i32::MAX);
+        
+        agent.state = serde_json::json!({ "not": "a state" });
+        assert!(matches!(from_rows(&agent, blocks.clone(), messages.clone()), Err(LettaError::Serialization(_))));
+        let (config, loaded) = from_rows_or_default(&agent, blocks, messages).unwrap();
+        assert_eq!(config.name, AgentConfig::default().name);
+        assert_eq!(loaded.id, state.id);
+        assert_eq!(loaded.memory.get_block("notes").unwrap().value, "xx");
+        assert_eq!(loaded.messages.messages.len(), state.messages.messages.len());
+    }
+    
+    #[tokio::test]
+    async fn test_export_all_to_af() {
+        let storage = Storage::memory().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        agent.step("Hello!".to_string()).await.unwrap();
+        agent.save(&storage).unwrap();
+        storage.add_chunk(&letta_storage::StoredChunk::new(&agent.state.id, "notes", "Tea is green")).unwrap();
+        
+        let mut broken = StoredAgent::new("broken", "Test prompt");
+        broken.state = serde_json::json!({ "not": "a state" });
+        storage.create_agent(&broken).unwrap();
+        // A file from an earlier export keeps its name
+        std::fs::write(dir.path().join(format!("{}.af", agent.state.id)), "earlier").unwrap();
+        
+        let report = export_all_to_af(&storage, dir.path(), ExportOptions::default()).unwrap();
+        assert_eq!(report.written, vec![dir.path().join(format!("{}-2.af", agent.state.id))]);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.skipped[0].contains(&broken.id));
+        assert_eq!(std::fs::read_to_string(dir.path().join(format!("{}.af", agent.state.id))).unwrap(), "earlier");
+        
+        let af = AgentFile::from_reader(std::fs::File::open(&report.written[0]).unwrap()).unwrap();
+        let (config, state) = AgentFile::import(&af).unwrap();
+        assert_eq!(config.name, agent.config.name);
+        assert_eq!(state.memory.get_block("human").unwrap().value, "Likes tea");
+        assert_eq!(state.messages.messages.len(), agent.state.messages.messages.len());
+        assert_eq!(state.archival_entries.len(), 1);
+        assert_eq!(state.archival_entries[0]["text"], "Tea is green");
+    }
+    
+    #[tokio::test]
+    async fn test_slow_storage_does_not_stall_completions() {
+        let storage = Storage::memory().unwrap().to_async();
+        let provider = ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() });
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() })));
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        
+        // A single-threaded runtime: a blocking call would hold up everything else
+        let started = Instant::now();
+        let slow_save = async {
+            storage.run(|_| {
+                std::thread::sleep(Duration::from_millis(300));
+                Ok::<_, letta_storage::StorageError>(())
+            }).await.unwrap();
+            agent.save_async(&storage).await.unwrap();
+            started.elapsed()
+        };
+        let completion = async {
+            let request = CompletionRequest {
+                prompt: "Hello".to_string(),
+                messages: vec![],
+                tools: Vec::new(),
+                temperature: None,
+                max_tokens: None,
+                stream: false,
+                stop: vec![],
+                cancel: Default::default(),
+            };
+            provider.complete(request).await.unwrap();
+            started.elapsed()
+        };
+        
+        let (saved_after, completed_after) = tokio::join!(slow_save, completion);
+        assert!(completed_after < Duration::from_millis(150), "completion waited {:?}", completed_after);
+        assert!(saved_after >= Duration::from_millis(300));
+        assert!(storage.get_agent(&agent.state.id).await.unwrap().is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_dirty_agent_is_saved_on_drop() {
+        let storage = Storage::memory().unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.flush_on_drop(storage.clone(), DEFAULT_FLUSH_TIMEOUT);
+        agent.step("Remember me".to_string()).await.unwrap();
+        agent.save(&storage).unwrap();
+        assert!(!agent.needs_flush());
+        
+        // Changes after the last save, including ones made on the buffer directly
+        agent.step("And this".to_string()).await.unwrap();
+        agent.state.messages.push(Message::user("Pushed directly"));
+        assert!(agent.needs_flush());
+        let id = agent.state.id.clone();
+        let expected: Vec<_> = agent.state.messages.messages.iter().map(|m| m.id.clone()).collect();
+        drop(agent);
+        
+        let (_, state) = load_agent(&storage, &id).unwrap();
+        let ids: Vec<_> = state.messages.messages.iter().map(|m| m.id.clone()).collect();
+        assert_eq!(ids, expected);
+        
+        // Disarmed agents, and agents without changes, write nothing
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut disarmed = Agent::new(AgentConfig::default(), provider);
+        disarmed.flush_on_drop(storage.clone(), DEFAULT_FLUSH_TIMEOUT);
+        disarmed.send_only("Forget me".to_string());
+        disarmed.disarm_flush_on_drop();
+        let disarmed_id = disarmed.state.id.clone();
+        drop(disarmed);
+        assert!(storage.get_agent(&disarmed_id).unwrap().is_none());
+        
+        let (config, state) = load_agent(&storage, &id).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut reopened = Agent::new(config, provider).with_state(state);
+        reopened.flush_on_drop(storage.clone(), DEFAULT_FLUSH_TIMEOUT);
+        assert!(reopened.flush().unwrap());
+        assert!(!reopened.flush().unwrap());
+    }
+    
+    #[tokio::test]
+    async fn test_attached_storage_saves_each_step() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.attach_storage(storage.clone());
+        agent.set_memory_block("human", "Likes tea").unwrap();
+        agent.add_archival("notes", "Tea is green");
+        let result = agent.step("Hello!".to_string()).await.unwrap();
+        assert!(result.warnings.is_empty());
+        
+        let (_, state) = load_agent(&storage, &agent.state.id).unwrap();
+        assert_eq!(state.memory.get_block("human").unwrap().value, "Likes tea");
+        assert_eq!(state.messages.messages.len(), agent.state.messages.messages.len());
+        let chunks = storage.get_chunks(&agent.state.id).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].folder.as_str(), chunks[0].text.as_str()), ("notes", "Tea is green"));
+        
+        // Later steps add only what is new, and exports don't repeat mirrored entries
+        agent.add_archival("notes", "Coffee is brown");
+        agent.step("Again".to_string()).await.unwrap();
+        assert_eq!(storage.get_chunks(&agent.state.id).unwrap().len(), 2);
+        assert_eq!(storage.count_messages(&agent.state.id).unwrap(), agent.state.messages.messages.len());
+        let stored = storage.get_agent(&agent.state.id).unwrap().unwrap();
+        assert_eq!(export_parts(&storage, &stored).unwrap().1.archival_entries.len(), 2);
+        
+        agent.detach_storage();
+        agent.step("Unsaved".to_string()).await.unwrap();
+        assert!(storage.count_messages(&agent.state.id).unwrap() < agent.state.messages.messages.len());
+    }
+    
+    #[tokio::test]
+    async fn test_pruned_messages_stay_pruned() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.attach_storage(storage.clone());
+        for text in ["one", "two", "three"] {
+            agent.step(text.to_string()).await.unwrap();
+        }
+        
+        let policy = letta_storage::RetentionPolicy { max_count: Some(2), ..Default::default() };
+        assert_eq!(storage.prune_messages(&agent.state.id, &policy).unwrap(), 4);
+        agent.step("four".to_string()).await.unwrap();
+        
+        let (_, state) = load_agent(&storage, &agent.state.id).unwrap();
+        let sent: Vec<_> = state.messages.messages.iter()
+            .filter(|m| m.role == MessageRole::User)
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(sent, ["three", "four"]);
+    }
+    
+    #[tokio::test]
+    async fn test_regenerated_reply_replaces_the_stored_one() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let script = serde_json::from_value(serde_json::json!([
+            { "text": "First reply" },
+            { "text": "Second reply" },
+        ])).unwrap();
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, script, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.attach_storage(storage.clone());
+        agent.step("Hello".to_string()).await.unwrap();
+        agent.regenerate(None).await.unwrap();
+        
+        let (_, state) = load_agent(&storage, &agent.state.id).unwrap();
+        let contents: Vec<_> = state.messages.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Hello", "Second reply"]);
+    }
+    
+    #[tokio::test]
+    async fn test_fork_is_saved_as_a_new_agent() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.attach_storage(storage.clone());
+        agent.add_archival("notes", "Tea is green");
+        agent.step("Hello!".to_string()).await.unwrap();
+        agent.step("Again".to_string()).await.unwrap();
+        
+        let first_reply = agent.state.messages.messages[1].id.clone();
+        let mut fork = agent.fork_at(&first_reply).unwrap();
+        let (_, state) = load_agent(&storage, &fork.state.id).unwrap();
+        assert_eq!(state.messages.messages.len(), 2);
+        assert_eq!(state.metadata["parent_agent_id"], agent.state.id.as_str());
+        assert_eq!(storage.get_chunks(&fork.state.id).unwrap().len(), 1);
+        assert_eq!(storage.count_messages(&agent.state.id).unwrap(), 4);
+        
+        fork.step("Something else".to_string()).await.unwrap();
+        assert_eq!(storage.count_messages(&fork.state.id).unwrap(), 4);
+        assert_eq!(storage.count_messages(&agent.state.id).unwrap(), 4);
+    }
+    
+    #[tokio::test]
+    async fn test_failed_step_save_warns_unless_strict() {
+        let storage = Arc::new(Storage::memory().unwrap());
+        let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+        let mut agent = Agent::new(AgentConfig::default(), provider);
+        agent.attach_storage(storage.clone());
+        // Storage refuses empty embeddings, so every save fails
+        agent.state.archival_entries.push(serde_json::json!({ "folder": "notes", "text": "Broken", "embedding": [], "embedding_model": "toy" }));
+        
+        let result = agent.step("Hello!".to_string()).await.unwrap();
+        assert_eq!(result.warnings.len(), 1);
+        assert!(result.warnings[0].contains("empty embedding"), "{:?}", result.warnings);
+        assert!(storage.get_agent(&agent.state.id).unwrap().is_none());
+        
+        agent.config.strict_persistence = true;
+        let error = agent.step("Again".to_string()).await.unwrap_err();
+        assert!(matches!(error, LettaError::Storage(_)), "{:?}", error);
+    }
+}
@@ -1,15 +1,92 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
 use async_trait::async_trait;
-use crate::error::Result;
+use futures::StreamExt;
+use crate::cancel::CancellationToken;
+use crate::error::{LettaError, ProviderError, ProviderErrorKind, Result};
+use crate::message::MessageRole;
+use crate::retry::RetryConfig;
+use crate::telemetry::Stopwatch;
+use crate::tokenizer::{HeuristicTokenizer, Tokenizer};
 use crate::tool::ToolCall;
 
+#[cfg(feature = "anthropic")]
+pub mod anthropic;
+#[cfg(feature = "openai")]
+pub mod openai;
+#[cfg(feature = "openai")]
+pub mod openrouter;
+#[cfg(feature = "storage")]
+pub mod cache;
+pub mod logging;
+#[cfg(feature = "http")]
+pub mod rate_limit;
+#[cfg(feature = "http")]
+pub mod timeout;
+pub mod toy;
+
+#[cfg(feature = "storage")]
+pub use cache::CachingProvider;
+pub use logging::LoggingProvider;
+#[cfg(feature = "http")]
+pub use rate_limit::{RateLimitedProvider, RateLimiter};
+#[cfg(feature = "http")]
+pub use timeout::TimeoutProvider;
+pub use toy::{ToyEmbedder, ToyProvider};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompletionRequest {
+    /// The whole context flattened into one text, for text-completion
+    /// providers
     pub prompt: String,
+    /// The same context as role-separated messages, for chat providers; the
+    /// first is the system prompt with memory. Empty for requests built by
+    /// hand, which only set `prompt`.
+    #[serde(default)]
+    pub messages: Vec<ChatMessage>,
     pub tools: Vec<serde_json::Value>,
     pub temperature: Option<f32>,
     pub max_tokens: Option<usize>,
     pub stream: bool,
+    /// End the reply before the first of these, leaving it out
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// Cancelled when the caller gives up. Dropping the provider's future
+    /// stops most work; providers generating locally also check it between
+    /// tokens.
+    #[serde(skip)]
+    pub cancel: CancellationToken,
+}
+
+impl CompletionRequest {
+    /// Where in `text` the reply should have stopped, if it reached one of
+    /// the stop sequences
+    pub fn stop_index(&self, text: &str) -> Option<usize> {
+        self.stop.iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| text.find(stop.as_str()))
+            .min()
+    }
+}
+
+/// One message of a chat-style request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: MessageRole,
+    pub content: String,
+    /// Calls an assistant message made
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// The call a tool message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: MessageRole, content: impl Into<String>) -> Self {
+        Self { role, content: content.into(), tool_calls: Vec::new(), tool_call_id: None }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,17 +97,31 @@ pub struct Completion {
     pub usage: TokenUsage,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct TokenUsage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize,
+    /// What the provider charged, for those that report it (OpenRouter)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost_usd: Option<f64>,
+}
+
+impl TokenUsage {
+    pub fn add(&mut self, other: &TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        if let Some(cost) = other.cost_usd {
+            *self.cost_usd.get_or_insert(0.0) += cost;
+        }
+    }
 }
 
 impl Completion {
     pub fn text(content: impl Into<String>) -> Self {
         let text = content.into();
-        let tokens = text.len() / 4; // Rough estimate
+        let tokens = HeuristicTokenizer.count_tokens(&text);
         Self {
             text,
             tool_calls: vec![],
@@ -39,6 +130,7 @@ impl Completion {
                 prompt_tokens: 0,
                 completion_tokens: tokens,
                 total_tokens: tokens,
+                cost_usd: None,
             },
         }
     }
@@ -52,22 +144,345 @@ impl Completion {
         self.request_heartbeat = true;
         self
     }
+    
+    /// Cut the text at the first of `request`'s stop sequences, for
+    /// providers that can't stop generating there themselves
+    pub fn stopped_for(mut self, request: &CompletionRequest) -> Self {
+        if let Some(index) = request.stop_index(&self.text) {
+            self.text.truncate(index);
+            let tokens = HeuristicTokenizer.count_tokens(&self.text);
+            self.usage.total_tokens = self.usage.prompt_tokens + tokens;
+            self.usage.completion_tokens = tokens;
+        }
+        self
+    }
+    
+    /// The completion as stream chunks: its text as one delta, each tool
+    /// call whole, then `Done`
+    pub fn into_chunks(self) -> Vec<CompletionChunk> {
+        let mut chunks = Vec::with_capacity(self.tool_calls.len() + 2);
+        if !self.text.is_empty() {
+            chunks.push(CompletionChunk::Text { delta: self.text });
+        }
+        for (index, call) in self.tool_calls.into_iter().enumerate() {
+            chunks.push(CompletionChunk::ToolCall {
+                index,
+                id: Some(call.id),
+                name: Some(call.name),
+                arguments: call.arguments.to_string(),
+            });
+        }
+        chunks.push(CompletionChunk::Done {
+            usage: self.usage,
+            request_heartbeat: self.request_heartbeat,
+        });
+        chunks
+    }
+}
+
+/// One piece of a streamed completion
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CompletionChunk {
+    /// More of the reply text
+    Text { delta: String },
+    /// Part of the tool call at `index`. `id` and `name` come with its first
+    /// fragment; `arguments` is JSON text to append to what came before.
+    ToolCall {
+        index: usize,
+        id: Option<String>,
+        name: Option<String>,
+        arguments: String,
+    },
+    /// Always the last chunk
+    Done {
+        usage: TokenUsage,
+        request_heartbeat: bool,
+    },
+}
+
+/// The stream returned by `LlmProvider::complete_stream`; `Send` except with
+/// the `wasm` feature
+#[cfg(not(feature = "wasm"))]
+pub type CompletionStream<'a> = futures::stream::BoxStream<'a, Result<CompletionChunk>>;
+#[cfg(feature = "wasm")]
+pub type CompletionStream<'a> = futures::stream::LocalBoxStream<'a, Result<CompletionChunk>>;
+
+/// Reassembles a `Completion` from the chunks of a stream
+#[derive(Debug, Default)]
+pub struct CompletionAccumulator {
+    text: String,
+    tool_calls: Vec<(String, String, String)>,
+    done: Option<(TokenUsage, bool)>,
+}
+
+impl CompletionAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Text received so far
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    
+    pub fn push(&mut self, chunk: CompletionChunk) -> Result<()> {
+        match chunk {
+            CompletionChunk::Text { delta } => self.text.push_str(&delta),
+            CompletionChunk::ToolCall { index, id, name, arguments } => {
+                if index == self.tool_calls.len() {
+                    self.tool_calls.push(Default::default());
+                }
+                let (call_id, call_name, call_arguments) = self.tool_calls.get_mut(index)
+                    .ok_or_else(|| ProviderError::new(ProviderErrorKind::InvalidResponse, format!("Stream skipped to tool call {}", index)))?;
+                if let Some(id) = id {
+                    *call_id = id;
+                }
+                if let Some(name) = name {
+                    *call_name = name;
+                }
+                call_arguments.push_str(&arguments);
+            }
+            CompletionChunk::Done { usage, request_heartbeat } => self.done = Some((usage, request_heartbeat)),
+        }
+        Ok(())
+    }
+    
+    /// The completed `Completion`; fails if the stream never sent `Done` or
+    /// a tool call's arguments aren't JSON
+    pub fn finish(self) -> Result<Completion> {
+        let (usage, request_heartbeat) = self.done
+            .ok_or_else(|| ProviderError::new(ProviderErrorKind::Transport, "Completion stream ended early"))?;
+        let tool_calls = self.tool_calls.into_iter()
+            .map(|(id, name, arguments)| {
+                let arguments = if arguments.is_empty() {
+                    serde_json::json!({})
+                } else {
+                    serde_json::from_str(&arguments).map_err(|e| {
+                        ProviderError::new(ProviderErrorKind::InvalidResponse, format!("Streamed arguments for {} are not JSON: {}", name, e))
+                    })?
+                };
+                Ok(ToolCall { id, name, arguments })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Completion { text: self.text, tool_calls, request_heartbeat, usage })
+    }
 }
 
-#[async_trait]
-pub trait LlmProvider: Send + Sync {
+/// `Send` on native targets. With the `wasm` feature providers may hold
+/// JS handles, which are not `Send`, so every type qualifies.
+#[cfg(not(feature = "wasm"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(feature = "wasm"))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(feature = "wasm")]
+pub trait MaybeSend {}
+#[cfg(feature = "wasm")]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` on native targets; every type with the `wasm` feature
+#[cfg(not(feature = "wasm"))]
+pub trait MaybeSync: Sync {}
+#[cfg(not(feature = "wasm"))]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(feature = "wasm")]
+pub trait MaybeSync {}
+#[cfg(feature = "wasm")]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// Implementations use `#[async_trait]`, or `#[async_trait(?Send)]` when
+/// built with the `wasm` feature, whose futures run on a single-threaded
+/// executor and need not be `Send`.
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+pub trait LlmProvider: MaybeSend + MaybeSync {
     async fn complete(&self, request: CompletionRequest) -> Result<Completion>;
     
+    /// Like `complete`, but yields the completion in chunks as it is
+    /// generated, ending with `CompletionChunk::Done`. The default
+    /// implementation yields the whole completion at once.
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        let completion = self.complete(request).await?;
+        Ok(Box::pin(futures::stream::iter(completion.into_chunks().into_iter().map(Ok))))
+    }
+    
     async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
         // Default implementation returns empty embeddings
         Ok(texts.iter().map(|_| vec![0.0; 768]).collect())
     }
     
+    /// `embed` in batches of `batch_size` texts, at most `max_concurrency`
+    /// at a time, for bulk imports. The vectors come back in input order.
+    async fn embed_batched(&self, texts: Vec<String>, batch_size: usize, max_concurrency: usize) -> Result<Vec<Vec<f32>>> {
+        embed_in_batches(texts, batch_size, max_concurrency, |batch| self.embed(batch)).await
+    }
+    
     fn name(&self) -> &str;
     
     fn max_tokens(&self) -> usize {
         8192
     }
+    
+    /// What the provider can do beyond plain completion. The default claims
+    /// tool support only, matching the other default methods: `embed` returns
+    /// zero vectors and `complete_stream` yields the whole completion at once.
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities { supports_tools: true, ..Default::default() }
+    }
+    
+    /// Whether the provider is reachable and accepts its credentials. The
+    /// default implementation asks for a one-token completion; providers with
+    /// a cheaper endpoint, such as a model list, use that instead.
+    async fn health(&self) -> Result<ProviderHealth> {
+        let started = Stopwatch::start();
+        self.complete(CompletionRequest {
+            prompt: "ping".to_string(),
+            messages: vec![ChatMessage::new(MessageRole::User, "ping")],
+            tools: vec![],
+            temperature: None,
+            max_tokens: Some(1),
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }).await?;
+        Ok(ProviderHealth::new(self.name(), started.elapsed()))
+    }
+}
+
+/// What an `LlmProvider` supports, so the agent only asks for what it can
+/// get back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderCapabilities {
+    /// Reads `CompletionRequest::tools` and replies with tool calls
+    pub supports_tools: bool,
+    /// `embed` returns real vectors rather than the zero-vector default
+    pub supports_embeddings: bool,
+    /// `complete_stream` yields chunks as they are generated
+    pub supports_streaming: bool,
+    /// Most tool schemas one request may carry, if limited
+    pub max_tools: Option<usize>,
+}
+
+/// A successful `LlmProvider::health` check
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProviderHealth {
+    pub provider: String,
+    /// How long the check took, worth a warning when a local model is slow
+    pub latency_ms: u64,
+}
+
+impl ProviderHealth {
+    pub fn new(provider: &str, latency: std::time::Duration) -> Self {
+        Self { provider: provider.to_string(), latency_ms: latency.as_millis() as u64 }
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<P: LlmProvider + ?Sized> LlmProvider for Box<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        (**self).complete(request).await
+    }
+    
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        (**self).complete_stream(request).await
+    }
+    
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        (**self).embed(texts).await
+    }
+    
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+    
+    fn max_tokens(&self) -> usize {
+        (**self).max_tokens()
+    }
+    
+    fn capabilities(&self) -> ProviderCapabilities {
+        (**self).capabilities()
+    }
+    
+    async fn health(&self) -> Result<ProviderHealth> {
+        (**self).health().await
+    }
+}
+
+/// Turns text into vectors for semantic search, apart from any chat
+/// provider, so an agent can search archival memory offline while chatting
+/// with a cloud model. See `Agent::with_embedder`.
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+pub trait Embedder: MaybeSend + MaybeSync {
+    /// One vector per text, in order
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>>;
+    
+    /// Like `LlmProvider::embed_batched`
+    async fn embed_batched(&self, texts: Vec<String>, batch_size: usize, max_concurrency: usize) -> Result<Vec<Vec<f32>>> {
+        embed_in_batches(texts, batch_size, max_concurrency, |batch| self.embed(batch)).await
+    }
+    
+    /// The embedding model. Stored embeddings are tagged with it, so
+    /// switching models embeds entries again rather than comparing vectors
+    /// from different spaces.
+    fn name(&self) -> &str;
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<E: Embedder + ?Sized> Embedder for Box<E> {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        (**self).embed(texts).await
+    }
+    
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+}
+
+/// `texts` passed to `embed` in batches, keeping up to `max_concurrency`
+/// batches in flight, with the vectors put back in input order
+async fn embed_in_batches<F, Fut>(texts: Vec<String>, batch_size: usize, max_concurrency: usize, embed: F) -> Result<Vec<Vec<f32>>>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<Vec<f32>>>>,
+{
+    let total = texts.len();
+    let mut texts = texts.into_iter();
+    let batches = std::iter::from_fn(|| {
+        let batch: Vec<String> = texts.by_ref().take(batch_size.max(1)).collect();
+        (!batch.is_empty()).then_some(batch)
+    });
+    let mut embedded = futures::stream::iter(batches.map(|batch| {
+        let len = batch.len();
+        let vectors = embed(batch);
+        async move { (len, vectors.await) }
+    })).buffered(max_concurrency.max(1));
+    
+    let mut vectors = Vec::with_capacity(total);
+    while let Some((len, batch)) = embedded.next().await {
+        let batch = batch?;
+        if batch.len() != len {
+            return Err(ProviderError::new(ProviderErrorKind::InvalidResponse, format!("Asked for {} embeddings, got {}", len, batch.len())).into());
+        }
+        vectors.extend(batch);
+    }
+    Ok(vectors)
+}
+
+/// Which `Embedder` an agent uses; `ProviderFactory::create_embedder`
+/// builds it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum EmbeddingConfig {
+    /// `ToyEmbedder`'s hashed words
+    #[serde(rename = "toy")]
+    Toy,
+    /// A GGUF sentence-embedding model run on the device, through
+    /// `LlamaEmbedder` in letta-provider-llama
+    #[serde(rename = "local")]
+    Local { model_path: String },
 }
 
 // Provider configuration
@@ -78,6 +493,8 @@ pub enum ProviderConfig {
     Toy(ToyConfig),
     #[serde(rename = "openai")]
     OpenAI(OpenAIConfig),
+    #[serde(rename = "openrouter")]
+    OpenRouter(OpenRouterConfig),
     #[serde(rename = "anthropic")]
     Anthropic(AnthropicConfig),
     #[serde(rename = "llama")]
@@ -86,22 +503,151 @@ pub enum ProviderConfig {
     LettaCloud(LettaCloudConfig),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ToyConfig {
     pub deterministic: bool,
+    /// Replies for the first calls, one per call, before the usual canned ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub script: Vec<ScriptedTurn>,
+    /// A JSON file holding an array of turns, played after `script`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script_file: Option<PathBuf>,
+    /// Seed for the random draws: which reply to give when not
+    /// `deterministic`, and simulated latencies. The same seed and calls give
+    /// the same replies on every run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// Wait a random time of up to this long before each reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_latency_ms: Option<u64>,
+    /// Fail every nth call (the nth, the 2nth, and so on) with a `Provider`
+    /// error, scripted turns included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fail_every_n: Option<usize>,
+}
+
+/// One reply of a `ToyConfig` script
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScriptedTurn {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub tool_calls: Vec<ScriptedToolCall>,
+    #[serde(default)]
+    pub request_heartbeat: bool,
+    /// Fail the call with this provider error instead of replying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScriptedToolCall {
+    /// `call_<turn>_<index>` unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+impl ScriptedTurn {
+    /// The turns in the JSON array at `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<ScriptedTurn>> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("reading {}: {}", path.display(), e)))?;
+        serde_json::from_str(&text)
+            .map_err(|e| LettaError::InvalidConfig(format!("{}: invalid toy script: {}", path.display(), e)))
+    }
+}
+
+/// What the factory wraps around any provider, flattened into its config
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProviderOptions {
+    /// Fail calls that take longer than this with `LettaError::Timeout`
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// Append every request and completion to this JSONL file
+    #[serde(default)]
+    pub log_requests: Option<PathBuf>,
+    /// Replay completions of identical requests from storage
+    #[serde(default)]
+    pub cache: bool,
+}
+
+/// Settings shared by the providers called over HTTP, flattened into their
+/// configs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HttpOptions {
+    /// Send requests through this proxy (`http://` or
+    /// `https://`); otherwise the usual proxy environment variables apply
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Hosts to reach directly despite `proxy`, comma-separated as in `NO_PROXY`
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    /// Retries for rate-limited and failed requests
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Client-side limits on requests and tokens per minute, shared by
+    /// every provider with the same endpoint, API key and limits
+    #[serde(default)]
+    pub rpm: Option<u32>,
+    #[serde(default)]
+    pub tpm: Option<u32>,
+    #[serde(flatten)]
+    pub options: ProviderOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenAIConfig {
     pub api_key: String,
     pub model: String,
+    /// `https://api.openai.com/v1` unless set; for Azure, the resource
+    /// endpoint such as `https://my-resource.openai.azure.com`
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(flatten)]
+    pub http: HttpOptions,
+    /// Call an Azure OpenAI deployment, authenticating with an `api-key`
+    /// header rather than a bearer token
+    #[serde(default)]
+    pub azure: bool,
+    /// The Azure deployment serving `model`; the model name unless set
+    #[serde(default)]
+    pub deployment: Option<String>,
+    /// The Azure `api-version` query parameter
+    #[serde(default)]
+    pub api_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenRouterConfig {
+    pub api_key: String,
+    /// As OpenRouter names it, e.g. `anthropic/claude-3.5-sonnet`
+    pub model: String,
+    /// Sent as `HTTP-Referer` and `X-Title`, which OpenRouter attributes
+    /// requests to apps by
+    #[serde(default)]
+    pub site_url: Option<String>,
+    #[serde(default)]
+    pub app_name: Option<String>,
+    /// `https://openrouter.ai/api/v1` unless set
+    #[serde(default)]
     pub base_url: Option<String>,
+    #[serde(flatten)]
+    pub http: HttpOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AnthropicConfig {
     pub api_key: String,
     pub model: String,
+    /// `https://api.anthropic.com` unless set
+    #[serde(default)]
+    pub base_url: Option<String>,
+    #[serde(flatten)]
+    pub http: HttpOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,6 +655,8 @@ pub struct LlamaConfig {
     pub model_path: String,
     pub context_size: usize,
     pub n_threads: usize,
+    #[serde(flatten)]
+    pub options: ProviderOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,24 +664,348 @@ pub struct LettaCloudConfig {
     pub endpoint: String,
     pub api_key: String,
     pub model: String,
+    #[serde(flatten)]
+    pub http: HttpOptions,
+}
+
+/// The HTTP client for a cloud provider, sending through `proxy` if set.
+/// An invalid proxy URL is an `InvalidConfig` error.
+#[cfg(feature = "http")]
+pub(crate) fn http_client(proxy: Option<&str>, no_proxy: Option<&str>) -> Result<reqwest::Client> {
+    let builder = crate::retry::client_builder(proxy, no_proxy).map_err(|e| {
+        LettaError::InvalidConfig(format!("Invalid proxy {}: {}", proxy.unwrap_or_default(), e))
+    })?;
+    builder.build().map_err(|e| LettaError::Provider(format!("HTTP client could not be built: {}", e).into()))
+}
+
+/// A failure to send a request or read its response: a `Timeout` error if
+/// the client gave up waiting, `Transport` otherwise
+#[cfg(feature = "http")]
+pub(crate) fn transport_error(error: &reqwest::Error, message: String) -> LettaError {
+    let kind = match error.is_timeout() {
+        true => ProviderErrorKind::Timeout,
+        false => ProviderErrorKind::Transport,
+    };
+    ProviderError::new(kind, message).into()
+}
+
+/// " through proxy <proxy>" when `error` is a failure to connect with a
+/// proxy set, since then it is the proxy that could not be reached
+#[cfg(feature = "http")]
+pub(crate) fn via_proxy(proxy: Option<&str>, error: &reqwest::Error) -> String {
+    match proxy {
+        Some(proxy) if error.is_connect() => format!(" through proxy {}", proxy),
+        _ => String::new(),
+    }
 }
 
 // Provider factory
 pub struct ProviderFactory;
 
+/// API keys and endpoints `ProviderFactory::create_from_model` fills
+/// provider configs with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProviderEnv {
+    pub openai_api_key: Option<String>,
+    pub openai_base_url: Option<String>,
+    pub anthropic_api_key: Option<String>,
+    pub anthropic_base_url: Option<String>,
+    /// Where Ollama serves; `http://localhost:11434` unless set
+    pub ollama_host: Option<String>,
+}
+
+impl ProviderEnv {
+    /// Read `OPENAI_API_KEY`, `OPENAI_BASE_URL`, `ANTHROPIC_API_KEY`,
+    /// `ANTHROPIC_BASE_URL` and `OLLAMA_HOST` from the process environment
+    pub fn from_env() -> Self {
+        Self::from_vars(std::env::vars())
+    }
+    
+    /// Like `from_env`, reading `vars` instead; empty values count as unset
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut env = Self::default();
+        for (name, value) in vars {
+            let slot = match name.as_str() {
+                "OPENAI_API_KEY" => &mut env.openai_api_key,
+                "OPENAI_BASE_URL" => &mut env.openai_base_url,
+                "ANTHROPIC_API_KEY" => &mut env.anthropic_api_key,
+                "ANTHROPIC_BASE_URL" => &mut env.anthropic_base_url,
+                "OLLAMA_HOST" => &mut env.ollama_host,
+                _ => continue,
+            };
+            *slot = Some(value).filter(|value| !value.is_empty());
+        }
+        env
+    }
+}
+
+impl ProviderConfig {
+    /// The timeout, log and cache settings; the toy provider has none
+    pub fn options(&self) -> Option<&ProviderOptions> {
+        match self {
+            ProviderConfig::Toy(_) => None,
+            ProviderConfig::Llama(cfg) => Some(&cfg.options),
+            config => config.http().map(|http| &http.options),
+        }
+    }
+    
+    /// The connection settings of a provider called over HTTP
+    pub fn http(&self) -> Option<&HttpOptions> {
+        match self {
+            ProviderConfig::OpenAI(cfg) => Some(&cfg.http),
+            ProviderConfig::OpenRouter(cfg) => Some(&cfg.http),
+            ProviderConfig::Anthropic(cfg) => Some(&cfg.http),
+            ProviderConfig::LettaCloud(cfg) => Some(&cfg.http),
+            ProviderConfig::Toy(_) | ProviderConfig::Llama(_) => None,
+        }
+    }
+    
+    /// Where the provider's calls are logged, if anywhere
+    pub fn log_requests(&self) -> Option<&Path> {
+        self.options()?.log_requests.as_deref()
+    }
+    
+    /// How long the provider's calls may take, if bounded
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.options()?.timeout_ms.map(std::time::Duration::from_millis)
+    }
+    
+    /// The account whose limits apply, and the configured requests and
+    /// tokens per minute, if any are set
+    #[cfg(feature = "http")]
+    fn rate_limits(&self) -> Option<(String, Option<u32>, Option<u32>)> {
+        let (kind, base_url, api_key) = match self {
+            ProviderConfig::OpenAI(cfg) => ("openai", cfg.base_url.as_deref(), &cfg.api_key),
+            ProviderConfig::OpenRouter(cfg) => ("openrouter", cfg.base_url.as_deref(), &cfg.api_key),
+            ProviderConfig::Anthropic(cfg) => ("anthropic", cfg.base_url.as_deref(), &cfg.api_key),
+            ProviderConfig::LettaCloud(cfg) => ("letta", Some(cfg.endpoint.as_str()), &cfg.api_key),
+            ProviderConfig::Toy(_) | ProviderConfig::Llama(_) => return None,
+        };
+        let HttpOptions { rpm, tpm, .. } = *self.http()?;
+        let account = format!("{}|{}|{}", kind, base_url.unwrap_or_default(), api_key);
+        (rpm.is_some() || tpm.is_some()).then_some((account, rpm, tpm))
+    }
+    
+    /// What completions are cached under if `cache` is set: the provider
+    /// and model, since another model would answer differently
+    fn cache_scope(&self) -> Option<String> {
+        if !self.options()?.cache {
+            return None;
+        }
+        Some(match self {
+            ProviderConfig::OpenAI(cfg) if cfg.azure => format!(
+                "azure-openai:{}@{}",
+                cfg.deployment.as_deref().unwrap_or(&cfg.model),
+                cfg.base_url.as_deref().unwrap_or_default(),
+            ),
+            ProviderConfig::OpenAI(cfg) => format!("openai:{}", cfg.model),
+            ProviderConfig::OpenRouter(cfg) => format!("openrouter:{}", cfg.model),
+            ProviderConfig::Anthropic(cfg) => format!("anthropic:{}", cfg.model),
+            ProviderConfig::Llama(cfg) => format!("llama:{}", cfg.model_path),
+            ProviderConfig::LettaCloud(cfg) => format!("letta:{}@{}", cfg.model, cfg.endpoint),
+            ProviderConfig::Toy(_) => return None,
+        })
+    }
+    
+    /// The provider config serving `model`, by its name:
+    ///
+    /// - `toy`: the deterministic toy provider
+    /// - `gpt-*`, `o1*`, `o3*`, `o4*` or `openai:<model>`: OpenAI
+    /// - `claude-*` or `anthropic:<model>`: Anthropic
+    /// - `ollama:<model>`: a model served by Ollama, over its OpenAI API
+    /// - `llama:<path>`: a local GGUF file
+    ///
+    /// Cloud providers take their API key from `env`, failing with
+    /// `InvalidConfig` naming the variable if it is missing.
+    pub fn from_model(model: &str, env: &ProviderEnv) -> Result<ProviderConfig> {
+        let openai = |model: &str, api_key: String, base_url: Option<String>| ProviderConfig::OpenAI(OpenAIConfig {
+            api_key,
+            model: model.to_string(),
+            base_url,
+            http: HttpOptions::default(),
+            azure: false,
+            deployment: None,
+            api_version: None,
+        });
+        let key = |key: &Option<String>, variable: &str| key.clone().ok_or_else(|| {
+            LettaError::InvalidConfig(format!("Model {} needs an API key; set {}", model, variable))
+        });
+        
+        if model == "toy" {
+            return Ok(ProviderConfig::Toy(ToyConfig { deterministic: true, ..Default::default() }));
+        }
+        if let Some(name) = model.strip_prefix("ollama:") {
+            let host = env.ollama_host.as_deref().unwrap_or("http://localhost:11434");
+            let base_url = format!("{}/v1", host.trim_end_matches('/'));
+            // Ollama ignores the key, but OpenAI clients must send one
+            return Ok(openai(name, "ollama".to_string(), Some(base_url)));
+        }
+        if let Some(path) = model.strip_prefix("llama:") {
+            return Ok(ProviderConfig::Llama(LlamaConfig {
+                model_path: path.to_string(),
+                context_size: 4096,
+                n_threads: std::thread::available_parallelism().map_or(4, |n| n.get()),
+                options: ProviderOptions::default(),
+            }));
+        }
+        let openai_model = model.strip_prefix("openai:").or_else(|| {
+            ["gpt-", "o1", "o3", "o4"].iter().any(|prefix| model.starts_with(prefix)).then_some(model)
+        });
+        if let Some(name) = openai_model {
+            return Ok(openai(name, key(&env.openai_api_key, "OPENAI_API_KEY")?, env.openai_base_url.clone()));
+        }
+        let anthropic_model = model.strip_prefix("anthropic:")
+            .or_else(|| model.starts_with("claude-").then_some(model));
+        if let Some(name) = anthropic_model {
+            return Ok(ProviderConfig::Anthropic(AnthropicConfig {
+                api_key: key(&env.anthropic_api_key, "ANTHROPIC_API_KEY")?,
+                model: name.to_string(),
+                base_url: env.anthropic_base_url.clone(),
+                http: HttpOptions::default(),
+            }));
+        }
+        Err(LettaError::InvalidConfig(format!(
+            "Unknown model {}; name a provider, as in openai:{} or ollama:{}", model, model, model,
+        )))
+    }
+    
+    /// Fill in the API key from `vars`. An explicit key is kept as is;
+    /// `${NAME}` in it is replaced by the variable's value; an empty key is
+    /// read from the provider's variable, `OPENAI_API_KEY`,
+    /// `OPENROUTER_API_KEY`, `ANTHROPIC_API_KEY` or `LETTA_API_KEY`. An
+    /// unset variable is an `InvalidConfig` error naming it, so a missing key
+    /// fails here rather than with a 401 on the first call.
+    pub fn resolve_api_key(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        let (api_key, variable) = match self {
+            ProviderConfig::OpenAI(cfg) => (&mut cfg.api_key, "OPENAI_API_KEY"),
+            ProviderConfig::OpenRouter(cfg) => (&mut cfg.api_key, "OPENROUTER_API_KEY"),
+            ProviderConfig::Anthropic(cfg) => (&mut cfg.api_key, "ANTHROPIC_API_KEY"),
+            ProviderConfig::LettaCloud(cfg) => (&mut cfg.api_key, "LETTA_API_KEY"),
+            ProviderConfig::Toy(_) | ProviderConfig::Llama(_) => return Ok(()),
+        };
+        if !api_key.is_empty() && !api_key.contains("${") {
+            return Ok(());
+        }
+        // Empty values count as unset
+        let vars: HashMap<String, String> = vars.into_iter().filter(|(_, value)| !value.is_empty()).collect();
+        *api_key = match api_key.is_empty() {
+            true => vars.get(variable).cloned().ok_or_else(|| {
+                LettaError::InvalidConfig(format!("`api_key` is empty and {} is not set", variable))
+            })?,
+            false => crate::config::expand(api_key, &vars)
+                .map_err(|e| LettaError::InvalidConfig(format!("`api_key`: {}", e)))?,
+        };
+        Ok(())
+    }
+    
+    fn api_key(&self) -> Option<&str> {
+        match self {
+            ProviderConfig::OpenAI(cfg) => Some(&cfg.api_key),
+            ProviderConfig::OpenRouter(cfg) => Some(&cfg.api_key),
+            ProviderConfig::Anthropic(cfg) => Some(&cfg.api_key),
+            ProviderConfig::LettaCloud(cfg) => Some(&cfg.api_key),
+            ProviderConfig::Toy(_) | ProviderConfig::Llama(_) => None,
+        }
+    }
+}
+
 impl ProviderFactory {
+    /// Create the provider `config` describes, wrapped in a `LoggingProvider`
+    /// if it sets `log_requests`, and in a `RateLimitedProvider` if it sets
+    /// `rpm` or `tpm`. Caching needs storage; see `create_with_storage`.
+    /// The API key is resolved from the process environment first; see
+    /// `ProviderConfig::resolve_api_key`.
     pub async fn create(config: ProviderConfig) -> Result<Box<dyn LlmProvider>> {
+        if config.cache_scope().is_some() {
+            tracing::warn!("Provider config sets `cache` but no storage was given; completions won't be cached");
+        }
+        Self::create_uncached(config).await
+    }
+    
+    /// Create the provider serving `model`, resolved by
+    /// `ProviderConfig::from_model`
+    pub async fn create_from_model(model: &str, env: &ProviderEnv) -> Result<Box<dyn LlmProvider>> {
+        Self::create(ProviderConfig::from_model(model, env)?).await
+    }
+    
+    /// Create the embedder `config` describes. Local models run in
+    /// letta-provider-llama, which builds them with `LlamaEmbedder`.
+    pub fn create_embedder(config: EmbeddingConfig) -> Result<Box<dyn Embedder>> {
+        match config {
+            EmbeddingConfig::Toy => Ok(Box::new(ToyEmbedder)),
+            EmbeddingConfig::Local { .. } => Err(LettaError::Provider(
+                "Local embedding models are built by letta-provider-llama; use its LlamaEmbedder".into(),
+            )),
+        }
+    }
+    
+    /// Like `create`, but a config setting `cache` gets a `CachingProvider`
+    /// over `storage`
+    #[cfg(feature = "storage")]
+    pub async fn create_with_storage(config: ProviderConfig, storage: &letta_storage::Storage) -> Result<Box<dyn LlmProvider>> {
+        let cache_scope = config.cache_scope();
+        let provider = Self::create_uncached(config).await?;
+        match cache_scope {
+            // Outside the rate limiter, so hits don't wait
+            Some(scope) => Ok(Box::new(CachingProvider::new(provider, storage).with_scope(scope))),
+            None => Ok(provider),
+        }
+    }
+    
+    async fn create_uncached(mut config: ProviderConfig) -> Result<Box<dyn LlmProvider>> {
+        config.resolve_api_key(std::env::vars())?;
+        let log_requests = config.log_requests().map(Path::to_path_buf);
+        let api_key = config.api_key().unwrap_or_default().to_string();
+        #[cfg(feature = "http")]
+        let rate_limits = config.rate_limits();
+        #[cfg(feature = "http")]
+        let timeout = config.timeout();
+        
+        let mut provider = Self::create_unlogged(config).await?;
+        // Innermost, so a timed out call is still logged with its error
+        #[cfg(feature = "http")]
+        if let Some(timeout) = timeout {
+            provider = Box::new(TimeoutProvider::new(provider, timeout));
+        }
+        if let Some(path) = log_requests {
+            provider = Box::new(LoggingProvider::new(provider).log_to(path)?.redact(api_key));
+        }
+        // Outermost, so logged latencies leave out time spent waiting
+        #[cfg(feature = "http")]
+        if let Some((account, rpm, tpm)) = rate_limits {
+            provider = Box::new(RateLimitedProvider::new(provider, RateLimiter::shared(&account, rpm, tpm)));
+        }
+        Ok(provider)
+    }
+    
+    async fn create_unlogged(config: ProviderConfig) -> Result<Box<dyn LlmProvider>> {
         match config {
             ProviderConfig::Toy(cfg) => {
-                Ok(Box::new(ToyProvider::new(cfg)))
+                Ok(Box::new(ToyProvider::from_config(cfg)?))
+            }
+            #[cfg(feature = "openai")]
+            ProviderConfig::OpenAI(cfg) => {
+                Ok(Box::new(openai::OpenAIProvider::new(cfg)?))
             }
-            ProviderConfig::OpenAI(_cfg) => {
-                // TODO: Implement OpenAI provider
-                Err(crate::error::LettaError::Provider("OpenAI provider not yet implemented".into()))
+            #[cfg(not(feature = "openai"))]
+            ProviderConfig::OpenAI(_) => {
+                Err(crate::error::LettaError::Provider("OpenAI provider needs letta-core's `openai` feature".into()))
             }
+            #[cfg(feature = "openai")]
+            ProviderConfig::OpenRouter(cfg) => {
+                Ok(Box::new(openrouter::OpenRouterProvider::new(cfg)?))
+            }
+            #[cfg(not(feature = "openai"))]
+            ProviderConfig::OpenRouter(_) => {
+                Err(crate::error::LettaError::Provider("OpenRouter provider needs letta-core's `openai` feature".into()))
+            }
+            #[cfg(feature = "anthropic")]
+            ProviderConfig::Anthropic(cfg) => {
+                Ok(Box::new(anthropic::AnthropicProvider::new(cfg)?))
+            }
+            #[cfg(not(feature = "anthropic"))]
             ProviderConfig::Anthropic(_cfg) => {
-                // TODO: Implement Anthropic provider
-                Err(crate::error::LettaError::Provider("Anthropic provider not yet implemented".into()))
+                Err(crate::error::LettaError::Provider("Anthropic provider needs letta-core's `anthropic` feature".into()))
             }
             ProviderConfig::Llama(_cfg) => {
                 // TODO: Implement Llama provider
@@ -147,73 +1019,276 @@ impl ProviderFactory {
     }
 }
 
-// Toy provider for testing
-pub struct ToyProvider {
-    config: ToyConfig,
-}
-
-impl ToyProvider {
-    pub fn new(config: ToyConfig) -> Self {
-        Self { config }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn request(prompt: &str) -> CompletionRequest {
+        CompletionRequest {
+            prompt: prompt.to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: true,
+            stop: vec![],
+            cancel: Default::default(),
+        }
     }
-}
-
-#[async_trait]
-impl LlmProvider for ToyProvider {
-    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
-        // Deterministic responses for testing
-        if request.prompt.contains("#DO_SEARCH") {
-            // Trigger archival search
-            Ok(Completion {
-                text: String::new(),
-                tool_calls: vec![ToolCall {
-                    id: "call_1".to_string(),
-                    name: "archival_search".to_string(),
-                    arguments: serde_json::json!({
-                        "query": "latest readings",
-                        "top_k": 3
-                    }),
-                }],
-                request_heartbeat: true,
-                usage: TokenUsage {
-                    prompt_tokens: request.prompt.len() / 4,
-                    completion_tokens: 10,
-                    total_tokens: request.prompt.len() / 4 + 10,
-                },
-            })
-        } else if request.prompt.contains("#MEMORY_UPDATE") {
-            // Update memory
-            Ok(Completion {
-                text: String::new(),
-                tool_calls: vec![ToolCall {
-                    id: "call_2".to_string(),
-                    name: "memory_replace".to_string(),
-                    arguments: serde_json::json!({
-                        "label": "human",
-                        "value": "Updated user information"
-                    }),
-                }],
-                request_heartbeat: false,
-                usage: TokenUsage {
-                    prompt_tokens: request.prompt.len() / 4,
-                    completion_tokens: 10,
-                    total_tokens: request.prompt.len() / 4 + 10,
-                },
-            })
-        } else if request.prompt.contains("Tool [") {
-            // Response after tool execution
-            Ok(Completion::text("Based on the search results, here's a summary of the latest readings: The most recent values show stable patterns with readings at 168 mg/dL and 112 mg/dL."))
-        } else {
-            // Default response
-            Ok(Completion::text(if self.config.deterministic {
-                "I understand your request. How can I help you further?"
+    
+    async fn collect(stream: CompletionStream<'_>) -> Vec<CompletionChunk> {
+        stream.map(|chunk| chunk.unwrap()).collect().await
+    }
+    
+    /// Only implements `complete`, so streams through the default
+    struct Whole;
+    
+    #[cfg_attr(feature = "wasm", async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait)]
+    impl LlmProvider for Whole {
+        async fn complete(&self, _request: CompletionRequest) -> Result<Completion> {
+            Ok(Completion::text("All at once"))
+        }
+        
+        fn name(&self) -> &str {
+            "whole"
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_default_stream_is_one_chunk() {
+        let chunks = collect(Whole.complete_stream(request("Hi")).await.unwrap()).await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], CompletionChunk::Text { delta: "All at once".into() });
+        assert!(matches!(chunks[1], CompletionChunk::Done { request_heartbeat: false, .. }));
+    }
+    
+    #[tokio::test]
+    async fn test_toy_streams_words() {
+        let toy = ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() });
+        for prompt in ["Hello", "#DO_SEARCH"] {
+            let whole = toy.complete(request(prompt)).await.unwrap();
+            let chunks = collect(toy.complete_stream(request(prompt)).await.unwrap()).await;
+            assert!(matches!(chunks.last(), Some(CompletionChunk::Done { .. })));
+            
+            let mut accumulator = CompletionAccumulator::new();
+            for chunk in chunks.iter().cloned() {
+                accumulator.push(chunk).unwrap();
+            }
+            let streamed = accumulator.finish().unwrap();
+            assert_eq!(streamed.text, whole.text);
+            assert_eq!(streamed.tool_calls.len(), whole.tool_calls.len());
+            assert_eq!(streamed.usage, whole.usage);
+            assert_eq!(streamed.request_heartbeat, whole.request_heartbeat);
+            if prompt == "Hello" {
+                let texts = chunks.iter().filter(|c| matches!(c, CompletionChunk::Text { .. })).count();
+                assert_eq!(texts, whole.text.split(' ').count());
             } else {
-                "This is a test response from the toy provider."
-            }))
+                assert_eq!(streamed.tool_calls[0].arguments, whole.tool_calls[0].arguments);
+            }
         }
     }
     
-    fn name(&self) -> &str {
-        "toy"
+    #[test]
+    fn test_accumulator_joins_tool_call_fragments() {
+        let mut accumulator = CompletionAccumulator::new();
+        let fragment = |index, id: Option<&str>, arguments: &str| CompletionChunk::ToolCall {
+            index,
+            id: id.map(String::from),
+            name: id.map(|_| "memory_append".to_string()),
+            arguments: arguments.to_string(),
+        };
+        accumulator.push(fragment(0, Some("call_1"), "{\"label\": ")).unwrap();
+        accumulator.push(fragment(1, Some("call_2"), "")).unwrap();
+        accumulator.push(fragment(0, None, "\"human\"}")).unwrap();
+        assert!(accumulator.push(fragment(3, None, "{}")).is_err());
+        
+        // Nothing is complete until `Done`
+        let unfinished = CompletionAccumulator::new();
+        assert!(unfinished.finish().is_err());
+        
+        accumulator.push(CompletionChunk::Done { usage: TokenUsage::default(), request_heartbeat: true }).unwrap();
+        let completion = accumulator.finish().unwrap();
+        assert_eq!(completion.tool_calls[0].id, "call_1");
+        assert_eq!(completion.tool_calls[0].arguments, serde_json::json!({ "label": "human" }));
+        assert_eq!(completion.tool_calls[1].arguments, serde_json::json!({}));
+        assert!(completion.request_heartbeat);
     }
-}
\ No newline at end of file
+    
+    #[tokio::test]
+    async fn test_create_from_model_names() {
+        let env = ProviderEnv::from_vars([
+            ("OPENAI_API_KEY".to_string(), "sk-openai".to_string()),
+            ("ANTHROPIC_API_KEY".to_string(), String::new()),
+            ("OLLAMA_HOST".to_string(), "http://ollama:11434/".to_string()),
+        ]);
+        
+        assert!(matches!(ProviderConfig::from_model("toy", &env), Ok(ProviderConfig::Toy(ToyConfig { deterministic: true, .. }))));
+        match ProviderConfig::from_model("gpt-4o-mini", &env).unwrap() {
+            ProviderConfig::OpenAI(cfg) => {
+                assert_eq!((cfg.model.as_str(), cfg.api_key.as_str(), cfg.base_url), ("gpt-4o-mini", "sk-openai", None));
+            }
+            other => panic!("{:?}", other),
+        }
+        match ProviderConfig::from_model("ollama:llama3.2", &env).unwrap() {
+            ProviderConfig::OpenAI(cfg) => {
+                assert_eq!(cfg.model, "llama3.2");
+                assert_eq!(cfg.base_url.as_deref(), Some("http://ollama:11434/v1"));
+            }
+            other => panic!("{:?}", other),
+        }
+        match ProviderConfig::from_model("llama:/models/tiny.gguf", &env).unwrap() {
+            ProviderConfig::Llama(cfg) => assert_eq!(cfg.model_path, "/models/tiny.gguf"),
+            other => panic!("{:?}", other),
+        }
+        
+        // An empty key is no key
+        match ProviderConfig::from_model("claude-3-5-haiku-latest", &env) {
+            Err(LettaError::InvalidConfig(message)) => assert!(message.contains("ANTHROPIC_API_KEY"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        match ProviderFactory::create_from_model("mistral-large", &env).await {
+            Err(LettaError::InvalidConfig(message)) => assert!(message.contains("mistral-large"), "{}", message),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        assert_eq!(ProviderFactory::create_from_model("toy", &env).await.unwrap().name(), "toy");
+    }
+    
+    #[test]
+    fn test_resolve_api_key_precedence() {
+        let vars = || [
+            ("OPENAI_API_KEY".to_string(), "sk-default".to_string()),
+            ("MY_KEY".to_string(), "sk-indirect".to_string()),
+            ("EMPTY_KEY".to_string(), String::new()),
+        ];
+        let resolved = |api_key: &str| -> Result<String> {
+            let mut config: ProviderConfig = serde_json::from_value(serde_json::json!({
+                "type": "openai", "api_key": api_key, "model": "gpt-4o-mini",
+            })).unwrap();
+            config.resolve_api_key(vars())?;
+            Ok(config.api_key().unwrap().to_string())
+        };
+        
+        // An explicit key beats `${NAME}`, which beats the default variable
+        assert_eq!(resolved("sk-explicit").unwrap(), "sk-explicit");
+        assert_eq!(resolved("${MY_KEY}").unwrap(), "sk-indirect");
+        assert_eq!(resolved("").unwrap(), "sk-default");
+        
+        for (api_key, variable) in [("${MISSING_KEY}", "MISSING_KEY"), ("${EMPTY_KEY}", "EMPTY_KEY")] {
+            match resolved(api_key) {
+                Err(LettaError::InvalidConfig(message)) => assert!(message.contains(variable), "{}", message),
+                other => panic!("{:?}", other),
+            }
+        }
+        let mut config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "letta", "endpoint": "https://api.letta.com", "api_key": "", "model": "letta",
+        })).unwrap();
+        match config.resolve_api_key(vars()) {
+            Err(LettaError::InvalidConfig(message)) => assert!(message.contains("LETTA_API_KEY"), "{}", message),
+            other => panic!("{:?}", other),
+        }
+        let mut toy = ProviderConfig::Toy(ToyConfig::default());
+        assert!(toy.resolve_api_key(vars()).is_ok());
+    }
+    
+    #[test]
+    fn test_shared_options_are_read_flat() {
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "letta", "endpoint": "https://api.letta.com", "api_key": "key", "model": "letta",
+            "proxy": "http://proxy.corp:3128", "rpm": 60, "timeout_ms": 5000, "cache": true,
+        })).unwrap();
+        assert_eq!(config.http().unwrap().proxy.as_deref(), Some("http://proxy.corp:3128"));
+        assert_eq!(config.timeout(), Some(std::time::Duration::from_millis(5000)));
+        assert_eq!(config.cache_scope().as_deref(), Some("letta:letta@https://api.letta.com"));
+        #[cfg(feature = "http")]
+        assert_eq!(config.rate_limits(), Some(("letta|https://api.letta.com|key".to_string(), Some(60), None)));
+        
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "llama", "model_path": "model.gguf", "context_size": 4096, "n_threads": 4,
+            "log_requests": "calls.jsonl",
+        })).unwrap();
+        assert!(config.http().is_none());
+        assert_eq!(config.log_requests(), Some(Path::new("calls.jsonl")));
+        assert_eq!(config.timeout(), None);
+        
+        // Written back flat, as it was read
+        let written = serde_json::to_value(&config).unwrap();
+        assert_eq!(written["log_requests"], "calls.jsonl");
+        assert!(written.get("options").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_toy_embedder_matches_toy_provider() {
+        let texts = vec!["Tea at four".to_string(), "tea, at FOUR".to_string()];
+        let toy = ToyProvider::new(ToyConfig::default());
+        let embedder = ProviderFactory::create_embedder(serde_json::from_str(r#"{"type": "toy"}"#).unwrap()).unwrap();
+        let vectors = embedder.embed(texts.clone()).await.unwrap();
+        assert_eq!(vectors, toy.embed(texts).await.unwrap());
+        assert_eq!(vectors[0], vectors[1]);
+        assert_eq!(embedder.name(), toy.name());
+        
+        let local: EmbeddingConfig = serde_json::from_str(r#"{"type": "local", "model_path": "/models/minilm.gguf"}"#).unwrap();
+        assert_eq!(local, EmbeddingConfig::Local { model_path: "/models/minilm.gguf".into() });
+        assert!(matches!(ProviderFactory::create_embedder(local), Err(LettaError::Provider(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_embed_batched_calls_once_per_batch() {
+        let texts: Vec<String> = (0..1000).map(|i| format!("Archived note number {}", i)).collect();
+        let toy = ToyProvider::new(ToyConfig::default());
+        let vectors = toy.embed_batched(texts.clone(), 64, 4).await.unwrap();
+        assert_eq!(toy.embed_call_count(), 1000usize.div_ceil(64));
+        assert_eq!(vectors, ToyEmbedder.embed(texts).await.unwrap());
+        
+        assert!(toy.embed_batched(vec![], 64, 4).await.unwrap().is_empty());
+        assert_eq!(toy.embed_call_count(), 16);
+    }
+    
+    /// Embeds each text as its number, taking longer for earlier numbers
+    struct Staggered;
+    
+    #[cfg_attr(feature = "wasm", async_trait(?Send))]
+    #[cfg_attr(not(feature = "wasm"), async_trait)]
+    impl Embedder for Staggered {
+        async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            let first: u64 = texts[0].parse().unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100 - first)).await;
+            Ok(texts.iter().map(|text| vec![text.parse().unwrap()]).collect())
+        }
+        
+        fn name(&self) -> &str {
+            "staggered"
+        }
+    }
+    
+    #[tokio::test(start_paused = true)]
+    async fn test_embed_batched_keeps_input_order() {
+        let texts: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let vectors = Staggered.embed_batched(texts, 7, 8).await.unwrap();
+        assert_eq!(vectors, (0..100).map(|i| vec![i as f32]).collect::<Vec<_>>());
+    }
+    
+    #[tokio::test]
+    async fn test_toy_script_from_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("script.json");
+        std::fs::write(&path, r#"[{"tool_calls": [{"name": "archival_search", "arguments": {"query": "tea"}}], "request_heartbeat": true}]"#).unwrap();
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "toy",
+            "deterministic": true,
+            "script": [{ "text": "First" }],
+            "script_file": path,
+        })).unwrap();
+        let provider = ProviderFactory::create(config).await.unwrap();
+        
+        assert_eq!(provider.complete(request("Hello")).await.unwrap().text, "First");
+        let second = provider.complete(request("Hello")).await.unwrap();
+        assert_eq!((second.tool_calls[0].id.as_str(), second.tool_calls[0].name.as_str()), ("call_1_0", "archival_search"));
+        assert!(second.request_heartbeat);
+        assert_eq!(provider.complete(request("Hello")).await.unwrap().text, "I understand your request. How can I help you further?");
+        
+        std::fs::write(&path, "not json").unwrap();
+        let config = ProviderConfig::Toy(ToyConfig { script_file: Some(path), ..Default::default() });
+        assert!(matches!(ProviderFactory::create(config).await, Err(LettaError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,418 @@
+//! Claude through Anthropic's Messages API
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::{ProviderError, ProviderErrorKind, Result};
+use crate::message::MessageRole;
+use crate::provider::{http_client, transport_error, via_proxy};
+use crate::provider::{AnthropicConfig, ChatMessage, Completion, CompletionRequest, LlmProvider, ProviderHealth, TokenUsage};
+use crate::retry;
+use crate::telemetry::Stopwatch;
+use crate::tool::{ToolCall, ToolSchema};
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const API_VERSION: &str = "2023-06-01";
+
+/// Sent when the request doesn't say; the API requires a limit
+const DEFAULT_MAX_TOKENS: usize = 1024;
+
+pub struct AnthropicProvider {
+    config: AnthropicConfig,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text { text: String },
+    ToolUse { id: String, name: String, input: Value },
+    /// Thinking and any block types added later
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+impl AnthropicProvider {
+    /// Fails if `proxy` is not a valid proxy URL
+    pub fn new(config: AnthropicConfig) -> Result<Self> {
+        let client = http_client(config.http.proxy.as_deref(), config.http.no_proxy.as_deref())?;
+        Ok(Self { config, client })
+    }
+
+    /// The Messages API request body for `request`
+    fn body(&self, request: &CompletionRequest) -> Result<Value> {
+        let mut body = json!({
+            "model": self.config.model,
+            "max_tokens": request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": [{ "role": "user", "content": request.prompt }],
+        });
+        if !request.messages.is_empty() {
+            let (system, messages) = messages(&request.messages);
+            body["system"] = json!(system);
+            body["messages"] = Value::Array(messages);
+        }
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !request.stop.is_empty() {
+            body["stop_sequences"] = json!(request.stop);
+        }
+        if !request.tools.is_empty() {
+            let tools = request.tools.iter()
+                .map(|tool| {
+                    let schema: ToolSchema = serde_json::from_value(tool.clone())?;
+                    Ok(json!({
+                        "name": schema.name,
+                        "description": schema.description,
+                        "input_schema": schema.parameters,
+                    }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            body["tools"] = Value::Array(tools);
+        }
+        Ok(body)
+    }
+}
+
+/// The system prompt and Messages API turns for `chat`. Tool results go back
+/// in user turns, system messages after the first become user text, and
+/// consecutive turns of one role are merged, as the API expects.
+fn messages(chat: &[ChatMessage]) -> (String, Vec<Value>) {
+    let mut chat = chat.iter().peekable();
+    let mut system = Vec::new();
+    while let Some(msg) = chat.next_if(|msg| msg.role == MessageRole::System) {
+        system.push(msg.content.as_str());
+    }
+
+    let mut turns: Vec<(&str, Vec<Value>)> = Vec::new();
+    for msg in chat {
+        let (role, blocks) = match msg.role {
+            MessageRole::User => ("user", vec![json!({ "type": "text", "text": msg.content })]),
+            MessageRole::System => ("user", vec![json!({ "type": "text", "text": format!("System: {}", msg.content) })]),
+            MessageRole::Tool => ("user", vec![json!({
+                "type": "tool_result",
+                "tool_use_id": msg.tool_call_id.as_deref().unwrap_or_default(),
+                "content": msg.content,
+            })]),
+            MessageRole::Assistant => {
+                let text = (!msg.content.is_empty()).then(|| json!({ "type": "text", "text": msg.content }));
+                let calls = msg.tool_calls.iter()
+                    .map(|call| json!({ "type": "tool_use", "id": call.id, "name": call.name, "input": call.arguments }));
+                ("assistant", text.into_iter().chain(calls).collect())
+            }
+        };
+        match turns.last_mut() {
+            _ if blocks.is_empty() => {}
+            Some((last, content)) if *last == role => content.extend(blocks),
+            _ => turns.push((role, blocks)),
+        }
+    }
+    let turns = turns.into_iter().map(|(role, content)| json!({ "role": role, "content": content })).collect();
+    (system.join("\n\n"), turns)
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        let base_url = self.config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL);
+        let url = format!("{}/v1/messages", base_url.trim_end_matches('/'));
+        let body = self.body(&request)?;
+        let retried = retry::send(&self.config.http.retry, || async {
+            Ok::<_, reqwest::Error>(self.client
+                .post(&url)
+                .header("x-api-key", &self.config.api_key)
+                .header("anthropic-version", API_VERSION)
+                .json(&body))
+        }).await;
+        let attempts = match retried.attempts {
+            1 => String::new(),
+            n => format!(" (after {} attempts)", n),
+        };
+        let response = retried.result
+            .map_err(|e| transport_error(&e, format!(
+                "Anthropic request failed{}{}: {}", attempts, via_proxy(self.config.http.proxy.as_deref(), &e), e,
+            )))?;
+
+        // 401 and 429 alike keep their status and body, so callers can tell
+        // a bad key from being rate limited
+        let status = response.status();
+        let text = response.text().await
+            .map_err(|e| transport_error(&e, format!("Anthropic response could not be read: {}", e)))?;
+        if !status.is_success() {
+            return Err(ProviderError::from_status(
+                status.as_u16(),
+                format!("Anthropic API returned {}{}: {}", status, attempts, text),
+            ).into());
+        }
+        let response: MessagesResponse = serde_json::from_str(&text).map_err(|e| {
+            ProviderError::new(ProviderErrorKind::InvalidResponse, format!("Unexpected Anthropic response: {}", e))
+        })?;
+
+        let mut text = String::new();
+        let mut tool_calls = Vec::new();
+        for block in response.content {
+            match block {
+                ContentBlock::Text { text: part } => text.push_str(&part),
+                ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall { id, name, arguments: input }),
+                ContentBlock::Other => {}
+            }
+        }
+        let Usage { input_tokens, output_tokens } = response.usage;
+        Ok(Completion {
+            text,
+            tool_calls,
+            // The model waits for the tool results before answering
+            request_heartbeat: response.stop_reason.as_deref() == Some("tool_use"),
+            usage: TokenUsage {
+                prompt_tokens: input_tokens,
+                completion_tokens: output_tokens,
+                total_tokens: input_tokens + output_tokens,
+                cost_usd: None,
+            },
+        })
+    }
+
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    /// Lists the models, which needs a valid key but generates nothing
+    async fn health(&self) -> Result<ProviderHealth> {
+        let base_url = self.config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL);
+        let started = Stopwatch::start();
+        let response = self.client
+            .get(format!("{}/v1/models", base_url.trim_end_matches('/')))
+            .header("x-api-key", &self.config.api_key)
+            .header("anthropic-version", API_VERSION)
+            .send().await
+            .map_err(|e| transport_error(&e, format!(
+                "Anthropic is unreachable{}: {}", via_proxy(self.config.http.proxy.as_deref(), &e), e,
+            )))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(ProviderError::from_status(status.as_u16(), format!("Anthropic API returned {}: {}", status, text)).into());
+        }
+        Ok(ProviderHealth::new(self.name(), started.elapsed()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LettaError;
+    use crate::provider::HttpOptions;
+    use crate::retry::RetryConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answer one request with `status` and `body`; resolves to the request body
+    async fn serve_once(status: &'static str, body: Value) -> (String, tokio::task::JoinHandle<Value>) {
+        serve(vec![(status, body)]).await
+    }
+
+    /// Answer a request with each response in turn; resolves to the last
+    /// request body
+    async fn serve(responses: Vec<(&'static str, Value)>) -> (String, tokio::task::JoinHandle<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut last = Value::Null;
+            for (status, body) in responses {
+                last = answer(&listener, status, body).await;
+            }
+            last
+        });
+        (url, server)
+    }
+
+    async fn answer(listener: &TcpListener, status: &str, body: Value) -> Value {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let request_body = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head.lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    assert!(head.contains("x-api-key: secret"));
+                    if head.starts_with("GET /v1/models ") {
+                        break Value::Null;
+                    }
+                    break serde_json::from_str(body).unwrap();
+                }
+            }
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status, body.len(), body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        request_body
+    }
+
+    fn provider(base_url: String, retry: RetryConfig) -> AnthropicProvider {
+        AnthropicProvider::new(AnthropicConfig {
+            api_key: "secret".to_string(),
+            model: "claude-test".to_string(),
+            base_url: Some(base_url),
+            http: HttpOptions { retry, ..Default::default() },
+        }).unwrap()
+    }
+
+    fn request(tools: Vec<Value>) -> CompletionRequest {
+        CompletionRequest {
+            prompt: "Remember that I like tea".to_string(),
+            messages: vec![],
+            tools,
+            temperature: Some(0.3),
+            max_tokens: Some(200),
+            stream: false,
+            stop: vec!["\n\nUser:".to_string()],
+            cancel: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tool_use_round_trip() {
+        let (url, server) = serve_once("200 OK", json!({
+            "content": [
+                { "type": "text", "text": "Saving that." },
+                { "type": "tool_use", "id": "toolu_1", "name": "memory_append", "input": { "label": "human", "text": "Likes tea" } }
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 40, "output_tokens": 12 }
+        })).await;
+        let schemas = crate::tool::ToolExecutor::new().get_schemas();
+        let tools = schemas.iter().map(|s| serde_json::to_value(s).unwrap()).collect();
+
+        let completion = provider(url, RetryConfig::none()).complete(request(tools)).await.unwrap();
+        assert_eq!(completion.text, "Saving that.");
+        assert_eq!(completion.tool_calls.len(), 1);
+        assert_eq!(completion.tool_calls[0].id, "toolu_1");
+        assert_eq!(completion.tool_calls[0].name, "memory_append");
+        assert_eq!(completion.tool_calls[0].arguments["text"], "Likes tea");
+        assert!(completion.request_heartbeat);
+        assert_eq!(completion.usage, TokenUsage { prompt_tokens: 40, completion_tokens: 12, total_tokens: 52, cost_usd: None });
+
+        let sent = server.await.unwrap();
+        assert_eq!(sent["model"], "claude-test");
+        assert_eq!(sent["max_tokens"], 200);
+        assert_eq!(sent["stop_sequences"], json!(["\n\nUser:"]));
+        assert!((sent["temperature"].as_f64().unwrap() - 0.3).abs() < 1e-6);
+        assert_eq!(sent["messages"][0]["content"], "Remember that I like tea");
+        assert_eq!(sent["tools"].as_array().unwrap().len(), schemas.len());
+        assert_eq!(sent["tools"][0]["name"], schemas[0].name);
+        assert_eq!(sent["tools"][0]["input_schema"], schemas[0].parameters);
+    }
+
+    fn chat() -> Vec<ChatMessage> {
+        let call = ToolCall { id: "call_1".into(), name: "archival_search".into(), arguments: json!({ "query": "tea" }) };
+        vec![
+            ChatMessage::new(MessageRole::System, "Be brief."),
+            ChatMessage::new(MessageRole::User, "What do I drink?"),
+            ChatMessage { tool_calls: vec![call], ..ChatMessage::new(MessageRole::Assistant, "") },
+            ChatMessage { tool_call_id: Some("call_1".into()), ..ChatMessage::new(MessageRole::Tool, "[]") },
+            ChatMessage::new(MessageRole::System, "Context summary: none"),
+            ChatMessage::new(MessageRole::Assistant, "Tea, I think."),
+        ]
+    }
+
+    #[test]
+    fn test_chat_becomes_alternating_turns() {
+        let (system, turns) = messages(&chat());
+        assert_eq!(system, "Be brief.");
+        let roles: Vec<_> = turns.iter().map(|turn| turn["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+        assert_eq!(turns[1]["content"], json!([{ "type": "tool_use", "id": "call_1", "name": "archival_search", "input": { "query": "tea" } }]));
+        assert_eq!(turns[2]["content"][0], json!({ "type": "tool_result", "tool_use_id": "call_1", "content": "[]" }));
+        assert_eq!(turns[2]["content"][1]["text"], "System: Context summary: none");
+    }
+
+    #[tokio::test]
+    async fn test_health_lists_models() {
+        let (url, _server) = serve(vec![("200 OK", json!({ "data": [] })), ("401 Unauthorized", json!({}))]).await;
+        let provider = provider(url, RetryConfig::none());
+        assert_eq!(provider.health().await.unwrap().provider, "anthropic");
+        assert!(provider.health().await.unwrap_err().to_string().contains("401"));
+    }
+
+    #[tokio::test]
+    async fn test_http_errors_keep_status_and_body() {
+        let cases = [
+            ("401 Unauthorized", 401, ProviderErrorKind::Auth),
+            ("429 Too Many Requests", 429, ProviderErrorKind::RateLimit),
+        ];
+        for (status, code, kind) in cases {
+            let (url, server) = serve_once(status, json!({ "type": "error", "error": { "type": "some_error" } })).await;
+            let error = provider(url, RetryConfig::none()).complete(request(Vec::new())).await.unwrap_err();
+            let LettaError::Provider(error) = error else { panic!("unexpected error {:?}", error) };
+            assert_eq!((error.kind, error.status), (kind, Some(code)));
+            assert!(error.message.contains(&code.to_string()), "{}", error);
+            assert!(error.message.contains("some_error"), "{}", error);
+
+            // No tools, no `tools` field
+            assert!(server.await.unwrap().get("tools").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_overloaded_is_retried() {
+        let overloaded = json!({ "type": "error", "error": { "type": "overloaded_error" } });
+        let answered = json!({
+            "content": [{ "type": "text", "text": "Hello" }],
+            "stop_reason": "end_turn",
+            "usage": { "input_tokens": 3, "output_tokens": 1 }
+        });
+        let retry = RetryConfig { max_attempts: 3, base_delay_ms: 1, ..Default::default() };
+
+        let (url, server) = serve(vec![
+            ("529 Overloaded", overloaded.clone()),
+            ("429 Too Many Requests", overloaded.clone()),
+            ("200 OK", answered),
+        ]).await;
+        let completion = provider(url, retry.clone()).complete(request(Vec::new())).await.unwrap();
+        assert_eq!(completion.text, "Hello");
+        assert_eq!(server.await.unwrap()["model"], "claude-test");
+
+        // The last refusal is reported with the number of attempts
+        let (url, _server) = serve(vec![("529 Overloaded", overloaded.clone()); 3]).await;
+        let error = provider(url, retry).complete(request(Vec::new())).await.unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("529") && message.contains("(after 3 attempts)"), "{}", message);
+        assert!(message.contains("overloaded_error"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_proxy_is_named() {
+        // Nothing listens on the discard port
+        let mut config = provider("http://api.anthropic.test".to_string(), RetryConfig::none()).config;
+        config.http.proxy = Some("http://127.0.0.1:9".to_string());
+        let error = AnthropicProvider::new(config.clone()).unwrap().complete(request(Vec::new())).await.unwrap_err();
+        assert!(error.to_string().contains("through proxy http://127.0.0.1:9"), "{}", error);
+
+        config.http.proxy = Some("not a url".to_string());
+        let bad = config;
+        assert!(matches!(AnthropicProvider::new(bad), Err(LettaError::InvalidConfig(_))));
+    }
+}
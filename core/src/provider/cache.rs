@@ -0,0 +1,259 @@
+//! Replaying completions for requests already answered, from the
+//! `completions_cache` table in `letta_storage`.
+//!
+//! Requests are keyed by a hash of what decides the reply: the prompt, tools,
+//! temperature, token limit and stop sequences, under a scope naming the
+//! provider and model. A cached completion reports no prompt tokens, since
+//! none were sent.
+
+use blake2::{Blake2s256, Digest};
+use chrono::Duration;
+use futures::StreamExt;
+use letta_storage::{AsyncStorage, Storage};
+use serde_json::json;
+
+use async_trait::async_trait;
+
+use crate::error::Result;
+use crate::provider::{Completion, CompletionAccumulator, CompletionChunk, CompletionRequest, CompletionStream, LlmProvider, ProviderCapabilities, ProviderHealth};
+
+/// Entries kept unless `with_max_entries` says otherwise
+pub const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+pub struct CachingProvider<P> {
+    inner: P,
+    storage: AsyncStorage,
+    scope: String,
+    ttl: Option<Duration>,
+    max_entries: usize,
+}
+
+impl<P: LlmProvider> CachingProvider<P> {
+    pub fn new(inner: P, storage: &Storage) -> Self {
+        let scope = inner.name().to_string();
+        Self { inner, storage: storage.to_async(), scope, ttl: None, max_entries: DEFAULT_MAX_ENTRIES }
+    }
+
+    /// Keep entries apart from other providers sharing the storage, such as
+    /// the same provider with another model. Defaults to the provider's name.
+    pub fn with_scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = scope.into();
+        self
+    }
+
+    /// Ignore completions cached longer ago than `ttl`
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Evict the least recently used entries beyond `max_entries`
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    /// The cache key for `request`
+    fn key(&self, request: &CompletionRequest) -> String {
+        let normalized = json!({
+            "scope": self.scope,
+            "prompt": request.prompt,
+            "tools": request.tools,
+            "temperature": request.temperature,
+            "max_tokens": request.max_tokens,
+            "stop": request.stop,
+        });
+        Blake2s256::digest(normalized.to_string().as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// The cached completion for `key`. Storage errors and unreadable entries
+    /// count as misses; the cache is only an optimization.
+    async fn lookup(&self, key: &str) -> Option<Completion> {
+        let cached = match self.storage.get_cached_completion(key, self.ttl).await {
+            Ok(cached) => cached?,
+            Err(e) => {
+                tracing::warn!("Completion cache lookup failed: {}", e);
+                return None;
+            }
+        };
+        let mut completion: Completion = serde_json::from_str(&cached).ok()?;
+        completion.usage.prompt_tokens = 0;
+        completion.usage.total_tokens = completion.usage.completion_tokens;
+        tracing::debug!(provider = self.inner.name(), "Completion cache hit");
+        Some(completion)
+    }
+
+    async fn store(&self, key: String, completion: &Completion) {
+        let stored = match serde_json::to_string(completion) {
+            Ok(stored) => self.storage.put_cached_completion(key, stored, self.max_entries).await,
+            Err(e) => Err(letta_storage::StorageError::InvalidData(e.to_string())),
+        };
+        if let Err(e) = stored {
+            tracing::warn!("Failed to cache completion: {}", e);
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<P: LlmProvider> LlmProvider for CachingProvider<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        let key = self.key(&request);
+        if let Some(completion) = self.lookup(&key).await {
+            return Ok(completion);
+        }
+        let completion = self.inner.complete(request).await?;
+        self.store(key, &completion).await;
+        Ok(completion)
+    }
+
+    /// A hit is replayed whole; a miss streams from the provider and is
+    /// cached once it completes
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        let key = self.key(&request);
+        if let Some(completion) = self.lookup(&key).await {
+            return Ok(Box::pin(futures::stream::iter(completion.into_chunks().into_iter().map(Ok))));
+        }
+
+        let stream = self.inner.complete_stream(request).await?;
+        let mut accumulator = Some(CompletionAccumulator::new());
+        let chunks = stream.then(move |chunk| {
+            let finished = match (&chunk, accumulator.as_mut()) {
+                (Ok(next), Some(pending)) => match pending.push(next.clone()) {
+                    Ok(()) if matches!(next, CompletionChunk::Done { .. }) => accumulator.take(),
+                    Ok(()) => None,
+                    Err(_) => {
+                        accumulator = None;
+                        None
+                    }
+                },
+                _ => {
+                    accumulator = None;
+                    None
+                }
+            };
+            let key = key.clone();
+            async move {
+                if let Some(completion) = finished.and_then(|pending| pending.finish().ok()) {
+                    self.store(key, &completion).await;
+                }
+                chunk
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed(texts).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.inner.max_tokens()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health(&self) -> Result<ProviderHealth> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use crate::provider::{ToyConfig, ToyProvider};
+
+    /// The toy provider, counting calls
+    struct Counted(ToyProvider, Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl LlmProvider for Counted {
+        async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.complete(request).await
+        }
+
+        fn name(&self) -> &str {
+            "counted"
+        }
+    }
+
+    fn request(prompt: &str) -> CompletionRequest {
+        CompletionRequest {
+            prompt: prompt.to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: Some(0.0),
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    fn cached(storage: &Storage) -> (CachingProvider<Counted>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner = Counted(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }), calls.clone());
+        (CachingProvider::new(inner, storage), calls)
+    }
+
+    #[tokio::test]
+    async fn test_second_identical_call_is_cached() {
+        let storage = Storage::memory().unwrap();
+        let (provider, calls) = cached(&storage);
+
+        let first = provider.complete(request("Hello")).await.unwrap();
+        let second = provider.complete(request("Hello")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(second.text, first.text);
+        assert_eq!(second.usage.prompt_tokens, 0);
+        assert_eq!(second.usage.total_tokens, first.usage.completion_tokens);
+
+        // Anything that changes the reply is a different entry
+        let mut warmer = request("Hello");
+        warmer.temperature = Some(0.9);
+        provider.complete(warmer).await.unwrap();
+        provider.complete(request("Hello again")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // The cache outlives the provider, but not its scope
+        let (provider, calls) = cached(&storage);
+        provider.complete(request("Hello")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let provider = provider.with_scope("counted:other-model");
+        provider.complete(request("Hello")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_completion_is_cached() {
+        let storage = Storage::memory().unwrap();
+        let (provider, calls) = cached(&storage);
+
+        let streamed: Vec<_> = provider.complete_stream(request("#DO_SEARCH")).await.unwrap().collect().await;
+        assert!(streamed.iter().all(|chunk| chunk.is_ok()));
+        let cached = provider.complete(request("#DO_SEARCH")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cached.tool_calls[0].name, "archival_search");
+
+        let (provider, calls) = (provider.with_ttl(Duration::zero()), calls);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        provider.complete(request("#DO_SEARCH")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,245 @@
+//! Recording each call to a provider, so an odd reply can be traced back to
+//! the prompt that produced it.
+//!
+//! `LoggingProvider` logs every request with its completion (or error) and
+//! latency at debug level under the `letta::provider` tracing target, and can
+//! append the same record as a JSON line to a file. Secrets registered with
+//! `redact`, and anything shaped like an `sk-` API key, are replaced before
+//! a record leaves the process.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
+use serde_json::json;
+
+use crate::error::{LettaError, Result};
+use crate::provider::{Completion, CompletionAccumulator, CompletionChunk, CompletionRequest, CompletionStream, LlmProvider, ProviderCapabilities, ProviderHealth};
+use crate::telemetry::Stopwatch;
+
+/// The tracing target calls are logged under
+pub const TARGET: &str = "letta::provider";
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Shortest run after `sk-` taken for a key rather than ordinary text
+const MIN_KEY_LEN: usize = 16;
+
+pub struct LoggingProvider<P> {
+    inner: P,
+    file: Option<Mutex<File>>,
+    secrets: Vec<String>,
+}
+
+impl<P: LlmProvider> LoggingProvider<P> {
+    pub fn new(inner: P) -> Self {
+        Self { inner, file: None, secrets: Vec::new() }
+    }
+
+    /// Also append each call to `path` as a line of JSON, creating the file
+    /// if needed
+    pub fn log_to(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.file = Some(Mutex::new(file));
+        Ok(self)
+    }
+
+    /// Replace `secret` wherever it appears in a record
+    pub fn redact(mut self, secret: impl Into<String>) -> Self {
+        let secret = secret.into();
+        if !secret.is_empty() {
+            self.secrets.push(secret);
+        }
+        self
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn record(&self, request: &CompletionRequest, outcome: std::result::Result<&Completion, &LettaError>, latency: Duration) {
+        let mut record = json!({
+            "timestamp": Utc::now(),
+            "provider": self.inner.name(),
+            "latency_ms": latency.as_secs_f64() * 1000.0,
+            "request": request,
+        });
+        match outcome {
+            Ok(completion) => record["completion"] = json!(completion),
+            Err(e) => record["error"] = json!(e.to_string()),
+        }
+        let line = self.redacted(&record.to_string());
+
+        tracing::debug!(
+            target: TARGET,
+            provider = self.inner.name(),
+            latency_ms = latency.as_millis() as u64,
+            success = outcome.is_ok(),
+            "{}", line,
+        );
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = writeln!(file, "{}", line) {
+                tracing::warn!(target: TARGET, "Failed to write provider call log: {}", e);
+            }
+        }
+    }
+
+    fn redacted(&self, text: &str) -> String {
+        let text = self.secrets.iter().fold(text.to_string(), |text, secret| text.replace(secret.as_str(), REDACTED));
+        redact_key_like(&text)
+    }
+}
+
+/// `text` with every `sk-` key, such as OpenAI's and Anthropic's, replaced
+fn redact_key_like(text: &str) -> String {
+    let is_key_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("sk-") {
+        let at_boundary = !rest[..start].ends_with(is_key_char);
+        let after = &rest[start + 3..];
+        let len = after.find(|c: char| !is_key_char(c)).unwrap_or(after.len());
+        redacted.push_str(&rest[..start]);
+        if at_boundary && len >= MIN_KEY_LEN {
+            redacted.push_str(REDACTED);
+            rest = &after[len..];
+        } else {
+            redacted.push_str("sk-");
+            rest = after;
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<P: LlmProvider> LlmProvider for LoggingProvider<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        let started = Stopwatch::start();
+        let completion = self.inner.complete(request.clone()).await;
+        self.record(&request, completion.as_ref(), started.elapsed());
+        completion
+    }
+
+    /// Logged once the stream ends, with the chunks put back together
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        let started = Stopwatch::start();
+        let stream = match self.inner.complete_stream(request.clone()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.record(&request, Err(&e), started.elapsed());
+                return Err(e);
+            }
+        };
+
+        let mut pending = Some((request, CompletionAccumulator::new()));
+        Ok(Box::pin(stream.map(move |chunk| {
+            let Some((_, accumulator)) = pending.as_mut() else {
+                return chunk;
+            };
+            let push_error = match &chunk {
+                Ok(next) => match accumulator.push(next.clone()) {
+                    Ok(()) if !matches!(next, CompletionChunk::Done { .. }) => return chunk,
+                    pushed => pushed.err(),
+                },
+                Err(_) => None,
+            };
+
+            // The stream ended or failed
+            let (request, accumulator) = pending.take().expect("pending checked above");
+            let completion = match push_error {
+                Some(e) => Err(e),
+                None => accumulator.finish(),
+            };
+            let outcome = match &chunk {
+                Ok(_) => completion.as_ref(),
+                Err(e) => Err(e),
+            };
+            self.record(&request, outcome, started.elapsed());
+            chunk
+        })))
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.inner.embed(texts).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.inner.max_tokens()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health(&self) -> Result<ProviderHealth> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{ToyConfig, ToyProvider};
+
+    fn request(prompt: &str) -> CompletionRequest {
+        CompletionRequest {
+            prompt: prompt.to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_redacts_keys() {
+        let provider = LoggingProvider::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }))
+            .redact("my-own-secret");
+        assert_eq!(
+            provider.redacted("key my-own-secret and sk-ant-REDACTED, task-list sk-short"),
+            "key [REDACTED] and [REDACTED], task-list sk-short",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logs_calls_as_json_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("calls.jsonl");
+        let provider = LoggingProvider::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }))
+            .log_to(&path).unwrap()
+            .redact("sekrit-value");
+
+        let completion = provider.complete(request("Hello, my password is sekrit-value")).await.unwrap();
+        let mut stream = provider.complete_stream(request("#DO_SEARCH")).await.unwrap();
+        while stream.next().await.is_some() {}
+        drop(stream);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 2);
+
+        assert_eq!(records[0]["provider"], "toy");
+        assert_eq!(records[0]["request"]["prompt"], "Hello, my password is [REDACTED]");
+        assert_eq!(records[0]["completion"]["text"], completion.text);
+        assert!(records[0]["latency_ms"].as_f64().unwrap() >= 0.0);
+        assert!(!log.contains("sekrit-value"));
+
+        // Streamed calls are logged whole, once
+        assert_eq!(records[1]["completion"]["tool_calls"][0]["name"], "archival_search");
+    }
+}
@@ -0,0 +1,500 @@
+//! GPT models through OpenAI's Chat Completions API, or an Azure OpenAI
+//! deployment of one.
+//!
+//! Azure serves the same API under the resource's endpoint with the
+//! deployment in the path and an `api-version` query parameter, and takes the
+//! key in an `api-key` header instead of a bearer token.
+
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::error::{LettaError, ProviderError, ProviderErrorKind, Result};
+use crate::message::MessageRole;
+use crate::provider::{http_client, transport_error, via_proxy};
+use crate::provider::{ChatMessage, Completion, CompletionRequest, LlmProvider, OpenAIConfig, ProviderCapabilities, ProviderHealth, TokenUsage};
+use crate::retry;
+use crate::telemetry::Stopwatch;
+use crate::tool::{ToolCall, ToolSchema};
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// Most functions the chat completions API takes in one request
+const MAX_TOOLS: usize = 128;
+
+/// Used when an Azure config doesn't name one
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-10-21";
+
+pub struct OpenAIProvider {
+    config: OpenAIConfig,
+    client: reqwest::Client,
+    url: String,
+    /// The model list, which `health` fetches
+    models_url: String,
+    /// The provider's name, and how errors refer to the API
+    name: &'static str,
+    label: &'static str,
+    /// Sent with every request, besides the key
+    headers: HeaderMap,
+    /// Added to every completion request body
+    extra_body: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<ResponseToolCall>,
+}
+
+#[derive(Deserialize)]
+struct ResponseToolCall {
+    id: String,
+    function: FunctionCall,
+}
+
+#[derive(Deserialize)]
+struct FunctionCall {
+    name: String,
+    /// JSON, as a string
+    arguments: String,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    /// In USD; OpenRouter reports it
+    #[serde(default)]
+    cost: Option<f64>,
+}
+
+impl OpenAIProvider {
+    /// Fails for an Azure config without the resource endpoint in `base_url`,
+    /// or if `proxy` is not a valid proxy URL
+    pub fn new(config: OpenAIConfig) -> Result<Self> {
+        let (url, models_url) = Self::urls(&config)?;
+        let client = http_client(config.http.proxy.as_deref(), config.http.no_proxy.as_deref())?;
+        let (name, label) = match config.azure {
+            true => ("azure-openai", "Azure OpenAI"),
+            false => ("openai", "OpenAI"),
+        };
+        Ok(Self { config, client, url, models_url, name, label, headers: HeaderMap::new(), extra_body: Map::new() })
+    }
+
+    /// Call another vendor's OpenAI-compatible API under its own name,
+    /// sending `headers` with every request and `extra_body` with every
+    /// completion request
+    pub(crate) fn compatible(
+        self,
+        name: &'static str,
+        label: &'static str,
+        headers: HeaderMap,
+        extra_body: Map<String, Value>,
+    ) -> Self {
+        Self { name, label, headers, extra_body, ..self }
+    }
+
+    /// The chat completions and model list endpoints `config` names
+    fn urls(config: &OpenAIConfig) -> Result<(String, String)> {
+        if !config.azure {
+            let base_url = config.base_url.as_deref().unwrap_or(DEFAULT_BASE_URL).trim_end_matches('/');
+            return Ok((format!("{}/chat/completions", base_url), format!("{}/models", base_url)));
+        }
+        let endpoint = config.base_url.as_deref().ok_or_else(|| LettaError::InvalidConfig(
+            "Azure OpenAI needs the resource endpoint as `base_url`".into(),
+        ))?.trim_end_matches('/');
+        let api_version = config.api_version.as_deref().unwrap_or(DEFAULT_AZURE_API_VERSION);
+        Ok((
+            format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                endpoint,
+                config.deployment.as_deref().unwrap_or(&config.model),
+                api_version,
+            ),
+            format!("{}/openai/models?api-version={}", endpoint, api_version),
+        ))
+    }
+
+    /// `builder` with the key, as Azure or OpenAI expects it
+    fn authorized(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let builder = builder.headers(self.headers.clone());
+        match self.config.azure {
+            true => builder.header("api-key", &self.config.api_key),
+            false => builder.bearer_auth(&self.config.api_key),
+        }
+    }
+
+    /// The Chat Completions request body for `request`
+    fn body(&self, request: &CompletionRequest) -> Result<Value> {
+        let messages = match request.messages.is_empty() {
+            true => vec![json!({ "role": "user", "content": request.prompt })],
+            false => request.messages.iter().map(message).collect(),
+        };
+        let mut body = json!({
+            "model": self.config.model,
+            "messages": messages,
+        });
+        if let Some(max_tokens) = request.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+        if !request.stop.is_empty() {
+            body["stop"] = json!(request.stop);
+        }
+        if !request.tools.is_empty() {
+            let tools = request.tools.iter()
+                .map(|tool| {
+                    let schema: ToolSchema = serde_json::from_value(tool.clone())?;
+                    Ok(json!({
+                        "type": "function",
+                        "function": {
+                            "name": schema.name,
+                            "description": schema.description,
+                            "parameters": schema.parameters,
+                        },
+                    }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            body["tools"] = Value::Array(tools);
+        }
+        for (key, value) in &self.extra_body {
+            body[key] = value.clone();
+        }
+        Ok(body)
+    }
+
+    fn label(&self) -> &'static str {
+        self.label
+    }
+}
+
+/// `msg` as a Chat Completions message
+fn message(msg: &ChatMessage) -> Value {
+    match msg.role {
+        MessageRole::Tool => json!({
+            "role": "tool",
+            "tool_call_id": msg.tool_call_id,
+            "content": msg.content,
+        }),
+        MessageRole::Assistant if !msg.tool_calls.is_empty() => json!({
+            "role": "assistant",
+            "content": Some(&msg.content).filter(|content| !content.is_empty()),
+            "tool_calls": msg.tool_calls.iter().map(|call| json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments.to_string() },
+            })).collect::<Vec<_>>(),
+        }),
+        ref role => json!({ "role": role, "content": msg.content }),
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl LlmProvider for OpenAIProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        let body = self.body(&request)?;
+        let retried = retry::send(&self.config.http.retry, || async {
+            Ok::<_, reqwest::Error>(self.authorized(self.client.post(&self.url).json(&body)))
+        }).await;
+        let attempts = match retried.attempts {
+            1 => String::new(),
+            n => format!(" (after {} attempts)", n),
+        };
+        let response = retried.result
+            .map_err(|e| transport_error(&e, format!(
+                "{} request failed{}{}: {}", self.label(), attempts, via_proxy(self.config.http.proxy.as_deref(), &e), e,
+            )))?;
+
+        let status = response.status();
+        let text = response.text().await
+            .map_err(|e| transport_error(&e, format!("{} response could not be read: {}", self.label(), e)))?;
+        if !status.is_success() {
+            return Err(ProviderError::from_status(
+                status.as_u16(),
+                format!("{} API returned {}{}: {}", self.label(), status, attempts, text),
+            ).into());
+        }
+        let response: ChatResponse = serde_json::from_str(&text).map_err(|e| {
+            ProviderError::new(ProviderErrorKind::InvalidResponse, format!("Unexpected {} response: {}", self.label(), e))
+        })?;
+        let choice = response.choices.into_iter().next().ok_or_else(|| {
+            ProviderError::new(ProviderErrorKind::InvalidResponse, format!("{} returned no choices", self.label()))
+        })?;
+
+        let tool_calls = choice.message.tool_calls.into_iter()
+            .map(|call| {
+                let arguments = serde_json::from_str(&call.function.arguments).map_err(|e| ProviderError::new(
+                    ProviderErrorKind::InvalidResponse,
+                    format!("Tool call {} has invalid arguments: {}", call.function.name, e),
+                ))?;
+                Ok(ToolCall { id: call.id, name: call.function.name, arguments })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let usage = response.usage.map_or_else(TokenUsage::default, |usage| TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.prompt_tokens + usage.completion_tokens,
+            cost_usd: usage.cost,
+        });
+        Ok(Completion {
+            text: choice.message.content.unwrap_or_default(),
+            tool_calls,
+            // The model waits for the tool results before answering
+            request_heartbeat: choice.finish_reason.as_deref() == Some("tool_calls"),
+            usage,
+        })
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities { supports_tools: true, max_tools: Some(MAX_TOOLS), ..Default::default() }
+    }
+
+    /// Lists the models, which needs a valid key but generates nothing.
+    /// Ollama serves the same list.
+    async fn health(&self) -> Result<ProviderHealth> {
+        let started = Stopwatch::start();
+        let response = self.authorized(self.client.get(&self.models_url)).send().await
+            .map_err(|e| transport_error(&e, format!(
+                "{} is unreachable{}: {}", self.label(), via_proxy(self.config.http.proxy.as_deref(), &e), e,
+            )))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(ProviderError::from_status(status.as_u16(), format!("{} API returned {}: {}", self.label(), status, text)).into());
+        }
+        Ok(ProviderHealth::new(self.name(), started.elapsed()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::provider::HttpOptions;
+    use crate::retry::RetryConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// What the mock server received
+    pub(crate) struct Received {
+        /// Request line and headers, lowercased
+        pub head: String,
+        pub body: Value,
+    }
+
+    /// Answer one request with `status` and `body`
+    pub(crate) async fn serve_once(status: &'static str, body: Value) -> (String, tokio::task::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            let received = loop {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let head = head.to_ascii_lowercase();
+                    let length: usize = head.lines()
+                        .find_map(|line| line.strip_prefix("content-length:").map(|v| v.trim().parse().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).unwrap() };
+                        break Received { head, body };
+                    }
+                }
+            };
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            received
+        });
+        (url, server)
+    }
+
+    fn config(base_url: Option<String>) -> OpenAIConfig {
+        OpenAIConfig {
+            api_key: "secret".to_string(),
+            model: "gpt-test".to_string(),
+            base_url,
+            http: HttpOptions { retry: RetryConfig::none(), ..Default::default() },
+            azure: false,
+            deployment: None,
+            api_version: None,
+        }
+    }
+
+    fn request(tools: Vec<Value>) -> CompletionRequest {
+        CompletionRequest {
+            prompt: "Remember that I like tea".to_string(),
+            messages: vec![],
+            tools,
+            temperature: Some(0.3),
+            max_tokens: Some(200),
+            stream: false,
+            stop: vec!["\n\nUser:".to_string()],
+            cancel: Default::default(),
+        }
+    }
+
+    fn answer() -> Value {
+        json!({
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "memory_append", "arguments": "{\"label\":\"human\",\"text\":\"Likes tea\"}" }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": { "prompt_tokens": 40, "completion_tokens": 12, "total_tokens": 52 }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_round_trip() {
+        let (url, server) = serve_once("200 OK", answer()).await;
+        let schemas = crate::tool::ToolExecutor::new().get_schemas();
+        let tools = schemas.iter().map(|s| serde_json::to_value(s).unwrap()).collect();
+
+        let provider = OpenAIProvider::new(config(Some(format!("{}/v1/", url)))).unwrap();
+        let completion = provider.complete(request(tools)).await.unwrap();
+        assert_eq!(completion.text, "");
+        assert_eq!(completion.tool_calls[0].id, "call_1");
+        assert_eq!(completion.tool_calls[0].name, "memory_append");
+        assert_eq!(completion.tool_calls[0].arguments["text"], "Likes tea");
+        assert!(completion.request_heartbeat);
+        assert_eq!(completion.usage, TokenUsage { prompt_tokens: 40, completion_tokens: 12, total_tokens: 52, cost_usd: None });
+
+        let received = server.await.unwrap();
+        assert!(received.head.starts_with("post /v1/chat/completions http/1.1"), "{}", received.head);
+        assert!(received.head.contains("authorization: bearer secret"));
+        let sent = received.body;
+        assert_eq!(sent["model"], "gpt-test");
+        assert_eq!(sent["max_tokens"], 200);
+        assert_eq!(sent["stop"], json!(["\n\nUser:"]));
+        assert_eq!(sent["messages"][0]["content"], "Remember that I like tea");
+        assert_eq!(sent["tools"].as_array().unwrap().len(), schemas.len());
+        assert_eq!(sent["tools"][0]["function"]["name"], schemas[0].name);
+        assert_eq!(sent["tools"][0]["function"]["parameters"], schemas[0].parameters);
+    }
+
+    #[tokio::test]
+    async fn test_azure_deployment_url_and_key_header() {
+        let (url, server) = serve_once("200 OK", answer()).await;
+        let azure = OpenAIConfig {
+            azure: true,
+            deployment: Some("letta-gpt".to_string()),
+            api_version: Some("2024-06-01".to_string()),
+            ..config(Some(url))
+        };
+        let provider = OpenAIProvider::new(azure).unwrap();
+        assert_eq!(provider.name(), "azure-openai");
+        provider.complete(request(Vec::new())).await.unwrap();
+
+        let received = server.await.unwrap();
+        assert!(
+            received.head.starts_with("post /openai/deployments/letta-gpt/chat/completions?api-version=2024-06-01 http/1.1"),
+            "{}", received.head,
+        );
+        assert!(received.head.contains("api-key: secret"));
+        assert!(!received.head.contains("authorization:"));
+
+        // The deployment defaults to the model, the version to a GA release
+        let defaults = OpenAIConfig { azure: true, ..config(Some("https://letta.openai.azure.com/".into())) };
+        assert_eq!(
+            OpenAIProvider::urls(&defaults).unwrap().0,
+            format!("https://letta.openai.azure.com/openai/deployments/gpt-test/chat/completions?api-version={}", DEFAULT_AZURE_API_VERSION),
+        );
+        let no_endpoint = OpenAIConfig { azure: true, ..config(None) };
+        assert!(matches!(OpenAIProvider::new(no_endpoint), Err(LettaError::InvalidConfig(_))));
+    }
+
+    fn chat() -> Vec<ChatMessage> {
+        let call = ToolCall { id: "call_1".into(), name: "archival_search".into(), arguments: json!({ "query": "tea" }) };
+        vec![
+            ChatMessage::new(MessageRole::System, "Be brief."),
+            ChatMessage::new(MessageRole::User, "What do I drink?"),
+            ChatMessage { tool_calls: vec![call], ..ChatMessage::new(MessageRole::Assistant, "") },
+            ChatMessage { tool_call_id: Some("call_1".into()), ..ChatMessage::new(MessageRole::Tool, "[]") },
+            ChatMessage::new(MessageRole::System, "Context summary: none"),
+            ChatMessage::new(MessageRole::Assistant, "Tea, I think."),
+        ]
+    }
+
+    #[test]
+    fn test_chat_messages_keep_roles_and_calls() {
+        let messages: Vec<Value> = chat().iter().map(message).collect();
+        assert_eq!(messages[0], json!({ "role": "system", "content": "Be brief." }));
+        assert_eq!(messages[2]["content"], Value::Null);
+        assert_eq!(messages[2]["tool_calls"][0]["function"]["arguments"], "{\"query\":\"tea\"}");
+        assert_eq!(messages[3], json!({ "role": "tool", "tool_call_id": "call_1", "content": "[]" }));
+        assert_eq!(messages[5], json!({ "role": "assistant", "content": "Tea, I think." }));
+    }
+
+    #[tokio::test]
+    async fn test_health_lists_models() {
+        let (url, server) = serve_once("200 OK", json!({ "object": "list", "data": [] })).await;
+        let health = OpenAIProvider::new(config(Some(format!("{}/v1", url)))).unwrap().health().await.unwrap();
+        assert_eq!(health.provider, "openai");
+        let received = server.await.unwrap();
+        assert!(received.head.starts_with("get /v1/models http/1.1"), "{}", received.head);
+        assert!(received.head.contains("authorization: bearer secret"));
+
+        let (url, server) = serve_once("401 Unauthorized", json!({ "error": { "code": "invalid_api_key" } })).await;
+        let azure = OpenAIConfig { azure: true, ..config(Some(url)) };
+        let error = OpenAIProvider::new(azure).unwrap().health().await.unwrap_err();
+        assert!(error.to_string().contains("401"), "{}", error);
+        let received = server.await.unwrap();
+        assert!(received.head.starts_with(&format!("get /openai/models?api-version={} ", DEFAULT_AZURE_API_VERSION)));
+    }
+
+    #[tokio::test]
+    async fn test_http_errors_keep_status_and_body() {
+        let (url, _server) = serve_once("401 Unauthorized", json!({ "error": { "code": "invalid_api_key" } })).await;
+        let error = OpenAIProvider::new(config(Some(url))).unwrap().complete(request(Vec::new())).await.unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("401") && message.contains("invalid_api_key"), "{}", message);
+        assert!(matches!(error, LettaError::Provider(ProviderError { kind: ProviderErrorKind::Auth, status: Some(401), .. })));
+        assert!(!error.is_retryable());
+
+        let (url, _server) = serve_once("400 Bad Request", json!({ "error": { "code": "context_length_exceeded" } })).await;
+        let error = OpenAIProvider::new(config(Some(url))).unwrap().complete(request(Vec::new())).await.unwrap_err();
+        assert!(matches!(error, LettaError::Provider(ProviderError { kind: ProviderErrorKind::ContextLength, .. })), "{}", error);
+
+        // Nothing listens on a port just let go of
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let refused = OpenAIProvider::new(config(Some(format!("http://{}", closed)))).unwrap();
+        let error = refused.complete(request(Vec::new())).await.unwrap_err();
+        assert!(matches!(error, LettaError::Provider(ProviderError { kind: ProviderErrorKind::Transport, .. })), "{}", error);
+        assert!(error.is_retryable());
+    }
+}
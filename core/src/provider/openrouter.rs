@@ -0,0 +1,157 @@
+//! Any model OpenRouter routes to, through its OpenAI-compatible Chat
+//! Completions API.
+//!
+//! Models are named as OpenRouter names them (`anthropic/claude-3.5-sonnet`)
+//! and passed through unchanged. OpenRouter also reports what each call
+//! cost, which ends up in `TokenUsage::cost_usd`.
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Map};
+
+use crate::error::{LettaError, Result};
+use crate::provider::openai::OpenAIProvider;
+use crate::provider::{Completion, CompletionRequest, LlmProvider, HttpOptions, OpenAIConfig, OpenRouterConfig, ProviderCapabilities, ProviderHealth};
+
+const DEFAULT_BASE_URL: &str = "https://openrouter.ai/api/v1";
+
+pub struct OpenRouterProvider {
+    inner: OpenAIProvider,
+}
+
+impl OpenRouterProvider {
+    /// Fails if `site_url` or `app_name` can't be sent as a header, or if
+    /// `proxy` is not a valid proxy URL
+    pub fn new(config: OpenRouterConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [("http-referer", &config.site_url), ("x-title", &config.app_name)] {
+            if let Some(value) = value {
+                let value = HeaderValue::from_str(value).map_err(|e| {
+                    LettaError::InvalidConfig(format!("OpenRouter {} {:?} can't be sent: {}", name, value, e))
+                })?;
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+        // Asks for the cost with the token counts
+        let mut extra_body = Map::new();
+        extra_body.insert("usage".to_string(), json!({ "include": true }));
+
+        let inner = OpenAIProvider::new(OpenAIConfig {
+            api_key: config.api_key,
+            model: config.model,
+            base_url: Some(config.base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string())),
+            // The factory applies the rest around the provider
+            http: HttpOptions {
+                proxy: config.http.proxy,
+                no_proxy: config.http.no_proxy,
+                retry: config.http.retry,
+                ..Default::default()
+            },
+            azure: false,
+            deployment: None,
+            api_version: None,
+        })?;
+        Ok(Self { inner: inner.compatible("openrouter", "OpenRouter", headers, extra_body) })
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl LlmProvider for OpenRouterProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        self.inner.complete(request).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health(&self) -> Result<ProviderHealth> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::openai::tests::serve_once;
+    use crate::provider::TokenUsage;
+    use crate::retry::RetryConfig;
+
+    fn config(base_url: String) -> OpenRouterConfig {
+        OpenRouterConfig {
+            api_key: "sk-or-secret".to_string(),
+            model: "anthropic/claude-3.5-sonnet".to_string(),
+            site_url: Some("https://letta.example".to_string()),
+            app_name: Some("Letta Lite".to_string()),
+            base_url: Some(base_url),
+            http: HttpOptions { retry: RetryConfig::none(), ..Default::default() },
+        }
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            prompt: "Hello".to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_headers_model_and_cost() {
+        let (url, server) = serve_once("200 OK", json!({
+            "id": "gen-1",
+            "model": "anthropic/claude-3.5-sonnet",
+            "choices": [{ "message": { "role": "assistant", "content": "Hi!" }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 8, "completion_tokens": 3, "total_tokens": 11, "cost": 0.000069 }
+        })).await;
+        let provider = OpenRouterProvider::new(config(format!("{}/api/v1", url))).unwrap();
+        assert_eq!(provider.name(), "openrouter");
+
+        let completion = provider.complete(request()).await.unwrap();
+        assert_eq!(completion.text, "Hi!");
+        assert_eq!(completion.usage, TokenUsage { prompt_tokens: 8, completion_tokens: 3, total_tokens: 11, cost_usd: Some(0.000069) });
+
+        let received = server.await.unwrap();
+        assert!(received.head.starts_with("post /api/v1/chat/completions http/1.1"), "{}", received.head);
+        assert!(received.head.contains("authorization: bearer sk-or-secret"));
+        assert!(received.head.contains("http-referer: https://letta.example"));
+        assert!(received.head.contains("x-title: letta lite"));
+        assert_eq!(received.body["model"], "anthropic/claude-3.5-sonnet");
+        assert_eq!(received.body["usage"], json!({ "include": true }));
+    }
+
+    #[tokio::test]
+    async fn test_optional_headers_and_missing_cost() {
+        let (url, server) = serve_once("200 OK", json!({
+            "choices": [{ "message": { "role": "assistant", "content": "Hi!" }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 8, "completion_tokens": 3, "total_tokens": 11 }
+        })).await;
+        let bare = OpenRouterConfig { site_url: None, app_name: None, ..config(url) };
+        let completion = OpenRouterProvider::new(bare).unwrap().complete(request()).await.unwrap();
+        assert_eq!(completion.usage.cost_usd, None);
+
+        let received = server.await.unwrap();
+        assert!(!received.head.contains("http-referer:") && !received.head.contains("x-title:"), "{}", received.head);
+
+        let invalid = OpenRouterConfig { app_name: Some("Letta\nLite".to_string()), ..config("http://localhost".into()) };
+        assert!(matches!(OpenRouterProvider::new(invalid), Err(LettaError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_errors_name_openrouter() {
+        let (url, _server) = serve_once("402 Payment Required", json!({ "error": { "message": "Insufficient credits" } })).await;
+        let error = OpenRouterProvider::new(config(url)).unwrap().complete(request()).await.unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("OpenRouter API returned 402") && message.contains("Insufficient credits"), "{}", message);
+    }
+}
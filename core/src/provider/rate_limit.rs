@@ -0,0 +1,261 @@
+//! Client-side rate limiting, so agents sharing an API key stay inside its
+//! requests-per-minute and tokens-per-minute limits instead of being turned
+//! away by the server.
+//!
+//! Each limit is a token bucket holding a minute's allowance, refilled
+//! continuously. A call waits until every bucket can cover it. Tokens are
+//! estimated up front from the prompt and `max_tokens`, then corrected from
+//! the usage the provider reports.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::error::Result;
+use crate::provider::{Completion, CompletionRequest, CompletionStream, LlmProvider, ProviderCapabilities, ProviderHealth};
+use crate::tokenizer::{HeuristicTokenizer, Tokenizer};
+
+#[derive(Debug)]
+struct Bucket {
+    /// A minute's allowance
+    capacity: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = per_minute.max(1) as f64;
+        Self { capacity, available: capacity, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long until `amount` is available; never longer than a minute,
+    /// since amounts are capped at the capacity
+    fn wait_for(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        Duration::from_secs_f64((missing * 60.0 / self.capacity).max(0.0))
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// Requests-per-minute and tokens-per-minute budgets, shared by every
+/// provider built from the same account and limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+/// Limiters handed out by `shared`, by account and limits
+static SHARED: Mutex<Vec<(String, Arc<RateLimiter>)>> = Mutex::new(Vec::new());
+
+impl RateLimiter {
+    pub fn new(rpm: Option<u32>, tpm: Option<u32>) -> Self {
+        let now = Instant::now();
+        Self {
+            buckets: Mutex::new(Buckets {
+                requests: rpm.map(|rpm| Bucket::new(rpm, now)),
+                tokens: tpm.map(|tpm| Bucket::new(tpm, now)),
+            }),
+        }
+    }
+
+    /// The limiter for `account`, such as a base URL and API key, created on
+    /// first use. Providers configured alike share one budget.
+    pub fn shared(account: &str, rpm: Option<u32>, tpm: Option<u32>) -> Arc<Self> {
+        let key = format!("{}|{:?}|{:?}", account, rpm, tpm);
+        let mut shared = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, limiter)) = shared.iter().find(|(shared_key, _)| *shared_key == key) {
+            return limiter.clone();
+        }
+        let limiter = Arc::new(Self::new(rpm, tpm));
+        shared.push((key, limiter.clone()));
+        limiter
+    }
+
+    /// Wait until a request using about `tokens` tokens fits the budget, then
+    /// take it out
+    pub async fn acquire(&self, tokens: usize) {
+        loop {
+            let wait = {
+                let mut buckets = self.lock();
+                let now = Instant::now();
+                let mut wait = Duration::ZERO;
+                for (bucket, amount) in buckets.with_amounts(tokens) {
+                    bucket.refill(now);
+                    wait = wait.max(bucket.wait_for(amount));
+                }
+                if wait.is_zero() {
+                    for (bucket, amount) in buckets.with_amounts(tokens) {
+                        bucket.available -= amount.min(bucket.capacity);
+                    }
+                    return;
+                }
+                wait
+            };
+            tracing::debug!(wait_ms = wait.as_millis() as u64, "Rate limit reached, waiting");
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Correct the token budget once a call has reported using `actual`
+    /// tokens where `estimated` were taken. The balance may go negative,
+    /// delaying later calls.
+    pub fn settle(&self, estimated: usize, actual: usize) {
+        if let Some(bucket) = self.lock().tokens.as_mut() {
+            bucket.available = (bucket.available + estimated as f64 - actual as f64).min(bucket.capacity);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        self.buckets.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Buckets {
+    fn with_amounts(&mut self, tokens: usize) -> impl Iterator<Item = (&mut Bucket, f64)> {
+        self.requests.as_mut().map(|bucket| (bucket, 1.0))
+            .into_iter()
+            .chain(self.tokens.as_mut().map(|bucket| (bucket, tokens as f64)))
+    }
+}
+
+/// An `LlmProvider` whose calls wait for a `RateLimiter`
+pub struct RateLimitedProvider<P> {
+    inner: P,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<P: LlmProvider> RateLimitedProvider<P> {
+    pub fn new(inner: P, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+/// Tokens `request` may use: its prompt and the most it may generate
+fn estimate(request: &CompletionRequest) -> usize {
+    HeuristicTokenizer.count_tokens(&request.prompt) + request.max_tokens.unwrap_or(0)
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<P: LlmProvider> LlmProvider for RateLimitedProvider<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        let estimated = estimate(&request);
+        self.limiter.acquire(estimated).await;
+        let completion = self.inner.complete(request).await?;
+        // Providers that don't report usage leave the estimate standing
+        if completion.usage.total_tokens > 0 {
+            self.limiter.settle(estimated, completion.usage.total_tokens);
+        }
+        Ok(completion)
+    }
+
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        self.limiter.acquire(estimate(&request)).await;
+        self.inner.complete_stream(request).await
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let tokens = texts.iter().map(|text| HeuristicTokenizer.count_tokens(text)).sum();
+        self.limiter.acquire(tokens).await;
+        self.inner.embed(texts).await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.inner.max_tokens()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health(&self) -> Result<ProviderHealth> {
+        self.inner.health().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{ToyConfig, ToyProvider};
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            prompt: "Hello".to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_third_call_waits_for_rpm() {
+        let provider = RateLimitedProvider::new(
+            ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }),
+            Arc::new(RateLimiter::new(Some(2), None)),
+        );
+
+        let started = Instant::now();
+        provider.complete(request()).await.unwrap();
+        provider.complete(request()).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Two a minute: the third waits for half a minute's refill
+        provider.complete(request()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(30), "{:?}", started.elapsed());
+        assert!(started.elapsed() < Duration::from_secs(31));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tokens_per_minute() {
+        let limiter = RateLimiter::new(None, Some(600));
+        let started = Instant::now();
+        limiter.acquire(500).await;
+        // 100 left; 300 more refill at 10 a second
+        limiter.acquire(400).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(30));
+
+        // A call that used more than estimated delays the next
+        limiter.settle(0, 600);
+        limiter.acquire(1).await;
+        assert!(started.elapsed() >= Duration::from_secs(90));
+
+        // More than a minute's allowance goes through once the bucket is full
+        let started = Instant::now();
+        limiter.acquire(10_000).await;
+        assert!(started.elapsed() <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_shared_per_account_and_limits() {
+        let a = RateLimiter::shared("https://api.example.com|key-a", Some(2), None);
+        assert!(Arc::ptr_eq(&a, &RateLimiter::shared("https://api.example.com|key-a", Some(2), None)));
+        assert!(!Arc::ptr_eq(&a, &RateLimiter::shared("https://api.example.com|key-b", Some(2), None)));
+        assert!(!Arc::ptr_eq(&a, &RateLimiter::shared("https://api.example.com|key-a", Some(3), None)));
+    }
+}
@@ -0,0 +1,145 @@
+//! Bounding how long a provider call may take, so a stalled connection or a
+//! runaway local generation fails the step instead of hanging it.
+//!
+//! A streamed call shares one deadline across the whole stream: the chunks
+//! must all arrive before it passes.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use crate::error::{LettaError, Result};
+use crate::provider::{Completion, CompletionRequest, CompletionStream, LlmProvider, ProviderCapabilities, ProviderHealth};
+
+pub struct TimeoutProvider<P> {
+    inner: P,
+    timeout: Duration,
+}
+
+impl<P: LlmProvider> TimeoutProvider<P> {
+    pub fn new(inner: P, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
+    fn expired(&self) -> LettaError {
+        LettaError::Timeout { elapsed_ms: self.timeout.as_millis() as u64 }
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl<P: LlmProvider> LlmProvider for TimeoutProvider<P> {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        tokio::time::timeout(self.timeout, self.inner.complete(request)).await
+            .map_err(|_| self.expired())?
+    }
+
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        let deadline = Instant::now() + self.timeout;
+        let stream = tokio::time::timeout_at(deadline, self.inner.complete_stream(request)).await
+            .map_err(|_| self.expired())??;
+
+        // Ends after the error once the deadline passes
+        let chunks = futures::stream::unfold(Some(stream), move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(chunk)) => Some((chunk, Some(stream))),
+                Ok(None) => None,
+                Err(_) => Some((Err(self.expired()), None)),
+            }
+        });
+        Ok(Box::pin(chunks))
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        tokio::time::timeout(self.timeout, self.inner.embed(texts)).await
+            .map_err(|_| self.expired())?
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn max_tokens(&self) -> usize {
+        self.inner.max_tokens()
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        self.inner.capabilities()
+    }
+
+    async fn health(&self) -> Result<ProviderHealth> {
+        tokio::time::timeout(self.timeout, self.inner.health()).await
+            .map_err(|_| self.expired())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Agent, AgentConfig};
+    use crate::message::MessageRole;
+    use crate::provider::{ToyConfig, ToyProvider};
+
+    /// The toy provider, answering after `delay`
+    struct Slow(ToyProvider, Duration);
+
+    #[async_trait]
+    impl LlmProvider for Slow {
+        async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+            tokio::time::sleep(self.1).await;
+            self.0.complete(request).await
+        }
+
+        fn name(&self) -> &str {
+            "slow"
+        }
+    }
+
+    fn slow(delay: Duration, timeout: Duration) -> TimeoutProvider<Slow> {
+        let toy = ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() });
+        TimeoutProvider::new(Slow(toy, delay), timeout)
+    }
+
+    fn request() -> CompletionRequest {
+        CompletionRequest {
+            prompt: "Hello".to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_calls_time_out() {
+        let provider = slow(Duration::from_secs(5), Duration::from_secs(2));
+        let error = provider.complete(request()).await.unwrap_err();
+        assert!(matches!(error, LettaError::Timeout { elapsed_ms: 2000 }), "{:?}", error);
+        assert!(matches!(provider.complete_stream(request()).await.map(|_| ()), Err(LettaError::Timeout { .. })));
+
+        let provider = slow(Duration::from_secs(1), Duration::from_secs(2));
+        assert!(provider.complete(request()).await.is_ok());
+        let chunks: Vec<_> = provider.complete_stream(request()).await.unwrap().collect().await;
+        assert!(chunks.iter().all(|chunk| chunk.is_ok()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_out_step_leaves_history_whole() {
+        let mut agent = Agent::new(AgentConfig::default(), Box::new(slow(Duration::from_secs(60), Duration::from_secs(1))));
+        let error = agent.step("#DO_SEARCH please".to_string()).await.unwrap_err();
+        assert!(matches!(error, LettaError::Timeout { .. }));
+
+        let roles: Vec<_> = agent.state.messages.messages.iter().map(|m| m.role.clone()).collect();
+        assert_eq!(roles, [MessageRole::User]);
+    }
+}
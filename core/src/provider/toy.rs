@@ -0,0 +1,359 @@
+//! The toy provider: canned or scripted replies without a model, for tests
+//! and for trying the library out. The `letta-provider-toy` crate re-exports
+//! these types, so every crate gets the same behavior.
+//!
+//! Without a script, prompts containing `#DO_SEARCH` or `#MEMORY_UPDATE`
+//! trigger a tool call, a search only until its result follows it, a prompt
+//! with a tool result gets a summary, and anything else gets a fixed reply,
+//! or in non-deterministic mode one of a few replies.
+//!
+//! `deterministic` decides whether the reply varies at all; `seed` decides
+//! how. Deterministic replies are the same whatever the seed. Otherwise
+//! they rotate by call count, or with a seed are drawn at random, repeatably.
+//! Simulated latencies are always drawn from the seeded PRNG, with seed 0
+//! when none is set, and failures from `fail_every_n` come by call count,
+//! so chaos tests replay exactly.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::error::{LettaError, ProviderError, ProviderErrorKind, Result};
+use crate::provider::{
+    Completion, CompletionChunk, CompletionRequest, CompletionStream, Embedder, LlmProvider, ProviderCapabilities,
+    ScriptedTurn, ToyConfig, TokenUsage,
+};
+use crate::tokenizer::{HeuristicTokenizer, Tokenizer};
+use crate::tool::ToolCall;
+
+const TOY_EMBEDDING_DIMS: usize = 64;
+
+/// The reply in deterministic mode
+const DETERMINISTIC_REPLY: &str = "I understand your request. How can I help you further?";
+
+/// Replies taken in turn, by call count, in non-deterministic mode
+const VARIABLE_REPLIES: [&str; 5] = [
+    "I'm here to help. What would you like to know?",
+    "Thank you for your message. Let me assist you with that.",
+    "I've processed your request. Is there anything specific you'd like me to focus on?",
+    "That's an interesting point. Could you provide more details?",
+    "I understand. Let me think about the best way to help you.",
+];
+
+/// Step of the splitmix64 generator
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// The reply to a prompt holding a tool result
+const TOOL_RESULT_REPLY: &str = "Based on the search results, here's a summary of the latest readings: \
+    The most recent values show stable patterns with readings at 168 mg/dL and 112 mg/dL.";
+
+pub struct ToyProvider {
+    config: ToyConfig,
+    /// Calls made, and so the next scripted turn
+    calls: AtomicUsize,
+    /// Calls to `embed`, kept apart so they don't use up scripted turns
+    embed_calls: AtomicUsize,
+    /// State of the PRNG behind random replies and latencies
+    random: AtomicU64,
+}
+
+impl ToyProvider {
+    /// Plays `config.script` only; `from_config` also loads `script_file`
+    pub fn new(config: ToyConfig) -> Self {
+        let random = AtomicU64::new(config.seed.unwrap_or(0));
+        Self { config, calls: AtomicUsize::new(0), embed_calls: AtomicUsize::new(0), random }
+    }
+
+    /// Like `new`, appending the turns in `config.script_file` to the script
+    pub fn from_config(mut config: ToyConfig) -> Result<Self> {
+        if let Some(path) = config.script_file.take() {
+            config.script.extend(ScriptedTurn::load(path)?);
+        }
+        Ok(Self::new(config))
+    }
+
+    /// Completions asked for so far, streamed or not
+    pub fn call_count(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// Calls to `embed` so far, one per batch when embedding in batches
+    pub fn embed_call_count(&self) -> usize {
+        self.embed_calls.load(Ordering::SeqCst)
+    }
+
+    /// The next number from a splitmix64 generator; lock-free, so calls on
+    /// several tasks each get a distinct draw
+    fn next_random(&self) -> u64 {
+        let mut z = self.random.fetch_add(GOLDEN_GAMMA, Ordering::SeqCst).wrapping_add(GOLDEN_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Wait out a random delay of up to `max_latency_ms`. Waiting needs the
+    /// tokio timer, which comes with the `http` feature; without it the
+    /// delay is drawn but not waited for.
+    async fn simulate_latency(&self) {
+        let Some(max) = self.config.max_latency_ms else {
+            return;
+        };
+        let delay = Duration::from_millis(self.next_random() % max.saturating_add(1));
+        #[cfg(feature = "http")]
+        tokio::time::sleep(delay).await;
+        #[cfg(not(feature = "http"))]
+        let _ = delay;
+    }
+
+    fn respond(&self, request: &CompletionRequest) -> Result<Completion> {
+        let turn = self.calls.fetch_add(1, Ordering::SeqCst);
+        if let Some(n) = self.config.fail_every_n.filter(|&n| n > 0 && (turn + 1).is_multiple_of(n)) {
+            let message = format!("Simulated failure on call {} (every {})", turn + 1, n);
+            return Err(ProviderError::new(ProviderErrorKind::Transport, message).into());
+        }
+        if let Some(scripted) = self.config.script.get(turn) {
+            return scripted.completion(turn, request);
+        }
+
+        let tool_call = |name: &str, arguments, request_heartbeat| {
+            let prompt_tokens = HeuristicTokenizer.count_tokens(&request.prompt);
+            Completion {
+                text: String::new(),
+                tool_calls: vec![ToolCall { id: format!("call_{}", turn), name: name.to_string(), arguments }],
+                request_heartbeat,
+                usage: TokenUsage { prompt_tokens, completion_tokens: 10, total_tokens: prompt_tokens + 10, cost_usd: None },
+            }
+        };
+        let unanswered_search = request.prompt.rfind("#DO_SEARCH")
+            .is_some_and(|at| !request.prompt[at..].contains("Tool ["));
+        if unanswered_search {
+            let arguments = serde_json::json!({ "query": "latest readings", "top_k": 3 });
+            Ok(tool_call("archival_search", arguments, true))
+        } else if request.prompt.contains("#MEMORY_UPDATE") {
+            let arguments = serde_json::json!({ "label": "human", "value": "Updated user information" });
+            Ok(tool_call("memory_replace", arguments, false))
+        } else if request.prompt.contains("Tool [") {
+            Ok(Completion::text(TOOL_RESULT_REPLY))
+        } else if self.config.deterministic {
+            Ok(Completion::text(DETERMINISTIC_REPLY))
+        } else {
+            let pick = match self.config.seed {
+                Some(_) => self.next_random() as usize,
+                None => turn,
+            };
+            Ok(Completion::text(VARIABLE_REPLIES[pick % VARIABLE_REPLIES.len()]))
+        }
+    }
+}
+
+impl ScriptedTurn {
+    /// The turn as the `turn`th reply to `request`
+    fn completion(&self, turn: usize, request: &CompletionRequest) -> Result<Completion> {
+        if let Some(error) = &self.error {
+            return Err(LettaError::Provider(error.as_str().into()));
+        }
+        let tool_calls = self.tool_calls.iter().enumerate()
+            .map(|(i, call)| ToolCall {
+                id: call.id.clone().unwrap_or_else(|| format!("call_{}_{}", turn, i)),
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+            })
+            .collect();
+        let prompt_tokens = HeuristicTokenizer.count_tokens(&request.prompt);
+        let completion_tokens = HeuristicTokenizer.count_tokens(&self.text);
+        Ok(Completion {
+            text: self.text.clone(),
+            tool_calls,
+            request_heartbeat: self.request_heartbeat,
+            usage: TokenUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens, cost_usd: None },
+        })
+    }
+}
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl LlmProvider for ToyProvider {
+    async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
+        self.simulate_latency().await;
+        Ok(self.respond(&request)?.stopped_for(&request))
+    }
+
+    async fn complete_stream<'a>(&'a self, request: CompletionRequest) -> Result<CompletionStream<'a>> {
+        // Word-sized deltas, so streaming consumers see more than one chunk
+        let mut completion = self.complete(request).await?;
+        let text = std::mem::take(&mut completion.text);
+        let chunks: Vec<_> = text.split_inclusive(' ')
+            .map(|word| CompletionChunk::Text { delta: word.to_string() })
+            .chain(completion.into_chunks())
+            .map(Ok)
+            .collect();
+        Ok(Box::pin(futures::stream::iter(chunks)))
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed_calls.fetch_add(1, Ordering::SeqCst);
+        ToyEmbedder.embed(texts).await
+    }
+
+    fn name(&self) -> &str {
+        "toy"
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities { supports_tools: true, supports_embeddings: true, supports_streaming: true, max_tools: None }
+    }
+}
+
+/// Embeds each lowercased word hashed into one of 64 buckets, so texts
+/// sharing words are similar. Deterministic and free, for tests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ToyEmbedder;
+
+#[cfg_attr(feature = "wasm", async_trait(?Send))]
+#[cfg_attr(not(feature = "wasm"), async_trait)]
+impl Embedder for ToyEmbedder {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| {
+            let mut vector = vec![0.0f32; TOY_EMBEDDING_DIMS];
+            for word in text.split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+                let hash = word.to_lowercase().bytes()
+                    .fold(0xcbf29ce484222325u64, |acc, b| (acc ^ b as u64).wrapping_mul(0x100000001b3));
+                vector[(hash % TOY_EMBEDDING_DIMS as u64) as usize] += 1.0;
+            }
+            vector
+        }).collect())
+    }
+
+    /// The toy provider's name, so its embeddings and these are interchangeable
+    fn name(&self) -> &str {
+        "toy"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt: &str) -> CompletionRequest {
+        CompletionRequest {
+            prompt: prompt.to_string(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        }
+    }
+
+    fn toy(deterministic: bool) -> ToyProvider {
+        ToyProvider::new(ToyConfig { deterministic, ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_replies_repeat() {
+        let toy = toy(true);
+        for _ in 0..3 {
+            assert_eq!(toy.complete(request("Hello")).await.unwrap().text, DETERMINISTIC_REPLY);
+        }
+        let search = toy.complete(request("#DO_SEARCH")).await.unwrap();
+        assert_eq!((search.tool_calls[0].id.as_str(), search.tool_calls[0].name.as_str()), ("call_3", "archival_search"));
+        assert!(search.request_heartbeat);
+        let update = toy.complete(request("#MEMORY_UPDATE")).await.unwrap();
+        assert_eq!((update.tool_calls[0].id.as_str(), update.tool_calls[0].name.as_str()), ("call_4", "memory_replace"));
+        assert!(!update.request_heartbeat);
+        assert_eq!(toy.complete(request("Tool [call_3]: []")).await.unwrap().text, TOOL_RESULT_REPLY);
+        let answered = toy.complete(request("User: #DO_SEARCH\nTool [call_3]: []")).await.unwrap();
+        assert_eq!(answered.text, TOOL_RESULT_REPLY);
+        assert_eq!(toy.call_count(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_variable_replies_rotate() {
+        let toy = toy(false);
+        let mut replies = Vec::new();
+        for _ in 0..VARIABLE_REPLIES.len() + 1 {
+            replies.push(toy.complete(request("Hello")).await.unwrap().text);
+        }
+        assert_eq!(replies[..VARIABLE_REPLIES.len()], VARIABLE_REPLIES);
+        assert_eq!(replies[VARIABLE_REPLIES.len()], VARIABLE_REPLIES[0]);
+
+        // Streaming counts as a call too
+        drop(toy.complete_stream(request("Hello")).await.unwrap());
+        assert_eq!(toy.call_count(), VARIABLE_REPLIES.len() + 2);
+        assert_eq!(toy.complete(request("Hello")).await.unwrap().text, VARIABLE_REPLIES[2]);
+    }
+
+    #[tokio::test]
+    async fn test_embeddings_do_not_depend_on_mode() {
+        let texts = vec!["Tea at four".to_string(), "Flight to Lisbon".to_string()];
+        let deterministic = toy(true).embed(texts.clone()).await.unwrap();
+        assert_eq!(deterministic, toy(false).embed(texts).await.unwrap());
+        assert!(deterministic.iter().all(|vector| vector.len() == TOY_EMBEDDING_DIMS));
+        assert_ne!(deterministic[0], deterministic[1]);
+    }
+
+    fn seeded(seed: u64) -> ToyProvider {
+        ToyProvider::new(ToyConfig { seed: Some(seed), ..Default::default() })
+    }
+
+    async fn replies(toy: &ToyProvider, n: usize) -> Vec<String> {
+        let mut replies = Vec::new();
+        for _ in 0..n {
+            replies.push(toy.complete(request("Hello")).await.unwrap().text);
+        }
+        replies
+    }
+
+    #[tokio::test]
+    async fn test_seeded_replies_repeat_across_runs() {
+        let first = replies(&seeded(7), 20).await;
+        assert_eq!(first, replies(&seeded(7), 20).await);
+        assert_ne!(first, replies(&seeded(8), 20).await);
+        assert_ne!(first[..VARIABLE_REPLIES.len()], VARIABLE_REPLIES);
+        assert!(first.iter().all(|reply| VARIABLE_REPLIES.contains(&reply.as_str())));
+
+        // Deterministic mode ignores the seed
+        let toy = ToyProvider::new(ToyConfig { deterministic: true, seed: Some(7), ..Default::default() });
+        assert!(replies(&toy, 3).await.iter().all(|reply| reply == DETERMINISTIC_REPLY));
+    }
+
+    #[tokio::test]
+    async fn test_fail_every_n() {
+        let script = vec![ScriptedTurn { text: "Scripted".into(), ..Default::default() }; 2];
+        let toy = ToyProvider::new(ToyConfig { deterministic: true, script, fail_every_n: Some(2), ..Default::default() });
+        assert_eq!(toy.complete(request("Hello")).await.unwrap().text, "Scripted");
+        let error = toy.complete(request("Hello")).await.unwrap_err();
+        assert!(matches!(&error, LettaError::Provider(e) if e.message.contains("call 2")), "{}", error);
+        assert!(error.is_retryable());
+        assert_eq!(toy.complete(request("Hello")).await.unwrap().text, DETERMINISTIC_REPLY);
+        assert!(toy.complete_stream(request("Hello")).await.is_err());
+        assert_eq!(toy.call_count(), 4);
+
+        let never = ToyProvider::new(ToyConfig { fail_every_n: Some(0), ..Default::default() });
+        assert!(never.complete(request("Hello")).await.is_ok());
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test(start_paused = true)]
+    async fn test_seeded_latency() {
+        async fn latencies(seed: Option<u64>) -> Vec<Duration> {
+            let toy = ToyProvider::new(ToyConfig { seed, max_latency_ms: Some(500), ..Default::default() });
+            let mut latencies = Vec::new();
+            for _ in 0..5 {
+                let started = tokio::time::Instant::now();
+                toy.complete(request("Hello")).await.unwrap();
+                latencies.push(started.elapsed());
+            }
+            latencies
+        }
+
+        let seeded = latencies(Some(3)).await;
+        assert_eq!(seeded, latencies(Some(3)).await);
+        assert!(seeded.iter().all(|latency| *latency <= Duration::from_millis(500)));
+        assert!(seeded.iter().any(|latency| *latency > Duration::ZERO));
+        assert_eq!(latencies(None).await, latencies(None).await);
+    }
+}
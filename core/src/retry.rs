@@ -0,0 +1,273 @@
+//! Retrying HTTP requests that a server turned away or that never arrived,
+//! shared by the cloud providers and `letta-sync`.
+//!
+//! A request is retried when the server answered 429, 503 or 529, which mean
+//! it was not processed, or when the connection could not be made. Gateway
+//! errors and timeouts are retried too, but only for idempotent methods,
+//! since the server may have acted on the request. A `Retry-After` header
+//! overrides the computed delay; if it asks for longer than `max_delay_ms`
+//! the request is not retried.
+
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Requests to send in total, counting the first; 1 disables retries
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubles with each one after
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Shorten each delay by a random fraction of up to this much (0.0 to
+    /// 1.0), so clients that failed together don't retry together
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 30000,
+            jitter: 0.25,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Send every request once
+    pub fn none() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    /// Delay before retry number `retry` (from 1), or `None` if the server's
+    /// `retry_after` is longer than `max_delay_ms`. `random` is in 0.0..1.0.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>, random: f64) -> Option<Duration> {
+        let max = Duration::from_millis(self.max_delay_ms);
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= max).then_some(retry_after);
+        }
+        let doubled = self.base_delay_ms.saturating_mul(1u64 << retry.saturating_sub(1).min(63));
+        let delay = Duration::from_millis(doubled).min(max);
+        Some(delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random))
+    }
+}
+
+/// The outcome of a retried operation
+#[derive(Debug)]
+pub struct Retried<T> {
+    pub result: T,
+    /// Requests sent, counting the first
+    pub attempts: u32,
+    /// The last attempt failed in a way worth retrying, but no attempts or
+    /// time were left
+    pub gave_up: bool,
+}
+
+/// Whether a response with `status` may be retried
+pub fn is_retriable_status(status: u16, idempotent: bool) -> bool {
+    match status {
+        // Rate limited, unavailable, and Anthropic's "overloaded"
+        429 | 503 | 529 => true,
+        502 | 504 => idempotent,
+        _ => false,
+    }
+}
+
+/// How long a `Retry-After` header value asks to wait: either seconds or an
+/// HTTP date
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(feature = "http")]
+mod http {
+    use std::future::Future;
+    use reqwest::{Client, ClientBuilder, Method, NoProxy, Proxy, RequestBuilder, Response};
+    use super::*;
+
+    /// Send the request built by `request` until it succeeds, fails in a way
+    /// not worth retrying, or `config` runs out of attempts. `request` is
+    /// called for each attempt, so it can attach fresh credentials. The last
+    /// response is returned whatever its status.
+    pub async fn send<E, F, Fut>(config: &RetryConfig, mut request: F) -> Retried<Result<Response, E>>
+    where
+        E: From<reqwest::Error>,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<RequestBuilder, E>>,
+    {
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let done = |result, gave_up| Retried { result, attempts, gave_up };
+
+            let (client, built) = match request().await {
+                Ok(builder) => builder.build_split(),
+                Err(e) => return done(Err(e), false),
+            };
+            let built = match built {
+                Ok(built) => built,
+                Err(e) => return done(Err(e.into()), false),
+            };
+            let idempotent = is_idempotent(built.method());
+            let result = client.execute(built).await;
+
+            let retry_after = match &result {
+                Ok(response) if is_retriable_status(response.status().as_u16(), idempotent) => {
+                    Some(response.headers().get(reqwest::header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| parse_retry_after(value, Utc::now())))
+                }
+                Err(e) if e.is_connect() || (idempotent && e.is_timeout()) => Some(None),
+                _ => None,
+            };
+            let Some(retry_after) = retry_after else {
+                return done(result.map_err(E::from), false);
+            };
+
+            let delay = config.delay(attempts, retry_after, fastrand::f64());
+            match delay.filter(|_| attempts < config.max_attempts) {
+                Some(delay) => {
+                    tracing::debug!(attempt = attempts, delay_ms = delay.as_millis() as u64, "Retrying HTTP request");
+                    tokio::time::sleep(delay).await;
+                }
+                None => return done(result.map_err(E::from), true),
+            }
+        }
+    }
+
+    fn is_idempotent(method: &Method) -> bool {
+        matches!(*method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS)
+    }
+
+    /// A client builder that sends every request through `proxy` (`http://`
+    /// or `https://`) if set, except to the hosts in `no_proxy`,
+    /// a comma-separated list in the format of the `NO_PROXY` variable.
+    /// Without `proxy` the usual proxy environment variables apply.
+    pub fn client_builder(proxy: Option<&str>, no_proxy: Option<&str>) -> reqwest::Result<ClientBuilder> {
+        let builder = Client::builder();
+        let Some(proxy) = proxy else {
+            return Ok(builder);
+        };
+        let proxy = Proxy::all(proxy)?.no_proxy(no_proxy.and_then(NoProxy::from_string));
+        Ok(builder.proxy(proxy))
+    }
+}
+
+#[cfg(feature = "http")]
+pub use http::{client_builder, send};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_doubles_and_caps() {
+        let config = RetryConfig { base_delay_ms: 100, max_delay_ms: 1000, jitter: 0.5, ..Default::default() };
+        assert_eq!(config.delay(1, None, 0.0), Some(Duration::from_millis(100)));
+        assert_eq!(config.delay(2, None, 0.0), Some(Duration::from_millis(200)));
+        assert_eq!(config.delay(5, None, 0.0), Some(Duration::from_millis(1000)));
+        assert_eq!(config.delay(100, None, 0.0), Some(Duration::from_millis(1000)));
+        // Jitter only shortens
+        assert_eq!(config.delay(2, None, 0.5), Some(Duration::from_millis(150)));
+
+        // The server's wait wins, unless it is longer than we're willing to wait
+        assert_eq!(config.delay(1, Some(Duration::from_secs(1)), 0.9), Some(Duration::from_secs(1)));
+        assert_eq!(config.delay(1, Some(Duration::from_secs(2)), 0.0), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_retriable_statuses() {
+        assert!(is_retriable_status(429, false));
+        assert!(is_retriable_status(503, false));
+        assert!(!is_retriable_status(502, false));
+        assert!(is_retriable_status(502, true));
+        assert!(!is_retriable_status(500, true));
+        assert!(!is_retriable_status(404, true));
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_send_retries_until_success() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Turns the first two requests away
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            for status in ["503 Service Unavailable", "429 Too Many Requests", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4096];
+                let _ = stream.read(&mut buffer).await.unwrap();
+                let response = format!("HTTP/1.1 {}\r\nretry-after: 0\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let config = RetryConfig { base_delay_ms: 1, ..Default::default() };
+        let retried = send(&config, || async { Ok::<_, reqwest::Error>(client.post(&url).body("{}")) }).await;
+        assert_eq!(retried.result.unwrap().status(), 200);
+        assert_eq!(retried.attempts, 3);
+        assert!(!retried.gave_up);
+        server.await.unwrap();
+
+        // The server is gone; connecting fails every time
+        let config = RetryConfig { max_attempts: 2, base_delay_ms: 1, ..Default::default() };
+        let retried = send(&config, || async { Ok::<_, reqwest::Error>(client.get(&url)) }).await;
+        assert!(retried.result.is_err());
+        assert_eq!(retried.attempts, 2);
+        assert!(retried.gave_up);
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_client_builder_proxies_all_but_no_proxy_hosts() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Answers one request, sending back its request line
+        async fn serve_once() -> (String, tokio::task::JoinHandle<String>) {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4096];
+                let n = stream.read(&mut buffer).await.unwrap();
+                let head = String::from_utf8_lossy(&buffer[..n]).into_owned();
+                stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").await.unwrap();
+                head.lines().next().unwrap_or_default().to_string()
+            });
+            (url, server)
+        }
+
+        let (proxy, server) = serve_once().await;
+        let client = client_builder(Some(&proxy), None).unwrap().build().unwrap();
+        let response = client.get("http://agents.example/v1/agents").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(server.await.unwrap(), "GET http://agents.example/v1/agents HTTP/1.1");
+
+        // Nothing listens on the proxy, so this only succeeds by going direct
+        let (url, server) = serve_once().await;
+        let client = client_builder(Some("http://127.0.0.1:9"), Some("localhost,127.0.0.1")).unwrap().build().unwrap();
+        assert_eq!(client.get(format!("{}/v1/agents", url)).send().await.unwrap().status(), 200);
+        assert_eq!(server.await.unwrap(), "GET /v1/agents HTTP/1.1");
+
+        assert!(client_builder(Some("not a url"), None).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Process-wide shutdown hooks. Whatever owns agents, background tasks or
+//! databases registers what to do with them, and `shutdown_all` runs the
+//! hooks a stage at a time, so storage is only closed once everything that
+//! writes to it has been saved and stopped.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::error::Result;
+
+/// When a hook runs; stages run in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownStage {
+    /// Save agents with unsaved changes
+    SaveAgents,
+    /// Stop background work such as auto-sync
+    StopTasks,
+    /// Close storage pools
+    CloseStorage,
+}
+
+type Hook = Arc<Mutex<dyn FnMut() -> Result<()> + Send>>;
+
+struct Registered {
+    id: u64,
+    stage: ShutdownStage,
+    name: String,
+    hook: Hook,
+}
+
+static HOOKS: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A hook registered with `on_shutdown`; dropping it removes the hook
+#[must_use = "the hook is removed as soon as this is dropped"]
+pub struct ShutdownHook {
+    id: u64,
+}
+
+impl ShutdownHook {
+    /// Keep the hook for the rest of the process
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for ShutdownHook {
+    fn drop(&mut self) {
+        hooks().retain(|registered| registered.id != self.id);
+    }
+}
+
+/// Run `hook` during `stage` of every `shutdown_all`. `name` identifies it
+/// in logs.
+pub fn on_shutdown(
+    stage: ShutdownStage,
+    name: impl Into<String>,
+    hook: impl FnMut() -> Result<()> + Send + 'static,
+) -> ShutdownHook {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    hooks().push(Registered { id, stage, name: name.into(), hook: Arc::new(Mutex::new(hook)) });
+    ShutdownHook { id }
+}
+
+/// Run every registered hook, stage by stage and in registration order
+/// within a stage. The rest of a stage still runs after a hook fails, but
+/// later stages don't, so storage stays open for agents that could not be
+/// saved; the first error is returned. Hooks stay registered.
+pub fn shutdown_all() -> Result<()> {
+    let mut stages: Vec<(ShutdownStage, String, Hook)> = hooks().iter()
+        .map(|registered| (registered.stage, registered.name.clone(), registered.hook.clone()))
+        .collect();
+    // Stable, so registration order is kept within a stage
+    stages.sort_by_key(|(stage, _, _)| *stage);
+
+    let mut first_error = None;
+    let mut current = None;
+    for (stage, name, hook) in stages {
+        if current != Some(stage) && first_error.is_some() {
+            break;
+        }
+        current = Some(stage);
+
+        // Hooks may register or drop others, so none run under the list's lock
+        let result = (hook.lock().unwrap_or_else(PoisonError::into_inner))();
+        if let Err(e) = result {
+            tracing::warn!(hook = %name, stage = ?stage, error = %e, "Shutdown hook failed");
+            first_error.get_or_insert(e);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+fn hooks() -> MutexGuard<'static, Vec<Registered>> {
+    HOOKS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::LettaError;
+
+    // The registry is process-wide, so everything is checked in one test
+    #[test]
+    fn test_shutdown_stages() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |entry: &'static str, fail: bool| {
+            let log = log.clone();
+            move || {
+                log.lock().unwrap().push(entry);
+                if fail { Err(LettaError::Unknown(entry.into())) } else { Ok(()) }
+            }
+        };
+
+        let close = on_shutdown(ShutdownStage::CloseStorage, "close", record("close", false));
+        let stop = on_shutdown(ShutdownStage::StopTasks, "stop", record("stop", false));
+        let first = on_shutdown(ShutdownStage::SaveAgents, "first", record("save 1", false));
+        let second = on_shutdown(ShutdownStage::SaveAgents, "second", record("save 2", false));
+        shutdown_all().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["save 1", "save 2", "stop", "close"]);
+
+        // A failed save keeps storage open
+        log.lock().unwrap().clear();
+        drop(first);
+        let failing = on_shutdown(ShutdownStage::SaveAgents, "failing", record("failing", true));
+        assert!(matches!(shutdown_all(), Err(LettaError::Unknown(message)) if message == "failing"));
+        assert_eq!(*log.lock().unwrap(), ["save 2", "failing"]);
+
+        log.lock().unwrap().clear();
+        drop((failing, second, stop, close));
+        shutdown_all().unwrap();
+        assert!(log.lock().unwrap().is_empty());
+    }
+}
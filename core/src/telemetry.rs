@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::provider::TokenUsage;
+
+/// Times a step, provider call or tool call. `std::time::Instant` panics on
+/// wasm32-unknown-unknown, so the `wasm` feature reads the wall clock instead.
+pub(crate) struct Stopwatch {
+    #[cfg(not(feature = "wasm"))]
+    started: std::time::Instant,
+    #[cfg(feature = "wasm")]
+    started: chrono::DateTime<chrono::Utc>,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self {
+            #[cfg(not(feature = "wasm"))]
+            started: std::time::Instant::now(),
+            #[cfg(feature = "wasm")]
+            started: chrono::Utc::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(feature = "wasm"))]
+        return self.started.elapsed();
+        #[cfg(feature = "wasm")]
+        return (chrono::Utc::now() - self.started).to_std().unwrap_or_default();
+    }
+}
+
+/// A finished `Agent` step, from the user message to the final reply
+#[derive(Debug, Clone, Copy)]
+pub struct StepMetrics<'a> {
+    pub agent_id: &'a str,
+    pub duration: Duration,
+    /// Provider calls the step took, one more for each tool round trip
+    pub iterations: usize,
+    /// Tokens used by all of the step's provider calls
+    pub usage: &'a TokenUsage,
+    pub tool_calls: usize,
+    pub success: bool,
+}
+
+/// One completion request to the agent's provider
+#[derive(Debug, Clone, Copy)]
+pub struct ProviderCallMetrics<'a> {
+    pub agent_id: &'a str,
+    pub provider: &'a str,
+    pub duration: Duration,
+    /// `None` when the call failed
+    pub usage: Option<&'a TokenUsage>,
+    pub streamed: bool,
+}
+
+/// One tool call made by an agent
+#[derive(Debug, Clone, Copy)]
+pub struct ToolMetrics<'a> {
+    pub agent_id: &'a str,
+    pub tool: &'a str,
+    pub duration: Duration,
+    pub success: bool,
+}
+
+/// Receives numeric measurements from agents, for an app to forward to its
+/// own analytics. Install one with `set_metrics`. Every method defaults to
+/// doing nothing; they are called on the step's task, so should return
+/// quickly.
+pub trait Metrics: Send + Sync {
+    fn on_step(&self, _step: &StepMetrics<'_>) {}
+    
+    fn on_provider_call(&self, _call: &ProviderCallMetrics<'_>) {}
+    
+    fn on_tool(&self, _tool: &ToolMetrics<'_>) {}
+}
+
+/// The recorder in place until `set_metrics` is called
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+static METRICS: RwLock<Option<Arc<dyn Metrics>>> = RwLock::new(None);
+
+/// Send every agent's measurements to `recorder` from now on, replacing the
+/// one installed before
+pub fn set_metrics(recorder: Arc<dyn Metrics>) {
+    *METRICS.write().unwrap_or_else(PoisonError::into_inner) = Some(recorder);
+}
+
+/// Go back to discarding measurements
+pub fn clear_metrics() {
+    *METRICS.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// Pass a measurement to the installed recorder, if any. `emit` only runs
+/// when there is one, so nothing is built for a recorder that discards it.
+pub(crate) fn record(emit: impl FnOnce(&dyn Metrics)) {
+    let recorder = METRICS.read().unwrap_or_else(PoisonError::into_inner).clone();
+    if let Some(recorder) = recorder {
+        emit(recorder.as_ref());
+    }
+}
+
+/// Count, total and extremes of a duration, in milliseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub total_ms: f64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+}
+
+impl LatencySummary {
+    pub fn record(&mut self, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        if self.count == 0 || ms < self.min_ms {
+            self.min_ms = ms;
+        }
+        self.max_ms = self.max_ms.max(ms);
+        self.total_ms += ms;
+        self.count += 1;
+    }
+    
+    pub fn mean_ms(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.total_ms / self.count as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ToolSnapshot {
+    pub calls: u64,
+    pub failures: u64,
+    pub latency: LatencySummary,
+}
+
+/// Everything an `InMemoryMetrics` recorded, overall or for one agent
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub steps: u64,
+    pub failed_steps: u64,
+    pub step_latency: LatencySummary,
+    pub provider_calls: u64,
+    pub failed_provider_calls: u64,
+    pub provider_latency: LatencySummary,
+    /// Tokens used by successful provider calls
+    pub usage: TokenUsage,
+    pub tools: BTreeMap<String, ToolSnapshot>,
+}
+
+impl MetricsSnapshot {
+    fn on_step(&mut self, step: &StepMetrics<'_>) {
+        self.steps += 1;
+        if !step.success {
+            self.failed_steps += 1;
+        }
+        self.step_latency.record(step.duration);
+    }
+    
+    fn on_provider_call(&mut self, call: &ProviderCallMetrics<'_>) {
+        self.provider_calls += 1;
+        match call.usage {
+            Some(usage) => self.usage.add(usage),
+            None => self.failed_provider_calls += 1,
+        }
+        self.provider_latency.record(call.duration);
+    }
+    
+    fn on_tool(&mut self, tool: &ToolMetrics<'_>) {
+        let snapshot = match self.tools.get_mut(tool.tool) {
+            Some(snapshot) => snapshot,
+            None => self.tools.entry(tool.tool.to_string()).or_default(),
+        };
+        snapshot.calls += 1;
+        if !tool.success {
+            snapshot.failures += 1;
+        }
+        snapshot.latency.record(tool.duration);
+    }
+}
+
+/// A recorder keeping running totals in memory, overall and per agent, for
+/// tests and for apps that read them back rather than forward them
+#[derive(Default)]
+pub struct InMemoryMetrics {
+    totals: Mutex<(MetricsSnapshot, HashMap<String, MetricsSnapshot>)>,
+}
+
+impl InMemoryMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Totals across every agent
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.lock().0.clone()
+    }
+    
+    /// Totals for one agent; empty if it recorded nothing
+    pub fn agent_snapshot(&self, agent_id: &str) -> MetricsSnapshot {
+        self.lock().1.get(agent_id).cloned().unwrap_or_default()
+    }
+    
+    pub fn reset(&self) {
+        *self.lock() = Default::default();
+    }
+    
+    fn update(&self, agent_id: &str, apply: impl Fn(&mut MetricsSnapshot)) {
+        let mut totals = self.lock();
+        let (overall, per_agent) = &mut *totals;
+        apply(overall);
+        match per_agent.get_mut(agent_id) {
+            Some(snapshot) => apply(snapshot),
+            None => apply(per_agent.entry(agent_id.to_string()).or_default()),
+        }
+    }
+    
+    fn lock(&self) -> std::sync::MutexGuard<'_, (MetricsSnapshot, HashMap<String, MetricsSnapshot>)> {
+        self.totals.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn on_step(&self, step: &StepMetrics<'_>) {
+        self.update(step.agent_id, |snapshot| snapshot.on_step(step));
+    }
+    
+    fn on_provider_call(&self, call: &ProviderCallMetrics<'_>) {
+        self.update(call.agent_id, |snapshot| snapshot.on_provider_call(call));
+    }
+    
+    fn on_tool(&self, tool: &ToolMetrics<'_>) {
+        self.update(tool.agent_id, |snapshot| snapshot.on_tool(tool));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_in_memory_metrics_totals() {
+        let metrics = InMemoryMetrics::new();
+        let usage = TokenUsage { prompt_tokens: 10, completion_tokens: 5, total_tokens: 15, cost_usd: None };
+        let call = |agent_id, usage| ProviderCallMetrics {
+            agent_id,
+            provider: "toy",
+            duration: Duration::from_millis(20),
+            usage,
+            streamed: false,
+        };
+        metrics.on_provider_call(&call("a", Some(&usage)));
+        metrics.on_provider_call(&call("a", None));
+        metrics.on_provider_call(&call("b", Some(&usage)));
+        for duration in [10, 30] {
+            metrics.on_tool(&ToolMetrics {
+                agent_id: "a",
+                tool: "memory_append",
+                duration: Duration::from_millis(duration),
+                success: duration < 20,
+            });
+        }
+        
+        let a = metrics.agent_snapshot("a");
+        assert_eq!((a.provider_calls, a.failed_provider_calls), (2, 1));
+        assert_eq!(a.usage.total_tokens, 15);
+        let tool = &a.tools["memory_append"];
+        assert_eq!((tool.calls, tool.failures), (2, 1));
+        assert_eq!((tool.latency.min_ms, tool.latency.max_ms, tool.latency.mean_ms()), (10.0, 30.0, 20.0));
+        
+        let overall = metrics.snapshot();
+        assert_eq!(overall.provider_calls, 3);
+        assert_eq!(overall.usage.total_tokens, 30);
+        assert_eq!(metrics.agent_snapshot("c"), MetricsSnapshot::default());
+        
+        metrics.reset();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+    }
+}
@@ -0,0 +1,212 @@
+//! Token counting for prompt budgeting. `HeuristicTokenizer` needs no data
+//! and is the default; with the `tiktoken` feature, `BpeTokenizer` counts
+//! with OpenAI's BPE encodings, given their ranks file.
+
+use std::fmt;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use crate::error::{LettaError, Result};
+
+pub trait Tokenizer: Send + Sync + fmt::Debug {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// About four characters per token for alphabetic scripts, and a token per
+/// character for Chinese, Japanese and Korean, which BPE vocabularies rarely
+/// merge
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        let mut tokens = 0;
+        let mut run: usize = 0;
+        for c in text.chars() {
+            if is_cjk(c) {
+                tokens += run.div_ceil(4) + 1;
+                run = 0;
+            } else {
+                run += 1;
+            }
+        }
+        tokens + run.div_ceil(4)
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'     // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}'   // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}'   // CJK Unified Ideographs
+        | '\u{AC00}'..='\u{D7AF}'   // Hangul syllables
+        | '\u{F900}'..='\u{FAFF}'   // CJK Compatibility Ideographs
+        | '\u{FF00}'..='\u{FFEF}'   // Full-width forms
+        | '\u{20000}'..='\u{2FFFF}' // Supplementary ideographs
+    )
+}
+
+/// Which tokenizer an agent counts its prompt with
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TokenizerConfig {
+    #[default]
+    Heuristic,
+    /// A tiktoken ranks file such as `cl100k_base.tiktoken`; needs the
+    /// `tiktoken` feature
+    Tiktoken { ranks_path: String },
+}
+
+impl TokenizerConfig {
+    pub fn build(&self) -> Result<Arc<dyn Tokenizer>> {
+        match self {
+            TokenizerConfig::Heuristic => Ok(Arc::new(HeuristicTokenizer)),
+            #[cfg(feature = "tiktoken")]
+            TokenizerConfig::Tiktoken { ranks_path } => Ok(bpe::load_cached(ranks_path)?),
+            #[cfg(not(feature = "tiktoken"))]
+            TokenizerConfig::Tiktoken { .. } => Err(LettaError::InvalidConfig(
+                "The tiktoken tokenizer needs letta-core's `tiktoken` feature".into(),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "tiktoken")]
+pub use bpe::BpeTokenizer;
+
+#[cfg(feature = "tiktoken")]
+mod bpe {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, PoisonError};
+    use base64::Engine;
+    use regex::Regex;
+    use super::*;
+
+    /// cl100k's pre-tokenizer, less the `\s+(?!\S)` alternative the regex
+    /// crate can't express. Runs of spaces before a word keep their last
+    /// space, so counts can differ from tiktoken by a token per such run.
+    const PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+    /// Ranks files are large, so agents naming the same one share it
+    static LOADED: Mutex<Vec<(String, Arc<BpeTokenizer>)>> = Mutex::new(Vec::new());
+
+    pub(super) fn load_cached(path: &str) -> Result<Arc<BpeTokenizer>> {
+        let mut loaded = LOADED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, tokenizer)) = loaded.iter().find(|(loaded_path, _)| loaded_path == path) {
+            return Ok(tokenizer.clone());
+        }
+        let tokenizer = Arc::new(BpeTokenizer::from_file(path)?);
+        loaded.push((path.to_string(), tokenizer.clone()));
+        Ok(tokenizer)
+    }
+
+    /// Byte-pair encoding over a tiktoken ranks file
+    pub struct BpeTokenizer {
+        ranks: HashMap<Vec<u8>, u32>,
+        pattern: Regex,
+    }
+
+    impl fmt::Debug for BpeTokenizer {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("BpeTokenizer").field("tokens", &self.ranks.len()).finish()
+        }
+    }
+
+    impl BpeTokenizer {
+        pub fn from_file(path: &str) -> Result<Self> {
+            let ranks = std::fs::read_to_string(path)
+                .map_err(|e| LettaError::InvalidConfig(format!("Can't read tokenizer ranks {}: {}", path, e)))?;
+            Self::from_ranks(&ranks)
+        }
+
+        /// Parse ranks in tiktoken's format: a base64 token and its rank per
+        /// line
+        pub fn from_ranks(ranks: &str) -> Result<Self> {
+            let invalid = |line: &str| LettaError::InvalidConfig(format!("Invalid tokenizer rank line: {:?}", line));
+            let ranks = ranks.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    let (token, rank) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+                    let token = base64::engine::general_purpose::STANDARD.decode(token).map_err(|_| invalid(line))?;
+                    let rank = rank.trim().parse().map_err(|_| invalid(line))?;
+                    Ok((token, rank))
+                })
+                .collect::<Result<_>>()?;
+            Ok(Self { ranks, pattern: Regex::new(PATTERN).expect("valid pattern") })
+        }
+
+        /// Tokens in one pre-tokenized piece: merge the lowest-ranked
+        /// adjacent pair until none of the pairs is a token
+        fn count_piece(&self, piece: &[u8]) -> usize {
+            if self.ranks.contains_key(piece) {
+                return 1;
+            }
+            let mut bounds: Vec<usize> = (0..=piece.len()).collect();
+            while bounds.len() > 2 {
+                let best = (0..bounds.len() - 2)
+                    .filter_map(|i| self.ranks.get(&piece[bounds[i]..bounds[i + 2]]).map(|rank| (*rank, i)))
+                    .min();
+                match best {
+                    Some((_, i)) => {
+                        bounds.remove(i + 1);
+                    }
+                    None => break,
+                }
+            }
+            bounds.len() - 1
+        }
+    }
+
+    impl Tokenizer for BpeTokenizer {
+        fn count_tokens(&self, text: &str) -> usize {
+            self.pattern.find_iter(text).map(|piece| self.count_piece(piece.as_str().as_bytes())).sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heuristic_counts_cjk_per_character() {
+        let chinese = "我们今天在公园里散步，天气非常好。".repeat(10);
+        let characters = chinese.chars().count();
+        let tokens = HeuristicTokenizer.count_tokens(&chinese);
+        // Three UTF-8 bytes a character, so `len() / 4` gave under one token
+        // per character where BPE tokenizers produce one or more
+        assert!(chinese.len() / 4 < characters);
+        assert!(tokens >= characters, "{} tokens for {} characters", tokens, characters);
+
+        assert_eq!(HeuristicTokenizer.count_tokens("Hello, world"), 3);
+        assert_eq!(HeuristicTokenizer.count_tokens("日本語 text"), 3 + 2);
+        assert_eq!(HeuristicTokenizer.count_tokens(""), 0);
+    }
+
+    #[cfg(not(feature = "tiktoken"))]
+    #[test]
+    fn test_tiktoken_needs_feature() {
+        let config = TokenizerConfig::Tiktoken { ranks_path: "cl100k_base.tiktoken".into() };
+        assert!(matches!(config.build(), Err(LettaError::InvalidConfig(_))));
+    }
+
+    #[cfg(feature = "tiktoken")]
+    #[test]
+    fn test_bpe_merges_by_rank() {
+        use base64::Engine;
+
+        let encode = |token: &str| base64::engine::general_purpose::STANDARD.encode(token);
+        let mut ranks: Vec<String> = (b'a'..=b'z').chain([b' ']).enumerate()
+            .map(|(rank, byte)| format!("{} {}", encode(&(byte as char).to_string()), rank))
+            .collect();
+        for (rank, token) in ["th", "he", "the", " the"].iter().enumerate() {
+            ranks.push(format!("{} {}", encode(token), 100 + rank));
+        }
+        let tokenizer = BpeTokenizer::from_ranks(&ranks.join("\n")).unwrap();
+
+        assert_eq!(tokenizer.count_tokens("the"), 1);
+        // " the" is a token; "cat" is three single letters
+        assert_eq!(tokenizer.count_tokens("the cat the"), 1 + 4 + 1);
+        assert_eq!(tokenizer.count_tokens("hethe"), 2);
+        assert!(BpeTokenizer::from_ranks("not-base64!").is_err());
+    }
+}
@@ -3,6 +3,7 @@ use serde_json::Value;
 use std::collections::HashMap;
 use crate::error::{LettaError, Result};
 use crate::agent::AgentState;
+use crate::archival::{self, SearchMode};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolSchema {
@@ -176,16 +177,18 @@ impl ToolHandler for ArchivalSearchHandler {
             .and_then(|v| v.as_u64())
             .unwrap_or(5) as usize;
         
-        let results: Vec<&Value> = state.archival_entries
-            .iter()
-            .filter(|entry| {
-                entry.get("text")
-                    .and_then(|t| t.as_str())
-                    .map(|t| t.to_lowercase().contains(&query.to_lowercase()))
-                    .unwrap_or(false)
-            })
-            .take(top_k)
-            .collect();
+        // Semantic searches are run by the agent, which has the provider
+        match SearchMode::from_args(args) {
+            Some(SearchMode::Keyword) => {}
+            Some(SearchMode::Semantic) => return Err(LettaError::ToolExecution(
+                "Semantic archival search needs an agent's embeddings provider".into(),
+            )),
+            None => return Err(LettaError::ToolExecution(
+                "'mode' must be \"keyword\" or \"semantic\"".into(),
+            )),
+        }
+        
+        let results = archival::keyword_search(&state.archival_entries, query, top_k);
         
         Ok(ToolResult::success(serde_json::json!({
             "results": results,
@@ -290,7 +293,12 @@ impl ToolExecutor {
                     "type": "object",
                     "properties": {
                         "query": {"type": "string", "description": "Search query"},
-                        "top_k": {"type": "integer", "description": "Number of results"}
+                        "top_k": {"type": "integer", "description": "Number of results"},
+                        "mode": {
+                            "type": "string",
+                            "enum": ["keyword", "semantic"],
+                            "description": "keyword (default) matches text containing the query; semantic ranks by meaning"
+                        }
                     },
                     "required": ["query"]
                 }),
@@ -313,6 +321,12 @@ impl ToolExecutor {
     }
 }
 
+impl Default for ToolExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // 修复核心：显式指定 HashMap 类型为 Box<dyn ToolHandler>，避免自动推断错误
 impl Clone for ToolExecutor {
     fn clone(&self) -> Self {
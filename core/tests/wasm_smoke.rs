@@ -0,0 +1,48 @@
+//! Single-threaded smoke test for the `wasm` feature:
+//! `cargo test -p letta-core --no-default-features --features wasm`
+#![cfg(feature = "wasm")]
+
+use std::cell::Cell;
+use std::rc::Rc;
+use async_trait::async_trait;
+use letta_core::provider::{ToyConfig, ToyProvider};
+use letta_core::{Agent, AgentConfig, Completion, CompletionRequest, LlmProvider, Result};
+
+/// Holds an `Rc`, like a provider wrapping a JS handle would, so it is
+/// neither `Send` nor `Sync`
+struct LocalProvider {
+    calls: Rc<Cell<usize>>,
+}
+
+#[async_trait(?Send)]
+impl LlmProvider for LocalProvider {
+    async fn complete(&self, _request: CompletionRequest) -> Result<Completion> {
+        self.calls.set(self.calls.get() + 1);
+        Ok(Completion::text("hello from the browser"))
+    }
+
+    fn name(&self) -> &str {
+        "local"
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_toy_agent_step() {
+    let provider = Box::new(ToyProvider::new(ToyConfig { deterministic: true, ..Default::default() }));
+    let mut agent = Agent::new(AgentConfig::default(), provider);
+
+    let result = agent.step("Hello!".to_string()).await.unwrap();
+    assert!(!result.text.is_empty());
+    assert!(result.usage.total_tokens > 0);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_non_send_provider_step() {
+    let calls = Rc::new(Cell::new(0));
+    let mut agent = Agent::new(AgentConfig::default(), Box::new(LocalProvider { calls: calls.clone() }));
+
+    let result = agent.step("Hello!".to_string()).await.unwrap();
+    assert_eq!(result.text, "hello from the browser");
+    assert_eq!(calls.get(), 1);
+    assert_eq!(agent.state.messages.messages.len(), 2);
+}
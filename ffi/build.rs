@@ -7,7 +7,16 @@ fn main() {
     
     std::fs::create_dir_all(&output_dir).unwrap();
     
+    // Error codes become LETTA_ERROR_CODE_OK, LETTA_ERROR_CODE_INVALID_ARG, ...
+    let mut config = cbindgen::Config::default();
+    config.enumeration.rename_variants = cbindgen::RenameRule::QualifiedScreamingSnakeCase;
+    config.export.include.push("LettaErrorCode".to_string());
+    config.export.include.push("LettaAfCompression".to_string());
+    config.export.include.push("LettaImportMode".to_string());
+    config.export.include.push("LettaLogLevel".to_string());
+    
     cbindgen::Builder::new()
+        .with_config(config)
         .with_crate(crate_dir)
         .with_language(cbindgen::Language::C)
         .with_include_guard("LETTA_LITE_H")
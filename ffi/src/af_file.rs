@@ -0,0 +1,304 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use letta_core::persist::{export_all_to_af, ExportOptions};
+use letta_core::{AfCompression, AgentFile};
+
+use crate::error::{catch_panic, pointer, set_last_error, status, FfiError, FfiResult, LettaErrorCode};
+use crate::{c_str_arg, null_on_error, storage, string_to_c_str, with_agent, AgentHandle};
+
+/// Encoding written by `letta_export_af_file`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LettaAfCompression {
+    None = 0,
+    Gzip = 1,
+}
+
+/// How `letta_import_af_file` applies a file to the agent
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LettaImportMode {
+    /// Replace the agent's state, as `letta_load_af` does
+    Replace = 0,
+    /// Keep the agent's state, taking the file's memory blocks and any
+    /// messages the agent doesn't already have
+    Merge = 1,
+}
+
+/// Write the agent to `path` as an agent file, streamed rather than built in
+/// memory. `compression` is a `LettaAfCompression`. The file is replaced only
+/// once fully written. Failures to create or write it return
+/// `LETTA_ERROR_CODE_IO_ERROR`.
+#[no_mangle]
+pub extern "C" fn letta_export_af_file(handle: *mut AgentHandle, path: *const c_char, compression: i32) -> i32 {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        status(export_af_file(handle, &path_str, compression))
+    })
+}
+
+fn export_af_file(handle: *mut AgentHandle, path_str: &str, compression: i32) -> FfiResult<()> {
+    let path = file_path(path_str)?;
+    let compression = match compression {
+        c if c == LettaAfCompression::None as i32 => AfCompression::None,
+        c if c == LettaAfCompression::Gzip as i32 => AfCompression::Gzip,
+        other => return Err(FfiError::invalid_arg(format!("Unknown compression {}", other))),
+    };
+    
+    let af = with_agent(handle, |agent| Ok(AgentFile::export(&agent.config, &agent.state, vec![])?))?;
+    replace_file(&path, |file| AgentFile::to_writer(&af, file, compression))
+}
+
+/// Write the agent to `path` as an agent file encrypted with `passphrase`,
+/// which must not be empty. Read it back with `letta_import_af_file_encrypted`.
+/// The file is replaced only once fully written.
+#[no_mangle]
+pub extern "C" fn letta_export_af_file_encrypted(handle: *mut AgentHandle, path: *const c_char, passphrase: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        let passphrase = c_str_arg!(passphrase, "passphrase", set_last_error);
+        status(export_af_file_encrypted(handle, &path_str, &passphrase))
+    })
+}
+
+fn export_af_file_encrypted(handle: *mut AgentHandle, path_str: &str, passphrase: &str) -> FfiResult<()> {
+    let path = file_path(path_str)?;
+    let af = with_agent(handle, |agent| Ok(AgentFile::export(&agent.config, &agent.state, vec![])?))?;
+    let bytes = AgentFile::export_encrypted(&af, passphrase)?;
+    replace_file(&path, |mut file| Ok(file.write_all(&bytes)?))
+}
+
+/// Write `path` through a `.partial` file beside it, renamed into place once
+/// `write` succeeds
+fn replace_file(path: &Path, write: impl FnOnce(File) -> letta_core::Result<()>) -> FfiResult<()> {
+    let mut partial = path.to_path_buf().into_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    
+    let written = File::create(&partial)
+        .map_err(|e| file_error("create", &partial, e))
+        .and_then(|file| {
+            write(file).map_err(|e| match e {
+                letta_core::LettaError::Io(e) => file_error("write", &partial, e),
+                e => e.into(),
+            })
+        })
+        .and_then(|()| fs::rename(&partial, path).map_err(|e| file_error("replace", path, e)));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Load an agent file from `path` into the agent. `mode` is a
+/// `LettaImportMode`; plain and gzipped files are both accepted. Returns
+/// `LETTA_ERROR_CODE_IO_ERROR` if the file can't be read,
+/// `LETTA_ERROR_CODE_INVALID_JSON` if it is corrupt,
+/// `LETTA_ERROR_CODE_INVALID_ARG` if it holds no usable agent, and
+/// `LETTA_ERROR_CODE_PASSPHRASE_REQUIRED` if it is encrypted, in which case
+/// ask for the passphrase and call `letta_import_af_file_encrypted`.
+#[no_mangle]
+pub extern "C" fn letta_import_af_file(handle: *mut AgentHandle, path: *const c_char, mode: i32) -> i32 {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        status(import_af_file(handle, &path_str, mode, None))
+    })
+}
+
+/// `letta_import_af_file` for files that may be encrypted, opened with
+/// `passphrase`; plain files are accepted too. Additionally returns
+/// `LETTA_ERROR_CODE_WRONG_PASSPHRASE` or, for a damaged or altered file,
+/// `LETTA_ERROR_CODE_TAMPERED`.
+#[no_mangle]
+pub extern "C" fn letta_import_af_file_encrypted(
+    handle: *mut AgentHandle,
+    path: *const c_char,
+    mode: i32,
+    passphrase: *const c_char,
+) -> i32 {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        let passphrase = c_str_arg!(passphrase, "passphrase", set_last_error);
+        status(import_af_file(handle, &path_str, mode, Some(&passphrase)))
+    })
+}
+
+fn import_af_file(handle: *mut AgentHandle, path_str: &str, mode: i32, passphrase: Option<&str>) -> FfiResult<()> {
+    let path = file_path(path_str)?;
+    let mode = match mode {
+        m if m == LettaImportMode::Replace as i32 => LettaImportMode::Replace,
+        m if m == LettaImportMode::Merge as i32 => LettaImportMode::Merge,
+        other => return Err(FfiError::invalid_arg(format!("Unknown import mode {}", other))),
+    };
+    
+    let file = File::open(&path).map_err(|e| file_error("open", &path, e))?;
+    let af = match passphrase {
+        Some(passphrase) => AgentFile::from_reader_with_passphrase(file, passphrase)?,
+        None => AgentFile::from_reader(file)?,
+    };
+    let (_config, state) = AgentFile::import(&af)?;
+    
+    with_agent(handle, |agent| {
+        match mode {
+            LettaImportMode::Replace => agent.state = state,
+            LettaImportMode::Merge => {
+                for (label, block) in state.memory.blocks() {
+                    agent.state.memory.blocks_mut().insert(label.clone(), block.clone());
+                }
+                for message in state.messages.messages {
+                    if !agent.state.messages.messages.iter().any(|m| m.id == message.id) {
+                        agent.state.messages.push(message);
+                    }
+                }
+                agent.state.updated_at = chrono::Utc::now();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Write every stored agent into the directory `dir`, one gzipped agent
+/// file per agent named after its id, creating the directory if needed.
+/// Existing files are kept and a new name is picked beside them. Returns
+/// `{"written": [paths], "skipped": [messages]}`, where `skipped` names the
+/// agents whose stored data is corrupted. Requires `letta_init_storage`;
+/// free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_export_all(dir: *const c_char) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let dir_str = c_str_arg!(dir, "dir", null_on_error);
+        pointer(export_all(&dir_str).map(|report| string_to_c_str(report.to_string())))
+    })
+}
+
+fn export_all(dir_str: &str) -> FfiResult<serde_json::Value> {
+    let dir = file_path(dir_str)?;
+    let report = export_all_to_af(&storage()?, &dir, ExportOptions::default()).map_err(|e| match e {
+        letta_core::LettaError::Io(e) => file_error("export to", &dir, e),
+        e => e.into(),
+    })?;
+    Ok(serde_json::to_value(report)?)
+}
+
+/// Host paths arrive as UTF-8; `Path` converts them to the platform's native
+/// encoding, including UTF-16 on Windows
+fn file_path(path_str: &str) -> FfiResult<PathBuf> {
+    if path_str.is_empty() {
+        return Err(FfiError::invalid_arg("Empty file path"));
+    }
+    Ok(PathBuf::from(path_str))
+}
+
+fn file_error(action: &str, path: &Path, e: io::Error) -> FfiError {
+    FfiError::new(LettaErrorCode::IoError, format!("Cannot {} '{}': {}", action, path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use crate::{letta_create_agent, letta_free_agent, letta_get_block, letta_set_block, letta_free_str};
+    
+    fn block(handle: *mut AgentHandle, label: &str) -> String {
+        let label = CString::new(label).unwrap();
+        let value = letta_get_block(handle, label.as_ptr());
+        assert!(!value.is_null());
+        let text = unsafe { std::ffi::CStr::from_ptr(value) }.to_str().unwrap().to_string();
+        letta_free_str(value);
+        text
+    }
+    
+    fn set_block(handle: *mut AgentHandle, label: &str, value: &str) {
+        let label = CString::new(label).unwrap();
+        let value = CString::new(value).unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+    }
+    
+    #[test]
+    fn test_ffi_af_file_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = CString::new(r#"{"name": "exporter"}"#).unwrap();
+        let source = letta_create_agent(config.as_ptr());
+        set_block(source, "human", "Likes hiking");
+        
+        for (name, compression) in [("plain.af", LettaAfCompression::None), ("packed.af.gz", LettaAfCompression::Gzip)] {
+            let path = CString::new(dir.path().join(name).to_str().unwrap()).unwrap();
+            assert_eq!(letta_export_af_file(source, path.as_ptr(), compression as i32), 0);
+            
+            let target = letta_create_agent(config.as_ptr());
+            set_block(target, "goals", "Learn Rust");
+            assert_eq!(letta_import_af_file(target, path.as_ptr(), LettaImportMode::Merge as i32), 0);
+            assert_eq!(block(target, "human"), "Likes hiking");
+            assert_eq!(block(target, "goals"), "Learn Rust");
+            
+            assert_eq!(letta_import_af_file(target, path.as_ptr(), LettaImportMode::Replace as i32), 0);
+            assert_eq!(block(target, "human"), "Likes hiking");
+            let goals = CString::new("goals").unwrap();
+            assert!(letta_get_block(target, goals.as_ptr()).is_null());
+            letta_free_agent(target);
+        }
+        assert!(!dir.path().join("plain.af.partial").exists());
+        
+        let missing = CString::new(dir.path().join("missing.af").to_str().unwrap()).unwrap();
+        assert_eq!(letta_import_af_file(source, missing.as_ptr(), 0), LettaErrorCode::IoError as i32);
+        
+        let corrupt_path = dir.path().join("corrupt.af.gz");
+        fs::write(&corrupt_path, [0x1f, 0x8b, 0x00, 0x01]).unwrap();
+        let corrupt = CString::new(corrupt_path.to_str().unwrap()).unwrap();
+        assert_eq!(letta_import_af_file(source, corrupt.as_ptr(), 0), LettaErrorCode::InvalidJson as i32);
+        
+        let empty_path = dir.path().join("empty.af");
+        let mut empty: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("plain.af")).unwrap()).unwrap();
+        empty["agents"] = serde_json::json!([]);
+        fs::write(&empty_path, empty.to_string()).unwrap();
+        let empty = CString::new(empty_path.to_str().unwrap()).unwrap();
+        assert_eq!(letta_import_af_file(source, empty.as_ptr(), 0), LettaErrorCode::InvalidArg as i32);
+        
+        let unwritable = CString::new(dir.path().join("no-such-dir").join("out.af").to_str().unwrap()).unwrap();
+        assert_eq!(letta_export_af_file(source, unwritable.as_ptr(), 0), LettaErrorCode::IoError as i32);
+        let plain = CString::new(dir.path().join("plain.af").to_str().unwrap()).unwrap();
+        assert_eq!(letta_export_af_file(source, plain.as_ptr(), 7), LettaErrorCode::InvalidArg as i32);
+        assert_eq!(letta_import_af_file(source, plain.as_ptr(), 7), LettaErrorCode::InvalidArg as i32);
+        
+        letta_free_agent(source);
+    }
+    
+    #[test]
+    fn test_ffi_encrypted_af_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = CString::new(r#"{"name": "keeper"}"#).unwrap();
+        let source = letta_create_agent(config.as_ptr());
+        set_block(source, "human", "Keeps secrets");
+        
+        let path_buf = dir.path().join("secret.af");
+        let path = CString::new(path_buf.to_str().unwrap()).unwrap();
+        let passphrase = CString::new("correct horse").unwrap();
+        let wrong = CString::new("battery staple").unwrap();
+        let empty = CString::new("").unwrap();
+        assert_eq!(letta_export_af_file_encrypted(source, path.as_ptr(), empty.as_ptr()), LettaErrorCode::InvalidArg as i32);
+        assert_eq!(letta_export_af_file_encrypted(source, path.as_ptr(), passphrase.as_ptr()), 0);
+        assert!(AgentFile::is_encrypted(&fs::read(&path_buf).unwrap()));
+        
+        let target = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_import_af_file(target, path.as_ptr(), 0), LettaErrorCode::PassphraseRequired as i32);
+        assert_eq!(letta_import_af_file_encrypted(target, path.as_ptr(), 0, wrong.as_ptr()), LettaErrorCode::WrongPassphrase as i32);
+        assert_eq!(letta_import_af_file_encrypted(target, path.as_ptr(), 0, passphrase.as_ptr()), 0);
+        assert_eq!(block(target, "human"), "Keeps secrets");
+        
+        let mut bytes = fs::read(&path_buf).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&path_buf, bytes).unwrap();
+        assert_eq!(letta_import_af_file_encrypted(target, path.as_ptr(), 0, passphrase.as_ptr()), LettaErrorCode::Tampered as i32);
+        
+        // Plain files open with or without a passphrase
+        let plain = CString::new(dir.path().join("plain.af").to_str().unwrap()).unwrap();
+        assert_eq!(letta_export_af_file(source, plain.as_ptr(), LettaAfCompression::Gzip as i32), 0);
+        assert_eq!(letta_import_af_file_encrypted(target, plain.as_ptr(), 0, passphrase.as_ptr()), 0);
+        
+        letta_free_agent(target);
+        letta_free_agent(source);
+    }
+}
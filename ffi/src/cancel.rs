@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use letta_core::CancellationToken;
+
+use crate::error::{catch_panic, set_last_error, status};
+use crate::{lock, shared_agent, AgentHandle};
+
+lazy_static! {
+    /// Token of the step running on each agent
+    static ref RUNNING_STEPS: Mutex<HashMap<StepKey, CancellationToken>> = Mutex::new(HashMap::new());
+}
+
+/// The index and generation of a handle that `shared_agent` accepted, for
+/// registering its steps from another thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct StepKey(usize, u32);
+
+impl StepKey {
+    pub fn of(handle: *mut AgentHandle) -> Self {
+        let AgentHandle { index, generation, .. } = unsafe { *handle };
+        Self(index, generation)
+    }
+}
+
+/// The cancellation token of a running step, which `letta_cancel` can reach
+/// until this is dropped. Start it with the agent locked, so only one step
+/// per agent is registered at a time.
+pub(crate) struct RunningStep {
+    key: StepKey,
+    pub token: CancellationToken,
+}
+
+impl RunningStep {
+    pub fn start(key: StepKey) -> Self {
+        let token = CancellationToken::new();
+        lock(&RUNNING_STEPS).insert(key, token.clone());
+        Self { key, token }
+    }
+}
+
+impl Drop for RunningStep {
+    fn drop(&mut self) {
+        lock(&RUNNING_STEPS).remove(&self.key);
+    }
+}
+
+/// Cancel the step running on the agent, from any thread. The call running
+/// it (`letta_converse`, `letta_reply_only`, `letta_regenerate`,
+/// `letta_converse_async` or `letta_converse_stream`) fails with
+/// `LETTA_ERROR_CODE_CANCELLED` as soon as the provider call in progress is
+/// dropped. The user message stays in the history, but none of the step's
+/// assistant or tool messages do. Does nothing if no step is running.
+#[no_mangle]
+pub extern "C" fn letta_cancel(handle: *mut AgentHandle) -> i32 {
+    catch_panic(set_last_error, || {
+        status(shared_agent(handle).map(|_| {
+            if let Some(token) = lock(&RUNNING_STEPS).get(&StepKey::of(handle)) {
+                token.cancel();
+            }
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::time::{Duration, Instant};
+    use letta_core::{Agent, AgentConfig, MessageRole};
+    use crate::error::LettaErrorCode;
+    use crate::test_support::Stalled;
+    use crate::{letta_converse, letta_free_agent, letta_free_str, register_agent};
+
+    #[test]
+    fn test_ffi_cancel_running_step() {
+        let handle = register_agent(Agent::new(AgentConfig::default(), Box::new(Stalled))).unwrap();
+        assert_eq!(letta_cancel(handle), 0);
+
+        let address = handle as usize;
+        let converse = std::thread::spawn(move || {
+            let msg = CString::new(r#"{"text": "Write me an essay"}"#).unwrap();
+            let response = letta_converse(address as *mut AgentHandle, msg.as_ptr());
+            let json: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(response) }.to_str().unwrap()).unwrap();
+            letta_free_str(response);
+            json
+        });
+        let key = StepKey::of(handle);
+        let started = Instant::now();
+        while !lock(&RUNNING_STEPS).contains_key(&key) {
+            assert!(started.elapsed() < Duration::from_secs(10), "step never started");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(letta_cancel(handle), 0);
+
+        let response = converse.join().unwrap();
+        assert_eq!(response["code"], LettaErrorCode::Cancelled as i32);
+        assert_eq!(response["error_code"], "cancelled");
+        let agent = shared_agent(handle).unwrap();
+        let messages = &agent.blocking_lock().state.messages.messages;
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0].role, MessageRole::User));
+        assert!(!lock(&RUNNING_STEPS).contains_key(&key));
+
+        letta_free_agent(handle);
+        assert_eq!(letta_cancel(std::ptr::null_mut()), LettaErrorCode::InvalidArg as i32);
+    }
+}
@@ -0,0 +1,244 @@
+use std::cell::RefCell;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use letta_core::LettaError;
+use serde_json::json;
+use letta_storage::StorageError;
+use letta_sync::SyncError;
+
+/// Status codes returned by the int-returning functions. Failures are
+/// negative; details of the most recent one on the calling thread are
+/// available from `letta_last_error_message`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LettaErrorCode {
+    Ok = 0,
+    InvalidArg = -1,
+    InvalidJson = -2,
+    AgentNotFound = -3,
+    ProviderError = -4,
+    StorageError = -5,
+    SyncError = -6,
+    Busy = -7,
+    Panic = -8,
+    /// Storage or sync was used before being initialised
+    NotInitialized = -9,
+    /// The request was cancelled before it completed
+    Cancelled = -10,
+    /// The change cannot be applied to a live agent, e.g. switching its provider
+    Unsupported = -11,
+    /// The sync server could not be reached
+    NetworkError = -12,
+    /// Sync finished with conflicts left for the host to resolve
+    SyncConflict = -13,
+    /// A file could not be opened, read or written
+    IoError = -14,
+    /// The agent file holds several agents; list them and import one by one
+    MultipleAgents = -15,
+    /// The library was shut down; handles from before are no longer valid
+    ShutDown = -16,
+    /// The agent file is encrypted; open it with the `_encrypted` variant
+    PassphraseRequired = -17,
+    /// The passphrase does not open this encrypted agent file
+    WrongPassphrase = -18,
+    /// The encrypted agent file is damaged or was altered
+    Tampered = -19,
+    /// The provider did not answer within its configured `timeout_ms`
+    Timeout = -20,
+    /// A tool the agent called failed
+    ToolError = -21,
+    /// A memory block is missing or its template could not be rendered
+    MemoryError = -22,
+    /// The prompt does not fit the model's context window
+    ContextOverflow = -23,
+    /// An internal failure with no more specific code
+    Internal = -24,
+}
+
+/// A failure to report across the boundary: a code plus a message, and the
+/// `LettaError::to_json` form when it came from the core
+#[derive(Debug)]
+pub(crate) struct FfiError {
+    pub code: LettaErrorCode,
+    pub message: String,
+    pub detail: Option<serde_json::Value>,
+}
+
+impl FfiError {
+    pub fn new(code: LettaErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), detail: None }
+    }
+
+    /// `{"error": message, "code": status}`, plus `error_code`, `retryable`
+    /// and `details` from the core error when there is one, and the `kind`
+    /// of a provider error
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = json!({ "error": self.message, "code": self.code as i32 });
+        if let Some(detail) = &self.detail {
+            value["error_code"] = detail["code"].clone();
+            value["retryable"] = detail["retryable"].clone();
+            if let Some(details) = detail.get("details") {
+                value["details"] = details.clone();
+            }
+            if let Some(kind) = detail.pointer("/details/kind") {
+                value["kind"] = kind.clone();
+            }
+        }
+        value
+    }
+
+    pub fn invalid_arg(message: impl Into<String>) -> Self {
+        Self::new(LettaErrorCode::InvalidArg, message)
+    }
+
+    pub fn not_initialized(what: &str) -> Self {
+        Self::new(LettaErrorCode::NotInitialized, format!("{} not initialized", what))
+    }
+}
+
+impl From<serde_json::Error> for FfiError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(LettaErrorCode::InvalidJson, e.to_string())
+    }
+}
+
+impl From<StorageError> for FfiError {
+    fn from(e: StorageError) -> Self {
+        Self::new(LettaErrorCode::StorageError, e.to_string())
+    }
+}
+
+impl From<SyncError> for FfiError {
+    fn from(e: SyncError) -> Self {
+        Self::new(sync_error_code(&e), e.to_string())
+    }
+}
+
+/// Failed retries report the code of the last failure
+fn sync_error_code(e: &SyncError) -> LettaErrorCode {
+    match e {
+        SyncError::Http(_) | SyncError::Proxy { .. } => LettaErrorCode::NetworkError,
+        SyncError::Storage(_) => LettaErrorCode::StorageError,
+        SyncError::RetriesExhausted { source, .. } => sync_error_code(source),
+        _ => LettaErrorCode::SyncError,
+    }
+}
+
+impl From<LettaError> for FfiError {
+    fn from(e: LettaError) -> Self {
+        let code = match &e {
+            LettaError::Provider(_) => LettaErrorCode::ProviderError,
+            LettaError::Serialization(_) => LettaErrorCode::InvalidJson,
+            LettaError::Storage(_) | LettaError::Io(_) => LettaErrorCode::StorageError,
+            LettaError::ToolExecution(_) => LettaErrorCode::ToolError,
+            LettaError::Memory(_) => LettaErrorCode::MemoryError,
+            LettaError::MemoryLimitExceeded { .. } => LettaErrorCode::InvalidArg,
+            LettaError::ContextOverflow { .. } => LettaErrorCode::ContextOverflow,
+            LettaError::AgentNotFound(_) => LettaErrorCode::AgentNotFound,
+            LettaError::Sync(_) => LettaErrorCode::SyncError,
+            LettaError::InvalidConfig(_) => LettaErrorCode::InvalidArg,
+            LettaError::PassphraseRequired => LettaErrorCode::PassphraseRequired,
+            LettaError::WrongPassphrase => LettaErrorCode::WrongPassphrase,
+            LettaError::Tampered => LettaErrorCode::Tampered,
+            LettaError::Timeout { .. } => LettaErrorCode::Timeout,
+            LettaError::Cancelled => LettaErrorCode::Cancelled,
+            LettaError::Unknown(_) => LettaErrorCode::Internal,
+        };
+        Self { detail: Some(e.to_json()), ..Self::new(code, e.to_string()) }
+    }
+}
+
+pub(crate) type FfiResult<T> = Result<T, FfiError>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<FfiError>> = const { RefCell::new(None) };
+}
+
+/// Record `error` as this thread's last error and return its code
+pub(crate) fn set_last_error(error: FfiError) -> i32 {
+    let code = error.code as i32;
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+    code
+}
+
+pub(crate) fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Convert the outcome of an int-returning call into its status code
+pub(crate) fn status(result: FfiResult<()>) -> i32 {
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            LettaErrorCode::Ok as i32
+        }
+        Err(e) => set_last_error(e),
+    }
+}
+
+/// Convert the outcome of a pointer-returning call; failures yield null
+pub(crate) fn pointer<T>(result: FfiResult<*mut T>) -> *mut T {
+    match result {
+        Ok(p) => {
+            clear_last_error();
+            p
+        }
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Run the body of an exported function, reporting a panic through
+/// `on_panic` as a `Panic` error rather than unwinding into the host
+pub(crate) fn catch_panic<T>(on_panic: impl FnOnce(FfiError) -> T, body: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            on_panic(FfiError::new(LettaErrorCode::Panic, format!("Panicked: {}", message)))
+        }
+    }
+}
+
+/// Code of the last failure on the calling thread, or 0 if the last call succeeded
+#[no_mangle]
+pub extern "C" fn letta_last_error_code() -> i32 {
+    catch_panic(set_last_error, || {
+        LAST_ERROR.with(|last| last.borrow().as_ref().map_or(0, |e| e.code as i32))
+    })
+}
+
+/// The last failure on the calling thread as JSON, or null if the last call
+/// succeeded: `{"error": message, "code": status}`, plus for agent errors a
+/// stable `error_code` such as `"context.overflow"`, `retryable`, and
+/// `details` like `{"current": ..., "max": ...}`. Provider errors also carry
+/// their `kind`: `"auth"`, `"rate_limit"`, `"context_length"`, `"timeout"`,
+/// `"transport"`, `"invalid_response"` or `"other"`, with any HTTP `status`
+/// in `details`. Free with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_last_error() -> *mut c_char {
+    catch_panic(crate::null_on_error, || {
+        LAST_ERROR.with(|last| match last.borrow().as_ref() {
+            Some(e) => crate::string_to_c_str(e.to_json().to_string()),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Message of the last failure on the calling thread, or null if the last call
+/// succeeded. Free with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_last_error_message() -> *mut c_char {
+    catch_panic(crate::null_on_error, || {
+        LAST_ERROR.with(|last| match last.borrow().as_ref() {
+            Some(e) => crate::string_to_c_str(e.message.clone()),
+            None => ptr::null_mut(),
+        })
+    })
+}
@@ -1,42 +1,142 @@
+// Exported functions take raw pointers from the host and validate them before use.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::ptr;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Once, PoisonError};
+use std::time::Duration;
 use lazy_static::lazy_static;
 use serde_json::json;
 
 use letta_core::{
-    Agent, AgentConfig, AgentState,
-    provider::{ProviderFactory, ProviderConfig, ToyConfig},
+    Agent, AgentConfig, AppConfig,
+    agent::StepResult,
+    LlmProvider,
+    provider::{ProviderFactory, ProviderConfig, ProviderEnv},
     tool::ToolSchema,
     af::AgentFile,
+    telemetry::{self, InMemoryMetrics},
+    on_shutdown, ShutdownStage,
 };
-use letta_storage::{Storage, StorageConfig};
-use letta_sync::{SyncClient, SyncConfig};
+use letta_storage::{AgentSyncSettings, MaintenanceOptions, MessageQuery, PageCursor, Storage, StorageConfig, SyncStatus};
+use letta_sync::{ConflictResolution, SyncClient, SyncConfig, SyncManager};
+
+mod af_file;
+mod cancel;
+mod error;
+mod log;
+mod registry;
+mod requests;
+mod stream;
+#[cfg(test)]
+mod test_support;
+
+pub use af_file::{LettaAfCompression, LettaImportMode};
+pub use error::LettaErrorCode;
+pub use log::LettaLogLevel;
+use error::{catch_panic, clear_last_error, pointer, set_last_error, status, FfiError, FfiResult};
+use cancel::{RunningStep, StepKey};
+use registry::Registry;
 
 // Global runtime for async operations
 lazy_static! {
     static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Runtime::new().unwrap();
-    static ref AGENTS: Mutex<Vec<Option<Box<Agent>>>> = Mutex::new(Vec::new());
+    static ref AGENTS: Mutex<Registry<SharedAgent>> = Mutex::new(Registry::new());
     static ref STORAGE: Mutex<Option<Storage>> = Mutex::new(None);
-    static ref SYNC_CLIENT: Mutex<Option<SyncClient>> = Mutex::new(None);
+    static ref SYNC_CLIENT: Mutex<Option<Arc<SyncClient>>> = Mutex::new(None);
+    static ref SYNC_CALLBACK_TASK: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+    /// Config file loaded by `letta_init_from_config`, whose agent and
+    /// provider settings fill in what `letta_create_agent` leaves out
+    static ref APP_CONFIG: Mutex<Option<AppConfig>> = Mutex::new(None);
+    /// Step, provider and tool metrics of every agent, installed as the
+    /// recorder when the first agent is registered
+    static ref METRICS: Arc<InMemoryMetrics> = {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        telemetry::set_metrics(metrics.clone());
+        metrics
+    };
+}
+
+/// Bumped by `letta_shutdown`; handles from an earlier epoch are stale
+static EPOCH: AtomicU32 = AtomicU32::new(0);
+
+/// Set by `letta_shutdown` until the next `letta_init_storage`
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Report of the last `letta_create_agent_from_af` on this thread
+    static LAST_IMPORT_REPORT: RefCell<Option<serde_json::Value>> = const { RefCell::new(None) };
+}
+
+/// A live agent. Streaming holds its lock for the whole step, during which
+/// other calls on the agent fail with `Busy`.
+type SharedAgent = Arc<tokio::sync::Mutex<Agent>>;
+
+/// Receives each sync event as a JSON string, valid only for the duration of the call
+pub type LettaSyncCallback = extern "C" fn(event_json: *const c_char, user_data: *mut c_void);
+
+/// Callback plus host context, moved onto the runtime's forwarding task
+struct SyncCallback {
+    callback: LettaSyncCallback,
+    user_data: *mut c_void,
 }
 
-/// Agent handle for FFI
+// The host is responsible for `user_data` being usable from the runtime thread
+unsafe impl Send for SyncCallback {}
+
+/// Agent handle for FFI. The generation detects handles whose agent was
+/// freed after the slot was reused by another agent; the epoch detects
+/// handles from before `letta_shutdown`.
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct AgentHandle {
     index: usize,
+    generation: u32,
+    epoch: u32,
 }
 
-/// Convert C string to Rust String
-unsafe fn c_str_to_string(ptr: *const c_char) -> String {
+/// Convert C string to Rust String. Null is treated as empty; invalid UTF-8
+/// is rejected rather than replaced, so host data is never silently altered.
+unsafe fn c_str_to_string(ptr: *const c_char, name: &str) -> FfiResult<String> {
     if ptr.is_null() {
-        String::new()
-    } else {
-        CStr::from_ptr(ptr).to_string_lossy().into_owned()
+        return Ok(String::new());
+    }
+    
+    match CStr::from_ptr(ptr).to_str() {
+        Ok(s) => Ok(s.to_owned()),
+        Err(e) => Err(FfiError::invalid_arg(format!(
+            "Invalid UTF-8 in `{}` at byte offset {}", name, e.valid_up_to()
+        ))),
     }
 }
 
+/// Convert a C string argument, returning `$on_error(error)` from the calling
+/// function if it is not valid UTF-8
+macro_rules! c_str_arg {
+    ($ptr:expr, $name:literal, $on_error:expr) => {
+        match unsafe { $crate::c_str_to_string($ptr, $name) } {
+            Ok(s) => s,
+            Err(e) => return ($on_error)(e),
+        }
+    };
+}
+pub(crate) use c_str_arg;
+
+/// Lock a global, recovering it if a panic poisoned it. Globals are only
+/// ever replaced or updated in a single statement, so they stay consistent.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Record `error` and return null, for pointer-returning functions
+fn null_on_error<T>(error: FfiError) -> *mut T {
+    set_last_error(error);
+    ptr::null_mut()
+}
+
 /// Convert Rust String to C string
 fn string_to_c_str(s: String) -> *mut c_char {
     match CString::new(s) {
@@ -45,455 +145,1377 @@ fn string_to_c_str(s: String) -> *mut c_char {
     }
 }
 
-/// Initialize the storage system
+/// Look up the agent behind `handle`
+fn shared_agent(handle: *mut AgentHandle) -> FfiResult<SharedAgent> {
+    if handle.is_null() {
+        return Err(FfiError::invalid_arg("Null agent handle"));
+    }
+    
+    let AgentHandle { index, generation, epoch } = unsafe { *handle };
+    if epoch != EPOCH.load(Ordering::SeqCst) {
+        return Err(shut_down());
+    }
+    lock(&AGENTS)
+        .get_mut(index, generation)
+        .cloned()
+        .ok_or_else(|| FfiError::new(LettaErrorCode::AgentNotFound, "Invalid agent handle"))
+}
+
+fn shut_down() -> FfiError {
+    FfiError::new(LettaErrorCode::ShutDown, "Library was shut down")
+}
+
+/// Fail with `ShutDown` between `letta_shutdown` and the next `letta_init_storage`
+fn ensure_running() -> FfiResult<()> {
+    if SHUT_DOWN.load(Ordering::SeqCst) {
+        return Err(shut_down());
+    }
+    Ok(())
+}
+
+fn busy() -> FfiError {
+    FfiError::new(LettaErrorCode::Busy, "Agent is busy with another request")
+}
+
+/// Run `f` against the agent behind `handle`
+fn with_agent<T>(handle: *mut AgentHandle, f: impl FnOnce(&mut Agent) -> FfiResult<T>) -> FfiResult<T> {
+    let agent = shared_agent(handle)?;
+    let mut agent = agent.try_lock().map_err(|_| busy())?;
+    f(&mut agent)
+}
+
+/// Text of a `{"text": ...}` user message
+fn message_text(user_msg_json: &str) -> FfiResult<String> {
+    let msg_value: serde_json::Value = serde_json::from_str(user_msg_json)?;
+    Ok(msg_value.get("text")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string())
+}
+
+/// The JSON shape `letta_converse` returns for a completed step, with
+/// `warnings` only if there are any
+fn step_json(step_result: &StepResult) -> serde_json::Value {
+    let mut json = json!({
+        "text": step_result.text,
+        "tool_trace": step_result.tool_trace,
+        "usage": step_result.usage,
+        "stop_reason": step_result.stop_reason,
+    });
+    if !step_result.warnings.is_empty() {
+        json["warnings"] = json!(step_result.warnings);
+    }
+    json
+}
+
+fn storage() -> FfiResult<Storage> {
+    ensure_running()?;
+    lock(&STORAGE).clone().ok_or_else(|| FfiError::not_initialized("Storage"))
+}
+
+fn sync_client() -> FfiResult<Arc<SyncClient>> {
+    ensure_running()?;
+    lock(&SYNC_CLIENT).clone().ok_or_else(|| FfiError::not_initialized("Sync"))
+}
+
+/// JSON `{"error": ..., "code": ...}` (see `FfiError::to_json`) for calls
+/// whose result is always a JSON string
+fn error_json(error: FfiError) -> *mut c_char {
+    let json = error.to_json();
+    set_last_error(error);
+    string_to_c_str(json.to_string())
+}
+
+/// Initialize the storage system. `path` is either the database path or a
+/// JSON config such as `{"path": "letta.db", "encryption_key": "..."}`;
+/// omitted fields take their defaults. An encryption key requires a build
+/// with the `sqlcipher` feature.
 #[no_mangle]
 pub extern "C" fn letta_init_storage(path: *const c_char) -> i32 {
-    let path_str = unsafe { c_str_to_string(path) };
-    
-    let config = if path_str.is_empty() {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        status(init_storage(&path_str))
+    })
+}
+
+fn init_storage(path_or_config: &str) -> FfiResult<()> {
+    let config = if path_or_config.trim_start().starts_with('{') {
+        serde_json::from_str(path_or_config)?
+    } else if path_or_config.is_empty() {
         StorageConfig::default()
     } else {
         StorageConfig {
-            path: path_str.into(),
-            max_connections: 5,
+            path: path_or_config.into(),
+            ..Default::default()
         }
     };
     
-    match Storage::new(config) {
-        Ok(storage) => {
-            *STORAGE.lock().unwrap() = Some(storage);
-            0
+    let storage = Storage::new(config)?;
+    *lock(&STORAGE) = Some(storage);
+    SHUT_DOWN.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Initialize from a `.toml` or `.json` config file (see
+/// `letta_core::config`): opens its `[storage]` database and configures
+/// `[sync]` when those sections are present, and keeps its `[agent]`,
+/// `[provider]` and `[tools]` settings as defaults for `letta_create_agent`.
+/// Environment variables are interpolated and `LETTA_<SECTION>__<KEY>`
+/// variables override keys. Errors name the file and the offending key.
+#[no_mangle]
+pub extern "C" fn letta_init_from_config(path: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let path_str = c_str_arg!(path, "path", set_last_error);
+        status(init_from_config(&path_str))
+    })
+}
+
+fn init_from_config(path: &str) -> FfiResult<()> {
+    let config = AppConfig::load(path)?;
+    let sync_config = SyncConfig::from_app_config(&config)?;
+    
+    if let Some(storage_config) = &config.storage {
+        *lock(&STORAGE) = Some(Storage::new(storage_config.clone())?);
+    }
+    SHUT_DOWN.store(false, Ordering::SeqCst);
+    if let Some(sync_config) = sync_config {
+        install_sync_client(sync_config)?;
+    }
+    *lock(&APP_CONFIG) = Some(config);
+    Ok(())
+}
+
+/// Release everything the library holds: in-flight requests are cancelled,
+/// the sync callback is unregistered, and the sync client, storage and all
+/// agents are dropped. With `flush`, every agent is first saved to storage
+/// (if initialised); agents given `letta_set_flush_on_drop` are saved either
+/// way. If saving fails nothing is shut down.
+///
+/// This runs `letta_core::shutdown_all`, so background work registered with
+/// the core, such as auto-sync, stops as well.
+///
+/// Afterwards existing handles fail with `LETTA_ERROR_CODE_SHUT_DOWN` and
+/// must only be passed to `letta_free_agent`. Other calls fail the same way
+/// until `letta_init_storage` or `letta_init_from_config` starts the library
+/// afresh, with any path.
+#[no_mangle]
+pub extern "C" fn letta_shutdown(flush: bool) -> i32 {
+    catch_panic(set_last_error, || status(shutdown(flush)))
+}
+
+fn shutdown(flush: bool) -> FfiResult<()> {
+    if flush {
+        if let Some(storage) = lock(&STORAGE).clone() {
+            // Waits for any agent that is mid-step
+            let agents: Vec<SharedAgent> = lock(&AGENTS).values().cloned().collect();
+            for agent in &agents {
+                agent.blocking_lock().save(&storage)?;
+            }
         }
-        Err(_) => -1,
     }
+    
+    static HOOKS: Once = Once::new();
+    HOOKS.call_once(install_shutdown_hooks);
+    letta_core::shutdown_all()?;
+    Ok(())
+}
+
+/// The library's part of `letta_core::shutdown_all`, kept for the life of
+/// the process so that every shutdown runs it
+fn install_shutdown_hooks() {
+    on_shutdown(ShutdownStage::SaveAgents, "letta-ffi agents", || {
+        let agents: Vec<SharedAgent> = lock(&AGENTS).values().cloned().collect();
+        for agent in &agents {
+            agent.blocking_lock().flush()?;
+        }
+        Ok(())
+    }).forget();
+    
+    on_shutdown(ShutdownStage::StopTasks, "letta-ffi requests", || {
+        SHUT_DOWN.store(true, Ordering::SeqCst);
+        EPOCH.fetch_add(1, Ordering::SeqCst);
+        
+        requests::cancel_all_requests();
+        if let Some(task) = lock(&SYNC_CALLBACK_TASK).take() {
+            task.abort();
+        }
+        Ok(())
+    }).forget();
+    
+    on_shutdown(ShutdownStage::CloseStorage, "letta-ffi storage", || {
+        // Agents first: those saving on drop hold the storage open
+        lock(&AGENTS).remove_where(|_| true);
+        *lock(&SYNC_CLIENT) = None;
+        *lock(&STORAGE) = None;
+        *lock(&APP_CONFIG) = None;
+        Ok(())
+    }).forget();
 }
 
-/// Create a new agent
+/// Create a new agent. Without a `provider` object, the provider is picked
+/// from `model` (`gpt-4o-mini`, `claude-*`, `ollama:<model>`, `toy`, ...) with
+/// its API key from `OPENAI_API_KEY` or `ANTHROPIC_API_KEY`.
+///
+/// Once `letta_init_storage` has run, the agent is saved after every step.
+/// A failed save is listed in the step's `warnings`, or fails the step if
+/// the config sets `"strict_persistence": true`.
 #[no_mangle]
 pub extern "C" fn letta_create_agent(config_json: *const c_char) -> *mut AgentHandle {
-    let config_str = unsafe { c_str_to_string(config_json) };
+    catch_panic(null_on_error, || {
+        let config_str = c_str_arg!(config_json, "config_json", null_on_error);
+        pointer(create_agent(&config_str))
+    })
+}
+
+fn create_agent(config_str: &str) -> FfiResult<*mut AgentHandle> {
+    let config_value: serde_json::Value = serde_json::from_str(config_str)?;
     
-    let config_result: Result<serde_json::Value, _> = serde_json::from_str(&config_str);
-    if config_result.is_err() {
-        return ptr::null_mut();
-    }
+    // Fields left out come from the config file, if one was loaded
+    let app_config = lock(&APP_CONFIG).clone();
+    let defaults = match &app_config {
+        Some(app_config) => app_config.agent_config(),
+        None => AgentConfig { system_prompt: "You are a helpful AI assistant.".to_string(), ..Default::default() },
+    };
+    let provider = match (config_value.get("provider"), app_config.and_then(|c| c.provider)) {
+        (Some(provider), _) => Some(provider.clone()),
+        (None, Some(provider)) => Some(serde_json::to_value(provider)?),
+        (None, None) => None,
+    };
     
-    let config_value = config_result.unwrap();
+    // An explicit provider names the model unless one is given
+    let default_model = provider.as_ref()
+        .and_then(|p| p.get("model").or_else(|| p.get("type")))
+        .and_then(|v| v.as_str())
+        .unwrap_or(&defaults.model);
     
     // Parse agent configuration
     let agent_config = AgentConfig {
         name: config_value.get("name")
             .and_then(|v| v.as_str())
-            .unwrap_or("assistant")
+            .unwrap_or(&defaults.name)
             .to_string(),
         system_prompt: config_value.get("system_prompt")
             .and_then(|v| v.as_str())
-            .unwrap_or("You are a helpful AI assistant.")
+            .unwrap_or(&defaults.system_prompt)
             .to_string(),
         model: config_value.get("model")
             .and_then(|v| v.as_str())
-            .unwrap_or("toy")
+            .unwrap_or(default_model)
             .to_string(),
         max_messages: config_value.get("max_messages")
             .and_then(|v| v.as_u64())
-            .unwrap_or(100) as usize,
+            .map_or(defaults.max_messages, |n| n as usize),
         max_context_tokens: config_value.get("max_context_tokens")
             .and_then(|v| v.as_u64())
-            .unwrap_or(8192) as usize,
+            .map_or(defaults.max_context_tokens, |n| n as usize),
         temperature: config_value.get("temperature")
             .and_then(|v| v.as_f64())
-            .unwrap_or(0.7) as f32,
+            .map_or(defaults.temperature, |t| t as f32),
         tools_enabled: config_value.get("tools_enabled")
             .and_then(|v| v.as_bool())
-            .unwrap_or(true),
-    };
-    
-    // Create provider based on model
-    let provider_config = if agent_config.model == "toy" {
-        ProviderConfig::Toy(ToyConfig { deterministic: true })
-    } else {
-        // Default to toy for now
-        ProviderConfig::Toy(ToyConfig { deterministic: false })
+            .unwrap_or(defaults.tools_enabled),
+        disabled_tools: match config_value.get("disabled_tools") {
+            Some(tools) => serde_json::from_value(tools.clone())?,
+            None => defaults.disabled_tools.clone(),
+        },
+        tokenizer: match config_value.get("tokenizer") {
+            Some(tokenizer) => serde_json::from_value(tokenizer.clone())?,
+            None => defaults.tokenizer.clone(),
+        },
+        max_response_tokens: config_value.get("max_response_tokens")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize)
+            .or(defaults.max_response_tokens),
+        stop_sequences: match config_value.get("stop_sequences") {
+            Some(stop) => serde_json::from_value(stop.clone())?,
+            None => defaults.stop_sequences.clone(),
+        },
+        strict_persistence: config_value.get("strict_persistence")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(defaults.strict_persistence),
+        max_tool_iterations: config_value.get("max_tool_iterations")
+            .and_then(|v| v.as_u64())
+            .map_or(defaults.max_tool_iterations, |n| n as usize),
     };
     
-    // Create provider
-    let provider = RUNTIME.block_on(async {
-        ProviderFactory::create(provider_config).await
-    });
-    
-    if provider.is_err() {
-        return ptr::null_mut();
-    }
-    
     // Create agent
-    let agent = Agent::new(agent_config, provider.unwrap());
-    
-    // Store agent
-    let mut agents = AGENTS.lock().unwrap();
-    let index = agents.len();
-    agents.push(Some(Box::new(agent)));
-    
-    Box::into_raw(Box::new(AgentHandle { index }))
+    let provider = create_provider(&agent_config, provider)?;
+    register_agent(attach_storage(Agent::new(agent_config, provider)))
 }
 
-/// Free an agent
-#[no_mangle]
-pub extern "C" fn letta_free_agent(handle: *mut AgentHandle) {
-    if handle.is_null() {
-        return;
+/// Save the agent after each step, if storage is initialised
+fn attach_storage(mut agent: Agent) -> Agent {
+    if let Some(storage) = lock(&STORAGE).clone() {
+        agent.attach_storage(Arc::new(storage));
     }
+    agent
+}
+
+/// Build the provider from an explicit `provider` object in `ProviderConfig`'s
+/// type-tagged format, or else from the model name, with API keys from the
+/// environment
+fn create_provider(agent_config: &AgentConfig, provider: Option<serde_json::Value>) -> FfiResult<Box<dyn LlmProvider>> {
+    let provider_config = match provider {
+        Some(provider) => serde_json::from_value(provider)
+            .map_err(|e| FfiError::new(LettaErrorCode::InvalidJson, format!("Invalid provider config: {}", e)))?,
+        None => ProviderConfig::from_model(&agent_config.model, &ProviderEnv::from_env())?,
+    };
     
-    unsafe {
-        let handle = Box::from_raw(handle);
-        let mut agents = AGENTS.lock().unwrap();
-        if handle.index < agents.len() {
-            agents[handle.index] = None;
+    // Create provider, caching completions in storage if asked and it's open
+    let storage = lock(&STORAGE).clone();
+    Ok(RUNTIME.block_on(async {
+        match storage {
+            Some(storage) => ProviderFactory::create_with_storage(provider_config, &storage).await,
+            None => ProviderFactory::create(provider_config).await,
         }
-    }
+    })?)
 }
 
-/// Load agent from AF file
+/// Store a live agent and hand out a new handle to it
+fn register_agent(agent: Agent) -> FfiResult<*mut AgentHandle> {
+    ensure_running()?;
+    lazy_static::initialize(&METRICS);
+    let agent = Arc::new(tokio::sync::Mutex::new(agent));
+    let (index, generation) = lock(&AGENTS).insert(agent);
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    Ok(Box::into_raw(Box::new(AgentHandle { index, generation, epoch })))
+}
+
+/// Persist the agent's config, memory blocks and history. Requires
+/// `letta_init_storage`; reopen it later with `letta_open_agent`.
 #[no_mangle]
-pub extern "C" fn letta_load_af(handle: *mut AgentHandle, af_json: *const c_char) -> i32 {
-    if handle.is_null() {
-        return -1;
-    }
-    
-    let af_str = unsafe { c_str_to_string(af_json) };
-    
-    unsafe {
-        let handle = &*handle;
-        let mut agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return -1;
-        }
-        
-        // Parse AF
-        let af_result = AgentFile::from_json(&af_str);
-        if af_result.is_err() {
-            return -1;
-        }
-        
-        // Import state
-        let import_result = AgentFile::import(&af_result.unwrap());
-        if import_result.is_err() {
-            return -1;
-        }
-        
-        let (_config, state) = import_result.unwrap();
-        
-        // Update agent state
-        if let Some(agent) = &mut agents[handle.index] {
-            if agent.import_state(&serde_json::to_string(&state).unwrap()).is_err() {
-                return -1;
+pub extern "C" fn letta_save_agent(handle: *mut AgentHandle) -> i32 {
+    catch_panic(set_last_error, || {
+        status(storage().and_then(|storage| {
+            with_agent(handle, |agent| Ok(agent.save(&storage)?))
+        }))
+    })
+}
+
+/// Save the agent to storage whenever it is dropped with unsaved changes:
+/// by `letta_free_agent`, `letta_shutdown`, or the library unloading. The
+/// save may take up to `timeout_ms` milliseconds and is skipped with a
+/// logged warning if it doesn't finish in time. A timeout of 0 turns this
+/// off again. Requires `letta_init_storage`.
+#[no_mangle]
+pub extern "C" fn letta_set_flush_on_drop(handle: *mut AgentHandle, timeout_ms: u32) -> i32 {
+    catch_panic(set_last_error, || {
+        status(with_agent(handle, |agent| {
+            if timeout_ms == 0 {
+                agent.disarm_flush_on_drop();
+            } else {
+                agent.flush_on_drop(storage()?, Duration::from_millis(timeout_ms.into()));
             }
-        }
-    }
-    
-    0
+            Ok(())
+        }))
+    })
 }
 
-/// Export agent to AF format
+/// Load a saved agent into a new handle. `config_overrides_json` may be null,
+/// or a JSON object whose fields replace the stored config, e.g.
+/// `{"temperature": 0.2}`. Like a new agent, it is saved after every step.
+/// The provider is picked from the stored `model` as in `letta_create_agent`;
+/// provider settings are not stored, so pass a `provider` object here for
+/// anything the model name and environment don't give. Fails with
+/// `LETTA_ERROR_CODE_AGENT_NOT_FOUND` if no agent with that id was saved.
+/// Requires `letta_init_storage`.
 #[no_mangle]
-pub extern "C" fn letta_export_af(handle: *mut AgentHandle) -> *mut c_char {
-    if handle.is_null() {
-        return ptr::null_mut();
-    }
+pub extern "C" fn letta_open_agent(agent_id: *const c_char, config_overrides_json: *const c_char) -> *mut AgentHandle {
+    catch_panic(null_on_error, || {
+        let id = c_str_arg!(agent_id, "agent_id", null_on_error);
+        let overrides = c_str_arg!(config_overrides_json, "config_overrides_json", null_on_error);
+        pointer(open_agent(&id, &overrides))
+    })
+}
+
+fn open_agent(id: &str, overrides: &str) -> FfiResult<*mut AgentHandle> {
+    let (mut config, state) = letta_core::persist::load_agent(&storage()?, id)?;
     
-    unsafe {
-        let handle = &*handle;
-        let agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return ptr::null_mut();
-        }
-        
-        if let Some(agent) = &agents[handle.index] {
-            // Get tool schemas
-            let tool_schemas: Vec<ToolSchema> = vec![]; // TODO: Get from agent
-            
-            // Export to AF
-            let af_result = AgentFile::export(&agent.config, &agent.state, tool_schemas);
-            if af_result.is_err() {
-                return ptr::null_mut();
-            }
-            
-            // Convert to JSON
-            let json_result = AgentFile::to_json(&af_result.unwrap());
-            if json_result.is_err() {
-                return ptr::null_mut();
-            }
-            
-            return string_to_c_str(json_result.unwrap());
+    let mut provider = None;
+    if !overrides.is_empty() {
+        let mut overrides: serde_json::Map<String, serde_json::Value> = serde_json::from_str(overrides)?;
+        provider = overrides.remove("provider");
+        let mut merged = serde_json::to_value(&config)?;
+        for (key, value) in overrides {
+            merged[key] = value;
         }
+        config = serde_json::from_value(merged)?;
     }
     
-    ptr::null_mut()
+    let provider = create_provider(&config, provider)?;
+    let agent = Agent::new(config, provider).with_state(state);
+    register_agent(attach_storage(agent))
 }
 
-/// Set a memory block
+/// Fork the agent into a new handle whose history ends at `message_id`,
+/// leaving this one as it is. At a tool call or result the fork ends at the
+/// user message before it. The fork's metadata names its parent. With
+/// storage initialised it is saved as a new agent. Fails with
+/// `LETTA_ERROR_CODE_INVALID_ARG` if the message isn't in the agent's history.
 #[no_mangle]
-pub extern "C" fn letta_set_block(handle: *mut AgentHandle, label: *const c_char, value: *const c_char) -> i32 {
-    if handle.is_null() {
-        return -1;
-    }
-    
-    let label_str = unsafe { c_str_to_string(label) };
-    let value_str = unsafe { c_str_to_string(value) };
-    
-    unsafe {
-        let handle = &*handle;
-        let mut agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return -1;
+pub extern "C" fn letta_fork_agent(handle: *mut AgentHandle, message_id: *const c_char) -> *mut AgentHandle {
+    catch_panic(null_on_error, || {
+        let id = c_str_arg!(message_id, "message_id", null_on_error);
+        pointer(with_agent(handle, |agent| Ok(agent.fork_at(&id)?)).and_then(register_agent))
+    })
+}
+
+/// Free an agent
+#[no_mangle]
+pub extern "C" fn letta_free_agent(handle: *mut AgentHandle) {
+    catch_panic(|e| { set_last_error(e); }, || {
+        if handle.is_null() {
+            return;
         }
         
-        if let Some(agent) = &mut agents[handle.index] {
-            if agent.set_memory_block(&label_str, &value_str).is_err() {
-                return -1;
-            }
+        unsafe {
+            let handle = Box::from_raw(handle);
+            lock(&AGENTS).remove(handle.index, handle.generation);
         }
-    }
-    
-    0
+    })
 }
 
-/// Get a memory block
+/// Delete an agent. The handle is consumed and must not be used afterwards.
+/// With `delete_from_storage` its persisted rows and pending sync work are
+/// removed as well, which requires `letta_init_storage`.
 #[no_mangle]
-pub extern "C" fn letta_get_block(handle: *mut AgentHandle, label: *const c_char) -> *mut c_char {
-    if handle.is_null() {
-        return ptr::null_mut();
-    }
-    
-    let label_str = unsafe { c_str_to_string(label) };
-    
-    unsafe {
-        let handle = &*handle;
-        let agents = AGENTS.lock().unwrap();
+pub extern "C" fn letta_delete_agent(handle: *mut AgentHandle, delete_from_storage: bool) -> i32 {
+    catch_panic(set_last_error, || {
+        let agent_id = match with_agent(handle, |agent| Ok(agent.state.id.clone())) {
+            Ok(id) => id,
+            Err(e) => return set_last_error(e),
+        };
         
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return ptr::null_mut();
-        }
-        
-        if let Some(agent) = &agents[handle.index] {
-            if let Some(value) = agent.get_memory_block(&label_str) {
-                return string_to_c_str(value);
+        if delete_from_storage {
+            let deleted = storage().and_then(|storage| Ok(storage.delete_agent(&agent_id)?));
+            if let Err(e) = deleted {
+                // Leave the handle usable so the caller can retry
+                return set_last_error(e);
             }
+            // Saving on drop, or after a step, would bring it straight back
+            let _ = with_agent(handle, |agent| {
+                agent.detach_storage();
+                agent.disarm_flush_on_drop();
+                Ok(())
+            });
         }
-    }
-    
-    ptr::null_mut()
+        
+        letta_free_agent(handle);
+        status(Ok(()))
+    })
 }
 
-/// Add to archival memory
+/// Delete a persisted agent by id, including agents that are not loaded.
+/// Any live handle for it stops working. Requires `letta_init_storage`.
 #[no_mangle]
-pub extern "C" fn letta_append_archival(handle: *mut AgentHandle, folder: *const c_char, text: *const c_char) -> i32 {
-    if handle.is_null() {
-        return -1;
+pub extern "C" fn letta_delete_agent_by_id(agent_id: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let id = c_str_arg!(agent_id, "agent_id", set_last_error);
+        status(delete_agent_by_id(&id))
+    })
+}
+
+fn delete_agent_by_id(id: &str) -> FfiResult<()> {
+    let storage = storage()?;
+    if storage.get_agent(id)?.is_none() {
+        return Err(FfiError::new(LettaErrorCode::AgentNotFound, format!("Agent {} not found", id)));
     }
     
-    let folder_str = unsafe { c_str_to_string(folder) };
-    let text_str = unsafe { c_str_to_string(text) };
+    close_live_agents(id);
+    storage.delete_agent(id)?;
+    Ok(())
+}
+
+/// Invalidate every live handle to agent `id` and stop it writing to
+/// storage, so a step still running can't save the agent back once its rows
+/// are gone. Waits for such steps to finish, without holding up other calls.
+fn close_live_agents(id: &str) {
+    let live: Vec<_> = lock(&AGENTS).entries()
+        .map(|(index, generation, agent)| (index, generation, agent.clone()))
+        .collect();
     
-    unsafe {
-        let handle = &*handle;
-        let mut agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return -1;
-        }
-        
-        if let Some(agent) = &mut agents[handle.index] {
-            agent.add_archival(&folder_str, &text_str);
+    for (index, generation, agent) in live {
+        let mut agent = agent.blocking_lock();
+        if agent.state.id == id {
+            agent.detach_storage();
+            agent.disarm_flush_on_drop();
+            lock(&AGENTS).remove(index, generation);
         }
     }
-    
-    0
 }
 
-/// Search archival memory
+/// Move a persisted agent to the trash. It leaves `letta_list_agents` and
+/// any live handle for it stops working, but its data is kept until purged
+/// and `letta_restore_agent` brings it back. With sync configured the
+/// deletion is sent to the cloud. Requires `letta_init_storage`.
 #[no_mangle]
-pub extern "C" fn letta_search_archival(handle: *mut AgentHandle, query: *const c_char, top_k: i32) -> *mut c_char {
-    if handle.is_null() {
-        return ptr::null_mut();
+pub extern "C" fn letta_trash_agent(agent_id: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let id = c_str_arg!(agent_id, "agent_id", set_last_error);
+        status(trash_agent(&id))
+    })
+}
+
+fn trash_agent(id: &str) -> FfiResult<()> {
+    let storage = storage()?;
+    if storage.get_agent(id)?.is_none_or(|agent| agent.deleted_at.is_some()) {
+        return Err(FfiError::new(LettaErrorCode::AgentNotFound, format!("Agent {} not found outside the trash", id)));
     }
     
-    let query_str = unsafe { c_str_to_string(query) };
-    
-    unsafe {
-        let handle = &*handle;
-        let agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return ptr::null_mut();
-        }
-        
-        if let Some(agent) = &agents[handle.index] {
-            let results = agent.search_archival(&query_str, top_k as usize);
-            let json = serde_json::to_string(&results).unwrap_or_default();
-            return string_to_c_str(json);
-        }
+    close_live_agents(id);
+    storage.soft_delete_agent(id)?;
+    Ok(())
+}
+
+/// Take an agent out of the trash; open it again with `letta_open_agent`.
+/// Returns `LETTA_ERROR_CODE_AGENT_NOT_FOUND` if it isn't in the trash.
+/// Requires `letta_init_storage`.
+#[no_mangle]
+pub extern "C" fn letta_restore_agent(agent_id: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let id = c_str_arg!(agent_id, "agent_id", set_last_error);
+        status(restore_agent(&id))
+    })
+}
+
+fn restore_agent(id: &str) -> FfiResult<()> {
+    if !storage()?.restore_agent(id)? {
+        return Err(FfiError::new(LettaErrorCode::AgentNotFound, format!("Agent {} not found in the trash", id)));
     }
-    
-    ptr::null_mut()
+    Ok(())
 }
 
-/// Converse with the agent
+/// Effective configuration of the agent as JSON. Free with `letta_free_str`.
 #[no_mangle]
-pub extern "C" fn letta_converse(handle: *mut AgentHandle, user_msg_json: *const c_char) -> *mut c_char {
-    if handle.is_null() {
-        return ptr::null_mut();
+pub extern "C" fn letta_get_config(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(with_agent(handle, |agent| {
+            Ok(string_to_c_str(serde_json::to_string(&agent.config)?))
+        }))
+    })
+}
+
+/// Apply a partial config update, e.g. `{"temperature": 0.3, "max_messages": 50}`.
+/// Invalid values fail with `LETTA_ERROR_CODE_INVALID_ARG`; changing `model`
+/// needs a new provider and fails with `LETTA_ERROR_CODE_UNSUPPORTED`.
+/// Persisted immediately if the agent has been saved to storage.
+#[no_mangle]
+pub extern "C" fn letta_update_config(handle: *mut AgentHandle, patch_json: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let patch_str = c_str_arg!(patch_json, "patch_json", set_last_error);
+        status(with_agent(handle, |agent| update_config(agent, &patch_str)))
+    })
+}
+
+fn update_config(agent: &mut Agent, patch_str: &str) -> FfiResult<()> {
+    let patch: serde_json::Map<String, serde_json::Value> = serde_json::from_str(patch_str)?;
+    let mut merged = serde_json::to_value(&agent.config)?;
+    for (key, value) in patch {
+        if key == "provider" {
+            return Err(FfiError::new(LettaErrorCode::Unsupported, "The provider of a live agent cannot be replaced"));
+        }
+        if merged.get(&key).is_none() {
+            return Err(FfiError::invalid_arg(format!("Unknown config field '{}'", key)));
+        }
+        merged[key] = value;
     }
+    let config: AgentConfig = serde_json::from_value(merged)?;
     
-    let msg_str = unsafe { c_str_to_string(user_msg_json) };
-    
-    // Parse message
-    let msg_result: Result<serde_json::Value, _> = serde_json::from_str(&msg_str);
-    if msg_result.is_err() {
-        return string_to_c_str(json!({
-            "error": "Invalid message JSON"
-        }).to_string());
+    if config.model != agent.config.model {
+        return Err(FfiError::new(
+            LettaErrorCode::Unsupported,
+            "Changing the model requires a new provider; save and reopen the agent with a config override",
+        ));
     }
     
-    let msg_value = msg_result.unwrap();
-    let text = msg_value.get("text")
-        .and_then(|v| v.as_str())
-        .unwrap_or("")
-        .to_string();
+    agent.update_config(config)?;
     
-    unsafe {
-        let handle = &*handle;
-        let mut agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return string_to_c_str(json!({
-                "error": "Invalid agent handle"
-            }).to_string());
+    if let Ok(storage) = storage() {
+        if storage.get_agent(&agent.state.id)?.is_some() {
+            agent.save(&storage)?;
         }
+    }
+    Ok(())
+}
+
+/// Load agent from AF file
+#[no_mangle]
+pub extern "C" fn letta_load_af(handle: *mut AgentHandle, af_json: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let af_str = c_str_arg!(af_json, "af_json", set_last_error);
         
-        if let Some(agent) = &mut agents[handle.index] {
-            // Run step in runtime
-            let result = RUNTIME.block_on(async {
-                agent.step(text).await
-            });
+        status(with_agent(handle, |agent| {
+            // Parse AF and import state
+            let af = AgentFile::from_json(&af_str)?;
+            let (_config, state) = AgentFile::import(&af)?;
             
-            match result {
-                Ok(step_result) => {
-                    let response = json!({
-                        "text": step_result.text,
-                        "tool_trace": step_result.tool_trace,
-                        "usage": step_result.usage,
-                    });
-                    return string_to_c_str(response.to_string());
-                }
-                Err(e) => {
-                    let error = json!({
-                        "error": e.to_string()
-                    });
-                    return string_to_c_str(error.to_string());
-                }
-            }
-        }
-    }
-    
-    string_to_c_str(json!({
-        "error": "Unknown error"
-    }).to_string())
+            // Update agent state
+            agent.import_state(&serde_json::to_string(&state)?)?;
+            Ok(())
+        }))
+    })
 }
 
-/// Configure cloud sync
+/// Create a new agent from an agent file, e.g. one shared by another user.
+/// `provider_config_json` may be null to use the model named in the file,
+/// or a provider object as for `letta_create_agent`. Returns null on error;
+/// files with several agents fail with `LETTA_ERROR_CODE_MULTIPLE_AGENTS`.
+/// Anything the import skipped is reported by `letta_last_import_report`.
 #[no_mangle]
-pub extern "C" fn letta_configure_sync(config_json: *const c_char) -> i32 {
-    let config_str = unsafe { c_str_to_string(config_json) };
+pub extern "C" fn letta_create_agent_from_af(af_json: *const c_char, provider_config_json: *const c_char) -> *mut AgentHandle {
+    catch_panic(null_on_error, || {
+        let af_str = c_str_arg!(af_json, "af_json", null_on_error);
+        let provider_str = c_str_arg!(provider_config_json, "provider_config_json", null_on_error);
+        pointer(create_agent_from_af(&af_str, &provider_str))
+    })
+}
+
+fn create_agent_from_af(af_str: &str, provider_str: &str) -> FfiResult<*mut AgentHandle> {
+    LAST_IMPORT_REPORT.with(|report| *report.borrow_mut() = None);
     
-    let config_result: Result<serde_json::Value, _> = serde_json::from_str(&config_str);
-    if config_result.is_err() {
-        return -1;
+    let af = AgentFile::from_json(af_str)?;
+    if af.agents.len() > 1 {
+        return Err(FfiError::new(
+            LettaErrorCode::MultipleAgents,
+            format!("Agent file holds {} agents; import them one at a time", af.agents.len()),
+        ));
     }
+    let (config, state) = AgentFile::import(&af)?;
+    config.validate()?;
     
-    let config_value = config_result.unwrap();
-    
-    let sync_config = SyncConfig {
-        endpoint: config_value.get("endpoint")
-            .and_then(|v| v.as_str())
-            .unwrap_or("https://api.letta.ai")
-            .to_string(),
-        api_key: config_value.get("api_key")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .to_string(),
-        sync_interval: config_value.get("sync_interval")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(300000), // 5 minutes
-        conflict_resolution: config_value.get("conflict_resolution")
-            .and_then(|v| v.as_str())
-            .unwrap_or("last-write-wins")
-            .to_string(),
-        auto_sync: config_value.get("auto_sync")
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false),
+    let provider = match provider_str {
+        "" => None,
+        provider_str => Some(serde_json::from_str(provider_str)?),
     };
+    let provider = create_provider(&config, provider)?;
     
-    match SyncClient::new(sync_config) {
-        Ok(client) => {
-            *SYNC_CLIENT.lock().unwrap() = Some(client);
-            0
-        }
-        Err(_) => -1,
-    }
+    let report = json!({
+        "agent_id": state.id,
+        "warnings": AgentFile::import_warnings(&af),
+    });
+    let handle = register_agent(attach_storage(Agent::new(config, provider).with_state(state)))?;
+    LAST_IMPORT_REPORT.with(|last| *last.borrow_mut() = Some(report));
+    Ok(handle)
 }
 
-/// Sync with cloud
+/// What the last successful `letta_create_agent_from_af` on this thread could
+/// not import, as `{"agent_id": ..., "warnings": [{"kind": "unknown_tool", "name": ...}, ...]}`.
+/// Warning kinds are `unknown_tool`, `skipped_section` and `missing_block`.
+/// Null if there is no report; free it with `letta_free_str`.
 #[no_mangle]
-pub extern "C" fn letta_sync_with_cloud(handle: *mut AgentHandle) -> i32 {
-    if handle.is_null() {
-        return -1;
-    }
-    
-    let sync_client = SYNC_CLIENT.lock().unwrap();
-    if sync_client.is_none() {
-        return -1; // Sync not configured
-    }
-    
-    unsafe {
-        let handle = &*handle;
-        let agents = AGENTS.lock().unwrap();
-        
-        if handle.index >= agents.len() || agents[handle.index].is_none() {
-            return -1;
+pub extern "C" fn letta_last_import_report() -> *mut c_char {
+    catch_panic(null_on_error, || {
+        LAST_IMPORT_REPORT.with(|report| match report.borrow().as_ref() {
+            Some(report) => string_to_c_str(report.to_string()),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Export agent to AF format
+#[no_mangle]
+pub extern "C" fn letta_export_af(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(with_agent(handle, |agent| {
+            // Get tool schemas
+            let tool_schemas: Vec<ToolSchema> = vec![]; // TODO: Get from agent
+            
+            // Export to AF and convert to JSON
+            let af = AgentFile::export(&agent.config, &agent.state, tool_schemas)?;
+            Ok(string_to_c_str(AgentFile::to_json(&af)?))
+        }))
+    })
+}
+
+/// Usage and health figures for the agent as JSON: `messages` (buffer
+/// counts by role, token estimate, last message time), `usage` (tokens used
+/// so far), `memory` (characters used per block and overall `fullness`),
+/// `archival` (entries by folder), `tools` (calls per tool), `metrics`
+/// (step, provider and tool counts and latencies in milliseconds, since the
+/// library was loaded), plus `id`, `name`, `created_at` and `updated_at`.
+/// Cheap enough to poll; fails with
+/// `LETTA_ERROR_CODE_BUSY` while the agent is mid-step. Free the result with
+/// `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_agent_stats(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(with_agent(handle, |agent| {
+            let mut stats = serde_json::to_value(agent.stats())?;
+            stats["metrics"] = serde_json::to_value(METRICS.agent_snapshot(&agent.state.id))?;
+            Ok(string_to_c_str(stats.to_string()))
+        }))
+    })
+}
+
+/// Tokens the agent's completions have used so far as JSON
+/// `{total, by_provider}`, each usage being `{prompt_tokens,
+/// completion_tokens, total_tokens}` and `by_provider` keyed by provider
+/// name. Saved with the agent's state. Fails with `LETTA_ERROR_CODE_BUSY`
+/// while the agent is mid-step. Free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_get_usage(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(with_agent(handle, |agent| {
+            Ok(string_to_c_str(serde_json::to_string(&agent.usage())?))
+        }))
+    })
+}
+
+/// List every known agent as a JSON array of
+/// `{id, name, created_at, updated_at, message_count, persisted}`.
+/// Agents only held in memory are included with `persisted: false`; stored
+/// agents too corrupted to read are left out. Requires `letta_init_storage`; free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_list_agents() -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(list_agents().map(|agents| string_to_c_str(agents.to_string())))
+    })
+}
+
+fn list_agents() -> FfiResult<serde_json::Value> {
+    let storage = storage()?;
+    
+    let mut listed = Vec::new();
+    let mut persisted_ids = std::collections::HashSet::new();
+    for stored in storage.list_agents_lenient()?.items {
+        listed.push(json!({
+            "id": stored.id,
+            "name": stored.name,
+            "created_at": stored.created_at,
+            "updated_at": stored.updated_at,
+            "message_count": storage.count_messages(&stored.id)?,
+            "persisted": true,
+        }));
+        persisted_ids.insert(stored.id);
+    }
+    
+    // Waits for any agent that is mid-stream
+    let agents: Vec<SharedAgent> = lock(&AGENTS).values().cloned().collect();
+    for agent in &agents {
+        let agent = agent.blocking_lock();
+        if persisted_ids.contains(&agent.state.id) {
+            continue;
         }
+        listed.push(json!({
+            "id": agent.state.id,
+            "name": agent.config.name,
+            "created_at": agent.state.created_at,
+            "updated_at": agent.state.updated_at,
+            "message_count": agent.state.messages.messages.len(),
+            "persisted": false,
+        }));
+    }
+    
+    Ok(serde_json::Value::Array(listed))
+}
+
+/// Database size and contents as JSON: `{file_size_bytes, page_count,
+/// free_page_count, agent_count, message_count, chunk_count, per_agent}`, where
+/// each `per_agent` entry is `{agent_id, name, message_count, chunk_count,
+/// block_count}`. Requires `letta_init_storage`; free the result with
+/// `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_storage_stats() -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(storage_stats().map(|stats| string_to_c_str(stats.to_string())))
+    })
+}
+
+fn storage_stats() -> FfiResult<serde_json::Value> {
+    Ok(serde_json::to_value(storage()?.stats()?)?)
+}
+
+/// Run database maintenance. `options_json` may be null to run everything,
+/// or e.g. `{"vacuum": true}`; the tasks are `vacuum`, `analyze` and
+/// `fts_optimize`. Returns `{size_before_bytes, size_after_bytes,
+/// reclaimed_bytes}`. Requires `letta_init_storage`; free the result with
+/// `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_storage_maintain(options_json: *const c_char) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let options = c_str_arg!(options_json, "options_json", null_on_error);
+        pointer(storage_maintain(&options).map(|report| string_to_c_str(report.to_string())))
+    })
+}
+
+fn storage_maintain(options: &str) -> FfiResult<serde_json::Value> {
+    let options = if options.is_empty() {
+        MaintenanceOptions::all()
+    } else {
+        serde_json::from_str(options)?
+    };
+    Ok(serde_json::to_value(storage()?.maintain(&options)?)?)
+}
+
+/// Describe a live agent as JSON: `{id, name, config, memory, message_count,
+/// archival_count}`, where `memory` summarises each block without its full value.
+/// Free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_get_agent_info(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(with_agent(handle, |agent| {
+            // Same figures as `letta_agent_stats`
+            let memory: Vec<_> = agent.state.memory.usage().blocks.into_iter()
+                .map(|usage| json!({
+                    "label": usage.label,
+                    "description": agent.state.memory.get_block(&usage.label).map(|b| &b.description),
+                    "chars": usage.used,
+                    "limit": usage.limit,
+                }))
+                .collect();
+            
+            let info = json!({
+                "id": agent.state.id,
+                "name": agent.config.name,
+                "config": agent.config,
+                "memory": memory,
+                "message_count": agent.state.messages.messages.len(),
+                "archival_count": agent.state.archival_entries.len(),
+            });
+            Ok(string_to_c_str(info.to_string()))
+        }))
+    })
+}
+
+/// Page through an agent's history as a JSON array of messages
+/// (`id`, `role`, `content`, `tool_calls`, `tool_call_id`, `timestamp`, `metadata`),
+/// oldest first. Returns at most `limit` messages (all when `limit <= 0`) that
+/// come before `before_id`, or the newest ones when `before_id` is null.
+/// Messages evicted from the in-memory buffer are read from storage when the
+/// agent is persisted, one page at a time. Free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_get_messages(handle: *mut AgentHandle, limit: i32, before_id: *const c_char) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let before_id = c_str_arg!(before_id, "before_id", null_on_error);
         
-        if let Some(agent) = &agents[handle.index] {
-            // Export agent state
-            let state_json = agent.export_state();
-            if state_json.is_err() {
-                return -1;
+        pointer(with_agent(handle, |agent| {
+            let page = messages_page(agent, limit, &before_id)?;
+            Ok(string_to_c_str(serde_json::to_string(&page)?))
+        }))
+    })
+}
+
+/// Up to `limit` messages before `before_id`, oldest first. The page is
+/// merged from storage and the buffer, whose newest messages may not have
+/// been saved yet, ordered like storage by `(timestamp, id)`.
+fn messages_page(agent: &Agent, limit: i32, before_id: &str) -> FfiResult<Vec<serde_json::Value>> {
+    let storage = storage().ok();
+    let limit = if limit > 0 { limit as usize } else { u32::MAX as usize };
+    let before = match before_id {
+        "" => None,
+        id => Some(message_cursor(agent, storage.as_ref(), id)?),
+    };
+    let is_before = |timestamp, id: &str| match &before {
+        Some(cursor) => (timestamp, id) < (cursor.timestamp, cursor.id.as_str()),
+        None => true,
+    };
+    
+    let mut page = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    for message in &agent.state.messages.messages {
+        if is_before(message.timestamp, &message.id) {
+            seen.insert(message.id.clone());
+            page.push((message.timestamp, message.id.clone(), serde_json::to_value(message)?));
+        }
+    }
+    if let Some(storage) = &storage {
+        let query = MessageQuery { before: before.clone(), limit: Some(limit), ascending: true, ..Default::default() };
+        for stored in storage.query_messages(&agent.state.id, &query)? {
+            if seen.insert(stored.id.clone()) {
+                page.push((stored.timestamp, stored.id.clone(), stored_message_json(stored)));
+            }
+        }
+    }
+    
+    page.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    let start = page.len().saturating_sub(limit);
+    Ok(page.into_iter().skip(start).map(|(_, _, message)| message).collect())
+}
+
+fn message_cursor(agent: &Agent, storage: Option<&Storage>, id: &str) -> FfiResult<PageCursor> {
+    if let Some(message) = agent.state.messages.messages.iter().find(|m| m.id == id) {
+        return Ok(PageCursor { timestamp: message.timestamp, id: message.id.clone() });
+    }
+    
+    let stored = match storage {
+        Some(storage) => storage.get_message(id)?,
+        None => None,
+    };
+    stored.filter(|message| message.agent_id == agent.state.id)
+        .map(|message| PageCursor { timestamp: message.timestamp, id: message.id })
+        .ok_or_else(|| FfiError::invalid_arg(format!("Message {} not found", id)))
+}
+
+fn stored_message_json(stored: letta_storage::StoredMessage) -> serde_json::Value {
+    let mut message = json!({
+        "id": stored.id,
+        "role": stored.role,
+        "content": stored.content,
+        "timestamp": stored.timestamp,
+        "metadata": stored.metadata,
+    });
+    if let Some(tool_calls) = stored.tool_calls {
+        message["tool_calls"] = tool_calls;
+    }
+    if let Some(tool_call_id) = stored.tool_call_id {
+        message["tool_call_id"] = json!(tool_call_id);
+    }
+    message
+}
+
+/// Number of messages `letta_get_messages` can page through, or a negative error code
+#[no_mangle]
+pub extern "C" fn letta_get_message_count(handle: *mut AgentHandle) -> i64 {
+    catch_panic(|e| set_last_error(e) as i64, || {
+        match with_agent(handle, |agent| message_history(agent)) {
+            Ok(history) => {
+                clear_last_error();
+                history.len() as i64
             }
+            Err(e) => set_last_error(e) as i64,
+        }
+    })
+}
+
+/// Full chronological history: persisted messages in insertion order followed
+/// by buffered ones that have not been written to storage
+fn message_history(agent: &Agent) -> FfiResult<Vec<serde_json::Value>> {
+    let mut history = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    
+    if let Ok(storage) = storage() {
+        for stored in storage.get_messages_since(&agent.state.id, 0)? {
+            seen.insert(stored.id.clone());
+            history.push(stored_message_json(stored));
+        }
+    }
+    
+    for message in &agent.state.messages.messages {
+        if !seen.contains(&message.id) {
+            history.push(serde_json::to_value(message)?);
+        }
+    }
+    
+    Ok(history)
+}
+
+/// Set a memory block
+#[no_mangle]
+pub extern "C" fn letta_set_block(handle: *mut AgentHandle, label: *const c_char, value: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let label_str = c_str_arg!(label, "label", set_last_error);
+        let value_str = c_str_arg!(value, "value", set_last_error);
+        
+        status(with_agent(handle, |agent| {
+            agent.set_memory_block(&label_str, &value_str)?;
+            Ok(())
+        }))
+    })
+}
+
+/// Get a memory block
+#[no_mangle]
+pub extern "C" fn letta_get_block(handle: *mut AgentHandle, label: *const c_char) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let label_str = c_str_arg!(label, "label", null_on_error);
+        
+        pointer(with_agent(handle, |agent| {
+            agent.get_memory_block(&label_str)
+                .map(string_to_c_str)
+                .ok_or_else(|| FfiError::invalid_arg(format!("Memory block '{}' not found", label_str)))
+        }))
+    })
+}
+
+/// Add to archival memory
+#[no_mangle]
+pub extern "C" fn letta_append_archival(handle: *mut AgentHandle, folder: *const c_char, text: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let folder_str = c_str_arg!(folder, "folder", set_last_error);
+        let text_str = c_str_arg!(text, "text", set_last_error);
+        
+        status(with_agent(handle, |agent| {
+            agent.add_archival(&folder_str, &text_str);
+            Ok(())
+        }))
+    })
+}
+
+/// Search archival memory
+#[no_mangle]
+pub extern "C" fn letta_search_archival(handle: *mut AgentHandle, query: *const c_char, top_k: i32) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let query_str = c_str_arg!(query, "query", null_on_error);
+        
+        pointer(with_agent(handle, |agent| {
+            let results = agent.search_archival(&query_str, top_k.max(0) as usize);
+            Ok(string_to_c_str(serde_json::to_string(&results)?))
+        }))
+    })
+}
+
+/// Converse with the agent. Failures are returned as the same JSON as
+/// `letta_last_error`, e.g. `{"error": ..., "code": ..., "error_code": ...}`.
+#[no_mangle]
+pub extern "C" fn letta_converse(handle: *mut AgentHandle, user_msg_json: *const c_char) -> *mut c_char {
+    catch_panic(error_json, || {
+        if handle.is_null() {
+            set_last_error(FfiError::invalid_arg("Null agent handle"));
+            return ptr::null_mut();
+        }
+        
+        let msg_str = c_str_arg!(user_msg_json, "user_msg_json", error_json);
+        
+        let result = with_agent(handle, |agent| {
+            // Parse message
+            let text = message_text(&msg_str)?;
             
-            // TODO: Implement actual sync with Letta server
-            // For now, just return success
-            return 0;
+            // Run step in runtime
+            let step = RunningStep::start(StepKey::of(handle));
+            let step_result = RUNTIME.block_on(async {
+                agent.step_with_cancel(text, &step.token).await
+            })?;
+            
+            Ok(step_json(&step_result))
+        });
+        
+        match result {
+            Ok(response) => {
+                clear_last_error();
+                string_to_c_str(response.to_string())
+            }
+            Err(e) => error_json(e),
         }
+    })
+}
+
+/// Add a user message without generating a reply. Returns the new message's
+/// id, or null on error; free it with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_send_only(handle: *mut AgentHandle, user_msg_json: *const c_char) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let msg_str = c_str_arg!(user_msg_json, "user_msg_json", null_on_error);
+        
+        pointer(with_agent(handle, |agent| {
+            let text = message_text(&msg_str)?;
+            Ok(string_to_c_str(agent.send_only(text)))
+        }))
+    })
+}
+
+/// Generate a reply to the messages sent so far without adding a new one.
+/// Returns the same JSON as `letta_converse`, including `{"error": ...}` on failure.
+#[no_mangle]
+pub extern "C" fn letta_reply_only(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(error_json, || {
+        if handle.is_null() {
+            set_last_error(FfiError::invalid_arg("Null agent handle"));
+            return ptr::null_mut();
+        }
+        
+        let result = with_agent(handle, |agent| {
+            let step = RunningStep::start(StepKey::of(handle));
+            let step_result = RUNTIME.block_on(agent.reply_only_with_cancel(&step.token))?;
+            Ok(step_json(&step_result))
+        });
+        
+        match result {
+            Ok(response) => {
+                clear_last_error();
+                string_to_c_str(response.to_string())
+            }
+            Err(e) => error_json(e),
+        }
+    })
+}
+
+/// Replace the agent's last reply with a new one, dropping the assistant and
+/// tool messages after the last user message first. Returns the same JSON as
+/// `letta_converse`; on failure the old reply is kept. Fails with
+/// `LETTA_ERROR_CODE_INVALID_ARG` if there is no reply to replace.
+/// Replies already saved to storage stay there.
+#[no_mangle]
+pub extern "C" fn letta_regenerate(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(error_json, || {
+        if handle.is_null() {
+            set_last_error(FfiError::invalid_arg("Null agent handle"));
+            return ptr::null_mut();
+        }
+        
+        let result = with_agent(handle, |agent| {
+            let step = RunningStep::start(StepKey::of(handle));
+            let step_result = RUNTIME.block_on(agent.regenerate_with_cancel(None, &step.token))?;
+            Ok(step_json(&step_result))
+        });
+        
+        match result {
+            Ok(response) => {
+                clear_last_error();
+                string_to_c_str(response.to_string())
+            }
+            Err(e) => error_json(e),
+        }
+    })
+}
+
+/// Check that the agent's provider is reachable and accepts its API key.
+/// Returns 0, or an error code with the provider's message in
+/// `letta_last_error_message`.
+#[no_mangle]
+pub extern "C" fn letta_check_provider(handle: *mut AgentHandle) -> i32 {
+    catch_panic(set_last_error, || {
+        status(with_agent(handle, |agent| {
+            let health = RUNTIME.block_on(agent.check_provider())?;
+            tracing::debug!(provider = %health.provider, latency_ms = health.latency_ms, "Provider is healthy");
+            Ok(())
+        }))
+    })
+}
+
+/// Search the message buffer for `query`, returning up to `top_k` matching
+/// messages as a JSON array
+#[no_mangle]
+pub extern "C" fn letta_search_conversation(handle: *mut AgentHandle, query: *const c_char, top_k: i32) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        let query_str = c_str_arg!(query, "query", null_on_error);
+        
+        pointer(with_agent(handle, |agent| {
+            let results = agent.search_conversation(&query_str, top_k.max(0) as usize);
+            Ok(string_to_c_str(serde_json::to_string(&results)?))
+        }))
+    })
+}
+
+/// Clear the agent's message buffer. Messages already saved to storage are kept.
+#[no_mangle]
+pub extern "C" fn letta_clear_messages(handle: *mut AgentHandle) -> i32 {
+    catch_panic(set_last_error, || {
+        status(with_agent(handle, |agent| {
+            agent.clear_messages();
+            Ok(())
+        }))
+    })
+}
+
+/// Configure cloud sync with `SyncConfig` as JSON. Set `proxy` (and
+/// optionally `no_proxy`) to send sync requests through an outbound proxy.
+#[no_mangle]
+pub extern "C" fn letta_configure_sync(config_json: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let config_str = c_str_arg!(config_json, "config_json", set_last_error);
+        status(configure_sync(&config_str))
+    })
+}
+
+fn configure_sync(config_str: &str) -> FfiResult<()> {
+    ensure_running()?;
+    
+    // Missing fields fall back to SyncConfig::default()
+    install_sync_client(serde_json::from_str(config_str)?)
+}
+
+fn install_sync_client(sync_config: SyncConfig) -> FfiResult<()> {
+    // With storage initialised the device id survives app restarts
+    let client = match lock(&STORAGE).as_ref() {
+        Some(storage) => SyncClient::with_storage(sync_config, storage)?,
+        None => SyncClient::new(sync_config)?,
+    };
+    
+    // A registered callback listens to the old client's events
+    if let Some(task) = lock(&SYNC_CALLBACK_TASK).take() {
+        task.abort();
     }
+    *lock(&SYNC_CLIENT) = Some(Arc::new(client));
+    Ok(())
+}
+
+/// Register a callback invoked with every sync event as JSON, e.g.
+/// `{"type":"completed","agent_id":"...","new_version":3}`.
+/// Passing a null callback unregisters. Must be called after `letta_configure_sync`;
+/// reconfiguring sync unregisters the callback.
+#[no_mangle]
+pub extern "C" fn letta_set_sync_callback(
+    callback: Option<extern "C" fn(event_json: *const c_char, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> i32 {
+    catch_panic(set_last_error, || {
+        let client = match sync_client() {
+            Ok(client) => client,
+            Err(e) => return set_last_error(e),
+        };
+        
+        let mut task = lock(&SYNC_CALLBACK_TASK);
+        if let Some(previous) = task.take() {
+            previous.abort();
+        }
+        
+        let callback = match callback {
+            Some(callback) => SyncCallback { callback, user_data },
+            None => return status(Ok(())),
+        };
+        
+        let mut events = client.subscribe();
+        *task = Some(RUNTIME.spawn(async move {
+            let callback = callback;
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        let json = serde_json::to_string(&event).unwrap_or_default();
+                        if let Ok(c_json) = CString::new(json) {
+                            (callback.callback)(c_json.as_ptr(), callback.user_data);
+                        }
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }));
+        
+        status(Ok(()))
+    })
+}
+
+/// Override sync behaviour for one agent, e.g.
+/// `{"enabled": false}` or `{"sync_interval": 60000, "conflict_resolution": "cloud-wins"}`.
+/// Omitted fields fall back to the global sync config. Requires `letta_init_storage`.
+#[no_mangle]
+pub extern "C" fn letta_set_agent_sync(handle: *mut AgentHandle, settings_json: *const c_char) -> i32 {
+    catch_panic(set_last_error, || {
+        let settings_str = c_str_arg!(settings_json, "settings_json", set_last_error);
+        status(set_agent_sync(handle, &settings_str))
+    })
+}
+
+fn set_agent_sync(handle: *mut AgentHandle, settings_str: &str) -> FfiResult<()> {
+    let mut settings: AgentSyncSettings = serde_json::from_str(settings_str)?;
     
-    -1
+    if let Some(name) = &settings.conflict_resolution {
+        serde_json::from_value::<ConflictResolution>(json!(name))
+            .map_err(|_| FfiError::invalid_arg(format!("Unknown conflict resolution '{}'", name)))?;
+    }
+    
+    settings.agent_id = with_agent(handle, |agent| Ok(agent.state.id.clone()))?;
+    storage()?.set_agent_sync_settings(&settings)?;
+    Ok(())
+}
+
+/// Preview what syncing the agent would do, without changing anything.
+/// Returns a `SyncPlan` as JSON, or `{"error": ...}`. Requires storage and sync
+/// to be configured; free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_sync_plan(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(error_json, || {
+        if handle.is_null() {
+            set_last_error(FfiError::invalid_arg("Null agent handle"));
+            return ptr::null_mut();
+        }
+        
+        match sync_plan(handle) {
+            Ok(plan) => {
+                clear_last_error();
+                string_to_c_str(plan.to_string())
+            }
+            Err(e) => error_json(e),
+        }
+    })
+}
+
+fn sync_plan(handle: *mut AgentHandle) -> FfiResult<serde_json::Value> {
+    let agent_id = with_agent(handle, |agent| Ok(agent.state.id.clone()))?;
+    let manager = SyncManager::new(sync_client()?, storage()?);
+    let plan = RUNTIME.block_on(manager.plan(&agent_id))?;
+    Ok(serde_json::to_value(plan)?)
+}
+
+/// Describe where the agent stands with the cloud as JSON:
+/// `{agent_id, local_version, cloud_version, last_sync_at, status, pending, conflicts}`.
+/// `status` is `"pending"`, `"synced"` or `"conflict"`; an agent that has never
+/// synced reports `"pending"` with a null `last_sync_at`. `pending` counts its
+/// queued uploads and `conflicts` lists any deferred for the host. Requires
+/// `letta_init_storage`; free the result with `letta_free_str`.
+#[no_mangle]
+pub extern "C" fn letta_sync_status(handle: *mut AgentHandle) -> *mut c_char {
+    catch_panic(null_on_error, || {
+        pointer(sync_status(handle).map(|status| string_to_c_str(status.to_string())))
+    })
+}
+
+fn sync_status(handle: *mut AgentHandle) -> FfiResult<serde_json::Value> {
+    let storage = storage()?;
+    let agent_id = with_agent(handle, |agent| Ok(agent.state.id.clone()))?;
+    
+    let metadata = storage.get_sync_metadata("agent", &agent_id)?;
+    let pending = storage.list_sync_queue()?.iter()
+        .filter(|entry| entry.entity_type == "agent" && entry.entity_id == agent_id && entry.status == "pending")
+        .count();
+    
+    Ok(json!({
+        "agent_id": agent_id,
+        "local_version": metadata.as_ref().map_or(0, |m| m.local_version),
+        "cloud_version": metadata.as_ref().map_or(0, |m| m.cloud_version),
+        "last_sync_at": metadata.as_ref().map(|m| m.last_sync_at),
+        "status": metadata.as_ref().map_or(SyncStatus::Pending, |m| m.sync_status),
+        "pending": pending,
+        "conflicts": metadata.and_then(|m| m.conflicts).unwrap_or_else(|| json!([])),
+    }))
+}
+
+/// Sync the agent with the cloud.
+///
+/// The agent is saved to storage, synced, and reloaded with whatever the
+/// server sent back, resolved per its conflict strategy. Requires
+/// `letta_init_storage` and `letta_configure_sync`; returns
+/// `LETTA_ERROR_CODE_NETWORK_ERROR` if the server is unreachable and
+/// `LETTA_ERROR_CODE_SYNC_CONFLICT` if conflicts were deferred for the host.
+#[no_mangle]
+pub extern "C" fn letta_sync_with_cloud(handle: *mut AgentHandle) -> i32 {
+    catch_panic(set_last_error, || {
+        status(sync_with_cloud(handle))
+    })
+}
+
+fn sync_with_cloud(handle: *mut AgentHandle) -> FfiResult<()> {
+    let client = sync_client()?;
+    let storage = storage()?;
+    
+    let agent_id = with_agent(handle, |agent| {
+        agent.save(&storage)?;
+        
+        let manager = SyncManager::new(client, storage.clone());
+        RUNTIME.block_on(manager.sync_and_apply(&agent.state.id))?;
+        
+        // The provider stays as is, so keep the model it was built for
+        let (mut config, state) = letta_core::persist::load_agent(&storage, &agent.state.id)?;
+        config.model = agent.config.model.clone();
+        agent.update_config(config)?;
+        agent.state = state;
+        Ok(agent.state.id.clone())
+    })?;
+    
+    let metadata = storage.get_sync_metadata("agent", &agent_id)?;
+    if metadata.is_some_and(|m| m.sync_status == SyncStatus::Conflict) {
+        return Err(FfiError::new(LettaErrorCode::SyncConflict, "Sync left unresolved conflicts"));
+    }
+    Ok(())
 }
 
 /// Free a string allocated by Rust
 #[no_mangle]
 pub extern "C" fn letta_free_str(s: *mut c_char) {
-    if !s.is_null() {
-        unsafe {
-            let _ = CString::from_raw(s);
+    catch_panic(|e| { set_last_error(e); }, || {
+        if !s.is_null() {
+            unsafe {
+                let _ = CString::from_raw(s);
+            }
         }
-    }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use error::{letta_last_error, letta_last_error_code, letta_last_error_message};
+    use af_file::letta_export_all;
     
     #[test]
     fn test_ffi_agent_creation() {
@@ -505,4 +1527,1037 @@ mod tests {
         
         letta_free_agent(handle);
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_ffi_freed_handle_rejected() {
+        let config = CString::new(r#"{"name": "short-lived"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let mut stale = unsafe { *handle };
+        letta_free_agent(handle);
+        
+        // Whatever now occupies the slot, the old generation must not reach it
+        let replacement = letta_create_agent(config.as_ptr());
+        let label = CString::new("persona").unwrap();
+        let value = CString::new("Someone else").unwrap();
+        assert_eq!(letta_set_block(&mut stale, label.as_ptr(), value.as_ptr()), LettaErrorCode::AgentNotFound as i32);
+        assert!(letta_get_agent_info(&mut stale).is_null());
+        
+        letta_free_agent(replacement);
+    }
+    
+    #[test]
+    fn test_ffi_get_and_update_config() {
+        let config = CString::new(r#"{"name": "tunable"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        
+        let patch = CString::new(r#"{"temperature": 0.25, "max_messages": 20}"#).unwrap();
+        assert_eq!(letta_update_config(handle, patch.as_ptr()), 0);
+        let current = take_json(letta_get_config(handle));
+        assert_eq!(current["max_messages"], 20);
+        assert!((current["temperature"].as_f64().unwrap() - 0.25).abs() < 1e-6);
+        
+        let exported = take_json(letta_export_af(handle));
+        assert_eq!(exported["agents"][0]["message_buffer_size"], 20);
+        
+        for (patch, code) in [
+            (r#"{"model": "gpt-4o"}"#, LettaErrorCode::Unsupported),
+            (r#"{"temperature": 9.0}"#, LettaErrorCode::InvalidArg),
+            (r#"{"no_such_field": 1}"#, LettaErrorCode::InvalidArg),
+            (r#"{"max_messages": "many"}"#, LettaErrorCode::InvalidJson),
+        ] {
+            let patch = CString::new(patch).unwrap();
+            assert_eq!(letta_update_config(handle, patch.as_ptr()), code as i32);
+        }
+        assert_eq!(take_json(letta_get_config(handle)), current);
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_rejects_invalid_utf8() {
+        let config = CString::new(r#"{"name": "strict"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let label = CString::new("human").unwrap();
+        let original = CString::new("Unchanged").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), original.as_ptr()), 0);
+        
+        let invalid = CString::new(vec![b'o', b'k', 0xC3, 0x28]).unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), invalid.as_ptr()), LettaErrorCode::InvalidArg as i32);
+        let message = letta_last_error_message();
+        let message_str = unsafe { CStr::from_ptr(message) }.to_str().unwrap().to_string();
+        letta_free_str(message);
+        assert!(message_str.contains("`value`") && message_str.contains("byte offset 2"), "{}", message_str);
+        
+        let block = letta_get_block(handle, label.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(block) }.to_str().unwrap(), "Unchanged");
+        letta_free_str(block);
+        
+        let invalid_msg = CString::new(vec![b'{', b'"', 0xFF, b'"', b'}']).unwrap();
+        let response = take_json(letta_converse(handle, invalid_msg.as_ptr()));
+        assert!(response["error"].as_str().unwrap().contains("byte offset 2"));
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidArg as i32);
+        assert_eq!(letta_get_message_count(handle), 0);
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_provider_configs() {
+        let toy = CString::new(r#"{"name": "toy", "provider": {"type": "toy", "deterministic": true}}"#).unwrap();
+        let handle = letta_create_agent(toy.as_ptr());
+        assert!(!handle.is_null());
+        assert_eq!(take_json(letta_get_config(handle))["model"], "toy");
+        letta_free_agent(handle);
+        
+        let malformed = CString::new(r#"{"provider": {"type": "openai", "model": 4}}"#).unwrap();
+        assert!(letta_create_agent(malformed.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidJson as i32);
+        
+        let anthropic = CString::new(
+            r#"{"provider": {"type": "anthropic", "api_key": "key", "model": "claude"}}"#
+        ).unwrap();
+        let handle = letta_create_agent(anthropic.as_ptr());
+        assert!(!handle.is_null());
+        assert_eq!(take_json(letta_get_config(handle))["model"], "claude");
+        letta_free_agent(handle);
+        
+        let dir = tempfile::TempDir::new().unwrap();
+        let log = dir.path().join("calls.jsonl");
+        let logged = CString::new(serde_json::json!({
+            "provider": {"type": "anthropic", "api_key": "key", "model": "claude", "log_requests": log},
+        }).to_string()).unwrap();
+        let handle = letta_create_agent(logged.as_ptr());
+        assert!(!handle.is_null());
+        assert!(log.exists());
+        letta_free_agent(handle);
+        
+        let azure = CString::new(r#"{"provider": {
+            "type": "openai", "api_key": "key", "model": "gpt-4o", "azure": true,
+            "base_url": "https://letta.openai.azure.com", "deployment": "letta-gpt", "api_version": "2024-10-21"
+        }}"#).unwrap();
+        let handle = letta_create_agent(azure.as_ptr());
+        assert!(!handle.is_null());
+        assert_eq!(take_json(letta_get_config(handle))["model"], "gpt-4o");
+        letta_free_agent(handle);
+        
+        let no_endpoint = CString::new(
+            r#"{"provider": {"type": "openai", "api_key": "key", "model": "gpt-4o", "azure": true}}"#
+        ).unwrap();
+        assert!(letta_create_agent(no_endpoint.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidArg as i32);
+        
+        let unknown = CString::new(r#"{"model": "mistral-large"}"#).unwrap();
+        assert!(letta_create_agent(unknown.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidArg as i32);
+        let message = letta_last_error_message();
+        assert!(unsafe { CStr::from_ptr(message) }.to_str().unwrap().contains("mistral-large"));
+        letta_free_str(message);
+        
+        let unimplemented = CString::new(
+            r#"{"provider": {"type": "letta", "endpoint": "https://api.letta.ai", "api_key": "key", "model": "letta"}}"#
+        ).unwrap();
+        assert!(letta_create_agent(unimplemented.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::ProviderError as i32);
+        let message = letta_last_error_message();
+        assert!(unsafe { CStr::from_ptr(message) }.to_str().unwrap().contains("not yet implemented"));
+        letta_free_str(message);
+    }
+    
+    #[test]
+    fn test_ffi_check_provider() {
+        let healthy = CString::new(r#"{"model": "toy"}"#).unwrap();
+        let handle = letta_create_agent(healthy.as_ptr());
+        assert_eq!(letta_check_provider(handle), 0);
+        letta_free_agent(handle);
+        
+        let failing = CString::new(r#"{"provider": {"type": "toy", "deterministic": true, "script": [{"error": "invalid API key"}]}}"#).unwrap();
+        let handle = letta_create_agent(failing.as_ptr());
+        assert_eq!(letta_check_provider(handle), LettaErrorCode::ProviderError as i32);
+        let message = letta_last_error_message();
+        assert!(unsafe { CStr::from_ptr(message) }.to_str().unwrap().contains("invalid API key"));
+        letta_free_str(message);
+        letta_free_agent(handle);
+        
+        assert_eq!(letta_check_provider(ptr::null_mut()), LettaErrorCode::InvalidArg as i32);
+    }
+    
+    #[test]
+    fn test_ffi_bad_config_error_code() {
+        let bad = CString::new("{not json").unwrap();
+        
+        assert!(letta_create_agent(bad.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidJson as i32);
+        
+        assert_eq!(letta_configure_sync(bad.as_ptr()), LettaErrorCode::InvalidJson as i32);
+        let message = letta_last_error_message();
+        assert!(!message.is_null());
+        letta_free_str(message);
+        
+        let config = CString::new(r#"{"name": "test"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_last_error_code(), 0);
+        assert!(letta_last_error_message().is_null());
+        
+        let label = CString::new("missing").unwrap();
+        assert!(letta_get_block(handle, label.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidArg as i32);
+        
+        letta_free_agent(handle);
+        
+        let mut stale = AgentHandle { index: usize::MAX, generation: 0, epoch: 0 };
+        let value = CString::new("value").unwrap();
+        assert_eq!(letta_set_block(&mut stale, label.as_ptr(), value.as_ptr()), LettaErrorCode::AgentNotFound as i32);
+    }
+    
+    /// Provider that panics mid-step, standing in for a bug anywhere below the boundary
+    struct PanickingProvider;
+    
+    #[async_trait::async_trait]
+    impl LlmProvider for PanickingProvider {
+        async fn complete(&self, _request: letta_core::CompletionRequest) -> letta_core::Result<letta_core::Completion> {
+            panic!("provider exploded");
+        }
+        
+        fn name(&self) -> &str {
+            "panicking"
+        }
+    }
+    
+    #[test]
+    fn test_ffi_panic_becomes_error_code() {
+        let handle = register_agent(Agent::new(AgentConfig::default(), Box::new(PanickingProvider))).unwrap();
+        
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        let response = take_json(letta_converse(handle, msg.as_ptr()));
+        assert!(response["error"].as_str().unwrap().contains("provider exploded"));
+        assert_eq!(letta_last_error_code(), LettaErrorCode::Panic as i32);
+        
+        // A panic while a global was locked leaves it usable
+        let _ = std::thread::spawn(|| {
+            let _agents = AGENTS.lock().unwrap();
+            panic!("poisoning the registry");
+        }).join();
+        assert!(AGENTS.is_poisoned());
+        
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Survived").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+        letta_free_agent(handle);
+    }
+    
+    /// Provider whose every call fails, as when the backend is unreachable
+    struct FailingProvider;
+    
+    #[async_trait::async_trait]
+    impl LlmProvider for FailingProvider {
+        async fn complete(&self, _request: letta_core::CompletionRequest) -> letta_core::Result<letta_core::Completion> {
+            Err(letta_core::ProviderError::new(letta_core::ProviderErrorKind::Transport, "connection refused").into())
+        }
+        
+        fn name(&self) -> &str {
+            "failing"
+        }
+    }
+    
+    #[test]
+    fn test_ffi_injected_provider_failure() {
+        let config = CString::new(r#"{"provider": {"type": "toy", "deterministic": false, "seed": 1, "fail_every_n": 2}}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert!(!handle.is_null());
+        
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        assert!(take_json(letta_converse(handle, msg.as_ptr())).get("error").is_none());
+        let response = take_json(letta_converse(handle, msg.as_ptr()));
+        assert_eq!(response["error"], "Provider error: Simulated failure on call 2 (every 2)");
+        assert_eq!(response["code"], LettaErrorCode::ProviderError as i32);
+        assert_eq!(response["error_code"], "provider.transport");
+        assert!(take_json(letta_converse(handle, msg.as_ptr())).get("error").is_none());
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_structured_errors() {
+        let handle = register_agent(Agent::new(AgentConfig::default(), Box::new(FailingProvider))).unwrap();
+        
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        let response = take_json(letta_converse(handle, msg.as_ptr()));
+        assert_eq!(response["error"], "Provider error: connection refused");
+        assert_eq!(response["code"], LettaErrorCode::ProviderError as i32);
+        assert_eq!(response["error_code"], "provider.transport");
+        assert_eq!(response["retryable"], true);
+        assert_eq!(response["kind"], "transport");
+        assert_eq!(response["details"]["status"], serde_json::Value::Null);
+        assert_eq!(take_json(letta_last_error()), response);
+        
+        let label = CString::new("persona").unwrap();
+        let value = CString::new("x".repeat(10_000)).unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), LettaErrorCode::InvalidArg as i32);
+        let error = take_json(letta_last_error());
+        assert_eq!(error["error_code"], "memory.limit_exceeded");
+        assert_eq!(error["retryable"], false);
+        assert_eq!(error["details"]["label"], "persona");
+        assert_eq!(error["details"]["len"], 10_000);
+        
+        // Errors raised by the boundary itself have no core code
+        let missing = CString::new("missing").unwrap();
+        assert!(letta_get_block(handle, missing.as_ptr()).is_null());
+        assert!(take_json(letta_last_error()).get("error_code").is_none());
+        
+        let value = CString::new("Fine").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+        assert!(letta_last_error().is_null());
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_error_codes_by_variant() {
+        let code = |e: letta_core::LettaError| FfiError::from(e).code;
+        assert_eq!(code(letta_core::LettaError::ToolExecution("failed".into())), LettaErrorCode::ToolError);
+        assert_eq!(code(letta_core::LettaError::Memory("Block 'x' not found".into())), LettaErrorCode::MemoryError);
+        assert_eq!(code(letta_core::LettaError::ContextOverflow { current: 10, max: 5 }), LettaErrorCode::ContextOverflow);
+        assert_eq!(code(letta_core::LettaError::Unknown("?".into())), LettaErrorCode::Internal);
+        assert_eq!(code(letta_core::LettaError::InvalidConfig("bad".into())), LettaErrorCode::InvalidArg);
+    }
+    
+    #[test]
+    fn test_ffi_create_agent_from_af() {
+        let config = CString::new(r#"{"name": "original"}"#).unwrap();
+        let source = letta_create_agent(config.as_ptr());
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Shared by a friend").unwrap();
+        assert_eq!(letta_set_block(source, label.as_ptr(), value.as_ptr()), 0);
+        let mut af = take_json(letta_export_af(source));
+        letta_free_agent(source);
+        
+        af["agents"][0]["agent_state"]["tools"] = json!(["web_search"]);
+        af["groups"] = json!([{"id": "g1", "name": "team", "members": []}]);
+        let af_json = CString::new(af.to_string()).unwrap();
+        let provider = CString::new(r#"{"type": "toy", "deterministic": true}"#).unwrap();
+        let handle = letta_create_agent_from_af(af_json.as_ptr(), provider.as_ptr());
+        assert!(!handle.is_null());
+        
+        let block = letta_get_block(handle, label.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(block) }.to_str().unwrap(), "Shared by a friend");
+        letta_free_str(block);
+        
+        let report = take_json(letta_last_import_report());
+        assert_eq!(report["agent_id"], af["agents"][0]["id"]);
+        assert_eq!(report["warnings"], json!([
+            {"kind": "skipped_section", "section": "groups"},
+            {"kind": "unknown_tool", "name": "web_search"},
+        ]));
+        letta_free_agent(handle);
+        
+        // Without a provider the model named in the file is used
+        let handle = letta_create_agent_from_af(af_json.as_ptr(), ptr::null());
+        assert!(!handle.is_null());
+        letta_free_agent(handle);
+        
+        af["agents"] = json!([af["agents"][0], af["agents"][0]]);
+        let multi = CString::new(af.to_string()).unwrap();
+        assert!(letta_create_agent_from_af(multi.as_ptr(), ptr::null()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::MultipleAgents as i32);
+        assert!(letta_last_import_report().is_null());
+        
+        let garbage = CString::new("{not an agent file").unwrap();
+        assert!(letta_create_agent_from_af(garbage.as_ptr(), ptr::null()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidJson as i32);
+    }
+    
+    #[test]
+    fn test_ffi_shutdown_and_reinit() {
+        // Shutdown invalidates every handle in the process, so the cycle runs
+        // in a child process rather than alongside the other tests
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::shutdown_cycle", "--exact", "--ignored", "--test-threads=1"])
+            .env("LETTA_SHUTDOWN_CYCLE", "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    
+    #[test]
+    #[ignore = "run in its own process by test_ffi_shutdown_and_reinit"]
+    fn shutdown_cycle() {
+        if std::env::var_os("LETTA_SHUTDOWN_CYCLE").is_none() {
+            return;
+        }
+        
+        let dir = tempfile::TempDir::new().unwrap();
+        let first = CString::new(dir.path().join("first.db").to_str().unwrap()).unwrap();
+        let second = CString::new(dir.path().join("second.db").to_str().unwrap()).unwrap();
+        
+        assert_eq!(letta_init_storage(first.as_ptr()), 0);
+        let config = CString::new(r#"{"name": "survivor"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Saved on shutdown").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+        let id = take_json(letta_get_agent_info(handle))["id"].as_str().unwrap().to_string();
+        
+        assert_eq!(letta_shutdown(true), 0);
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), LettaErrorCode::ShutDown as i32);
+        assert!(letta_create_agent(config.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::ShutDown as i32);
+        assert!(letta_list_agents().is_null());
+        
+        // A fresh start on another database
+        assert_eq!(letta_init_storage(second.as_ptr()), 0);
+        let fresh = letta_create_agent(config.as_ptr());
+        assert!(!fresh.is_null());
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), LettaErrorCode::ShutDown as i32);
+        let listed = take_json(letta_list_agents());
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["persisted"], false);
+        letta_free_agent(handle);
+        
+        // Saving on drop outlives a shutdown without `flush`
+        assert_eq!(letta_set_flush_on_drop(fresh, 2000), 0);
+        let flushed = CString::new("Flushed on shutdown").unwrap();
+        assert_eq!(letta_set_block(fresh, label.as_ptr(), flushed.as_ptr()), 0);
+        let fresh_id = take_json(letta_get_agent_info(fresh))["id"].as_str().unwrap().to_string();
+        assert_eq!(letta_shutdown(false), 0);
+        letta_free_agent(fresh);
+        
+        let reopened_block = |db: &CString, id: &str| {
+            assert_eq!(letta_init_storage(db.as_ptr()), 0);
+            let c_id = CString::new(id).unwrap();
+            let reopened = letta_open_agent(c_id.as_ptr(), ptr::null());
+            assert!(!reopened.is_null());
+            let block = letta_get_block(reopened, label.as_ptr());
+            let value = unsafe { CStr::from_ptr(block) }.to_str().unwrap().to_string();
+            letta_free_str(block);
+            letta_free_agent(reopened);
+            value
+        };
+        assert_eq!(reopened_block(&second, &fresh_id), "Flushed on shutdown");
+        
+        // The flushed agent is in the first database
+        assert_eq!(reopened_block(&first, &id), "Saved on shutdown");
+    }
+    
+    #[test]
+    fn test_ffi_flush_on_drop() {
+        let _guard = init_test_storage();
+        let config = CString::new(r#"{"name": "unsaved"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_set_flush_on_drop(handle, 2000), 0);
+        let msg = CString::new(r#"{"text": "Keep this"}"#).unwrap();
+        take_json(letta_converse(handle, msg.as_ptr()));
+        let id = CString::new(take_json(letta_get_agent_info(handle))["id"].as_str().unwrap()).unwrap();
+        letta_free_agent(handle);
+        
+        let reopened = letta_open_agent(id.as_ptr(), ptr::null());
+        assert!(!reopened.is_null());
+        assert_eq!(take_json(letta_get_agent_info(reopened))["message_count"], 2);
+        
+        // Deleting from storage is not undone by the drop
+        assert_eq!(letta_set_flush_on_drop(reopened, 2000), 0);
+        assert_eq!(letta_delete_agent(reopened, true), 0);
+        assert!(letta_open_agent(id.as_ptr(), ptr::null()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::AgentNotFound as i32);
+        
+        // Turned off again, nothing is written
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_set_flush_on_drop(handle, 2000), 0);
+        assert_eq!(letta_set_flush_on_drop(handle, 0), 0);
+        let id = CString::new(take_json(letta_get_agent_info(handle))["id"].as_str().unwrap()).unwrap();
+        letta_free_agent(handle);
+        assert!(letta_open_agent(id.as_ptr(), ptr::null()).is_null());
+    }
+    
+    #[test]
+    fn test_ffi_init_storage_json_config() {
+        let _guard = GLOBALS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("configured.db");
+        
+        let config = CString::new(json!({ "path": path, "max_connections": 2 }).to_string()).unwrap();
+        assert_eq!(letta_init_storage(config.as_ptr()), 0);
+        assert!(path.exists());
+        
+        let malformed = CString::new(r#"{"path": "#).unwrap();
+        assert_eq!(letta_init_storage(malformed.as_ptr()), LettaErrorCode::InvalidJson as i32);
+        
+        let encrypted = json!({ "path": dir.path().join("encrypted.db"), "encryption_key": "secret" });
+        let encrypted = CString::new(encrypted.to_string()).unwrap();
+        if cfg!(feature = "sqlcipher") {
+            assert_eq!(letta_init_storage(encrypted.as_ptr()), 0);
+        } else {
+            assert_eq!(letta_init_storage(encrypted.as_ptr()), LettaErrorCode::StorageError as i32);
+        }
+    }
+    
+    #[test]
+    fn test_ffi_get_usage() {
+        let config = CString::new(r#"{"name": "searcher"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r##"{"text": "#DO_SEARCH"}"##).unwrap();
+        let search = take_json(letta_converse(handle, msg.as_ptr()));
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        let hello = take_json(letta_converse(handle, msg.as_ptr()));
+        
+        let usage = take_json(letta_get_usage(handle));
+        let total = |result: &serde_json::Value| result["usage"]["total_tokens"].as_u64().unwrap();
+        assert_eq!(usage["total"]["total_tokens"].as_u64().unwrap(), total(&search) + total(&hello));
+        assert_eq!(usage["by_provider"]["toy"], usage["total"]);
+        
+        letta_free_agent(handle);
+        assert!(letta_get_usage(ptr::null_mut()).is_null());
+    }
+    
+    #[test]
+    fn test_ffi_agent_stats() {
+        let config = CString::new(r#"{"name": "counted"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let folder = CString::new("diary").unwrap();
+        let text = CString::new("Ran 10km today").unwrap();
+        assert_eq!(letta_append_archival(handle, folder.as_ptr(), text.as_ptr()), 0);
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        let reply = take_json(letta_converse(handle, msg.as_ptr()));
+        
+        let stats = take_json(letta_agent_stats(handle));
+        assert_eq!(stats["name"], "counted");
+        assert_eq!(stats["messages"]["by_role"]["user"], 1);
+        assert_eq!(stats["usage"]["total_tokens"], reply["usage"]["total_tokens"]);
+        assert_eq!(stats["archival"]["folders"]["diary"], 1);
+        assert!(stats["memory"]["fullness"].as_f64().unwrap() > 0.0);
+        assert_eq!(stats["metrics"]["steps"], 1);
+        assert_eq!(stats["metrics"]["usage"], reply["usage"]);
+        assert!(stats["metrics"]["step_latency"]["max_ms"].as_f64().unwrap() >= 0.0);
+        
+        let info = take_json(letta_get_agent_info(handle));
+        assert_eq!(info["message_count"], stats["messages"]["count"]);
+        let persona = |blocks: &serde_json::Value, field: &str| blocks.as_array().unwrap().iter()
+            .find(|b| b["label"] == "persona").unwrap()[field].clone();
+        assert_eq!(persona(&info["memory"], "chars"), persona(&stats["memory"]["blocks"], "used"));
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_storage_stats_and_maintain() {
+        let _guard = init_test_storage();
+        let config = CString::new(r#"{"name": "measured"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        take_json(letta_converse(handle, msg.as_ptr()));
+        assert_eq!(letta_save_agent(handle), 0);
+        
+        let stats = take_json(letta_storage_stats());
+        assert_eq!(stats["agent_count"], 1);
+        assert_eq!(stats["per_agent"][0]["name"], "measured");
+        assert_eq!(stats["per_agent"][0]["message_count"], stats["message_count"]);
+        assert!(stats["file_size_bytes"].as_u64().unwrap() > 0);
+        
+        let report = take_json(letta_storage_maintain(ptr::null()));
+        assert!(report["reclaimed_bytes"].is_u64());
+        let options = CString::new(r#"{"analyze": true}"#).unwrap();
+        let report = take_json(letta_storage_maintain(options.as_ptr()));
+        assert_eq!(report["reclaimed_bytes"], 0);
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_export_all() {
+        let _guard = init_test_storage();
+        let config = CString::new(r#"{"name": "exported"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_save_agent(handle), 0);
+        
+        let dir = tempfile::tempdir().unwrap();
+        let c_dir = CString::new(dir.path().join("out").to_str().unwrap()).unwrap();
+        let report = take_json(letta_export_all(c_dir.as_ptr()));
+        assert_eq!(report["written"].as_array().unwrap().len(), 1);
+        assert!(report["skipped"].as_array().unwrap().is_empty());
+        let path = report["written"][0].as_str().unwrap();
+        let af = letta_core::AgentFile::from_reader(std::fs::File::open(path).unwrap()).unwrap();
+        assert_eq!(af.agents[0].name, "exported");
+        
+        let empty = CString::new("").unwrap();
+        assert!(letta_export_all(empty.as_ptr()).is_null());
+        letta_free_agent(handle);
+    }
+    
+    /// Serialises tests that replace the process-wide STORAGE or SYNC_CLIENT
+    static GLOBALS_TEST_LOCK: Mutex<()> = Mutex::new(());
+    
+    /// Point STORAGE at a fresh database that outlives the calling test
+    fn init_test_storage() -> std::sync::MutexGuard<'static, ()> {
+        let guard = GLOBALS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = tempfile::TempDir::new().unwrap().keep().join("agents.db");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(letta_init_storage(c_path.as_ptr()), 0);
+        guard
+    }
+    
+    #[test]
+    fn test_ffi_init_from_config() {
+        let _guard = GLOBALS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::TempDir::new().unwrap().keep();
+        let path = dir.join("letta.toml");
+        std::fs::write(&path, r#"
+            [storage]
+            path = "configured.db"
+            
+            [provider]
+            type = "toy"
+            deterministic = true
+            
+            [agent]
+            system_prompt = "Configured prompt"
+            max_messages = 42
+            
+            [tools]
+            disabled = ["archival_insert"]
+        "#).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(letta_init_from_config(c_path.as_ptr()), 0);
+        assert!(dir.join("configured.db").exists());
+        
+        let config = CString::new(r#"{"max_messages": 7}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        *lock(&APP_CONFIG) = None;
+        let agent_config = with_agent(handle, |agent| Ok(agent.config.clone())).unwrap();
+        assert_eq!(agent_config.system_prompt, "Configured prompt");
+        assert_eq!(agent_config.max_messages, 7);
+        assert_eq!(agent_config.model, "toy");
+        assert_eq!(agent_config.disabled_tools, vec!["archival_insert".to_string()]);
+        letta_free_agent(handle);
+        
+        std::fs::write(&path, "[storage]\nbusy_timeout_ms = -5\n").unwrap();
+        assert_eq!(letta_init_from_config(c_path.as_ptr()), LettaErrorCode::InvalidArg as i32);
+        let error = take_json(letta_last_error());
+        assert_eq!(error["error_code"], "config.invalid");
+        let message = error["error"].as_str().unwrap();
+        assert!(message.contains("letta.toml") && message.contains("`storage.busy_timeout_ms`"), "{}", message);
+        assert!(lock(&APP_CONFIG).is_none());
+    }
+    
+    /// Take ownership of a returned string and parse it as JSON
+    fn take_json(s: *mut c_char) -> serde_json::Value {
+        assert!(!s.is_null());
+        let json = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        letta_free_str(s);
+        serde_json::from_str(&json).unwrap()
+    }
+    
+    #[test]
+    fn test_ffi_list_agents_and_info() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "listed"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        
+        let info = take_json(letta_get_agent_info(handle));
+        assert_eq!(info["name"], "listed");
+        assert!(info["memory"].as_array().unwrap().iter().any(|b| b["label"] == "persona"));
+        
+        let agents = take_json(letta_list_agents());
+        let listed = agents.as_array().unwrap().iter()
+            .find(|a| a["id"] == info["id"])
+            .unwrap();
+        assert_eq!(listed["persisted"], false);
+        assert_eq!(listed["message_count"], 0);
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_delete_agent() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "doomed"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let info = take_json(letta_get_agent_info(handle));
+        let id = info["id"].as_str().unwrap().to_string();
+        
+        let storage = STORAGE.lock().unwrap().clone().unwrap();
+        let mut stored = letta_storage::StoredAgent::new("doomed", "prompt");
+        stored.id = id.clone();
+        storage.create_agent(&stored).unwrap();
+        
+        assert_eq!(letta_delete_agent(handle, true), 0);
+        assert!(storage.get_agent(&id).unwrap().is_none());
+        
+        let c_id = CString::new(id).unwrap();
+        assert_eq!(letta_delete_agent_by_id(c_id.as_ptr()), LettaErrorCode::AgentNotFound as i32);
+    }
+    
+    #[test]
+    fn test_ffi_trash_and_restore_agent() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "trashed"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_save_agent(handle), 0);
+        let id = take_json(letta_get_agent_info(handle))["id"].as_str().unwrap().to_string();
+        let c_id = CString::new(id.clone()).unwrap();
+        
+        assert_eq!(letta_trash_agent(c_id.as_ptr()), 0);
+        assert!(take_json(letta_list_agents()).as_array().unwrap().iter().all(|a| a["id"] != id.as_str()));
+        assert!(letta_get_agent_info(handle).is_null());
+        assert_eq!(letta_trash_agent(c_id.as_ptr()), LettaErrorCode::AgentNotFound as i32);
+        assert!(letta_open_agent(c_id.as_ptr(), ptr::null()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::AgentNotFound as i32);
+        
+        assert_eq!(letta_restore_agent(c_id.as_ptr()), 0);
+        assert!(take_json(letta_list_agents()).as_array().unwrap().iter().any(|a| a["id"] == id.as_str()));
+        assert_eq!(letta_restore_agent(c_id.as_ptr()), LettaErrorCode::AgentNotFound as i32);
+    }
+
+    #[test]
+    fn test_ffi_trash_waits_for_running_step_without_blocking_others() {
+        let _storage = init_test_storage();
+
+        let config = CString::new(r#"{"name": "busy"}"#).unwrap();
+        let busy = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_save_agent(busy), 0);
+        let id = take_json(letta_get_agent_info(busy))["id"].as_str().unwrap().to_string();
+        let config = CString::new(r#"{"name": "bystander"}"#).unwrap();
+        let bystander = letta_create_agent(config.as_ptr());
+
+        // Stands in for a step still running on the agent being trashed
+        let agent = shared_agent(busy).unwrap();
+        let guard = agent.blocking_lock();
+        let trashing = std::thread::spawn(move || {
+            let c_id = CString::new(id).unwrap();
+            letta_trash_agent(c_id.as_ptr())
+        });
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert!(!trashing.is_finished());
+        assert_eq!(take_json(letta_get_agent_info(bystander))["name"], "bystander");
+
+        drop(guard);
+        assert_eq!(trashing.join().unwrap(), 0);
+        assert!(letta_get_agent_info(busy).is_null());
+        // The step's agent no longer writes to storage
+        assert!(agent.blocking_lock().attached_storage().is_none());
+
+        letta_free_agent(bystander);
+    }
+
+    #[test]
+    fn test_ffi_get_messages_pagination() {
+        let config = CString::new(r#"{"name": "chatty"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        
+        for text in ["one", "two", "three"] {
+            let msg = CString::new(json!({ "text": text }).to_string()).unwrap();
+            letta_free_str(letta_converse(handle, msg.as_ptr()));
+        }
+        let count = letta_get_message_count(handle);
+        assert!(count >= 6);
+        
+        let all = take_json(letta_get_messages(handle, 0, ptr::null()));
+        assert_eq!(all.as_array().unwrap().len() as i64, count);
+        
+        let newest = take_json(letta_get_messages(handle, 2, ptr::null()));
+        let newest = newest.as_array().unwrap();
+        assert_eq!(newest.len(), 2);
+        assert_eq!(newest[1]["id"], all[count as usize - 1]["id"]);
+        
+        let cursor = CString::new(newest[0]["id"].as_str().unwrap()).unwrap();
+        let older = take_json(letta_get_messages(handle, 2, cursor.as_ptr()));
+        assert_eq!(older[1]["id"], all[count as usize - 3]["id"]);
+        assert_eq!(older[0]["role"], all[count as usize - 4]["role"]);
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_send_and_reply_only() {
+        let config = CString::new(r#"{"name": "patient"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        // Another test may have initialised storage; keep the history in memory
+        shared_agent(handle).unwrap().blocking_lock().detach_storage();
+        
+        let msg = CString::new(r#"{"text": "Pick up milk"}"#).unwrap();
+        let id_ptr = letta_send_only(handle, msg.as_ptr());
+        assert!(!id_ptr.is_null());
+        let id = unsafe { CStr::from_ptr(id_ptr) }.to_str().unwrap().to_string();
+        letta_free_str(id_ptr);
+        assert_eq!(letta_get_message_count(handle), 1);
+        
+        let query = CString::new("milk").unwrap();
+        let found = take_json(letta_search_conversation(handle, query.as_ptr(), 5));
+        assert_eq!(found.as_array().unwrap().len(), 1);
+        assert_eq!(found[0]["id"], id.as_str());
+        
+        let reply = take_json(letta_reply_only(handle));
+        assert!(reply.get("error").is_none());
+        assert!(reply["text"].is_string());
+        assert!(reply["tool_trace"].is_array());
+        assert_eq!(reply["stop_reason"], "completed");
+        assert!(letta_get_message_count(handle) > 1);
+        
+        // Held by another request
+        let agent = shared_agent(handle).unwrap();
+        let guard = agent.blocking_lock();
+        assert!(letta_send_only(handle, msg.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::Busy as i32);
+        let busy = take_json(letta_reply_only(handle));
+        assert_eq!(busy["error"].as_str().unwrap(), "Agent is busy with another request");
+        assert_eq!(letta_clear_messages(handle), LettaErrorCode::Busy as i32);
+        drop(guard);
+        
+        assert_eq!(letta_clear_messages(handle), 0);
+        assert_eq!(letta_get_message_count(handle), 0);
+        
+        letta_free_agent(handle);
+        let mut stale = AgentHandle { index: usize::MAX, generation: 0, epoch: 0 };
+        assert_eq!(letta_clear_messages(&mut stale), LettaErrorCode::AgentNotFound as i32);
+    }
+    
+    #[test]
+    fn test_ffi_regenerate() {
+        let config = CString::new(r#"{"name": "redo", "provider": {"type": "toy", "deterministic": true, "script": [{"text": "First."}, {"text": "Second."}]}}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        // Another test may have initialised storage; keep the history in memory
+        shared_agent(handle).unwrap().blocking_lock().detach_storage();
+        let nothing = take_json(letta_regenerate(handle));
+        assert_eq!(nothing["code"], LettaErrorCode::InvalidArg as i32);
+        
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        assert_eq!(take_json(letta_converse(handle, msg.as_ptr()))["text"], "First.");
+        let reply = take_json(letta_regenerate(handle));
+        assert_eq!(reply["text"], "Second.");
+        assert_eq!(reply["stop_reason"], "completed");
+        assert_eq!(letta_get_message_count(handle), 2);
+        
+        letta_free_agent(handle);
+        assert!(letta_regenerate(ptr::null_mut()).is_null());
+    }
+    
+    #[test]
+    fn test_ffi_fork_agent() {
+        let config = CString::new(r#"{"name": "brancher"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        // Another test may have initialised storage; keep the history in memory
+        shared_agent(handle).unwrap().blocking_lock().detach_storage();
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+        letta_free_str(letta_converse(handle, msg.as_ptr()));
+        letta_free_str(letta_converse(handle, msg.as_ptr()));
+        let parent_id = take_json(letta_get_agent_info(handle))["id"].clone();
+        let first_reply = shared_agent(handle).unwrap().blocking_lock().state.messages.messages[1].id.clone();
+        
+        let c_message = CString::new(first_reply).unwrap();
+        let fork = letta_fork_agent(handle, c_message.as_ptr());
+        assert!(!fork.is_null());
+        assert_eq!(letta_get_message_count(fork), 2);
+        assert_eq!(letta_get_message_count(handle), 4);
+        let forked = shared_agent(fork).unwrap();
+        assert_eq!(forked.blocking_lock().state.metadata["parent_agent_id"], parent_id);
+        
+        let missing = CString::new("no-such-message").unwrap();
+        assert!(letta_fork_agent(handle, missing.as_ptr()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::InvalidArg as i32);
+        letta_free_agent(fork);
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_save_and_open_agent() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "keeper"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Prefers short answers").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+        let msg = CString::new(r#"{"text": "Remember me"}"#).unwrap();
+        letta_free_str(letta_converse(handle, msg.as_ptr()));
+        assert_eq!(letta_save_agent(handle), 0);
+        
+        let info = take_json(letta_get_agent_info(handle));
+        let history = take_json(letta_get_messages(handle, 0, ptr::null()));
+        letta_free_agent(handle);
+        
+        let c_id = CString::new(info["id"].as_str().unwrap()).unwrap();
+        let overrides = CString::new(r#"{"temperature": 0.2}"#).unwrap();
+        let reopened = letta_open_agent(c_id.as_ptr(), overrides.as_ptr());
+        assert!(!reopened.is_null());
+        
+        let block = letta_get_block(reopened, label.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(block) }.to_str().unwrap(), "Prefers short answers");
+        letta_free_str(block);
+        assert_eq!(take_json(letta_get_messages(reopened, 0, ptr::null())), history);
+        let reopened_info = take_json(letta_get_agent_info(reopened));
+        assert_eq!(reopened_info["name"], "keeper");
+        assert!((reopened_info["config"]["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+        letta_free_agent(reopened);
+        
+        let missing = CString::new("no-such-agent").unwrap();
+        assert!(letta_open_agent(missing.as_ptr(), ptr::null()).is_null());
+        assert_eq!(letta_last_error_code(), LettaErrorCode::AgentNotFound as i32);
+    }
+    
+    #[test]
+    fn test_ffi_agents_save_each_step() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "diarist"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r#"{"text": "Dear diary"}"#).unwrap();
+        let reply = take_json(letta_converse(handle, msg.as_ptr()));
+        assert!(reply.get("warnings").is_none(), "{}", reply);
+        
+        // Stored without letta_save_agent
+        let id = take_json(letta_get_agent_info(handle))["id"].as_str().unwrap().to_string();
+        let history = take_json(letta_get_messages(handle, 0, ptr::null()));
+        assert_eq!(storage().unwrap().count_messages(&id).unwrap(), history.as_array().unwrap().len());
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_imported_agents_save_each_step() {
+        let _storage = init_test_storage();
+        
+        let config = CString::new(r#"{"name": "shared"}"#).unwrap();
+        let source = letta_create_agent(config.as_ptr());
+        let af = CString::new(take_json(letta_export_af(source)).to_string()).unwrap();
+        letta_free_agent(source);
+        
+        let handle = letta_create_agent_from_af(af.as_ptr(), ptr::null());
+        assert!(!handle.is_null());
+        let msg = CString::new(r#"{"text": "Hello from the import"}"#).unwrap();
+        assert!(take_json(letta_converse(handle, msg.as_ptr())).get("error").is_none());
+        let id = CString::new(take_json(letta_get_agent_info(handle))["id"].as_str().unwrap()).unwrap();
+        let history = take_json(letta_get_messages(handle, 0, ptr::null()));
+        letta_free_agent(handle);
+        
+        // Stored without letta_save_agent
+        let reopened = letta_open_agent(id.as_ptr(), ptr::null());
+        assert!(!reopened.is_null());
+        assert_eq!(take_json(letta_get_messages(reopened, 0, ptr::null())), history);
+        letta_free_agent(reopened);
+    }
+    
+    extern "C" fn ignore_event(_event_json: *const c_char, _user_data: *mut c_void) {}
+    
+    #[test]
+    fn test_ffi_sync_callback_registration() {
+        let _lock = GLOBALS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let c_config = CString::new(r#"{"endpoint": "http://127.0.0.1:9"}"#).unwrap();
+        assert_eq!(letta_configure_sync(c_config.as_ptr()), 0);
+        
+        assert_eq!(letta_set_sync_callback(Some(ignore_event), ptr::null_mut()), 0);
+        assert!(SYNC_CALLBACK_TASK.lock().unwrap().is_some());
+        
+        assert_eq!(letta_set_sync_callback(None, ptr::null_mut()), 0);
+        assert!(SYNC_CALLBACK_TASK.lock().unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_ffi_sync_proxy() {
+        let _lock = GLOBALS_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let c_config = CString::new(r#"{"endpoint": "https://api.letta.ai", "proxy": "http://proxy.corp:3128", "no_proxy": "localhost"}"#).unwrap();
+        assert_eq!(letta_configure_sync(c_config.as_ptr()), 0);
+        let client = sync_client().unwrap();
+        assert_eq!(client.config().proxy.as_deref(), Some("http://proxy.corp:3128"));
+        assert_eq!(client.config().no_proxy.as_deref(), Some("localhost"));
+        
+        let bad = CString::new(r#"{"proxy": "not a url"}"#).unwrap();
+        assert_eq!(letta_configure_sync(bad.as_ptr()), LettaErrorCode::NetworkError as i32);
+    }
+    
+    fn configure_test_sync(endpoint: &str, conflict_resolution: &str) {
+        let config = json!({ "endpoint": endpoint, "conflict_resolution": conflict_resolution }).to_string();
+        let c_config = CString::new(config).unwrap();
+        assert_eq!(letta_configure_sync(c_config.as_ptr()), 0);
+    }
+    
+    #[test]
+    fn test_ffi_sync_with_cloud() {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::{method, path};
+        
+        let _storage = init_test_storage();
+        let server = RUNTIME.block_on(MockServer::start());
+        RUNTIME.block_on(Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "agent_file": null,
+                "cloud_version": 1,
+                "conflicts": [],
+                "status": "ok"
+            })))
+            .mount(&server));
+        
+        let config = CString::new(r#"{"name": "synced"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Likes tea").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+        
+        // Not configured yet
+        *SYNC_CLIENT.lock().unwrap() = None;
+        assert_eq!(letta_sync_with_cloud(handle), LettaErrorCode::NotInitialized as i32);
+        
+        configure_test_sync(&server.uri(), "last-write-wins");
+        assert_eq!(letta_sync_with_cloud(handle), 0);
+        
+        let agent_id = with_agent(handle, |agent| Ok(agent.state.id.clone())).unwrap();
+        let metadata = storage().unwrap().get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        assert_eq!(metadata.cloud_version, 1);
+        assert_eq!(metadata.sync_status, SyncStatus::Synced);
+        let block = letta_get_block(handle, label.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(block) }.to_str().unwrap(), "Likes tea");
+        letta_free_str(block);
+        
+        // The server is gone
+        configure_test_sync("http://127.0.0.1:9", "last-write-wins");
+        assert_eq!(letta_sync_with_cloud(handle), LettaErrorCode::NetworkError as i32);
+        
+        letta_free_agent(handle);
+    }
+    
+    #[test]
+    fn test_ffi_sync_with_cloud_conflict() {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+        use wiremock::matchers::method;
+        
+        let _storage = init_test_storage();
+        let server = RUNTIME.block_on(MockServer::start());
+        RUNTIME.block_on(Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "agent_file": null,
+                "cloud_version": 2,
+                "conflicts": [{
+                    "field": "blocks.human",
+                    "local_value": "Alice",
+                    "cloud_value": "Alicia",
+                    "resolution": "manual"
+                }],
+                "status": "ok"
+            })))
+            .mount(&server));
+        configure_test_sync(&server.uri(), "manual");
+        
+        let config = CString::new(r#"{"name": "contested"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        assert_eq!(letta_sync_status(ptr::null_mut()), ptr::null_mut());
+        
+        let read_status = || {
+            let status = letta_sync_status(handle);
+            let json: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(status) }.to_str().unwrap()).unwrap();
+            letta_free_str(status);
+            json
+        };
+        let never_synced = read_status();
+        assert_eq!(never_synced["status"], "pending");
+        assert!(never_synced["last_sync_at"].is_null());
+        assert_eq!(never_synced["conflicts"], json!([]));
+        
+        assert_eq!(letta_sync_with_cloud(handle), LettaErrorCode::SyncConflict as i32);
+        
+        let contested = read_status();
+        assert_eq!(contested["status"], "conflict");
+        assert_eq!(contested["cloud_version"], 2);
+        assert_eq!(contested["conflicts"][0]["field"], "blocks.human");
+        assert!(contested["last_sync_at"].is_string());
+        
+        letta_free_agent(handle);
+    }
+}
@@ -0,0 +1,256 @@
+use std::cell::Cell;
+use std::ffi::CString;
+use std::fmt::{self, Write as _};
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Mutex, Once};
+use std::thread::{self, JoinHandle};
+
+use lazy_static::lazy_static;
+use tracing::field::{Field, Visit};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+use crate::error::{catch_panic, set_last_error, status, FfiError, FfiResult, LettaErrorCode};
+use crate::lock;
+
+/// Receives one log record; `target` and `message` are valid only for the duration of the call
+pub type LettaLogCallback = extern "C" fn(level: i32, target: *const c_char, message: *const c_char, user_data: *mut c_void);
+
+/// Severity passed to `letta_set_log_callback` and the log callback
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LettaLogLevel {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+/// Records waiting for a slow callback; further records are dropped
+const LOG_QUEUE_CAPACITY: usize = 1024;
+
+/// Least severe level forwarded; above `Error` while no callback is set
+static MIN_LEVEL: AtomicI32 = AtomicI32::new(i32::MAX);
+
+static INSTALL: Once = Once::new();
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref FORWARDER: Mutex<Option<LogForwarder>> = Mutex::new(None);
+}
+
+thread_local! {
+    /// Set on the delivery thread, so logging from inside the callback is dropped
+    static DELIVERING: Cell<bool> = const { Cell::new(false) };
+}
+
+struct LogRecord {
+    level: LettaLogLevel,
+    target: String,
+    message: String,
+}
+
+/// Queue feeding the thread that calls the host, so a slow callback never
+/// stalls the code doing the logging
+struct LogForwarder {
+    sender: SyncSender<LogRecord>,
+    thread: JoinHandle<()>,
+}
+
+/// Callback plus host context, moved onto the delivery thread
+struct HostCallback {
+    callback: LettaLogCallback,
+    user_data: *mut c_void,
+}
+
+// The host is responsible for `user_data` being usable from the delivery thread
+unsafe impl Send for HostCallback {}
+
+fn log_level(level: &Level) -> LettaLogLevel {
+    match *level {
+        Level::TRACE => LettaLogLevel::Trace,
+        Level::DEBUG => LettaLogLevel::Debug,
+        Level::INFO => LettaLogLevel::Info,
+        Level::WARN => LettaLogLevel::Warn,
+        Level::ERROR => LettaLogLevel::Error,
+    }
+}
+
+fn forwarded(level: &Level) -> bool {
+    log_level(level) as i32 >= MIN_LEVEL.load(Ordering::Relaxed)
+}
+
+/// The event's message followed by its other fields as `key=value`
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+struct HostLayer;
+
+impl<S: Subscriber> Layer<S> for HostLayer {
+    // The level can change at any time, so never let callsites cache a decision
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        forwarded(metadata.level())
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if DELIVERING.with(Cell::get) || !forwarded(event.metadata().level()) {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let record = LogRecord {
+            level: log_level(event.metadata().level()),
+            target: event.metadata().target().to_string(),
+            message: visitor.message + &visitor.fields,
+        };
+        if let Some(forwarder) = lock(&FORWARDER).as_ref() {
+            let _ = forwarder.sender.try_send(record);
+        }
+    }
+}
+
+/// Install the forwarding subscriber process-wide, once
+fn install() -> FfiResult<()> {
+    INSTALL.call_once(|| {
+        let subscriber = tracing_subscriber::registry().with(HostLayer);
+        INSTALLED.store(tracing::subscriber::set_global_default(subscriber).is_ok(), Ordering::SeqCst);
+    });
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return Err(FfiError::new(LettaErrorCode::Unsupported, "Another tracing subscriber is already installed"));
+    }
+    Ok(())
+}
+
+/// Stop `forwarder`, delivering what it has queued first
+fn stop(forwarder: Option<LogForwarder>) {
+    if let Some(LogForwarder { sender, thread }) = forwarder {
+        drop(sender);
+        if thread.thread().id() != thread::current().id() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Forward the library's log output at `min_level` (a `LettaLogLevel`) and
+/// above to `callback`, replacing any callback set before.
+///
+/// Records are queued and delivered in order on a dedicated thread, never
+/// concurrently; if the callback falls behind, records beyond the queue are
+/// dropped rather than slowing the library down. Logging from inside the
+/// callback is ignored.
+#[no_mangle]
+pub extern "C" fn letta_set_log_callback(
+    min_level: i32,
+    callback: Option<extern "C" fn(level: i32, target: *const c_char, message: *const c_char, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> i32 {
+    catch_panic(set_last_error, || status(set_log_callback(min_level, callback, user_data)))
+}
+
+fn set_log_callback(min_level: i32, callback: Option<LettaLogCallback>, user_data: *mut c_void) -> FfiResult<()> {
+    let callback = callback.ok_or_else(|| FfiError::invalid_arg("Null log callback"))?;
+    if !(LettaLogLevel::Trace as i32..=LettaLogLevel::Error as i32).contains(&min_level) {
+        return Err(FfiError::invalid_arg(format!("Unknown log level {}", min_level)));
+    }
+    install()?;
+
+    let (sender, receiver) = mpsc::sync_channel::<LogRecord>(LOG_QUEUE_CAPACITY);
+    let host = HostCallback { callback, user_data };
+    let thread = thread::Builder::new()
+        .name("letta-log".to_string())
+        .spawn(move || {
+            let host = host;
+            DELIVERING.with(|delivering| delivering.set(true));
+            for record in receiver {
+                let target = CString::new(record.target).unwrap_or_default();
+                let message = CString::new(record.message.replace('\0', " ")).unwrap_or_default();
+                (host.callback)(record.level as i32, target.as_ptr(), message.as_ptr(), host.user_data);
+            }
+        })
+        .map_err(|e| FfiError::new(LettaErrorCode::Unsupported, format!("Cannot start log thread: {}", e)))?;
+
+    let previous = lock(&FORWARDER).replace(LogForwarder { sender, thread });
+    MIN_LEVEL.store(min_level, Ordering::SeqCst);
+    stop(previous);
+    Ok(())
+}
+
+/// Stop forwarding logs. Records already queued are delivered before this
+/// returns; no calls are made afterwards.
+#[no_mangle]
+pub extern "C" fn letta_clear_log_callback() -> i32 {
+    catch_panic(set_last_error, || {
+        MIN_LEVEL.store(i32::MAX, Ordering::SeqCst);
+        let previous = lock(&FORWARDER).take();
+        stop(previous);
+        status(Ok(()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::time::Duration;
+
+    type Captured = (i32, String, String);
+
+    extern "C" fn capture(level: i32, target: *const c_char, message: *const c_char, user_data: *mut c_void) {
+        let tx = unsafe { &*(user_data as *const mpsc::Sender<Captured>) };
+        let text = |s: *const c_char| unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        let _ = tx.send((level, text(target), text(message)));
+    }
+
+    #[test]
+    fn test_log_callback_captures_migrations() {
+        assert_eq!(letta_set_log_callback(LettaLogLevel::Info as i32, None, std::ptr::null_mut()), LettaErrorCode::InvalidArg as i32);
+
+        let (tx, rx) = mpsc::channel::<Captured>();
+        let tx = Box::new(tx);
+        let user_data = &*tx as *const mpsc::Sender<Captured> as *mut c_void;
+        assert_eq!(letta_set_log_callback(LettaLogLevel::Info as i32, Some(capture), user_data), 0);
+
+        letta_storage::Storage::memory().unwrap();
+        let (level, target, _) = loop {
+            let record = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            if record.2.starts_with("Applying migration") {
+                break record;
+            }
+        };
+        assert_eq!(level, LettaLogLevel::Info as i32);
+        assert!(target.starts_with("letta_storage"));
+
+        // Replacing the callback stops the first one
+        let (quiet_tx, quiet_rx) = mpsc::channel::<Captured>();
+        let quiet_tx = Box::new(quiet_tx);
+        let quiet_data = &*quiet_tx as *const mpsc::Sender<Captured> as *mut c_void;
+        assert_eq!(letta_set_log_callback(LettaLogLevel::Error as i32, Some(capture), quiet_data), 0);
+        while rx.try_recv().is_ok() {}
+        letta_storage::Storage::memory().unwrap();
+        assert_eq!(letta_clear_log_callback(), 0);
+        assert!(rx.try_recv().is_err());
+        assert!(quiet_rx.try_iter().all(|(_, _, message)| !message.starts_with("Applying migration")));
+    }
+}
@@ -0,0 +1,103 @@
+/// Generational arena behind agent handles.
+///
+/// Freed slots are reused, and each reuse bumps the slot's generation so a
+/// stale handle to an earlier occupant no longer resolves.
+pub(crate) struct Registry<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+impl<T> Registry<T> {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new(), free: Vec::new() }
+    }
+
+    /// Store `value`, returning its `(index, generation)` key
+    pub fn insert(&mut self, value: T) -> (usize, u32) {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.value = Some(value);
+                (index, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, value: Some(value) });
+                (self.slots.len() - 1, 0)
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, index: usize, generation: u32) -> Option<&mut T> {
+        self.slots.get_mut(index)
+            .filter(|slot| slot.generation == generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn remove(&mut self, index: usize, generation: u32) -> Option<T> {
+        let slot = self.slots.get_mut(index).filter(|slot| slot.generation == generation)?;
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+        Some(value)
+    }
+
+    /// Remove every value matching `predicate`
+    pub fn remove_where(&mut self, mut predicate: impl FnMut(&T) -> bool) {
+        for index in 0..self.slots.len() {
+            let generation = self.slots[index].generation;
+            if self.slots[index].value.as_ref().is_some_and(&mut predicate) {
+                self.remove(index, generation);
+            }
+        }
+    }
+
+    /// Every value with its `(index, generation)` key
+    pub fn entries(&self) -> impl Iterator<Item = (usize, u32, &T)> {
+        self.slots.iter().enumerate()
+            .filter_map(|(index, slot)| slot.value.as_ref().map(|value| (index, slot.generation, value)))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    /// Number of slots ever allocated, live or free
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_key_rejected_after_reuse() {
+        let mut registry = Registry::new();
+        let (index, generation) = registry.insert("first");
+        assert_eq!(registry.remove(index, generation), Some("first"));
+
+        let (reused, next_generation) = registry.insert("second");
+        assert_eq!(reused, index);
+        assert_ne!(next_generation, generation);
+        assert!(registry.get_mut(index, generation).is_none());
+        assert!(registry.remove(index, generation).is_none());
+        assert_eq!(registry.get_mut(reused, next_generation), Some(&mut "second"));
+    }
+
+    #[test]
+    fn test_create_free_cycles_reuse_slots() {
+        let mut registry = Registry::new();
+        for i in 0..10_000 {
+            let (index, generation) = registry.insert(i);
+            registry.remove(index, generation);
+        }
+        assert_eq!(registry.capacity(), 1);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use tokio::task::AbortHandle;
+
+use letta_core::CancellationToken;
+
+use crate::error::{catch_panic, clear_last_error, set_last_error, status, FfiError, FfiResult};
+use crate::stream::{CallbackSink, LettaStreamCallback};
+use crate::cancel::{RunningStep, StepKey};
+use crate::{c_str_arg, lock, message_text, shared_agent, AgentHandle, RUNTIME};
+
+lazy_static! {
+    /// In-flight `letta_converse_async` requests by id
+    static ref REQUESTS: Mutex<HashMap<i64, Request>> = Mutex::new(HashMap::new());
+}
+
+/// How to cancel an in-flight request
+enum Request {
+    /// Waiting for the agent: dropping the task leaves it untouched
+    Queued(AbortHandle),
+    /// Holding the agent: its step is cancelled like `letta_cancel`'s, so the
+    /// history isn't left half way through a step
+    Running(CancellationToken),
+}
+
+impl Request {
+    fn cancel(self) {
+        match self {
+            Request::Queued(task) => task.abort(),
+            Request::Running(token) => token.cancel(),
+        }
+    }
+}
+
+static NEXT_REQUEST_ID: AtomicI64 = AtomicI64::new(1);
+
+/// Converse with the agent without blocking the calling thread.
+///
+/// Returns a positive request id, or a negative error code if the request
+/// could not be queued. `on_complete` is then invoked exactly once, from an
+/// internal runtime thread, with the `letta_converse` result JSON or
+/// `{"error": ..., "code": ...}` (including when cancelled). Requests for the
+/// same agent run one at a time in the order they were made.
+#[no_mangle]
+pub extern "C" fn letta_converse_async(
+    handle: *mut AgentHandle,
+    user_msg_json: *const c_char,
+    on_complete: Option<extern "C" fn(result_json: *const c_char, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> i64 {
+    catch_panic(|e| set_last_error(e) as i64, || {
+        let msg_str = c_str_arg!(user_msg_json, "user_msg_json", |e| set_last_error(e) as i64);
+        match converse_async(handle, &msg_str, on_complete, user_data) {
+            Ok(request_id) => {
+                clear_last_error();
+                request_id
+            }
+            Err(e) => set_last_error(e) as i64,
+        }
+    })
+}
+
+fn converse_async(
+    handle: *mut AgentHandle,
+    msg_str: &str,
+    on_complete: Option<LettaStreamCallback>,
+    user_data: *mut c_void,
+) -> FfiResult<i64> {
+    let on_complete = on_complete.ok_or_else(|| FfiError::invalid_arg("Null completion callback"))?;
+    let text = message_text(msg_str)?;
+    let agent = shared_agent(handle)?;
+    let key = StepKey::of(handle);
+
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let mut sink = CallbackSink::new(on_complete, user_data, None);
+
+    // Held across the spawn so the task cannot finish before it is registered
+    let mut requests = lock(&REQUESTS);
+    let task = RUNTIME.spawn(async move {
+        let mut agent = agent.lock_owned().await;
+        let step = RunningStep::start(key);
+        if let Some(request) = lock(&REQUESTS).get_mut(&request_id) {
+            *request = Request::Running(step.token.clone());
+        }
+        let result = agent.step_with_cancel(text, &step.token).await;
+        drop(step);
+        drop(agent);
+        lock(&REQUESTS).remove(&request_id);
+        sink.finish(result);
+    });
+    requests.insert(request_id, Request::Queued(task.abort_handle()));
+
+    Ok(request_id)
+}
+
+/// Cancel a request made with `letta_converse_async`. Its callback receives
+/// `LETTA_ERROR_CODE_CANCELLED` unless the step already completed. A request
+/// whose step has started is stopped as `letta_cancel` stops it, keeping the
+/// user message in the history. Fails with `LETTA_ERROR_CODE_INVALID_ARG` for
+/// unknown or finished requests.
+#[no_mangle]
+pub extern "C" fn letta_cancel_request(request_id: i64) -> i32 {
+    catch_panic(set_last_error, || {
+        let request = lock(&REQUESTS).remove(&request_id);
+        status(match request {
+            Some(request) => {
+                request.cancel();
+                Ok(())
+            }
+            None => Err(FfiError::invalid_arg(format!("No pending request {}", request_id))),
+        })
+    })
+}
+
+/// Cancel every in-flight request; their callbacks report cancellation
+pub(crate) fn cancel_all_requests() {
+    for (_, request) in lock(&REQUESTS).drain() {
+        request.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::{CStr, CString};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use letta_core::{Agent, AgentConfig, MessageRole};
+    use crate::error::LettaErrorCode;
+    use crate::test_support::Stalled;
+    use crate::{letta_create_agent, letta_free_agent, register_agent};
+
+    extern "C" fn forward_result(result_json: *const c_char, user_data: *mut c_void) {
+        let tx = unsafe { &*(user_data as *const mpsc::Sender<serde_json::Value>) };
+        let json = unsafe { CStr::from_ptr(result_json) }.to_str().unwrap();
+        let _ = tx.send(serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    fn test_ffi_converse_async_serializes_per_agent() {
+        let config = CString::new(r#"{"name": "async"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+
+        let (tx, rx) = mpsc::channel::<serde_json::Value>();
+        let tx = Box::new(tx);
+        let user_data = &*tx as *const mpsc::Sender<serde_json::Value> as *mut c_void;
+
+        let first = letta_converse_async(handle, msg.as_ptr(), Some(forward_result), user_data);
+        let second = letta_converse_async(handle, msg.as_ptr(), Some(forward_result), user_data);
+        assert!(first > 0 && second > first);
+
+        for _ in 0..2 {
+            let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(result.get("error").is_none(), "{}", result);
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(letta_cancel_request(first), LettaErrorCode::InvalidArg as i32);
+
+        letta_free_agent(handle);
+    }
+
+    #[test]
+    fn test_ffi_cancel_request() {
+        let config = CString::new(r#"{"name": "cancelled"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+
+        let (tx, rx) = mpsc::channel::<serde_json::Value>();
+        let tx = Box::new(tx);
+        let user_data = &*tx as *const mpsc::Sender<serde_json::Value> as *mut c_void;
+
+        // Hold the agent so the request stays queued until cancelled
+        let agent = shared_agent(handle).unwrap();
+        let guard = agent.blocking_lock();
+        let request = letta_converse_async(handle, msg.as_ptr(), Some(forward_result), user_data);
+        assert_eq!(letta_cancel_request(request), 0);
+        drop(guard);
+
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result["code"], LettaErrorCode::Cancelled as i32);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        letta_free_agent(handle);
+    }
+
+    #[test]
+    fn test_ffi_cancel_running_request() {
+        let handle = register_agent(Agent::new(AgentConfig::default(), Box::new(Stalled))).unwrap();
+        let msg = CString::new(r#"{"text": "Write me an essay"}"#).unwrap();
+
+        let (tx, rx) = mpsc::channel::<serde_json::Value>();
+        let tx = Box::new(tx);
+        let user_data = &*tx as *const mpsc::Sender<serde_json::Value> as *mut c_void;
+
+        let request = letta_converse_async(handle, msg.as_ptr(), Some(forward_result), user_data);
+        let started = std::time::Instant::now();
+        while !matches!(lock(&REQUESTS).get(&request), Some(Request::Running(_))) {
+            assert!(started.elapsed() < Duration::from_secs(10), "step never started");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(letta_cancel_request(request), 0);
+
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result["code"], LettaErrorCode::Cancelled as i32);
+        // The step unwound instead of being dropped half way
+        let agent = shared_agent(handle).unwrap();
+        let messages = &agent.blocking_lock().state.messages.messages;
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0].role, MessageRole::User));
+
+        letta_free_agent(handle);
+    }
+}
@@ -0,0 +1,179 @@
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+
+use serde_json::json;
+
+use letta_core::agent::StepResult;
+
+use crate::error::{catch_panic, set_last_error, status, FfiError, FfiResult, LettaErrorCode};
+use crate::cancel::{RunningStep, StepKey};
+use crate::{busy, c_str_arg, message_text, shared_agent, step_json, AgentHandle, RUNTIME};
+
+/// Receives each stream event as a JSON string, valid only for the duration of the call
+pub type LettaStreamCallback = extern "C" fn(event_json: *const c_char, user_data: *mut c_void);
+
+/// Delivers events to a host callback and guarantees exactly one final
+/// result, even if the task running the step is cancelled or panics.
+pub(crate) struct CallbackSink {
+    callback: LettaStreamCallback,
+    user_data: *mut c_void,
+    /// `type` tag added to the final result, if any
+    final_type: Option<&'static str>,
+    done: bool,
+}
+
+// The host is responsible for `user_data` being usable from the runtime thread
+unsafe impl Send for CallbackSink {}
+
+impl CallbackSink {
+    pub fn new(callback: LettaStreamCallback, user_data: *mut c_void, final_type: Option<&'static str>) -> Self {
+        Self { callback, user_data, final_type, done: false }
+    }
+
+    fn emit(&mut self, event: &serde_json::Value) {
+        if self.done {
+            return;
+        }
+        if let Ok(c_json) = CString::new(event.to_string()) {
+            (self.callback)(c_json.as_ptr(), self.user_data);
+        }
+    }
+
+    pub fn finish(&mut self, result: letta_core::Result<StepResult>) {
+        let result = match result {
+            Ok(step_result) => step_json(&step_result),
+            Err(e) => error_event(FfiError::from(e)),
+        };
+        self.finish_with(result);
+    }
+
+    fn finish_with(&mut self, mut result: serde_json::Value) {
+        if let Some(final_type) = self.final_type {
+            result["type"] = json!(final_type);
+        }
+        self.emit(&result);
+        self.done = true;
+    }
+}
+
+impl Drop for CallbackSink {
+    fn drop(&mut self) {
+        if !self.done {
+            let error = if std::thread::panicking() {
+                FfiError::new(LettaErrorCode::Panic, "Step panicked before completing")
+            } else {
+                FfiError::new(LettaErrorCode::Cancelled, "Request cancelled")
+            };
+            self.finish_with(error_event(error));
+        }
+    }
+}
+
+fn error_event(error: FfiError) -> serde_json::Value {
+    error.to_json()
+}
+
+/// Converse with the agent, streaming the reply.
+///
+/// Returns an error code immediately if the stream cannot start (invalid
+/// handle or JSON, null callback, or the agent is already busy); otherwise
+/// returns 0 and runs the step in the background.
+///
+/// Threading contract: `callback` is invoked on an internal runtime thread,
+/// never concurrently, with events in order:
+/// `{"type":"text_delta","text":...}` and
+/// `{"type":"tool_call","tool":...,"args":...,"result":...}`, followed by
+/// exactly one `{"type":"done",...}` carrying either the `letta_converse`
+/// result (`text`, `tool_trace`, `usage`) or `error` and `code`. No calls are
+/// made after `done`, so `user_data` only needs to stay valid until then.
+/// Other calls on this agent fail with `LETTA_ERROR_CODE_BUSY` until `done`.
+#[no_mangle]
+pub extern "C" fn letta_converse_stream(
+    handle: *mut AgentHandle,
+    user_msg_json: *const c_char,
+    callback: Option<extern "C" fn(event_json: *const c_char, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> i32 {
+    catch_panic(set_last_error, || {
+        let msg_str = c_str_arg!(user_msg_json, "user_msg_json", set_last_error);
+        status(converse_stream(handle, &msg_str, callback, user_data))
+    })
+}
+
+fn converse_stream(
+    handle: *mut AgentHandle,
+    msg_str: &str,
+    callback: Option<LettaStreamCallback>,
+    user_data: *mut c_void,
+) -> FfiResult<()> {
+    let callback = callback.ok_or_else(|| FfiError::invalid_arg("Null stream callback"))?;
+    let text = message_text(msg_str)?;
+    let mut agent = shared_agent(handle)?.try_lock_owned().map_err(|_| busy())?;
+    let step = RunningStep::start(StepKey::of(handle));
+
+    let mut sink = CallbackSink::new(callback, user_data, Some("done"));
+    RUNTIME.spawn(async move {
+        let result = agent.step_stream_with_cancel(text, |event| {
+            if let Ok(event) = serde_json::to_value(&event) {
+                sink.emit(&event);
+            }
+        }, &step.token).await;
+        // Release the agent first so the host can use it as soon as `done` arrives
+        drop(step);
+        drop(agent);
+        sink.finish(result);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::{letta_create_agent, letta_free_agent, letta_set_block};
+
+    extern "C" fn forward_event(event_json: *const c_char, user_data: *mut c_void) {
+        let tx = unsafe { &*(user_data as *const mpsc::Sender<serde_json::Value>) };
+        let json = unsafe { std::ffi::CStr::from_ptr(event_json) }.to_str().unwrap();
+        let _ = tx.send(serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    fn test_ffi_converse_stream() {
+        let config = CString::new(r#"{"name": "streamer"}"#).unwrap();
+        let handle = letta_create_agent(config.as_ptr());
+        let msg = CString::new(r#"{"text": "Hello"}"#).unwrap();
+
+        assert_eq!(
+            letta_converse_stream(handle, msg.as_ptr(), None, std::ptr::null_mut()),
+            LettaErrorCode::InvalidArg as i32,
+        );
+
+        let (tx, rx) = mpsc::channel::<serde_json::Value>();
+        let tx = Box::new(tx);
+        let user_data = &*tx as *const mpsc::Sender<serde_json::Value> as *mut c_void;
+        assert_eq!(letta_converse_stream(handle, msg.as_ptr(), Some(forward_event), user_data), 0);
+
+        let mut streamed = String::new();
+        let done = loop {
+            let event = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            match event["type"].as_str().unwrap() {
+                "text_delta" => streamed.push_str(event["text"].as_str().unwrap()),
+                "done" => break event,
+                _ => {}
+            }
+        };
+        assert!(done.get("error").is_none());
+        assert_eq!(done["text"].as_str().unwrap(), streamed);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // The agent is released once `done` has been delivered
+        let label = CString::new("human").unwrap();
+        let value = CString::new("Streams a lot").unwrap();
+        assert_eq!(letta_set_block(handle, label.as_ptr(), value.as_ptr()), 0);
+
+        letta_free_agent(handle);
+    }
+}
@@ -0,0 +1,15 @@
+use letta_core::{Completion, CompletionRequest, LlmProvider};
+
+/// Provider that never answers, so a step runs until it is cancelled
+pub(crate) struct Stalled;
+
+#[async_trait::async_trait]
+impl LlmProvider for Stalled {
+    async fn complete(&self, _request: CompletionRequest) -> letta_core::Result<Completion> {
+        std::future::pending().await
+    }
+
+    fn name(&self) -> &str {
+        "stalled"
+    }
+}
@@ -0,0 +1,26 @@
+//! With the `llama-cpp` feature, compile `src/llama_shim.c` and link
+//! llama.cpp. Set `LLAMA_CPP_DIR` to an install prefix holding
+//! `include/llama.h` and `lib/libllama.*`; otherwise the compiler's and
+//! linker's default paths are searched.
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/llama_shim.c");
+    println!("cargo:rerun-if-env-changed=LLAMA_CPP_DIR");
+    
+    #[cfg(feature = "llama-cpp")]
+    build_shim();
+}
+
+#[cfg(feature = "llama-cpp")]
+fn build_shim() {
+    let prefix = std::env::var_os("LLAMA_CPP_DIR").map(std::path::PathBuf::from);
+    
+    let mut build = cc::Build::new();
+    build.file("src/llama_shim.c");
+    if let Some(prefix) = &prefix {
+        build.include(prefix.join("include"));
+        println!("cargo:rustc-link-search=native={}", prefix.join("lib").display());
+    }
+    build.compile("letta_llama_shim");
+    println!("cargo:rustc-link-lib=llama");
+}
@@ -1,13 +1,42 @@
 use async_trait::async_trait;
 use letta_core::{
-    provider::{LlmProvider, CompletionRequest, Completion, TokenUsage},
+    provider::{LlmProvider, CompletionRequest, Completion, Embedder, EmbeddingConfig, ProviderCapabilities},
     error::{Result, LettaError},
 };
+#[cfg(feature = "llama-cpp")]
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "llama-cpp")]
+use letta_core::provider::TokenUsage;
+use std::time::Duration;
+#[cfg(feature = "llama-cpp")]
+use std::time::Instant;
+
+#[cfg(feature = "llama-cpp")]
+mod model;
 
+#[cfg(feature = "llama-cpp")]
+use model::{Model, Session};
+
+/// Reply length when the request doesn't set `max_tokens`
+#[cfg(feature = "llama-cpp")]
+const DEFAULT_MAX_TOKENS: usize = 512;
+
+/// Sampling temperature when the request doesn't set one
+#[cfg(feature = "llama-cpp")]
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// Runs a GGUF model in-process with llama.cpp. The model is loaded on first
+/// use and shared by every later request; inference runs on the blocking pool
+/// of the calling tokio runtime. Built without the `llama-cpp` feature, every
+/// call fails with a `Provider` error saying so.
+#[cfg_attr(not(feature = "llama-cpp"), allow(dead_code))] // model_path and n_threads are only read by the llama.cpp integration
 pub struct LlamaProvider {
     model_path: String,
     context_size: usize,
     n_threads: usize,
+    timeout: Option<Duration>,
+    #[cfg(feature = "llama-cpp")]
+    model: Arc<Mutex<Option<Arc<Model>>>>,
 }
 
 impl LlamaProvider {
@@ -16,24 +45,173 @@ impl LlamaProvider {
             model_path,
             context_size,
             n_threads,
+            timeout: None,
+            #[cfg(feature = "llama-cpp")]
+            model: Arc::new(Mutex::new(None)),
+        }
+    }
+    
+    /// Stop generating once a completion has run for `timeout`, failing it
+    /// with `LettaError::Timeout`. Checked between tokens, so the blocking
+    /// thread is freed too rather than left running after the caller gives up.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    
+    /// Run `f` with the model on the blocking pool, loading it first if needed
+    #[cfg(feature = "llama-cpp")]
+    async fn with_model<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Model, usize, usize) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let slot = self.model.clone();
+        let path = self.model_path.clone();
+        let (context_size, n_threads) = (self.context_size, self.n_threads);
+        let task = tokio::task::spawn_blocking(move || {
+            let model = {
+                // Held while loading, so concurrent first requests load once
+                let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+                match &*slot {
+                    Some(model) => model.clone(),
+                    None => slot.insert(Arc::new(Model::load(&path)?)).clone(),
+                }
+            };
+            f(&model, context_size, n_threads)
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(LettaError::Provider("llama.cpp task was cancelled".into())),
+        }
+    }
+}
+
+#[cfg(feature = "llama-cpp")]
+impl LlamaProvider {
+    async fn complete_local(&self, request: CompletionRequest) -> Result<Completion> {
+        let (started, timeout) = (Instant::now(), self.timeout);
+        self.with_model(move |model, context_size, n_threads| {
+            generate(model, &request, context_size, n_threads, started, timeout)
+        }).await
+    }
+    
+    async fn embed_local(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.with_model(move |model, context_size, n_threads| embed_texts(model, &texts, context_size, n_threads)).await
+    }
+}
+
+#[cfg(not(feature = "llama-cpp"))]
+impl LlamaProvider {
+    async fn complete_local(&self, _request: CompletionRequest) -> Result<Completion> {
+        Err(not_built())
+    }
+    
+    async fn embed_local(&self, _texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        Err(not_built())
+    }
+}
+
+#[cfg(not(feature = "llama-cpp"))]
+fn not_built() -> LettaError {
+    LettaError::Provider("letta-provider-llama was built without the `llama-cpp` feature".into())
+}
+
+/// Decode the prompt, then sample until an end-of-generation token, a stop
+/// sequence, the token limit, or the end of the context. Fails with
+/// `Timeout` once `timeout` has passed since `started`, and with `Cancelled`
+/// once `request.cancel` is cancelled.
+#[cfg(feature = "llama-cpp")]
+fn generate(
+    model: &Model,
+    request: &CompletionRequest,
+    context_size: usize,
+    n_threads: usize,
+    started: Instant,
+    timeout: Option<Duration>,
+) -> Result<Completion> {
+    let mut prompt = model.tokenize(&request.prompt)?;
+    if prompt.len() >= context_size {
+        return Err(LettaError::ContextOverflow { current: prompt.len(), max: context_size });
+    }
+    let max_tokens = request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS).min(context_size - prompt.len());
+    
+    let temperature = request.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+    let mut session = Session::new(model, context_size, n_threads, false, temperature)?;
+    session.decode(&mut prompt)?;
+    
+    let mut text = Vec::new();
+    let mut generated = 0;
+    while generated < max_tokens {
+        request.cancel.check()?;
+        if let Some(timeout) = timeout.filter(|timeout| started.elapsed() >= *timeout) {
+            return Err(LettaError::Timeout { elapsed_ms: timeout.as_millis() as u64 });
+        }
+        let mut token = session.sample();
+        if model.is_eog(token) {
+            break;
         }
+        let piece_start = text.len();
+        model.push_piece(token, &mut text);
+        generated += 1;
+        if let Some(stop) = find_stop(&text, piece_start, &request.stop) {
+            text.truncate(stop);
+            break;
+        }
+        session.decode(std::slice::from_mut(&mut token))?;
     }
+    
+    Ok(Completion {
+        text: String::from_utf8_lossy(&text).into_owned(),
+        tool_calls: vec![],
+        request_heartbeat: false,
+        usage: TokenUsage {
+            prompt_tokens: prompt.len(),
+            completion_tokens: generated,
+            total_tokens: prompt.len() + generated,
+            cost_usd: None,
+        },
+    })
+}
+
+/// Where the first stop sequence in `text` starts, looking only at those
+/// that end in the piece from `piece_start`, since earlier text was checked
+/// as it arrived
+#[cfg(feature = "llama-cpp")]
+fn find_stop(text: &[u8], piece_start: usize, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .map(String::as_bytes)
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| {
+            let from = (piece_start + 1).saturating_sub(stop.len());
+            text[from..].windows(stop.len()).position(|window| window == stop).map(|i| from + i)
+        })
+        .min()
+}
+
+#[cfg(feature = "llama-cpp")]
+fn embed_texts(model: &Model, texts: &[String], context_size: usize, n_threads: usize) -> Result<Vec<Vec<f32>>> {
+    texts.iter().map(|text| {
+        let mut tokens = model.tokenize(text)?;
+        if tokens.len() > context_size {
+            return Err(LettaError::ContextOverflow { current: tokens.len(), max: context_size });
+        }
+        let mut session = Session::new(model, context_size, n_threads, true, 0.0)?;
+        session.decode(&mut tokens)?;
+        session.embedding()
+    }).collect()
 }
 
 #[async_trait]
 impl LlmProvider for LlamaProvider {
     async fn complete(&self, request: CompletionRequest) -> Result<Completion> {
-        // TODO: Integrate with llama.cpp
-        // For now, return a stub response
-        Err(LettaError::Provider(
-            "Llama provider not yet implemented. Use 'toy' provider for testing.".to_string()
-        ))
+        self.complete_local(request).await
     }
     
-    async fn embed(&self, _texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
-        Err(LettaError::Provider(
-            "Llama embeddings not yet implemented".to_string()
-        ))
+    /// Mean-pooled embeddings from the model itself. Models without an
+    /// embedding output fail with a `Provider` error.
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.embed_local(texts).await
     }
     
     fn name(&self) -> &str {
@@ -43,36 +221,84 @@ impl LlmProvider for LlamaProvider {
     fn max_tokens(&self) -> usize {
         self.context_size
     }
+    
+    /// Plain text completion, so tool schemas would only be ignored
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities { supports_embeddings: true, ..Default::default() }
+    }
 }
 
-// Future integration with llama.cpp C API
-#[cfg(feature = "llama-cpp")]
-mod ffi {
-    use libc::{c_char, c_float, c_int};
+/// Context for sentence-embedding models, which rarely take more than
+/// 512 tokens
+const EMBEDDING_CONTEXT_SIZE: usize = 512;
+
+/// Embeds text with a GGUF sentence-embedding model (such as all-MiniLM or
+/// nomic-embed) through llama.cpp, so semantic search over archival memory
+/// works without a network. Give it to an agent with `Agent::with_embedder`.
+pub struct LlamaEmbedder {
+    model: LlamaProvider,
+    name: String,
+}
+
+impl LlamaEmbedder {
+    pub fn new(model_path: String, context_size: usize, n_threads: usize) -> Self {
+        let name = format!("llama:{}", model_path);
+        Self { model: LlamaProvider::new(model_path, context_size, n_threads), name }
+    }
+    
+    /// The embedder for `EmbeddingConfig::Local`, using every core
+    pub fn from_config(config: &EmbeddingConfig) -> Result<Self> {
+        match config {
+            EmbeddingConfig::Local { model_path } => {
+                let n_threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+                Ok(Self::new(model_path.clone(), EMBEDDING_CONTEXT_SIZE, n_threads))
+            }
+            other => Err(LettaError::InvalidConfig(format!("Not a local embedding model: {:?}", other))),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for LlamaEmbedder {
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        self.model.embed_local(texts).await
+    }
     
-    #[repr(C)]
-    pub struct LlamaContext {
-        _private: [u8; 0],
+    /// `llama:` and the model path
+    fn name(&self) -> &str {
+        &self.name
     }
+}
+
+#[cfg(all(test, not(feature = "llama-cpp")))]
+mod tests {
+    use super::*;
     
-    extern "C" {
-        pub fn llama_init_from_file(path: *const c_char) -> *mut LlamaContext;
-        pub fn llama_free(ctx: *mut LlamaContext);
-        pub fn llama_eval(
-            ctx: *mut LlamaContext,
-            tokens: *const c_int,
-            n_tokens: c_int,
-            n_past: c_int,
-            n_threads: c_int,
-        ) -> c_int;
-        pub fn llama_sample_top_p_top_k(
-            ctx: *mut LlamaContext,
-            last_n_tokens: *const c_int,
-            last_n_size: c_int,
-            top_k: c_int,
-            top_p: c_float,
-            temp: c_float,
-            repeat_penalty: c_float,
-        ) -> c_int;
-    }
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn test_without_llama_cpp_says_so() {
+        let provider = LlamaProvider::new("model.gguf".into(), 2048, 4);
+        let request = CompletionRequest {
+            prompt: "Hello".into(),
+            messages: vec![],
+            tools: vec![],
+            temperature: None,
+            max_tokens: None,
+            stream: false,
+            stop: vec![],
+            cancel: Default::default(),
+        };
+        for error in [provider.complete(request).await.unwrap_err(), provider.embed(vec!["Hello".into()]).await.unwrap_err()] {
+            assert!(matches!(&error, LettaError::Provider(e) if e.message.contains("`llama-cpp` feature")), "{}", error);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_embedder_from_config() {
+        let config = EmbeddingConfig::Local { model_path: "minilm.gguf".into() };
+        let embedder = LlamaEmbedder::from_config(&config).unwrap();
+        assert_eq!(embedder.name(), "llama:minilm.gguf");
+        assert!(matches!(embedder.embed(vec!["Hello".into()]).await, Err(LettaError::Provider(_))));
+        
+        assert!(matches!(LlamaEmbedder::from_config(&EmbeddingConfig::Toy), Err(LettaError::InvalidConfig(_))));
+    }
+}
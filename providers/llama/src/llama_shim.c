@@ -0,0 +1,125 @@
+/*
+ * A narrow layer over llama.h, so the Rust side only passes pointers and
+ * integers and never depends on the layout of llama.cpp's parameter structs,
+ * which change between releases. Written against the llama_vocab API
+ * (llama.cpp b4500 and later).
+ */
+
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#include "llama.h"
+
+struct letta_llama_session {
+    struct llama_context *ctx;
+    struct llama_sampler *sampler;
+};
+
+static void init_backend(void) {
+    static bool initialised = false;
+    if (!initialised) {
+        llama_backend_init();
+        initialised = true;
+    }
+}
+
+struct llama_model *letta_llama_load(const char *path) {
+    init_backend();
+    struct llama_model_params params = llama_model_default_params();
+    return llama_model_load_from_file(path, params);
+}
+
+void letta_llama_free_model(struct llama_model *model) {
+    llama_model_free(model);
+}
+
+/* A fresh context, so each completion starts from an empty cache. A
+ * temperature of zero or below samples greedily. */
+struct letta_llama_session *letta_llama_session_new(
+    struct llama_model *model,
+    uint32_t n_ctx,
+    int32_t n_threads,
+    bool embeddings,
+    float temperature,
+    uint32_t seed
+) {
+    struct llama_context_params params = llama_context_default_params();
+    params.n_ctx = n_ctx;
+    params.n_batch = n_ctx;
+    params.n_threads = n_threads;
+    params.n_threads_batch = n_threads;
+    params.embeddings = embeddings;
+    if (embeddings) {
+        params.pooling_type = LLAMA_POOLING_TYPE_MEAN;
+    }
+
+    struct llama_context *ctx = llama_init_from_model(model, params);
+    if (ctx == NULL) {
+        return NULL;
+    }
+
+    struct llama_sampler *sampler = llama_sampler_chain_init(llama_sampler_chain_default_params());
+    if (temperature > 0.0f) {
+        llama_sampler_chain_add(sampler, llama_sampler_init_temp(temperature));
+        llama_sampler_chain_add(sampler, llama_sampler_init_dist(seed));
+    } else {
+        llama_sampler_chain_add(sampler, llama_sampler_init_greedy());
+    }
+
+    struct letta_llama_session *session = malloc(sizeof *session);
+    if (session == NULL) {
+        llama_sampler_free(sampler);
+        llama_free(ctx);
+        return NULL;
+    }
+    session->ctx = ctx;
+    session->sampler = sampler;
+    return session;
+}
+
+void letta_llama_session_free(struct letta_llama_session *session) {
+    llama_sampler_free(session->sampler);
+    llama_free(session->ctx);
+    free(session);
+}
+
+/* Number of tokens written, or minus the number needed if `n_max` is too small */
+int32_t letta_llama_tokenize(
+    const struct llama_model *model,
+    const char *text,
+    int32_t text_len,
+    int32_t *tokens,
+    int32_t n_max
+) {
+    const struct llama_vocab *vocab = llama_model_get_vocab(model);
+    return llama_tokenize(vocab, text, text_len, tokens, n_max, true, false);
+}
+
+/* Evaluate `tokens` after everything decoded so far; 0 on success */
+int32_t letta_llama_decode(struct letta_llama_session *session, int32_t *tokens, int32_t n_tokens) {
+    return llama_decode(session->ctx, llama_batch_get_one(tokens, n_tokens));
+}
+
+int32_t letta_llama_sample(struct letta_llama_session *session) {
+    return llama_sampler_sample(session->sampler, session->ctx, -1);
+}
+
+bool letta_llama_is_eog(const struct llama_model *model, int32_t token) {
+    return llama_vocab_is_eog(llama_model_get_vocab(model), token);
+}
+
+/* Bytes of the token's text written to `buf`, or minus the number needed */
+int32_t letta_llama_piece(const struct llama_model *model, int32_t token, char *buf, int32_t len) {
+    return llama_token_to_piece(llama_model_get_vocab(model), token, buf, len, 0, false);
+}
+
+int32_t letta_llama_n_embd(const struct llama_model *model) {
+    return llama_model_n_embd(model);
+}
+
+/* Mean-pooled embedding of everything decoded, `letta_llama_n_embd` floats
+ * owned by the session, or NULL if the model can't produce one */
+const float *letta_llama_embedding(struct letta_llama_session *session) {
+    return llama_get_embeddings_seq(session->ctx, 0);
+}
@@ -0,0 +1,177 @@
+//! Safe wrappers over the llama.cpp shim in `llama_shim.c`
+
+use std::ffi::CString;
+use std::ptr::NonNull;
+use libc::{c_char, c_float};
+use letta_core::error::{LettaError, ProviderError, ProviderErrorKind, Result};
+
+mod ffi {
+    use libc::{c_char, c_float};
+
+    #[repr(C)]
+    pub struct LlamaModel {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct LlamaSession {
+        _private: [u8; 0],
+    }
+
+    extern "C" {
+        pub fn letta_llama_load(path: *const c_char) -> *mut LlamaModel;
+        pub fn letta_llama_free_model(model: *mut LlamaModel);
+        pub fn letta_llama_session_new(
+            model: *mut LlamaModel,
+            n_ctx: u32,
+            n_threads: i32,
+            embeddings: bool,
+            temperature: c_float,
+            seed: u32,
+        ) -> *mut LlamaSession;
+        pub fn letta_llama_session_free(session: *mut LlamaSession);
+        pub fn letta_llama_tokenize(
+            model: *const LlamaModel,
+            text: *const c_char,
+            text_len: i32,
+            tokens: *mut i32,
+            n_max: i32,
+        ) -> i32;
+        pub fn letta_llama_decode(session: *mut LlamaSession, tokens: *mut i32, n_tokens: i32) -> i32;
+        pub fn letta_llama_sample(session: *mut LlamaSession) -> i32;
+        pub fn letta_llama_is_eog(model: *const LlamaModel, token: i32) -> bool;
+        pub fn letta_llama_piece(model: *const LlamaModel, token: i32, buf: *mut c_char, len: i32) -> i32;
+        pub fn letta_llama_n_embd(model: *const LlamaModel) -> i32;
+        pub fn letta_llama_embedding(session: *mut LlamaSession) -> *const c_float;
+    }
+}
+
+/// llama.cpp's "pick a random seed"
+const RANDOM_SEED: u32 = u32::MAX;
+
+/// A loaded model file. Sessions only read it, so it can be shared between
+/// threads.
+pub struct Model {
+    raw: NonNull<ffi::LlamaModel>,
+}
+
+unsafe impl Send for Model {}
+unsafe impl Sync for Model {}
+
+impl Model {
+    pub fn load(path: &str) -> Result<Self> {
+        let c_path = CString::new(path)
+            .map_err(|_| LettaError::InvalidConfig("model_path contains a NUL byte".into()))?;
+        let raw = unsafe { ffi::letta_llama_load(c_path.as_ptr()) };
+        NonNull::new(raw)
+            .map(|raw| Self { raw })
+            .ok_or_else(|| LettaError::Provider(format!("llama.cpp could not load model '{}'", path).into()))
+    }
+
+    pub fn tokenize(&self, text: &str) -> Result<Vec<i32>> {
+        let text_len = i32::try_from(text.len())
+            .map_err(|_| ProviderError::new(ProviderErrorKind::ContextLength, "Prompt is too long to tokenize"))?;
+        // Tokens rarely outnumber bytes; the shim says how many it needs if they do
+        let mut tokens = vec![0i32; text.len() + 2];
+        loop {
+            let written = unsafe {
+                ffi::letta_llama_tokenize(
+                    self.raw.as_ptr(),
+                    text.as_ptr() as *const c_char,
+                    text_len,
+                    tokens.as_mut_ptr(),
+                    tokens.len() as i32,
+                )
+            };
+            if written >= 0 {
+                tokens.truncate(written as usize);
+                return Ok(tokens);
+            }
+            tokens.resize(written.unsigned_abs() as usize, 0);
+        }
+    }
+
+    pub fn is_eog(&self, token: i32) -> bool {
+        unsafe { ffi::letta_llama_is_eog(self.raw.as_ptr(), token) }
+    }
+
+    /// Append the bytes of `token`'s text to `out`. A token may end partway
+    /// through a UTF-8 character, so text is decoded once generation ends.
+    pub fn push_piece(&self, token: i32, out: &mut Vec<u8>) {
+        let mut buf = [0u8; 64];
+        let mut len = unsafe { ffi::letta_llama_piece(self.raw.as_ptr(), token, buf.as_mut_ptr() as *mut c_char, buf.len() as i32) };
+        if len < 0 {
+            let mut large = vec![0u8; len.unsigned_abs() as usize];
+            len = unsafe { ffi::letta_llama_piece(self.raw.as_ptr(), token, large.as_mut_ptr() as *mut c_char, large.len() as i32) };
+            out.extend_from_slice(&large[..len.max(0) as usize]);
+            return;
+        }
+        out.extend_from_slice(&buf[..len as usize]);
+    }
+
+    pub fn embedding_size(&self) -> usize {
+        unsafe { ffi::letta_llama_n_embd(self.raw.as_ptr()) }.max(0) as usize
+    }
+}
+
+impl Drop for Model {
+    fn drop(&mut self) {
+        unsafe { ffi::letta_llama_free_model(self.raw.as_ptr()) }
+    }
+}
+
+/// One evaluation context over a model, with its own cache and sampler
+pub struct Session<'m> {
+    raw: NonNull<ffi::LlamaSession>,
+    model: &'m Model,
+}
+
+impl<'m> Session<'m> {
+    /// `temperature` of zero or below samples greedily. With `embeddings`
+    /// the session pools an embedding instead of producing logits.
+    pub fn new(model: &'m Model, context_size: usize, n_threads: usize, embeddings: bool, temperature: f32) -> Result<Self> {
+        let raw = unsafe {
+            ffi::letta_llama_session_new(
+                model.raw.as_ptr(),
+                u32::try_from(context_size).unwrap_or(u32::MAX),
+                i32::try_from(n_threads.max(1)).unwrap_or(i32::MAX),
+                embeddings,
+                temperature,
+                RANDOM_SEED,
+            )
+        };
+        NonNull::new(raw)
+            .map(|raw| Self { raw, model })
+            .ok_or_else(|| LettaError::Provider(format!("llama.cpp could not create a {}-token context", context_size).into()))
+    }
+
+    /// Evaluate `tokens` following everything decoded so far
+    pub fn decode(&mut self, tokens: &mut [i32]) -> Result<()> {
+        let status = unsafe { ffi::letta_llama_decode(self.raw.as_ptr(), tokens.as_mut_ptr(), tokens.len() as i32) };
+        if status != 0 {
+            return Err(LettaError::Provider(format!("llama.cpp failed to evaluate the prompt (status {})", status).into()));
+        }
+        Ok(())
+    }
+
+    /// The next token, from the logits of the last decode
+    pub fn sample(&mut self) -> i32 {
+        unsafe { ffi::letta_llama_sample(self.raw.as_ptr()) }
+    }
+
+    /// Mean-pooled embedding of everything decoded
+    pub fn embedding(&mut self) -> Result<Vec<f32>> {
+        let raw = unsafe { ffi::letta_llama_embedding(self.raw.as_ptr()) };
+        if raw.is_null() {
+            return Err(LettaError::Provider("This model does not produce embeddings".into()));
+        }
+        let values = unsafe { std::slice::from_raw_parts(raw as *const c_float, self.model.embedding_size()) };
+        Ok(values.to_vec())
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        unsafe { ffi::letta_llama_session_free(self.raw.as_ptr()) }
+    }
+}
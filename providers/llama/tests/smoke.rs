@@ -0,0 +1,84 @@
+//! Runs a real model through llama.cpp:
+//! `LLAMA_TEST_MODEL=model.gguf cargo test -p letta-provider-llama --features llama-cpp`
+//! Does nothing when `LLAMA_TEST_MODEL` is unset.
+#![cfg(feature = "llama-cpp")]
+
+use letta_core::{CompletionRequest, Embedder, LettaError, LlmProvider};
+use letta_provider_llama::{LlamaEmbedder, LlamaProvider};
+
+fn provider() -> Option<LlamaProvider> {
+    let Ok(path) = std::env::var("LLAMA_TEST_MODEL") else {
+        eprintln!("LLAMA_TEST_MODEL is not set; skipping");
+        return None;
+    };
+    Some(LlamaProvider::new(path, 512, 2))
+}
+
+fn request(prompt: &str, max_tokens: usize) -> CompletionRequest {
+    CompletionRequest {
+        prompt: prompt.to_string(),
+        messages: vec![],
+        tools: vec![],
+        temperature: Some(0.0),
+        max_tokens: Some(max_tokens),
+        stream: false,
+        stop: vec![],
+        cancel: Default::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_complete() {
+    let Some(provider) = provider() else { return };
+
+    let completion = provider.complete(request("The capital of France is", 8)).await.unwrap();
+    assert!(completion.usage.prompt_tokens > 0);
+    assert!(completion.usage.completion_tokens <= 8);
+    assert_eq!(completion.usage.total_tokens, completion.usage.prompt_tokens + completion.usage.completion_tokens);
+
+    // The model is loaded once and reused; greedy sampling repeats itself
+    let again = provider.complete(request("The capital of France is", 8)).await.unwrap();
+    assert_eq!(again.text, completion.text);
+
+    let overflow = provider.complete(request(&"word ".repeat(1000), 8)).await.unwrap_err();
+    assert!(matches!(overflow, LettaError::ContextOverflow { max: 512, .. }), "{}", overflow);
+}
+
+#[tokio::test]
+async fn test_stop_sequence() {
+    let Some(provider) = provider() else { return };
+
+    let full = provider.complete(request("One, two, three, four,", 16)).await.unwrap();
+    let Some(stop) = full.text.split_whitespace().nth(1) else { return };
+    let mut stopped = request("One, two, three, four,", 16);
+    stopped.stop = vec![stop.to_string()];
+    let stopped = provider.complete(stopped).await.unwrap();
+    assert_eq!(stopped.text, full.text[..full.text.find(stop).unwrap()]);
+    assert!(stopped.usage.completion_tokens < full.usage.completion_tokens);
+}
+
+#[tokio::test]
+async fn test_embed() {
+    let Some(provider) = provider() else { return };
+
+    // Chat models may have no embedding output; that must be an error, not garbage
+    match provider.embed(vec!["tea".into(), "coffee".into()]).await {
+        Ok(embeddings) => {
+            assert_eq!(embeddings.len(), 2);
+            assert!(!embeddings[0].is_empty());
+            assert_eq!(embeddings[0].len(), embeddings[1].len());
+        }
+        Err(e) => assert!(matches!(e, LettaError::Provider(_)), "{}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_embedder() {
+    let Ok(path) = std::env::var("LLAMA_TEST_MODEL") else { return };
+    let embedder = LlamaEmbedder::new(path, 512, 2);
+
+    match embedder.embed(vec!["tea".into(), "coffee".into()]).await {
+        Ok(embeddings) => assert_eq!(embeddings.len(), 2),
+        Err(e) => assert!(matches!(e, LettaError::Provider(_)), "{}", e),
+    }
+}
@@ -0,0 +1,111 @@
+use hyper::StatusCode;
+use letta_core::{LettaError, ProviderErrorKind};
+use serde_json::json;
+
+/// A failed request: an HTTP status and the `{"error": {...}}` body. Core
+/// errors keep their `LettaError::code`; problems with the request itself use
+/// `request.*` codes.
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    body: serde_json::Value,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            body: json!({ "code": code, "message": message.into(), "retryable": false }),
+        }
+    }
+
+    /// The request body or parameters failed validation
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "request.invalid", message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "request.not_found", message)
+    }
+
+    /// The response body
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({ "error": self.body })
+    }
+}
+
+/// The status a core error is reported with, chosen by its code, and for
+/// provider errors by their kind
+pub fn status_for(error: &LettaError) -> StatusCode {
+    if let LettaError::Provider(e) = error {
+        return match e.kind {
+            ProviderErrorKind::RateLimit => StatusCode::TOO_MANY_REQUESTS,
+            ProviderErrorKind::ContextLength => StatusCode::UNPROCESSABLE_ENTITY,
+            ProviderErrorKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+    }
+    match error.code() {
+        "agent.not_found" => StatusCode::NOT_FOUND,
+        "config.invalid" | "serialization.invalid" => StatusCode::BAD_REQUEST,
+        "memory.failed" | "memory.limit_exceeded" | "context.overflow" => StatusCode::UNPROCESSABLE_ENTITY,
+        "sync.failed" => StatusCode::BAD_GATEWAY,
+        _ if error.is_retryable() => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl From<LettaError> for ApiError {
+    fn from(error: LettaError) -> Self {
+        Self { status: status_for(&error), body: error.to_json() }
+    }
+}
+
+impl From<letta_storage::StorageError> for ApiError {
+    fn from(error: letta_storage::StorageError) -> Self {
+        LettaError::from(error).into()
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::invalid(format!("Invalid JSON: {}", error))
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.body["message"].as_str().unwrap_or_default())
+    }
+}
+
+pub type ApiResult<T> = std::result::Result<T, ApiError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use letta_core::ProviderError;
+
+    #[test]
+    fn test_status_follows_code() {
+        let cases = [
+            (LettaError::AgentNotFound("a".into()), StatusCode::NOT_FOUND),
+            (LettaError::InvalidConfig("bad".into()), StatusCode::BAD_REQUEST),
+            (LettaError::MemoryLimitExceeded { label: "human".into(), len: 3, limit: 2 }, StatusCode::UNPROCESSABLE_ENTITY),
+            (LettaError::Provider("down".into()), StatusCode::BAD_GATEWAY),
+            (ProviderError::from_status(401, "invalid_api_key").into(), StatusCode::BAD_GATEWAY),
+            (ProviderError::from_status(429, "slow down").into(), StatusCode::TOO_MANY_REQUESTS),
+            (ProviderError::new(ProviderErrorKind::ContextLength, "too long").into(), StatusCode::UNPROCESSABLE_ENTITY),
+            (ProviderError::new(ProviderErrorKind::Timeout, "slow").into(), StatusCode::GATEWAY_TIMEOUT),
+            (LettaError::Storage(letta_storage::StorageError::Cancelled), StatusCode::INTERNAL_SERVER_ERROR),
+            (LettaError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "slow")), StatusCode::SERVICE_UNAVAILABLE),
+            (LettaError::ToolExecution("failed".into()), StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (error, status) in cases {
+            let code = error.code();
+            let api_error = ApiError::from(error);
+            assert_eq!(api_error.status, status, "{}", code);
+            assert_eq!(api_error.to_json()["error"]["code"], code);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! A local REST API over stored agents, loosely following Letta's:
+//!
+//! - `POST /v1/agents`, `GET /v1/agents`
+//! - `POST /v1/agents/{id}/messages`, with `"stream": true` for server-sent events
+//! - `GET /v1/agents/{id}/memory/blocks`, `GET`/`PUT .../memory/blocks/{label}`
+//! - `POST /v1/agents/{id}/archival`, `GET .../archival?query=...&limit=...`
+//! - `GET /v1/agents/{id}/export` for the agent file
+//!
+//! Errors are `{"error": {"code", "message", "retryable", ...}}` with the
+//! status chosen from the code, or a provider error's kind; see
+//! `error::status_for`.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+pub mod error;
+mod routes;
+mod state;
+
+pub use error::{ApiError, ApiResult};
+pub use state::{ServerState, SharedAgent};
+
+/// Accept connections on `listener` until the returned future is dropped
+pub async fn serve(listener: TcpListener, state: Arc<ServerState>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(routes::handle(state, request).await) }
+            });
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                tracing::debug!("connection closed: {}", e);
+            }
+        });
+    }
+}
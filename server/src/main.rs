@@ -0,0 +1,53 @@
+//! letta-server: serve stored agents over a local REST API
+
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use letta_core::AppConfig;
+use letta_server::{serve, ServerState};
+use letta_storage::{Storage, StorageConfig};
+use tokio::net::TcpListener;
+
+fn cli() -> Command<'static> {
+    Command::new("letta-server")
+        .about("Serve letta-lite agents over HTTP")
+        .version(letta_core::VERSION)
+        .arg(Arg::new("config").long("config").takes_value(true).value_name("PATH")
+            .help("TOML or JSON config file; letta.toml is used when present"))
+        .arg(Arg::new("db").long("db").takes_value(true).value_name("PATH")
+            .help("Database file to keep agents in; overrides the config's [storage]"))
+        .arg(Arg::new("addr").long("addr").takes_value(true).value_name("HOST:PORT")
+            .default_value("127.0.0.1:8283")
+            .help("Address to listen on"))
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt::init();
+    if let Err(e) = run(cli().get_matches()).await {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(matches: ArgMatches) -> Result<()> {
+    let config = match matches.value_of("config") {
+        Some(path) => AppConfig::load(path)?,
+        None if Path::new("letta.toml").exists() => AppConfig::load("letta.toml")?,
+        None => AppConfig::default(),
+    };
+    let storage_config = match (matches.value_of("db"), &config.storage) {
+        (Some(path), storage) => StorageConfig { path: path.into(), ..storage.clone().unwrap_or_default() },
+        (None, Some(storage)) => storage.clone(),
+        (None, None) => return Err(anyhow!("no database; pass --db or add [storage] to the config")),
+    };
+    let path = storage_config.path.display().to_string();
+    let storage = Storage::new(storage_config).with_context(|| format!("opening database {}", path))?;
+
+    let addr = matches.value_of("addr").expect("addr has a default");
+    let listener = TcpListener::bind(addr).await.with_context(|| format!("listening on {}", addr))?;
+    println!("letta-server listening on http://{}", listener.local_addr()?);
+    serve(listener, Arc::new(ServerState::new(storage, config))).await;
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use bytes::Bytes;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Full, LengthLimitError, Limited, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use letta_core::{af::AgentFile, agent::StepResult};
+
+use crate::error::{ApiError, ApiResult};
+use crate::state::{ServerState, SharedAgent};
+
+pub type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// Largest request body accepted
+const MAX_BODY_BYTES: usize = 1 << 20;
+
+/// Most archival results one search returns
+const MAX_SEARCH_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessagesRequest {
+    messages: Vec<MessageInput>,
+    /// Reply as server-sent events instead of one JSON response
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageInput {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockUpdate {
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArchivalInsert {
+    text: String,
+    #[serde(default = "default_folder")]
+    folder: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ArchivalSearch {
+    query: String,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_folder() -> String {
+    "default".to_string()
+}
+
+fn default_limit() -> usize {
+    10
+}
+
+/// Answer one request; failures become `{"error": {...}}` responses
+pub async fn handle(state: Arc<ServerState>, request: Request<Incoming>) -> Response<Body> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = route(&state, request).await.unwrap_or_else(|error| {
+        json_response(error.status, &error.to_json())
+    });
+    tracing::debug!("{} {} -> {}", method, path, response.status());
+    response
+}
+
+async fn route(state: &Arc<ServerState>, request: Request<Incoming>) -> ApiResult<Response<Body>> {
+    let method = request.method().as_str().to_string();
+    let path = request.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (segments.as_slice(), method.as_str()) {
+        (["v1", "agents"], "POST") => {
+            let agent = state.create_agent(read_json(request).await?).await?;
+            let agent = agent.lock().await;
+            Ok(json_response(StatusCode::CREATED, &json!({
+                "id": agent.state.id,
+                "name": agent.config.name,
+                "config": agent.config,
+                "created_at": agent.state.created_at,
+            })))
+        }
+        (["v1", "agents"], "GET") => {
+            let agents: Vec<Value> = state.storage().list_agents().await?
+                .into_iter()
+                .map(|stored| json!({
+                    "id": stored.id,
+                    "name": stored.name,
+                    "created_at": stored.created_at,
+                    "updated_at": stored.updated_at,
+                }))
+                .collect();
+            Ok(json_response(StatusCode::OK, &Value::Array(agents)))
+        }
+        (["v1", "agents", id, "messages"], "POST") => {
+            let body: MessagesRequest = read_json(request).await?;
+            send_messages(state, id, body).await
+        }
+        (["v1", "agents", id, "memory", "blocks"], "GET") => {
+            let agent = state.agent(id).await?;
+            let agent = agent.lock().await;
+            let mut blocks: Vec<_> = agent.state.memory.blocks().values().collect();
+            blocks.sort_by(|a, b| a.label.cmp(&b.label));
+            Ok(json_response(StatusCode::OK, &json!(blocks)))
+        }
+        (["v1", "agents", id, "memory", "blocks", label], "GET") => {
+            let agent = state.agent(id).await?;
+            let agent = agent.lock().await;
+            match agent.state.memory.get_block(label) {
+                Some(block) => Ok(json_response(StatusCode::OK, &json!(block))),
+                None => Err(ApiError::not_found(format!("Memory block '{}' not found", label))),
+            }
+        }
+        (["v1", "agents", id, "memory", "blocks", label], "PUT") => {
+            let body: BlockUpdate = read_json(request).await?;
+            let agent = state.agent(id).await?;
+            let mut agent = agent.lock().await;
+            agent.set_memory_block(label, &body.value)?;
+            state.save(&agent).await?;
+            Ok(json_response(StatusCode::OK, &json!(agent.state.memory.get_block(label))))
+        }
+        (["v1", "agents", id, "archival"], "POST") => {
+            let body: ArchivalInsert = read_json(request).await?;
+            if body.text.trim().is_empty() {
+                return Err(ApiError::invalid("`text` must not be empty"));
+            }
+            let agent = state.agent(id).await?;
+            let mut agent = agent.lock().await;
+            agent.add_archival(&body.folder, &body.text);
+            state.save(&agent).await?;
+            Ok(json_response(StatusCode::CREATED, &json!(agent.state.archival_entries.last())))
+        }
+        (["v1", "agents", id, "archival"], "GET") => {
+            let query: ArchivalSearch = serde_urlencoded::from_str(request.uri().query().unwrap_or(""))
+                .map_err(|e| ApiError::invalid(format!("Invalid query: {}", e)))?;
+            if !(1..=MAX_SEARCH_LIMIT).contains(&query.limit) {
+                return Err(ApiError::invalid(format!("`limit` must be between 1 and {}", MAX_SEARCH_LIMIT)));
+            }
+            let agent = state.agent(id).await?;
+            let results = agent.lock().await.search_archival(&query.query, query.limit);
+            Ok(json_response(StatusCode::OK, &Value::Array(results)))
+        }
+        (["v1", "agents", id, "export"], "GET") => {
+            let agent = state.agent(id).await?;
+            let agent = agent.lock().await;
+            let af = AgentFile::export(&agent.config, &agent.state, Vec::new())?;
+            let mut response = json_response(StatusCode::OK, &serde_json::to_value(&af).map_err(letta_core::LettaError::from)?);
+            let disposition = format!("attachment; filename=\"{}.af\"", agent.state.id);
+            response.headers_mut().insert(CONTENT_DISPOSITION, disposition.parse().expect("ids are valid header text"));
+            Ok(response)
+        }
+        (["v1", "agents"] | ["v1", "agents", _, "messages" | "archival" | "export"] | ["v1", "agents", _, "memory", "blocks", ..], _) => {
+            Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "request.method_not_allowed", format!("{} is not supported on {}", method, path)))
+        }
+        _ => Err(ApiError::not_found(format!("No route for {}", path))),
+    }
+}
+
+/// The user messages to send, oldest first; all but the last are added
+/// without a reply
+fn user_messages(body: MessagesRequest) -> ApiResult<Vec<String>> {
+    if body.messages.is_empty() {
+        return Err(ApiError::invalid("`messages` must not be empty"));
+    }
+    body.messages.into_iter().enumerate().map(|(index, message)| {
+        if message.role != "user" {
+            return Err(ApiError::invalid(format!("`messages[{}].role` must be \"user\", got {:?}", index, message.role)));
+        }
+        if message.content.trim().is_empty() {
+            return Err(ApiError::invalid(format!("`messages[{}].content` must not be empty", index)));
+        }
+        Ok(message.content)
+    }).collect()
+}
+
+async fn send_messages(state: &Arc<ServerState>, id: &str, body: MessagesRequest) -> ApiResult<Response<Body>> {
+    let stream = body.stream;
+    let mut messages = user_messages(body)?;
+    let last = messages.pop().expect("messages is not empty");
+    let agent = state.agent(id).await?;
+
+    if stream {
+        return Ok(stream_step(state.clone(), agent, messages, last));
+    }
+
+    let mut agent = agent.lock().await;
+    for message in messages {
+        agent.send_only(message);
+    }
+    let result = agent.step(last).await;
+    // The user's messages are kept even if the step failed
+    state.save(&agent).await?;
+    Ok(json_response(StatusCode::OK, &step_json(&result?)))
+}
+
+/// Run the step in the background, sending each `StepEvent` as a server-sent
+/// event and finishing with `{"type": "done", ...}` or `{"type": "error", ...}`
+fn stream_step(state: Arc<ServerState>, agent: SharedAgent, earlier: Vec<String>, last: String) -> Response<Body> {
+    let (sender, receiver) = mpsc::unbounded_channel::<Result<Frame<Bytes>, Infallible>>();
+
+    tokio::spawn(async move {
+        // A client that hung up just stops receiving
+        let send = |event: Value| {
+            let _ = sender.send(Ok(Frame::data(Bytes::from(format!("data: {}\n\n", event)))));
+        };
+
+        let mut agent = agent.lock().await;
+        for message in earlier {
+            agent.send_only(message);
+        }
+        let result = agent.step_stream(last, |event| send(json!(event))).await;
+        let saved = state.save(&agent).await;
+
+        let mut last_event = match (result, saved) {
+            (Ok(step_result), Ok(())) => step_json(&step_result),
+            (Err(e), _) => ApiError::from(e).to_json(),
+            (Ok(_), Err(e)) => e.to_json(),
+        };
+        last_event["type"] = json!(if last_event.get("error").is_some() { "error" } else { "done" });
+        send(last_event);
+    });
+
+    let mut response = Response::new(StreamBody::new(UnboundedReceiverStream::new(receiver)).boxed_unsync());
+    response.headers_mut().insert(CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    response.headers_mut().insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+    response
+}
+
+/// The JSON shape a completed step is returned in, as `letta_converse` does
+fn step_json(step_result: &StepResult) -> Value {
+    json!({
+        "text": step_result.text,
+        "tool_trace": step_result.tool_trace,
+        "usage": step_result.usage,
+        "stop_reason": step_result.stop_reason,
+    })
+}
+
+async fn read_json<T: DeserializeOwned>(request: Request<Incoming>) -> ApiResult<T> {
+    let body = Limited::new(request.into_body(), MAX_BODY_BYTES).collect().await.map_err(|e| {
+        if e.downcast_ref::<LengthLimitError>().is_some() {
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "request.too_large", format!("Request body is over {} bytes", MAX_BODY_BYTES))
+        } else {
+            ApiError::invalid(format!("Could not read request body: {}", e))
+        }
+    })?;
+    Ok(serde_json::from_slice(&body.to_bytes())?)
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    let mut response = Response::new(Full::new(Bytes::from(value.to_string())).boxed_unsync());
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
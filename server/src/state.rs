@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use serde_json::Value;
+
+use letta_core::{persist, provider::ProviderFactory, Agent, AgentConfig, AppConfig, LettaError};
+use letta_storage::{AsyncStorage, Storage};
+
+use crate::error::{ApiError, ApiResult};
+
+/// An agent in use, locked for the whole of a request so that steps on the
+/// same agent run one after another
+pub type SharedAgent = Arc<tokio::sync::Mutex<Agent>>;
+
+/// Storage plus the agents loaded from it. Every change an endpoint makes is
+/// saved before it responds, so the cache can be dropped at any time.
+pub struct ServerState {
+    storage: AsyncStorage,
+    config: AppConfig,
+    agents: Mutex<HashMap<String, SharedAgent>>,
+}
+
+impl ServerState {
+    /// `config` supplies the provider and the defaults for new agents
+    pub fn new(storage: Storage, config: AppConfig) -> Self {
+        Self {
+            storage: AsyncStorage::new(storage),
+            config,
+            agents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn storage(&self) -> &AsyncStorage {
+        &self.storage
+    }
+
+    /// Create and save an agent from a JSON object of `AgentConfig` fields,
+    /// plus an optional `provider` in `ProviderConfig`'s format. Fields left
+    /// out come from the config file's `[agent]` section.
+    pub async fn create_agent(&self, body: Value) -> ApiResult<SharedAgent> {
+        let Value::Object(mut fields) = body else {
+            return Err(ApiError::invalid("Expected a JSON object of agent config fields"));
+        };
+        let provider = match fields.remove("provider") {
+            Some(provider) => serde_json::from_value(provider)
+                .map_err(|e| ApiError::invalid(format!("Invalid provider config: {}", e)))?,
+            None => self.config.provider_config(),
+        };
+
+        let mut merged = serde_json::to_value(self.config.agent_config()).map_err(LettaError::from)?;
+        if let Value::Object(defaults) = &mut merged {
+            defaults.extend(fields);
+        }
+        let config: AgentConfig = letta_core::config::section("agent", &merged)?;
+        config.validate()?;
+
+        let agent = Agent::new(config, ProviderFactory::create(provider).await?);
+        agent.save_async(&self.storage).await?;
+        let id = agent.state.id.clone();
+        let agent = Arc::new(tokio::sync::Mutex::new(agent));
+        self.agents().insert(id, agent.clone());
+        Ok(agent)
+    }
+
+    /// The agent with `id`, loaded from storage on first use. Providers are
+    /// not stored, so loaded agents use the config file's provider.
+    pub async fn agent(&self, id: &str) -> ApiResult<SharedAgent> {
+        if let Some(agent) = self.agents().get(id) {
+            return Ok(agent.clone());
+        }
+
+        let agent_id = id.to_string();
+        let (config, state) = self.storage
+            .run(move |storage| persist::load_agent(storage, &agent_id))
+            .await?;
+        let provider = ProviderFactory::create(self.config.provider_config()).await?;
+        let agent = Arc::new(tokio::sync::Mutex::new(Agent::new(config, provider).with_state(state)));
+
+        // Another request may have loaded it meanwhile; everyone shares the first
+        Ok(self.agents().entry(id.to_string()).or_insert(agent).clone())
+    }
+
+    pub async fn save(&self, agent: &Agent) -> ApiResult<()> {
+        Ok(agent.save_async(&self.storage).await?)
+    }
+
+    fn agents(&self) -> std::sync::MutexGuard<'_, HashMap<String, SharedAgent>> {
+        self.agents.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
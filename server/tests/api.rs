@@ -0,0 +1,163 @@
+use std::path::Path;
+use std::sync::Arc;
+use serde_json::{json, Value};
+
+use letta_core::AppConfig;
+use letta_server::{serve, ServerState};
+use letta_storage::{Storage, StorageConfig};
+
+/// Serve the database at `path` on a free port; returns the base URL
+async fn spawn_server(path: &Path) -> String {
+    let storage = Storage::new(StorageConfig { path: path.to_path_buf(), ..Default::default() }).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve(listener, Arc::new(ServerState::new(storage, AppConfig::default()))));
+    url
+}
+
+async fn create_agent(client: &reqwest::Client, url: &str, name: &str) -> String {
+    let response = client.post(format!("{}/v1/agents", url))
+        .json(&json!({ "name": name }))
+        .send().await.unwrap();
+    assert_eq!(response.status(), 201);
+    let agent: Value = response.json().await.unwrap();
+    assert_eq!(agent["name"], name);
+    agent["id"].as_str().unwrap().to_string()
+}
+
+fn say(text: &str) -> Value {
+    json!({ "messages": [{ "role": "user", "content": text }] })
+}
+
+/// Status and `error.code` of a failed request
+async fn error(response: reqwest::Response) -> (u16, String) {
+    let status = response.status().as_u16();
+    let body: Value = response.json().await.unwrap();
+    (status, body["error"]["code"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn test_agent_lifecycle() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("agents.db");
+    let url = spawn_server(&db).await;
+    let client = reqwest::Client::new();
+    let id = create_agent(&client, &url, "desk").await;
+
+    let agents: Value = client.get(format!("{}/v1/agents", url)).send().await.unwrap().json().await.unwrap();
+    assert_eq!(agents[0]["id"], id.as_str());
+
+    let reply: Value = client.post(format!("{}/v1/agents/{}/messages", url, id))
+        .json(&say("Hello"))
+        .send().await.unwrap().json().await.unwrap();
+    assert!(reply["text"].as_str().unwrap().starts_with("I understand your request."));
+
+    let block = client.put(format!("{}/v1/agents/{}/memory/blocks/human", url, id))
+        .json(&json!({ "value": "Name: Ada" }))
+        .send().await.unwrap();
+    assert_eq!(block.status(), 200);
+    let blocks: Value = client.get(format!("{}/v1/agents/{}/memory/blocks", url, id))
+        .send().await.unwrap().json().await.unwrap();
+    assert!(blocks.as_array().unwrap().iter().any(|b| b["label"] == "human" && b["value"] == "Name: Ada"));
+
+    let inserted = client.post(format!("{}/v1/agents/{}/archival", url, id))
+        .json(&json!({ "text": "The launch is on Friday", "folder": "notes" }))
+        .send().await.unwrap();
+    assert_eq!(inserted.status(), 201);
+    let found: Value = client.get(format!("{}/v1/agents/{}/archival?query=launch&limit=5", url, id))
+        .send().await.unwrap().json().await.unwrap();
+    assert_eq!(found[0]["folder"], "notes");
+
+    // A fresh server over the same database sees everything that was saved
+    let url = spawn_server(&db).await;
+    let block: Value = client.get(format!("{}/v1/agents/{}/memory/blocks/human", url, id))
+        .send().await.unwrap().json().await.unwrap();
+    assert_eq!(block["value"], "Name: Ada");
+
+    let export = client.get(format!("{}/v1/agents/{}/export", url, id)).send().await.unwrap();
+    assert!(export.headers()["content-disposition"].to_str().unwrap().contains(&format!("{}.af", id)));
+    let af: Value = export.json().await.unwrap();
+    assert_eq!(af["agents"][0]["id"], id.as_str());
+    assert_eq!(af["agents"][0]["messages"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_streamed_step() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = spawn_server(&dir.path().join("agents.db")).await;
+    let client = reqwest::Client::new();
+    let id = create_agent(&client, &url, "streamer").await;
+
+    let mut body = say("Hello");
+    body["stream"] = json!(true);
+    let response = client.post(format!("{}/v1/agents/{}/messages", url, id)).json(&body).send().await.unwrap();
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    let text = response.text().await.unwrap();
+    let events: Vec<Value> = text.split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    let deltas: String = events.iter()
+        .filter(|e| e["type"] == "text_delta")
+        .map(|e| e["text"].as_str().unwrap())
+        .collect();
+    let done = events.last().unwrap();
+    assert_eq!(done["type"], "done");
+    assert_eq!(deltas, done["text"].as_str().unwrap());
+    assert!(events.len() > 2);
+}
+
+#[tokio::test]
+async fn test_steps_on_one_agent_are_serialised() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = spawn_server(&dir.path().join("agents.db")).await;
+    let client = reqwest::Client::new();
+    let id = create_agent(&client, &url, "busy").await;
+
+    let messages = format!("{}/v1/agents/{}/messages", url, id);
+    let (first, second) = tokio::join!(
+        client.post(&messages).json(&say("one")).send(),
+        client.post(&messages).json(&say("two")).send(),
+    );
+    assert_eq!(first.unwrap().status(), 200);
+    assert_eq!(second.unwrap().status(), 200);
+
+    let af: Value = client.get(format!("{}/v1/agents/{}/export", url, id)).send().await.unwrap().json().await.unwrap();
+    let roles: Vec<&str> = af["agents"][0]["messages"].as_array().unwrap().iter().map(|m| m["role"].as_str().unwrap()).collect();
+    assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+}
+
+#[tokio::test]
+async fn test_errors_map_to_statuses() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = spawn_server(&dir.path().join("agents.db")).await;
+    let client = reqwest::Client::new();
+    let id = create_agent(&client, &url, "strict").await;
+    let agents = format!("{}/v1/agents", url);
+
+    let cases = [
+        (client.post(&agents).json(&json!({ "temperature": 5.0 })), 400, "config.invalid"),
+        (client.post(&agents).json(&json!({ "colour": "blue" })), 400, "config.invalid"),
+        (client.post(&agents).json(&json!({ "provider": { "type": "nope" } })), 400, "request.invalid"),
+        (client.post(&agents).body("{not json"), 400, "request.invalid"),
+        (client.get(format!("{}/missing/messages", agents)), 405, "request.method_not_allowed"),
+        (client.post(format!("{}/missing/messages", agents)).json(&say("hi")), 404, "agent.not_found"),
+        (client.post(format!("{}/{}/messages", agents, id)).json(&json!({ "messages": [] })), 400, "request.invalid"),
+        (
+            client.post(format!("{}/{}/messages", agents, id))
+                .json(&json!({ "messages": [{ "role": "assistant", "content": "hi" }] })),
+            400,
+            "request.invalid",
+        ),
+        (client.put(format!("{}/{}/memory/blocks/persona", agents, id)).json(&json!({ "value": "x".repeat(5000) })), 422, "memory.limit_exceeded"),
+        (client.get(format!("{}/{}/memory/blocks/nope", agents, id)), 404, "request.not_found"),
+        (client.get(format!("{}/{}/archival?limit=5", agents, id)), 400, "request.invalid"),
+        (client.get(format!("{}/v2/agents", url)), 404, "request.not_found"),
+    ];
+    for (request, status, code) in cases {
+        let request = request.build().unwrap();
+        let description = format!("{} {}", request.method(), request.url());
+        assert_eq!(error(client.execute(request).await.unwrap()).await, (status, code.to_string()), "{}", description);
+    }
+}
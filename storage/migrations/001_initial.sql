@@ -19,7 +19,7 @@ CREATE TABLE IF NOT EXISTS blocks (
     label TEXT NOT NULL,
     description TEXT,
     value TEXT,
-    limit INTEGER DEFAULT 2000,
+    "limit" INTEGER DEFAULT 2000,
     updated_at TIMESTAMP NOT NULL,
     FOREIGN KEY (agent_id) REFERENCES agents(id) ON DELETE CASCADE,
     UNIQUE(agent_id, label)
@@ -0,0 +1,12 @@
+-- Per-agent message sequence numbers used as a delta-sync cursor
+ALTER TABLE messages ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+UPDATE messages SET seq = (
+    SELECT COUNT(*) FROM messages m2
+    WHERE m2.agent_id = messages.agent_id AND m2.rowid <= messages.rowid
+);
+
+CREATE INDEX IF NOT EXISTS idx_messages_agent_seq ON messages(agent_id, seq);
+
+-- Highest message seq included in the last successful sync
+ALTER TABLE sync_metadata ADD COLUMN last_synced_seq INTEGER NOT NULL DEFAULT 0;
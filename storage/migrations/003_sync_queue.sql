@@ -0,0 +1,15 @@
+-- Durable queue of uploads owed to the cloud, drained by SyncManager
+CREATE TABLE IF NOT EXISTS sync_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    entity_type TEXT NOT NULL,
+    entity_id TEXT NOT NULL,
+    operation TEXT NOT NULL,               -- 'upsert', 'delete'
+    enqueued_at TIMESTAMP NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL,
+    last_error TEXT,
+    status TEXT NOT NULL DEFAULT 'pending', -- 'pending', 'failed'
+    UNIQUE(entity_type, entity_id, operation)
+);
+
+CREATE INDEX IF NOT EXISTS idx_sync_queue_due ON sync_queue(status, next_attempt_at);
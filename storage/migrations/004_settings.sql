@@ -0,0 +1,6 @@
+-- Key/value settings owned by the library, e.g. the sync device id
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Per-agent overrides of the global SyncConfig; a missing row means defaults
+CREATE TABLE IF NOT EXISTS agent_sync_settings (
+    agent_id TEXT PRIMARY KEY,
+    enabled INTEGER NOT NULL DEFAULT 1,
+    sync_interval INTEGER,                 -- milliseconds, NULL = global interval
+    conflict_resolution TEXT,              -- NULL = global strategy
+    updated_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (agent_id) REFERENCES agents(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- Conflicts deferred to the user, as a JSON array; set while sync_status = 'conflict'
+ALTER TABLE sync_metadata ADD COLUMN conflicts TEXT;
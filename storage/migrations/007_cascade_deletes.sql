@@ -0,0 +1,28 @@
+-- Sync rows are keyed by entity rather than agent id, so they cannot carry a
+-- foreign key; clear them when their agent goes instead
+CREATE TRIGGER IF NOT EXISTS agents_ad AFTER DELETE ON agents BEGIN
+    DELETE FROM sync_metadata WHERE entity_type = 'agent' AND entity_id = old.id;
+    DELETE FROM sync_queue WHERE entity_type = 'agent' AND entity_id = old.id;
+END;
+
+-- External-content FTS rows must be removed with the 'delete' command, which
+-- needs the old text; a plain DELETE leaves stale tokens behind
+DROP TRIGGER IF EXISTS chunks_ad;
+CREATE TRIGGER chunks_ad AFTER DELETE ON chunks BEGIN
+    INSERT INTO chunks_fts(chunks_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+END;
+
+DROP TRIGGER IF EXISTS chunks_au;
+CREATE TRIGGER chunks_au AFTER UPDATE ON chunks BEGIN
+    INSERT INTO chunks_fts(chunks_fts, rowid, text) VALUES ('delete', old.rowid, old.text);
+    INSERT INTO chunks_fts(rowid, text) VALUES (new.rowid, new.text);
+END;
+
+-- Drop rows orphaned before foreign keys were enforced
+DELETE FROM blocks WHERE agent_id NOT IN (SELECT id FROM agents);
+DELETE FROM messages WHERE agent_id NOT IN (SELECT id FROM agents);
+DELETE FROM chunks WHERE agent_id NOT IN (SELECT id FROM agents);
+DELETE FROM agent_sync_settings WHERE agent_id NOT IN (SELECT id FROM agents);
+DELETE FROM sync_metadata WHERE entity_type = 'agent' AND entity_id NOT IN (SELECT id FROM agents);
+DELETE FROM sync_queue WHERE entity_type = 'agent' AND entity_id NOT IN (SELECT id FROM agents);
+INSERT INTO chunks_fts(chunks_fts) VALUES ('rebuild');
@@ -0,0 +1,2 @@
+-- `limit` is a reserved word and must be quoted everywhere it appears
+ALTER TABLE blocks RENAME COLUMN "limit" TO char_limit;
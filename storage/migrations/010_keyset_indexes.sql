@@ -0,0 +1,3 @@
+-- Keyset pagination over (timestamp, id) and (updated_at, id)
+CREATE INDEX IF NOT EXISTS idx_messages_agent_page ON messages(agent_id, timestamp, id);
+CREATE INDEX IF NOT EXISTS idx_agents_updated_page ON agents(updated_at, id);
@@ -0,0 +1,11 @@
+-- Per-agent indexes for the hot queries. Messages by (agent_id, timestamp)
+-- are served by idx_messages_agent_page, and the ON CONFLICT targets
+-- blocks(agent_id, label) and sync_metadata(entity_type, entity_id) are
+-- backed by their table's UNIQUE and PRIMARY KEY constraints.
+CREATE INDEX IF NOT EXISTS idx_chunks_agent_folder ON chunks(agent_id, folder);
+CREATE INDEX IF NOT EXISTS idx_chunks_agent_created ON chunks(agent_id, created_at);
+
+-- Prefixes of the composite indexes above, only adding write cost
+DROP INDEX IF EXISTS idx_messages_agent;
+DROP INDEX IF EXISTS idx_blocks_agent;
+DROP INDEX IF EXISTS idx_chunks_agent;
@@ -0,0 +1,10 @@
+-- Record each embedding's size and the model that produced it, so vectors
+-- from different models are never compared. A blob whose length isn't a
+-- whole number of f32s is left without a dimension and out of vector search.
+ALTER TABLE chunks ADD COLUMN embedding_dim INTEGER;
+ALTER TABLE chunks ADD COLUMN embedding_model TEXT;
+
+UPDATE chunks SET embedding_dim = length(embedding) / 4
+WHERE embedding IS NOT NULL AND length(embedding) > 0 AND length(embedding) % 4 = 0;
+
+CREATE INDEX IF NOT EXISTS idx_chunks_agent_dim ON chunks(agent_id, embedding_dim);
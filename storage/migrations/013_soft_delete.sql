@@ -0,0 +1,14 @@
+-- Agents moved to the trash keep their rows until purged
+ALTER TABLE agents ADD COLUMN deleted_at TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS idx_agents_deleted ON agents(deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Purging a trashed agent keeps its tombstone and queued deletion, so the
+-- cloud still hears about it after the local rows are gone
+DROP TRIGGER IF EXISTS agents_ad;
+CREATE TRIGGER agents_ad AFTER DELETE ON agents BEGIN
+    DELETE FROM sync_metadata WHERE entity_type = 'agent' AND entity_id = old.id
+        AND old.deleted_at IS NULL;
+    DELETE FROM sync_queue WHERE entity_type = 'agent' AND entity_id = old.id
+        AND (old.deleted_at IS NULL OR operation <> 'delete');
+END;
@@ -0,0 +1,13 @@
+-- Earlier values of each memory block, newest last by id
+CREATE TABLE IF NOT EXISTS blocks_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    agent_id TEXT NOT NULL,
+    label TEXT NOT NULL,
+    old_value TEXT,                        -- NULL when the block was created
+    new_value TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL,
+    changed_by TEXT NOT NULL,              -- see BlockWriter
+    FOREIGN KEY (agent_id) REFERENCES agents(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_blocks_history_block ON blocks_history(agent_id, label, id);
@@ -0,0 +1,10 @@
+-- Provider completions by a hash of the request that produced them, for
+-- letta-core's CachingProvider
+CREATE TABLE IF NOT EXISTS completions_cache (
+    key TEXT PRIMARY KEY,
+    completion TEXT NOT NULL,              -- JSON
+    created_at TIMESTAMP NOT NULL,
+    last_used_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_completions_cache_last_used ON completions_cache(last_used_at);
@@ -0,0 +1,3 @@
+-- Bumped on every enqueue, so a finished upload only removes its entry if
+-- no write queued the entity again while the upload ran
+ALTER TABLE sync_queue ADD COLUMN generation INTEGER NOT NULL DEFAULT 0;
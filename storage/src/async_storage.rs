@@ -0,0 +1,361 @@
+use chrono::{DateTime, Utc};
+use crate::{
+    db::Storage,
+    error::{Result, StorageError},
+    models::*,
+    retention::RetentionPolicy,
+};
+
+/// Async facade over `Storage` for callers on a tokio runtime.
+///
+/// Each call runs on tokio's blocking pool against the shared connection
+/// pool, so slow queries don't hold up the runtime's worker threads. Cloning
+/// is cheap; `storage()` gives the synchronous API back.
+#[derive(Clone)]
+pub struct AsyncStorage {
+    storage: Storage,
+}
+
+impl AsyncStorage {
+    pub fn new(storage: Storage) -> Self {
+        Self { storage }
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Run `operation` on the blocking pool. A panic inside it resumes in the
+    /// caller, as if it had been called directly.
+    pub async fn run<T, E, F>(&self, operation: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&Storage) -> std::result::Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<StorageError> + Send + 'static,
+    {
+        let storage = self.storage.clone();
+        match tokio::task::spawn_blocking(move || operation(&storage)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(StorageError::Cancelled.into()),
+        }
+    }
+
+    // Agent operations
+    pub async fn create_agent(&self, agent: StoredAgent) -> Result<()> {
+        self.run(move |s| s.create_agent(&agent)).await
+    }
+
+    pub async fn get_agent(&self, id: impl Into<String>) -> Result<Option<StoredAgent>> {
+        let id = id.into();
+        self.run(move |s| s.get_agent(&id)).await
+    }
+
+    pub async fn update_agent(&self, agent: StoredAgent) -> Result<()> {
+        self.run(move |s| s.update_agent(&agent)).await
+    }
+
+    pub async fn list_agents(&self) -> Result<Vec<StoredAgent>> {
+        self.run(|s| s.list_agents()).await
+    }
+
+    pub async fn list_agents_filtered(&self, include_deleted: bool) -> Result<Vec<StoredAgent>> {
+        self.run(move |s| s.list_agents_filtered(include_deleted)).await
+    }
+
+    pub async fn list_agents_lenient(&self) -> Result<Lenient<StoredAgent>> {
+        self.run(|s| s.list_agents_lenient()).await
+    }
+
+    pub async fn list_agents_page(&self, limit: usize, after_updated_at: Option<PageCursor>) -> Result<Page<StoredAgent>> {
+        self.run(move |s| s.list_agents_page(limit, after_updated_at.as_ref())).await
+    }
+
+    pub async fn delete_agent(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.run(move |s| s.delete_agent(&id)).await
+    }
+
+    pub async fn soft_delete_agent(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.run(move |s| s.soft_delete_agent(&id)).await
+    }
+
+    pub async fn restore_agent(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.run(move |s| s.restore_agent(&id)).await
+    }
+
+    pub async fn purge_deleted(&self, older_than: chrono::Duration) -> Result<usize> {
+        self.run(move |s| s.purge_deleted(older_than)).await
+    }
+
+    pub async fn save_agent_snapshot(
+        &self,
+        agent: StoredAgent,
+        blocks: Vec<StoredBlock>,
+        messages: Vec<StoredMessage>,
+    ) -> Result<()> {
+        self.run(move |s| s.save_agent_snapshot(&agent, &blocks, &messages)).await
+    }
+
+    pub async fn save_agent_snapshot_with_chunks(
+        &self,
+        agent: StoredAgent,
+        blocks: Vec<StoredBlock>,
+        messages: Vec<StoredMessage>,
+        chunks: Vec<StoredChunk>,
+    ) -> Result<()> {
+        self.run(move |s| s.save_agent_snapshot_with_chunks(&agent, &blocks, &messages, &chunks)).await
+    }
+
+    pub async fn save_agent_step(
+        &self,
+        agent: StoredAgent,
+        blocks: Vec<StoredBlock>,
+        messages: Vec<StoredMessage>,
+        chunks: Vec<StoredChunk>,
+        deleted_messages: Vec<String>,
+    ) -> Result<()> {
+        self.run(move |s| s.save_agent_step(&agent, &blocks, &messages, &chunks, &deleted_messages)).await
+    }
+
+    // Block operations
+    pub async fn upsert_block(&self, block: StoredBlock) -> Result<()> {
+        self.run(move |s| s.upsert_block(&block)).await
+    }
+
+    pub async fn upsert_block_by(&self, block: StoredBlock, writer: BlockWriter) -> Result<()> {
+        self.run(move |s| s.upsert_block_by(&block, writer)).await
+    }
+
+    pub async fn get_block_history(
+        &self,
+        agent_id: impl Into<String>,
+        label: impl Into<String>,
+        limit: usize,
+    ) -> Result<Vec<BlockChange>> {
+        let (agent_id, label) = (agent_id.into(), label.into());
+        self.run(move |s| s.get_block_history(&agent_id, &label, limit)).await
+    }
+
+    pub async fn get_blocks(&self, agent_id: impl Into<String>) -> Result<Vec<StoredBlock>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_blocks(&agent_id)).await
+    }
+
+    // Message operations
+    pub async fn add_message(&self, message: StoredMessage) -> Result<()> {
+        self.run(move |s| s.add_message(&message)).await
+    }
+
+    pub async fn delete_messages(&self, agent_id: impl Into<String>, ids: Vec<String>) -> Result<usize> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.delete_messages(&agent_id, &ids)).await
+    }
+
+    pub async fn get_messages(&self, agent_id: impl Into<String>, limit: usize) -> Result<Vec<StoredMessage>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_messages(&agent_id, limit)).await
+    }
+
+    pub async fn get_messages_since(&self, agent_id: impl Into<String>, after_seq: i64) -> Result<Vec<StoredMessage>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_messages_since(&agent_id, after_seq)).await
+    }
+
+    pub async fn query_messages(&self, agent_id: impl Into<String>, query: MessageQuery) -> Result<Vec<StoredMessage>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.query_messages(&agent_id, &query)).await
+    }
+
+    pub async fn count_messages_in_range(&self, agent_id: impl Into<String>, range: TimeRange) -> Result<usize> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.count_messages_in_range(&agent_id, &range)).await
+    }
+
+    pub async fn get_messages_lenient(&self, agent_id: impl Into<String>, limit: usize) -> Result<Lenient<StoredMessage>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_messages_lenient(&agent_id, limit)).await
+    }
+
+    pub async fn get_messages_page(&self, agent_id: impl Into<String>, request: PageRequest) -> Result<Page<StoredMessage>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_messages_page(&agent_id, &request)).await
+    }
+
+    pub async fn search_messages(
+        &self,
+        agent_id: impl Into<String>,
+        query: impl Into<String>,
+        limit: usize,
+    ) -> Result<Vec<StoredMessage>> {
+        let (agent_id, query) = (agent_id.into(), query.into());
+        self.run(move |s| s.search_messages(&agent_id, &query, limit)).await
+    }
+
+    pub async fn prune_messages(&self, agent_id: impl Into<String>, policy: RetentionPolicy) -> Result<usize> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.prune_messages(&agent_id, &policy)).await
+    }
+
+    pub async fn prune_all(&self, policy: RetentionPolicy) -> Result<usize> {
+        self.run(move |s| s.prune_all(&policy)).await
+    }
+
+    // Chunk operations
+    pub async fn add_chunk(&self, chunk: StoredChunk) -> Result<()> {
+        self.run(move |s| s.add_chunk(&chunk)).await
+    }
+
+    pub async fn add_chunks(&self, chunks: Vec<StoredChunk>, on_conflict: Option<OnConflict>) -> Result<usize> {
+        self.run(move |s| s.add_chunks(&chunks, on_conflict)).await
+    }
+
+    pub async fn delete_chunk(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.run(move |s| s.delete_chunk(&id)).await
+    }
+
+    pub async fn delete_chunks_by_folder(&self, agent_id: impl Into<String>, folder: impl Into<String>) -> Result<usize> {
+        let (agent_id, folder) = (agent_id.into(), folder.into());
+        self.run(move |s| s.delete_chunks_by_folder(&agent_id, &folder)).await
+    }
+
+    pub async fn update_chunk_text(
+        &self,
+        id: impl Into<String>,
+        text: impl Into<String>,
+        embedding: Option<Vec<f32>>,
+    ) -> Result<bool> {
+        let (id, text) = (id.into(), text.into());
+        self.run(move |s| s.update_chunk_text(&id, &text, embedding.as_deref())).await
+    }
+
+    pub async fn list_folders(&self, agent_id: impl Into<String>) -> Result<Vec<(String, usize)>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.list_folders(&agent_id)).await
+    }
+
+    pub async fn get_chunks(&self, agent_id: impl Into<String>) -> Result<Vec<StoredChunk>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_chunks(&agent_id)).await
+    }
+
+    pub async fn search_chunks_fts(
+        &self,
+        agent_id: impl Into<String>,
+        query: impl Into<String>,
+        limit: usize,
+    ) -> Result<Vec<StoredChunk>> {
+        let (agent_id, query) = (agent_id.into(), query.into());
+        self.run(move |s| s.search_chunks_fts(&agent_id, &query, limit)).await
+    }
+
+    pub async fn search_chunks_vector(
+        &self,
+        agent_id: impl Into<String>,
+        embedding: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<StoredChunk>> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.search_chunks_vector(&agent_id, &embedding, limit)).await
+    }
+
+    // Sync operations
+    pub async fn get_sync_metadata(
+        &self,
+        entity_type: impl Into<String>,
+        entity_id: impl Into<String>,
+    ) -> Result<Option<SyncMetadata>> {
+        let (entity_type, entity_id) = (entity_type.into(), entity_id.into());
+        self.run(move |s| s.get_sync_metadata(&entity_type, &entity_id)).await
+    }
+
+    pub async fn update_sync_metadata(&self, metadata: SyncMetadata) -> Result<()> {
+        self.run(move |s| s.update_sync_metadata(&metadata)).await
+    }
+
+    pub async fn enqueue_sync(
+        &self,
+        entity_type: impl Into<String>,
+        entity_id: impl Into<String>,
+        operation: impl Into<String>,
+    ) -> Result<()> {
+        let (entity_type, entity_id, operation) = (entity_type.into(), entity_id.into(), operation.into());
+        self.run(move |s| s.enqueue_sync(&entity_type, &entity_id, &operation)).await
+    }
+
+    pub async fn due_sync_entries(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SyncQueueEntry>> {
+        self.run(move |s| s.due_sync_entries(now, limit)).await
+    }
+
+    pub async fn complete_sync_entry(&self, id: i64, generation: i64) -> Result<()> {
+        self.run(move |s| s.complete_sync_entry(id, generation)).await
+    }
+
+    pub async fn record_sync_failure(
+        &self,
+        id: i64,
+        error: impl Into<String>,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let error = error.into();
+        self.run(move |s| s.record_sync_failure(id, &error, next_attempt_at)).await
+    }
+
+    pub async fn get_agent_sync_settings(&self, agent_id: impl Into<String>) -> Result<AgentSyncSettings> {
+        let agent_id = agent_id.into();
+        self.run(move |s| s.get_agent_sync_settings(&agent_id)).await
+    }
+
+    // Completion cache
+    pub async fn get_cached_completion(&self, key: impl Into<String>, max_age: Option<chrono::Duration>) -> Result<Option<String>> {
+        let key = key.into();
+        self.run(move |s| s.get_cached_completion(&key, max_age)).await
+    }
+
+    pub async fn put_cached_completion(&self, key: impl Into<String>, completion: impl Into<String>, max_entries: usize) -> Result<()> {
+        let (key, completion) = (key.into(), completion.into());
+        self.run(move |s| s.put_cached_completion(&key, &completion, max_entries)).await
+    }
+}
+
+impl From<Storage> for AsyncStorage {
+    fn from(storage: Storage) -> Self {
+        Self::new(storage)
+    }
+}
+
+impl Storage {
+    /// An async facade sharing this storage's connection pool
+    pub fn to_async(&self) -> AsyncStorage {
+        AsyncStorage::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let storage = Storage::memory().unwrap().to_async();
+
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(agent.clone()).await.unwrap();
+        storage.add_message(StoredMessage::new(&agent.id, "user", "Hello")).await.unwrap();
+
+        assert_eq!(storage.get_agent(&agent.id).await.unwrap().unwrap().name, "test-agent");
+        assert_eq!(storage.get_messages(&agent.id, 10).await.unwrap().len(), 1);
+        assert_eq!(storage.storage().count_messages(&agent.id).unwrap(), 1);
+        assert!(storage.delete_agent(&agent.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "query exploded")]
+    async fn test_panic_resumes_in_caller() {
+        let storage = Storage::memory().unwrap().to_async();
+        let _: Result<()> = storage.run(|_| panic!("query exploded")).await;
+    }
+}
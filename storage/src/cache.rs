@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use serde::{Deserialize, Serialize};
+use crate::{
+    error::Result,
+    models::{StoredAgent, StoredBlock},
+};
+
+/// Lookups served by the agent cache, for judging its capacity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Agents currently cached
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl CacheStats {
+    /// Share of lookups answered without a query, 0 before the first one
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// Read-through cache of agent rows and block lists by agent id, shared by
+/// every clone of a `Storage`. Writes invalidate the agent once committed;
+/// past `capacity` agents the least recently used one is dropped.
+pub(crate) struct AgentCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Bumped by every invalidation, so a lookup that raced a write doesn't
+    /// keep what it read before the write committed
+    generation: u64,
+    /// Orders entries by last use
+    clock: u64,
+}
+
+#[derive(Default)]
+struct Entry {
+    /// `Some(None)` remembers that the agent doesn't exist
+    agent: Option<Option<StoredAgent>>,
+    blocks: Option<Vec<StoredBlock>>,
+    last_used: u64,
+}
+
+impl AgentCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn agent(
+        &self,
+        id: &str,
+        load: impl FnOnce() -> Result<Option<StoredAgent>>,
+    ) -> Result<Option<StoredAgent>> {
+        self.get_or_load(id, |entry| &mut entry.agent, load)
+    }
+
+    pub(crate) fn blocks(&self, agent_id: &str, load: impl FnOnce() -> Result<Vec<StoredBlock>>) -> Result<Vec<StoredBlock>> {
+        self.get_or_load(agent_id, |entry| &mut entry.blocks, load)
+    }
+
+    /// Forget an agent after a committed write to it
+    pub(crate) fn invalidate(&self, id: &str) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.entries.remove(id);
+    }
+
+    /// Forget every agent, after the whole database changed underneath
+    pub(crate) fn clear(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.entries.clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock().entries.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    fn get_or_load<T: Clone>(
+        &self,
+        id: &str,
+        field: fn(&mut Entry) -> &mut Option<T>,
+        load: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        if self.capacity == 0 {
+            return load();
+        }
+
+        let generation = {
+            let mut state = self.lock();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(id) {
+                if let Some(value) = field(entry) {
+                    let value = value.clone();
+                    entry.last_used = clock;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(value);
+                }
+            }
+            state.generation
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        // Queried without the lock held, so other agents' lookups go ahead
+        let value = load()?;
+        let mut state = self.lock();
+        if state.generation == generation {
+            state.clock += 1;
+            let clock = state.clock;
+            let entry = state.entries.entry(id.to_string()).or_default();
+            *field(entry) = Some(value.clone());
+            entry.last_used = clock;
+            if state.entries.len() > self.capacity {
+                evict_least_recent(&mut state.entries);
+            }
+        }
+        Ok(value)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn evict_least_recent(entries: &mut HashMap<String, Entry>) {
+    let oldest = entries.iter()
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(id, _)| id.clone());
+    if let Some(id) = oldest {
+        entries.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(id: &str) -> Option<StoredAgent> {
+        let mut agent = StoredAgent::new(id, "Test prompt");
+        agent.id = id.to_string();
+        Some(agent)
+    }
+
+    #[test]
+    fn test_least_recently_used_agent_is_evicted() {
+        let cache = AgentCache::new(2);
+        cache.agent("a", || Ok(agent("a"))).unwrap();
+        cache.agent("b", || Ok(agent("b"))).unwrap();
+        // Touching "a" leaves "b" as the oldest
+        cache.agent("a", || panic!("should be cached")).unwrap();
+        cache.agent("c", || Ok(agent("c"))).unwrap();
+
+        cache.agent("a", || panic!("should be cached")).unwrap();
+        let reloaded = cache.agent("b", || Ok(None)).unwrap();
+        assert!(reloaded.is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 4, 2));
+        assert_eq!(stats.hit_rate(), 2.0 / 6.0);
+    }
+
+    #[test]
+    fn test_lookup_racing_a_write_is_not_kept() {
+        let cache = AgentCache::new(4);
+        cache.agent("a", || {
+            // A write commits while the stale row is being read
+            cache.invalidate("a");
+            Ok(agent("stale"))
+        }).unwrap();
+        let fresh = cache.agent("a", || Ok(agent("fresh"))).unwrap();
+        assert_eq!(fresh.unwrap().name, "fresh");
+
+        let disabled = AgentCache::new(0);
+        disabled.blocks("a", || Ok(Vec::new())).unwrap();
+        assert_eq!(disabled.stats(), CacheStats::default());
+    }
+}
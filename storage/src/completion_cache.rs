@@ -0,0 +1,80 @@
+use chrono::{Duration, Utc};
+use rusqlite::{params, OptionalExtension};
+use crate::{db::Storage, error::Result};
+
+impl Storage {
+    /// The completion cached under `key`, unless it was stored more than
+    /// `max_age` ago, in which case it is dropped. A hit counts as a use for
+    /// `put_cached_completion`'s eviction.
+    pub fn get_cached_completion(&self, key: &str, max_age: Option<Duration>) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let now = Utc::now();
+        if let Some(max_age) = max_age {
+            conn.execute(
+                "DELETE FROM completions_cache WHERE key = ?1 AND created_at < ?2",
+                params![key, now - max_age],
+            )?;
+        }
+        let completion = conn.query_row(
+            "UPDATE completions_cache SET last_used_at = ?2 WHERE key = ?1 RETURNING completion",
+            params![key, now],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(completion)
+    }
+
+    /// Cache `completion` under `key`, then evict the least recently used
+    /// entries beyond `max_entries`
+    pub fn put_cached_completion(&self, key: &str, completion: &str, max_entries: usize) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let now = Utc::now();
+        tx.execute(
+            "INSERT INTO completions_cache (key, completion, created_at, last_used_at) VALUES (?1, ?2, ?3, ?3)
+             ON CONFLICT(key) DO UPDATE SET completion = excluded.completion,
+                created_at = excluded.created_at, last_used_at = excluded.last_used_at",
+            params![key, completion, now],
+        )?;
+        let evicted = tx.execute(
+            "DELETE FROM completions_cache WHERE key NOT IN (
+                SELECT key FROM completions_cache ORDER BY last_used_at DESC, created_at DESC LIMIT ?1
+             )",
+            params![max_entries as i64],
+        )?;
+        tx.commit()?;
+
+        if evicted > 0 {
+            tracing::debug!(evicted, "Evicted cached completions");
+        }
+        Ok(())
+    }
+
+    /// Empty the completion cache, returning how many entries it held
+    pub fn clear_completion_cache(&self) -> Result<usize> {
+        Ok(self.conn()?.execute("DELETE FROM completions_cache", [])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_completions_expire_and_evict() {
+        let storage = Storage::memory().unwrap();
+        storage.put_cached_completion("a", r#"{"text":"A"}"#, 2).unwrap();
+        storage.put_cached_completion("b", r#"{"text":"B"}"#, 2).unwrap();
+        assert_eq!(storage.get_cached_completion("a", None).unwrap().as_deref(), Some(r#"{"text":"A"}"#));
+
+        // "b" is now the least recently used
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        storage.put_cached_completion("c", r#"{"text":"C"}"#, 2).unwrap();
+        assert!(storage.get_cached_completion("b", None).unwrap().is_none());
+        assert!(storage.get_cached_completion("a", None).unwrap().is_some());
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(storage.get_cached_completion("c", Some(Duration::milliseconds(1))).unwrap().is_none());
+        assert!(storage.get_cached_completion("c", None).unwrap().is_none());
+        assert_eq!(storage.clear_completion_cache().unwrap(), 1);
+    }
+}
@@ -1,19 +1,45 @@
 use std::path::{Path, PathBuf};
-use rusqlite::{Connection, params, OptionalExtension};
+use std::sync::{Arc, PoisonError, RwLock};
+use rusqlite::{Connection, ErrorCode, params, OptionalExtension};
+use rusqlite::types::Type;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use serde::{Deserialize, Serialize};
-use chrono::Utc;  // 修复：删除未使用的 DateTime 导入
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use chrono::{DateTime, Utc};
 use crate::{
-    error::Result,  // 修复：删除未使用的 StorageError 导入
+    cache::{AgentCache, CacheStats},
+    error::{CorruptColumn, Result, StorageError},
     models::*,
     migrations,
+    vector,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Changes kept in each memory block's history; older ones are dropped
+pub const BLOCK_HISTORY_LIMIT: usize = 100;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct StorageConfig {
     pub path: PathBuf,
     pub max_connections: u32,
+    /// Use write-ahead logging, so reads don't wait on a write in progress.
+    /// Has no effect on in-memory databases.
+    pub wal: bool,
+    /// How long a connection waits for another's lock before failing with
+    /// SQLITE_BUSY
+    pub busy_timeout_ms: u32,
+    /// Key the whole database file is encrypted with; requires the
+    /// `sqlcipher` feature. A new file is created encrypted.
+    pub encryption_key: Option<String>,
+    /// Open a database already migrated by a newer letta-lite instead of
+    /// failing. Its schema may have changed in ways this build misreads.
+    pub allow_newer_schema: bool,
+    /// Mark the owning agent dirty for sync on every local write. Turn off
+    /// for deployments that never sync, so the queue doesn't grow unread.
+    pub track_changes: bool,
+    /// Agents whose row and blocks are kept in memory for repeated lookups;
+    /// 0 turns the cache off
+    pub cache_capacity: usize,
 }
 
 impl Default for StorageConfig {
@@ -21,46 +47,134 @@ impl Default for StorageConfig {
         Self {
             path: PathBuf::from("letta.db"),
             max_connections: 5,
+            wal: true,
+            busy_timeout_ms: 5000,
+            encryption_key: None,
+            allow_newer_schema: false,
+            track_changes: true,
+            cache_capacity: 64,
         }
     }
 }
 
+impl std::fmt::Debug for StorageConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("path", &self.path)
+            .field("max_connections", &self.max_connections)
+            .field("wal", &self.wal)
+            .field("busy_timeout_ms", &self.busy_timeout_ms)
+            .field("encryption_key", &self.encryption_key.as_ref().map(|_| "<redacted>"))
+            .field("allow_newer_schema", &self.allow_newer_schema)
+            .field("track_changes", &self.track_changes)
+            .field("cache_capacity", &self.cache_capacity)
+            .finish()
+    }
+}
+
+#[derive(Clone)]
 pub struct Storage {
+    connections: Arc<RwLock<Connections>>,
+    /// Whether sqlite-vec is loaded, so vector search can use `chunks_vec`
+    vector_index: bool,
+    /// Whether writes mark their agent dirty for sync; see `untracked`
+    track_changes: bool,
+    cache: Arc<AgentCache>,
+}
+
+/// The pool, and for a database file the config it was opened with, so
+/// `rekey` can reopen it
+struct Connections {
     pool: Pool<SqliteConnectionManager>,
+    config: Option<StorageConfig>,
 }
 
 impl Storage {
     pub fn new(config: StorageConfig) -> Result<Self> {
-        let manager = SqliteConnectionManager::file(&config.path);
-        let pool = Pool::builder()
-            .max_size(config.max_connections)
-            .build(manager)?;
+        vector::register();
+        let pool = open_pool(&config)?;
         
         // Run migrations on first connection
         let conn = pool.get()?;
-        migrations::run_migrations(&conn)?;
+        migrations::run_migrations(&conn, config.allow_newer_schema)?;
+        let vector_index = init_vector_index(&conn)?;
+        drop(conn);
         
-        Ok(Self { pool })
+        let track_changes = config.track_changes;
+        let cache = Arc::new(AgentCache::new(config.cache_capacity));
+        let connections = Connections { pool, config: Some(config) };
+        Ok(Self { connections: Arc::new(RwLock::new(connections)), vector_index, track_changes, cache })
     }
     
     pub fn memory() -> Result<Self> {
-        let manager = SqliteConnectionManager::memory();
+        vector::register();
+        let StorageConfig { busy_timeout_ms, cache_capacity, .. } = StorageConfig::default();
+        let manager = SqliteConnectionManager::memory()
+            .with_init(move |conn| init_connection(conn, None, false, busy_timeout_ms));
         let pool = Pool::builder().max_size(1).build(manager)?;
         
         let conn = pool.get()?;
-        migrations::run_migrations(&conn)?;
+        migrations::run_migrations(&conn, false)?;
+        let vector_index = init_vector_index(&conn)?;
+        drop(conn);
+        
+        let connections = Connections { pool, config: None };
+        let cache = Arc::new(AgentCache::new(cache_capacity));
+        Ok(Self { connections: Arc::new(RwLock::new(connections)), vector_index, track_changes: true, cache })
+    }
+    
+    /// Whether vector search is served by the sqlite-vec index rather than a
+    /// scan; requires the `vector-index` feature
+    pub fn has_vector_index(&self) -> bool {
+        self.vector_index
+    }
+    
+    /// Whether local writes mark their agent dirty for sync
+    pub fn tracks_changes(&self) -> bool {
+        self.track_changes
+    }
+    
+    /// Hits and misses of the agent and block cache, shared by every clone
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+    
+    /// A handle on the same database whose writes don't mark agents dirty,
+    /// for writing down changes that came from the cloud. Marking those
+    /// would only upload them straight back.
+    pub fn untracked(&self) -> Storage {
+        Storage { track_changes: false, ..self.clone() }
+    }
+    
+    /// Re-encrypt an encrypted database under `new_key`. Connections already
+    /// taken from the pool still use the old key, so call this while the
+    /// storage is otherwise idle.
+    pub fn rekey(&self, new_key: &str) -> Result<()> {
+        let mut connections = self.connections.write().unwrap_or_else(PoisonError::into_inner);
+        let config = match &connections.config {
+            Some(config) if config.encryption_key.is_some() => config.clone(),
+            Some(_) => return Err(StorageError::InvalidData("Database is not encrypted".to_string())),
+            None => return Err(StorageError::InvalidData("In-memory databases cannot be rekeyed".to_string())),
+        };
         
-        Ok(Self { pool })
+        connections.pool.get()?.pragma_update(None, "rekey", new_key)?;
+        let config = StorageConfig { encryption_key: Some(new_key.to_string()), ..config };
+        connections.pool = open_pool(&config)?;
+        connections.config = Some(config);
+        tracing::info!("Database rekeyed");
+        Ok(())
     }
     
-    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
-        Ok(self.pool.get()?)
+    pub(crate) fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        let connections = self.connections.read().unwrap_or_else(PoisonError::into_inner);
+        Ok(connections.pool.get()?)
     }
     
     // Agent operations
     pub fn create_agent(&self, agent: &StoredAgent) -> Result<()> {
-        let conn = self.conn()?;
-        conn.execute(
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
             "INSERT INTO agents (id, name, system_prompt, config, state, created_at, updated_at)
              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             params![
@@ -73,33 +187,30 @@ impl Storage {
                 agent.updated_at,
             ],
         )?;
+        self.mark_dirty(&tx, &agent.id)?;
+        tx.commit()?;
+        self.cache.invalidate(&agent.id);
         Ok(())
     }
     
+    /// An agent by id, trashed or not. Served from the cache when it can be.
     pub fn get_agent(&self, id: &str) -> Result<Option<StoredAgent>> {
-        let conn = self.conn()?;
-        let result = conn.query_row(
-            "SELECT id, name, system_prompt, config, state, created_at, updated_at
-             FROM agents WHERE id = ?1",
-            params![id],
-            |row| {
-                Ok(StoredAgent {
-                    id: row.get(0)?,
-                    name: row.get(1)?,
-                    system_prompt: row.get(2)?,
-                    config: serde_json::from_str(&row.get::<_, String>(3)?).unwrap(),
-                    state: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
-                    created_at: row.get(5)?,
-                    updated_at: row.get(6)?,
-                })
-            },
-        ).optional()?;
-        Ok(result)
+        self.cache.agent(id, || {
+            let conn = self.conn()?;
+            let result = conn.query_row(
+                "SELECT id, name, system_prompt, config, state, created_at, updated_at, deleted_at
+                 FROM agents WHERE id = ?1",
+                params![id],
+                agent_from_row,
+            ).optional()?;
+            Ok(result)
+        })
     }
     
     pub fn update_agent(&self, agent: &StoredAgent) -> Result<()> {
-        let conn = self.conn()?;
-        conn.execute(
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
             "UPDATE agents SET name = ?2, system_prompt = ?3, config = ?4, state = ?5, updated_at = ?6
              WHERE id = ?1",
             params![
@@ -111,85 +222,323 @@ impl Storage {
                 Utc::now(),
             ],
         )?;
+        self.mark_dirty(&tx, &agent.id)?;
+        tx.commit()?;
+        self.cache.invalidate(&agent.id);
         Ok(())
     }
     
+    /// Write an agent with its blocks and messages in one transaction, so a
+    /// failure part way leaves the previous snapshot intact. The agent is
+    /// created or updated, blocks are upserted by label, and only messages
+    /// with new ids are inserted.
+    pub fn save_agent_snapshot(
+        &self,
+        agent: &StoredAgent,
+        blocks: &[StoredBlock],
+        messages: &[StoredMessage],
+    ) -> Result<()> {
+        self.save_agent_snapshot_with_chunks(agent, blocks, messages, &[])
+    }
+    
+    /// Like `save_agent_snapshot`, also adding `chunks` in the same
+    /// transaction. Chunks whose id is already stored are left as they are.
+    pub fn save_agent_snapshot_with_chunks(
+        &self,
+        agent: &StoredAgent,
+        blocks: &[StoredBlock],
+        messages: &[StoredMessage],
+        chunks: &[StoredChunk],
+    ) -> Result<()> {
+        self.save_agent_step(agent, blocks, messages, chunks, &[])
+    }
+    
+    /// Like `save_agent_snapshot_with_chunks`, also deleting the agent's
+    /// messages with ids in `deleted_messages`, e.g. the reply a regenerate
+    /// replaced, so the history never holds both
+    pub fn save_agent_step(
+        &self,
+        agent: &StoredAgent,
+        blocks: &[StoredBlock],
+        messages: &[StoredMessage],
+        chunks: &[StoredChunk],
+        deleted_messages: &[String],
+    ) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO agents (id, name, system_prompt, config, state, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(id) DO UPDATE SET
+                name = excluded.name,
+                system_prompt = excluded.system_prompt,
+                config = excluded.config,
+                state = excluded.state,
+                updated_at = ?8",
+            params![
+                agent.id,
+                agent.name,
+                agent.system_prompt,
+                serde_json::to_string(&agent.config)?,
+                serde_json::to_string(&agent.state)?,
+                agent.created_at,
+                agent.updated_at,
+                Utc::now(),
+            ],
+        )?;
+        for block in blocks {
+            upsert_block(&tx, block, BlockWriter::Snapshot)?;
+        }
+        delete_messages(&tx, &agent.id, deleted_messages)?;
+        for message in messages {
+            insert_message_if_absent(&tx, message)?;
+        }
+        self.insert_chunks(&tx, chunks, Some(OnConflict::Skip))?;
+        self.mark_dirty(&tx, &agent.id)?;
+        tx.commit()?;
+        self.cache.invalidate(&agent.id);
+        Ok(())
+    }
+    
+    /// Every agent not in the trash, most recently updated first
     pub fn list_agents(&self) -> Result<Vec<StoredAgent>> {
+        self.list_agents_filtered(false)
+    }
+    
+    /// Like `list_agents`, with `include_deleted` also listing trashed agents
+    pub fn list_agents_filtered(&self, include_deleted: bool) -> Result<Vec<StoredAgent>> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, name, system_prompt, config, state, created_at, updated_at
-             FROM agents ORDER BY updated_at DESC"
+            "SELECT id, name, system_prompt, config, state, created_at, updated_at, deleted_at
+             FROM agents WHERE ?1 OR deleted_at IS NULL
+             ORDER BY updated_at DESC"
         )?;
         
-        let agents = stmt.query_map([], |row| {
-            Ok(StoredAgent {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                system_prompt: row.get(2)?,
-                config: serde_json::from_str(&row.get::<_, String>(3)?).unwrap(),
-                state: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
-                created_at: row.get(5)?,
-                updated_at: row.get(6)?,
-            })
-        })?
-        .collect::<rusqlite::Result<Vec<_>>>()?;
+        let agents = stmt.query_map(params![include_deleted], agent_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
         
         Ok(agents)
     }
     
-    // Block operations
-    pub fn upsert_block(&self, block: &StoredBlock) -> Result<()> {
+    /// Like `list_agents`, but an agent whose stored JSON is corrupted is
+    /// skipped with a warning rather than failing the listing
+    pub fn list_agents_lenient(&self) -> Result<Lenient<StoredAgent>> {
         let conn = self.conn()?;
-        conn.execute(
-            "INSERT INTO blocks (id, agent_id, label, description, value, limit, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-             ON CONFLICT(agent_id, label) DO UPDATE SET
-                value = excluded.value,
-                description = excluded.description,
-                limit = excluded.limit,
-                updated_at = excluded.updated_at",
-            params![
-                block.id,
-                block.agent_id,
-                block.label,
-                block.description,
-                block.value,
-                block.limit,
-                block.updated_at,
-            ],
+        let mut stmt = conn.prepare(
+            "SELECT id, name, system_prompt, config, state, created_at, updated_at, deleted_at
+             FROM agents WHERE deleted_at IS NULL ORDER BY updated_at DESC"
+        )?;
+        let rows = stmt.query_map([], agent_from_row)?;
+        collect_lenient(rows)
+    }
+    
+    /// Up to `limit` agents updated after the cursor, least recently updated
+    /// first. Start with `None` and pass each page's `next_cursor` to walk
+    /// every agent, or to pick up agents changed since a previous walk.
+    pub fn list_agents_page(&self, limit: usize, after_updated_at: Option<&PageCursor>) -> Result<Page<StoredAgent>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, name, system_prompt, config, state, created_at, updated_at, deleted_at
+             FROM agents
+             WHERE (?1 IS NULL OR (updated_at, id) > (?1, ?2)) AND deleted_at IS NULL
+             ORDER BY updated_at ASC, id ASC LIMIT ?3"
+        )?;
+        
+        let cursor = after_updated_at.map(|c| (c.timestamp, c.id.as_str()));
+        let agents = stmt.query_map(
+            params![cursor.map(|c| c.0), cursor.map(|c| c.1), limit + 1],
+            agent_from_row,
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(page(agents, limit, |agent| PageCursor { timestamp: agent.updated_at, id: agent.id.clone() }))
+    }
+    
+    /// Delete an agent with its blocks, messages, chunks (and their search
+    /// entries), settings and sync state in one transaction. Queued uploads
+    /// are dropped too so auto-sync cannot recreate it. Returns whether the
+    /// agent existed.
+    ///
+    /// Dependent rows also go through foreign keys and triggers when an
+    /// agent row is deleted directly.
+    pub fn delete_agent(&self, id: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        if self.vector_index {
+            vector::remove_agent(&tx, id)?;
+        }
+        let deleted = tx.execute("DELETE FROM agents WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        self.cache.invalidate(id);
+        Ok(deleted > 0)
+    }
+    
+    /// Move an agent to the trash: it drops out of listings but keeps its
+    /// rows until restored or purged. A tombstone in the sync metadata and a
+    /// queued deletion pass it on to the cloud. Returns whether an agent
+    /// outside the trash was found.
+    pub fn soft_delete_agent(&self, id: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let now = Utc::now();
+        let trashed = tx.execute(
+            "UPDATE agents SET deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL",
+            params![id, now],
+        )?;
+        if trashed == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "INSERT INTO sync_metadata (entity_type, entity_id, last_sync_at, sync_status)
+             VALUES ('agent', ?1, ?2, ?3)
+             ON CONFLICT(entity_type, entity_id) DO UPDATE SET sync_status = excluded.sync_status",
+            params![id, now, SyncStatus::Deleted],
+        )?;
+        // Uploading the agent now would only be undone by the deletion
+        tx.execute(
+            "DELETE FROM sync_queue WHERE entity_type = 'agent' AND entity_id = ?1 AND operation = 'upsert'",
+            params![id],
+        )?;
+        enqueue(&tx, "agent", id, "delete")?;
+        tx.commit()?;
+        self.cache.invalidate(id);
+        Ok(true)
+    }
+    
+    /// Take an agent back out of the trash, returning whether it was there.
+    /// A deletion not yet sent is dropped; one already sent is undone by
+    /// uploading the agent again.
+    pub fn restore_agent(&self, id: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let restored = tx.execute(
+            "UPDATE agents SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
+            params![id],
+        )?;
+        if restored == 0 {
+            return Ok(false);
+        }
+        tx.execute(
+            "UPDATE sync_metadata SET sync_status = ?2
+             WHERE entity_type = 'agent' AND entity_id = ?1 AND sync_status = ?3",
+            params![id, SyncStatus::Pending, SyncStatus::Deleted],
         )?;
+        tx.execute(
+            "DELETE FROM sync_queue WHERE entity_type = 'agent' AND entity_id = ?1 AND operation = 'delete'",
+            params![id],
+        )?;
+        self.mark_dirty(&tx, id)?;
+        tx.commit()?;
+        self.cache.invalidate(id);
+        Ok(true)
+    }
+    
+    /// Permanently delete agents trashed longer ago than `older_than`, as
+    /// `delete_agent` does, returning how many went. Their tombstones and
+    /// queued deletions are kept until the cloud has been told.
+    pub fn purge_deleted(&self, older_than: chrono::Duration) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let ids = {
+            let mut stmt = tx.prepare("SELECT id FROM agents WHERE deleted_at <= ?1")?;
+            let ids = stmt.query_map(params![Utc::now() - older_than], |row| row.get::<_, String>(0))?;
+            ids.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for id in &ids {
+            if self.vector_index {
+                vector::remove_agent(&tx, id)?;
+            }
+            tx.execute("DELETE FROM agents WHERE id = ?1", params![id])?;
+        }
+        tx.commit()?;
+        for id in &ids {
+            self.cache.invalidate(id);
+        }
+        if !ids.is_empty() {
+            tracing::info!(purged = ids.len(), "Purged trashed agents");
+        }
+        Ok(ids.len())
+    }
+    
+    // Block operations
+    /// Create or update a block, as changed by the host app. See
+    /// `upsert_block_by`.
+    pub fn upsert_block(&self, block: &StoredBlock) -> Result<()> {
+        self.upsert_block_by(block, BlockWriter::User)
+    }
+    
+    /// Create or update a block by `(agent_id, label)`. A change to its value
+    /// is recorded in the block's history, crediting `writer`; the history
+    /// keeps the newest `BLOCK_HISTORY_LIMIT` changes per block.
+    pub fn upsert_block_by(&self, block: &StoredBlock, writer: BlockWriter) -> Result<()> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        upsert_block(&tx, block, writer)?;
+        self.mark_dirty(&tx, &block.agent_id)?;
+        tx.commit()?;
+        self.cache.invalidate(&block.agent_id);
         Ok(())
     }
     
-    pub fn get_blocks(&self, agent_id: &str) -> Result<Vec<StoredBlock>> {
+    /// The newest `limit` changes to a block's value, newest first
+    pub fn get_block_history(&self, agent_id: &str, label: &str, limit: usize) -> Result<Vec<BlockChange>> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, agent_id, label, description, value, limit, updated_at
-             FROM blocks WHERE agent_id = ?1"
+            "SELECT id, agent_id, label, old_value, new_value, changed_at, changed_by
+             FROM blocks_history WHERE agent_id = ?1 AND label = ?2
+             ORDER BY id DESC LIMIT ?3"
         )?;
         
-        let blocks = stmt.query_map(params![agent_id], |row| {
-            Ok(StoredBlock {
+        let changes = stmt.query_map(params![agent_id, label, limit], |row| {
+            Ok(BlockChange {
                 id: row.get(0)?,
                 agent_id: row.get(1)?,
                 label: row.get(2)?,
-                description: row.get(3)?,
-                value: row.get(4)?,
-                limit: row.get(5)?,
-                updated_at: row.get(6)?,
+                old_value: row.get(3)?,
+                new_value: row.get(4)?,
+                changed_at: row.get(5)?,
+                changed_by: row.get(6)?,
             })
         })?
         .collect::<rusqlite::Result<Vec<_>>>()?;
         
-        Ok(blocks)
+        Ok(changes)
+    }
+    
+    /// An agent's memory blocks. Served from the cache when it can be.
+    pub fn get_blocks(&self, agent_id: &str) -> Result<Vec<StoredBlock>> {
+        self.cache.blocks(agent_id, || {
+            let conn = self.conn()?;
+            let mut stmt = conn.prepare(
+                "SELECT id, agent_id, label, description, value, char_limit, updated_at
+                 FROM blocks WHERE agent_id = ?1"
+            )?;
+            
+            let blocks = stmt.query_map(params![agent_id], |row| {
+                Ok(StoredBlock {
+                    id: row.get(0)?,
+                    agent_id: row.get(1)?,
+                    label: row.get(2)?,
+                    description: row.get(3)?,
+                    value: row.get(4)?,
+                    char_limit: row.get(5)?,
+                    updated_at: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+            
+            Ok(blocks)
+        })
     }
     
     // Message operations
     pub fn add_message(&self, message: &StoredMessage) -> Result<()> {
-        let conn = self.conn()?;
-        conn.execute(
-            "INSERT INTO messages (id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO messages (id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8,
+                     (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE agent_id = ?2))",
             params![
                 message.id,
                 message.agent_id,
@@ -201,121 +550,462 @@ impl Storage {
                 message.timestamp,
             ],
         )?;
+        self.mark_dirty(&tx, &message.agent_id)?;
+        tx.commit()?;
         Ok(())
     }
     
+    /// The agent's newest `limit` messages, newest first
     pub fn get_messages(&self, agent_id: &str, limit: usize) -> Result<Vec<StoredMessage>> {
+        self.query_messages(agent_id, &MessageQuery { limit: Some(limit), ..Default::default() })
+    }
+    
+    /// The agent's messages matching `query`, ordered by `(timestamp, id)`.
+    /// With a limit the newest matches are kept, so an ascending query with
+    /// a limit gives the tail of the history in reading order.
+    pub fn query_messages(&self, agent_id: &str, query: &MessageQuery) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn()?;
+        let order = if query.ascending { "ASC" } else { "DESC" };
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM (
+                SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
+                FROM messages
+                WHERE agent_id = ?1
+                  AND (?2 IS NULL OR timestamp >= ?2) AND (?3 IS NULL OR timestamp < ?3)
+                  AND (?4 IS NULL OR (timestamp, id) < (?4, ?5))
+                ORDER BY timestamp DESC, id DESC LIMIT ?6
+             ) ORDER BY timestamp {0}, id {0}",
+            order,
+        ))?;
+        
+        let cursor = query.before.as_ref().map(|c| (c.timestamp, c.id.as_str()));
+        let limit = query.limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        let messages = stmt.query_map(
+            params![agent_id, query.range.from, query.range.until, cursor.map(|c| c.0), cursor.map(|c| c.1), limit],
+            message_from_row,
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(messages)
+    }
+    
+    /// Like `get_messages`, but a message whose stored JSON is corrupted is
+    /// skipped with a warning. Skipped messages still count towards `limit`.
+    pub fn get_messages_lenient(&self, agent_id: &str, limit: usize) -> Result<Lenient<StoredMessage>> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
              FROM messages WHERE agent_id = ?1
              ORDER BY timestamp DESC LIMIT ?2"
         )?;
+        let rows = stmt.query_map(params![agent_id, limit], message_from_row)?;
+        collect_lenient(rows)
+    }
+    
+    /// One page of an agent's messages by keyset on `(timestamp, id)`: newest
+    /// first by default, oldest first with `ascending`. Messages added while
+    /// paging never cause duplicates or gaps in the pages still to come.
+    pub fn get_messages_page(&self, agent_id: &str, request: &PageRequest) -> Result<Page<StoredMessage>> {
+        let conn = self.conn()?;
+        let sql = if request.ascending {
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
+             FROM messages
+             WHERE agent_id = ?1 AND (?2 IS NULL OR (timestamp, id) > (?2, ?3))
+             ORDER BY timestamp ASC, id ASC LIMIT ?4"
+        } else {
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
+             FROM messages
+             WHERE agent_id = ?1 AND (?2 IS NULL OR (timestamp, id) < (?2, ?3))
+             ORDER BY timestamp DESC, id DESC LIMIT ?4"
+        };
+        let mut stmt = conn.prepare(sql)?;
         
-        let messages = stmt.query_map(params![agent_id, limit], |row| {
-            Ok(StoredMessage {
-                id: row.get(0)?,
-                agent_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                tool_calls: row.get::<_, Option<String>>(4)?
-                    .map(|s| serde_json::from_str(&s).unwrap()),
-                tool_call_id: row.get(5)?,
-                metadata: serde_json::from_str(&row.get::<_, String>(6)?).unwrap(),
-                timestamp: row.get(7)?,
-            })
-        })?
+        let cursor = request.before.as_ref().map(|c| (c.timestamp, c.id.as_str()));
+        let messages = stmt.query_map(
+            params![agent_id, cursor.map(|c| c.0), cursor.map(|c| c.1), request.limit + 1],
+            message_from_row,
+        )?
         .collect::<rusqlite::Result<Vec<_>>>()?;
         
-        Ok(messages)
+        Ok(page(messages, request.limit, |message| PageCursor {
+            timestamp: message.timestamp,
+            id: message.id.clone(),
+        }))
+    }
+    
+    pub fn get_message(&self, id: &str) -> Result<Option<StoredMessage>> {
+        let conn = self.conn()?;
+        let message = conn.query_row(
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
+             FROM messages WHERE id = ?1",
+            params![id],
+            message_from_row,
+        ).optional()?;
+        Ok(message)
     }
     
+    /// Search an agent's messages with an FTS5 query, best match first.
+    /// Phrases (`"brown fox"`), boolean operators (`tea NOT coffee`) and
+    /// prefixes (`tea*`) are supported. A query that isn't valid FTS5 syntax
+    /// falls back to a plain substring match, newest first.
     pub fn search_messages(&self, agent_id: &str, query: &str, limit: usize) -> Result<Vec<StoredMessage>> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp
+            "SELECT m.id, m.agent_id, m.role, m.content, m.tool_calls, m.tool_call_id, m.metadata, m.timestamp, m.seq
+             FROM messages m
+             JOIN messages_fts f ON m.rowid = f.rowid
+             WHERE m.agent_id = ?1 AND messages_fts MATCH ?2
+             ORDER BY bm25(messages_fts) LIMIT ?3"
+        )?;
+        
+        let matched = stmt.query_map(params![agent_id, query, limit], message_from_row)
+            .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>());
+        match matched {
+            // FTS5 reports malformed queries as a generic SQLITE_ERROR, told
+            // apart from other failures only by the message
+            Err(rusqlite::Error::SqliteFailure(e, Some(message)))
+                if e.code == rusqlite::ErrorCode::Unknown && is_fts5_syntax_error(&message) =>
+            {
+                tracing::debug!("Message query {:?} is not FTS5 syntax, using a substring match", query);
+            }
+            matched => return Ok(matched?),
+        }
+        
+        let mut stmt = conn.prepare(
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
              FROM messages 
              WHERE agent_id = ?1 AND content LIKE ?2
              ORDER BY timestamp DESC LIMIT ?3"
         )?;
         
         let pattern = format!("%{}%", query);
-        let messages = stmt.query_map(params![agent_id, pattern, limit], |row| {
-            Ok(StoredMessage {
-                id: row.get(0)?,
-                agent_id: row.get(1)?,
-                role: row.get(2)?,
-                content: row.get(3)?,
-                tool_calls: row.get::<_, Option<String>>(4)?
-                    .map(|s| serde_json::from_str(&s).unwrap()),
-                tool_call_id: row.get(5)?,
-                metadata: serde_json::from_str(&row.get::<_, String>(6)?).unwrap(),
-                timestamp: row.get(7)?,
-            })
-        })?
-        .collect::<rusqlite::Result<Vec<_>>>()?;
+        let messages = stmt.query_map(params![agent_id, pattern, limit], message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(messages)
+    }
+    
+    /// Messages with `seq` strictly greater than `after_seq`, oldest first
+    pub fn get_messages_since(&self, agent_id: &str, after_seq: i64) -> Result<Vec<StoredMessage>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq
+             FROM messages WHERE agent_id = ?1 AND seq > ?2
+             ORDER BY seq ASC"
+        )?;
+        
+        let messages = stmt.query_map(params![agent_id, after_seq], message_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
         
         Ok(messages)
     }
     
+    /// Insert a message unless one with the same id already exists.
+    /// Returns whether a row was written.
+    pub fn add_message_if_absent(&self, message: &StoredMessage) -> Result<bool> {
+        let conn = self.conn()?;
+        insert_message_if_absent(&conn, message)
+    }
+    
+    /// Delete an agent's messages by id, returning how many were removed.
+    /// Ids not stored for that agent are skipped.
+    pub fn delete_messages(&self, agent_id: &str, ids: &[String]) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let removed = delete_messages(&tx, agent_id, ids)?;
+        if removed > 0 {
+            self.mark_dirty(&tx, agent_id)?;
+        }
+        tx.commit()?;
+        Ok(removed)
+    }
+    
+    pub fn count_messages(&self, agent_id: &str) -> Result<usize> {
+        let conn = self.conn()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE agent_id = ?1",
+            params![agent_id],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+    
+    /// How many of the agent's messages fall within `range`
+    pub fn count_messages_in_range(&self, agent_id: &str, range: &TimeRange) -> Result<usize> {
+        let conn = self.conn()?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages
+             WHERE agent_id = ?1 AND (?2 IS NULL OR timestamp >= ?2) AND (?3 IS NULL OR timestamp < ?3)",
+            params![agent_id, range.from, range.until],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+    
+    /// Highest message `seq` stored for an agent, or 0 when it has none
+    pub fn latest_message_seq(&self, agent_id: &str) -> Result<i64> {
+        let conn = self.conn()?;
+        let seq = conn.query_row(
+            "SELECT COALESCE(MAX(seq), 0) FROM messages WHERE agent_id = ?1",
+            params![agent_id],
+            |row| row.get(0),
+        )?;
+        Ok(seq)
+    }
+    
     // Chunk operations
+    /// Store a chunk. Its embedding, if any, must be non-empty and finite,
+    /// and the same size as the agent's other embeddings from that model.
     pub fn add_chunk(&self, chunk: &StoredChunk) -> Result<()> {
+        self.add_chunks(std::slice::from_ref(chunk), None)?;
+        Ok(())
+    }
+    
+    /// Insert many chunks in one transaction, returning how many were written.
+    /// A chunk whose id is already stored fails the whole batch unless
+    /// `on_conflict` says to skip or replace it; skipped chunks aren't counted.
+    /// The full-text index is updated once, as the transaction commits.
+    /// Embeddings are checked as for `add_chunk`.
+    pub fn add_chunks(&self, chunks: &[StoredChunk], on_conflict: Option<OnConflict>) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let written = self.insert_chunks(&tx, chunks, on_conflict)?;
+        tx.commit()?;
+        Ok(written)
+    }
+    
+    /// The inserts of `add_chunks`, within the caller's transaction
+    fn insert_chunks(&self, tx: &Connection, chunks: &[StoredChunk], on_conflict: Option<OnConflict>) -> Result<usize> {
+        if chunks.is_empty() {
+            return Ok(0);
+        }
+        let conflict_clause = match on_conflict {
+            None => "",
+            Some(OnConflict::Skip) => "ON CONFLICT(id) DO NOTHING",
+            Some(OnConflict::Replace) => "ON CONFLICT(id) DO UPDATE SET
+                agent_id = excluded.agent_id,
+                folder = excluded.folder,
+                text = excluded.text,
+                metadata = excluded.metadata,
+                embedding = excluded.embedding,
+                embedding_dim = excluded.embedding_dim,
+                embedding_model = excluded.embedding_model,
+                created_at = excluded.created_at",
+        };
+        
+        let mut written = 0;
+        let mut agent_ids = std::collections::BTreeSet::new();
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO chunks (id, agent_id, folder, text, metadata, embedding, embedding_dim, embedding_model, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 {}
+                 RETURNING rowid",
+                conflict_clause,
+            ))?;
+            for chunk in chunks {
+                if let Some(embedding) = &chunk.embedding {
+                    validate_embedding(tx, &chunk.id, &chunk.agent_id, chunk.embedding_model.as_deref(), embedding)?;
+                }
+                let rowid: Option<i64> = stmt.query_row(
+                    params![
+                        chunk.id,
+                        chunk.agent_id,
+                        chunk.folder,
+                        chunk.text,
+                        serde_json::to_string(&chunk.metadata)?,
+                        chunk.embedding.as_deref().map(vector::embedding_bytes),
+                        chunk.embedding.as_ref().map(|e| e.len()),
+                        chunk.embedding_model,
+                        chunk.created_at,
+                    ],
+                    |row| row.get(0),
+                ).optional()?;
+                let Some(rowid) = rowid else {
+                    continue;
+                };
+                match (self.vector_index, &chunk.embedding) {
+                    (true, Some(embedding)) => vector::index_chunk(tx, rowid, &chunk.agent_id, embedding)?,
+                    (true, None) => vector::remove_chunk(tx, rowid)?,
+                    (false, _) => {}
+                }
+                agent_ids.insert(chunk.agent_id.as_str());
+                written += 1;
+            }
+        }
+        for agent_id in agent_ids {
+            self.mark_dirty(tx, agent_id)?;
+        }
+        Ok(written)
+    }
+    
+    /// Delete a chunk, returning whether it existed
+    pub fn delete_chunk(&self, id: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let deleted: Option<(i64, String)> = tx.query_row(
+            "DELETE FROM chunks WHERE id = ?1 RETURNING rowid, agent_id",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).optional()?;
+        let Some((rowid, agent_id)) = deleted else {
+            return Ok(false);
+        };
+        if self.vector_index {
+            vector::remove_chunk(&tx, rowid)?;
+        }
+        self.mark_dirty(&tx, &agent_id)?;
+        tx.commit()?;
+        Ok(true)
+    }
+    
+    /// Delete every chunk in one of an agent's folders, returning how many went
+    pub fn delete_chunks_by_folder(&self, agent_id: &str, folder: &str) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let rowids = {
+            let mut stmt = tx.prepare("DELETE FROM chunks WHERE agent_id = ?1 AND folder = ?2 RETURNING rowid")?;
+            let rowids = stmt.query_map(params![agent_id, folder], |row| row.get::<_, i64>(0))?;
+            rowids.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        if rowids.is_empty() {
+            return Ok(0);
+        }
+        if self.vector_index {
+            for rowid in &rowids {
+                vector::remove_chunk(&tx, *rowid)?;
+            }
+        }
+        self.mark_dirty(&tx, agent_id)?;
+        tx.commit()?;
+        Ok(rowids.len())
+    }
+    
+    /// Replace a chunk's text, returning whether it existed. The old
+    /// embedding no longer describes the text, so it is replaced by
+    /// `embedding`, or cleared if that is `None`.
+    pub fn update_chunk_text(&self, id: &str, text: &str, embedding: Option<&[f32]>) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let updated: Option<(i64, String, Option<String>)> = tx.query_row(
+            "UPDATE chunks SET text = ?2, embedding = ?3, embedding_dim = ?4 WHERE id = ?1
+             RETURNING rowid, agent_id, embedding_model",
+            params![id, text, embedding.map(vector::embedding_bytes), embedding.map(|e| e.len())],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?;
+        let Some((rowid, agent_id, model)) = updated else {
+            return Ok(false);
+        };
+        if let Some(embedding) = embedding {
+            validate_embedding(&tx, id, &agent_id, model.as_deref(), embedding)?;
+        }
+        match (self.vector_index, embedding) {
+            (true, Some(embedding)) => vector::index_chunk(&tx, rowid, &agent_id, embedding)?,
+            (true, None) => vector::remove_chunk(&tx, rowid)?,
+            (false, _) => {}
+        }
+        self.mark_dirty(&tx, &agent_id)?;
+        tx.commit()?;
+        Ok(true)
+    }
+    
+    /// An agent's chunk folders with how many chunks each holds, by name
+    pub fn list_folders(&self, agent_id: &str) -> Result<Vec<(String, usize)>> {
         let conn = self.conn()?;
-        conn.execute(
-            "INSERT INTO chunks (id, agent_id, folder, text, metadata, embedding, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![
-                chunk.id,
-                chunk.agent_id,
-                chunk.folder,
-                chunk.text,
-                serde_json::to_string(&chunk.metadata)?,
-                chunk.embedding.as_ref().map(|v| {
-                    let bytes: Vec<u8> = v.iter().flat_map(|f| f.to_le_bytes()).collect();
-                    bytes
-                }),
-                chunk.created_at,
-            ],
+        let mut stmt = conn.prepare(
+            "SELECT folder, COUNT(*) FROM chunks WHERE agent_id = ?1
+             GROUP BY folder ORDER BY folder"
         )?;
-        Ok(())
+        let folders = stmt.query_map(params![agent_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(folders)
     }
     
+    /// Every chunk of an agent, oldest first
+    pub fn get_chunks(&self, agent_id: &str) -> Result<Vec<StoredChunk>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, agent_id, folder, text, metadata, embedding, created_at, embedding_model
+             FROM chunks WHERE agent_id = ?1 ORDER BY created_at, id"
+        )?;
+        let chunks = stmt.query_map(params![agent_id], chunk_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(chunks)
+    }
+
     pub fn search_chunks_fts(&self, agent_id: &str, query: &str, limit: usize) -> Result<Vec<StoredChunk>> {
         let conn = self.conn()?;
         let mut stmt = conn.prepare(
-            "SELECT c.id, c.agent_id, c.folder, c.text, c.metadata, c.embedding, c.created_at
+            "SELECT c.id, c.agent_id, c.folder, c.text, c.metadata, c.embedding, c.created_at, c.embedding_model
              FROM chunks c
              JOIN chunks_fts f ON c.rowid = f.rowid
              WHERE c.agent_id = ?1 AND chunks_fts MATCH ?2
              ORDER BY rank LIMIT ?3"
         )?;
         
-        let chunks = stmt.query_map(params![agent_id, query, limit], |row| {
-            Ok(StoredChunk {
-                id: row.get(0)?,
-                agent_id: row.get(1)?,
-                folder: row.get(2)?,
-                text: row.get(3)?,
-                metadata: serde_json::from_str(&row.get::<_, String>(4)?).unwrap(),
-                embedding: row.get::<_, Option<Vec<u8>>>(5)?
-                    .map(|bytes| {
-                        bytes.chunks(4)
-                            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
-                            .collect()
-                    }),
-                created_at: row.get(6)?,
-            })
-        })?
-        .collect::<rusqlite::Result<Vec<_>>>()?;
+        let chunks = stmt.query_map(params![agent_id, query, limit], chunk_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
         
         Ok(chunks)
     }
     
+    /// The agent's `limit` chunks whose embeddings are most similar to
+    /// `embedding` by cosine similarity, most similar first. Chunks without an
+    /// embedding of the same size are skipped.
+    ///
+    /// With the `vector-index` feature this is a KNN query against sqlite-vec;
+    /// otherwise, or if the extension failed to load, every embedding is scanned.
+    pub fn search_chunks_vector(&self, agent_id: &str, embedding: &[f32], limit: usize) -> Result<Vec<StoredChunk>> {
+        let conn = self.conn()?;
+        if self.vector_index {
+            if let Some(rowids) = vector::nearest(&conn, agent_id, embedding, limit)? {
+                let mut stmt = conn.prepare(
+                    "SELECT id, agent_id, folder, text, metadata, embedding, created_at, embedding_model
+                     FROM chunks WHERE rowid = ?1"
+                )?;
+                let mut chunks = Vec::with_capacity(rowids.len());
+                for rowid in rowids {
+                    if let Some(chunk) = stmt.query_row(params![rowid], chunk_from_row).optional()? {
+                        chunks.push(chunk);
+                    }
+                }
+                return Ok(chunks);
+            }
+        }
+        
+        let mut stmt = conn.prepare(
+            "SELECT id, agent_id, folder, text, metadata, embedding, created_at, embedding_model
+             FROM chunks WHERE agent_id = ?1 AND embedding_dim = ?2"
+        )?;
+        let mut scored = stmt.query_map(params![agent_id, embedding.len()], chunk_from_row)?
+            .filter_map(|chunk| match chunk {
+                Ok(chunk) => {
+                    let stored = chunk.embedding.as_deref().filter(|e| e.len() == embedding.len())?;
+                    let score = vector::cosine_similarity(stored, embedding);
+                    Some(Ok((score, chunk)))
+                }
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored.into_iter().take(limit).map(|(_, chunk)| chunk).collect())
+    }
+    
+    /// Record, within the caller's transaction, that the owning agent has
+    /// local changes the cloud hasn't seen, unless changes aren't tracked
+    fn mark_dirty(&self, conn: &Connection, agent_id: &str) -> Result<()> {
+        if self.track_changes {
+            mark_dirty(conn, agent_id)?;
+        }
+        Ok(())
+    }
+    
     // Sync operations
     pub fn get_sync_metadata(&self, entity_type: &str, entity_id: &str) -> Result<Option<SyncMetadata>> {
         let conn = self.conn()?;
         let result = conn.query_row(
-            "SELECT entity_type, entity_id, local_version, cloud_version, last_sync_at, sync_status
+            "SELECT entity_type, entity_id, local_version, cloud_version, last_sync_at, sync_status, last_synced_seq, conflicts
              FROM sync_metadata WHERE entity_type = ?1 AND entity_id = ?2",
             params![entity_type, entity_id],
             |row| {
@@ -326,6 +1016,9 @@ impl Storage {
                     cloud_version: row.get(3)?,
                     last_sync_at: row.get(4)?,
                     sync_status: row.get(5)?,
+                    last_synced_seq: row.get(6)?,
+                    conflicts: row.get::<_, Option<String>>(7)?
+                        .and_then(|s| serde_json::from_str(&s).ok()),
                 })
             },
         ).optional()?;
@@ -335,13 +1028,15 @@ impl Storage {
     pub fn update_sync_metadata(&self, metadata: &SyncMetadata) -> Result<()> {
         let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO sync_metadata (entity_type, entity_id, local_version, cloud_version, last_sync_at, sync_status)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "INSERT INTO sync_metadata (entity_type, entity_id, local_version, cloud_version, last_sync_at, sync_status, last_synced_seq, conflicts)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
              ON CONFLICT(entity_type, entity_id) DO UPDATE SET
                 local_version = excluded.local_version,
                 cloud_version = excluded.cloud_version,
                 last_sync_at = excluded.last_sync_at,
-                sync_status = excluded.sync_status",
+                sync_status = excluded.sync_status,
+                last_synced_seq = excluded.last_synced_seq,
+                conflicts = excluded.conflicts",
             params![
                 metadata.entity_type,
                 metadata.entity_id,
@@ -349,38 +1044,540 @@ impl Storage {
                 metadata.cloud_version,
                 metadata.last_sync_at,
                 metadata.sync_status,
+                metadata.last_synced_seq,
+                metadata.conflicts.as_ref().map(|c| c.to_string()),
             ],
         )?;
         Ok(())
     }
     
-    // Backup and restore
-    pub fn backup(&self, path: &Path) -> Result<()> {
+    // Sync queue operations
+    pub fn enqueue_sync(&self, entity_type: &str, entity_id: &str, operation: &str) -> Result<()> {
         let conn = self.conn()?;
-        let mut backup_conn = Connection::open(path)?;  // 修复1：添加 mut 关键字，声明可变连接
-        // 修复2：第二个参数改为 &mut backup_conn，满足函数对可变引用的要求
-        let backup = rusqlite::backup::Backup::new(&conn, &mut backup_conn)?;
-        backup.run_to_completion(5, std::time::Duration::from_millis(250), None)?;
-        Ok(())
+        enqueue(&conn, entity_type, entity_id, operation)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
     
-    #[test]
-    fn test_storage_creation() {
-        let storage = Storage::memory().unwrap();
-        assert!(storage.list_agents().unwrap().is_empty());
+    /// Pending entries whose backoff has elapsed, oldest first
+    pub fn due_sync_entries(&self, now: DateTime<Utc>, limit: usize) -> Result<Vec<SyncQueueEntry>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, entity_type, entity_id, operation, enqueued_at, attempts, next_attempt_at, last_error, status, generation
+             FROM sync_queue WHERE status = 'pending' AND next_attempt_at <= ?1
+             ORDER BY enqueued_at ASC LIMIT ?2"
+        )?;
+        
+        let entries = stmt.query_map(params![now, limit], queue_entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(entries)
     }
     
-    #[test]
-    fn test_agent_crud() {
-        let storage = Storage::memory().unwrap();
+    pub fn list_sync_queue(&self) -> Result<Vec<SyncQueueEntry>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, entity_type, entity_id, operation, enqueued_at, attempts, next_attempt_at, last_error, status, generation
+             FROM sync_queue ORDER BY enqueued_at ASC"
+        )?;
         
-        let agent = StoredAgent::new("test-agent", "Test prompt");
+        let entries = stmt.query_map([], queue_entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        
+        Ok(entries)
+    }
+    
+    /// Drop an entry after its upload succeeded. An entry enqueued again
+    /// since it was read, so at a later `generation`, is kept: the upload may
+    /// have missed the write that queued it.
+    pub fn complete_sync_entry(&self, id: i64, generation: i64) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM sync_queue WHERE id = ?1 AND generation = ?2", params![id, generation])?;
+        Ok(())
+    }
+    
+    /// Drop queued work for an entity whose local state now matches the cloud
+    pub fn cancel_sync_entries(&self, entity_type: &str, entity_id: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM sync_queue WHERE entity_type = ?1 AND entity_id = ?2",
+            params![entity_type, entity_id],
+        )?;
+        Ok(())
+    }
+    
+    /// Record a failed attempt. With `next_attempt_at` of `None` the entry is
+    /// marked failed permanently and is no longer returned as due.
+    pub fn record_sync_failure(&self, id: i64, error: &str, next_attempt_at: Option<DateTime<Utc>>) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "UPDATE sync_queue SET
+                attempts = attempts + 1,
+                last_error = ?2,
+                next_attempt_at = COALESCE(?3, next_attempt_at),
+                status = CASE WHEN ?3 IS NULL THEN 'failed' ELSE 'pending' END
+             WHERE id = ?1",
+            params![id, error, next_attempt_at],
+        )?;
+        Ok(())
+    }
+    
+    // Per-agent sync settings
+    pub fn set_agent_sync_settings(&self, settings: &AgentSyncSettings) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO agent_sync_settings (agent_id, enabled, sync_interval, conflict_resolution, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(agent_id) DO UPDATE SET
+                enabled = excluded.enabled,
+                sync_interval = excluded.sync_interval,
+                conflict_resolution = excluded.conflict_resolution,
+                updated_at = excluded.updated_at",
+            params![
+                settings.agent_id,
+                settings.enabled,
+                settings.sync_interval.map(|ms| ms as i64),
+                settings.conflict_resolution,
+                Utc::now(),
+            ],
+        )?;
+        Ok(())
+    }
+    
+    /// Overrides for `agent_id`, or defaults when none were set
+    pub fn get_agent_sync_settings(&self, agent_id: &str) -> Result<AgentSyncSettings> {
+        let conn = self.conn()?;
+        let settings = conn.query_row(
+            "SELECT agent_id, enabled, sync_interval, conflict_resolution
+             FROM agent_sync_settings WHERE agent_id = ?1",
+            params![agent_id],
+            |row| {
+                Ok(AgentSyncSettings {
+                    agent_id: row.get(0)?,
+                    enabled: row.get(1)?,
+                    sync_interval: row.get::<_, Option<i64>>(2)?.map(|ms| ms as u64),
+                    conflict_resolution: row.get(3)?,
+                })
+            },
+        ).optional()?;
+        Ok(settings.unwrap_or_else(|| AgentSyncSettings::new(agent_id)))
+    }
+    
+    // Settings
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let conn = self.conn()?;
+        let value = conn.query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(value)
+    }
+    
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO settings (key, value, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+            params![key, value, Utc::now()],
+        )?;
+        Ok(())
+    }
+    
+    /// Return the stored value for `key`, storing `init()` first if it has none.
+    /// Concurrent callers all observe the first value written.
+    pub fn get_or_init_setting(&self, key: &str, init: impl FnOnce() -> String) -> Result<String> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO settings (key, value, updated_at) VALUES (?1, ?2, ?3)",
+            params![key, init(), Utc::now()],
+        )?;
+        let value = conn.query_row(
+            "SELECT value FROM settings WHERE key = ?1",
+            params![key],
+            |row| row.get(0),
+        )?;
+        Ok(value)
+    }
+    
+    // Backup and restore
+    pub fn backup(&self, path: &Path) -> Result<()> {
+        let conn = self.conn()?;
+        let mut backup_conn = Connection::open(path)?;  // 修复1：添加 mut 关键字，声明可变连接
+        // 修复2：第二个参数改为 &mut backup_conn，满足函数对可变引用的要求
+        let backup = rusqlite::backup::Backup::new(&conn, &mut backup_conn)?;
+        backup.run_to_completion(5, std::time::Duration::from_millis(250), None)?;
+        Ok(())
+    }
+    
+    /// Replace the database's contents with a backup made by `backup`. The
+    /// backup is checked first and copied in with SQLite's backup API, so a
+    /// failure leaves the current data in place. Migrations added since the
+    /// backup was made are applied afterwards.
+    pub fn restore(&self, path: &Path) -> Result<()> {
+        // Hold off new connections until the restored schema is up to date
+        let connections = self.connections.write().unwrap_or_else(PoisonError::into_inner);
+        let key = connections.config.as_ref().and_then(|config| config.encryption_key.clone());
+        let allow_newer_schema = connections.config.as_ref().is_some_and(|config| config.allow_newer_schema);
+        
+        // Not read-only: checking an FTS5 index needs to write temporary state
+        let source = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        apply_key(&source, key.as_deref())?;
+        validate_backup(&source)?;
+        migrations::check_schema_version(&source, allow_newer_schema)?;
+        
+        let mut conn = connections.pool.get()?;
+        // In a single step, so readers never see a half-restored database
+        let restore = rusqlite::backup::Backup::new(&source, &mut conn)?;
+        restore.run_to_completion(i32::MAX, std::time::Duration::from_millis(50), None)?;
+        drop(restore);
+        migrations::run_migrations(&conn, allow_newer_schema)?;
+        if self.vector_index {
+            vector::reconcile(&conn)?;
+        }
+        self.cache.clear();
+        tracing::info!(path = %path.display(), "Restored database from backup");
+        Ok(())
+    }
+}
+
+/// A restorable backup is an intact database this crate created
+fn validate_backup(conn: &Connection) -> Result<()> {
+    let integrity: String = match conn.query_row("PRAGMA integrity_check", [], |row| row.get(0)) {
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::NotADatabase => {
+            return Err(StorageError::NotADatabase);
+        }
+        result => result?,
+    };
+    if integrity != "ok" {
+        return Err(StorageError::InvalidData(format!("Backup failed integrity check: {}", integrity)));
+    }
+    
+    let has_migrations: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'migrations')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_migrations {
+        return Err(StorageError::InvalidData("Backup is not a letta database".to_string()));
+    }
+    Ok(())
+}
+
+/// Check for sqlite-vec and bring its index up to date
+fn init_vector_index(conn: &Connection) -> Result<bool> {
+    if !vector::available(conn) {
+        return Ok(false);
+    }
+    vector::reconcile(conn)?;
+    Ok(true)
+}
+
+fn chunk_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredChunk> {
+    Ok(StoredChunk {
+        id: row.get(0)?,
+        agent_id: row.get(1)?,
+        folder: row.get(2)?,
+        text: row.get(3)?,
+        metadata: json_column(row, "chunks", 4)?,
+        embedding: match row.get::<_, Option<Vec<u8>>>(5)? {
+            Some(bytes) => Some(vector::embedding_from_bytes(&bytes).ok_or_else(|| {
+                corrupt_column(row, "chunks", 5, format!("embedding of {} bytes is not a whole number of f32s", bytes.len()))
+            })?),
+            None => None,
+        },
+        created_at: row.get(6)?,
+        embedding_model: row.get(7)?,
+    })
+}
+
+/// Reject embeddings that can't be compared: empty, not finite, or a
+/// different size from the agent's other embeddings from the same model
+fn validate_embedding(conn: &Connection, chunk_id: &str, agent_id: &str, model: Option<&str>, embedding: &[f32]) -> Result<()> {
+    if embedding.is_empty() {
+        return Err(StorageError::InvalidData(format!("Chunk {} has an empty embedding", chunk_id)));
+    }
+    if !embedding.iter().all(|x| x.is_finite()) {
+        return Err(StorageError::InvalidData(format!("Chunk {} has a non-finite embedding value", chunk_id)));
+    }
+    
+    let existing: Option<usize> = conn.query_row(
+        "SELECT embedding_dim FROM chunks
+         WHERE agent_id = ?1 AND embedding_model IS ?2 AND embedding_dim IS NOT NULL AND id != ?3
+         LIMIT 1",
+        params![agent_id, model, chunk_id],
+        |row| row.get(0),
+    ).optional()?;
+    match existing {
+        Some(dims) if dims != embedding.len() => Err(StorageError::InvalidData(format!(
+            "Chunk {} has a {}-dimensional embedding, but the agent's embeddings from model {} have {}",
+            chunk_id,
+            embedding.len(),
+            model.unwrap_or("(unnamed)"),
+            dims,
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn upsert_block(conn: &Connection, block: &StoredBlock, writer: BlockWriter) -> Result<()> {
+    let old_value: Option<String> = conn.query_row(
+        "SELECT value FROM blocks WHERE agent_id = ?1 AND label = ?2",
+        params![block.agent_id, block.label],
+        |row| row.get(0),
+    ).optional()?;
+    if old_value.as_deref() != Some(block.value.as_str()) {
+        conn.execute(
+            "INSERT INTO blocks_history (agent_id, label, old_value, new_value, changed_at, changed_by)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![block.agent_id, block.label, old_value, block.value, Utc::now(), writer],
+        )?;
+        conn.execute(
+            "DELETE FROM blocks_history WHERE agent_id = ?1 AND label = ?2 AND id NOT IN (
+                SELECT id FROM blocks_history WHERE agent_id = ?1 AND label = ?2
+                ORDER BY id DESC LIMIT ?3
+             )",
+            params![block.agent_id, block.label, BLOCK_HISTORY_LIMIT],
+        )?;
+    }
+    
+    conn.execute(
+        "INSERT INTO blocks (id, agent_id, label, description, value, char_limit, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT(agent_id, label) DO UPDATE SET
+            value = excluded.value,
+            description = excluded.description,
+            char_limit = excluded.char_limit,
+            updated_at = excluded.updated_at",
+        params![
+            block.id,
+            block.agent_id,
+            block.label,
+            block.description,
+            block.value,
+            block.char_limit,
+            block.updated_at,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Whether an FTS5 error means the query itself couldn't be parsed
+fn is_fts5_syntax_error(message: &str) -> bool {
+    message.starts_with("fts5: syntax error") || message == "unterminated string"
+}
+
+fn delete_messages(conn: &Connection, agent_id: &str, ids: &[String]) -> Result<usize> {
+    let mut stmt = conn.prepare("DELETE FROM messages WHERE agent_id = ?1 AND id = ?2")?;
+    let mut removed = 0;
+    for id in ids {
+        removed += stmt.execute(params![agent_id, id])?;
+    }
+    Ok(removed)
+}
+
+fn insert_message_if_absent(conn: &Connection, message: &StoredMessage) -> Result<bool> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO messages (id, agent_id, role, content, tool_calls, tool_call_id, metadata, timestamp, seq)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8,
+                 (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE agent_id = ?2))",
+        params![
+            message.id,
+            message.agent_id,
+            message.role,
+            message.content,
+            message.tool_calls.as_ref().map(serde_json::to_string).transpose()?,
+            message.tool_call_id,
+            serde_json::to_string(&message.metadata)?,
+            message.timestamp,
+        ],
+    )?;
+    Ok(inserted > 0)
+}
+
+/// Build the pool for a database file. The key is tried on a connection of
+/// its own first: a pool whose connections fail to initialise only gives up
+/// after its connection timeout.
+fn open_pool(config: &StorageConfig) -> Result<Pool<SqliteConnectionManager>> {
+    if config.encryption_key.is_some() && !cfg!(feature = "sqlcipher") {
+        return Err(StorageError::EncryptionUnavailable);
+    }
+    
+    let conn = Connection::open(&config.path)?;
+    apply_key(&conn, config.encryption_key.as_deref())?;
+    match conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(())) {
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::NotADatabase => {
+            return Err(StorageError::NotADatabase);
+        }
+        result => result?,
+    }
+    drop(conn);
+    
+    let (key, wal, busy_timeout_ms) = (config.encryption_key.clone(), config.wal, config.busy_timeout_ms);
+    let manager = SqliteConnectionManager::file(&config.path)
+        .with_init(move |conn| init_connection(conn, key.as_deref(), wal, busy_timeout_ms));
+    Ok(Pool::builder().max_size(config.max_connections).build(manager)?)
+}
+
+/// SQLCipher needs the key before anything else touches the file
+fn apply_key(conn: &Connection, key: Option<&str>) -> rusqlite::Result<()> {
+    match key {
+        Some(key) => conn.pragma_update(None, "key", key),
+        None => Ok(()),
+    }
+}
+
+/// Per-connection settings. Foreign keys are always enforced, since deleting
+/// an agent relies on them to cascade.
+fn init_connection(conn: &mut Connection, key: Option<&str>, wal: bool, busy_timeout_ms: u32) -> rusqlite::Result<()> {
+    apply_key(conn, key)?;
+    conn.busy_timeout(std::time::Duration::from_millis(busy_timeout_ms.into()))?;
+    if wal {
+        // journal_mode reports the mode it ended up in, so it is queried
+        // rather than set
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+    }
+    conn.pragma_update(None, "foreign_keys", true)
+}
+
+/// Record that the owning agent has local changes the cloud hasn't seen:
+/// its local version goes up, a synced agent goes back to pending and an
+/// upload is queued. A permanently failed queue entry is revived by the new
+/// change. Agents never synced have no metadata yet and already count as
+/// pending; conflicts and tombstones keep their status.
+fn mark_dirty(conn: &Connection, agent_id: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE sync_metadata SET
+            local_version = local_version + 1,
+            sync_status = CASE WHEN sync_status = ?2 THEN ?3 ELSE sync_status END
+         WHERE entity_type = 'agent' AND entity_id = ?1 AND sync_status != ?4",
+        params![agent_id, SyncStatus::Synced, SyncStatus::Pending, SyncStatus::Deleted],
+    )?;
+    enqueue(conn, "agent", agent_id, "upsert")
+}
+
+fn enqueue(conn: &Connection, entity_type: &str, entity_id: &str, operation: &str) -> Result<()> {
+    let now = Utc::now();
+    conn.execute(
+        "INSERT INTO sync_queue (entity_type, entity_id, operation, enqueued_at, next_attempt_at)
+         VALUES (?1, ?2, ?3, ?4, ?4)
+         ON CONFLICT(entity_type, entity_id, operation) DO UPDATE SET
+            generation = sync_queue.generation + 1,
+            status = 'pending',
+            attempts = CASE sync_queue.status WHEN 'failed' THEN 0 ELSE sync_queue.attempts END,
+            next_attempt_at = CASE sync_queue.status WHEN 'failed' THEN excluded.next_attempt_at ELSE sync_queue.next_attempt_at END,
+            last_error = CASE sync_queue.status WHEN 'failed' THEN NULL ELSE sync_queue.last_error END",
+        params![entity_type, entity_id, operation, now],
+    )?;
+    Ok(())
+}
+
+fn queue_entry_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<SyncQueueEntry> {
+    Ok(SyncQueueEntry {
+        id: row.get(0)?,
+        entity_type: row.get(1)?,
+        entity_id: row.get(2)?,
+        operation: row.get(3)?,
+        enqueued_at: row.get(4)?,
+        attempts: row.get(5)?,
+        next_attempt_at: row.get(6)?,
+        last_error: row.get(7)?,
+        status: row.get(8)?,
+        generation: row.get(9)?,
+    })
+}
+
+/// Turn `limit + 1` fetched rows into a page; the extra row only shows that
+/// another page follows
+fn page<T>(mut items: Vec<T>, limit: usize, cursor: impl Fn(&T) -> PageCursor) -> Page<T> {
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(cursor)
+    } else {
+        None
+    };
+    Page { items, next_cursor }
+}
+
+fn agent_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredAgent> {
+    Ok(StoredAgent {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        system_prompt: row.get(2)?,
+        config: json_column(row, "agents", 3)?,
+        state: json_column(row, "agents", 4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+        deleted_at: row.get(7)?,
+    })
+}
+
+fn message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMessage> {
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        agent_id: row.get(1)?,
+        role: row.get(2)?,
+        content: row.get(3)?,
+        tool_calls: match row.get_ref(4)? {
+            rusqlite::types::ValueRef::Null => None,
+            _ => Some(json_column(row, "messages", 4)?),
+        },
+        tool_call_id: row.get(5)?,
+        metadata: json_column(row, "messages", 6)?,
+        timestamp: row.get(7)?,
+        seq: row.get(8)?,
+    })
+}
+
+/// Parse the JSON in column `idx`, naming the table, row and column when it
+/// is malformed. Expects the row's id in column 0.
+fn json_column<T: DeserializeOwned>(row: &rusqlite::Row<'_>, table: &'static str, idx: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(idx)?;
+    serde_json::from_str(&text)
+        .map_err(|e| corrupt_column(row, table, idx, format!("invalid JSON: {}", e)))
+}
+
+/// Report column `idx` as undecodable. Expects the row's id in column 0.
+fn corrupt_column(row: &rusqlite::Row<'_>, table: &'static str, idx: usize, reason: String) -> rusqlite::Error {
+    let corrupt = CorruptColumn {
+        table,
+        id: row.get(0).unwrap_or_default(),
+        column: row.as_ref().column_name(idx).unwrap_or("?").to_string(),
+        reason,
+    };
+    rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(corrupt))
+}
+
+/// Keep the rows that could be read; corrupted ones become warnings
+fn collect_lenient<T>(rows: impl Iterator<Item = rusqlite::Result<T>>) -> Result<Lenient<T>> {
+    let mut lenient = Lenient { items: Vec::new(), warnings: Vec::new() };
+    for row in rows {
+        match row.map_err(StorageError::from) {
+            Ok(item) => lenient.items.push(item),
+            Err(StorageError::InvalidData(warning)) => {
+                tracing::warn!(%warning, "Skipping unreadable row");
+                lenient.warnings.push(warning);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(lenient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_storage_creation() {
+        let storage = Storage::memory().unwrap();
+        assert!(storage.list_agents().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_agent_crud() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
         storage.create_agent(&agent).unwrap();
         
         let loaded = storage.get_agent(&agent.id).unwrap().unwrap();
@@ -390,6 +1587,241 @@ mod tests {
         assert_eq!(agents.len(), 1);
     }
     
+    #[test]
+    fn test_delete_agent_cascade() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "persona", "Friendly")).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Hello")).unwrap();
+        assert!(!storage.list_sync_queue().unwrap().is_empty());
+        
+        storage.add_chunk(&StoredChunk::new(&agent.id, "docs", "The quick brown fox")).unwrap();
+        storage.set_agent_sync_settings(&AgentSyncSettings::new(&agent.id)).unwrap();
+        storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent.id.clone(),
+            local_version: 1,
+            cloud_version: 1,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Synced,
+            last_synced_seq: 1,
+            conflicts: None,
+        }).unwrap();
+        
+        // Another agent's rows are left alone
+        let other = StoredAgent::new("other-agent", "Test prompt");
+        storage.create_agent(&other).unwrap();
+        storage.add_chunk(&StoredChunk::new(&other.id, "docs", "A different fox")).unwrap();
+        
+        assert!(storage.delete_agent(&agent.id).unwrap());
+        assert!(storage.get_agent(&agent.id).unwrap().is_none());
+        assert!(storage.get_blocks(&agent.id).unwrap().is_empty());
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 0);
+        assert!(storage.search_chunks_fts(&agent.id, "fox", 10).unwrap().is_empty());
+        assert!(storage.get_sync_metadata("agent", &agent.id).unwrap().is_none());
+        assert!(storage.list_sync_queue().unwrap().iter().all(|entry| entry.entity_id != agent.id));
+        
+        let conn = storage.conn().unwrap();
+        for table in ["blocks", "messages", "chunks", "agent_sync_settings"] {
+            let orphans: i64 = conn.query_row(
+                &format!("SELECT COUNT(*) FROM {} WHERE agent_id NOT IN (SELECT id FROM agents)", table),
+                [],
+                |row| row.get(0),
+            ).unwrap();
+            assert_eq!(orphans, 0, "orphaned rows in {}", table);
+        }
+        let fts_rows: i64 = conn.query_row("SELECT COUNT(*) FROM chunks_fts WHERE chunks_fts MATCH 'fox'", [], |row| row.get(0)).unwrap();
+        assert_eq!(fts_rows, 1);
+        drop(conn);
+        assert_eq!(storage.search_chunks_fts(&other.id, "fox", 10).unwrap().len(), 1);
+        
+        assert!(!storage.delete_agent(&agent.id).unwrap());
+    }
+    
+    #[test]
+    fn test_block_history() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("companion", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        let mut block = StoredBlock::new(&agent.id, "human", "Name: Alice");
+        storage.upsert_block(&block).unwrap();
+        // Rewriting the same value, or only the description, is not a change
+        storage.upsert_block(&block).unwrap();
+        block.description = "About the user".to_string();
+        storage.upsert_block(&block).unwrap();
+        assert_eq!(storage.get_block_history(&agent.id, "human", 10).unwrap().len(), 1);
+        
+        block.value = "Name: Bob".to_string();
+        storage.upsert_block_by(&block, BlockWriter::Agent).unwrap();
+        storage.save_agent_snapshot(&agent, std::slice::from_ref(&block), &[]).unwrap();
+        block.value = "Name: Alice".to_string();
+        storage.save_agent_snapshot(&agent, std::slice::from_ref(&block), &[]).unwrap();
+        
+        let history = storage.get_block_history(&agent.id, "human", 10).unwrap();
+        let changes: Vec<_> = history.iter()
+            .map(|c| (c.old_value.as_deref(), c.new_value.as_str(), c.changed_by))
+            .collect();
+        assert_eq!(changes, vec![
+            (Some("Name: Bob"), "Name: Alice", BlockWriter::Snapshot),
+            (Some("Name: Alice"), "Name: Bob", BlockWriter::Agent),
+            (None, "Name: Alice", BlockWriter::User),
+        ]);
+        assert_eq!(storage.get_block_history(&agent.id, "human", 1).unwrap()[0].id, history[0].id);
+        assert!(storage.get_block_history(&agent.id, "persona", 10).unwrap().is_empty());
+        
+        // Only the newest changes of each block are kept
+        for i in 0..BLOCK_HISTORY_LIMIT {
+            block.value = format!("Name: Alice {}", i);
+            storage.upsert_block(&block).unwrap();
+        }
+        let history = storage.get_block_history(&agent.id, "human", 1000).unwrap();
+        assert_eq!(history.len(), BLOCK_HISTORY_LIMIT);
+        assert_eq!(history[0].new_value, format!("Name: Alice {}", BLOCK_HISTORY_LIMIT - 1));
+        
+        storage.delete_agent(&agent.id).unwrap();
+        assert!(storage.get_block_history(&agent.id, "human", 10).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_soft_delete_restore_and_purge() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("companion", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Hello")).unwrap();
+        let kept = StoredAgent::new("kept", "Test prompt");
+        storage.create_agent(&kept).unwrap();
+        let operations = |storage: &Storage| storage.list_sync_queue().unwrap().into_iter()
+            .filter(|entry| entry.entity_id == agent.id)
+            .map(|entry| entry.operation)
+            .collect::<Vec<_>>();
+        
+        assert!(storage.soft_delete_agent(&agent.id).unwrap());
+        assert!(!storage.soft_delete_agent(&agent.id).unwrap());
+        assert_eq!(storage.list_agents().unwrap().len(), 1);
+        assert_eq!(storage.list_agents_filtered(true).unwrap().len(), 2);
+        assert!(storage.get_agent(&agent.id).unwrap().unwrap().deleted_at.is_some());
+        let tombstone = storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap();
+        assert_eq!(tombstone.sync_status, SyncStatus::Deleted);
+        assert_eq!(operations(&storage), vec!["delete"]);
+        
+        // Restoring brings back everything and queues an upload instead
+        assert!(storage.restore_agent(&agent.id).unwrap());
+        assert!(!storage.restore_agent(&agent.id).unwrap());
+        assert!(storage.get_agent(&agent.id).unwrap().unwrap().deleted_at.is_none());
+        assert_eq!(storage.list_agents().unwrap().len(), 2);
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 1);
+        assert_eq!(storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap().sync_status, SyncStatus::Pending);
+        assert_eq!(operations(&storage), vec!["upsert"]);
+        
+        // Purging waits out the window, then keeps only the tombstone
+        storage.soft_delete_agent(&agent.id).unwrap();
+        assert_eq!(storage.purge_deleted(chrono::Duration::days(30)).unwrap(), 0);
+        storage.conn().unwrap().execute(
+            "UPDATE agents SET deleted_at = ?2 WHERE id = ?1",
+            params![agent.id, Utc::now() - chrono::Duration::days(31)],
+        ).unwrap();
+        assert_eq!(storage.purge_deleted(chrono::Duration::days(30)).unwrap(), 1);
+        assert!(storage.get_agent(&agent.id).unwrap().is_none());
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 0);
+        assert_eq!(storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap().sync_status, SyncStatus::Deleted);
+        assert_eq!(operations(&storage), vec!["delete"]);
+        assert!(!storage.restore_agent(&agent.id).unwrap());
+        assert!(storage.get_agent(&kept.id).unwrap().is_some());
+    }
+    
+    #[test]
+    fn test_deleting_agent_row_cascades() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Hello")).unwrap();
+        
+        // Raw SQL from the embedding app cleans up the same way
+        storage.conn().unwrap().execute("DELETE FROM agents WHERE id = ?1", params![agent.id]).unwrap();
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 0);
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_block_round_trip() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        let mut block = StoredBlock::new(&agent.id, "persona", "Friendly");
+        block.description = "Who the agent is".to_string();
+        block.char_limit = 512;
+        storage.upsert_block(&block).unwrap();
+        
+        let blocks = storage.get_blocks(&agent.id).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].value, "Friendly");
+        assert_eq!(blocks[0].description, "Who the agent is");
+        assert_eq!(blocks[0].char_limit, 512);
+        
+        block.value = "Terse".to_string();
+        block.char_limit = 64;
+        storage.upsert_block(&block).unwrap();
+        
+        let blocks = storage.get_blocks(&agent.id).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].value, "Terse");
+        assert_eq!(blocks[0].char_limit, 64);
+    }
+    
+    #[test]
+    fn test_save_agent_snapshot() {
+        let storage = Storage::memory().unwrap();
+        
+        let mut agent = StoredAgent::new("test-agent", "Test prompt");
+        let block = StoredBlock::new(&agent.id, "human", "Alice");
+        let hello = StoredMessage::new(&agent.id, "user", "Hello");
+        storage.save_agent_snapshot(&agent, std::slice::from_ref(&block), std::slice::from_ref(&hello)).unwrap();
+        
+        // Saving again updates in place and skips known messages
+        agent.state = serde_json::json!({ "turn": 2 });
+        let reply = StoredMessage::new(&agent.id, "assistant", "Hi!");
+        storage.save_agent_snapshot(&agent, std::slice::from_ref(&block), &[hello, reply]).unwrap();
+        assert_eq!(storage.get_agent(&agent.id).unwrap().unwrap().state["turn"], 2);
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 2);
+        
+        // A bad message rolls back the state and blocks written before it
+        let mut changed = agent.clone();
+        changed.state = serde_json::json!({ "turn": 3 });
+        let mut renamed = block.clone();
+        renamed.value = "Bob".to_string();
+        let orphan = StoredMessage::new("missing-agent", "user", "Lost");
+        assert!(storage.save_agent_snapshot(&changed, &[renamed], &[orphan]).is_err());
+        
+        assert_eq!(storage.get_agent(&agent.id).unwrap().unwrap().state["turn"], 2);
+        assert_eq!(storage.get_blocks(&agent.id).unwrap()[0].value, "Alice");
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 2);
+    }
+    
+    #[test]
+    fn test_save_agent_snapshot_with_chunks() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        let note = StoredChunk::new(&agent.id, "archival", "Likes tea");
+        storage.save_agent_snapshot_with_chunks(&agent, &[], &[], std::slice::from_ref(&note)).unwrap();
+        
+        // Chunks already stored are skipped rather than failing the snapshot
+        let mut edited = note.clone();
+        edited.text = "Likes coffee".to_string();
+        let other = StoredChunk::new(&agent.id, "archival", "Has a cat");
+        storage.save_agent_snapshot_with_chunks(&agent, &[], &[], &[edited, other]).unwrap();
+        
+        let mut texts: Vec<String> = storage.get_chunks(&agent.id).unwrap().into_iter().map(|c| c.text).collect();
+        texts.sort();
+        assert_eq!(texts, ["Has a cat", "Likes tea"]);
+    }
+    
     #[test]
     fn test_message_storage() {
         let storage = Storage::memory().unwrap();
@@ -403,6 +1835,796 @@ mod tests {
         let messages = storage.get_messages(&agent.id, 10).unwrap();
         assert_eq!(messages.len(), 1);
         assert_eq!(messages[0].content, "Hello");
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 1);
+    }
+    
+    #[test]
+    fn test_message_search_queries() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        for text in [
+            "The quick brown fox",
+            "A brown dog that is quick",
+            "I like tea",
+            "I like tea and coffee",
+            "Teapots everywhere",
+            "Is C++ better?",
+        ] {
+            storage.add_message(&StoredMessage::new(&agent.id, "user", text)).unwrap();
+        }
+        
+        let contents = |query: &str| -> Vec<String> {
+            let mut found: Vec<_> = storage.search_messages(&agent.id, query, 10).unwrap()
+                .into_iter()
+                .map(|m| m.content)
+                .collect();
+            found.sort();
+            found
+        };
+        
+        assert_eq!(contents("\"quick brown\""), vec!["The quick brown fox"]);
+        assert_eq!(contents("quick AND brown").len(), 2);
+        assert_eq!(contents("tea NOT coffee"), vec!["I like tea"]);
+        assert_eq!(contents("fox OR dog").len(), 2);
+        assert_eq!(contents("tea*").len(), 3);
+        
+        // Best match first
+        let ranked = storage.search_messages(&agent.id, "tea OR coffee", 10).unwrap();
+        assert_eq!(ranked[0].content, "I like tea and coffee");
+        
+        // Not valid FTS5 syntax, so matched as a substring
+        assert_eq!(contents("C++"), vec!["Is C++ better?"]);
+        assert_eq!(contents("\"like tea"), Vec::<String>::new());
+        // Valid syntax the index can't answer is an error, not a substring match
+        assert!(storage.search_messages(&agent.id, "author:tea", 10).is_err());
+        
+        // Other agents' messages never match
+        let other = StoredAgent::new("other-agent", "Test prompt");
+        storage.create_agent(&other).unwrap();
+        assert!(storage.search_messages(&other.id, "tea", 10).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_messages_fts_backfill() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        // History written before the index existed
+        let conn = storage.conn().unwrap();
+        conn.execute_batch(
+            "DROP TRIGGER messages_ai; DROP TRIGGER messages_ad; DROP TRIGGER messages_au;
+             DROP TABLE messages_fts;
+             DELETE FROM migrations WHERE name = '009_messages_fts';"
+        ).unwrap();
+        drop(conn);
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Remember the milk")).unwrap();
+        
+        migrations::run_migrations(&storage.conn().unwrap(), false).unwrap();
+        assert_eq!(storage.search_messages(&agent.id, "milk", 10).unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn test_query_messages_order_and_range() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let start = Utc::now() - chrono::Duration::days(7);
+        let at = |day: i64| start + chrono::Duration::days(day);
+        for day in 0..5 {
+            let mut message = StoredMessage::new(&agent.id, "user", format!("Day {}", day));
+            message.timestamp = at(day);
+            storage.add_message(&message).unwrap();
+        }
+        let contents = |query: MessageQuery| storage.query_messages(&agent.id, &query).unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect::<Vec<_>>();
+        
+        assert_eq!(contents(MessageQuery { ascending: true, ..Default::default() }), ["Day 0", "Day 1", "Day 2", "Day 3", "Day 4"]);
+        assert_eq!(contents(MessageQuery::default())[0], "Day 4");
+        // A limit keeps the newest, in either order
+        assert_eq!(contents(MessageQuery { limit: Some(2), ascending: true, ..Default::default() }), ["Day 3", "Day 4"]);
+        assert_eq!(contents(MessageQuery { limit: Some(2), ..Default::default() }), ["Day 4", "Day 3"]);
+        assert_eq!(storage.get_messages(&agent.id, 2).unwrap()[0].content, "Day 4");
+        
+        // The start of a range is inclusive and the end exclusive
+        let range = TimeRange { from: Some(at(1)), until: Some(at(3)) };
+        assert_eq!(contents(MessageQuery { range, ascending: true, ..Default::default() }), ["Day 1", "Day 2"]);
+        assert_eq!(storage.count_messages_in_range(&agent.id, &range).unwrap(), 2);
+        let open_start = TimeRange { from: None, until: Some(at(2)) };
+        assert_eq!(storage.count_messages_in_range(&agent.id, &open_start).unwrap(), 2);
+        let open_end = TimeRange { from: Some(at(4)), until: None };
+        assert_eq!(contents(MessageQuery { range: open_end, ..Default::default() }), ["Day 4"]);
+        assert_eq!(storage.count_messages_in_range(&agent.id, &TimeRange::default()).unwrap(), 5);
+        
+        let before = PageCursor { timestamp: at(3), id: String::new() };
+        assert_eq!(contents(MessageQuery { before: Some(before), limit: Some(2), ascending: true, ..Default::default() }), ["Day 1", "Day 2"]);
+    }
+    
+    #[test]
+    fn test_message_pages() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        // Shared timestamps make the id tie-break matter
+        let start = Utc::now() - chrono::Duration::hours(1);
+        for i in 0..1_000 {
+            let mut message = StoredMessage::new(&agent.id, "user", format!("Message {}", i));
+            message.timestamp = start + chrono::Duration::milliseconds(i / 3);
+            storage.add_message(&message).unwrap();
+        }
+        
+        for ascending in [false, true] {
+            let mut request = PageRequest { limit: 64, before: None, ascending };
+            let mut seen = Vec::new();
+            loop {
+                let page = storage.get_messages_page(&agent.id, &request).unwrap();
+                seen.extend(page.items.into_iter().map(|m| (m.timestamp, m.id)));
+                
+                // Newer messages arriving mid-walk don't disturb a newest-first walk
+                if !ascending && seen.len() == 64 {
+                    let late = StoredMessage::new(&agent.id, "user", "Late arrival");
+                    storage.add_message(&late).unwrap();
+                }
+                match page.next_cursor {
+                    Some(cursor) => request.before = Some(cursor),
+                    None => break,
+                }
+            }
+            
+            assert_eq!(seen.len(), 1_000 + usize::from(ascending));
+            let mut sorted = seen.clone();
+            sorted.sort();
+            if !ascending {
+                sorted.reverse();
+            }
+            assert_eq!(seen, sorted);
+            sorted.dedup();
+            assert_eq!(sorted.len(), seen.len());
+        }
+        
+        let newest = storage.get_messages_page(&agent.id, &PageRequest { limit: 1, ..Default::default() }).unwrap();
+        assert_eq!(newest.items[0].content, "Late arrival");
+        let id = &newest.items[0].id;
+        assert_eq!(storage.get_message(id).unwrap().unwrap().content, "Late arrival");
+    }
+    
+    #[test]
+    fn test_agent_pages() {
+        let storage = Storage::memory().unwrap();
+        for i in 0..25 {
+            storage.create_agent(&StoredAgent::new(format!("agent-{}", i), "Test prompt")).unwrap();
+        }
+        
+        let mut cursor = None;
+        let mut ids = Vec::new();
+        loop {
+            let page = storage.list_agents_page(10, cursor.as_ref()).unwrap();
+            assert!(page.items.len() <= 10);
+            ids.extend(page.items.into_iter().map(|a| a.id));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 25);
+    }
+    
+    #[test]
+    fn test_message_seq_cursor() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        for text in ["one", "two", "three"] {
+            storage.add_message(&StoredMessage::new(&agent.id, "user", text)).unwrap();
+        }
+        assert_eq!(storage.latest_message_seq(&agent.id).unwrap(), 3);
+        
+        let newer = storage.get_messages_since(&agent.id, 1).unwrap();
+        let contents: Vec<_> = newer.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["two", "three"]);
+        
+        // Re-inserting a known id is a no-op
+        assert!(!storage.add_message_if_absent(&newer[0]).unwrap());
+        assert_eq!(storage.latest_message_seq(&agent.id).unwrap(), 3);
+    }
+    
+    #[test]
+    fn test_local_writes_enqueue_sync() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Hello")).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        
+        // Repeated writes collapse into one queued upload
+        let queue = storage.list_sync_queue().unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].entity_id, agent.id);
+        assert_eq!(queue[0].operation, "upsert");
+        
+        storage.record_sync_failure(queue[0].id, "offline", None).unwrap();
+        assert!(storage.due_sync_entries(Utc::now(), 10).unwrap().is_empty());
+        
+        // A new local change revives a permanently failed entry
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Still there?")).unwrap();
+        let due = storage.due_sync_entries(Utc::now(), 10).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 0);
+        assert!(due[0].last_error.is_none());
+        
+        // A write while the upload runs keeps the entry queued
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Sent mid-upload")).unwrap();
+        storage.complete_sync_entry(due[0].id, due[0].generation).unwrap();
+        let queue = storage.list_sync_queue().unwrap();
+        assert_eq!(queue.len(), 1);
+        
+        storage.complete_sync_entry(queue[0].id, queue[0].generation).unwrap();
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_cached_lookups_see_writes() {
+        let storage = Storage::memory().unwrap();
+        let mut agent = StoredAgent::new("test-agent", "Test prompt");
+        assert!(storage.get_agent(&agent.id).unwrap().is_none());
+        storage.create_agent(&agent).unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().is_some());
+        assert!(storage.get_blocks(&agent.id).unwrap().is_empty());
+        
+        agent.name = "renamed".to_string();
+        storage.update_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        assert_eq!(storage.get_agent(&agent.id).unwrap().unwrap().name, "renamed");
+        assert_eq!(storage.get_blocks(&agent.id).unwrap()[0].value, "Alice");
+        
+        // Repeat lookups are served from the cache, shared by every clone
+        let clone = storage.untracked();
+        assert_eq!(clone.get_agent(&agent.id).unwrap().unwrap().name, "renamed");
+        let stats = storage.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 5));
+        
+        clone.upsert_block_by(&StoredBlock::new(&agent.id, "human", "Bob"), BlockWriter::Sync).unwrap();
+        assert_eq!(storage.get_blocks(&agent.id).unwrap()[0].value, "Bob");
+        storage.soft_delete_agent(&agent.id).unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().unwrap().deleted_at.is_some());
+        storage.delete_agent(&agent.id).unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().is_none());
+        assert!(storage.get_blocks(&agent.id).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_writes_mark_synced_agent_pending() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent.id.clone(),
+            local_version: 3,
+            cloud_version: 3,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Synced,
+            last_synced_seq: 0,
+            conflicts: None,
+        }).unwrap();
+        storage.cancel_sync_entries("agent", &agent.id).unwrap();
+        let status = || {
+            let metadata = storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap();
+            (metadata.sync_status, metadata.local_version)
+        };
+        
+        // Writing down a cloud copy leaves the agent synced
+        assert!(!storage.untracked().tracks_changes());
+        storage.untracked().upsert_block_by(&StoredBlock::new(&agent.id, "human", "Alice"), BlockWriter::Sync).unwrap();
+        assert_eq!(status(), (SyncStatus::Synced, 3));
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+        
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Bob")).unwrap();
+        assert_eq!(status(), (SyncStatus::Pending, 4));
+        assert_eq!(storage.list_sync_queue().unwrap().len(), 1);
+        
+        // A tombstone is not revived by a write to the trashed agent
+        storage.soft_delete_agent(&agent.id).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Hello")).unwrap();
+        assert_eq!(status(), (SyncStatus::Deleted, 4));
+        
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { path: dir.path().join("letta.db"), track_changes: false, ..Default::default() };
+        let storage = Storage::new(config).unwrap();
+        storage.create_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_agent_sync_settings() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("scratch", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        assert_eq!(storage.get_agent_sync_settings(&agent.id).unwrap(), AgentSyncSettings::new(&agent.id));
+        
+        let settings = AgentSyncSettings {
+            enabled: false,
+            sync_interval: Some(60_000),
+            conflict_resolution: Some("cloud-wins".to_string()),
+            ..AgentSyncSettings::new(&agent.id)
+        };
+        storage.set_agent_sync_settings(&settings).unwrap();
+        assert_eq!(storage.get_agent_sync_settings(&agent.id).unwrap(), settings);
+    }
+    
+    fn embedded_chunk(agent_id: &str, text: &str, embedding: Vec<f32>) -> StoredChunk {
+        let mut chunk = StoredChunk::new(agent_id, "docs", text);
+        chunk.embedding = Some(embedding);
+        chunk
+    }
+    
+    #[test]
+    fn test_vector_search() {
+        let storage = Storage::memory().unwrap();
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let other = StoredAgent::new("other-agent", "Test prompt");
+        storage.create_agent(&other).unwrap();
+        
+        storage.add_chunk(&embedded_chunk(&agent.id, "north", vec![0.0, 1.0, 0.0])).unwrap();
+        storage.add_chunk(&embedded_chunk(&agent.id, "east", vec![1.0, 0.0, 0.0])).unwrap();
+        storage.add_chunk(&embedded_chunk(&agent.id, "north-east", vec![0.7, 0.7, 0.0])).unwrap();
+        // Another model's embeddings may differ in size
+        let mut flat = embedded_chunk(&agent.id, "flat", vec![1.0, 0.0]);
+        flat.embedding_model = Some("flat-model".to_string());
+        storage.add_chunk(&flat).unwrap();
+        storage.add_chunk(&StoredChunk::new(&agent.id, "docs", "no embedding")).unwrap();
+        storage.add_chunk(&embedded_chunk(&other.id, "also east", vec![1.0, 0.0, 0.0])).unwrap();
+        
+        let results = storage.search_chunks_vector(&agent.id, &[0.9, 0.1, 0.0], 2).unwrap();
+        let texts: Vec<_> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["east", "north-east"]);
+        assert_eq!(results[0].embedding.as_deref(), Some(&[1.0, 0.0, 0.0][..]));
+        
+        let results = storage.search_chunks_vector(&agent.id, &[0.0, 1.0, 0.0], 10).unwrap();
+        assert_eq!(results.len(), 3);
+        
+        assert!(storage.delete_agent(&agent.id).unwrap());
+        assert!(storage.search_chunks_vector(&agent.id, &[1.0, 0.0, 0.0], 10).unwrap().is_empty());
+        assert_eq!(storage.search_chunks_vector(&other.id, &[1.0, 0.0, 0.0], 10).unwrap().len(), 1);
+    }
+    
+    #[cfg(feature = "vector-index")]
+    #[test]
+    fn test_vector_index_catches_up_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { path: dir.path().join("letta.db"), max_connections: 2, ..Default::default() };
+        let storage = Storage::new(config.clone()).unwrap();
+        assert!(storage.has_vector_index());
+        
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_chunk(&embedded_chunk(&agent.id, "east", vec![1.0, 0.0, 0.0])).unwrap();
+        
+        // Written behind the index's back, as by a build without the feature
+        let late = embedded_chunk(&agent.id, "north", vec![0.0, 1.0, 0.0]);
+        storage.conn().unwrap().execute(
+            "INSERT INTO chunks (id, agent_id, folder, text, metadata, embedding, created_at)
+             VALUES (?1, ?2, 'docs', ?3, '{}', ?4, ?5)",
+            params![late.id, agent.id, late.text, vector::embedding_bytes(&[0.0, 1.0, 0.0]), late.created_at],
+        ).unwrap();
+        drop(storage);
+        
+        let storage = Storage::new(config).unwrap();
+        let indexed: i64 = storage.conn().unwrap()
+            .query_row("SELECT COUNT(*) FROM chunks_vec", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(indexed, 2);
+        let results = storage.search_chunks_vector(&agent.id, &[0.1, 0.9, 0.0], 1).unwrap();
+        assert_eq!(results[0].text, "north");
+    }
+    
+    #[test]
+    fn test_concurrent_read_during_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { path: dir.path().join("letta.db"), max_connections: 3, ..Default::default() };
+        let storage = Storage::new(config).unwrap();
+        let mode: String = storage.conn().unwrap()
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        
+        let (written, wait_for_write) = std::sync::mpsc::channel();
+        let writer = {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                let mut conn = storage.conn().unwrap();
+                let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate).unwrap();
+                let agent = StoredAgent::new("writer", "Test prompt");
+                tx.execute(
+                    "INSERT INTO agents (id, name, system_prompt, config, state, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, '{}', '{}', ?4, ?4)",
+                    params![agent.id, agent.name, agent.system_prompt, agent.created_at],
+                ).unwrap();
+                written.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                tx.commit().unwrap();
+            })
+        };
+        wait_for_write.recv().unwrap();
+        
+        // Reads see the last commit while the write is in progress
+        assert!(storage.list_agents().unwrap().is_empty());
+        // A second writer waits out the first instead of failing
+        storage.create_agent(&StoredAgent::new("reader", "Test prompt")).unwrap();
+        writer.join().unwrap();
+        assert_eq!(storage.list_agents().unwrap().len(), 2);
+    }
+    
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypted_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyed = |key: Option<&str>| StorageConfig {
+            path: dir.path().join("letta.db"),
+            encryption_key: key.map(str::to_string),
+            ..Default::default()
+        };
+        
+        let storage = Storage::new(keyed(Some("first key"))).unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        drop(storage);
+        
+        assert!(matches!(Storage::new(keyed(Some("wrong key"))), Err(StorageError::NotADatabase)));
+        assert!(matches!(Storage::new(keyed(None)), Err(StorageError::NotADatabase)));
+        
+        let storage = Storage::new(keyed(Some("first key"))).unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().is_some());
+        storage.rekey("second key").unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().is_some());
+        drop(storage);
+        
+        assert!(matches!(Storage::new(keyed(Some("first key"))), Err(StorageError::NotADatabase)));
+        let storage = Storage::new(keyed(Some("second key"))).unwrap();
+        assert!(storage.get_agent(&agent.id).unwrap().is_some());
+    }
+    
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_encryption_key_requires_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig {
+            path: dir.path().join("letta.db"),
+            encryption_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(Storage::new(config), Err(StorageError::EncryptionUnavailable)));
+        assert!(!dir.path().join("letta.db").exists());
+    }
+    
+    #[test]
+    fn test_rekey_requires_encrypted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(StorageConfig { path: dir.path().join("letta.db"), ..Default::default() }).unwrap();
+        assert!(matches!(storage.rekey("secret"), Err(StorageError::InvalidData(_))));
+        assert!(matches!(Storage::memory().unwrap().rekey("secret"), Err(StorageError::InvalidData(_))));
+    }
+    
+    #[test]
+    fn test_restore_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(StorageConfig { path: dir.path().join("letta.db"), ..Default::default() }).unwrap();
+        let mut agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Before the backup")).unwrap();
+        
+        let backup_path = dir.path().join("backup.db");
+        storage.backup(&backup_path).unwrap();
+        
+        agent.name = "renamed".to_string();
+        storage.update_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "After the backup")).unwrap();
+        let later = StoredAgent::new("later-agent", "Test prompt");
+        storage.create_agent(&later).unwrap();
+        
+        // Cached before the restore, which must not keep serving it
+        assert_eq!(storage.get_agent(&agent.id).unwrap().unwrap().name, "renamed");
+        storage.restore(&backup_path).unwrap();
+        assert_eq!(storage.get_agent(&agent.id).unwrap().unwrap().name, "test-agent");
+        assert!(storage.get_agent(&later.id).unwrap().is_none());
+        let messages = storage.get_messages(&agent.id, 10).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Before the backup");
+        assert!(storage.search_messages(&agent.id, "after", 10).unwrap().is_empty());
+        
+        // A backup into an in-memory database works the same way
+        let memory = Storage::memory().unwrap();
+        memory.restore(&backup_path).unwrap();
+        assert_eq!(memory.list_agents().unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn test_open_newer_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { path: dir.path().join("letta.db"), ..Default::default() };
+        let storage = Storage::new(config.clone()).unwrap();
+        let backup_path = dir.path().join("backup.db");
+        storage.conn().unwrap()
+            .execute("INSERT INTO migrations (name) VALUES ('099_from_the_future')", [])
+            .unwrap();
+        storage.backup(&backup_path).unwrap();
+        drop(storage);
+        
+        assert!(matches!(Storage::new(config.clone()), Err(StorageError::Migration(_))));
+        assert!(matches!(Storage::memory().unwrap().restore(&backup_path), Err(StorageError::Migration(_))));
+        let storage = Storage::new(StorageConfig { allow_newer_schema: true, ..config }).unwrap();
+        storage.restore(&backup_path).unwrap();
+    }
+    
+    #[test]
+    fn test_restore_migrates_and_validates() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::memory().unwrap();
+        let backup_path = dir.path().join("backup.db");
+        storage.backup(&backup_path).unwrap();
+        
+        // Made before the keyset indexes existed
+        let old = Connection::open(&backup_path).unwrap();
+        old.execute_batch(
+            "DROP INDEX idx_messages_agent_page;
+             DROP INDEX idx_agents_updated_page;
+             DELETE FROM migrations WHERE name = '010_keyset_indexes';"
+        ).unwrap();
+        drop(old);
+        
+        storage.restore(&backup_path).unwrap();
+        let applied: bool = storage.conn().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM migrations WHERE name = '010_keyset_indexes')",
+            [],
+            |row| row.get(0),
+        ).unwrap();
+        assert!(applied);
+        
+        let foreign = dir.path().join("foreign.db");
+        Connection::open(&foreign).unwrap().execute_batch("CREATE TABLE notes (text TEXT)").unwrap();
+        assert!(matches!(storage.restore(&foreign), Err(StorageError::InvalidData(_))));
+        
+        let garbage = dir.path().join("garbage.db");
+        std::fs::write(&garbage, vec![7u8; 8192]).unwrap();
+        assert!(matches!(storage.restore(&garbage), Err(StorageError::NotADatabase)));
+    }
+    
+    #[test]
+    fn test_corrupted_json_is_an_error() {
+        let storage = Storage::memory().unwrap();
+        let good = StoredAgent::new("good", "Test prompt");
+        let bad = StoredAgent::new("bad", "Test prompt");
+        storage.create_agent(&good).unwrap();
+        storage.create_agent(&bad).unwrap();
+        storage.add_message(&StoredMessage::new(&good.id, "user", "Intact")).unwrap();
+        let mangled = StoredMessage::new(&good.id, "user", "Mangled");
+        storage.add_message(&mangled).unwrap();
+        storage.add_chunk(&StoredChunk::new(&good.id, "docs", "A hand-edited chunk")).unwrap();
+        
+        let conn = storage.conn().unwrap();
+        conn.execute("UPDATE agents SET state = '{not json' WHERE id = ?1", params![bad.id]).unwrap();
+        conn.execute("UPDATE messages SET tool_calls = '[' WHERE id = ?1", params![mangled.id]).unwrap();
+        conn.execute("UPDATE chunks SET metadata = 'oops'", []).unwrap();
+        drop(conn);
+        
+        let err = storage.get_agent(&bad.id).unwrap_err().to_string();
+        assert!(err.contains(&format!("agents row {}", bad.id)), "{}", err);
+        assert!(err.contains("column state"), "{}", err);
+        assert!(matches!(storage.list_agents(), Err(StorageError::InvalidData(_))));
+        let err = storage.get_messages(&good.id, 10).unwrap_err().to_string();
+        assert!(err.contains("column tool_calls"), "{}", err);
+        assert!(matches!(storage.search_chunks_fts(&good.id, "chunk", 10), Err(StorageError::InvalidData(_))));
+        
+        let agents = storage.list_agents_lenient().unwrap();
+        assert_eq!(agents.items.len(), 1);
+        assert_eq!(agents.items[0].id, good.id);
+        assert_eq!(agents.warnings.len(), 1);
+        let messages = storage.get_messages_lenient(&good.id, 10).unwrap();
+        assert_eq!(messages.items.len(), 1);
+        assert_eq!(messages.items[0].content, "Intact");
+        assert!(messages.warnings[0].contains(&mangled.id));
+    }
+    
+    #[test]
+    fn test_add_chunks() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        let mut chunks: Vec<_> = ["red fox", "blue whale", "green frog"].iter()
+            .map(|text| StoredChunk::new(&agent.id, "docs", *text))
+            .collect();
+        assert_eq!(storage.add_chunks(&chunks, None).unwrap(), 3);
+        assert_eq!(storage.search_chunks_fts(&agent.id, "whale", 10).unwrap().len(), 1);
+        
+        // Re-ingesting the same document
+        chunks[1].text = "grey whale".to_string();
+        let extra = StoredChunk::new(&agent.id, "docs", "yellow canary");
+        chunks.push(extra.clone());
+        assert!(storage.add_chunks(&chunks, None).is_err());
+        assert!(storage.search_chunks_fts(&agent.id, "canary", 10).unwrap().is_empty());
+        assert_eq!(storage.add_chunks(&chunks, Some(OnConflict::Skip)).unwrap(), 1);
+        assert!(storage.search_chunks_fts(&agent.id, "grey", 10).unwrap().is_empty());
+        assert_eq!(storage.add_chunks(&chunks, Some(OnConflict::Replace)).unwrap(), 4);
+        assert_eq!(storage.search_chunks_fts(&agent.id, "grey", 10).unwrap().len(), 1);
+        assert!(storage.search_chunks_fts(&agent.id, "blue", 10).unwrap().is_empty());
+        
+        // One row breaking a constraint rolls back the whole batch
+        let batch = vec![
+            StoredChunk::new(&agent.id, "docs", "purple octopus"),
+            StoredChunk::new("no-such-agent", "docs", "orphaned"),
+        ];
+        assert!(storage.add_chunks(&batch, Some(OnConflict::Replace)).is_err());
+        assert!(storage.search_chunks_fts(&agent.id, "octopus", 10).unwrap().is_empty());
+        let stored: i64 = storage.conn().unwrap()
+            .query_row("SELECT COUNT(*) FROM chunks", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 4);
+    }
+    
+    #[test]
+    fn test_chunk_edits() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        
+        let typo = StoredChunk::new(&agent.id, "notes", "Meeting on Tuesdya");
+        let draft = StoredChunk::new(&agent.id, "drafts", "First draft of the essay");
+        let chunks = vec![
+            typo.clone(),
+            StoredChunk::new(&agent.id, "notes", "Buy oat milk"),
+            draft.clone(),
+            StoredChunk::new(&agent.id, "drafts", "Second draft of the essay"),
+        ];
+        storage.add_chunks(&chunks, None).unwrap();
+        assert_eq!(storage.list_folders(&agent.id).unwrap(), vec![
+            ("drafts".to_string(), 2),
+            ("notes".to_string(), 2),
+        ]);
+        
+        assert!(storage.update_chunk_text(&typo.id, "Meeting on Tuesday", None).unwrap());
+        assert!(storage.search_chunks_fts(&agent.id, "Tuesdya", 10).unwrap().is_empty());
+        assert_eq!(storage.search_chunks_fts(&agent.id, "Tuesday", 10).unwrap()[0].id, typo.id);
+        assert!(!storage.update_chunk_text("missing", "text", None).unwrap());
+        
+        assert!(storage.delete_chunk(&draft.id).unwrap());
+        assert!(!storage.delete_chunk(&draft.id).unwrap());
+        assert_eq!(storage.search_chunks_fts(&agent.id, "draft", 10).unwrap().len(), 1);
+        
+        assert_eq!(storage.delete_chunks_by_folder(&agent.id, "drafts").unwrap(), 1);
+        assert!(storage.search_chunks_fts(&agent.id, "essay", 10).unwrap().is_empty());
+        assert_eq!(storage.list_folders(&agent.id).unwrap(), vec![("notes".to_string(), 2)]);
+    }
+    
+    #[test]
+    fn test_embedding_validation() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let from_model = |text: &str, model: &str, embedding: Vec<f32>| {
+            let mut chunk = embedded_chunk(&agent.id, text, embedding);
+            chunk.embedding_model = Some(model.to_string());
+            chunk
+        };
+        
+        storage.add_chunk(&from_model("small", "mini-lm", vec![1.0, 0.0, 0.0])).unwrap();
+        let err = storage.add_chunk(&from_model("wrong size", "mini-lm", vec![1.0, 0.0, 0.0, 0.0])).unwrap_err();
+        assert!(err.to_string().contains("mini-lm have 3"), "{}", err);
+        storage.add_chunk(&from_model("large", "big-model", vec![0.0, 1.0, 0.0, 0.0])).unwrap();
+        assert!(storage.add_chunk(&from_model("empty", "big-model", vec![])).is_err());
+        assert!(storage.add_chunk(&from_model("nan", "big-model", vec![f32::NAN, 0.0, 0.0, 0.0])).is_err());
+        
+        let results = storage.search_chunks_vector(&agent.id, &[0.0, 1.0, 0.0, 0.0], 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "large");
+        assert_eq!(results[0].embedding_model.as_deref(), Some("big-model"));
+    }
+    
+    #[test]
+    fn test_truncated_embedding_is_an_error() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        storage.add_chunk(&embedded_chunk(&agent.id, "intact vector", vec![1.0, 0.0, 0.0])).unwrap();
+        
+        // Written by an older build, so without a recorded dimension
+        let truncated = StoredChunk::new(&agent.id, "docs", "truncated vector");
+        storage.conn().unwrap().execute(
+            "INSERT INTO chunks (id, agent_id, folder, text, metadata, embedding, created_at)
+             VALUES (?1, ?2, 'docs', ?3, '{}', ?4, ?5)",
+            params![truncated.id, agent.id, truncated.text, vec![0u8; 11], truncated.created_at],
+        ).unwrap();
+        
+        let err = storage.search_chunks_fts(&agent.id, "truncated", 10).unwrap_err();
+        assert!(matches!(err, StorageError::InvalidData(_)));
+        assert!(err.to_string().contains("column embedding"), "{}", err);
+        let results = storage.search_chunks_vector(&agent.id, &[1.0, 0.0, 0.0], 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "intact vector");
+    }
+    
+    #[test]
+    fn test_chunk_edits_update_vector_search() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let east = embedded_chunk(&agent.id, "east", vec![1.0, 0.0, 0.0]);
+        let north = embedded_chunk(&agent.id, "north", vec![0.0, 1.0, 0.0]);
+        storage.add_chunks(&[east.clone(), north.clone()], None).unwrap();
+        
+        assert!(storage.update_chunk_text(&east.id, "up", Some(&[0.0, 0.0, 1.0])).unwrap());
+        let results = storage.search_chunks_vector(&agent.id, &[0.0, 0.1, 0.9], 1).unwrap();
+        assert_eq!(results[0].text, "up");
+        
+        assert!(storage.delete_chunk(&east.id).unwrap());
+        let results = storage.search_chunks_vector(&agent.id, &[0.0, 0.1, 0.9], 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, north.id);
+    }
+    
+    /// `cargo test -p letta-storage --release -- --ignored --nocapture bench_add_chunks`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_add_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(StorageConfig { path: dir.path().join("letta.db"), ..Default::default() }).unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let chunks = |document: usize| -> Vec<StoredChunk> {
+            (0..5000)
+                .map(|i| StoredChunk::new(&agent.id, "docs", format!("Document {} paragraph {} about topic {}", document, i, i % 97)))
+                .collect()
+        };
+        
+        let one_by_one = chunks(1);
+        let start = std::time::Instant::now();
+        for chunk in &one_by_one {
+            storage.add_chunk(chunk).unwrap();
+        }
+        let single = start.elapsed();
+        
+        let batch = chunks(2);
+        let start = std::time::Instant::now();
+        assert_eq!(storage.add_chunks(&batch, None).unwrap(), 5000);
+        let bulk = start.elapsed();
+        
+        println!("5,000 chunks: {:?} one at a time, {:?} in one batch", single, bulk);
+        assert!(bulk < single);
+    }
+    
+    /// `cargo test -p letta-storage --release -- --ignored --nocapture bench_message_search`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_message_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(StorageConfig { path: dir.path().join("letta.db"), ..Default::default() }).unwrap();
+        let agent = StoredAgent::new("test-agent", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        // A long conversation history
+        let messages: Vec<_> = (0..50_000)
+            .map(|i| StoredMessage::new(&agent.id, if i % 2 == 0 { "user" } else { "assistant" }, format!("Message {} about topic {}", i, i % 97)))
+            .collect();
+        storage.save_agent_step(&agent, &[], &messages, &[], &[]).unwrap();
+        
+        let start = std::time::Instant::now();
+        for _ in 0..100 {
+            assert_eq!(storage.search_messages(&agent.id, "\"topic 42\"", 10).unwrap().len(), 10);
+        }
+        println!("50,000 messages: {:?} per search", start.elapsed() / 100);
     }
     
     #[test]
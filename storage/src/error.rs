@@ -3,7 +3,7 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum StorageError {
     #[error("Database error: {0}")]
-    Database(#[from] rusqlite::Error),
+    Database(rusqlite::Error),
     
     #[error("Connection pool error: {0}")]
     Pool(#[from] r2d2::Error),
@@ -25,6 +25,53 @@ pub enum StorageError {
     
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    
+    #[error("Storage task cancelled")]
+    Cancelled,
+    
+    #[error("Database is encrypted or not a database; check the encryption key")]
+    NotADatabase,
+    
+    #[error("An encryption key was given but letta-storage was built without the sqlcipher feature")]
+    EncryptionUnavailable,
+}
+
+/// A stored column that can't be decoded, such as malformed JSON. Row
+/// mappers can only fail with a `rusqlite::Error`, so it travels inside one
+/// and becomes `InvalidData`.
+#[derive(Error, Debug)]
+#[error("{table} row {id}, column {column}: {reason}")]
+pub(crate) struct CorruptColumn {
+    pub table: &'static str,
+    pub id: String,
+    pub column: String,
+    pub reason: String,
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::FromSqlConversionFailure(_, _, source) if source.is::<CorruptColumn>() => {
+                StorageError::InvalidData(source.to_string())
+            }
+            e => StorageError::Database(e),
+        }
+    }
+}
+
+impl StorageError {
+    /// Whether the same call may succeed if retried, e.g. when another
+    /// connection holds the database lock or the pool timed out
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            StorageError::Database(rusqlite::Error::SqliteFailure(e, _)) => matches!(
+                e.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            ),
+            StorageError::Pool(_) => true,
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, StorageError>;
\ No newline at end of file
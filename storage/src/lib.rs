@@ -1,8 +1,18 @@
 pub mod db;
+pub mod async_storage;
 pub mod migrations;
 pub mod models;
 pub mod error;
+pub mod retention;
+pub mod maintenance;
+mod cache;
+mod completion_cache;
+mod vector;
 
-pub use db::{Storage, StorageConfig};
+pub use db::{Storage, StorageConfig, BLOCK_HISTORY_LIMIT};
+pub use async_storage::AsyncStorage;
+pub use cache::CacheStats;
+pub use retention::RetentionPolicy;
+pub use maintenance::{AgentStorageStats, MaintenanceOptions, MaintenanceReport, StorageStats};
 pub use error::{StorageError, Result};
-pub use models::{StoredAgent, StoredMessage, StoredBlock, StoredChunk};
\ No newline at end of file
+pub use models::{StoredAgent, StoredMessage, StoredBlock, StoredChunk, BlockChange, BlockWriter, OnConflict, Lenient, MessageQuery, Page, PageCursor, PageRequest, TimeRange, SyncMetadata, SyncQueueEntry, SyncStatus, AgentSyncSettings};
\ No newline at end of file
@@ -0,0 +1,184 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use crate::{
+    db::Storage,
+    error::{Result, StorageError},
+};
+
+/// Size of the database and what it holds, for a settings screen
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStats {
+    /// Pages in use times the page size; a WAL file not yet checkpointed is
+    /// not included
+    pub file_size_bytes: u64,
+    pub page_count: u64,
+    /// Pages freed by deletes that a vacuum would give back
+    pub free_page_count: u64,
+    pub agent_count: u64,
+    pub message_count: u64,
+    pub chunk_count: u64,
+    pub per_agent: Vec<AgentStorageStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStorageStats {
+    pub agent_id: String,
+    pub name: String,
+    pub message_count: u64,
+    pub chunk_count: u64,
+    pub block_count: u64,
+}
+
+/// Which maintenance tasks `Storage::maintain` runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceOptions {
+    /// Rebuild the file without free pages, shrinking it
+    pub vacuum: bool,
+    /// Refresh the statistics the query planner picks indexes with
+    pub analyze: bool,
+    /// Merge the full-text indexes' segments
+    pub fts_optimize: bool,
+}
+
+impl MaintenanceOptions {
+    pub fn all() -> Self {
+        Self { vacuum: true, analyze: true, fts_optimize: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    pub size_before_bytes: u64,
+    pub size_after_bytes: u64,
+    pub reclaimed_bytes: u64,
+}
+
+impl Storage {
+    /// Database size and row counts, from cheap aggregates
+    pub fn stats(&self) -> Result<StorageStats> {
+        let conn = self.conn()?;
+        let count = |table: &str| -> Result<u64> {
+            Ok(conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))?)
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT a.id, a.name,
+                (SELECT COUNT(*) FROM messages WHERE agent_id = a.id),
+                (SELECT COUNT(*) FROM chunks WHERE agent_id = a.id),
+                (SELECT COUNT(*) FROM blocks WHERE agent_id = a.id)
+             FROM agents a ORDER BY a.name, a.id"
+        )?;
+        let per_agent = stmt.query_map([], |row| {
+            Ok(AgentStorageStats {
+                agent_id: row.get(0)?,
+                name: row.get(1)?,
+                message_count: row.get(2)?,
+                chunk_count: row.get(3)?,
+                block_count: row.get(4)?,
+            })
+        })?.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let page_count = pragma_u64(&conn, "page_count")?;
+        Ok(StorageStats {
+            file_size_bytes: page_count * pragma_u64(&conn, "page_size")?,
+            page_count,
+            free_page_count: pragma_u64(&conn, "freelist_count")?,
+            agent_count: count("agents")?,
+            message_count: count("messages")?,
+            chunk_count: count("chunks")?,
+            per_agent,
+        })
+    }
+
+    /// Run the chosen maintenance tasks and report how much space they gave
+    /// back. Vacuuming rewrites the whole file, so it holds the write lock
+    /// for a while on a large database.
+    pub fn maintain(&self, options: &MaintenanceOptions) -> Result<MaintenanceReport> {
+        let conn = self.conn()?;
+        let size_before_bytes = database_size(&conn)?;
+
+        if options.fts_optimize {
+            conn.execute_batch(
+                "INSERT INTO chunks_fts (chunks_fts) VALUES ('optimize');
+                 INSERT INTO messages_fts (messages_fts) VALUES ('optimize');"
+            )?;
+        }
+        if options.analyze {
+            conn.execute_batch("ANALYZE")?;
+        }
+        if options.vacuum {
+            if !conn.is_autocommit() {
+                return Err(StorageError::InvalidData("VACUUM cannot run inside a transaction".to_string()));
+            }
+            conn.execute_batch("VACUUM")?;
+            // In WAL mode the vacuumed pages sit in the log until checkpointed
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        }
+
+        let size_after_bytes = database_size(&conn)?;
+        let reclaimed_bytes = size_before_bytes.saturating_sub(size_after_bytes);
+        tracing::info!(size_before_bytes, size_after_bytes, reclaimed_bytes, "Database maintenance finished");
+        Ok(MaintenanceReport { size_before_bytes, size_after_bytes, reclaimed_bytes })
+    }
+}
+
+fn pragma_u64(conn: &Connection, pragma: &str) -> Result<u64> {
+    Ok(conn.pragma_query_value(None, pragma, |row| row.get(0))?)
+}
+
+fn database_size(conn: &Connection) -> Result<u64> {
+    Ok(pragma_u64(conn, "page_count")? * pragma_u64(conn, "page_size")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::StorageConfig, models::{StoredAgent, StoredBlock, StoredChunk, StoredMessage}};
+
+    #[test]
+    fn test_stats() {
+        let storage = Storage::memory().unwrap();
+        let busy = StoredAgent::new("busy", "Test prompt");
+        let quiet = StoredAgent::new("quiet", "Test prompt");
+        storage.create_agent(&busy).unwrap();
+        storage.create_agent(&quiet).unwrap();
+        for i in 0..3 {
+            storage.add_message(&StoredMessage::new(&busy.id, "user", format!("Message {}", i))).unwrap();
+        }
+        storage.add_chunk(&StoredChunk::new(&busy.id, "docs", "A note")).unwrap();
+        storage.upsert_block(&StoredBlock::new(&quiet.id, "human", "Likes tea")).unwrap();
+
+        let stats = storage.stats().unwrap();
+        assert_eq!((stats.agent_count, stats.message_count, stats.chunk_count), (2, 3, 1));
+        assert!(stats.file_size_bytes > 0);
+        assert_eq!(stats.per_agent.len(), 2);
+        assert_eq!(stats.per_agent[0].name, "busy");
+        assert_eq!((stats.per_agent[0].message_count, stats.per_agent[0].chunk_count), (3, 1));
+        assert_eq!(stats.per_agent[1].block_count, 1);
+    }
+
+    #[test]
+    fn test_vacuum_reclaims_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(StorageConfig { path: dir.path().join("letta.db"), ..Default::default() }).unwrap();
+        let agent = StoredAgent::new("chatty", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+        let filler = "x".repeat(2000);
+        for _ in 0..200 {
+            storage.add_message(&StoredMessage::new(&agent.id, "user", &filler)).unwrap();
+        }
+        storage.delete_agent(&agent.id).unwrap();
+        assert!(storage.stats().unwrap().free_page_count > 0);
+
+        let report = storage.maintain(&MaintenanceOptions::all()).unwrap();
+        assert!(report.reclaimed_bytes > 0);
+        assert_eq!(report.size_before_bytes - report.reclaimed_bytes, report.size_after_bytes);
+        let stats = storage.stats().unwrap();
+        assert_eq!(stats.free_page_count, 0);
+        assert_eq!(stats.file_size_bytes, report.size_after_bytes);
+
+        let report = storage.maintain(&MaintenanceOptions::default()).unwrap();
+        assert_eq!(report.reclaimed_bytes, 0);
+    }
+}
@@ -1,11 +1,28 @@
 use rusqlite::Connection;
-use crate::error::Result;
+use crate::error::{Result, StorageError};
 
 const MIGRATIONS: &[(&str, &str)] = &[
     ("001_initial", include_str!("../migrations/001_initial.sql")),
+    ("002_sync_delta", include_str!("../migrations/002_sync_delta.sql")),
+    ("003_sync_queue", include_str!("../migrations/003_sync_queue.sql")),
+    ("004_settings", include_str!("../migrations/004_settings.sql")),
+    ("005_agent_sync_settings", include_str!("../migrations/005_agent_sync_settings.sql")),
+    ("006_sync_conflicts", include_str!("../migrations/006_sync_conflicts.sql")),
+    ("007_cascade_deletes", include_str!("../migrations/007_cascade_deletes.sql")),
+    ("008_block_char_limit", include_str!("../migrations/008_block_char_limit.sql")),
+    ("009_messages_fts", include_str!("../migrations/009_messages_fts.sql")),
+    ("010_keyset_indexes", include_str!("../migrations/010_keyset_indexes.sql")),
+    ("011_hot_query_indexes", include_str!("../migrations/011_hot_query_indexes.sql")),
+    ("012_embedding_metadata", include_str!("../migrations/012_embedding_metadata.sql")),
+    ("013_soft_delete", include_str!("../migrations/013_soft_delete.sql")),
+    ("014_block_history", include_str!("../migrations/014_block_history.sql")),
+    ("015_completions_cache", include_str!("../migrations/015_completions_cache.sql")),
+    ("016_sync_queue_generation", include_str!("../migrations/016_sync_queue_generation.sql")),
 ];
 
-pub fn run_migrations(conn: &Connection) -> Result<()> {
+/// Bring the schema up to date. A database already migrated by a newer
+/// letta-lite is refused, as in `check_schema_version`.
+pub fn run_migrations(conn: &Connection, allow_newer_schema: bool) -> Result<()> {
     // Create migrations table if it doesn't exist
     conn.execute(
         "CREATE TABLE IF NOT EXISTS migrations (
@@ -20,6 +37,7 @@ pub fn run_migrations(conn: &Connection) -> Result<()> {
     let applied: Vec<String> = stmt
         .query_map([], |row| row.get(0))?
         .collect::<rusqlite::Result<_>>()?;
+    check_applied(&applied, allow_newer_schema)?;
     
     // Apply new migrations
     for (name, sql) in MIGRATIONS {
@@ -34,4 +52,149 @@ pub fn run_migrations(conn: &Connection) -> Result<()> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Fail if the database has migrations this build doesn't know, applied by a
+/// newer letta-lite whose schema this build may misread. With
+/// `allow_newer_schema` they are only logged.
+pub fn check_schema_version(conn: &Connection, allow_newer_schema: bool) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM migrations")?;
+    let applied: Vec<String> = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    check_applied(&applied, allow_newer_schema)
+}
+
+fn check_applied(applied: &[String], allow_newer_schema: bool) -> Result<()> {
+    let unknown: Vec<&str> = applied.iter()
+        .map(String::as_str)
+        .filter(|name| !MIGRATIONS.iter().any(|(known, _)| known == name))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    
+    let found = unknown.join(", ");
+    if allow_newer_schema {
+        tracing::warn!(%found, "Opening a database migrated by a newer letta-lite");
+        return Ok(());
+    }
+    Err(StorageError::Migration(format!("Database requires a newer letta-lite (found {})", found)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use rusqlite::params;
+    
+    fn migrated() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        run_migrations(&conn, false).unwrap();
+        conn
+    }
+    
+    fn query_plan(conn: &Connection, sql: &str) -> String {
+        let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+        let details = stmt.query_map([], |row| row.get::<_, String>(3)).unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        details.join("; ")
+    }
+    
+    #[test]
+    fn test_newer_schema_is_refused() {
+        let conn = migrated();
+        conn.execute("INSERT INTO migrations (name) VALUES ('099_from_the_future')", []).unwrap();
+        
+        let err = run_migrations(&conn, false).unwrap_err();
+        assert!(matches!(err, StorageError::Migration(_)));
+        assert!(err.to_string().contains("requires a newer letta-lite (found 099_from_the_future)"), "{}", err);
+        assert!(check_schema_version(&conn, false).is_err());
+        run_migrations(&conn, true).unwrap();
+    }
+    
+    #[test]
+    fn test_hot_queries_use_indexes() {
+        let conn = migrated();
+        for sql in [
+            "SELECT * FROM messages WHERE agent_id = 'a' ORDER BY timestamp DESC LIMIT 10",
+            "SELECT * FROM blocks WHERE agent_id = 'a'",
+            "SELECT * FROM chunks WHERE agent_id = 'a' AND folder = 'docs'",
+            "SELECT * FROM chunks WHERE agent_id = 'a' ORDER BY created_at DESC",
+            "SELECT * FROM sync_metadata WHERE entity_type = 'agent' AND entity_id = 'a'",
+        ] {
+            let plan = query_plan(&conn, sql);
+            assert!(plan.contains("USING") && !plan.contains("SCAN") && !plan.contains("TEMP B-TREE"), "{}: {}", sql, plan);
+        }
+    }
+    
+    #[test]
+    fn test_on_conflict_targets_are_unique() {
+        let conn = migrated();
+        for (table, columns) in [
+            ("blocks", vec!["agent_id", "label"]),
+            ("sync_metadata", vec!["entity_type", "entity_id"]),
+            ("sync_queue", vec!["entity_type", "entity_id", "operation"]),
+        ] {
+            let mut stmt = conn.prepare(&format!("PRAGMA index_list({})", table)).unwrap();
+            let unique: Vec<String> = stmt.query_map([], |row| Ok((row.get::<_, String>(1)?, row.get::<_, bool>(2)?)))
+                .unwrap()
+                .filter_map(|index| index.ok().filter(|(_, unique)| *unique).map(|(name, _)| name))
+                .collect();
+            let matching = unique.iter().any(|index| {
+                let mut stmt = conn.prepare(&format!("PRAGMA index_info({})", index)).unwrap();
+                let indexed: Vec<String> = stmt.query_map([], |row| row.get(2)).unwrap()
+                    .collect::<rusqlite::Result<_>>()
+                    .unwrap();
+                indexed == columns
+            });
+            assert!(matching, "no unique index on {}({:?})", table, columns);
+        }
+    }
+    
+    /// `cargo test -p letta-storage --release -- --ignored --nocapture bench_message_indexes`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_message_indexes() {
+        let mut conn = migrated();
+        let tx = conn.transaction().unwrap();
+        for agent in 0..10 {
+            tx.execute(
+                "INSERT INTO agents (id, name, system_prompt, config, state, created_at, updated_at)
+                 VALUES (?1, ?1, '', '{}', '{}', datetime('now'), datetime('now'))",
+                params![format!("agent-{}", agent)],
+            ).unwrap();
+        }
+        for i in 0..100_000 {
+            tx.execute(
+                "INSERT INTO messages (id, agent_id, role, content, metadata, timestamp)
+                 VALUES (?1, ?2, 'user', 'Hello', '{}', datetime('now', ?3))",
+                params![format!("message-{}", i), format!("agent-{}", i % 10), format!("-{} seconds", 100_000 - i)],
+            ).unwrap();
+        }
+        tx.commit().unwrap();
+        
+        let time_queries = |conn: &Connection| {
+            let mut stmt = conn.prepare(
+                "SELECT id FROM messages WHERE agent_id = ?1 ORDER BY timestamp DESC LIMIT 50"
+            ).unwrap();
+            let start = Instant::now();
+            for i in 0..200 {
+                let rows = stmt.query_map(params![format!("agent-{}", i % 10)], |row| row.get::<_, String>(0)).unwrap();
+                assert_eq!(rows.count(), 50);
+            }
+            start.elapsed()
+        };
+        
+        let indexed = time_queries(&conn);
+        conn.execute_batch(
+            "DROP INDEX idx_messages_agent_page;
+             DROP INDEX idx_messages_agent_seq;
+             DROP INDEX idx_messages_timestamp;"
+        ).unwrap();
+        let unindexed = time_queries(&conn);
+        println!("200 recent-message queries over 100k messages: {:?} indexed, {:?} without", indexed, unindexed);
+        assert!(indexed < unindexed);
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,6 +12,9 @@ pub struct StoredAgent {
     pub state: serde_json::Value,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// When the agent was moved to the trash, if it has been
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +27,63 @@ pub struct StoredMessage {
     pub tool_call_id: Option<String>,
     pub metadata: serde_json::Value,
     pub timestamp: DateTime<Utc>,
+    /// Per-agent insertion sequence, assigned by `Storage::add_message`
+    #[serde(default)]
+    pub seq: i64,
+}
+
+/// Keyset position in a paged listing: the `(timestamp, id)` of the last row
+/// returned. Rows inserted later never shift a cursor, unlike an offset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageCursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: String,
+}
+
+/// One page of messages, ordered by `(timestamp, id)`
+#[derive(Debug, Clone, Default)]
+pub struct PageRequest {
+    pub limit: usize,
+    /// Continue past this cursor, as returned in the previous page's
+    /// `next_cursor`; `None` starts at the newest message (oldest when
+    /// `ascending`)
+    pub before: Option<PageCursor>,
+    pub ascending: bool,
+}
+
+/// A window over message timestamps: from `from`, inclusive, up to `until`,
+/// exclusive. An end left `None` is open.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Which of an agent's messages `Storage::query_messages` returns
+#[derive(Debug, Clone, Default)]
+pub struct MessageQuery {
+    pub range: TimeRange,
+    /// Only messages before this keyset position, e.g. the oldest one shown
+    pub before: Option<PageCursor>,
+    /// Keep the newest this many of the matching messages; `None` keeps all
+    pub limit: Option<usize>,
+    /// Return the messages oldest first instead of newest first
+    pub ascending: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor for the following page, `None` once the listing is exhausted
+    pub next_cursor: Option<PageCursor>,
+}
+
+/// Rows read leniently: each row too corrupted to read is skipped and leaves
+/// a warning naming it instead of failing the whole listing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lenient<T> {
+    pub items: Vec<T>,
+    pub warnings: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,7 +93,9 @@ pub struct StoredBlock {
     pub label: String,
     pub description: String,
     pub value: String,
-    pub limit: i32,
+    /// Maximum value length in characters
+    #[serde(alias = "limit")]
+    pub char_limit: i32,
     pub updated_at: DateTime<Utc>,
 }
 
@@ -44,9 +107,122 @@ pub struct StoredChunk {
     pub text: String,
     pub metadata: serde_json::Value,
     pub embedding: Option<Vec<f32>>,
+    /// Model that produced `embedding`. Embeddings are only compared with
+    /// others of the same size, and one model must always produce one size.
+    #[serde(default)]
+    pub embedding_model: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
+/// What a bulk insert does with a row whose id is already stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Keep the stored row
+    Skip,
+    /// Overwrite the stored row with the new one
+    Replace,
+}
+
+/// Who changed a memory block, as recorded in its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockWriter {
+    /// The host app, on the user's behalf
+    User,
+    /// The agent itself, through its memory tools
+    Agent,
+    /// A change pulled from the cloud or a resolved conflict
+    Sync,
+    /// A save of the whole agent, which doesn't say who made each change
+    Snapshot,
+}
+
+impl BlockWriter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockWriter::User => "user",
+            BlockWriter::Agent => "agent",
+            BlockWriter::Sync => "sync",
+            BlockWriter::Snapshot => "snapshot",
+        }
+    }
+}
+
+impl ToSql for BlockWriter {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for BlockWriter {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "user" => Ok(BlockWriter::User),
+            "agent" => Ok(BlockWriter::Agent),
+            "sync" => Ok(BlockWriter::Sync),
+            "snapshot" => Ok(BlockWriter::Snapshot),
+            other => Err(FromSqlError::Other(format!("Unknown block writer '{}'", other).into())),
+        }
+    }
+}
+
+/// One change to a memory block's value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockChange {
+    pub id: i64,
+    pub agent_id: String,
+    pub label: String,
+    /// `None` when the change created the block
+    pub old_value: Option<String>,
+    pub new_value: String,
+    pub changed_at: DateTime<Utc>,
+    pub changed_by: BlockWriter,
+}
+
+/// Where an entity stands relative to its cloud copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStatus {
+    /// Local changes not yet uploaded
+    Pending,
+    Synced,
+    /// Conflicts are waiting for a decision
+    Conflict,
+    /// Tombstone of an agent moved to the trash, whose deletion is sent to
+    /// the cloud and which is never pulled back down
+    Deleted,
+}
+
+impl SyncStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncStatus::Pending => "pending",
+            SyncStatus::Synced => "synced",
+            SyncStatus::Conflict => "conflict",
+            SyncStatus::Deleted => "deleted",
+        }
+    }
+}
+
+impl ToSql for SyncStatus {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for SyncStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "pending" => Ok(SyncStatus::Pending),
+            "synced" => Ok(SyncStatus::Synced),
+            "conflict" => Ok(SyncStatus::Conflict),
+            "deleted" => Ok(SyncStatus::Deleted),
+            other => Err(FromSqlError::Other(format!("Unknown sync status '{}'", other).into())),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncMetadata {
     pub entity_type: String,
@@ -54,7 +230,48 @@ pub struct SyncMetadata {
     pub local_version: i64,
     pub cloud_version: i64,
     pub last_sync_at: DateTime<Utc>,
-    pub sync_status: String,
+    pub sync_status: SyncStatus,
+    /// Highest message `seq` included in the last successful sync
+    #[serde(default)]
+    pub last_synced_seq: i64,
+    /// Conflicts awaiting a decision while `sync_status` is `Conflict`
+    #[serde(default)]
+    pub conflicts: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncQueueEntry {
+    pub id: i64,
+    pub entity_type: String,
+    pub entity_id: String,
+    pub operation: String,
+    pub enqueued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub status: String,
+    /// Bumped each time the entity is enqueued again; see
+    /// `Storage::complete_sync_entry`
+    pub generation: i64,
+}
+
+/// Per-agent sync overrides; `None` fields fall back to the global `SyncConfig`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentSyncSettings {
+    #[serde(default)]
+    pub agent_id: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Milliseconds between automatic syncs
+    #[serde(default)]
+    pub sync_interval: Option<u64>,
+    /// Conflict strategy name, e.g. "cloud-wins"
+    #[serde(default)]
+    pub conflict_resolution: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 impl StoredAgent {
@@ -68,6 +285,18 @@ impl StoredAgent {
             state: serde_json::json!({}),
             created_at: now,
             updated_at: now,
+            deleted_at: None,
+        }
+    }
+}
+
+impl AgentSyncSettings {
+    pub fn new(agent_id: impl Into<String>) -> Self {
+        Self {
+            agent_id: agent_id.into(),
+            enabled: true,
+            sync_interval: None,
+            conflict_resolution: None,
         }
     }
 }
@@ -83,6 +312,7 @@ impl StoredMessage {
             tool_call_id: None,
             metadata: serde_json::json!({}),
             timestamp: Utc::now(),
+            seq: 0,
         }
     }
 }
@@ -95,7 +325,7 @@ impl StoredBlock {
             label: label.into(),
             description: String::new(),
             value: value.into(),
-            limit: 2000,
+            char_limit: 2000,
             updated_at: Utc::now(),
         }
     }
@@ -110,6 +340,7 @@ impl StoredChunk {
             text: text.into(),
             metadata: serde_json::json!({}),
             embedding: None,
+            embedding_model: None,
             created_at: Utc::now(),
         }
     }
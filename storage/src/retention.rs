@@ -0,0 +1,196 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{Duration, Utc};
+use rusqlite::params;
+use crate::{db::Storage, error::Result};
+
+/// Which stored messages an agent keeps. Messages over either limit are
+/// removed, oldest first; with no limits set nothing is removed.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep at most this many messages
+    pub max_count: Option<usize>,
+    /// Remove messages older than this
+    pub max_age: Option<Duration>,
+    /// Never remove messages whose metadata has `"pinned": true`
+    pub keep_pinned: bool,
+}
+
+/// The columns pruning decides on
+struct MessageRow {
+    id: String,
+    tool_call_ids: Vec<String>,
+    tool_call_id: Option<String>,
+    pinned: bool,
+    expired: bool,
+}
+
+impl Storage {
+    /// Delete an agent's oldest messages outside `policy`, returning how many
+    /// were removed. Their search index entries go with them.
+    ///
+    /// An assistant message that called tools and the tool results answering
+    /// it are removed together or not at all, so a kept history never holds
+    /// half of a tool exchange. Pruning is local housekeeping and is not
+    /// queued for sync.
+    pub fn prune_messages(&self, agent_id: &str, policy: &RetentionPolicy) -> Result<usize> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+
+        let cutoff = policy.max_age.map(|age| Utc::now() - age);
+        let rows = {
+            let mut stmt = tx.prepare(
+                "SELECT id, tool_calls, tool_call_id, metadata, timestamp
+                 FROM messages WHERE agent_id = ?1
+                 ORDER BY timestamp ASC, id ASC"
+            )?;
+            let rows = stmt.query_map(params![agent_id], |row| {
+                let tool_calls: Option<String> = row.get(1)?;
+                let metadata: Option<String> = row.get(3)?;
+                let timestamp: chrono::DateTime<Utc> = row.get(4)?;
+                Ok(MessageRow {
+                    id: row.get(0)?,
+                    tool_call_ids: tool_calls.as_deref().map(tool_call_ids).unwrap_or_default(),
+                    tool_call_id: row.get(2)?,
+                    pinned: metadata.as_deref().is_some_and(is_pinned),
+                    expired: cutoff.is_some_and(|cutoff| timestamp < cutoff),
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+
+        let protected = |row: &MessageRow| policy.keep_pinned && row.pinned;
+        let mut over_count = rows.len().saturating_sub(policy.max_count.unwrap_or(usize::MAX));
+        let mut candidates = HashSet::new();
+        for row in rows.iter().filter(|row| !protected(row)) {
+            if row.expired || over_count > 0 {
+                over_count = over_count.saturating_sub(1);
+                candidates.insert(row.id.as_str());
+            }
+        }
+
+        // Group each tool call with its results, keyed by the calling message
+        let mut caller_of = HashMap::new();
+        for row in &rows {
+            for call_id in &row.tool_call_ids {
+                caller_of.insert(call_id.as_str(), row.id.as_str());
+            }
+        }
+        let mut groups: HashMap<&str, Vec<&str>> = HashMap::new();
+        for row in &rows {
+            let group = row.tool_call_id.as_deref()
+                .and_then(|call_id| caller_of.get(call_id).copied())
+                .unwrap_or(row.id.as_str());
+            groups.entry(group).or_default().push(row.id.as_str());
+        }
+
+        let mut removed = 0;
+        for members in groups.values() {
+            if members.iter().all(|id| candidates.contains(id)) {
+                for id in members {
+                    removed += tx.execute("DELETE FROM messages WHERE id = ?1", params![id])?;
+                }
+            }
+        }
+        tx.commit()?;
+
+        if removed > 0 {
+            tracing::info!(agent_id, removed, "Pruned messages");
+        }
+        Ok(removed)
+    }
+
+    /// Apply `policy` to every stored agent, returning the total removed
+    pub fn prune_all(&self, policy: &RetentionPolicy) -> Result<usize> {
+        let mut removed = 0;
+        for agent in self.list_agents()? {
+            removed += self.prune_messages(&agent.id, policy)?;
+        }
+        Ok(removed)
+    }
+}
+
+fn tool_call_ids(tool_calls: &str) -> Vec<String> {
+    let calls: Vec<serde_json::Value> = serde_json::from_str(tool_calls).unwrap_or_default();
+    calls.iter()
+        .filter_map(|call| call.get("id").and_then(|id| id.as_str()).map(str::to_string))
+        .collect()
+}
+
+fn is_pinned(metadata: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(metadata)
+        .is_ok_and(|metadata| metadata.get("pinned") == Some(&serde_json::Value::Bool(true)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{StoredAgent, StoredMessage};
+
+    fn message(agent_id: &str, content: &str, age_days: i64) -> StoredMessage {
+        let mut message = StoredMessage::new(agent_id, "user", content);
+        message.timestamp = Utc::now() - Duration::days(age_days);
+        message
+    }
+
+    #[test]
+    fn test_prune_keeps_pinned_and_recent() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("companion", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+
+        let mut pinned = message(&agent.id, "My birthday is in May", 90);
+        pinned.metadata = serde_json::json!({ "pinned": true });
+        storage.add_message(&pinned).unwrap();
+        storage.add_message(&message(&agent.id, "Old chatter about kites", 60)).unwrap();
+        storage.add_message(&message(&agent.id, "Older chatter", 45)).unwrap();
+        for day in (0..5).rev() {
+            storage.add_message(&message(&agent.id, &format!("Recent {}", day), day)).unwrap();
+        }
+
+        let policy = RetentionPolicy { max_age: Some(Duration::days(30)), keep_pinned: true, ..Default::default() };
+        assert_eq!(storage.prune_messages(&agent.id, &policy).unwrap(), 2);
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 6);
+        assert!(storage.search_messages(&agent.id, "kites", 10).unwrap().is_empty());
+        assert_eq!(storage.search_messages(&agent.id, "birthday", 10).unwrap().len(), 1);
+
+        // The count limit takes the oldest unpinned messages next
+        let policy = RetentionPolicy { max_count: Some(4), keep_pinned: true, ..Default::default() };
+        assert_eq!(storage.prune_messages(&agent.id, &policy).unwrap(), 2);
+        let contents: Vec<_> = storage.get_messages_since(&agent.id, 0).unwrap()
+            .into_iter()
+            .map(|m| m.content)
+            .collect();
+        assert_eq!(contents, vec!["My birthday is in May", "Recent 2", "Recent 1", "Recent 0"]);
+
+        // Without keep_pinned the pin is just another old message
+        let policy = RetentionPolicy { max_age: Some(Duration::days(30)), ..Default::default() };
+        assert_eq!(storage.prune_all(&policy).unwrap(), 1);
+        assert_eq!(storage.prune_messages(&agent.id, &RetentionPolicy::default()).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prune_keeps_tool_exchanges_whole() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("companion", "Test prompt");
+        storage.create_agent(&agent).unwrap();
+
+        let mut call = message(&agent.id, "", 40);
+        call.role = "assistant".to_string();
+        call.tool_calls = Some(serde_json::json!([{ "id": "call_1", "name": "archival_search", "arguments": {} }]));
+        storage.add_message(&call).unwrap();
+        // The result arrived later, inside the retention window
+        let mut result = message(&agent.id, "No results", 10);
+        result.role = "tool".to_string();
+        result.tool_call_id = Some("call_1".to_string());
+        storage.add_message(&result).unwrap();
+        storage.add_message(&message(&agent.id, "Stale", 50)).unwrap();
+
+        let policy = RetentionPolicy { max_age: Some(Duration::days(30)), ..Default::default() };
+        assert_eq!(storage.prune_messages(&agent.id, &policy).unwrap(), 1);
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 2);
+
+        let policy = RetentionPolicy { max_age: Some(Duration::days(5)), ..Default::default() };
+        assert_eq!(storage.prune_messages(&agent.id, &policy).unwrap(), 2);
+        assert_eq!(storage.count_messages(&agent.id).unwrap(), 0);
+    }
+}
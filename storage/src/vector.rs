@@ -0,0 +1,160 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::error::Result;
+
+/// Settings key holding the dimension `chunks_vec` was created with
+const DIMENSIONS_KEY: &str = "vector_index.dimensions";
+
+/// Make sqlite-vec available to every connection opened from now on.
+/// A no-op unless built with the `vector-index` feature.
+pub(crate) fn register() {
+    #[cfg(feature = "vector-index")]
+    {
+        static REGISTER: std::sync::Once = std::sync::Once::new();
+        // The extension is statically linked; its entry point has the
+        // signature SQLite expects but is declared without arguments
+        type EntryPoint = unsafe extern "C" fn(
+            *mut rusqlite::ffi::sqlite3,
+            *mut *const std::os::raw::c_char,
+            *const rusqlite::ffi::sqlite3_api_routines,
+        ) -> std::os::raw::c_int;
+        REGISTER.call_once(|| unsafe {
+            let init = std::mem::transmute::<*const (), EntryPoint>(sqlite_vec::sqlite3_vec_init as *const ());
+            rusqlite::ffi::sqlite3_auto_extension(Some(init));
+        });
+    }
+}
+
+/// Whether the sqlite-vec functions are loaded on this connection
+pub(crate) fn available(conn: &Connection) -> bool {
+    conn.query_row("SELECT vec_version()", [], |row| row.get::<_, String>(0)).is_ok()
+}
+
+pub(crate) fn embedding_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+/// `None` if the blob isn't a whole number of f32s
+pub(crate) fn embedding_from_bytes(bytes: &[u8]) -> Option<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(bytes.chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Dimension of the index, or `None` before the first embedding is stored
+fn dimensions(conn: &Connection) -> Result<Option<usize>> {
+    let value: Option<String> = conn.query_row(
+        "SELECT value FROM settings WHERE key = ?1",
+        params![DIMENSIONS_KEY],
+        |row| row.get(0),
+    ).optional()?;
+    Ok(value.and_then(|v| v.parse().ok()))
+}
+
+/// vec0 columns have a fixed size, so the table is created once the first
+/// embedding shows which size the provider produces. Chunks already stored
+/// with that size are indexed as it is created.
+fn ensure_table(conn: &Connection, dims: usize) -> Result<bool> {
+    if let Some(existing) = dimensions(conn)? {
+        return Ok(existing == dims);
+    }
+
+    conn.execute_batch(&format!(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS chunks_vec USING vec0(
+            agent_id TEXT PARTITION KEY,
+            embedding float[{}] distance_metric=cosine
+        )",
+        dims,
+    ))?;
+    conn.execute(
+        "INSERT INTO chunks_vec (rowid, agent_id, embedding)
+         SELECT rowid, agent_id, embedding FROM chunks
+         WHERE embedding IS NOT NULL AND length(embedding) = ?1",
+        params![dims * 4],
+    )?;
+    conn.execute(
+        "INSERT INTO settings (key, value, updated_at) VALUES (?1, ?2, ?3)",
+        params![DIMENSIONS_KEY, dims.to_string(), chrono::Utc::now()],
+    )?;
+    Ok(true)
+}
+
+/// Catch the index up with chunks written or deleted while the extension
+/// was not loaded
+pub(crate) fn reconcile(conn: &Connection) -> Result<()> {
+    let Some(dims) = dimensions(conn)? else {
+        return Ok(());
+    };
+    conn.execute("DELETE FROM chunks_vec WHERE rowid NOT IN (SELECT rowid FROM chunks)", [])?;
+    conn.execute(
+        "INSERT INTO chunks_vec (rowid, agent_id, embedding)
+         SELECT rowid, agent_id, embedding FROM chunks
+         WHERE embedding IS NOT NULL AND length(embedding) = ?1
+           AND rowid NOT IN (SELECT rowid FROM chunks_vec)",
+        params![dims * 4],
+    )?;
+    Ok(())
+}
+
+/// Mirror a stored chunk's embedding into `chunks_vec`. Embeddings whose size
+/// differs from the index are left to the scan fallback.
+pub(crate) fn index_chunk(conn: &Connection, rowid: i64, agent_id: &str, embedding: &[f32]) -> Result<()> {
+    if embedding.is_empty() || !ensure_table(conn, embedding.len())? {
+        return Ok(());
+    }
+    // A reused rowid may still have an entry from a chunk deleted outside `Storage`
+    conn.execute("DELETE FROM chunks_vec WHERE rowid = ?1", params![rowid])?;
+    conn.execute(
+        "INSERT INTO chunks_vec (rowid, agent_id, embedding) VALUES (?1, ?2, ?3)",
+        params![rowid, agent_id, embedding_bytes(embedding)],
+    )?;
+    Ok(())
+}
+
+/// Drop a chunk's index entry, as when it is replaced by one without an embedding
+pub(crate) fn remove_chunk(conn: &Connection, rowid: i64) -> Result<()> {
+    if dimensions(conn)?.is_none() {
+        return Ok(());
+    }
+    conn.execute("DELETE FROM chunks_vec WHERE rowid = ?1", params![rowid])?;
+    Ok(())
+}
+
+/// Drop the index entries of an agent's chunks, before the chunks themselves go
+pub(crate) fn remove_agent(conn: &Connection, agent_id: &str) -> Result<()> {
+    if dimensions(conn)?.is_none() {
+        return Ok(());
+    }
+    conn.execute("DELETE FROM chunks_vec WHERE agent_id = ?1", params![agent_id])?;
+    Ok(())
+}
+
+/// Rowids of the agent's `limit` nearest chunks, nearest first, or `None`
+/// when the index cannot answer for an embedding of this size
+pub(crate) fn nearest(conn: &Connection, agent_id: &str, embedding: &[f32], limit: usize) -> Result<Option<Vec<i64>>> {
+    if dimensions(conn)? != Some(embedding.len()) {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT rowid FROM chunks_vec
+         WHERE embedding MATCH ?1 AND k = ?2 AND agent_id = ?3
+         ORDER BY distance"
+    )?;
+    let rowids = stmt.query_map(params![embedding_bytes(embedding), limit, agent_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<i64>>>()?;
+    Ok(Some(rowids))
+}
@@ -0,0 +1,282 @@
+use async_trait::async_trait;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::error::{Result, SyncError};
+
+/// Supplies the credential header attached to every sync request
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    async fn auth_header(&self) -> Result<(HeaderName, HeaderValue)>;
+    
+    /// Called once after the server answers 401. Returns whether new
+    /// credentials were obtained and the request is worth retrying.
+    async fn refresh(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// How `SyncClient` authenticates, as read from `SyncConfig`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthConfig {
+    /// `SyncConfig::api_key` as a bearer token, or verbatim in `header` if set
+    ApiKey {
+        #[serde(default)]
+        header: Option<String>,
+    },
+    /// Short-lived access tokens obtained from `refresh_url`
+    RefreshingToken {
+        refresh_url: String,
+        refresh_token: String,
+        #[serde(default)]
+        header: Option<String>,
+    },
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig::ApiKey { header: None }
+    }
+}
+
+/// Build the provider described by `config`. Tokens are refreshed with
+/// `client`, so they go through the same proxy as sync requests.
+pub fn provider_from_config(config: &AuthConfig, api_key: &str, client: &Client) -> Result<Box<dyn AuthProvider>> {
+    Ok(match config {
+        AuthConfig::ApiKey { header } => Box::new(StaticApiKey::new(api_key, header.as_deref())?),
+        AuthConfig::RefreshingToken { refresh_url, refresh_token, header } => {
+            Box::new(RefreshingToken::new(refresh_url, refresh_token, header.as_deref())?.with_client(client.clone()))
+        }
+    })
+}
+
+/// A fixed API key
+pub struct StaticApiKey {
+    header: HeaderName,
+    value: HeaderValue,
+}
+
+impl StaticApiKey {
+    /// Sent as `Authorization: Bearer <key>`, or as the raw key under a custom header
+    pub fn new(api_key: &str, header: Option<&str>) -> Result<Self> {
+        let (header, value) = credential_header(header, api_key)?;
+        Ok(Self { header, value })
+    }
+}
+
+#[async_trait]
+impl AuthProvider for StaticApiKey {
+    async fn auth_header(&self) -> Result<(HeaderName, HeaderValue)> {
+        Ok((self.header.clone(), self.value.clone()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshResponse {
+    access_token: String,
+    /// Servers that rotate refresh tokens return the next one here
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+struct TokenState {
+    access_token: Option<String>,
+    refresh_token: String,
+}
+
+/// Exchanges a refresh token for short-lived access tokens.
+///
+/// The refresh endpoint receives `{"refresh_token": ...}` and must answer with
+/// `{"access_token": ..., "refresh_token": ...}`; the latter is optional.
+pub struct RefreshingToken {
+    refresh_url: String,
+    header: Option<String>,
+    client: Client,
+    state: Mutex<TokenState>,
+}
+
+impl RefreshingToken {
+    pub fn new(refresh_url: impl Into<String>, refresh_token: impl Into<String>, header: Option<&str>) -> Result<Self> {
+        let header = header.map(str::to_string);
+        // Reject a bad header name up front rather than on the first request
+        credential_header(header.as_deref(), "")?;
+        
+        Ok(Self {
+            refresh_url: refresh_url.into(),
+            header,
+            client: Client::new(),
+            state: Mutex::new(TokenState {
+                access_token: None,
+                refresh_token: refresh_token.into(),
+            }),
+        })
+    }
+    
+    /// Refresh with `client` rather than a default one, e.g. to go through a proxy
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+    
+    async fn fetch_token(&self, state: &mut TokenState) -> Result<String> {
+        let response = self.client
+            .post(&self.refresh_url)
+            .json(&serde_json::json!({ "refresh_token": state.refresh_token }))
+            .send()
+            .await?;
+        
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SyncError::Auth(format!("Token refresh failed with status {}", status.as_u16())));
+        }
+        
+        let refreshed: RefreshResponse = response.json().await?;
+        if let Some(refresh_token) = refreshed.refresh_token {
+            state.refresh_token = refresh_token;
+        }
+        state.access_token = Some(refreshed.access_token.clone());
+        Ok(refreshed.access_token)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for RefreshingToken {
+    async fn auth_header(&self) -> Result<(HeaderName, HeaderValue)> {
+        let mut state = self.state.lock().await;
+        let token = match state.access_token.clone() {
+            Some(token) => token,
+            None => self.fetch_token(&mut state).await?,
+        };
+        credential_header(self.header.as_deref(), &token)
+    }
+    
+    async fn refresh(&self) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        self.fetch_token(&mut state).await?;
+        Ok(true)
+    }
+}
+
+fn credential_header(header: Option<&str>, credential: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = match header {
+        Some(name) => (
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| SyncError::Auth(format!("Invalid auth header name '{}'", name)))?,
+            credential.to_string(),
+        ),
+        None => (AUTHORIZATION, format!("Bearer {}", credential)),
+    };
+    
+    let mut value = HeaderValue::from_str(&value)
+        .map_err(|_| SyncError::Auth("Credential is not a valid header value".into()))?;
+    value.set_sensitive(true);
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SyncClient, SyncConfig};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method, path};
+    
+    fn client(server: &MockServer, auth: AuthConfig) -> SyncClient {
+        SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            api_key: "secret".to_string(),
+            auth,
+            ..Default::default()
+        }).unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_unauthorized_refreshes_once_and_retries() {
+        let server = MockServer::start().await;
+        
+        // The first token handed out has already expired server-side
+        Mock::given(method("POST"))
+            .and(path("/auth/refresh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "stale",
+                "refresh_token": "rotated"
+            })))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/auth/refresh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "fresh"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .and(header("authorization", "Bearer stale"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .and(header("authorization", "Bearer fresh"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let client = client(&server, AuthConfig::RefreshingToken {
+            refresh_url: format!("{}/auth/refresh", server.uri()),
+            refresh_token: "initial".to_string(),
+            header: None,
+        });
+        assert!(client.list_remote_agents().await.unwrap().is_empty());
+        
+        let refreshes = server.received_requests().await.unwrap().into_iter()
+            .filter(|r| r.url.path() == "/auth/refresh")
+            .map(|r| r.body_json::<serde_json::Value>().unwrap()["refresh_token"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(refreshes, vec!["initial", "rotated"]);
+    }
+    
+    #[tokio::test]
+    async fn test_static_key_does_not_retry() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .and(header("x-api-key", "secret"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let client = client(&server, AuthConfig::ApiKey { header: Some("X-Api-Key".to_string()) });
+        match client.list_remote_agents().await {
+            Err(SyncError::Server { status, .. }) => assert_eq!(status, 401),
+            other => panic!("expected a 401 error, got {:?}", other.map(|_| ())),
+        }
+    }
+    
+    #[test]
+    fn test_auth_config_from_json() {
+        let config: SyncConfig = serde_json::from_str(r#"{
+            "auth": {
+                "type": "refreshing_token",
+                "refresh_url": "https://auth.example.com/token",
+                "refresh_token": "abc"
+            }
+        }"#).unwrap();
+        
+        assert_eq!(config.auth, AuthConfig::RefreshingToken {
+            refresh_url: "https://auth.example.com/token".to_string(),
+            refresh_token: "abc".to_string(),
+            header: None,
+        });
+        assert_eq!(SyncConfig::default().auth, AuthConfig::ApiKey { header: None });
+    }
+}
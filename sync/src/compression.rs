@@ -0,0 +1,103 @@
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::RequestBuilder;
+use std::io::Write;
+use crate::{error::Result, events::PayloadSize, SyncConfig};
+
+/// A serialized JSON request body, gzipped when compression is enabled and
+/// the body is large enough to benefit
+pub(crate) struct EncodedBody {
+    bytes: Vec<u8>,
+    gzip: bool,
+    pub size: PayloadSize,
+}
+
+impl EncodedBody {
+    pub fn encode(config: &SyncConfig, json: Vec<u8>) -> Result<Self> {
+        let uncompressed = json.len();
+        let gzip = config.compression && uncompressed >= config.compression_threshold;
+        
+        let bytes = if gzip {
+            let mut encoder = GzEncoder::new(Vec::with_capacity(uncompressed / 4), Compression::default());
+            encoder.write_all(&json)?;
+            encoder.finish()?
+        } else {
+            json
+        };
+        
+        Ok(Self {
+            size: PayloadSize { uncompressed, sent: bytes.len() },
+            bytes,
+            gzip,
+        })
+    }
+    
+    pub fn attach(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header(CONTENT_TYPE, "application/json");
+        let request = if self.gzip {
+            request.header(CONTENT_ENCODING, "gzip")
+        } else {
+            request
+        };
+        request.body(self.bytes.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SyncClient, SyncEvent};
+    use flate2::read::GzDecoder;
+    use letta_core::{af::AgentFile, AgentConfig, AgentState};
+    use std::io::Read;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{header, method};
+    
+    #[test]
+    fn test_small_bodies_are_not_compressed() {
+        let config = SyncConfig { compression: true, ..Default::default() };
+        let body = EncodedBody::encode(&config, b"{}".to_vec()).unwrap();
+        assert!(!body.gzip);
+        assert_eq!(body.size.sent, 2);
+    }
+    
+    #[tokio::test]
+    async fn test_push_sends_gzip_body() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(header("content-encoding", "gzip"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let mut state = AgentState::new("journal");
+        state.memory.set_block("human", "Likes long walks. ".repeat(50)).unwrap();
+        let agent_file = AgentFile::export(&AgentConfig::default(), &state, vec![]).unwrap();
+        
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            compression: true,
+            ..Default::default()
+        }).unwrap();
+        let mut events = client.subscribe();
+        client.push_agent(&agent_file).await.unwrap();
+        
+        let request = &server.received_requests().await.unwrap()[0];
+        assert_eq!(request.url.path(), format!("/v1/agents/{}/import", state.id));
+        let mut json = String::new();
+        GzDecoder::new(&request.body[..]).read_to_string(&mut json).unwrap();
+        assert_eq!(json, serde_json::to_string(&agent_file).unwrap());
+        
+        let mut completed = None;
+        while let Ok(event) = events.try_recv() {
+            if let SyncEvent::Completed { payload, .. } = event {
+                completed = payload;
+            }
+        }
+        let payload = completed.unwrap();
+        assert_eq!(payload.uncompressed, json.len());
+        assert_eq!(payload.sent, request.body.len());
+        assert!(payload.sent < payload.uncompressed);
+    }
+}
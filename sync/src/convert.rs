@@ -0,0 +1,36 @@
+use letta_core::{
+    af::{AgentFile, AgentFileV1},
+    persist::from_rows_or_default,
+};
+use letta_storage::Storage;
+use crate::error::{Result, SyncError};
+
+pub(crate) use letta_core::persist::AgentRows;
+
+/// Rebuild an agent file from the rows persisted for `agent_id`.
+///
+/// The `config`/`state` JSON columns are used when they deserialize; blocks and
+/// messages always come from their own tables since those are the rows that
+/// local writes and applied deltas touch. Unlike `persist::load_agent`, the
+/// full stored history is included.
+pub(crate) fn agent_file_from_storage(storage: &Storage, agent_id: &str) -> Result<AgentFileV1> {
+    agent_file_with_seq(storage, agent_id).map(|(agent_file, _)| agent_file)
+}
+
+/// `agent_file_from_storage`, along with the highest message `seq` the file
+/// includes, or 0 when it has no messages
+pub(crate) fn agent_file_with_seq(storage: &Storage, agent_id: &str) -> Result<(AgentFileV1, i64)> {
+    let stored = storage.get_agent(agent_id)?
+        .ok_or_else(|| SyncError::AgentNotFound(agent_id.to_string()))?;
+    let messages = storage.get_messages_since(agent_id, 0)?;
+    let last_seq = messages.iter().map(|message| message.seq).max().unwrap_or(0);
+    let (config, state) = from_rows_or_default(&stored, storage.get_blocks(agent_id)?, messages)?;
+    
+    Ok((AgentFile::export_owned(&config, state, vec![])?, last_seq))
+}
+
+/// Split an agent file into storage rows via `AgentFile::import`
+pub(crate) fn agent_rows_from_file(agent_file: &AgentFileV1) -> Result<AgentRows> {
+    let (config, state) = AgentFile::import(agent_file)?;
+    Ok(letta_core::persist::to_rows(&config, &state)?)
+}
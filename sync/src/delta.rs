@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use letta_storage::{BlockWriter, Storage, StoredBlock, StoredMessage, SyncMetadata};
+use crate::error::Result;
+
+/// Blocks and messages changed since the last successful sync
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncDelta {
+    pub blocks: Vec<StoredBlock>,
+    pub messages: Vec<StoredMessage>,
+}
+
+impl SyncDelta {
+    /// Collect the local changes made after `metadata` was recorded: blocks
+    /// updated after `last_sync_at` and messages past `last_synced_seq`.
+    pub fn collect(storage: &Storage, agent_id: &str, metadata: &SyncMetadata) -> Result<Self> {
+        let blocks = storage.get_blocks(agent_id)?
+            .into_iter()
+            .filter(|block| block.updated_at > metadata.last_sync_at)
+            .collect();
+        let messages = storage.get_messages_since(agent_id, metadata.last_synced_seq)?;
+        
+        Ok(Self { blocks, messages })
+    }
+    
+    /// Highest `seq` among the delta's messages, if it has any
+    pub fn last_seq(&self) -> Option<i64> {
+        self.messages.iter().map(|message| message.seq).max()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.messages.is_empty()
+    }
+    
+    /// Write an incoming delta into storage, returning the number of rows written.
+    /// Messages that already exist locally are skipped. The writes came from the
+    /// cloud, so they don't mark the agent dirty.
+    pub fn apply(&self, storage: &Storage, agent_id: &str) -> Result<usize> {
+        let storage = storage.untracked();
+        let mut written = 0;
+        
+        for block in &self.blocks {
+            let mut block = block.clone();
+            block.agent_id = agent_id.to_string();
+            storage.upsert_block_by(&block, BlockWriter::Sync)?;
+            written += 1;
+        }
+        
+        for message in &self.messages {
+            let mut message = message.clone();
+            message.agent_id = agent_id.to_string();
+            if storage.add_message_if_absent(&message)? {
+                written += 1;
+            }
+        }
+        
+        Ok(written)
+    }
+}
+
+/// Incremental counterpart of `SyncRequest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncDeltaRequest {
+    pub agent_id: String,
+    /// Cloud version the delta was computed against
+    pub base_version: i64,
+    pub local_version: i64,
+    /// Messages with a greater seq are included
+    pub since_seq: i64,
+    pub device_id: String,
+    pub delta: SyncDelta,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use letta_storage::{StoredAgent, SyncStatus};
+    use crate::{convert::agent_file_from_storage, SyncRequest};
+    
+    fn seeded_storage() -> (Storage, String) {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Name: Alice")).unwrap();
+        for i in 0..40 {
+            let text = format!("Journal entry {} about a long and eventful day at work and home.", i);
+            storage.add_message(&StoredMessage::new(&agent.id, "user", text)).unwrap();
+        }
+        
+        (storage, agent.id)
+    }
+    
+    #[test]
+    fn test_delta_is_much_smaller_than_full_sync() {
+        let (storage, agent_id) = seeded_storage();
+        
+        let metadata = SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent_id.clone(),
+            local_version: 1,
+            cloud_version: 1,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Synced,
+            last_synced_seq: storage.latest_message_seq(&agent_id).unwrap(),
+            conflicts: None,
+        };
+        
+        storage.add_message(&StoredMessage::new(&agent_id, "user", "One more entry")).unwrap();
+        
+        let delta = SyncDelta::collect(&storage, &agent_id, &metadata).unwrap();
+        assert!(delta.blocks.is_empty());
+        assert_eq!(delta.messages.len(), 1);
+        assert_eq!(delta.messages[0].content, "One more entry");
+        
+        let delta_request = SyncDeltaRequest {
+            agent_id: agent_id.clone(),
+            base_version: 1,
+            local_version: 2,
+            since_seq: metadata.last_synced_seq,
+            device_id: "device".to_string(),
+            delta,
+        };
+        let full_request = SyncRequest {
+            agent_id: agent_id.clone(),
+            agent_file: agent_file_from_storage(&storage, &agent_id).unwrap().into(),
+            local_version: 2,
+            device_id: "device".to_string(),
+        };
+        
+        let delta_size = serde_json::to_vec(&delta_request).unwrap().len();
+        let full_size = serde_json::to_vec(&full_request).unwrap().len();
+        assert!(
+            delta_size * 10 < full_size,
+            "delta payload {} bytes should be well under full payload {} bytes",
+            delta_size,
+            full_size
+        );
+    }
+    
+    #[test]
+    fn test_apply_incoming_delta() {
+        let (storage, agent_id) = seeded_storage();
+        let existing = storage.get_messages_since(&agent_id, 39).unwrap();
+        
+        let delta = SyncDelta {
+            blocks: vec![StoredBlock::new("cloud-side-id", "human", "Name: Alice Smith")],
+            messages: vec![
+                existing[0].clone(),
+                StoredMessage::new("cloud-side-id", "assistant", "Synced from another device"),
+            ],
+        };
+        
+        storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent_id.clone(),
+            local_version: 1,
+            cloud_version: 1,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Synced,
+            last_synced_seq: 40,
+            conflicts: None,
+        }).unwrap();
+        storage.cancel_sync_entries("agent", &agent_id).unwrap();
+        
+        assert_eq!(delta.apply(&storage, &agent_id).unwrap(), 2);
+        
+        // Cloud writes are not queued to be uploaded back
+        let metadata = storage.get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        assert_eq!((metadata.sync_status, metadata.local_version), (SyncStatus::Synced, 1));
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+        
+        let blocks = storage.get_blocks(&agent_id).unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].value, "Name: Alice Smith");
+        
+        let newest = storage.get_messages_since(&agent_id, 40).unwrap();
+        assert_eq!(newest.len(), 1);
+        assert_eq!(newest[0].content, "Synced from another device");
+    }
+}
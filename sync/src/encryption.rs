@@ -0,0 +1,247 @@
+use std::borrow::Cow;
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use letta_core::af::AgentFileV1;
+use serde::{Deserialize, Serialize};
+use crate::error::{Result, SyncError};
+
+/// Envelope schema identifier
+pub const E2E_SCHEMA: &str = "letta-e2e-v1";
+
+const SALT_LEN: usize = 16;
+
+/// Secret used to encrypt agent files before they leave the device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum E2eConfig {
+    /// Stretched into a key with Argon2id
+    Passphrase(String),
+    /// Base64-encoded 32-byte key, used as-is
+    Key(String),
+}
+
+/// Argon2id parameters needed to re-derive the key from the passphrase
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+/// An encrypted `AgentFileV1` as stored on the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedEnvelope {
+    pub schema: String,
+    pub ciphertext: String,
+    pub nonce: String,
+    /// Absent when a raw key was used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf_params: Option<KdfParams>,
+}
+
+/// An agent file as exchanged with the server: plain, or end-to-end
+/// encrypted. Uploads borrow a plain file; downloads own it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AgentPayload<'a> {
+    Encrypted(EncryptedEnvelope),
+    Plain(Cow<'a, AgentFileV1>),
+}
+
+impl From<AgentFileV1> for AgentPayload<'static> {
+    fn from(agent_file: AgentFileV1) -> Self {
+        AgentPayload::Plain(Cow::Owned(agent_file))
+    }
+}
+
+impl<'a> From<&'a AgentFileV1> for AgentPayload<'a> {
+    fn from(agent_file: &'a AgentFileV1) -> Self {
+        AgentPayload::Plain(Cow::Borrowed(agent_file))
+    }
+}
+
+/// Encrypts uploads and decrypts downloads for one configured secret.
+///
+/// For passphrases the key is derived once per client with a fresh salt and
+/// reused for every upload; envelopes carrying another salt are re-derived.
+pub(crate) struct E2eCipher {
+    secret: E2eConfig,
+    params: Params,
+    salt: [u8; SALT_LEN],
+    key: Key,
+}
+
+impl E2eCipher {
+    pub fn new(secret: &E2eConfig) -> Result<Self> {
+        Self::with_params(secret, Params::default())
+    }
+    
+    pub fn with_params(secret: &E2eConfig, params: Params) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive_key(secret, &params, &salt)?;
+        
+        Ok(Self {
+            secret: secret.clone(),
+            params,
+            salt,
+            key,
+        })
+    }
+    
+    pub fn encrypt(&self, agent_file: &AgentFileV1) -> Result<EncryptedEnvelope> {
+        let plaintext = serde_json::to_vec(agent_file)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key)
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| SyncError::Encryption("Failed to encrypt agent file".into()))?;
+        
+        let kdf_params = match self.secret {
+            E2eConfig::Passphrase(_) => Some(KdfParams {
+                algorithm: "argon2id".to_string(),
+                salt: STANDARD.encode(self.salt),
+                m_cost: self.params.m_cost(),
+                t_cost: self.params.t_cost(),
+                p_cost: self.params.p_cost(),
+            }),
+            E2eConfig::Key(_) => None,
+        };
+        
+        Ok(EncryptedEnvelope {
+            schema: E2E_SCHEMA.to_string(),
+            ciphertext: STANDARD.encode(ciphertext),
+            nonce: STANDARD.encode(nonce),
+            kdf_params,
+        })
+    }
+    
+    pub fn decrypt(&self, envelope: &EncryptedEnvelope) -> Result<AgentFileV1> {
+        if envelope.schema != E2E_SCHEMA {
+            return Err(SyncError::Encryption(format!("Unsupported envelope schema '{}'", envelope.schema)));
+        }
+        
+        let key = match (&envelope.kdf_params, &self.secret) {
+            (Some(kdf), E2eConfig::Passphrase(_)) => {
+                let salt = decode(&kdf.salt, "salt")?;
+                if salt == self.salt {
+                    self.key
+                } else {
+                    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, None)
+                        .map_err(|e| SyncError::Encryption(format!("Invalid KDF parameters: {}", e)))?;
+                    derive_key(&self.secret, &params, &salt)?
+                }
+            }
+            (None, E2eConfig::Key(_)) => self.key,
+            // Passphrase-encrypted file opened with a raw key or vice versa
+            _ => return Err(SyncError::WrongPassphrase),
+        };
+        
+        let nonce = decode(&envelope.nonce, "nonce")?;
+        if nonce.len() != 24 {
+            return Err(SyncError::Encryption("Invalid nonce length".into()));
+        }
+        let ciphertext = decode(&envelope.ciphertext, "ciphertext")?;
+        
+        let plaintext = XChaCha20Poly1305::new(&key)
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| SyncError::WrongPassphrase)?;
+        
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+    
+    /// Unwrap a downloaded payload, decrypting it if it is an envelope
+    pub fn open(cipher: Option<&Self>, payload: AgentPayload<'_>) -> Result<AgentFileV1> {
+        match (payload, cipher) {
+            (AgentPayload::Plain(agent_file), _) => Ok(agent_file.into_owned()),
+            (AgentPayload::Encrypted(envelope), Some(cipher)) => cipher.decrypt(&envelope),
+            (AgentPayload::Encrypted(_), None) => Err(SyncError::Encryption(
+                "Agent file is end-to-end encrypted but no encryption secret is configured".into(),
+            )),
+        }
+    }
+}
+
+fn derive_key(secret: &E2eConfig, params: &Params, salt: &[u8]) -> Result<Key> {
+    match secret {
+        E2eConfig::Passphrase(passphrase) => {
+            let mut key = Key::default();
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| SyncError::Encryption(format!("Key derivation failed: {}", e)))?;
+            Ok(key)
+        }
+        E2eConfig::Key(encoded) => {
+            let bytes = decode(encoded, "key")?;
+            if bytes.len() != 32 {
+                return Err(SyncError::Encryption("Encryption key must be 32 bytes".into()));
+            }
+            Ok(*Key::from_slice(&bytes))
+        }
+    }
+}
+
+fn decode(value: &str, what: &str) -> Result<Vec<u8>> {
+    STANDARD.decode(value)
+        .map_err(|_| SyncError::Encryption(format!("Invalid base64 {}", what)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use letta_core::{af::AgentFile, AgentConfig, AgentState};
+    
+    // Cheap parameters keep debug-build tests fast
+    fn cipher(passphrase: &str) -> E2eCipher {
+        let params = Params::new(256, 1, 1, None).unwrap();
+        E2eCipher::with_params(&E2eConfig::Passphrase(passphrase.to_string()), params).unwrap()
+    }
+    
+    fn agent_file() -> AgentFileV1 {
+        let mut state = AgentState::new("journal");
+        state.memory.set_block("human", "Allergic to peanuts").unwrap();
+        AgentFile::export(&AgentConfig::default(), &state, vec![]).unwrap()
+    }
+    
+    #[test]
+    fn test_encrypted_round_trip() {
+        let agent_file = agent_file();
+        let envelope = cipher("correct horse").encrypt(&agent_file).unwrap();
+        
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert!(!json.contains("peanuts"));
+        
+        // A second device derives the key again from the envelope's salt
+        let payload: AgentPayload = serde_json::from_str(&json).unwrap();
+        let decrypted = E2eCipher::open(Some(&cipher("correct horse")), payload).unwrap();
+        assert_eq!(serde_json::to_string(&decrypted).unwrap(), serde_json::to_string(&agent_file).unwrap());
+    }
+    
+    #[test]
+    fn test_wrong_passphrase_is_reported() {
+        let envelope = cipher("correct horse").encrypt(&agent_file()).unwrap();
+        assert!(matches!(cipher("battery staple").decrypt(&envelope), Err(SyncError::WrongPassphrase)));
+    }
+    
+    #[test]
+    fn test_raw_key_and_plain_payloads() {
+        let key = E2eConfig::Key(STANDARD.encode([7u8; 32]));
+        let cipher = E2eCipher::new(&key).unwrap();
+        let envelope = cipher.encrypt(&agent_file()).unwrap();
+        assert!(envelope.kdf_params.is_none());
+        assert!(cipher.decrypt(&envelope).is_ok());
+        
+        // Unencrypted files still load, with or without a secret configured
+        let plain: AgentPayload = serde_json::from_str(&serde_json::to_string(&agent_file()).unwrap()).unwrap();
+        assert!(E2eCipher::open(None, plain.clone()).is_ok());
+        assert!(E2eCipher::open(Some(&cipher), plain).is_ok());
+        
+        let encrypted = AgentPayload::Encrypted(envelope);
+        assert!(matches!(E2eCipher::open(None, encrypted), Err(SyncError::Encryption(_))));
+    }
+}
@@ -0,0 +1,48 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    
+    #[error("Could not connect through proxy {proxy}: {source}")]
+    Proxy { proxy: String, source: reqwest::Error },
+    
+    #[error("Storage error: {0}")]
+    Storage(#[from] letta_storage::StorageError),
+    
+    #[error("Agent error: {0}")]
+    Core(#[from] letta_core::LettaError),
+    
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    
+    #[error("Wrong passphrase or key for end-to-end encrypted agent file")]
+    WrongPassphrase,
+    
+    #[error("Authentication error: {0}")]
+    Auth(String),
+    
+    #[error("Server returned {status}: {message}")]
+    Server { status: u16, message: String },
+    
+    #[error("Gave up after {attempts} attempts: {source}")]
+    RetriesExhausted { attempts: u32, source: Box<SyncError> },
+    
+    #[error("Version gap: delta based on cloud version {base_version}, server is at {cloud_version}")]
+    VersionGap { base_version: i64, cloud_version: i64 },
+    
+    #[error("Agent not found: {0}")]
+    AgentNotFound(String),
+    
+    #[error("Invalid data: {0}")]
+    InvalidData(String),
+}
+
+pub type Result<T> = std::result::Result<T, SyncError>;
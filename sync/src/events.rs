@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+/// Capacity of the event channel; slow subscribers skip older events
+pub(crate) const EVENT_CAPACITY: usize = 64;
+
+/// Notifications emitted by `SyncClient` for every network sync operation,
+/// whether triggered manually or by the auto-sync loop.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncEvent {
+    Started {
+        agent_id: String,
+    },
+    /// Payload bytes sent (uploads) or received (downloads)
+    Progress {
+        agent_id: String,
+        bytes: usize,
+    },
+    Completed {
+        agent_id: String,
+        /// Cloud version after the sync, when the server reports one
+        new_version: Option<i64>,
+        /// Size of the uploaded body, for operations that send one
+        payload: Option<PayloadSize>,
+    },
+    Conflict {
+        agent_id: String,
+        fields: Vec<String>,
+    },
+    Failed {
+        agent_id: String,
+        error: String,
+    },
+}
+
+/// JSON size of an upload and the bytes actually sent after compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PayloadSize {
+    pub uncompressed: usize,
+    pub sent: usize,
+}
+
+impl SyncEvent {
+    pub fn agent_id(&self) -> &str {
+        match self {
+            SyncEvent::Started { agent_id }
+            | SyncEvent::Progress { agent_id, .. }
+            | SyncEvent::Completed { agent_id, .. }
+            | SyncEvent::Conflict { agent_id, .. }
+            | SyncEvent::Failed { agent_id, .. } => agent_id,
+        }
+    }
+}
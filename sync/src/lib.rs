@@ -1,32 +1,151 @@
 use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use letta_core::af::{AgentFileV1, AgentFile};
+use tokio::sync::broadcast;
+use chrono::{DateTime, Utc};
+use letta_core::{af::AgentFileV1, retry, RetryConfig, ShutdownStage};
+use letta_storage::{BlockWriter, Storage, SyncMetadata, SyncStatus};
+
+pub mod auth;
+mod compression;
+mod convert;
+pub mod delta;
+pub mod encryption;
+pub mod error;
+pub mod events;
+pub mod plan;
+pub mod queue;
+pub mod resolver;
+pub mod settings;
+
+pub use auth::{AuthConfig, AuthProvider, RefreshingToken, StaticApiKey};
+pub use delta::{SyncDelta, SyncDeltaRequest};
+pub use encryption::{AgentPayload, E2eConfig, EncryptedEnvelope};
+use encryption::E2eCipher;
+pub use error::{SyncError, Result};
+pub use events::{PayloadSize, SyncEvent};
+use compression::EncodedBody;
+use events::EVENT_CAPACITY;
+pub use plan::{SyncDirection, SyncPlan};
+pub use queue::QueueStatus;
+pub use resolver::{ConflictResolver, Resolution};
+pub use settings::AgentSyncPolicy;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SyncConfig {
     pub endpoint: String,
     pub api_key: String,
+    pub auth: AuthConfig,
+    /// Send every request through this proxy (`http://` or
+    /// `https://`); otherwise the usual proxy environment variables apply
+    pub proxy: Option<String>,
+    /// Hosts to reach directly despite `proxy`, comma-separated as in `NO_PROXY`
+    pub no_proxy: Option<String>,
     pub sync_interval: u64, // milliseconds
-    pub conflict_resolution: String,
+    pub conflict_resolution: ConflictResolution,
     pub auto_sync: bool,
+    /// Send only changed blocks/messages once an agent has a cloud version,
+    /// falling back to a full upload when the server rejects the delta
+    pub delta: bool,
+    /// Attempts before a queued upload is marked failed permanently
+    pub queue_max_attempts: u32,
+    /// First retry delay for a failed queued upload; doubles per attempt
+    pub queue_backoff_base_ms: u64,
+    pub queue_backoff_max_ms: u64,
+    /// Gzip request bodies and accept gzip responses
+    pub compression: bool,
+    /// Bodies smaller than this many bytes are sent uncompressed
+    pub compression_threshold: usize,
+    /// Encrypt agent files on the device before upload. Delta sync is not
+    /// used while this is set since deltas would reveal plaintext.
+    pub encryption: Option<E2eConfig>,
+    /// Fixed device id, mainly for tests; otherwise one is generated once and
+    /// persisted when the client is built with `SyncClient::with_storage`
+    pub device_id: Option<String>,
+    /// Retries for requests the server turns away (429, 503) or that can't
+    /// connect; separate from the upload queue's own backoff
+    pub retry: RetryConfig,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "https://api.letta.ai".to_string(),
+            api_key: String::new(),
+            auth: AuthConfig::default(),
+            proxy: None,
+            no_proxy: None,
+            sync_interval: 300000, // 5 minutes
+            conflict_resolution: ConflictResolution::default(),
+            auto_sync: false,
+            delta: true,
+            queue_max_attempts: 8,
+            queue_backoff_base_ms: 5000,
+            queue_backoff_max_ms: 3600000, // 1 hour
+            compression: false,
+            compression_threshold: 1024,
+            encryption: None,
+            device_id: None,
+            retry: RetryConfig::default(),
+        }
+    }
+}
+
+impl SyncConfig {
+    /// The `[sync]` section of an app config file, or `None` if it has none.
+    /// Keys left out keep their defaults.
+    pub fn from_app_config(config: &letta_core::AppConfig) -> Result<Option<Self>> {
+        Ok(config.sync.as_ref().map(|sync| letta_core::config::section("sync", sync)).transpose()?)
+    }
+}
+
+const DEVICE_ID_SETTING: &str = "sync.device_id";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictResolution {
+    /// The most recently updated side wins
+    #[default]
+    LastWriteWins,
+    CloudWins,
+    /// Keep local values and add anything only the cloud has
+    Merge,
+    /// Every conflict goes to the resolver set with `SyncClient::set_resolver`
+    Manual,
 }
 
+/// Body of a full upload. Built by `SyncClient::sync_agent` around a borrowed
+/// agent file, so serializing it doesn't copy the file first.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SyncRequest {
+pub struct SyncRequest<'a> {
     pub agent_id: String,
-    pub agent_file: AgentFileV1,
+    pub agent_file: AgentPayload<'a>,
     pub local_version: i64,
     pub device_id: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncResponse {
-    pub agent_file: Option<AgentFileV1>,
+    /// Cloud copy of the agent; see `SyncClient::open_payload`
+    pub agent_file: Option<AgentPayload<'static>>,
     pub cloud_version: i64,
     pub conflicts: Vec<ConflictInfo>,
     pub status: String,
+    /// Changes made on other devices since the delta's base version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<SyncDelta>,
+}
+
+/// An agent as listed by the server, without its contents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteAgentSummary {
+    pub id: String,
+    pub name: String,
+    pub updated_at: DateTime<Utc>,
+    pub version: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,123 +159,570 @@ pub struct ConflictInfo {
 pub struct SyncClient {
     config: SyncConfig,
     client: Client,
+    auth: Box<dyn AuthProvider>,
+    cipher: Option<E2eCipher>,
+    resolver: RwLock<Option<ConflictResolver>>,
     device_id: String,
+    events: broadcast::Sender<SyncEvent>,
 }
 
 impl SyncClient {
-    pub fn new(config: SyncConfig) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = Client::builder()
+    pub fn new(config: SyncConfig) -> Result<Self> {
+        let client = retry::client_builder(config.proxy.as_deref(), config.no_proxy.as_deref())?
             .timeout(Duration::from_secs(30))
+            .gzip(config.compression)
             .build()?;
         
-        let device_id = uuid::Uuid::new_v4().to_string();
+        let auth = auth::provider_from_config(&config.auth, &config.api_key, &client)?;
+        let cipher = config.encryption.as_ref().map(E2eCipher::new).transpose()?;
+        let device_id = config.device_id.clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
         
         Ok(Self {
             config,
             client,
+            auth,
+            cipher,
+            resolver: RwLock::new(None),
             device_id,
+            events,
         })
     }
     
-    pub async fn sync_agent(&self, agent_file: &AgentFileV1, local_version: i64) -> Result<SyncResponse, Box<dyn std::error::Error>> {
-        let agent_id = agent_file.agents.first()
-            .map(|a| a.id.clone())
-            .ok_or("No agent in file")?;
-        
-        let request = SyncRequest {
-            agent_id,
-            agent_file: agent_file.clone(),
-            local_version,
-            device_id: self.device_id.clone(),
-        };
+    /// Replace the authentication configured in `SyncConfig::auth`
+    pub fn with_auth(mut self, auth: impl AuthProvider + 'static) -> Self {
+        self.auth = Box::new(auth);
+        self
+    }
+    
+    /// Like `new`, but the device id is read from `storage`, generated and
+    /// saved there on first use so it stays stable across launches
+    pub fn with_storage(mut config: SyncConfig, storage: &Storage) -> Result<Self> {
+        if config.device_id.is_none() {
+            let device_id = storage.get_or_init_setting(DEVICE_ID_SETTING, || uuid::Uuid::new_v4().to_string())?;
+            config.device_id = Some(device_id);
+        }
+        Self::new(config)
+    }
+    
+    pub fn config(&self) -> &SyncConfig {
+        &self.config
+    }
+    
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+    
+    /// Receive a `SyncEvent` for every sync, push, and pull from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.events.subscribe()
+    }
+    
+    fn emit(&self, event: SyncEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+    
+    /// Emit `Started` before and `Completed`/`Conflict`/`Failed` after `operation`.
+    /// A `VersionGap` is not reported as a failure since the caller falls back
+    /// to a full sync.
+    async fn tracked<T>(
+        &self,
+        agent_id: &str,
+        operation: impl std::future::Future<Output = Result<(T, Option<PayloadSize>)>>,
+        new_version: impl FnOnce(&T) -> Option<i64>,
+        conflicts: impl FnOnce(&T) -> Vec<String>,
+    ) -> Result<T> {
+        self.emit(SyncEvent::Started { agent_id: agent_id.to_string() });
         
-        let response = self.client
-            .post(&format!("{}/v1/agents/sync", self.config.endpoint))
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .json(&request)
-            .send()
-            .await?;
+        match operation.await {
+            Ok((value, payload)) => {
+                let fields = conflicts(&value);
+                if !fields.is_empty() {
+                    self.emit(SyncEvent::Conflict { agent_id: agent_id.to_string(), fields });
+                }
+                self.emit(SyncEvent::Completed {
+                    agent_id: agent_id.to_string(),
+                    new_version: new_version(&value),
+                    payload,
+                });
+                Ok(value)
+            }
+            Err(e) => {
+                if !matches!(e, SyncError::VersionGap { .. }) {
+                    self.emit(SyncEvent::Failed { agent_id: agent_id.to_string(), error: e.to_string() });
+                }
+                Err(e)
+            }
+        }
+    }
+    
+    /// Encrypt `agent_file` for upload when end-to-end encryption is configured
+    fn seal<'a>(&self, agent_file: &'a AgentFileV1) -> Result<AgentPayload<'a>> {
+        match &self.cipher {
+            Some(cipher) => Ok(AgentPayload::Encrypted(cipher.encrypt(agent_file)?)),
+            None => Ok(agent_file.into()),
+        }
+    }
+    
+    /// Turn a downloaded payload into an agent file, decrypting it if needed
+    pub fn open_payload(&self, payload: AgentPayload<'_>) -> Result<AgentFileV1> {
+        E2eCipher::open(self.cipher.as_ref(), payload)
+    }
+    
+    /// Send a request with credentials attached. On a 401 the auth provider
+    /// may refresh once, after which the request is retried. Requests turned
+    /// away or unable to connect are retried per `SyncConfig::retry`.
+    async fn send(&self, request: impl Fn(&Client) -> RequestBuilder) -> Result<Response> {
+        let response = self.send_retrying(&request).await?;
         
-        if !response.status().is_success() {
-            return Err(format!("Sync failed: {}", response.status()).into());
+        if response.status() != StatusCode::UNAUTHORIZED || !self.auth.refresh().await? {
+            return Ok(response);
         }
         
-        let sync_response: SyncResponse = response.json().await?;
-        Ok(sync_response)
+        self.send_retrying(&request).await
     }
     
-    pub async fn pull_agent(&self, agent_id: &str) -> Result<Option<AgentFileV1>, Box<dyn std::error::Error>> {
-        let response = self.client
-            .get(&format!("{}/v1/agents/{}/export", self.config.endpoint, agent_id))
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .send()
-            .await?;
+    /// Send with the current credentials, retrying per the config. Running
+    /// out of attempts is an error naming how many were made.
+    async fn send_retrying(&self, request: &impl Fn(&Client) -> RequestBuilder) -> Result<Response> {
+        let retried = retry::send(&self.config.retry, || async {
+            let (name, value) = self.auth.auth_header().await?;
+            Ok::<_, SyncError>(request(&self.client).header(name, value))
+        }).await;
         
-        if response.status() == 404 {
-            return Ok(None);
+        let error = match retried.result {
+            Ok(response) if !retried.gave_up => return Ok(response),
+            Ok(response) => match check_status(response).await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            },
+            Err(e) => self.via_proxy(e),
+        };
+        if retried.attempts == 1 {
+            return Err(error);
         }
-        
-        if !response.status().is_success() {
-            return Err(format!("Pull failed: {}", response.status()).into());
+        Err(SyncError::RetriesExhausted { attempts: retried.attempts, source: Box::new(error) })
+    }
+    
+    /// Name the proxy in connection failures, since it is the proxy that
+    /// could not be reached rather than the server
+    fn via_proxy(&self, error: SyncError) -> SyncError {
+        match (error, &self.config.proxy) {
+            (SyncError::Http(source), Some(proxy)) if source.is_connect() => {
+                SyncError::Proxy { proxy: proxy.clone(), source }
+            }
+            (error, _) => error,
         }
-        
-        let agent_file: AgentFileV1 = response.json().await?;
-        Ok(Some(agent_file))
     }
     
-    pub async fn push_agent(&self, agent_file: &AgentFileV1) -> Result<(), Box<dyn std::error::Error>> {
+    /// Serialize `body` as JSON, compressed per the config, reporting the
+    /// bytes to send as progress
+    fn json_body<T: Serialize>(&self, agent_id: &str, body: &T) -> Result<EncodedBody> {
+        let body = EncodedBody::encode(&self.config, serde_json::to_vec(body)?)?;
+        self.emit(SyncEvent::Progress { agent_id: agent_id.to_string(), bytes: body.size.sent });
+        Ok(body)
+    }
+    
+    /// Read and parse a JSON response body, reporting its size as progress
+    async fn read_json<T: serde::de::DeserializeOwned>(&self, agent_id: &str, response: Response) -> Result<T> {
+        let bytes = response.bytes().await?;
+        self.emit(SyncEvent::Progress { agent_id: agent_id.to_string(), bytes: bytes.len() });
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+    
+    pub async fn sync_agent(&self, agent_file: &AgentFileV1, local_version: i64) -> Result<SyncResponse> {
         let agent_id = agent_file.agents.first()
             .map(|a| a.id.clone())
-            .ok_or("No agent in file")?;
+            .ok_or_else(|| SyncError::InvalidData("No agent in file".into()))?;
         
-        let response = self.client
-            .put(&format!("{}/v1/agents/{}/import", self.config.endpoint, agent_id))
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
-            .json(agent_file)
-            .send()
-            .await?;
+        self.tracked(&agent_id, async {
+            let request = SyncRequest {
+                agent_id: agent_id.clone(),
+                agent_file: self.seal(agent_file)?,
+                local_version,
+                device_id: self.device_id.clone(),
+            };
+            
+            let body = self.json_body(&agent_id, &request)?;
+            let response = self.send(|client| body.attach(client
+                .post(format!("{}/v1/agents/sync", self.config.endpoint))
+            )).await?;
+            
+            let sync_response = self.read_json(&agent_id, check_status(response).await?).await?;
+            Ok((sync_response, Some(body.size)))
+        }, |r: &SyncResponse| Some(r.cloud_version), conflict_fields).await
+    }
+    
+    /// Upload only the changes since `base_version`. Fails with
+    /// `SyncError::VersionGap` when the server can't apply the delta, in which
+    /// case the caller should fall back to `sync_agent`.
+    pub async fn sync_delta(&self, request: &SyncDeltaRequest) -> Result<SyncResponse> {
+        self.tracked(&request.agent_id, async {
+            let body = self.json_body(&request.agent_id, request)?;
+            let response = self.send(|client| body.attach(client
+                .post(format!("{}/v1/agents/{}/sync/delta", self.config.endpoint, request.agent_id))
+            )).await?;
+            
+            if response.status() == StatusCode::CONFLICT {
+                let cloud_version = response.json::<serde_json::Value>().await
+                    .ok()
+                    .and_then(|body| body.get("cloud_version").and_then(|v| v.as_i64()))
+                    .unwrap_or(-1);
+                return Err(SyncError::VersionGap {
+                    base_version: request.base_version,
+                    cloud_version,
+                });
+            }
+            
+            let sync_response: SyncResponse = self.read_json(&request.agent_id, check_status(response).await?).await?;
+            if sync_response.status == "version_gap" {
+                return Err(SyncError::VersionGap {
+                    base_version: request.base_version,
+                    cloud_version: sync_response.cloud_version,
+                });
+            }
+            
+            Ok((sync_response, Some(body.size)))
+        }, |r: &SyncResponse| Some(r.cloud_version), conflict_fields).await
+    }
+    
+    pub async fn pull_agent(&self, agent_id: &str) -> Result<Option<AgentFileV1>> {
+        self.tracked(agent_id, async {
+            let response = self.send(|client| client
+                .get(format!("{}/v1/agents/{}/export", self.config.endpoint, agent_id))
+            ).await?;
+            
+            if response.status() == 404 {
+                return Ok((None, None));
+            }
+            
+            let payload: AgentPayload = self.read_json(agent_id, check_status(response).await?).await?;
+            Ok((Some(self.open_payload(payload)?), None))
+        }, |_| None, |_| Vec::new()).await
+    }
+    
+    pub async fn push_agent(&self, agent_file: &AgentFileV1) -> Result<()> {
+        let agent_id = agent_file.agents.first()
+            .map(|a| a.id.clone())
+            .ok_or_else(|| SyncError::InvalidData("No agent in file".into()))?;
         
-        if !response.status().is_success() {
-            return Err(format!("Push failed: {}", response.status()).into());
-        }
+        self.tracked(&agent_id, async {
+            let body = self.json_body(&agent_id, &self.seal(agent_file)?)?;
+            let response = self.send(|client| body.attach(client
+                .put(format!("{}/v1/agents/{}/import", self.config.endpoint, agent_id))
+            )).await?;
+            
+            check_status(response).await?;
+            Ok(((), Some(body.size)))
+        }, |_| None, |_| Vec::new()).await
+    }
+    
+    /// Delete the agent on the server. An agent the server doesn't have
+    /// counts as deleted.
+    pub async fn delete_agent(&self, agent_id: &str) -> Result<()> {
+        self.tracked(agent_id, async {
+            let response = self.send(|client| client
+                .delete(format!("{}/v1/agents/{}", self.config.endpoint, agent_id))
+            ).await?;
+            
+            if response.status() != 404 {
+                check_status(response).await?;
+            }
+            Ok(((), None))
+        }, |_| None, |_| Vec::new()).await
+    }
+    
+    /// List the agents stored on the server for this account
+    pub async fn list_remote_agents(&self) -> Result<Vec<RemoteAgentSummary>> {
+        let response = self.send(|client| client
+            .get(format!("{}/v1/agents", self.config.endpoint))
+        ).await?;
         
-        Ok(())
+        Ok(check_status(response).await?.json().await?)
     }
     
+    /// The value to keep for `conflict` under the configured strategy.
+    /// Deferred conflicts keep the local value.
     pub fn resolve_conflict(&self, conflict: &ConflictInfo) -> serde_json::Value {
-        match self.config.conflict_resolution.as_str() {
-            "last-write-wins" => conflict.local_value.clone(),
-            "cloud-wins" => conflict.cloud_value.clone(),
-            "merge" => {
-                // Simple merge strategy: combine if both are objects
-                if conflict.local_value.is_object() && conflict.cloud_value.is_object() {
-                    let mut merged = conflict.cloud_value.clone();
-                    if let Some(local_obj) = conflict.local_value.as_object() {
-                        if let Some(merged_obj) = merged.as_object_mut() {
-                            for (k, v) in local_obj {
-                                merged_obj.insert(k.clone(), v.clone());
-                            }
-                        }
-                    }
-                    merged
-                } else {
-                    conflict.local_value.clone()
-                }
-            }
-            _ => conflict.local_value.clone(),
+        match self.resolve(self.config.conflict_resolution, conflict) {
+            Resolution::UseLocal | Resolution::Defer => conflict.local_value.clone(),
+            Resolution::UseCloud => conflict.cloud_value.clone(),
+            Resolution::UseValue(value) => value,
         }
     }
 }
 
+fn conflict_fields(response: &SyncResponse) -> Vec<String> {
+    response.conflicts.iter().map(|c| c.field.clone()).collect()
+}
+
+/// Turn a non-success response into `SyncError::Server` carrying the body
+async fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    
+    let message = response.text().await.unwrap_or_default();
+    Err(SyncError::Server {
+        status: status.as_u16(),
+        message,
+    })
+}
+
 // Background sync task
 pub struct SyncManager {
-    client: SyncClient,
-    storage: letta_storage::Storage,
+    client: Arc<SyncClient>,
+    storage: Storage,
 }
 
 impl SyncManager {
-    pub fn new(client: SyncClient, storage: letta_storage::Storage) -> Self {
-        Self { client, storage }
+    /// `client` may be shared, e.g. with a host that also subscribes to its events
+    pub fn new(client: impl Into<Arc<SyncClient>>, storage: Storage) -> Self {
+        Self { client: client.into(), storage }
+    }
+    
+    /// Sync one stored agent with the cloud.
+    ///
+    /// Once the agent has a cloud version (and delta mode is on) only the blocks
+    /// and messages changed since the last sync are sent; a version gap falls
+    /// back to uploading the full agent file. Incoming deltas are written to
+    /// storage and the sync metadata is advanced on success, past what was
+    /// sent only: an agent written to during the upload stays pending.
+    pub async fn sync_agent(&self, agent_id: &str) -> Result<SyncResponse> {
+        let storage = self.storage.to_async();
+        // Block changes from here on may miss the upload, so the next one
+        // looks for them
+        let started = Utc::now();
+        let metadata = storage.get_sync_metadata("agent", agent_id).await?;
+        let local_version = metadata.as_ref().map(|m| m.local_version).unwrap_or(0);
+        
+        let mut response = None;
+        let mut sent_seq = 0;
+        let config = &self.client.config;
+        let use_delta = config.delta && config.encryption.is_none();
+        if let Some(metadata) = metadata.as_ref().filter(|m| use_delta && m.cloud_version > 0) {
+            let request = SyncDeltaRequest {
+                agent_id: agent_id.to_string(),
+                base_version: metadata.cloud_version,
+                local_version,
+                since_seq: metadata.last_synced_seq,
+                device_id: self.client.device_id.clone(),
+                delta: {
+                    let (agent_id, metadata) = (agent_id.to_string(), metadata.clone());
+                    storage.run(move |s| SyncDelta::collect(s, &agent_id, &metadata)).await?
+                },
+            };
+            
+            match self.client.sync_delta(&request).await {
+                Ok(delta_response) => {
+                    sent_seq = request.delta.last_seq().unwrap_or(metadata.last_synced_seq);
+                    response = Some(delta_response);
+                }
+                Err(SyncError::VersionGap { base_version, cloud_version }) => {
+                    tracing::info!(
+                        "Delta sync for agent {} rejected (base {}, cloud {}), falling back to full sync",
+                        agent_id, base_version, cloud_version
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        
+        let response = match response {
+            Some(response) => response,
+            None => {
+                let id = agent_id.to_string();
+                let (agent_file, last_seq) = storage.run(move |s| convert::agent_file_with_seq(s, &id)).await?;
+                sent_seq = last_seq;
+                self.client.sync_agent(&agent_file, local_version).await?
+            }
+        };
+        
+        if let Some(delta) = &response.delta {
+            {
+                let (delta, id) = (delta.clone(), agent_id.to_string());
+                storage.run(move |s| delta.apply(s, &id)).await?;
+            }
+            // The cloud already has the messages it sent; anything local
+            // after them still has to go up
+            let incoming: HashSet<&str> = delta.messages.iter().map(|m| m.id.as_str()).collect();
+            for message in storage.get_messages_since(agent_id, sent_seq).await? {
+                if !incoming.contains(message.id.as_str()) {
+                    break;
+                }
+                sent_seq = message.seq;
+            }
+        }
+        
+        let deferred = {
+            let (id, conflicts) = (agent_id.to_string(), response.conflicts.clone());
+            self.run_blocking(move |manager| manager.handle_conflicts(&id, &conflicts)).await?
+        };
+        // Local writes since `metadata` was read bumped the version
+        let current_version = storage.get_sync_metadata("agent", agent_id).await?
+            .map_or(local_version, |m| m.local_version);
+        let (sync_status, conflicts) = if !deferred.is_empty() {
+            (SyncStatus::Conflict, Some(serde_json::to_value(&deferred)?))
+        } else if current_version != local_version {
+            (SyncStatus::Pending, None)
+        } else {
+            (SyncStatus::Synced, None)
+        };
+        
+        storage.update_sync_metadata(SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent_id.to_string(),
+            local_version: current_version,
+            cloud_version: response.cloud_version,
+            last_sync_at: started,
+            sync_status,
+            last_synced_seq: sent_seq,
+            conflicts,
+        }).await?;
+        
+        Ok(response)
+    }
+    
+    /// Sync one stored agent, then write any cloud copy the server returned
+    /// back into storage according to the agent's `ConflictResolution`, as
+    /// `pull_all` does. Deferred conflicts from the sync are kept.
+    pub async fn sync_and_apply(&self, agent_id: &str) -> Result<SyncResponse> {
+        let storage = self.storage.to_async();
+        let response = self.sync_agent(agent_id).await?;
+        
+        if let Some(payload) = &response.agent_file {
+            let agent_file = self.client.open_payload(payload.clone())?;
+            let updated_at = agent_file.agents.first()
+                .map(|agent| agent.agent_state.updated_at)
+                .ok_or_else(|| SyncError::InvalidData("No agent in file".into()))?;
+            let summary = RemoteAgentSummary {
+                id: agent_id.to_string(),
+                name: agent_file.agents[0].name.clone(),
+                updated_at,
+                version: response.cloud_version,
+            };
+            
+            let synced = storage.get_sync_metadata("agent", agent_id).await?;
+            self.run_blocking(move |manager| manager.store_pulled_agent(&summary, &agent_file)).await?;
+            if let Some(synced) = synced.filter(|m| m.sync_status == SyncStatus::Conflict) {
+                storage.update_sync_metadata(synced).await?;
+            }
+        }
+        
+        Ok(response)
+    }
+    
+    /// Download every agent on the server into local storage.
+    ///
+    /// Agents with sync disabled are skipped. Agents that already exist locally
+    /// are reconciled per their `ConflictResolution`; messages are append-only, so any the local copy
+    /// lacks are always added. Returns the number of agents pulled.
+    pub async fn pull_all(&self) -> Result<usize> {
+        let storage = self.storage.to_async();
+        let remote_agents = self.client.list_remote_agents().await?;
+        let mut pulled = 0;
+        
+        for summary in remote_agents {
+            if !self.policy_from_settings(storage.get_agent_sync_settings(summary.id.as_str()).await?)?.enabled {
+                continue;
+            }
+            // Trashed here; the queued deletion removes it there
+            let tombstone = storage.get_sync_metadata("agent", summary.id.as_str()).await?
+                .is_some_and(|m| m.sync_status == SyncStatus::Deleted);
+            if tombstone {
+                continue;
+            }
+            
+            let agent_file = match self.client.pull_agent(&summary.id).await? {
+                Some(agent_file) => agent_file,
+                None => continue, // Deleted since it was listed
+            };
+            self.run_blocking(move |manager| manager.store_pulled_agent(&summary, &agent_file)).await?;
+            pulled += 1;
+        }
+        
+        Ok(pulled)
+    }
+    
+    /// Run `operation` on the blocking pool with a manager sharing this one's
+    /// client and storage, for work mixing storage calls with sync policy
+    async fn run_blocking<T, F>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&SyncManager) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let client = self.client.clone();
+        self.storage.to_async()
+            .run(move |storage| operation(&SyncManager { client, storage: storage.clone() }))
+            .await
+    }
+    
+    /// Write a pulled agent file into storage. Blocking; callers run it
+    /// through `run_blocking`.
+    fn store_pulled_agent(&self, summary: &RemoteAgentSummary, agent_file: &AgentFileV1) -> Result<()> {
+        let rows = convert::agent_rows_from_file(agent_file)?;
+        let agent_id = rows.agent.id.clone();
+        let local = self.storage.get_agent(&agent_id)?;
+        let conflict_resolution = self.agent_sync_policy(&agent_id)?.conflict_resolution;
+        
+        let cloud_wins = match (&local, conflict_resolution) {
+            (None, _) | (Some(_), ConflictResolution::CloudWins) => true,
+            (Some(local), ConflictResolution::LastWriteWins) => summary.updated_at >= local.updated_at,
+            (Some(_), ConflictResolution::Merge | ConflictResolution::Manual) => false,
+        };
+        
+        // Writing down the cloud copy is not a local change to upload
+        let storage = self.storage.untracked();
+        if cloud_wins {
+            if local.is_some() {
+                storage.update_agent(&rows.agent)?;
+            } else {
+                storage.create_agent(&rows.agent)?;
+            }
+            for block in &rows.blocks {
+                storage.upsert_block_by(block, BlockWriter::Sync)?;
+            }
+        } else if conflict_resolution == ConflictResolution::Merge {
+            let local_labels: Vec<String> = self.storage.get_blocks(&agent_id)?
+                .into_iter()
+                .map(|b| b.label)
+                .collect();
+            for block in rows.blocks.iter().filter(|b| !local_labels.contains(&b.label)) {
+                storage.upsert_block_by(block, BlockWriter::Sync)?;
+            }
+        }
+        
+        for message in &rows.messages {
+            storage.add_message_if_absent(message)?;
+        }
+        
+        // Local state that still differs from the cloud stays queued for upload
+        let sync_status = if cloud_wins {
+            self.storage.cancel_sync_entries("agent", &agent_id)?;
+            SyncStatus::Synced
+        } else {
+            self.storage.enqueue_sync("agent", &agent_id, "upsert")?;
+            SyncStatus::Pending
+        };
+        
+        let local_version = self.storage.get_sync_metadata("agent", &agent_id)?
+            .map(|m| m.local_version)
+            .unwrap_or(0);
+        self.storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent_id.clone(),
+            local_version,
+            cloud_version: summary.version,
+            last_sync_at: Utc::now(),
+            sync_status,
+            last_synced_seq: self.storage.latest_message_seq(&agent_id)?,
+            conflicts: None,
+        })?;
+        
+        Ok(())
     }
     
     pub async fn start_auto_sync(&self) {
@@ -164,21 +730,40 @@ impl SyncManager {
             return;
         }
         
-        let interval = Duration::from_millis(self.client.config.sync_interval);
-        
+        // Storage work runs on the blocking pool so the runtime stays free for the host
+        let storage = self.storage.to_async();
+        // `letta_core::shutdown_all` ends the loop, letting a round in progress finish
+        let stop = Arc::new(tokio::sync::Notify::new());
+        let _hook = letta_core::on_shutdown(ShutdownStage::StopTasks, "auto-sync", {
+            let stop = stop.clone();
+            move || {
+                stop.notify_one();
+                Ok(())
+            }
+        });
         loop {
-            tokio::time::sleep(interval).await;
+            // Wake as often as the most frequently synced agent needs
+            let interval = self.auto_sync_tick().await.unwrap_or_else(|e| {
+                tracing::error!("Failed to read agent sync settings: {}", e);
+                Duration::from_millis(self.client.config.sync_interval)
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = stop.notified() => {
+                    tracing::info!("Auto-sync stopped for shutdown");
+                    return;
+                }
+            }
             
-            // Get all agents that need syncing
-            match self.storage.list_agents() {
+            // Agents flagged pending in sync metadata join the upload queue
+            match storage.list_agents().await {
                 Ok(agents) => {
                     for agent in agents {
-                        // Check sync metadata
-                        if let Ok(Some(metadata)) = self.storage.get_sync_metadata("agent", &agent.id) {
-                            if metadata.sync_status == "pending" {
-                                // Perform sync
-                                // TODO: Convert agent to AF and sync
-                                tracing::info!("Auto-syncing agent {}", agent.id);
+                        if let Ok(Some(metadata)) = storage.get_sync_metadata("agent", &agent.id).await {
+                            if metadata.sync_status == SyncStatus::Pending {
+                                if let Err(e) = storage.enqueue_sync("agent", &agent.id, "upsert").await {
+                                    tracing::error!("Failed to queue agent {} for sync: {}", agent.id, e);
+                                }
                             }
                         }
                     }
@@ -187,6 +772,10 @@ impl SyncManager {
                     tracing::error!("Failed to list agents for sync: {}", e);
                 }
             }
+            
+            if let Err(e) = self.drain(true).await {
+                tracing::error!("Failed to drain sync queue: {}", e);
+            }
         }
     }
 }
@@ -194,6 +783,9 @@ impl SyncManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use letta_storage::{StoredAgent, StoredBlock, StoredMessage};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
     
     #[test]
     fn test_sync_config() {
@@ -201,12 +793,61 @@ mod tests {
             endpoint: "https://api.letta.ai".to_string(),
             api_key: "test-key".to_string(),
             sync_interval: 300000,
-            conflict_resolution: "last-write-wins".to_string(),
+            conflict_resolution: ConflictResolution::LastWriteWins,
             auto_sync: true,
+            ..Default::default()
         };
         
         assert_eq!(config.endpoint, "https://api.letta.ai");
         assert_eq!(config.sync_interval, 300000);
+        assert!(config.delta);
+    }
+    
+    #[test]
+    fn test_sync_config_from_app_config() {
+        use letta_core::config::{AppConfig, ConfigFormat};
+        
+        let text = r#"
+            [sync]
+            endpoint = "https://sync.example.com"
+            conflict_resolution = "merge"
+            auth = { type = "refreshing_token", refresh_url = "https://auth.example.com", refresh_token = "${TOKEN}" }
+        "#;
+        let app = AppConfig::parse(text, ConfigFormat::Toml, vec![("TOKEN".to_string(), "r-1".to_string())]).unwrap();
+        let config = SyncConfig::from_app_config(&app).unwrap().unwrap();
+        assert_eq!(config.endpoint, "https://sync.example.com");
+        assert_eq!(config.conflict_resolution, ConflictResolution::Merge);
+        assert_eq!(config.queue_max_attempts, SyncConfig::default().queue_max_attempts);
+        assert!(matches!(config.auth, AuthConfig::RefreshingToken { ref refresh_token, .. } if refresh_token == "r-1"));
+        
+        assert!(SyncConfig::from_app_config(&AppConfig::default()).unwrap().is_none());
+        let bad = AppConfig::parse("[sync]
+conflict_resolution = \"newest\"", ConfigFormat::Toml, Vec::new()).unwrap();
+        let message = SyncConfig::from_app_config(&bad).unwrap_err().to_string();
+        assert!(message.contains("`sync.conflict_resolution`: unknown variant `newest`"), "{}", message);
+    }
+    
+    #[test]
+    fn test_sync_request_borrows_agent_file() {
+        let mut state = letta_core::AgentState::new("borrowed");
+        state.memory.set_block("human", "Alice").unwrap();
+        state.messages.push(letta_core::Message::user("Hello"));
+        let agent_file = letta_core::AgentFile::export(&Default::default(), &state, vec![]).unwrap();
+        
+        let request = |agent_file| SyncRequest {
+            agent_id: state.id.clone(),
+            agent_file,
+            local_version: 3,
+            device_id: "device".to_string(),
+        };
+        let borrowed = request((&agent_file).into());
+        let owned = request(agent_file.clone().into());
+        assert!(matches!(borrowed.agent_file, AgentPayload::Plain(std::borrow::Cow::Borrowed(_))));
+        
+        let json = serde_json::to_string(&borrowed).unwrap();
+        assert_eq!(json, serde_json::to_string(&owned).unwrap());
+        let received: SyncRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&received).unwrap(), json);
     }
     
     #[test]
@@ -215,8 +856,9 @@ mod tests {
             endpoint: "test".to_string(),
             api_key: "test".to_string(),
             sync_interval: 0,
-            conflict_resolution: "last-write-wins".to_string(),
+            conflict_resolution: ConflictResolution::LastWriteWins,
             auto_sync: false,
+            ..Default::default()
         };
         
         let client = SyncClient::new(config).unwrap();
@@ -231,4 +873,393 @@ mod tests {
         let resolved = client.resolve_conflict(&conflict);
         assert_eq!(resolved, serde_json::json!({"a": 1}));
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn test_device_id_persists_in_storage() {
+        let storage = Storage::memory().unwrap();
+        
+        let first = SyncClient::with_storage(SyncConfig::default(), &storage).unwrap();
+        let second = SyncClient::with_storage(SyncConfig::default(), &storage).unwrap();
+        assert_eq!(first.device_id(), second.device_id());
+        assert_eq!(storage.get_setting(DEVICE_ID_SETTING).unwrap().as_deref(), Some(first.device_id()));
+        
+        let pinned = SyncClient::with_storage(SyncConfig {
+            device_id: Some("test-device".to_string()),
+            ..Default::default()
+        }, &storage).unwrap();
+        assert_eq!(pinned.device_id(), "test-device");
+    }
+    
+    fn synced_agent(storage: &Storage) -> String {
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "First entry")).unwrap();
+        storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent.id.clone(),
+            local_version: 1,
+            cloud_version: 3,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Pending,
+            last_synced_seq: 1,
+            conflicts: None,
+        }).unwrap();
+        agent.id
+    }
+    
+    fn manager(server: &MockServer, storage: Storage) -> SyncManager {
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            api_key: "test".to_string(),
+            ..Default::default()
+        }).unwrap();
+        SyncManager::new(client, storage)
+    }
+    
+    #[tokio::test]
+    async fn test_delta_sync_applies_incoming_changes() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent_id = synced_agent(&storage);
+        storage.add_message(&StoredMessage::new(&agent_id, "user", "Second entry")).unwrap();
+        
+        let incoming = StoredMessage::new(&agent_id, "assistant", "Reply from the phone");
+        Mock::given(method("POST"))
+            .and(path(format!("/v1/agents/{}/sync/delta", agent_id)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 4,
+                "conflicts": [],
+                "status": "ok",
+                "delta": { "blocks": [], "messages": [incoming] }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let manager = manager(&server, storage.clone());
+        let response = manager.sync_agent(&agent_id).await.unwrap();
+        assert_eq!(response.cloud_version, 4);
+        
+        let sent: SyncDeltaRequest = server.received_requests().await.unwrap()[0].body_json().unwrap();
+        assert_eq!(sent.base_version, 3);
+        assert_eq!(sent.delta.messages.len(), 1);
+        assert_eq!(sent.delta.messages[0].content, "Second entry");
+        
+        let messages = storage.get_messages_since(&agent_id, 0).unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2].content, "Reply from the phone");
+        
+        let metadata = storage.get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        assert_eq!(metadata.cloud_version, 4);
+        assert_eq!(metadata.last_synced_seq, 3);
+        assert_eq!(metadata.sync_status, SyncStatus::Synced);
+    }
+    
+    #[tokio::test]
+    async fn test_write_during_upload_is_sent_next_time() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent_id = synced_agent(&storage);
+        storage.add_message(&StoredMessage::new(&agent_id, "user", "Second entry")).unwrap();
+        
+        let writer = storage.clone();
+        let writer_id = agent_id.clone();
+        Mock::given(method("POST"))
+            .and(path(format!("/v1/agents/{}/sync/delta", agent_id)))
+            .respond_with(move |_: &wiremock::Request| {
+                writer.add_message(&StoredMessage::new(&writer_id, "user", "Written mid-upload")).unwrap();
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "agent_file": null,
+                    "cloud_version": 4,
+                    "conflicts": [],
+                    "status": "ok"
+                }))
+            })
+            .mount(&server)
+            .await;
+        
+        let manager = manager(&server, storage.clone());
+        manager.sync_agent(&agent_id).await.unwrap();
+        let metadata = storage.get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        assert_eq!(metadata.sync_status, SyncStatus::Pending);
+        assert_eq!(metadata.last_synced_seq, 2);
+        
+        manager.sync_agent(&agent_id).await.unwrap();
+        let sent: SyncDeltaRequest = server.received_requests().await.unwrap()[1].body_json().unwrap();
+        let contents: Vec<_> = sent.delta.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Written mid-upload"]);
+    }
+    
+    #[tokio::test]
+    async fn test_pull_all_into_empty_database() {
+        let server = MockServer::start().await;
+        
+        let mut remote_ids = Vec::new();
+        let mut summaries = Vec::new();
+        for (name, block, message) in [("journal", "Alice", "Dear diary"), ("coach", "Bob", "Ready to run?")] {
+            let source = Storage::memory().unwrap();
+            let agent = StoredAgent::new(name, "Remote prompt");
+            source.create_agent(&agent).unwrap();
+            source.upsert_block(&StoredBlock::new(&agent.id, "human", block)).unwrap();
+            source.add_message(&StoredMessage::new(&agent.id, "user", message)).unwrap();
+            let agent_file = convert::agent_file_from_storage(&source, &agent.id).unwrap();
+            
+            Mock::given(method("GET"))
+                .and(path(format!("/v1/agents/{}/export", agent.id)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&agent_file))
+                .mount(&server)
+                .await;
+            summaries.push(RemoteAgentSummary {
+                id: agent.id.clone(),
+                name: name.to_string(),
+                updated_at: agent.updated_at,
+                version: 7,
+            });
+            remote_ids.push(agent.id);
+        }
+        
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&summaries))
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let manager = manager(&server, storage.clone());
+        assert_eq!(manager.pull_all().await.unwrap(), 2);
+        
+        assert_eq!(storage.list_agents().unwrap().len(), 2);
+        let journal = storage.get_agent(&remote_ids[0]).unwrap().unwrap();
+        assert_eq!(journal.name, "journal");
+        let blocks = storage.get_blocks(&remote_ids[1]).unwrap();
+        assert_eq!(blocks.iter().find(|b| b.label == "human").unwrap().value, "Bob");
+        assert_eq!(storage.get_messages_since(&remote_ids[1], 0).unwrap()[0].content, "Ready to run?");
+        
+        let metadata = storage.get_sync_metadata("agent", &remote_ids[0]).unwrap().unwrap();
+        assert_eq!(metadata.cloud_version, 7);
+        assert_eq!(metadata.sync_status, SyncStatus::Synced);
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_sync_and_apply_stores_newer_cloud_copy() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        
+        let mut cloud_file = convert::agent_file_from_storage(&storage, &agent.id).unwrap();
+        cloud_file.blocks.iter_mut().find(|b| b.label == "human").unwrap().value = "Alicia".to_string();
+        cloud_file.agents[0].agent_state.updated_at = Utc::now() + chrono::Duration::seconds(60);
+        
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": cloud_file,
+                "cloud_version": 5,
+                "conflicts": [],
+                "status": "ok"
+            })))
+            .mount(&server)
+            .await;
+        
+        let manager = manager(&server, storage.clone());
+        manager.sync_and_apply(&agent.id).await.unwrap();
+        
+        let blocks = storage.get_blocks(&agent.id).unwrap();
+        assert_eq!(blocks.iter().find(|b| b.label == "human").unwrap().value, "Alicia");
+        let metadata = storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap();
+        assert_eq!(metadata.cloud_version, 5);
+        assert_eq!(metadata.sync_status, SyncStatus::Synced);
+    }
+    
+    #[tokio::test]
+    async fn test_manual_sync_emits_events() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 2,
+                "conflicts": [{
+                    "field": "blocks.human",
+                    "local_value": "Alice",
+                    "cloud_value": "Alicia",
+                    "resolution": "last-write-wins"
+                }],
+                "status": "ok"
+            })))
+            .mount(&server)
+            .await;
+        
+        // auto_sync is off: events still fire for manual syncs
+        let manager = manager(&server, storage);
+        let mut events = manager.client.subscribe();
+        manager.sync_agent(&agent.id).await.unwrap();
+        
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        
+        assert_eq!(received[0], SyncEvent::Started { agent_id: agent.id.clone() });
+        assert!(matches!(&received[1], SyncEvent::Progress { bytes, .. } if *bytes > 0));
+        assert!(matches!(&received[2], SyncEvent::Progress { .. }));
+        assert_eq!(received[3], SyncEvent::Conflict {
+            agent_id: agent.id.clone(),
+            fields: vec!["blocks.human".to_string()],
+        });
+        assert!(matches!(
+            &received[4],
+            SyncEvent::Completed { new_version: Some(2), payload: Some(size), .. } if size.sent == size.uncompressed
+        ));
+    }
+    
+    #[tokio::test]
+    async fn test_failed_push_emits_failed_event() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        let agent_file = convert::agent_file_from_storage(&storage, &agent.id).unwrap();
+        
+        let manager = manager(&server, storage);
+        let mut events = manager.client.subscribe();
+        assert!(manager.client.push_agent(&agent_file).await.is_err());
+        
+        let mut last = None;
+        while let Ok(event) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(SyncEvent::Failed { error, .. }) if error.contains("500")));
+    }
+    
+    #[tokio::test]
+    async fn test_version_gap_falls_back_to_full_sync() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent_id = synced_agent(&storage);
+        
+        Mock::given(method("POST"))
+            .and(path(format!("/v1/agents/{}/sync/delta", agent_id)))
+            .respond_with(ResponseTemplate::new(409).set_body_json(serde_json::json!({
+                "cloud_version": 7
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 8,
+                "conflicts": [],
+                "status": "ok"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let manager = manager(&server, storage.clone());
+        let response = manager.sync_agent(&agent_id).await.unwrap();
+        assert_eq!(response.cloud_version, 8);
+        
+        let full: SyncRequest = server.received_requests().await.unwrap()[1].body_json().unwrap();
+        assert_eq!(full.agent_id, agent_id);
+        let agent_file = manager.client.open_payload(full.agent_file).unwrap();
+        assert_eq!(agent_file.agents[0].messages.len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_pull_retries_until_server_recovers() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        let agent_file = convert::agent_file_from_storage(&storage, &agent.id).unwrap();
+        
+        let export = format!("/v1/agents/{}/export", agent.id);
+        Mock::given(method("GET"))
+            .and(path(export.clone()))
+            .respond_with(ResponseTemplate::new(503).insert_header("retry-after", "0"))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(export))
+            .respond_with(ResponseTemplate::new(200).set_body_json(AgentPayload::from(&agent_file)))
+            .mount(&server)
+            .await;
+        
+        let manager = manager(&server, Storage::memory().unwrap());
+        let pulled = manager.client.pull_agent(&agent.id).await.unwrap().unwrap();
+        assert_eq!(pulled.agents[0].id, agent.id);
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_unreachable_proxy_is_named() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        // Nothing listens on the discard port
+        let config = SyncConfig {
+            endpoint: server.uri(),
+            proxy: Some("http://127.0.0.1:9".to_string()),
+            retry: RetryConfig::none(),
+            ..Default::default()
+        };
+        let client = SyncClient::new(config.clone()).unwrap();
+        let error = client.list_remote_agents().await.unwrap_err();
+        assert!(matches!(&error, SyncError::Proxy { proxy, .. } if proxy == "http://127.0.0.1:9"), "{:?}", error);
+        assert!(error.to_string().contains("127.0.0.1:9"), "{}", error);
+        
+        let direct = SyncClient::new(SyncConfig { no_proxy: Some("127.0.0.1".to_string()), ..config }).unwrap();
+        assert!(direct.list_remote_agents().await.unwrap().is_empty());
+        
+        let bad = SyncConfig { proxy: Some("not a url".to_string()), ..Default::default() };
+        assert!(matches!(SyncClient::new(bad), Err(SyncError::Http(_))));
+    }
+    
+    #[tokio::test]
+    async fn test_rate_limited_sync_reports_attempts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(429).insert_header("retry-after", "0").set_body_string("slow down"))
+            .expect(3)
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let agent_id = synced_agent(&storage);
+        let agent_file = convert::agent_file_from_storage(&storage, &agent_id).unwrap();
+        let manager = manager(&server, storage);
+        
+        let error = manager.client.sync_agent(&agent_file, 1).await.unwrap_err();
+        match error {
+            SyncError::RetriesExhausted { attempts, source } => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*source, SyncError::Server { status: 429, ref message } if message == "slow down"));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
+
@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use letta_core::af::{AgentFile, BlockDiff};
+use letta_storage::SyncStatus;
+use crate::{convert, error::Result, ConflictInfo, ConflictResolution, SyncManager};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    Push,
+    Pull,
+    Merge,
+    Noop,
+}
+
+/// What `sync_agent` would do right now, computed without changing anything
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub agent_id: String,
+    pub direction: SyncDirection,
+    pub local_version: i64,
+    /// 0 when the agent doesn't exist on the server yet
+    pub cloud_version: i64,
+    /// `left` is the local value, `right` the cloud value
+    pub changed_blocks: Vec<BlockDiff>,
+    pub new_local_messages: usize,
+    pub new_cloud_messages: usize,
+    /// Blocks changed on both sides, with the resolution that would apply
+    pub conflicts: Vec<ConflictInfo>,
+}
+
+impl SyncManager {
+    /// Preview a sync of `agent_id`: the cloud copy is downloaded and diffed
+    /// against local state, and the agent's `ConflictResolution` is applied
+    /// hypothetically. Neither storage nor sync metadata is modified.
+    pub async fn plan(&self, agent_id: &str) -> Result<SyncPlan> {
+        let local = convert::agent_file_from_storage(&self.storage, agent_id)?;
+        let metadata = self.storage.get_sync_metadata("agent", agent_id)?;
+        let local_version = metadata.as_ref().map(|m| m.local_version).unwrap_or(0);
+        let known_cloud_version = metadata.as_ref().map(|m| m.cloud_version).unwrap_or(0);
+        let local_dirty = metadata.as_ref().is_none_or(|m| m.sync_status != SyncStatus::Synced)
+            || self.storage.list_sync_queue()?.iter()
+                .any(|e| e.entity_type == "agent" && e.entity_id == agent_id);
+        
+        let remote = self.client.list_remote_agents().await?
+            .into_iter()
+            .find(|summary| summary.id == agent_id);
+        let cloud = match &remote {
+            Some(_) => self.client.pull_agent(agent_id).await?,
+            None => None,
+        };
+        
+        let mut plan = SyncPlan {
+            agent_id: agent_id.to_string(),
+            direction: SyncDirection::Noop,
+            local_version,
+            cloud_version: remote.as_ref().map(|r| r.version).unwrap_or(0),
+            changed_blocks: Vec::new(),
+            new_local_messages: 0,
+            new_cloud_messages: 0,
+            conflicts: Vec::new(),
+        };
+        
+        let cloud = match cloud {
+            Some(cloud) => cloud,
+            None => {
+                // First upload of this agent
+                plan.direction = SyncDirection::Push;
+                plan.new_local_messages = local.agents[0].messages.len();
+                return Ok(plan);
+            }
+        };
+        
+        let diff = AgentFile::diff(&local, &cloud)?;
+        if diff.is_empty() {
+            return Ok(plan);
+        }
+        
+        let local_changed = local_dirty || !diff.left_only_messages.is_empty();
+        let cloud_changed = plan.cloud_version > known_cloud_version || !diff.right_only_messages.is_empty();
+        let resolution = self.agent_sync_policy(agent_id)?.conflict_resolution;
+        
+        plan.direction = match (local_changed, cloud_changed) {
+            (true, false) => SyncDirection::Push,
+            (false, _) => SyncDirection::Pull,
+            (true, true) => match resolution {
+                ConflictResolution::CloudWins => SyncDirection::Pull,
+                ConflictResolution::Merge | ConflictResolution::Manual => SyncDirection::Merge,
+                ConflictResolution::LastWriteWins => {
+                    if local.agents[0].agent_state.updated_at >= cloud.agents[0].agent_state.updated_at {
+                        SyncDirection::Push
+                    } else {
+                        SyncDirection::Pull
+                    }
+                }
+            },
+        };
+        
+        if local_changed && cloud_changed {
+            let resolution = serde_json::to_value(resolution)?
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            plan.conflicts = diff.changed_blocks.iter()
+                .map(|block| ConflictInfo {
+                    field: format!("blocks.{}", block.label),
+                    local_value: serde_json::json!(block.left),
+                    cloud_value: serde_json::json!(block.right),
+                    resolution: resolution.clone(),
+                })
+                .collect();
+        }
+        
+        plan.new_local_messages = diff.left_only_messages.len();
+        plan.new_cloud_messages = diff.right_only_messages.len();
+        plan.changed_blocks = diff.changed_blocks;
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RemoteAgentSummary, SyncClient, SyncConfig};
+    use chrono::Utc;
+    use letta_core::message::Message;
+    use letta_storage::{AgentSyncSettings, Storage, StoredAgent, StoredBlock, StoredMessage, SyncMetadata};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+    
+    /// A synced local agent, plus a server whose copy gained a message and a
+    /// changed `human` block since
+    async fn setup() -> (MockServer, Storage, String) {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "First entry")).unwrap();
+        storage.update_sync_metadata(&SyncMetadata {
+            entity_type: "agent".to_string(),
+            entity_id: agent.id.clone(),
+            local_version: 1,
+            cloud_version: 3,
+            last_sync_at: Utc::now(),
+            sync_status: SyncStatus::Synced,
+            last_synced_seq: 1,
+            conflicts: None,
+        }).unwrap();
+        storage.cancel_sync_entries("agent", &agent.id).unwrap();
+        
+        let mut cloud = convert::agent_file_from_storage(&storage, &agent.id).unwrap();
+        cloud.agents[0].messages.push(Message::user("Written on the phone"));
+        cloud.blocks.iter_mut().find(|b| b.label == "human").unwrap().value = "Alicia".to_string();
+        
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(vec![RemoteAgentSummary {
+                id: agent.id.clone(),
+                name: agent.name.clone(),
+                updated_at: Utc::now(),
+                version: 4,
+            }]))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/v1/agents/{}/export", agent.id)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(&cloud))
+            .mount(&server)
+            .await;
+        
+        (server, storage, agent.id)
+    }
+    
+    fn manager(server: &MockServer, storage: &Storage) -> SyncManager {
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            ..Default::default()
+        }).unwrap();
+        SyncManager::new(client, storage.clone())
+    }
+    
+    #[tokio::test]
+    async fn test_plan_pull_without_mutating() {
+        let (server, storage, agent_id) = setup().await;
+        let metadata_before = storage.get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        
+        let plan = manager(&server, &storage).plan(&agent_id).await.unwrap();
+        assert_eq!(plan.direction, SyncDirection::Pull);
+        assert_eq!((plan.local_version, plan.cloud_version), (1, 4));
+        assert_eq!(plan.changed_blocks.len(), 1);
+        assert_eq!(plan.changed_blocks[0].right.as_deref(), Some("Alicia"));
+        assert_eq!((plan.new_local_messages, plan.new_cloud_messages), (0, 1));
+        assert!(plan.conflicts.is_empty());
+        
+        let metadata_after = storage.get_sync_metadata("agent", &agent_id).unwrap().unwrap();
+        assert_eq!(metadata_after.cloud_version, metadata_before.cloud_version);
+        assert_eq!(metadata_after.sync_status, SyncStatus::Synced);
+        assert_eq!(storage.get_messages_since(&agent_id, 0).unwrap().len(), 1);
+        assert!(storage.list_sync_queue().unwrap().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_plan_reports_conflicts_when_both_sides_changed() {
+        let (server, storage, agent_id) = setup().await;
+        storage.upsert_block(&StoredBlock::new(&agent_id, "human", "Alice B.")).unwrap();
+        storage.set_agent_sync_settings(&AgentSyncSettings {
+            conflict_resolution: Some("merge".to_string()),
+            ..AgentSyncSettings::new(&agent_id)
+        }).unwrap();
+        
+        let plan = manager(&server, &storage).plan(&agent_id).await.unwrap();
+        assert_eq!(plan.direction, SyncDirection::Merge);
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(plan.conflicts[0].field, "blocks.human");
+        assert_eq!(plan.conflicts[0].local_value, "Alice B.");
+        assert_eq!(plan.conflicts[0].cloud_value, "Alicia");
+        assert_eq!(plan.conflicts[0].resolution, "merge");
+    }
+}
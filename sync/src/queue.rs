@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use crate::{error::Result, SyncConfig, SyncManager};
+
+/// Entries drained per `drain_queue` call
+const DRAIN_BATCH: usize = 50;
+
+/// Snapshot of the durable upload queue, e.g. for "3 changes pending upload"
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueStatus {
+    pub pending: usize,
+    pub failed: usize,
+    /// Earliest time a pending entry becomes due
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    /// Last error of each permanently failed entry
+    pub failures: Vec<QueueFailure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueFailure {
+    pub entity_type: String,
+    pub entity_id: String,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+/// Delay before retry number `attempts` (1-based), doubling up to the cap
+pub(crate) fn backoff_delay(config: &SyncConfig, attempts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    let delay = config.queue_backoff_base_ms.saturating_mul(factor);
+    Duration::from_millis(delay.min(config.queue_backoff_max_ms))
+}
+
+impl SyncManager {
+    /// Upload every queued change whose backoff has elapsed. Failures are
+    /// rescheduled with exponential backoff until `queue_max_attempts`, after
+    /// which the entry is kept as failed with its last error. Returns the
+    /// number of entries uploaded.
+    ///
+    /// Entries of agents with sync disabled are left in the queue untouched.
+    pub async fn drain_queue(&self) -> Result<usize> {
+        self.drain(false).await
+    }
+    
+    /// Drain the queue; with `respect_intervals` agents whose own sync interval
+    /// hasn't elapsed yet are skipped until a later pass.
+    pub(crate) async fn drain(&self, respect_intervals: bool) -> Result<usize> {
+        let config = &self.client.config;
+        let storage = self.storage.to_async();
+        let now = Utc::now();
+        let mut uploaded = 0;
+        
+        for entry in storage.due_sync_entries(now, DRAIN_BATCH).await? {
+            if entry.entity_type == "agent" {
+                let policy = self.agent_sync_policy(&entry.entity_id)?;
+                if !policy.enabled
+                    || (respect_intervals && !self.sync_interval_elapsed(&entry.entity_id, &policy, now)?)
+                {
+                    continue;
+                }
+            }
+            
+            let outcome = match (entry.entity_type.as_str(), entry.operation.as_str()) {
+                ("agent", "upsert") => self.sync_agent(&entry.entity_id).await.map(|_| ()),
+                ("agent", "delete") => self.client.delete_agent(&entry.entity_id).await,
+                (entity_type, operation) => {
+                    tracing::warn!("Dropping unsupported sync operation {} on {}", operation, entity_type);
+                    storage.record_sync_failure(entry.id, "unsupported operation", None).await?;
+                    continue;
+                }
+            };
+            
+            match outcome {
+                Ok(()) => {
+                    storage.complete_sync_entry(entry.id, entry.generation).await?;
+                    uploaded += 1;
+                }
+                Err(e) => {
+                    let attempts = entry.attempts + 1;
+                    let next_attempt_at = if attempts >= config.queue_max_attempts {
+                        tracing::error!(
+                            "Giving up syncing {} {} after {} attempts: {}",
+                            entry.entity_type, entry.entity_id, attempts, e
+                        );
+                        None
+                    } else {
+                        let delay = backoff_delay(config, attempts);
+                        tracing::warn!(
+                            "Sync of {} {} failed (attempt {}), retrying in {:?}: {}",
+                            entry.entity_type, entry.entity_id, attempts, delay, e
+                        );
+                        chrono::Duration::from_std(delay).ok().map(|d| Utc::now() + d)
+                    };
+                    storage.record_sync_failure(entry.id, e.to_string(), next_attempt_at).await?;
+                }
+            }
+        }
+        
+        Ok(uploaded)
+    }
+    
+    pub fn queue_status(&self) -> Result<QueueStatus> {
+        let mut status = QueueStatus::default();
+        
+        for entry in self.storage.list_sync_queue()? {
+            if entry.status == "failed" {
+                status.failed += 1;
+                status.failures.push(QueueFailure {
+                    entity_type: entry.entity_type,
+                    entity_id: entry.entity_id,
+                    attempts: entry.attempts,
+                    error: entry.last_error,
+                });
+            } else {
+                status.pending += 1;
+                status.next_attempt_at = Some(match status.next_attempt_at {
+                    Some(next) => next.min(entry.next_attempt_at),
+                    None => entry.next_attempt_at,
+                });
+            }
+        }
+        
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyncClient;
+    use letta_storage::{Storage, StoredAgent, StoredMessage};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+    
+    fn manager(server: &MockServer, storage: Storage, max_attempts: u32) -> SyncManager {
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            queue_max_attempts: max_attempts,
+            // One request per queue attempt
+            retry: letta_core::RetryConfig::none(),
+            ..Default::default()
+        }).unwrap();
+        SyncManager::new(client, storage)
+    }
+    
+    fn dirty_agent(storage: &Storage) -> String {
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        storage.add_message(&StoredMessage::new(&agent.id, "user", "Offline entry")).unwrap();
+        agent.id
+    }
+    
+    #[test]
+    fn test_backoff_doubles_and_caps() {
+        let config = SyncConfig {
+            queue_backoff_base_ms: 1000,
+            queue_backoff_max_ms: 5000,
+            ..Default::default()
+        };
+        
+        assert_eq!(backoff_delay(&config, 1), Duration::from_millis(1000));
+        assert_eq!(backoff_delay(&config, 2), Duration::from_millis(2000));
+        assert_eq!(backoff_delay(&config, 3), Duration::from_millis(4000));
+        assert_eq!(backoff_delay(&config, 4), Duration::from_millis(5000));
+        assert_eq!(backoff_delay(&config, 60), Duration::from_millis(5000));
+    }
+    
+    #[tokio::test]
+    async fn test_failed_upload_is_rescheduled() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let agent_id = dirty_agent(&storage);
+        let manager = manager(&server, storage.clone(), 5);
+        
+        assert_eq!(manager.drain_queue().await.unwrap(), 0);
+        
+        let status = manager.queue_status().unwrap();
+        assert_eq!(status.pending, 1);
+        assert_eq!(status.failed, 0);
+        assert!(status.next_attempt_at.unwrap() > Utc::now());
+        
+        let entry = &storage.list_sync_queue().unwrap()[0];
+        assert_eq!(entry.entity_id, agent_id);
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.last_error.as_deref().unwrap().contains("503"));
+        
+        // Not due yet, so a second drain makes no request
+        assert_eq!(manager.drain_queue().await.unwrap(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_entry_fails_permanently_after_max_attempts() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(500).set_body_string("boom"))
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        dirty_agent(&storage);
+        let manager = manager(&server, storage.clone(), 1);
+        
+        manager.drain_queue().await.unwrap();
+        
+        let status = manager.queue_status().unwrap();
+        assert_eq!(status.pending, 0);
+        assert_eq!(status.failed, 1);
+        assert!(status.failures[0].error.as_deref().unwrap().contains("boom"));
+    }
+    
+    #[tokio::test]
+    async fn test_successful_upload_drains_queue() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 1,
+                "conflicts": [],
+                "status": "ok"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        dirty_agent(&storage);
+        let manager = manager(&server, storage.clone(), 5);
+        
+        assert_eq!(manager.queue_status().unwrap().pending, 1);
+        assert_eq!(manager.drain_queue().await.unwrap(), 1);
+        assert_eq!(manager.queue_status().unwrap().pending, 0);
+    }
+    
+    #[tokio::test]
+    async fn test_write_during_upload_stays_queued() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent_id = dirty_agent(&storage);
+        let writer = storage.clone();
+        let writer_id = agent_id.clone();
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(move |_: &wiremock::Request| {
+                writer.add_message(&StoredMessage::new(&writer_id, "user", "Written mid-upload")).unwrap();
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "agent_file": null,
+                    "cloud_version": 1,
+                    "conflicts": [],
+                    "status": "ok"
+                }))
+            })
+            .mount(&server)
+            .await;
+        let manager = manager(&server, storage.clone(), 5);
+        
+        assert_eq!(manager.drain_queue().await.unwrap(), 1);
+        let queue = storage.list_sync_queue().unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].entity_id, agent_id);
+    }
+    
+    #[tokio::test]
+    async fn test_conflict_kept_local_is_uploaded_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 2,
+                "conflicts": [
+                    { "field": "blocks.human", "local_value": "Alice", "cloud_value": "Alicia", "resolution": "" }
+                ],
+                "status": "conflict"
+            })))
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        dirty_agent(&storage);
+        let manager = manager(&server, storage.clone(), 5);
+        
+        // Last write wins keeps the local value, which the cloud has yet to see
+        assert_eq!(manager.drain_queue().await.unwrap(), 1);
+        assert_eq!(manager.queue_status().unwrap().pending, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_trashed_agent_is_deleted_remotely() {
+        let server = MockServer::start().await;
+        let storage = Storage::memory().unwrap();
+        let agent_id = dirty_agent(&storage);
+        Mock::given(method("DELETE"))
+            .and(path(format!("/v1/agents/{}", agent_id)))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/agents"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "id": agent_id,
+                "name": "journal",
+                "updated_at": Utc::now(),
+                "version": 1
+            }])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/v1/agents/{}/export", agent_id)))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let manager = manager(&server, storage.clone(), 5);
+        
+        storage.soft_delete_agent(&agent_id).unwrap();
+        assert_eq!(manager.drain_queue().await.unwrap(), 1);
+        assert_eq!(manager.queue_status().unwrap().pending, 0);
+        
+        // The tombstone stops a cloud copy from being pulled back down
+        storage.purge_deleted(chrono::Duration::zero()).unwrap();
+        assert_eq!(manager.pull_all().await.unwrap(), 0);
+        assert!(storage.get_agent(&agent_id).unwrap().is_none());
+    }
+}
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use letta_storage::{BlockWriter, StoredBlock, SyncStatus};
+use crate::{
+    error::{Result, SyncError},
+    ConflictInfo, ConflictResolution, SyncClient, SyncManager,
+};
+
+/// Outcome of resolving one conflict
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Resolution {
+    UseLocal,
+    UseCloud,
+    UseValue(serde_json::Value),
+    /// Leave the conflict for the app to resolve later via `SyncManager::resolve_deferred`
+    Defer,
+}
+
+/// App-specific conflict rules, e.g. "persona block: keep local"
+pub type ConflictResolver = Box<dyn Fn(&ConflictInfo) -> Resolution + Send + Sync>;
+
+/// What a built-in strategy decides, or `None` when it can't decide on its own
+pub(crate) fn builtin_resolution(strategy: ConflictResolution, conflict: &ConflictInfo) -> Option<Resolution> {
+    match strategy {
+        ConflictResolution::LastWriteWins => Some(Resolution::UseLocal),
+        ConflictResolution::CloudWins => Some(Resolution::UseCloud),
+        ConflictResolution::Merge => {
+            // Objects are merged key by key, local keys winning; anything else is ambiguous
+            match (conflict.local_value.as_object(), conflict.cloud_value.as_object()) {
+                (Some(local), Some(cloud)) => {
+                    let mut merged = cloud.clone();
+                    for (k, v) in local {
+                        merged.insert(k.clone(), v.clone());
+                    }
+                    Some(Resolution::UseValue(serde_json::Value::Object(merged)))
+                }
+                _ => None,
+            }
+        }
+        ConflictResolution::Manual => None,
+    }
+}
+
+impl SyncClient {
+    /// Install a resolver consulted for `ConflictResolution::Manual` and
+    /// whenever the built-in strategy can't decide. Without one such
+    /// conflicts are deferred.
+    pub fn set_resolver(&self, resolver: ConflictResolver) {
+        *self.resolver.write().unwrap() = Some(resolver);
+    }
+    
+    pub fn resolve(&self, strategy: ConflictResolution, conflict: &ConflictInfo) -> Resolution {
+        builtin_resolution(strategy, conflict)
+            .or_else(|| self.resolver.read().unwrap().as_ref().map(|resolver| resolver(conflict)))
+            .unwrap_or(Resolution::Defer)
+    }
+}
+
+impl SyncManager {
+    /// Resolve the conflicts a sync reported, returning those deferred to the user
+    pub(crate) fn handle_conflicts(&self, agent_id: &str, conflicts: &[ConflictInfo]) -> Result<Vec<ConflictInfo>> {
+        let strategy = self.agent_sync_policy(agent_id)?.conflict_resolution;
+        let mut deferred = Vec::new();
+        
+        for conflict in conflicts {
+            match self.client.resolve(strategy, conflict) {
+                Resolution::Defer => deferred.push(conflict.clone()),
+                resolution => self.apply_resolution(agent_id, conflict, &resolution)?,
+            }
+        }
+        
+        Ok(deferred)
+    }
+    
+    /// Settle every deferred conflict of `entity_id` with `resolution`. The
+    /// agent is then queued for upload so the cloud sees the outcome.
+    pub fn resolve_deferred(&self, entity_id: &str, resolution: Resolution) -> Result<()> {
+        if resolution == Resolution::Defer {
+            return Ok(());
+        }
+        
+        let mut metadata = self.storage.get_sync_metadata("agent", entity_id)?
+            .ok_or_else(|| SyncError::AgentNotFound(entity_id.to_string()))?;
+        let conflicts: Vec<ConflictInfo> = match metadata.conflicts.take() {
+            Some(conflicts) => serde_json::from_value(conflicts)?,
+            None => Vec::new(),
+        };
+        
+        for conflict in &conflicts {
+            self.apply_resolution(entity_id, conflict, &resolution)?;
+        }
+        
+        metadata.sync_status = SyncStatus::Pending;
+        self.storage.update_sync_metadata(&metadata)?;
+        self.storage.enqueue_sync("agent", entity_id, "upsert")?;
+        Ok(())
+    }
+    
+    /// Write the chosen value locally. Only `blocks.<label>` fields map to
+    /// local state; keeping the local value queues it for re-upload, as does
+    /// writing a value other than the cloud's.
+    fn apply_resolution(&self, agent_id: &str, conflict: &ConflictInfo, resolution: &Resolution) -> Result<()> {
+        let value = match resolution {
+            Resolution::UseLocal => {
+                self.storage.enqueue_sync("agent", agent_id, "upsert")?;
+                return Ok(());
+            }
+            Resolution::UseCloud => &conflict.cloud_value,
+            Resolution::UseValue(value) => value,
+            Resolution::Defer => return Ok(()),
+        };
+        
+        let label = match conflict.field.strip_prefix("blocks.") {
+            Some(label) => label,
+            None => {
+                tracing::warn!("Conflict on {} has no local counterpart, ignoring", conflict.field);
+                return Ok(());
+            }
+        };
+        
+        let text = match value {
+            serde_json::Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        let mut block = self.storage.get_blocks(agent_id)?
+            .into_iter()
+            .find(|b| b.label == label)
+            .unwrap_or_else(|| StoredBlock::new(agent_id, label, ""));
+        block.value = text;
+        block.updated_at = chrono::Utc::now();
+        let storage = match resolution {
+            Resolution::UseCloud => self.storage.untracked(),
+            _ => self.storage.clone(),
+        };
+        storage.upsert_block_by(&block, BlockWriter::Sync)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyncConfig;
+    use letta_storage::{Storage, StoredAgent};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+    
+    fn conflict(field: &str, local: serde_json::Value, cloud: serde_json::Value) -> ConflictInfo {
+        ConflictInfo {
+            field: field.to_string(),
+            local_value: local,
+            cloud_value: cloud,
+            resolution: String::new(),
+        }
+    }
+    
+    #[test]
+    fn test_resolver_handles_ambiguous_merge() {
+        let client = SyncClient::new(SyncConfig::default()).unwrap();
+        let text = conflict("blocks.human", "Alice".into(), "Alicia".into());
+        let objects = conflict("metadata", serde_json::json!({"a": 1}), serde_json::json!({"b": 2}));
+        
+        assert_eq!(client.resolve(ConflictResolution::Merge, &text), Resolution::Defer);
+        assert_eq!(
+            client.resolve(ConflictResolution::Merge, &objects),
+            Resolution::UseValue(serde_json::json!({"a": 1, "b": 2}))
+        );
+        
+        client.set_resolver(Box::new(|_| Resolution::UseCloud));
+        assert_eq!(client.resolve(ConflictResolution::Merge, &text), Resolution::UseCloud);
+        // Unambiguous strategies never reach the resolver
+        assert_eq!(client.resolve(ConflictResolution::LastWriteWins, &text), Resolution::UseLocal);
+    }
+    
+    #[tokio::test]
+    async fn test_manual_conflicts_are_deferred_and_resolved_later() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 5,
+                "conflicts": [
+                    { "field": "blocks.persona", "local_value": "Terse", "cloud_value": "Chatty", "resolution": "" },
+                    { "field": "blocks.human", "local_value": "Alice", "cloud_value": "Alicia", "resolution": "" }
+                ],
+                "status": "conflict"
+            })))
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        storage.upsert_block(&StoredBlock::new(&agent.id, "human", "Alice")).unwrap();
+        
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            conflict_resolution: ConflictResolution::Manual,
+            ..Default::default()
+        }).unwrap();
+        client.set_resolver(Box::new(|conflict| match conflict.field.as_str() {
+            "blocks.persona" => Resolution::UseLocal,
+            _ => Resolution::Defer,
+        }));
+        let manager = SyncManager::new(client, storage.clone());
+        manager.sync_agent(&agent.id).await.unwrap();
+        
+        let metadata = storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap();
+        assert_eq!(metadata.sync_status, SyncStatus::Conflict);
+        let deferred: Vec<ConflictInfo> = serde_json::from_value(metadata.conflicts.unwrap()).unwrap();
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].field, "blocks.human");
+        
+        manager.resolve_deferred(&agent.id, Resolution::UseCloud).unwrap();
+        let human = storage.get_blocks(&agent.id).unwrap().into_iter().find(|b| b.label == "human").unwrap();
+        assert_eq!(human.value, "Alicia");
+        
+        let metadata = storage.get_sync_metadata("agent", &agent.id).unwrap().unwrap();
+        assert_eq!(metadata.sync_status, SyncStatus::Pending);
+        assert!(metadata.conflicts.is_none());
+        assert_eq!(storage.list_sync_queue().unwrap().len(), 1);
+    }
+}
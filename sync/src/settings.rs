@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use letta_storage::AgentSyncSettings;
+use crate::{error::{Result, SyncError}, ConflictResolution, SyncManager};
+
+/// Sync behaviour for one agent: its stored overrides applied over `SyncConfig`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentSyncPolicy {
+    pub enabled: bool,
+    pub interval: Duration,
+    pub conflict_resolution: ConflictResolution,
+}
+
+impl SyncManager {
+    pub fn agent_sync_policy(&self, agent_id: &str) -> Result<AgentSyncPolicy> {
+        self.policy_from_settings(self.storage.get_agent_sync_settings(agent_id)?)
+    }
+    
+    pub(crate) fn policy_from_settings(&self, settings: AgentSyncSettings) -> Result<AgentSyncPolicy> {
+        let config = &self.client.config;
+        
+        let conflict_resolution = match settings.conflict_resolution {
+            Some(name) => serde_json::from_value(serde_json::Value::String(name.clone()))
+                .map_err(|_| SyncError::InvalidData(format!("Unknown conflict resolution '{}'", name)))?,
+            None => config.conflict_resolution,
+        };
+        
+        Ok(AgentSyncPolicy {
+            enabled: settings.enabled,
+            interval: Duration::from_millis(settings.sync_interval.unwrap_or(config.sync_interval)),
+            conflict_resolution,
+        })
+    }
+    
+    /// Whether the agent's own interval has passed since its last sync
+    pub(crate) fn sync_interval_elapsed(&self, agent_id: &str, policy: &AgentSyncPolicy, now: DateTime<Utc>) -> Result<bool> {
+        let last_sync_at = match self.storage.get_sync_metadata("agent", agent_id)? {
+            Some(metadata) => metadata.last_sync_at,
+            None => return Ok(true),
+        };
+        let interval = chrono::Duration::from_std(policy.interval).unwrap_or(chrono::Duration::MAX);
+        Ok(last_sync_at + interval <= now)
+    }
+    
+    /// How often the auto-sync loop wakes: the shortest interval of any enabled agent
+    pub(crate) async fn auto_sync_tick(&self) -> Result<Duration> {
+        let storage = self.storage.to_async();
+        let mut tick = Duration::from_millis(self.client.config.sync_interval);
+        for agent in storage.list_agents().await? {
+            let policy = self.policy_from_settings(storage.get_agent_sync_settings(agent.id).await?)?;
+            if policy.enabled {
+                tick = tick.min(policy.interval);
+            }
+        }
+        Ok(tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SyncClient, SyncConfig};
+    use letta_storage::{AgentSyncSettings, Storage, StoredAgent};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use wiremock::matchers::{method, path};
+    
+    fn manager(server: &MockServer, storage: Storage) -> SyncManager {
+        let client = SyncClient::new(SyncConfig {
+            endpoint: server.uri(),
+            sync_interval: 300_000,
+            ..Default::default()
+        }).unwrap();
+        SyncManager::new(client, storage)
+    }
+    
+    #[tokio::test]
+    async fn test_policy_falls_back_to_global_config() {
+        let storage = Storage::memory().unwrap();
+        let agent = StoredAgent::new("journal", "You keep a journal.");
+        storage.create_agent(&agent).unwrap();
+        let manager = SyncManager::new(SyncClient::new(SyncConfig::default()).unwrap(), storage.clone());
+        
+        let policy = manager.agent_sync_policy(&agent.id).unwrap();
+        assert!(policy.enabled);
+        assert_eq!(policy.interval, Duration::from_millis(300_000));
+        assert_eq!(policy.conflict_resolution, ConflictResolution::LastWriteWins);
+        
+        storage.set_agent_sync_settings(&AgentSyncSettings {
+            sync_interval: Some(60_000),
+            conflict_resolution: Some("cloud-wins".to_string()),
+            ..AgentSyncSettings::new(&agent.id)
+        }).unwrap();
+        
+        let policy = manager.agent_sync_policy(&agent.id).unwrap();
+        assert_eq!(policy.interval, Duration::from_millis(60_000));
+        assert_eq!(policy.conflict_resolution, ConflictResolution::CloudWins);
+        assert_eq!(manager.auto_sync_tick().await.unwrap(), Duration::from_millis(60_000));
+    }
+    
+    #[tokio::test]
+    async fn test_disabled_agents_are_never_uploaded() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/agents/sync"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "agent_file": null,
+                "cloud_version": 1,
+                "conflicts": [],
+                "status": "ok"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        
+        let storage = Storage::memory().unwrap();
+        let journal = StoredAgent::new("journal", "You keep a journal.");
+        let scratch = StoredAgent::new("scratch", "Throwaway test agent.");
+        storage.create_agent(&journal).unwrap();
+        storage.create_agent(&scratch).unwrap();
+        storage.set_agent_sync_settings(&AgentSyncSettings {
+            enabled: false,
+            ..AgentSyncSettings::new(&scratch.id)
+        }).unwrap();
+        
+        let manager = manager(&server, storage.clone());
+        assert_eq!(manager.drain_queue().await.unwrap(), 1);
+        
+        let queue = storage.list_sync_queue().unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].entity_id, scratch.id);
+        assert_eq!(queue[0].attempts, 0);
+    }
+}
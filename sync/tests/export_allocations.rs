@@ -0,0 +1,74 @@
+//! Bytes allocated turning a large agent into a sync upload, before and after
+//! `export_owned` and the borrowing `SyncRequest`. Run with
+//! `cargo test -p letta-sync --release --test export_allocations -- --ignored --nocapture`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use letta_core::{AgentConfig, AgentFile, AgentState, Message};
+use letta_sync::{AgentPayload, SyncRequest};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size.saturating_sub(layout.size()), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocated_by(work: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    work();
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn request(agent_id: &str, agent_file: AgentPayload<'_>) -> Vec<u8> {
+    let request = SyncRequest {
+        agent_id: agent_id.to_string(),
+        agent_file,
+        local_version: 1,
+        device_id: "bench".to_string(),
+    };
+    serde_json::to_vec(&request).unwrap()
+}
+
+#[test]
+#[ignore = "benchmark; run explicitly"]
+fn bench_sync_upload_allocations() {
+    let config = AgentConfig::default();
+    let mut state = AgentState::new("bench");
+    // Straight into the vector: the buffer itself would keep only the latest messages
+    for i in 0..10_000 {
+        state.messages.messages.push(Message::user(format!("Message {} with a sentence or two of ordinary chat text in it.", i)));
+    }
+    let id = state.id.clone();
+
+    // Export by reference, then copy the file into an owned request
+    let copied = allocated_by(|| {
+        let agent_file = AgentFile::export(&config, &state, vec![]).unwrap();
+        request(&id, agent_file.clone().into());
+    });
+    // Move the state into the file, which the request then borrows
+    let state = state.clone();
+    let moved = allocated_by(|| {
+        let agent_file = AgentFile::export_owned(&config, state, vec![]).unwrap();
+        request(&id, (&agent_file).into());
+    });
+
+    println!("10k messages: copying path {} KiB, borrowing path {} KiB", copied / 1024, moved / 1024);
+    assert!(moved * 2 < copied, "{} bytes vs {} bytes", moved, copied);
+}